@@ -0,0 +1,121 @@
+//! Compares the throughput of the epoll and io_uring piping paths when proxying a large transfer over loopback.
+//!
+//! Run with `cargo bench -p control-plane --features io_uring`. The transfer size in MiB can be overridden
+//! with the `BENCH_TRANSFER_MB` env var.
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TRANSFER_MB: usize = 512;
+const WRITE_CHUNK_SIZE: usize = 256 * 1024;
+const ITERATIONS: u32 = 5;
+
+/// Spawn a sink which accepts a single connection and drains it, returning the number of bytes received.
+fn spawn_sink() -> (SocketAddr, JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = vec![0u8; WRITE_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut buffer).unwrap() {
+                0 => return total,
+                n => total += n,
+            }
+        }
+    });
+    (addr, handle)
+}
+
+fn send_payload(proxy_addr: SocketAddr, transfer_bytes: usize) {
+    let mut stream = TcpStream::connect(proxy_addr).unwrap();
+    let chunk = vec![7u8; WRITE_CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < transfer_bytes {
+        let n = WRITE_CHUNK_SIZE.min(transfer_bytes - sent);
+        stream.write_all(&chunk[..n]).unwrap();
+        sent += n;
+    }
+    stream.shutdown(Shutdown::Write).unwrap();
+    // Wait for the proxy to close its side once the sink has drained the transfer
+    let _ = stream.read(&mut [0u8; 1]);
+}
+
+fn run_epoll_transfer(transfer_bytes: usize) -> Duration {
+    let (sink_addr, sink) = spawn_sink();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let proxy = runtime.spawn(async move {
+        let (inbound, _) = listener.accept().await.unwrap();
+        let outbound = tokio::net::TcpStream::connect(sink_addr).await.unwrap();
+        shared::utils::pipe_streams(inbound, outbound)
+            .await
+            .unwrap();
+    });
+
+    let start = Instant::now();
+    send_payload(proxy_addr, transfer_bytes);
+    assert_eq!(sink.join().unwrap(), transfer_bytes);
+    let elapsed = start.elapsed();
+    runtime.block_on(proxy).unwrap();
+    elapsed
+}
+
+fn run_uring_transfer(transfer_bytes: usize) -> Duration {
+    let (sink_addr, sink) = spawn_sink();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let proxy = std::thread::spawn(move || {
+        tokio_uring::start(async move {
+            let listener = tokio_uring::net::TcpListener::from_std(listener);
+            let (inbound, _) = listener.accept().await.unwrap();
+            let outbound = tokio_uring::net::TcpStream::connect(sink_addr)
+                .await
+                .unwrap();
+            control_plane::uring_proxy::pipe_streams(inbound, outbound)
+                .await
+                .unwrap();
+        })
+    });
+
+    let start = Instant::now();
+    send_payload(proxy_addr, transfer_bytes);
+    assert_eq!(sink.join().unwrap(), transfer_bytes);
+    let elapsed = start.elapsed();
+    proxy.join().unwrap();
+    elapsed
+}
+
+fn report(label: &str, transfer_mb: usize, durations: &[Duration]) {
+    let total: Duration = durations.iter().sum();
+    let mean = total / durations.len() as u32;
+    let throughput = transfer_mb as f64 / mean.as_secs_f64();
+    println!("{label:<8} mean {mean:>10.2?} over {ITERATIONS} runs — {throughput:.0} MiB/s");
+}
+
+fn main() {
+    let transfer_mb = std::env::var("BENCH_TRANSFER_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_TRANSFER_MB);
+    let transfer_bytes = transfer_mb * 1024 * 1024;
+    println!("Proxying {transfer_mb} MiB over loopback");
+
+    let epoll: Vec<Duration> = (0..ITERATIONS)
+        .map(|_| run_epoll_transfer(transfer_bytes))
+        .collect();
+    report("epoll", transfer_mb, &epoll);
+
+    let uring: Vec<Duration> = (0..ITERATIONS)
+        .map(|_| run_uring_transfer(transfer_bytes))
+        .collect();
+    report("io_uring", transfer_mb, &uring);
+}
@@ -136,8 +136,7 @@ impl CertProvisionerClient {
                 format!("application/json;version={}", &*CLIENT_MAJOR_VERSION),
             )
             .method(method)
-            .body(body)
-            .expect("Failed to build request");
+            .body(body)?;
 
         let (mut request_sender, connection) = self.get_connection().await?;
         tokio::spawn(async move {
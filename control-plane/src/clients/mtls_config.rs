@@ -10,6 +10,16 @@ pub struct CertProvisionerMtlsCerts {
 }
 
 impl CertProvisionerMtlsCerts {
+    pub fn new(
+        root_certificate: Certificate,
+        client_key_pair: (Vec<Certificate>, PrivateKey),
+    ) -> Self {
+        Self {
+            root_certificate,
+            client_key_pair,
+        }
+    }
+
     pub fn from_env_vars() -> Result<Self> {
         let client_certs_raw =
             configuration::get_cert_provisioner_mtls_cert_env().map_err(ServerError::EnvError)?;
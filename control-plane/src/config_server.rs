@@ -6,6 +6,7 @@ use storage_client_interface::StorageClientInterface;
 use crate::acme_account_details::AcmeAccountDetails;
 use crate::configuration;
 use crate::error::{Result as ServerResult, ServerError};
+use crate::stats_client::StatsClient;
 
 use hyper::server::conn;
 use hyper::service::service_fn;
@@ -13,19 +14,22 @@ use hyper::{Body, Method, Request, Response};
 use serde::de::DeserializeOwned;
 
 use shared::acme::jws::{jws, Jwk, NewOrderPayload};
-use shared::logging::TrxContext;
-use shared::server::config_server::requests::GetClockSyncResponse;
+use shared::error_code::{codes, ErrorBody, ErrorCode, HasErrorCode};
+use shared::logging::{AuditEvent, TrxContext};
 use shared::server::config_server::requests::{
-    ConfigServerPayload, DeleteObjectRequest, GetCertTokenResponseDataPlane,
-    GetE3TokenResponseDataPlane, GetObjectRequest, GetObjectResponse, JwsRequest,
-    PostTrxLogsRequest, PutObjectRequest,
+    ConfigServerPayload, CrashReport, DeleteObjectRequest, GetCertTokenResponseDataPlane,
+    GetE3TokenResponseDataPlane, GetObjectRequest, GetObjectResponse, JwsRequest, OcspFetchRequest,
+    OcspFetchResponse, PostAuditLogsRequest, PostTrxLogsRequest, PutObjectRequest, TrxLogBatch,
+    TRX_LOG_BATCH_CONTENT_TYPE,
 };
+use shared::server::config_server::requests::{GetClockSyncResponse, PostSessionTokenRequest};
 use shared::server::config_server::requests::{JwkResponse, JwsResponse, SignatureType};
 use shared::server::config_server::routes::ConfigServerPath;
+use shared::server::plane_version::PlaneVersion;
+use shared::server::session_token::SessionToken;
 use shared::server::CID::Parent;
 use shared::server::{get_vsock_server, Listener};
 use std::str::FromStr;
-use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 #[derive(Clone)]
@@ -87,7 +91,7 @@ impl<T: StorageClientInterface + Clone + Send + Sync + 'static> ConfigServer<T>
                             let enclave_context = enclave_context.clone();
                             let acme_account_details = acme_account_details.clone();
                             async move {
-                                handle_incoming_request(
+                                let response = handle_incoming_request(
                                     req,
                                     cert_client,
                                     storage_client,
@@ -95,6 +99,11 @@ impl<T: StorageClientInterface + Clone + Send + Sync + 'static> ConfigServer<T>
                                     acme_account_details,
                                 )
                                 .await
+                                .unwrap_or_else(|e| {
+                                    log::error!("Config server request failed — {e}");
+                                    e.to_error_response()
+                                });
+                                Ok::<_, ServerError>(response)
                             }
                         }),
                     )
@@ -139,6 +148,12 @@ async fn handle_incoming_request<T: StorageClientInterface>(
         Ok(ConfigServerPath::PostTrxLogs) => {
             Ok(handle_post_trx_logs_request(req, enclave_context).await)
         }
+        Ok(ConfigServerPath::PostTrxLogBatch) => {
+            Ok(handle_post_trx_log_batch_request(req, enclave_context).await)
+        }
+        Ok(ConfigServerPath::PostAuditLogs) => {
+            Ok(handle_post_audit_logs_request(req, enclave_context).await)
+        }
         Ok(ConfigServerPath::AcmeSign) => {
             Ok(handle_acme_signing_request(req, acme_account_details, enclave_context).await)
         }
@@ -156,6 +171,13 @@ async fn handle_incoming_request<T: StorageClientInterface>(
             _ => Ok(build_bad_request_response()),
         },
         Ok(ConfigServerPath::Time) => handle_time_sync_request().await,
+        Ok(ConfigServerPath::SessionToken) if req.method() == Method::POST => {
+            Ok(handle_session_token_request(req).await)
+        }
+        Ok(ConfigServerPath::EgressPolicy) => handle_egress_policy_request(),
+        Ok(ConfigServerPath::CrashReport) => Ok(handle_crash_report_request(req).await),
+        Ok(ConfigServerPath::Ocsp) => Ok(handle_ocsp_request(req).await),
+        Ok(ConfigServerPath::Version) => handle_version_request(),
         _ => Ok(build_bad_request_response()),
     }
 }
@@ -175,8 +197,41 @@ async fn handle_token_request(
     }
 }
 
+/// Store the session token generated by the enclave. It is never served back, so only the data plane knows it.
+async fn handle_session_token_request(req: Request<Body>) -> Response<Body> {
+    let parsed_result: ServerResult<PostSessionTokenRequest> = parse_request(req).await;
+    let token = parsed_result
+        .ok()
+        .and_then(|request| SessionToken::from_base64(request.token()));
+    match token {
+        Some(token) => {
+            crate::enclave_connection::set_session_token(token);
+            build_success_response(None)
+        }
+        None => {
+            log::error!("Invalid session token registered by data plane");
+            build_error_response("Invalid session token".to_string())
+        }
+    }
+}
+
+/// The latest runtime update to the egress policy, or a 404 if it hasn't changed since startup.
+fn handle_egress_policy_request() -> ServerResult<Response<Body>> {
+    #[cfg(feature = "network_egress")]
+    if let Some(update) = crate::egress_policy::latest_update() {
+        return Ok(build_success_response(Some(update.into_body()?)));
+    }
+    Ok(build_bad_request_response())
+}
+
+/// The control plane's version and protocol features, which the data plane checks against its own on startup.
+fn handle_version_request() -> ServerResult<Response<Body>> {
+    let version = PlaneVersion::current(env!("CARGO_PKG_VERSION"));
+    Ok(build_success_response(Some(version.into_body()?)))
+}
+
 async fn handle_time_sync_request() -> ServerResult<Response<Body>> {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
+    match shared::clock::now().duration_since(UNIX_EPOCH) {
         Ok(duration) => {
             let time = GetClockSyncResponse {
                 seconds: duration.as_secs() as i64,
@@ -240,11 +295,7 @@ async fn handle_post_trx_logs_request(
     let parsed_result: ServerResult<PostTrxLogsRequest> = parse_request(req).await;
     match parsed_result {
         Ok(log_body) => {
-            log_body.trx_logs().into_iter().for_each(|trx| {
-                if validate_trx_log(&trx, &enclave_context) {
-                    trx.record_trx();
-                }
-            });
+            record_trx_logs(log_body.trx_logs(), &enclave_context);
             build_success_response(None)
         }
         Err(e) => {
@@ -254,6 +305,160 @@ async fn handle_post_trx_logs_request(
     }
 }
 
+async fn handle_post_trx_log_batch_request(
+    req: Request<Body>,
+    enclave_context: configuration::EnclaveContext,
+) -> Response<Body> {
+    log::debug!("Recieved protobuf request in config server to log transactions");
+    let is_protobuf = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == TRX_LOG_BATCH_CONTENT_TYPE);
+    if !is_protobuf {
+        return build_unsupported_media_type_response();
+    }
+
+    let parsed_result = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => TrxLogBatch::decode(&body).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match parsed_result {
+        Ok(batch) => {
+            record_trx_logs(batch.trx_logs(), &enclave_context);
+            build_success_response(None)
+        }
+        Err(e) => {
+            log::error!("Failed to parse log batch from data plane - {e}");
+            build_error_response("Failed to parse log batch from data plane".to_string())
+        }
+    }
+}
+
+fn record_trx_logs(trx_logs: Vec<TrxContext>, enclave_context: &configuration::EnclaveContext) {
+    trx_logs.into_iter().for_each(|trx| {
+        if validate_trx_log(&trx, enclave_context) {
+            trx.record_trx();
+        }
+    });
+}
+
+fn validate_audit_log(
+    audit_log: &AuditEvent,
+    enclave_context: &configuration::EnclaveContext,
+) -> bool {
+    audit_log.resource_uuid == enclave_context.uuid
+        && audit_log.resource_name == enclave_context.name
+        && audit_log.team_uuid == enclave_context.team_uuid
+        && audit_log.app_uuid == enclave_context.app_uuid
+}
+
+async fn handle_post_audit_logs_request(
+    req: Request<Body>,
+    enclave_context: configuration::EnclaveContext,
+) -> Response<Body> {
+    log::debug!("Recieved request in config server to log audit events");
+    let parsed_result: ServerResult<PostAuditLogsRequest> = parse_request(req).await;
+    match parsed_result {
+        Ok(log_body) => {
+            log_body.audit_logs().into_iter().for_each(|audit_log| {
+                if validate_audit_log(&audit_log, &enclave_context) {
+                    audit_log.record();
+                }
+            });
+            build_success_response(None)
+        }
+        Err(e) => {
+            log::error!("Failed to parse audit log body from data plane - {e:?}");
+            build_error_response("Failed to parse audit log body from data plane".to_string())
+        }
+    }
+}
+
+async fn handle_crash_report_request(req: Request<Body>) -> Response<Body> {
+    let parsed_result: ServerResult<CrashReport> = parse_request(req).await;
+    match parsed_result {
+        Ok(report) => {
+            log::error!(
+                "Data plane crashed after {}s ({:?}) — {} at {} on thread {}, backtrace hash {}, version {}",
+                report.uptime_secs,
+                report.kind,
+                report.message,
+                report.location.as_deref().unwrap_or("unknown location"),
+                report.thread.as_deref().unwrap_or("unnamed"),
+                report.backtrace_hash,
+                report.version
+            );
+            StatsClient::record_enclave_event("crashed");
+            build_success_response(None)
+        }
+        Err(e) => {
+            log::error!("Failed to parse crash report from data plane - {e:?}");
+            build_error_response("Failed to parse crash report from data plane".to_string())
+        }
+    }
+}
+
+const OCSP_FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+async fn handle_ocsp_request(req: Request<Body>) -> Response<Body> {
+    let parsed_result: ServerResult<OcspFetchRequest> = parse_request(req).await;
+    let ocsp_request = match parsed_result {
+        Ok(ocsp_request) => ocsp_request,
+        Err(e) => {
+            log::error!("Failed to parse OCSP request from data plane - {e:?}");
+            return build_bad_request_response();
+        }
+    };
+    // OCSP is served over plain HTTP, and the response is signed by the CA, so it doesn't need to be fetched over TLS
+    if !ocsp_request.responder_url.starts_with("http://") {
+        log::error!(
+            "Rejecting OCSP request for non-HTTP responder {}",
+            ocsp_request.responder_url
+        );
+        return build_bad_request_response();
+    }
+
+    match fetch_ocsp_response(ocsp_request).await {
+        Ok(response) => match (OcspFetchResponse {
+            response: base64::encode(response),
+        })
+        .into_body()
+        {
+            Ok(body) => build_success_response(Some(body)),
+            Err(e) => build_error_response(format!("Failed to serialize OCSP response - {e:?}")),
+        },
+        Err(e) => {
+            log::error!("Failed to fetch OCSP response - {e}");
+            build_error_response(format!("Failed to fetch OCSP response - {e}"))
+        }
+    }
+}
+
+async fn fetch_ocsp_response(ocsp_request: OcspFetchRequest) -> Result<Vec<u8>, String> {
+    let body = base64::decode(ocsp_request.request).map_err(|e| e.to_string())?;
+    let request = Request::builder()
+        .method("POST")
+        .uri(&ocsp_request.responder_url)
+        .header("Content-Type", "application/ocsp-request")
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let response = tokio::time::timeout(OCSP_FETCH_TIMEOUT, hyper::Client::new().request(request))
+        .await
+        .map_err(|_| format!("{} timed out", ocsp_request.responder_url))?
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "{} responded with {}",
+            ocsp_request.responder_url,
+            response.status()
+        ));
+    }
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(body.to_vec())
+}
+
 async fn handle_acme_storage_get_request<T: StorageClientInterface>(
     req: Request<Body>,
     storage_client: T,
@@ -313,7 +518,7 @@ async fn handle_acme_storage_put_request<T: StorageClientInterface>(
                 namespaced_key
             );
             match storage_client
-                .put_object(namespaced_key, request_body.object())
+                .put_object(namespaced_key, request_body.object().to_string())
                 .await
             {
                 Ok(_) => Ok(build_success_response(None)),
@@ -398,7 +603,7 @@ async fn sign_acme_payload(
                 let order_payload: NewOrderPayload = serde_json::from_str(&jws_request.payload)?;
 
                 if !valid_order_identifiers(order_payload, enclave_context) {
-                    log::error!("[ACME] Domain for order was not for valid Evervault Enclave domain. Rejecting signing request.");
+                    log::error!("[ACME] Domain for order was not for valid Evervault Enclave domain or configured custom domain. Rejecting signing request.");
                     return Ok(build_bad_request_response());
                 }
             };
@@ -471,6 +676,7 @@ fn valid_order_identifiers(
                 base_domain
             )
         })
+        .chain(configuration::get_custom_domains())
         .collect();
 
     payload
@@ -492,20 +698,20 @@ fn build_success_response(body: Option<Body>) -> Response<Body> {
 }
 
 fn build_bad_request_response() -> Response<Body> {
-    Response::builder()
-        .status(404)
-        .header("Content-Type", "application/json")
-        .body(Body::empty())
-        .expect("Infallible")
+    build_coded_error_response(codes::NOT_FOUND, "Not Found")
+}
+
+fn build_unsupported_media_type_response() -> Response<Body> {
+    build_coded_error_response(codes::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type")
 }
 
 fn build_error_response(body_msg: String) -> Response<Body> {
     log::debug!("Request failed: {body_msg}");
-    Response::builder()
-        .status(500)
-        .header("Content-Type", "application/json")
-        .body(Body::from(body_msg))
-        .expect("Infallible")
+    build_coded_error_response(codes::INTERNAL, body_msg)
+}
+
+fn build_coded_error_response(code: ErrorCode, message: impl Into<String>) -> Response<Body> {
+    ErrorBody::new(code, message).into_response(code.status)
 }
 
 fn namespace_key(key: String, enclave_context: &configuration::EnclaveContext) -> String {
@@ -522,6 +728,7 @@ mod tests {
 
     use shared::acme::helpers;
     use shared::acme::jws::Identifier;
+    use shared::logging::AuditEventType;
 
     use super::*;
     use crate::mocks::storage_client_mock::MockStorageClientInterface;
@@ -615,7 +822,7 @@ mod tests {
         let key = "some_key".to_string();
         let object = "super_secret".to_string();
 
-        let req_body = PutObjectRequest::new(key.clone(), object.clone())
+        let req_body = PutObjectRequest::new(key.clone(), object.clone().into())
             .into_body()
             .unwrap();
         let req = hyper::Request::builder()
@@ -651,7 +858,7 @@ mod tests {
         let key = "some_key".to_string();
         let object = "super_secret".to_string();
 
-        let req_body = PutObjectRequest::new(key.clone(), object.clone())
+        let req_body = PutObjectRequest::new(key.clone(), object.clone().into())
             .into_body()
             .unwrap();
         let req = hyper::Request::builder()
@@ -865,6 +1072,54 @@ mod tests {
         assert!(result.status().is_client_error());
     }
 
+    #[test]
+    fn test_validate_audit_log() {
+        let enclave_context = get_enclave_context();
+        let audit_log = AuditEvent::new(
+            AuditEventType::SecretRead,
+            "API_KEY",
+            "environment_init",
+            &enclave_context.name,
+            &enclave_context.uuid,
+            &enclave_context.app_uuid,
+            &enclave_context.team_uuid,
+        );
+        assert!(validate_audit_log(&audit_log, &enclave_context));
+
+        let other_enclave_log = AuditEvent::new(
+            AuditEventType::SecretRead,
+            "API_KEY",
+            "environment_init",
+            "another-enclave",
+            &enclave_context.uuid,
+            &enclave_context.app_uuid,
+            &enclave_context.team_uuid,
+        );
+        assert!(!validate_audit_log(&other_enclave_log, &enclave_context));
+    }
+
+    #[tokio::test]
+    async fn test_handle_post_trx_log_batch_request_requires_protobuf() {
+        let batch = TrxLogBatch::new(vec![]);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/trx/logs/batch")
+            .header("Content-Type", "application/json")
+            .body(Body::from(batch.encode()))
+            .unwrap();
+        let response = handle_post_trx_log_batch_request(req, get_enclave_context()).await;
+        assert_eq!(response.status(), 415);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/trx/logs/batch")
+            .header("Content-Type", TRX_LOG_BATCH_CONTENT_TYPE)
+            .body(Body::from(batch.encode()))
+            .unwrap();
+        let response = handle_post_trx_log_batch_request(req, get_enclave_context()).await;
+        assert_eq!(response.status(), 200);
+    }
+
     #[test]
     fn test_validate_new_order_valid() {
         let enclave_context = get_enclave_context();
@@ -917,4 +1172,34 @@ mod tests {
 
         assert!(!valid_order_identifiers(payload, enclave_context));
     }
+
+    #[test]
+    fn test_validate_new_order_custom_domain() {
+        std::env::set_var("EV_CUSTOM_DOMAINS", "payments.example.com, api.example.com");
+        let payload = NewOrderPayload {
+            identifiers: vec![Identifier {
+                r#type: "dns".to_string(),
+                value: "api.example.com".to_string(),
+            }],
+        };
+
+        assert!(valid_order_identifiers(payload, get_enclave_context()));
+    }
+
+    #[tokio::test]
+    async fn test_ocsp_requests_for_non_http_responders_are_rejected() {
+        let req_body = OcspFetchRequest {
+            responder_url: "file:///etc/passwd".to_string(),
+            request: base64::encode("request"),
+        }
+        .into_body()
+        .unwrap();
+        let req = hyper::Request::builder()
+            .method(Method::POST)
+            .uri("/ocsp")
+            .body(req_body)
+            .unwrap();
+        let response = handle_ocsp_request(req).await;
+        assert_eq!(response.status(), 404);
+    }
 }
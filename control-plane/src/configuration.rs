@@ -1,9 +1,14 @@
 use std::str::FromStr;
 
+use crate::enclave_status;
+use crate::orchestrator::{self, OrchestratorConfig};
 use openssl::{
     ec::EcKey,
     pkey::{PKey, Private},
 };
+use shared::dry_run;
+use shared::runtime::RuntimeConfig;
+use shared::validation::ValidationReport;
 
 #[derive(PartialEq, Eq)]
 pub enum Environment {
@@ -77,6 +82,17 @@ impl EnclaveContext {
         }
     }
 
+    /// The enclave's context, or `None` if any of it is missing from the env.
+    pub fn try_from_env_vars() -> Option<EnclaveContext> {
+        Some(EnclaveContext {
+            uuid: std::env::var("CAGE_UUID").ok()?,
+            version: std::env::var("EV_CAGE_VERSION_ID").ok()?,
+            name: std::env::var("EV_CAGE_NAME").ok()?,
+            app_uuid: std::env::var("EV_APP_UUID").ok()?,
+            team_uuid: std::env::var("EV_TEAM_UUID").ok()?,
+        })
+    }
+
     pub fn hyphenated_app_uuid(&self) -> String {
         self.app_uuid.replace('_', "-")
     }
@@ -182,9 +198,320 @@ pub fn get_trusted_cert_base_domains() -> Vec<String> {
     enclave_base_domains
 }
 
+/// Customer domains, from the comma separated `EV_CUSTOM_DOMAINS`, which the enclave may order ACME certs for as well
+/// as its own trusted cert domains.
+pub fn get_custom_domains() -> Vec<String> {
+    std::env::var("EV_CUSTOM_DOMAINS")
+        .map(|domains| {
+            domains
+                .split(',')
+                .map(|domain| domain.trim().to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub const TCP_PASSTHROUGH_PORTS_ENV: &str = "EV_TCP_PASSTHROUGH_PORTS";
+
+/// Ports, from the comma separated `EV_TCP_PASSTHROUGH_PORTS`, whose connections are piped into the enclave as raw TCP
+/// rather than through the data plane's TLS server. Entries which aren't ports are skipped.
+pub fn get_tcp_passthrough_ports() -> Vec<u16> {
+    std::env::var(TCP_PASSTHROUGH_PORTS_ENV)
+        .map(|ports| {
+            ports
+                .split(',')
+                .filter_map(|port| port.trim().parse::<u16>().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether ingress connections to the enclave are prefixed with a PROXY protocol v2 header carrying the client's
+/// address. Only for deployments where the load balancer doesn't send its own header.
+pub fn get_send_proxy_protocol() -> bool {
+    match std::env::var("EV_SEND_PROXY_PROTOCOL") {
+        Ok(val) => val.to_lowercase() == "true",
+        Err(_) => false,
+    }
+}
+
 pub fn get_external_metrics_enabled() -> bool {
     match std::env::var("EXTERNAL_METRICS_ENABLED") {
         Ok(val) => val.to_lowercase() == "true",
         Err(_) => false,
     }
 }
+
+pub fn get_runtime_config() -> RuntimeConfig {
+    RuntimeConfig::from_env_vars("CONTROL_PLANE")
+}
+
+/// How long an ingress client has to send its first bytes before the connection is dropped without ever
+/// connecting to the enclave.
+pub fn get_first_byte_timeout() -> std::time::Duration {
+    let timeout_ms = std::env::var("CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS")
+        .ok()
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .unwrap_or(5000);
+    std::time::Duration::from_millis(timeout_ms)
+}
+
+/// How long the data plane has to send an egress request over a new egress connection before it's dropped.
+pub fn get_egress_handshake_timeout() -> std::time::Duration {
+    let timeout_ms = std::env::var("EV_EGRESS_HANDSHAKE_TIMEOUT_MS")
+        .ok()
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .unwrap_or(5000);
+    std::time::Duration::from_millis(timeout_ms)
+}
+
+const REQUIRED_ENV_VARS: [&str; 8] = [
+    "CAGE_UUID",
+    "EV_CAGE_VERSION_ID",
+    "EV_CAGE_NAME",
+    "EV_APP_UUID",
+    "EV_TEAM_UUID",
+    "ACME_S3_BUCKET",
+    "ACME_ACCOUNT_HMAC_KEY",
+    "ACME_ACCOUNT_HMAC_KEY_ID",
+];
+
+/// Check the control plane's environment before startup, so missing or malformed config is reported up front rather
+/// than panicking whenever it's first read.
+pub fn validate_config() -> ValidationReport {
+    let mut report = ValidationReport::new("Control plane");
+    for var_name in REQUIRED_ENV_VARS {
+        report.require_env(var_name);
+    }
+
+    if let Some(key) = report.require_env("ACME_ACCOUNT_EC_KEY") {
+        if EcKey::private_key_from_pem(key.as_bytes()).is_err() {
+            report.fatal("ACME_ACCOUNT_EC_KEY", "is not a valid PEM encoded EC key");
+        }
+    }
+
+    if let Ok(env) = std::env::var("RUST_ENV") {
+        if !matches!(env.as_str(), "development" | "staging" | "production") {
+            report.warning(
+                "RUST_ENV",
+                format!("unknown environment {env}, treating it as development"),
+            );
+        }
+    }
+
+    #[cfg(not(feature = "mock_provisioner"))]
+    if let Err(e) = crate::clients::mtls_config::CertProvisionerMtlsCerts::from_env_vars() {
+        report.fatal(
+            "CERT_PROVISIONER_MTLS_*",
+            format!("cert provisioner mTLS certs could not be loaded - {e}"),
+        );
+    }
+    #[cfg(feature = "mock_provisioner")]
+    if get_rust_env() != Environment::Development {
+        report.fatal(
+            "RUST_ENV",
+            "the mock_provisioner feature is only for local development",
+        );
+    }
+
+    if let Ok(timeout) = std::env::var("CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS") {
+        if timeout.parse::<u64>().is_err() {
+            report.warning(
+                "CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS",
+                format!(
+                    "{timeout} is not a number of milliseconds, the default of 5000 will be used"
+                ),
+            );
+        }
+    }
+
+    if let Some(orchestrator) = OrchestratorConfig::from_env() {
+        if !std::path::Path::new(&orchestrator.eif_path).is_file() {
+            report.fatal(
+                "EV_ENCLAVE_EIF_PATH",
+                format!("{} is not a file", orchestrator.eif_path),
+            );
+        }
+        for var_name in [
+            orchestrator::CPU_COUNT_ENV_VAR,
+            orchestrator::MEMORY_MIB_ENV_VAR,
+            orchestrator::CID_ENV_VAR,
+            orchestrator::MAX_RESTARTS_ENV_VAR,
+            orchestrator::RESTART_BACKOFF_MS_ENV_VAR,
+            orchestrator::HEARTBEAT_INTERVAL_MS_ENV_VAR,
+        ] {
+            let is_invalid = std::env::var(var_name).is_ok()
+                && orchestrator::parse_env_var::<u64>(var_name).is_none();
+            if is_invalid {
+                report.warning(var_name, "is not a number, the default will be used");
+            }
+        }
+    }
+
+    if let Ok(ports) = std::env::var(TCP_PASSTHROUGH_PORTS_ENV) {
+        for port in ports
+            .split(',')
+            .map(str::trim)
+            .filter(|port| !port.is_empty())
+        {
+            if port.parse::<u16>().is_err() {
+                report.warning(
+                    TCP_PASSTHROUGH_PORTS_ENV,
+                    format!("{port} is not a port number, and will be skipped"),
+                );
+            }
+        }
+    }
+
+    let status_interval_var = enclave_status::STATUS_INTERVAL_MS_ENV_VAR;
+    if std::env::var(status_interval_var).is_ok()
+        && orchestrator::parse_env_var::<u64>(status_interval_var).is_none()
+    {
+        report.warning(
+            status_interval_var,
+            "is not a number of milliseconds, the default of 30000 will be used",
+        );
+    }
+
+    #[cfg(feature = "network_egress")]
+    for (var_name, default) in [
+        ("EV_EGRESS_HANDSHAKE_TIMEOUT_MS", 5000),
+        (crate::dnsproxy::RESOLVER_TIMEOUT_KEY, 2000),
+    ] {
+        if let Ok(timeout) = std::env::var(var_name) {
+            if timeout.parse::<u64>().is_err() {
+                report.warning(
+                    var_name,
+                    format!(
+                        "{timeout} is not a number of milliseconds, the default of {default} will be used"
+                    ),
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "network_egress")]
+    {
+        let allow_list = std::env::var("EV_EGRESS_ALLOW_LIST").unwrap_or_default();
+        for entry in shared::server::egress::get_malformed_allow_list_entries(&allow_list) {
+            report.warning(
+                "EV_EGRESS_ALLOW_LIST",
+                format!(
+                    "\"{entry}\" is not a hostname, wildcard or IPv4 address with an optional port, and will never match"
+                ),
+            );
+        }
+    }
+
+    report
+}
+
+/// The control plane's config with defaults resolved, for dry runs. Secrets are only reported as set or unset.
+pub fn effective_config() -> serde_json::Value {
+    let environment = match get_rust_env() {
+        Environment::Development => "development",
+        Environment::Staging => "staging",
+        Environment::Production => "production",
+    };
+    #[cfg(feature = "network_egress")]
+    let egress = serde_json::json!({
+        "allow_list": shared::server::egress::get_egress_allow_list_from_env(),
+        "dns_servers": crate::dnsproxy::read_dns_server_ips_from_env_var()
+            .unwrap_or_else(|| crate::dnsproxy::DNS_SERVERS.clone()),
+        "handshake_timeout_ms": get_egress_handshake_timeout().as_millis() as u64,
+        "dns_resolver_timeout_ms": crate::dnsproxy::get_resolver_timeout().as_millis() as u64,
+    });
+    #[cfg(not(feature = "network_egress"))]
+    let egress = serde_json::Value::Null;
+    serde_json::json!({
+        "environment": environment,
+        "features": {
+            "enclave": cfg!(feature = "enclave"),
+            "network_egress": cfg!(feature = "network_egress"),
+            "mock_provisioner": cfg!(feature = "mock_provisioner"),
+            "io_uring": cfg!(feature = "io_uring"),
+            "local": cfg!(feature = "local"),
+        },
+        "enclave": {
+            "uuid": dry_run::env_value("CAGE_UUID"),
+            "version": dry_run::env_value("EV_CAGE_VERSION_ID"),
+            "name": dry_run::env_value("EV_CAGE_NAME"),
+            "app_uuid": dry_run::env_value("EV_APP_UUID"),
+            "team_uuid": dry_run::env_value("EV_TEAM_UUID"),
+        },
+        "data_plane_version": get_data_plane_version().ok(),
+        "aws": {
+            "profile": get_aws_profile(),
+            "region": get_aws_region().to_string(),
+        },
+        "cert_provisioner": {
+            "host": get_cert_provisoner_host(),
+            "mtls_client_cert": dry_run::redacted_env_value("CERT_PROVISIONER_MTLS_CLIENT_CERT"),
+            "mtls_client_key": dry_run::redacted_env_value("CERT_PROVISIONER_MTLS_CLIENT_KEY"),
+            "mtls_root_cert": dry_run::redacted_env_value("CERT_PROVISIONER_MTLS_ROOT_CERT"),
+        },
+        "acme": {
+            "hosts": get_acme_hosts(),
+            "s3_bucket": dry_run::env_value("ACME_S3_BUCKET"),
+            "account_ec_key": dry_run::redacted_env_value("ACME_ACCOUNT_EC_KEY"),
+            "account_hmac_key": dry_run::redacted_env_value("ACME_ACCOUNT_HMAC_KEY"),
+            "account_hmac_key_id": dry_run::env_value("ACME_ACCOUNT_HMAC_KEY_ID"),
+        },
+        "trusted_cert_base_domains": get_trusted_cert_base_domains(),
+        "custom_domains": get_custom_domains(),
+        "tcp_passthrough_ports": get_tcp_passthrough_ports(),
+        "send_proxy_protocol": get_send_proxy_protocol(),
+        "external_metrics_enabled": get_external_metrics_enabled(),
+        "first_byte_timeout_ms": get_first_byte_timeout().as_millis() as u64,
+        "handshake_trace": shared::handshake_trace::is_enabled(),
+        "runtime": get_runtime_config(),
+        "egress": egress,
+        "enclave_orchestrator": OrchestratorConfig::from_env(),
+        "enclave_status_polling": enclave_status::is_enabled(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{effective_config, validate_config, REQUIRED_ENV_VARS};
+
+    #[test]
+    fn test_validation_reports_every_missing_env_var() {
+        for var_name in REQUIRED_ENV_VARS {
+            std::env::remove_var(var_name);
+        }
+        std::env::set_var("ACME_ACCOUNT_EC_KEY", "not-a-key");
+        std::env::set_var("CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS", "soon");
+
+        let report = validate_config();
+        assert!(report.has_fatal());
+        let fields: Vec<&str> = report
+            .issues()
+            .iter()
+            .map(|issue| issue.field.as_str())
+            .collect();
+        for var_name in REQUIRED_ENV_VARS {
+            assert!(fields.contains(&var_name), "{var_name} not reported");
+        }
+        assert!(fields.contains(&"ACME_ACCOUNT_EC_KEY"));
+        assert!(fields.contains(&"CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS"));
+
+        std::env::remove_var("ACME_ACCOUNT_EC_KEY");
+        std::env::remove_var("CONTROL_PLANE_FIRST_BYTE_TIMEOUT_MS");
+    }
+
+    #[test]
+    fn test_effective_config_redacts_secrets() {
+        std::env::set_var("CERT_PROVISIONER_MTLS_CLIENT_KEY", "mtls-secret");
+        std::env::set_var("DATA_PLANE_VERSION", "1.2.3");
+
+        let config = effective_config();
+        assert_eq!(config["cert_provisioner"]["mtls_client_key"], "<redacted>");
+        assert!(!config.to_string().contains("mtls-secret"));
+        assert_eq!(config["data_plane_version"], "1.2.3");
+
+        std::env::remove_var("CERT_PROVISIONER_MTLS_CLIENT_KEY");
+        std::env::remove_var("DATA_PLANE_VERSION");
+    }
+}
@@ -1,16 +1,25 @@
 use crate::error::{Result, ServerError};
+use crate::socket_activation::{take_listener, DNS_LISTENER};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use shared::buffer_pool::DNS_BUFFER_POOL;
 use shared::server::egress::check_dns_allowed_for_domain;
 use shared::server::egress::{cache_ip_for_allowlist, EgressDestinations};
 use shared::server::CID::Parent;
-use shared::server::{get_vsock_server, Listener};
+use shared::server::{get_vsock_server, get_vsock_server_from_fd, Listener};
 use shared::DNS_PROXY_VSOCK_PORT;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UdpSocket;
 
 const DNS_SERVER_OVERRIDE_KEY: &str = "EV_CONTROL_PLANE_DNS_SERVER";
+pub const RESOLVER_TIMEOUT_KEY: &str = "EV_DNS_RESOLVER_TIMEOUT_MS";
+const DEFAULT_RESOLVER_TIMEOUT_MS: u64 = 2000;
+/// How long a resolver which failed a lookup is tried after the others
+const RESOLVER_DEMOTION_PERIOD: Duration = Duration::from_secs(30);
 
 lazy_static::lazy_static! {
   pub static ref DNS_SERVERS: Vec<IpAddr> = vec![
@@ -23,6 +32,15 @@ lazy_static::lazy_static! {
   ];
 }
 
+/// How long each resolver has to answer a lookup before the next one is tried.
+pub fn get_resolver_timeout() -> Duration {
+    let timeout_ms = std::env::var(RESOLVER_TIMEOUT_KEY)
+        .ok()
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RESOLVER_TIMEOUT_MS);
+    Duration::from_millis(timeout_ms)
+}
+
 pub fn read_dns_server_ips_from_env_var() -> Option<Vec<IpAddr>> {
     std::env::var(DNS_SERVER_OVERRIDE_KEY).ok().map(|env_var| {
         env_var
@@ -32,58 +50,113 @@ pub fn read_dns_server_ips_from_env_var() -> Option<Vec<IpAddr>> {
     })
 }
 
+/// Tracks resolvers which have recently failed lookups, so they're only tried once the healthy resolvers have been.
+#[derive(Default)]
+struct ResolverHealth {
+    demoted_until: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl ResolverHealth {
+    /// The resolvers to try, healthy resolvers in a random order first and then demoted resolvers, soonest to be
+    /// restored first.
+    fn order(&self, resolvers: &[SocketAddr]) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let demoted_until = self
+            .demoted_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (mut demoted, mut healthy): (Vec<_>, Vec<_>) =
+            resolvers.iter().copied().partition(|resolver| {
+                demoted_until
+                    .get(resolver)
+                    .is_some_and(|until| *until > now)
+            });
+        healthy.shuffle(&mut thread_rng());
+        demoted.sort_by_key(|resolver| demoted_until.get(resolver).copied());
+        healthy.extend(demoted);
+        healthy
+    }
+
+    fn record_failure(&self, resolver: SocketAddr) {
+        let mut demoted_until = self
+            .demoted_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let was_demoted = demoted_until.contains_key(&resolver);
+        demoted_until.insert(resolver, Instant::now() + RESOLVER_DEMOTION_PERIOD);
+        if !was_demoted {
+            log::warn!(
+                "Demoting DNS resolver {} for {}s after a failed lookup",
+                resolver.ip(),
+                RESOLVER_DEMOTION_PERIOD.as_secs()
+            );
+        }
+    }
+
+    fn record_success(&self, resolver: SocketAddr) {
+        let mut demoted_until = self
+            .demoted_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if demoted_until.remove(&resolver).is_some() {
+            log::info!("DNS resolver {} has recovered", resolver.ip());
+        }
+    }
+}
+
 pub struct DnsProxy {
-    dns_server_ips: Vec<IpAddr>,
+    resolvers: Vec<SocketAddr>,
+    resolver_timeout: Duration,
+    health: Arc<ResolverHealth>,
 }
 
 impl std::default::Default for DnsProxy {
     fn default() -> Self {
-        Self {
-            dns_server_ips: DNS_SERVERS.clone(),
-        }
+        Self::new(DNS_SERVERS.clone())
     }
 }
 
 impl DnsProxy {
     pub fn new(ips: Vec<IpAddr>) -> Self {
         Self {
-            dns_server_ips: ips,
+            resolvers: ips.into_iter().map(|ip| SocketAddr::new(ip, 53)).collect(),
+            resolver_timeout: get_resolver_timeout(),
+            health: Arc::new(ResolverHealth::default()),
         }
     }
 
     pub async fn listen(self) -> Result<()> {
-        let mut server = get_vsock_server(DNS_PROXY_VSOCK_PORT, Parent).await?;
+        let mut server = match take_listener(DNS_LISTENER) {
+            Some(fd) => get_vsock_server_from_fd(fd)?,
+            None => get_vsock_server(DNS_PROXY_VSOCK_PORT, Parent).await?,
+        };
 
-        let allowed_domains = shared::server::egress::get_egress_allow_list_from_env();
-        let mut rng = thread_rng();
         loop {
-            let domains = allowed_domains.clone();
             match server.accept().await {
                 Ok(mut stream) => {
-                    let mut dns_services = self.dns_server_ips.clone();
-                    dns_services.shuffle(&mut rng);
+                    let domains = crate::egress_policy::ALLOW_LIST.load();
+                    let resolvers = self.health.order(&self.resolvers);
+                    let health = self.health.clone();
+                    let resolver_timeout = self.resolver_timeout;
                     tokio::spawn(async move {
-                        for dns_service in dns_services.iter() {
-                            let dns_req_timing = std::time::Instant::now();
-                            let dns_lookup =
-                                Self::proxy_dns_connection(dns_service, &mut stream, &domains)
-                                    .await;
-                            let elapsed = std::time::Instant::now()
-                                .duration_since(dns_req_timing)
-                                .as_millis();
-                            match dns_lookup {
-                                Ok(_) => {
-                                  log::info!("DNS Resolved successfully after: {elapsed}ms");
-                                  return;
-                                },
-                                Err(ServerError::EgressError(e)) => {
-                                    log::error!("DNS Connection to {dns_service} rejected with egress error: {e}");
-                                    return;
-                                }
-                                Err(e) => log::error!(
-                                    "Error proxying dns connection to {dns_service}: {e}. Elapsed: {elapsed}ms"
-                                ),
+                        let dns_req_timing = std::time::Instant::now();
+                        let dns_lookup = Self::proxy_dns_connection(
+                            &mut stream,
+                            &domains,
+                            &resolvers,
+                            &health,
+                            resolver_timeout,
+                        )
+                        .await;
+                        let elapsed = dns_req_timing.elapsed().as_millis();
+                        match dns_lookup {
+                            Ok(_) => log::info!("DNS Resolved successfully after: {elapsed}ms"),
+                            Err(ServerError::EgressError(e)) => {
+                                log::error!("DNS Connection rejected with egress error: {e}")
                             }
+                            Err(e) => log::error!(
+                                "Error proxying dns connection: {e}. Elapsed: {elapsed}ms"
+                            ),
                         }
                     });
                 }
@@ -94,31 +167,110 @@ impl DnsProxy {
         Ok(())
     }
 
-    async fn remote_dns_socket(dns_server_ip: &IpAddr) -> Result<UdpSocket> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let socket_address = SocketAddr::new(*dns_server_ip, 53);
-        socket.connect(&socket_address).await?;
-        Ok(socket)
-    }
-
     async fn proxy_dns_connection<T: AsyncRead + AsyncWrite + Unpin>(
-        target_ip: &IpAddr,
         stream: &mut T,
         allowed_domains: &EgressDestinations,
+        resolvers: &[SocketAddr],
+        health: &ResolverHealth,
+        resolver_timeout: Duration,
     ) -> Result<()> {
-        log::info!("Proxying request to remote: {target_ip}");
-        let mut request_buffer = [0; 512];
+        let mut request_buffer = DNS_BUFFER_POOL.get();
         let packet_size = stream.read(&mut request_buffer).await?;
+        let request = &request_buffer[..packet_size];
+        check_dns_allowed_for_domain(request, allowed_domains)?;
 
-        let socket = Self::remote_dns_socket(target_ip).await?;
-        let mut response_buffer = [0; 512];
-        check_dns_allowed_for_domain(&request_buffer[..packet_size], allowed_domains)?;
-        socket.send(&request_buffer[..packet_size]).await?;
-        let (amt, _) = socket.recv_from(&mut response_buffer).await?;
-        let response_bytes = &response_buffer[..amt];
-        cache_ip_for_allowlist(response_bytes)?;
-        stream.write_all(response_bytes).await?;
+        let response = Self::resolve(request, resolvers, health, resolver_timeout).await?;
+        cache_ip_for_allowlist(&response)?;
+        stream.write_all(&response).await?;
         stream.flush().await?;
         Ok(())
     }
+
+    /// Send the query to each resolver in turn until one answers, demoting those which fail or time out.
+    async fn resolve(
+        request: &[u8],
+        resolvers: &[SocketAddr],
+        health: &ResolverHealth,
+        resolver_timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let mut last_error = ServerError::InvalidDnsConfig;
+        for resolver in resolvers {
+            log::info!("Proxying request to remote: {}", resolver.ip());
+            let lookup =
+                tokio::time::timeout(resolver_timeout, Self::query_resolver(*resolver, request))
+                    .await
+                    .unwrap_or_else(|_| Err(ServerError::DnsResolverTimeout(resolver.ip())));
+            match lookup {
+                Ok(response) => {
+                    health.record_success(*resolver);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    log::error!("Error proxying dns connection to {}: {e}", resolver.ip());
+                    health.record_failure(*resolver);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn query_resolver(resolver: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&resolver).await?;
+        socket.send(request).await?;
+        let mut response_buffer = DNS_BUFFER_POOL.get();
+        let amt = socket.recv(&mut response_buffer).await?;
+        Ok(response_buffer[..amt].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DnsProxy, ResolverHealth};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    /// A resolver which echoes each query back, or never answers.
+    async fn resolver(answers: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                if answers {
+                    socket.send_to(&buf[..n], src).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_lookups_fail_over_and_demote_unresponsive_resolvers() {
+        let silent = resolver(false).await;
+        let answering = resolver(true).await;
+        let health = ResolverHealth::default();
+
+        let response = DnsProxy::resolve(
+            b"query",
+            &[silent, answering],
+            &health,
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response, b"query");
+        assert_eq!(health.order(&[silent, answering]), vec![answering, silent]);
+
+        // Demoted resolvers are still tried when every other resolver fails
+        let result =
+            DnsProxy::resolve(b"query", &[silent], &health, Duration::from_millis(50)).await;
+        assert!(result.is_err());
+
+        health.record_success(silent);
+        assert_eq!(health.order(&[silent]), vec![silent]);
+        assert!(health.demoted_until.lock().unwrap().is_empty());
+    }
 }
@@ -33,7 +33,7 @@ impl E3Proxy {
     }
 
     #[cfg(not(feature = "enclave"))]
-    async fn shutdown_conn(mut connection: tokio::net::TcpStream) {
+    async fn shutdown_conn<C: tokio::io::AsyncWrite + Unpin>(mut connection: C) {
         if let Err(e) = connection.shutdown().await {
             log::warn!("Failed to shutdown data plane connection — {e:?}");
         }
@@ -0,0 +1,197 @@
+//! Runtime updates to the egress allow list and ports. Updates are made through an admin endpoint bound to the host's
+//! loopback interface, and swap the allow list used by the DNS and egress proxies straight away. The data plane polls
+//! the config server for the latest update, and applies it only where it narrows the enclave's own egress config.
+//!
+//! The admin server also proxies the data plane's egress debug endpoint, which reports the policy the enclave is
+//! actually enforcing along with its DNS cache.
+use crate::error::{Result, ServerError};
+use hyper::{Body, Method, Request, Response};
+use shared::error_code::HasErrorCode;
+use shared::server::config_server::requests::{ConfigServerPayload, EgressPolicyUpdate};
+use shared::server::egress::{
+    get_egress_allow_list_from_env, parse_policy_update, EgressDestinations, LivePolicy,
+};
+use shared::server::health::EGRESS_DEBUG_PATH;
+use shared::server::plane_version::features;
+use shared::server::{tcp::TcpServer, Listener};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONTROL_PLANE_ADMIN_PORT: u16 = 3033;
+const EGRESS_POLICY_PATH: &str = "/egress/policy";
+
+lazy_static::lazy_static! {
+    /// The allow list enforced by the control plane's DNS and egress proxies.
+    pub static ref ALLOW_LIST: LivePolicy<EgressDestinations> =
+        LivePolicy::new(get_egress_allow_list_from_env());
+}
+
+static LATEST_UPDATE: Mutex<Option<EgressPolicyUpdate>> = Mutex::new(None);
+
+/// Validate and apply an update, returning it with its assigned version.
+pub fn apply_update(mut update: EgressPolicyUpdate) -> Result<EgressPolicyUpdate> {
+    let (allow_list, _) = parse_policy_update(&update)?;
+    let mut latest = LATEST_UPDATE.lock().unwrap_or_else(|e| e.into_inner());
+    update.version = next_version(latest.as_ref().map(|latest| latest.version));
+    ALLOW_LIST.store(allow_list);
+    log::info!(
+        "Egress policy updated to version {}: allow list {:?}, ports {:?}",
+        update.version,
+        update.allow_list,
+        update.ports
+    );
+    *latest = Some(update.clone());
+    Ok(update)
+}
+
+/// Versions are millisecond timestamps, bumped if needed to stay above the last one, so they aren't reused after the
+/// control plane restarts and the data plane never mistakes a new update for one it has already applied.
+fn next_version(previous: Option<u64>) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    previous.map_or(now, |previous| now.max(previous + 1))
+}
+
+/// The most recent update, if the policy has been changed since startup.
+pub fn latest_update() -> Option<EgressPolicyUpdate> {
+    LATEST_UPDATE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+pub struct EgressPolicyAdminServer {
+    tcp_server: TcpServer,
+}
+
+impl EgressPolicyAdminServer {
+    pub async fn new() -> shared::server::error::ServerResult<Self> {
+        let tcp_server =
+            TcpServer::bind(SocketAddr::from(([127, 0, 0, 1], CONTROL_PLANE_ADMIN_PORT))).await?;
+        Ok(Self { tcp_server })
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        log::info!("Control plane admin server running on port {CONTROL_PLANE_ADMIN_PORT}");
+        loop {
+            let stream = self.tcp_server.accept().await?;
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(handle_admin_request);
+                if let Err(error) = hyper::server::conn::Http::new()
+                    .http1_only(true)
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    log::error!("Admin server error: {error}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_admin_request(request: Request<Body>) -> Result<Response<Body>> {
+    if request.uri().path() == EGRESS_DEBUG_PATH {
+        if request.method() != Method::GET {
+            return build_response(405, Body::empty());
+        }
+        if !crate::health::data_plane_supports(features::EGRESS_DEBUG) {
+            return build_response(
+                501,
+                Body::from("The data plane doesn't serve egress debug reports"),
+            );
+        }
+        return match crate::health::request_data_plane_health_server(EGRESS_DEBUG_PATH).await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                log::error!("Failed to get egress debug info from the data plane - {e}");
+                Ok(e.to_error_response())
+            }
+        };
+    }
+    if request.uri().path() != EGRESS_POLICY_PATH {
+        return build_response(404, Body::empty());
+    }
+    match *request.method() {
+        Method::GET => match latest_update() {
+            Some(update) => build_response(200, update.into_body()?),
+            None => build_response(404, Body::from("Egress policy has not been updated")),
+        },
+        Method::PUT => {
+            let body = hyper::body::to_bytes(request.into_body()).await?;
+            let update: EgressPolicyUpdate = match serde_json::from_slice(&body) {
+                Ok(update) => update,
+                Err(e) => return build_response(400, Body::from(e.to_string())),
+            };
+            match apply_update(update) {
+                Ok(update) => build_response(200, update.into_body()?),
+                Err(e) => build_response(400, Body::from(e.to_string())),
+            }
+        }
+        _ => build_response(405, Body::empty()),
+    }
+}
+
+fn build_response(status: u16, body: Body) -> Result<Response<Body>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .map_err(ServerError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_admin_request, latest_update, next_version, ALLOW_LIST};
+    use hyper::{Body, Request};
+    use shared::server::config_server::requests::EgressPolicyUpdate;
+
+    fn put(body: &str) -> Request<Body> {
+        Request::put("/egress/policy")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoint_swaps_the_allow_list() {
+        let response = handle_admin_request(put(r#"{ "allow_list": "https://bad" }"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        let response = handle_admin_request(put(
+            r#"{ "allow_list": "*.evervault.com,1.1.1.1", "ports": "443" }"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let applied: EgressPolicyUpdate = serde_json::from_slice(&body).unwrap();
+        assert_eq!(Some(applied.clone()), latest_update());
+
+        let allow_list = ALLOW_LIST.load();
+        assert_eq!(allow_list.wildcard, vec![".evervault.com"]);
+        assert_eq!(allow_list.ips, vec!["1.1.1.1"]);
+        assert!(!allow_list.allow_all);
+
+        let response = handle_admin_request(put(r#"{ "allow_list": "*" }"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(latest_update().unwrap().version > applied.version);
+        assert!(ALLOW_LIST.load().allow_all);
+    }
+
+    #[test]
+    fn test_versions_survive_restarts() {
+        // A restarted control plane has no previous version, but still versions updates after ones made before it
+        let before_restart = next_version(None);
+        assert!(next_version(None) >= before_restart);
+        assert!(before_restart > 1);
+        // Updates within the same millisecond still get distinct versions
+        let future = before_restart + 60_000;
+        assert_eq!(next_version(Some(future)), future + 1);
+    }
+}
@@ -1,12 +1,17 @@
 use crate::error::{Result, ServerError};
+use crate::socket_activation::{take_listener, EGRESS_LISTENER};
+use shared::buffer_pool::STREAM_BUFFER_POOL;
 use shared::rpc::request::ExternalRequest;
-use shared::server::egress::check_ip_allow_list;
+use shared::server::egress::check_egress_destination;
+use shared::server::egress::peek_client_data;
 use shared::server::egress::EgressDestinations;
+use shared::server::sni::get_hostname;
 use shared::server::CID::Parent;
-use shared::server::{get_vsock_server, Listener};
-use shared::utils::pipe_streams;
+use shared::server::{get_vsock_server, get_vsock_server_from_fd, Listener};
+use shared::utils::{pipe_streams, PeekableStream};
 use shared::{env_var_present_and_true, EGRESS_PROXY_VSOCK_PORT};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
@@ -24,7 +29,11 @@ lazy_static! {
 
 impl EgressProxy {
     pub async fn listen() -> Result<()> {
-        let mut server = match get_vsock_server(EGRESS_PROXY_VSOCK_PORT, Parent).await {
+        let server = match take_listener(EGRESS_LISTENER) {
+            Some(fd) => get_vsock_server_from_fd(fd),
+            None => get_vsock_server(EGRESS_PROXY_VSOCK_PORT, Parent).await,
+        };
+        let mut server = match server {
             Ok(server) => server,
             Err(e) => {
                 log::error!("Error starting egress proxy - {e:?}");
@@ -32,14 +41,15 @@ impl EgressProxy {
             }
         };
         log::info!("Egress proxy started");
-        let allowed_domains = shared::server::egress::get_egress_allow_list_from_env();
-
+        let handshake_timeout = crate::configuration::get_egress_handshake_timeout();
         loop {
-            let domains = allowed_domains.clone();
             match server.accept().await {
                 Ok(stream) => {
+                    let domains = crate::egress_policy::ALLOW_LIST.load();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, &domains).await {
+                        if let Err(e) =
+                            Self::handle_connection(stream, &domains, handshake_timeout).await
+                        {
                             log::error!(
                                 "An error occurred while handling an egress connection - {e:?}"
                             );
@@ -58,20 +68,46 @@ impl EgressProxy {
     async fn handle_connection<T: AsyncReadExt + AsyncWriteExt + Unpin>(
         mut external_stream: T,
         egress_destinations: &EgressDestinations,
+        handshake_timeout: Duration,
     ) -> Result<()> {
         log::debug!("Received request to egress proxy");
-        let mut request_buffer = [0; 4096];
-        let packet_size = external_stream.read(&mut request_buffer).await?;
+        let mut request_buffer = STREAM_BUFFER_POOL.get();
+        let packet_size =
+            tokio::time::timeout(handshake_timeout, external_stream.read(&mut request_buffer))
+                .await
+                .map_err(|_| ServerError::HandshakeTimeout(handshake_timeout))??;
         let req = &request_buffer[..packet_size];
-        let external_request = ExternalRequest::from_bytes(req.to_vec())?;
+        let (external_request, client_data) = ExternalRequest::from_bytes_with_remainder(req)?;
+        let mut external_stream =
+            PeekableStream::with_peeked(external_stream, client_data.to_vec());
 
         if let Err(e) = validate_requested_ip(external_request.ip, *ALLOW_EGRESS_TO_INTERNAL_IPS) {
             let _ = external_stream.shutdown().await;
             return Err(e);
         }
 
-        if let Err(err) = check_ip_allow_list(external_request.ip.to_string(), egress_destinations)
-        {
+        // The client's first bytes are only peeked at, so they reach the destination untouched. Data planes which
+        // predate this send them in the request instead.
+        let client_data = if external_request.inspect_client_data {
+            tokio::time::timeout(handshake_timeout, peek_client_data(&mut external_stream))
+                .await
+                .map_err(|_| ServerError::HandshakeTimeout(handshake_timeout))??
+        } else {
+            external_request.data.as_slice()
+        };
+        shared::handshake_trace!(
+            "Egress request to {}:{}, SNI {}",
+            external_request.ip,
+            external_request.port,
+            get_hostname(client_data).unwrap_or("none")
+        );
+
+        if let Err(err) = check_egress_destination(
+            external_request.ip.to_string(),
+            external_request.port,
+            client_data,
+            egress_destinations,
+        ) {
             let _ = external_stream.shutdown().await;
             log::info!("Blocking request to ip: {:?}  - {err}", external_request.ip);
             return Ok(());
@@ -270,4 +306,19 @@ mod test {
             Err(e) => assert!(matches!(e, ServerError::IllegalInternalIp(_))),
         }
     }
+
+    #[tokio::test]
+    async fn egress_connection_without_a_request_times_out() {
+        let (_data_plane, stream) = tokio::io::duplex(64);
+        let destinations = EgressDestinations {
+            exact: vec![],
+            wildcard: vec![],
+            allow_all: true,
+            ips: vec![],
+            ..Default::default()
+        };
+        let result =
+            EgressProxy::handle_connection(stream, &destinations, Duration::from_millis(20)).await;
+        assert!(matches!(result, Err(ServerError::HandshakeTimeout(_))));
+    }
 }
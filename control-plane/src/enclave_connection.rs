@@ -1,18 +1,146 @@
+use lazy_static::lazy_static;
+use shared::server::session_token::{write_session_token, SessionToken};
 #[cfg(feature = "enclave")]
 use shared::ENCLAVE_CID;
-#[cfg(not(feature = "enclave"))]
+use std::sync::RwLock;
+#[cfg(feature = "local")]
+use tokio::io::DuplexStream;
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 use tokio::net::TcpStream;
 #[cfg(feature = "enclave")]
 use tokio_vsock::VsockStream;
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub async fn get_connection_to_enclave(port: u16) -> std::io::Result<TcpStream> {
     let ip_addr = std::net::IpAddr::V4(std::net::Ipv4Addr::new(172, 20, 0, 7));
     log::debug!("Connecting to tcp data plane on ({ip_addr},{port})");
     TcpStream::connect(std::net::SocketAddr::new(ip_addr, port)).await
 }
 
+#[cfg(feature = "local")]
+pub async fn get_connection_to_enclave(port: u16) -> std::io::Result<DuplexStream> {
+    shared::server::get_vsock_client(port, shared::server::CID::Enclave).await
+}
+
 #[cfg(feature = "enclave")]
 pub async fn get_connection_to_enclave(port: u16) -> std::io::Result<VsockStream> {
     VsockStream::connect(ENCLAVE_CID, port.into()).await
 }
+
+lazy_static! {
+    /// Presented on every ingress connection to the enclave, so the data plane only accepts connections opened by
+    /// this control plane. Generated inside the enclave and registered by the data plane through the config server.
+    static ref SESSION_TOKEN: RwLock<Option<SessionToken>> = RwLock::new(None);
+}
+
+pub fn set_session_token(token: SessionToken) {
+    match SESSION_TOKEN.write() {
+        Ok(mut session_token) => *session_token = Some(token),
+        Err(e) => log::error!("Failed to store session token — {e}"),
+    }
+}
+
+/// The token registered by the enclave, failing if the data plane hasn't registered one yet.
+pub fn session_token() -> std::io::Result<SessionToken> {
+    SESSION_TOKEN
+        .read()
+        .ok()
+        .and_then(|session_token| session_token.clone())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Session token not yet registered by the enclave",
+            )
+        })
+}
+
+/// Connect to the enclave and present the session token, for connections carrying client traffic.
+#[cfg(not(any(feature = "enclave", feature = "local")))]
+pub async fn get_authenticated_connection_to_enclave(port: u16) -> std::io::Result<TcpStream> {
+    let token = session_token()?;
+    let mut stream = get_connection_to_enclave(port).await?;
+    write_session_token(&mut stream, &token).await?;
+    Ok(stream)
+}
+
+/// Connect to the enclave and present the session token, for connections carrying client traffic.
+#[cfg(feature = "local")]
+pub async fn get_authenticated_connection_to_enclave(port: u16) -> std::io::Result<DuplexStream> {
+    let token = session_token()?;
+    let mut stream = get_connection_to_enclave(port).await?;
+    write_session_token(&mut stream, &token).await?;
+    Ok(stream)
+}
+
+/// Connect to the enclave and present the session token, for connections carrying client traffic.
+#[cfg(feature = "enclave")]
+pub async fn get_authenticated_connection_to_enclave(port: u16) -> std::io::Result<VsockStream> {
+    let token = session_token()?;
+    let mut stream = get_connection_to_enclave(port).await?;
+    write_session_token(&mut stream, &token).await?;
+    Ok(stream)
+}
+
+/// Wait for a client to send its first bytes before a connection to the enclave is opened for it, so that port
+/// scanners and idle probes don't hold vsock connections or enclave-side accept slots. The bytes are peeked rather
+/// than read, so they are still piped to the enclave. Returns false if the client closed the connection or sent
+/// nothing within the timeout.
+///
+/// Clients on this port always speak first (a TLS ClientHello, or the proxy protocol header from the load
+/// balancer), so deferring the enclave connection never stalls a well-behaved client.
+pub async fn wait_for_first_bytes(
+    stream: &tokio::net::TcpStream,
+    timeout: std::time::Duration,
+) -> std::io::Result<bool> {
+    let mut peek_buffer = [0u8; 1];
+    match tokio::time::timeout(timeout, stream.peek(&mut peek_buffer)).await {
+        Ok(peeked) => Ok(peeked? > 0),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::wait_for_first_bytes;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_first_bytes_are_left_in_the_stream() {
+        let (mut client, mut server) = connected_pair().await;
+        client.write_all(b"hello").await.unwrap();
+        assert!(wait_for_first_bytes(&server, Duration::from_secs(1))
+            .await
+            .unwrap());
+        let mut received = [0u8; 5];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_times_out() {
+        let (_client, server) = connected_pair().await;
+        assert!(!wait_for_first_bytes(&server, Duration::from_millis(20))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_closed_client_is_rejected() {
+        let (client, server) = connected_pair().await;
+        drop(client);
+        assert!(!wait_for_first_bytes(&server, Duration::from_secs(1))
+            .await
+            .unwrap());
+    }
+}
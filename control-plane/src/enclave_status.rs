@@ -0,0 +1,178 @@
+//! Periodically describes the host's enclaves with `nitro-cli describe-enclaves`, so their state and resource
+//! allocation can be reported in the control plane's health checks and published as metrics for fleet dashboards.
+//!
+//! Polling runs whenever the control plane is built for an enclave host, or is starting the enclave itself.
+use crate::error::ServerError;
+use crate::orchestrator::{self, DescribedEnclave};
+use crate::stats_client::StatsClient;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const STATUS_INTERVAL_MS_ENV_VAR: &str = "EV_ENCLAVE_STATUS_INTERVAL_MS";
+const DEFAULT_STATUS_INTERVAL_MS: u64 = 30000;
+/// Shorter intervals would have nitro-cli run back to back, and an interval of 0 would never yield.
+const MIN_STATUS_INTERVAL_MS: u64 = 1000;
+
+static LATEST_STATUS: Mutex<Option<EnclaveStatusReport>> = Mutex::new(None);
+
+/// The enclaves on the host when nitro-cli was last run.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EnclaveStatusReport {
+    pub enclaves: Vec<DescribedEnclave>,
+    /// Seconds since the unix epoch when the enclaves were last described
+    pub checked_at: u64,
+    /// Why the last poll failed. The enclaves from the last successful poll are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl EnclaveStatusReport {
+    fn running(&self) -> impl Iterator<Item = &DescribedEnclave> {
+        self.enclaves.iter().filter(|enclave| enclave.is_running())
+    }
+
+    pub fn running_count(&self) -> u64 {
+        self.running().count() as u64
+    }
+
+    /// CPUs allocated to running enclaves
+    pub fn cpu_count(&self) -> u64 {
+        self.running()
+            .map(|enclave| u64::from(enclave.cpu_count))
+            .sum()
+    }
+
+    /// Memory allocated to running enclaves
+    pub fn memory_mib(&self) -> u64 {
+        self.running().map(|enclave| enclave.memory_mib).sum()
+    }
+}
+
+pub fn is_enabled() -> bool {
+    cfg!(feature = "enclave") || orchestrator::OrchestratorConfig::from_env().is_some()
+}
+
+/// The latest report, or `None` until the enclaves have first been described.
+pub fn latest_status() -> Option<EnclaveStatusReport> {
+    LATEST_STATUS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Describe the enclaves every `EV_ENCLAVE_STATUS_INTERVAL_MS`, for as long as the control plane runs.
+pub async fn poll_enclave_status() {
+    let interval = status_interval(orchestrator::parse_env_var(STATUS_INTERVAL_MS_ENV_VAR));
+    let nitro_cli = orchestrator::nitro_cli_path();
+    loop {
+        let nitro_cli = nitro_cli.clone();
+        let described =
+            tokio::task::spawn_blocking(move || orchestrator::describe_enclaves(&nitro_cli))
+                .await
+                .unwrap_or_else(|e| Err(ServerError::EnclaveStart(e.to_string())));
+        let report = update_status(described);
+        if let Some(e) = &report.error {
+            log::warn!("Failed to describe the host's enclaves - {e}");
+        }
+        StatsClient::record_enclave_resources(&report);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The configured interval, raised to the minimum if it's shorter.
+fn status_interval(configured_ms: Option<u64>) -> Duration {
+    let interval_ms = configured_ms.unwrap_or(DEFAULT_STATUS_INTERVAL_MS);
+    if interval_ms < MIN_STATUS_INTERVAL_MS {
+        log::warn!(
+            "{STATUS_INTERVAL_MS_ENV_VAR} of {interval_ms} is below the minimum, using {MIN_STATUS_INTERVAL_MS}"
+        );
+        return Duration::from_millis(MIN_STATUS_INTERVAL_MS);
+    }
+    Duration::from_millis(interval_ms)
+}
+
+fn update_status(described: crate::error::Result<Vec<DescribedEnclave>>) -> EnclaveStatusReport {
+    let checked_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let mut latest = LATEST_STATUS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let report = match described {
+        Ok(enclaves) => EnclaveStatusReport {
+            enclaves,
+            checked_at,
+            error: None,
+        },
+        Err(e) => EnclaveStatusReport {
+            enclaves: latest
+                .take()
+                .map(|report| report.enclaves)
+                .unwrap_or_default(),
+            checked_at,
+            error: Some(e.to_string()),
+        },
+    };
+    *latest = Some(report.clone());
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DESCRIBE_ENCLAVES_OUTPUT: &str = r#"[
+      {
+        "EnclaveName": "cage",
+        "EnclaveID": "i-0123456789abcdef0-enc0123456789abcdef",
+        "ProcessID": 12345,
+        "EnclaveCID": 16,
+        "NumberOfCPUs": 2,
+        "CPUIDs": [1, 3],
+        "MemoryMiB": 2048,
+        "State": "RUNNING",
+        "Flags": "NONE"
+      },
+      {
+        "EnclaveID": "i-0123456789abcdef0-enc0fedcba987654321",
+        "NumberOfCPUs": 4,
+        "CPUIDs": [5, 7, 9, 11],
+        "MemoryMiB": 4096,
+        "State": "TERMINATING"
+      }
+    ]"#;
+
+    #[test]
+    fn test_enclave_resources_are_reported_for_running_enclaves() {
+        let enclaves: Vec<DescribedEnclave> =
+            serde_json::from_str(DESCRIBE_ENCLAVES_OUTPUT).unwrap();
+        assert_eq!(enclaves[0].enclave_name.as_deref(), Some("cage"));
+        assert_eq!(enclaves[0].enclave_cid, Some(16));
+        assert_eq!(enclaves[0].cpu_ids, vec![1, 3]);
+        assert_eq!(enclaves[1].enclave_name, None);
+
+        let report = update_status(Ok(enclaves.clone()));
+        assert_eq!(report.running_count(), 1);
+        assert_eq!(report.cpu_count(), 2);
+        assert_eq!(report.memory_mib(), 2048);
+
+        // Failed polls keep the last known enclaves
+        let report = update_status(Err(ServerError::EnclaveStart("no nitro-cli".into())));
+        assert_eq!(report.enclaves, enclaves);
+        assert!(report.error.is_some());
+        assert_eq!(latest_status(), Some(report.clone()));
+
+        let reported: EnclaveStatusReport =
+            serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert_eq!(reported, report);
+    }
+
+    #[test]
+    fn test_status_interval_has_a_minimum() {
+        assert_eq!(status_interval(None), Duration::from_millis(30000));
+        assert_eq!(status_interval(Some(5000)), Duration::from_millis(5000));
+        assert_eq!(status_interval(Some(0)), Duration::from_millis(1000));
+    }
+}
@@ -1,3 +1,4 @@
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use thiserror::Error;
 use trust_dns_resolver::error::ResolveError;
 
@@ -36,8 +37,43 @@ pub enum ServerError {
     StorageClientError(#[from] StorageClientError),
     #[error("Acme Error - {0}")]
     AcmeError(#[from] shared::acme::error::AcmeError),
+    #[cfg(feature = "mock_provisioner")]
+    #[error("Failed to generate mock provisioner credentials — {0}")]
+    MockProvisioner(#[from] openssl::error::ErrorStack),
     #[error("Invalid DNS Config provided - at least 2 valid DNS Servers must be provided")]
     InvalidDnsConfig,
+    #[error("DNS resolver {0} didn't respond in time")]
+    DnsResolverTimeout(std::net::IpAddr),
+    #[error("Failed to start the enclave — {0}")]
+    EnclaveStart(String),
+    #[error("Egress request wasn't sent within {0:?}")]
+    HandshakeTimeout(std::time::Duration),
+}
+
+impl HasErrorCode for ServerError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::Io(_) | Self::Hyper(_) | Self::HyperHttp(_) => codes::CONNECTION_FAILED,
+            Self::Rpc(e) => e.error_code(),
+            Self::Server(e) => e.error_code(),
+            Self::DNSError(_) => codes::DNS_LOOKUP_FAILED,
+            Self::DnsResolverTimeout(_) => codes::DNS_TIMEOUT,
+            Self::IllegalInternalIp(_) => codes::EGRESS_BLOCKED,
+            Self::InvalidIp(_) => codes::BAD_REQUEST,
+            Self::JsonError(_) => codes::INVALID_PAYLOAD,
+            Self::FailedRequest(_) | Self::StorageClientError(_) | Self::AcmeError(_) => {
+                codes::UPSTREAM_FAILED
+            }
+            Self::CertProvisionerMtls(_) | Self::InvalidDnsConfig => codes::CONFIG_INVALID,
+            Self::EnvError(_) => codes::CONFIG_MISSING,
+            #[cfg(feature = "network_egress")]
+            Self::EgressError(e) => e.error_code(),
+            #[cfg(feature = "mock_provisioner")]
+            Self::MockProvisioner(_) => codes::INTERNAL,
+            Self::EnclaveStart(_) => codes::INTERNAL,
+            Self::HandshakeTimeout(_) => codes::TIMEOUT,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ServerError>;
@@ -1,20 +1,36 @@
 use crate::enclave_connection::get_connection_to_enclave;
+use crate::enclave_status::{self, EnclaveStatusReport};
 use crate::error::ServerError;
 use axum::http::HeaderValue;
 use hyper::{Body, Request, Response};
 use serde::{Deserialize, Serialize};
+use shared::server::plane_version::{features, PlaneVersion, VERSION_PATH};
 use shared::server::{
     error::ServerResult,
-    health::{ControlPlaneState, DataPlaneState, HealthCheck, HealthCheckLog, HealthCheckVersion},
+    health::{
+        ControlPlaneState, DataPlaneState, HealthCheck, HealthCheckLog, HealthCheckVersion,
+        HealthProbe, LivenessReport, ReadinessCheck, ReadinessReport, READINESS_PATH,
+    },
     tcp::TcpServer,
     Listener,
 };
 use shared::ENCLAVE_HEALTH_CHECK_PORT;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
 
 pub static IS_DRAINING: OnceLock<bool> = OnceLock::new();
 
+/// Whether the data plane last reported being ready. Ingress is only accepted while it is.
+static DATA_PLANE_READY: AtomicBool = AtomicBool::new(false);
+/// Whether the data plane's version has been negotiated since it last became unready.
+static DATA_PLANE_NEGOTIATED: AtomicBool = AtomicBool::new(false);
+/// The data plane's negotiated version, or None if it predates negotiation or hasn't been negotiated yet.
+static DATA_PLANE_VERSION: RwLock<Option<PlaneVersion>> = RwLock::new(None);
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const UNREADY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub const CONTROL_PLANE_HEALTH_CHECK_PORT: u16 = 3032;
 
 pub struct HealthCheckServer {
@@ -26,6 +42,125 @@ pub struct HealthCheckServer {
 struct CombinedHealthCheckLog {
     control_plane: ControlPlaneState,
     data_plane: HealthCheckVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enclave: Option<EnclaveStatusReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CombinedReadinessReport {
+    control_plane: ReadinessReport,
+    data_plane: ReadinessReport,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enclave: Option<EnclaveStatusReport>,
+}
+
+pub fn is_data_plane_ready() -> bool {
+    DATA_PLANE_READY.load(Ordering::Acquire)
+}
+
+/// Whether the data plane has advertised a protocol feature. Until versions are negotiated, and for data planes which
+/// predate negotiation, no features are assumed.
+pub fn data_plane_supports(feature: &str) -> bool {
+    DATA_PLANE_VERSION
+        .read()
+        .map(|version| {
+            version
+                .as_ref()
+                .is_some_and(|data_plane| data_plane.supports(feature))
+        })
+        .unwrap_or(false)
+}
+
+/// Poll the data plane's readiness for as long as the control plane runs, more often while it isn't ready so
+/// ingress is accepted soon after it becomes ready.
+pub async fn watch_data_plane_readiness() {
+    loop {
+        // Negotiate before checking readiness, as the readiness probe depends on the data plane's features
+        if !DATA_PLANE_NEGOTIATED.load(Ordering::Acquire) {
+            negotiate_data_plane_version().await;
+        }
+        let report = get_data_plane_readiness().await;
+        let was_ready = DATA_PLANE_READY.swap(report.ready, Ordering::AcqRel);
+        if report.ready != was_ready {
+            if report.ready {
+                log::info!("Data plane is ready, accepting ingress traffic");
+            } else {
+                log::warn!("Data plane is not ready, rejecting ingress traffic - {report:?}");
+                // The enclave may be restarted with a different version
+                DATA_PLANE_NEGOTIATED.store(false, Ordering::Release);
+            }
+        }
+        let interval = if report.ready {
+            READY_POLL_INTERVAL
+        } else {
+            UNREADY_POLL_INTERVAL
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The control plane is ready while it isn't draining, the enclave has booted if the control plane started it, and the
+/// data plane is ready.
+pub async fn run_readiness_check_service(
+    is_draining: bool,
+) -> std::result::Result<Response<Body>, ServerError> {
+    let draining = if is_draining {
+        ReadinessCheck::not_ready("draining", "Enclave is draining")
+    } else {
+        ReadinessCheck::ready("draining")
+    };
+    let mut checks = vec![draining];
+    checks.extend(crate::orchestrator::readiness_check());
+    let control_plane = ReadinessReport::new(checks);
+    let data_plane = get_data_plane_readiness().await;
+    let status = std::cmp::max(control_plane.status_code(), data_plane.status_code());
+
+    let combined_report = CombinedReadinessReport {
+        control_plane,
+        data_plane,
+        enclave: enclave_status::latest_status(),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&combined_report)?))
+        .map_err(ServerError::from)
+}
+
+async fn get_data_plane_readiness() -> ReadinessReport {
+    if !data_plane_supports(features::READINESS_PROBE) {
+        return get_legacy_data_plane_readiness().await;
+    }
+    match send_data_plane_health_check(READINESS_PATH).await {
+        Ok((_, bytes)) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            ReadinessReport::new(vec![ReadinessCheck::not_ready(
+                "data_plane",
+                format!("Invalid readiness response from data-plane: {e}"),
+            )])
+        }),
+        Err(e) => ReadinessReport::new(vec![ReadinessCheck::not_ready(
+            "data_plane",
+            format!("Failed to contact data-plane for readiness check: {e}"),
+        )]),
+    }
+}
+
+/// Data planes without readiness probes are ready while their health check passes.
+async fn get_legacy_data_plane_readiness() -> ReadinessReport {
+    match health_check_data_plane().await {
+        Ok(hc) if hc.status_code() == 200 => {
+            ReadinessReport::new(vec![ReadinessCheck::ready("health_check")])
+        }
+        Ok(hc) => ReadinessReport::new(vec![ReadinessCheck::not_ready(
+            "health_check",
+            format!("{hc:?}"),
+        )]),
+        Err(e) => ReadinessReport::new(vec![ReadinessCheck::not_ready(
+            "data_plane",
+            format!("Failed to contact data-plane for readiness check: {e}"),
+        )]),
+    }
 }
 
 pub async fn run_ecs_health_check_service(
@@ -38,6 +173,7 @@ pub async fn run_ecs_health_check_service(
                 "Enclave is draining, data-plane health will not be checked".into(),
             )
             .into(),
+            enclave: enclave_status::latest_status(),
         };
 
         let combined_log_json = serde_json::to_string(&combined_log)?;
@@ -58,8 +194,9 @@ pub async fn run_ecs_health_check_service(
     let combined_log = CombinedHealthCheckLog {
         control_plane,
         data_plane,
+        enclave: enclave_status::latest_status(),
     };
-    let combined_log_json = serde_json::to_string(&combined_log).unwrap();
+    let combined_log_json = serde_json::to_string(&combined_log)?;
 
     Response::builder()
         .status(status_to_return)
@@ -69,6 +206,14 @@ pub async fn run_ecs_health_check_service(
 }
 
 async fn health_check_data_plane() -> Result<HealthCheckVersion, ServerError> {
+    let (content_type, bytes) = send_data_plane_health_check("/").await?;
+    parse_health_check(content_type.as_deref(), &bytes)
+}
+
+/// Send a GET request to the data plane's health check server.
+pub(crate) async fn request_data_plane_health_server(
+    path: &str,
+) -> Result<Response<Body>, ServerError> {
     let stream = get_connection_to_enclave(ENCLAVE_HEALTH_CHECK_PORT).await?;
 
     let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
@@ -76,21 +221,60 @@ async fn health_check_data_plane() -> Result<HealthCheckVersion, ServerError> {
     tokio::spawn(connection);
     let request = Request::builder()
         .method("GET")
+        .uri(path)
         .header("User-Agent", "CageHealthChecker/0.0")
-        .body(Body::empty())
-        .expect("Cannot fail");
+        .body(Body::empty())?;
+
+    Ok(sender.send_request(request).await?)
+}
+
+/// Check the data plane's version against the control plane's, logging any mismatches, and keep it so requests to the
+/// data plane can be gated on its features.
+async fn negotiate_data_plane_version() {
+    let data_plane = match send_data_plane_health_check(VERSION_PATH).await {
+        Ok((_, bytes)) => serde_json::from_slice::<PlaneVersion>(&bytes),
+        Err(e) => return log::error!("Failed to get the data plane's version - {e}"),
+    };
+    let data_plane = match data_plane {
+        Ok(data_plane) => {
+            let control_plane = PlaneVersion::current(env!("CARGO_PKG_VERSION"));
+            control_plane.log_mismatches("data plane", &data_plane);
+            Some(data_plane)
+        }
+        // Data planes without version negotiation ignore the path, and return their health check
+        Err(_) => {
+            log::warn!("Data plane predates version negotiation");
+            None
+        }
+    };
+    if let Ok(mut version) = DATA_PLANE_VERSION.write() {
+        *version = data_plane;
+    }
+    DATA_PLANE_NEGOTIATED.store(true, Ordering::Release);
+}
 
-    let response = sender.send_request(request).await?;
+/// Send a request to the data plane's health check server, returning the response's content type and body.
+async fn send_data_plane_health_check(
+    path: &str,
+) -> Result<(Option<String>, hyper::body::Bytes), ServerError> {
+    let response = request_data_plane_health_server(path).await?;
     let (parts, response) = response.into_parts();
 
     let content_type = parts
         .headers
         .get("Content-Type")
         .map(HeaderValue::to_str)
-        .and_then(Result::ok);
+        .and_then(Result::ok)
+        .map(str::to_string);
 
-    let bytes = &hyper::body::to_bytes(response).await?;
+    let bytes = hyper::body::to_bytes(response).await?;
+    Ok((content_type, bytes))
+}
 
+fn parse_health_check(
+    content_type: Option<&str>,
+    bytes: &[u8],
+) -> Result<HealthCheckVersion, ServerError> {
     let hc = match content_type {
         Some("application/json;version=1") => {
             HealthCheckVersion::V1(serde_json::from_slice::<DataPlaneState>(bytes)?)
@@ -119,6 +303,21 @@ impl HealthCheckServer {
         loop {
             let stream = self.tcp_server.accept().await?;
             let service = hyper::service::service_fn(move |request: Request<Body>| async move {
+                match HealthProbe::from_path(request.uri().path()) {
+                    Some(HealthProbe::Liveness) => {
+                        let report = LivenessReport { alive: true };
+                        return Response::builder()
+                            .status(report.status_code())
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(serde_json::to_string(&report)?))
+                            .map_err(ServerError::from);
+                    }
+                    Some(HealthProbe::Readiness) => {
+                        let is_draining = IS_DRAINING.get().is_some();
+                        return run_readiness_check_service(is_draining).await;
+                    }
+                    None => {}
+                }
                 match request
                     .headers()
                     .get("User-Agent")
@@ -171,6 +370,18 @@ mod health_check_tests {
         assert!(matches!(dp_state, DataPlaneState::Error(_)));
     }
 
+    #[tokio::test]
+    async fn test_readiness_check_service_without_data_plane() {
+        let response = run_readiness_check_service(false).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let response_body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: CombinedReadinessReport = serde_json::from_slice(&response_body).unwrap();
+
+        assert!(report.control_plane.ready);
+        assert!(!report.data_plane.ready);
+        assert_eq!(report.data_plane.checks[0].name, "data_plane");
+    }
+
     #[tokio::test]
     async fn test_enclave_health_check_service_with_draining_set_to_true() {
         // the data-plane status should error, as its not running
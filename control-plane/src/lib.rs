@@ -7,13 +7,24 @@ pub mod dns;
 pub mod dnsproxy;
 pub mod e3proxy;
 #[cfg(feature = "network_egress")]
+pub mod egress_policy;
+#[cfg(feature = "network_egress")]
 pub mod egressproxy;
 pub mod enclave_connection;
+pub mod enclave_status;
 pub mod error;
 pub mod health;
+#[cfg(feature = "mock_provisioner")]
+pub mod mock_provisioner;
+pub mod orchestrator;
+pub mod socket_activation;
+pub mod startup;
 pub mod stats_client;
 pub mod stats_proxy;
+pub mod tcp_passthrough;
 pub mod tls_proxy;
+#[cfg(feature = "io_uring")]
+pub mod uring_proxy;
 
 #[cfg(test)]
 pub mod mocks;
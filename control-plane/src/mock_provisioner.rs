@@ -0,0 +1,415 @@
+//! A local stand-in for the cert provisioner, so the boot and provisioning flow can be run without access to it.
+//!
+//! A root CA is generated on startup, and used to issue the mock's server certificate, the control plane's mTLS
+//! client certificate and the intermediate CA handed to the data plane. Tokens are issued to the control plane on
+//! the mTLS port as usual, and the data plane must present the cert token to fetch its intermediate CA and secrets.
+//!
+//! Secrets are read from the JSON file at `MOCK_PROVISIONER_SECRETS_PATH` if it's set, otherwise a fixed set of
+//! fixture secrets is returned.
+#[cfg(feature = "enclave")]
+compile_error!("The mock_provisioner feature can't be used in an enclave");
+
+use crate::configuration::EnclaveContext;
+use crate::error::{Result, ServerError};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use openssl::x509::{X509NameBuilder, X509};
+use serde_json::json;
+use shared::server::config_server::requests::{GetCertRequestDataPlane, Secret};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+const TOKEN_PORT: u16 = 3443;
+const CERT_PORT: u16 = 3000;
+const CERT_VALIDITY_DAYS: u32 = 365;
+
+/// The routes served on each of the provisioner's ports
+#[derive(Clone, Copy)]
+enum ProvisionerApi {
+    /// mTLS authenticated token routes, used by the control plane
+    Token,
+    /// Intermediate CA and secret routes, used by the data plane
+    Cert,
+}
+
+struct IssuedCert {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl IssuedCert {
+    fn cert_chain(&self) -> Result<Vec<Certificate>> {
+        Ok(vec![Certificate(self.cert.to_der()?)])
+    }
+
+    fn private_key(&self) -> Result<PrivateKey> {
+        Ok(PrivateKey(self.key.private_key_to_pkcs8()?))
+    }
+}
+
+fn generate_key() -> std::result::Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Issue a certificate for a new key, self-signed when no issuer is given.
+fn issue_cert(
+    common_name: &str,
+    issuer: Option<&IssuedCert>,
+    is_ca: bool,
+) -> std::result::Result<IssuedCert, ErrorStack> {
+    let key = generate_key()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(issuer.map_or(&name, |issuer| issuer.cert.subject_name()))?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(CERT_VALIDITY_DAYS)?)?;
+    if is_ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()?,
+        )?;
+    } else {
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(
+            ExtendedKeyUsage::new()
+                .server_auth()
+                .client_auth()
+                .build()?,
+        )?;
+        let san = SubjectAlternativeName::new()
+            .dns(common_name)
+            .build(&builder.x509v3_context(issuer.map(|issuer| &*issuer.cert), None))?;
+        builder.append_extension(san)?;
+    }
+    builder.sign(
+        issuer.map_or(&key, |issuer| &issuer.key),
+        MessageDigest::sha256(),
+    )?;
+    Ok(IssuedCert {
+        cert: builder.build(),
+        key,
+    })
+}
+
+fn default_secrets() -> Vec<Secret> {
+    [("ANOTHER_ENV_VAR", "123"), ("ENCRYPTED_ENV", "ev:123")]
+        .into_iter()
+        .map(|(name, secret)| Secret {
+            name: name.to_string(),
+            secret: secret.to_string().into(),
+        })
+        .collect()
+}
+
+fn read_secrets() -> Result<Vec<Secret>> {
+    match std::env::var("MOCK_PROVISIONER_SECRETS_PATH") {
+        Ok(path) => Ok(serde_json::from_slice(&std::fs::read(path)?)?),
+        Err(_) => Ok(default_secrets()),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Infallible")
+}
+
+pub struct MockProvisioner {
+    root: IssuedCert,
+    server: IssuedCert,
+    client: IssuedCert,
+    intermediate: IssuedCert,
+    cert_token: String,
+    e3_token: String,
+    secrets: Vec<Secret>,
+    context: EnclaveContext,
+}
+
+impl MockProvisioner {
+    pub fn generate(hostname: &str) -> Result<Self> {
+        let root = issue_cert("Mock Provisioner Root CA", None, true)?;
+        let server = issue_cert(hostname, Some(&root), false)?;
+        let client = issue_cert("control-plane", Some(&root), false)?;
+        let intermediate = issue_cert("Mock Provisioner Intermediate CA", Some(&root), true)?;
+        Ok(Self {
+            root,
+            server,
+            client,
+            intermediate,
+            cert_token: hex_token(),
+            e3_token: hex_token(),
+            secrets: read_secrets()?,
+            context: EnclaveContext::from_env_vars(),
+        })
+    }
+
+    /// The root certificate and client key pair the control plane should use to reach this provisioner.
+    pub fn mtls_certs(&self) -> Result<crate::clients::mtls_config::CertProvisionerMtlsCerts> {
+        Ok(crate::clients::mtls_config::CertProvisionerMtlsCerts::new(
+            Certificate(self.root.cert.to_der()?),
+            (self.client.cert_chain()?, self.client.private_key()?),
+        ))
+    }
+
+    fn tls_config(&self, api: ProvisionerApi) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match api {
+            ProvisionerApi::Token => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(&Certificate(self.root.cert.to_der()?))
+                    .map_err(|e| ServerError::CertProvisionerMtls(e.to_string()))?;
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            ProvisionerApi::Cert => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(self.server.cert_chain()?, self.server.private_key()?)
+            .map_err(|e| ServerError::CertProvisionerMtls(e.to_string()))?;
+        Ok(Arc::new(config))
+    }
+
+    pub async fn listen(self: Arc<Self>) -> Result<()> {
+        tokio::try_join!(
+            self.clone().serve(TOKEN_PORT, ProvisionerApi::Token),
+            self.clone().serve(CERT_PORT, ProvisionerApi::Cert),
+        )?;
+        Ok(())
+    }
+
+    async fn serve(self: Arc<Self>, port: u16, api: ProvisionerApi) -> Result<()> {
+        let acceptor = TlsAcceptor::from(self.tls_config(api)?);
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))).await?;
+        log::info!("Mock provisioner listening on {port}");
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let acceptor = acceptor.clone();
+            let provisioner = self.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::error!("Mock provisioner TLS handshake failed — {e}");
+                        return;
+                    }
+                };
+                let service = service_fn(move |req: Request<Body>| {
+                    let provisioner = provisioner.clone();
+                    async move { Ok::<_, Infallible>(provisioner.handle_request(req, api).await) }
+                });
+                if let Err(e) = hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    log::error!("Error serving mock provisioner request — {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_request(&self, req: Request<Body>, api: ProvisionerApi) -> Response<Body> {
+        log::info!(
+            "Mock provisioner received request: {} {}",
+            req.method(),
+            req.uri().path()
+        );
+        match (api, req.method(), req.uri().path()) {
+            (ProvisionerApi::Token, &Method::GET, "/cert/token") => {
+                json_response(StatusCode::OK, json!({ "token": self.cert_token }))
+            }
+            (ProvisionerApi::Token, &Method::GET, "/e3/token") => json_response(
+                StatusCode::OK,
+                json!({ "token": self.e3_token, "token_id": "mock-e3-token" }),
+            ),
+            (ProvisionerApi::Cert, &Method::POST, "/cert") => {
+                self.authorize(req, |provisioner| provisioner.cert_response())
+                    .await
+            }
+            (ProvisionerApi::Cert, &Method::POST, "/secrets") => {
+                self.authorize(req, |provisioner| provisioner.secrets_response())
+                    .await
+            }
+            (ProvisionerApi::Cert, &Method::POST, "/attest") => {
+                self.authorize(req, |_| Ok(json!({}))).await
+            }
+            _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+        }
+    }
+
+    /// Outside an enclave, the data plane's attestation document is just the token it was given.
+    async fn authorize<F>(&self, req: Request<Body>, respond: F) -> Response<Body>
+    where
+        F: FnOnce(&Self) -> Result<serde_json::Value>,
+    {
+        let request: Result<GetCertRequestDataPlane> = async {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            Ok(serde_json::from_slice(&body)?)
+        }
+        .await;
+        let attested_token = request
+            .ok()
+            .and_then(|request| base64::decode(request.attestation_doc()).ok());
+        if attested_token.as_deref() != Some(self.cert_token.as_bytes()) {
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                json!({ "error": "Invalid cert token" }),
+            );
+        }
+        match respond(self) {
+            Ok(body) => json_response(StatusCode::OK, body),
+            Err(e) => json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": e.to_string() }),
+            ),
+        }
+    }
+
+    fn provisioner_context(&self) -> serde_json::Value {
+        json!({
+            "cage_uuid": self.context.uuid,
+            "cage_name": self.context.name,
+            "team_uuid": self.context.team_uuid,
+            "app_uuid": self.context.app_uuid,
+        })
+    }
+
+    fn cert_response(&self) -> Result<serde_json::Value> {
+        Ok(json!({
+            "intermediate_cert": base64::encode(self.intermediate.cert.to_pem()?),
+            "key_pair": base64::encode(self.intermediate.key.private_key_to_pem_pkcs8()?),
+            "secrets": self.secrets,
+            "context": self.provisioner_context(),
+        }))
+    }
+
+    fn secrets_response(&self) -> Result<serde_json::Value> {
+        Ok(json!({
+            "secrets": self.secrets,
+            "context": self.provisioner_context(),
+        }))
+    }
+}
+
+fn hex_token() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{issue_cert, MockProvisioner, ProvisionerApi};
+    use crate::configuration::EnclaveContext;
+    use hyper::{Body, Request, StatusCode};
+    use shared::server::config_server::requests::GetCertResponseDataPlane;
+
+    fn provisioner() -> MockProvisioner {
+        let root = issue_cert("Mock Provisioner Root CA", None, true).unwrap();
+        MockProvisioner {
+            server: issue_cert("localhost", Some(&root), false).unwrap(),
+            client: issue_cert("control-plane", Some(&root), false).unwrap(),
+            intermediate: issue_cert("Mock Provisioner Intermediate CA", Some(&root), true)
+                .unwrap(),
+            root,
+            cert_token: super::hex_token(),
+            e3_token: super::hex_token(),
+            secrets: super::default_secrets(),
+            context: EnclaveContext::new(
+                "enclave_123".to_string(),
+                "1".to_string(),
+                "test-enclave".to_string(),
+                "app_12345678".to_string(),
+                "team_123".to_string(),
+            ),
+        }
+    }
+
+    fn cert_request(token: &str) -> Request<Body> {
+        let body = serde_json::json!({ "attestation_doc": base64::encode(token) });
+        Request::post("/cert")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_intermediate_ca_is_issued_for_the_cert_token() {
+        let provisioner = provisioner();
+        let token_response = provisioner
+            .handle_request(
+                Request::get("/cert/token").body(Body::empty()).unwrap(),
+                ProvisionerApi::Token,
+            )
+            .await;
+        let token_body = hyper::body::to_bytes(token_response.into_body())
+            .await
+            .unwrap();
+        let token: serde_json::Value = serde_json::from_slice(&token_body).unwrap();
+        let token = token["token"].as_str().unwrap();
+
+        let response = provisioner
+            .handle_request(cert_request(token), ProvisionerApi::Cert)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let cert_response: GetCertResponseDataPlane = serde_json::from_slice(&body).unwrap();
+        assert_eq!(cert_response.context.cage_name, "test-enclave");
+        assert_eq!(cert_response.secrets.as_ref().unwrap().len(), 2);
+
+        let intermediate =
+            openssl::x509::X509::from_pem(&base64::decode(cert_response.cert()).unwrap()).unwrap();
+        let root_key = provisioner.root.cert.public_key().unwrap();
+        assert!(intermediate.verify(&root_key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tokens_and_routes_are_rejected() {
+        let provisioner = provisioner();
+        let response = provisioner
+            .handle_request(cert_request("not-the-token"), ProvisionerApi::Cert)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Tokens are only issued over mTLS
+        let response = provisioner
+            .handle_request(
+                Request::get("/cert/token").body(Body::empty()).unwrap(),
+                ProvisionerApi::Cert,
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(provisioner.mtls_certs().is_ok());
+        assert!(provisioner.tls_config(ProvisionerApi::Token).is_ok());
+    }
+}
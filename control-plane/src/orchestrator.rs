@@ -0,0 +1,554 @@
+//! Starts the enclave from the control plane with `nitro-cli`, rather than leaving it to the host's init scripts.
+//! Orchestration is optional, and is enabled by setting `EV_ENCLAVE_EIF_PATH`. Enclaves run in debug mode have their
+//! console captured into the control plane's logs, and the control plane only reports ready once the enclave has
+//! booted.
+//!
+//! Once started, the enclave is checked with `nitro-cli describe-enclaves` on every heartbeat. If it has died it's
+//! restarted with exponential backoff, until it fails to stay up for `EV_ENCLAVE_MAX_RESTARTS` restarts in a row.
+use crate::error::{Result, ServerError};
+use crate::stats_client::StatsClient;
+use serde::{Deserialize, Serialize};
+use shared::server::health::ReadinessCheck;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const EIF_PATH_ENV_VAR: &str = "EV_ENCLAVE_EIF_PATH";
+pub const CPU_COUNT_ENV_VAR: &str = "EV_ENCLAVE_CPU_COUNT";
+pub const MEMORY_MIB_ENV_VAR: &str = "EV_ENCLAVE_MEMORY_MIB";
+pub const CID_ENV_VAR: &str = "EV_ENCLAVE_CID";
+const DEBUG_MODE_ENV_VAR: &str = "EV_ENCLAVE_DEBUG_MODE";
+const NITRO_CLI_ENV_VAR: &str = "EV_NITRO_CLI_PATH";
+pub const MAX_RESTARTS_ENV_VAR: &str = "EV_ENCLAVE_MAX_RESTARTS";
+pub const RESTART_BACKOFF_MS_ENV_VAR: &str = "EV_ENCLAVE_RESTART_BACKOFF_MS";
+pub const HEARTBEAT_INTERVAL_MS_ENV_VAR: &str = "EV_ENCLAVE_HEARTBEAT_INTERVAL_MS";
+
+const DEFAULT_CPU_COUNT: u32 = 2;
+const DEFAULT_MEMORY_MIB: u32 = 2048;
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+const DEFAULT_RESTART_BACKOFF_MS: u64 = 5000;
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 10_000;
+/// Restart backoff doubles up to this
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+/// An enclave which stays up this long is considered healthy, and resets the restart count
+const STABLE_UPTIME: Duration = Duration::from_secs(600);
+/// Log target for lines read from the enclave's console, so they can be filtered separately
+const CONSOLE_LOG_TARGET: &str = "enclave_console";
+
+/// `None` unless the control plane is orchestrating the enclave.
+static BOOT_STATE: Mutex<Option<EnclaveBootState>> = Mutex::new(None);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnclaveBootState {
+    Starting,
+    Running {
+        enclave_id: String,
+        enclave_cid: u64,
+    },
+    /// Waiting to restart the enclave after it died or failed to start
+    Restarting {
+        attempt: u32,
+        reason: String,
+    },
+    Failed(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct OrchestratorConfig {
+    pub eif_path: String,
+    pub cpu_count: u32,
+    pub memory_mib: u32,
+    /// Must match the CID the control plane dials, so it defaults to the one the planes are built with
+    pub enclave_cid: u32,
+    /// Console output is only available from enclaves run in debug mode, whose attestation docs have zeroed PCRs
+    pub debug_mode: bool,
+    pub nitro_cli: String,
+    /// Consecutive restarts allowed before giving up on the enclave
+    pub max_restarts: u32,
+    /// Delay before the first restart, doubling with each restart after
+    pub restart_backoff_ms: u64,
+    /// How often the enclave is checked to still be running
+    pub heartbeat_interval_ms: u64,
+}
+
+impl OrchestratorConfig {
+    /// The orchestrator's config, or `None` if the control plane shouldn't start the enclave. Invalid CPU counts and
+    /// memory sizes fall back to the defaults, and are reported when the config is validated.
+    pub fn from_env() -> Option<Self> {
+        let eif_path = std::env::var(EIF_PATH_ENV_VAR)
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        Some(Self {
+            eif_path,
+            cpu_count: parse_env_var(CPU_COUNT_ENV_VAR).unwrap_or(DEFAULT_CPU_COUNT),
+            memory_mib: parse_env_var(MEMORY_MIB_ENV_VAR).unwrap_or(DEFAULT_MEMORY_MIB),
+            enclave_cid: parse_env_var(CID_ENV_VAR).unwrap_or(shared::ENCLAVE_CID),
+            debug_mode: std::env::var(DEBUG_MODE_ENV_VAR)
+                .is_ok_and(|debug_mode| debug_mode.eq_ignore_ascii_case("true")),
+            nitro_cli: nitro_cli_path(),
+            max_restarts: parse_env_var(MAX_RESTARTS_ENV_VAR).unwrap_or(DEFAULT_MAX_RESTARTS),
+            restart_backoff_ms: parse_env_var(RESTART_BACKOFF_MS_ENV_VAR)
+                .unwrap_or(DEFAULT_RESTART_BACKOFF_MS),
+            heartbeat_interval_ms: parse_env_var(HEARTBEAT_INTERVAL_MS_ENV_VAR)
+                .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MS),
+        })
+    }
+
+    pub fn run_enclave_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "run-enclave".to_string(),
+            "--eif-path".to_string(),
+            self.eif_path.clone(),
+            "--cpu-count".to_string(),
+            self.cpu_count.to_string(),
+            "--memory".to_string(),
+            self.memory_mib.to_string(),
+            "--enclave-cid".to_string(),
+            self.enclave_cid.to_string(),
+        ];
+        if self.debug_mode {
+            args.push("--debug-mode".to_string());
+        }
+        args
+    }
+}
+
+pub fn nitro_cli_path() -> String {
+    std::env::var(NITRO_CLI_ENV_VAR).unwrap_or_else(|_| "nitro-cli".to_string())
+}
+
+/// Parse an env var, returning `None` if it's unset or invalid.
+pub fn parse_env_var<T: std::str::FromStr>(var_name: &str) -> Option<T> {
+    std::env::var(var_name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// The description of the new enclave which `nitro-cli run-enclave` prints.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct RunEnclaveOutput {
+    #[serde(rename = "EnclaveID")]
+    pub enclave_id: String,
+    #[serde(rename = "EnclaveCID")]
+    pub enclave_cid: u64,
+}
+
+/// An enclave as listed by `nitro-cli describe-enclaves`. Fields are renamed to snake case when reported by the
+/// control plane's health checks.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DescribedEnclave {
+    #[serde(alias = "EnclaveID")]
+    pub enclave_id: String,
+    #[serde(alias = "EnclaveName", default)]
+    pub enclave_name: Option<String>,
+    #[serde(alias = "EnclaveCID", default)]
+    pub enclave_cid: Option<u64>,
+    #[serde(alias = "State")]
+    pub state: String,
+    #[serde(alias = "NumberOfCPUs", default)]
+    pub cpu_count: u32,
+    #[serde(alias = "CPUIDs", default)]
+    pub cpu_ids: Vec<u32>,
+    #[serde(alias = "MemoryMiB", default)]
+    pub memory_mib: u64,
+    #[serde(alias = "Flags", default)]
+    pub flags: Option<String>,
+}
+
+impl DescribedEnclave {
+    pub fn is_running(&self) -> bool {
+        self.state.eq_ignore_ascii_case("running")
+    }
+}
+
+/// Exponential backoff between restarts, which runs out after too many restarts in a row.
+struct RestartBackoff {
+    initial: Duration,
+    max_restarts: u32,
+    attempts: u32,
+}
+
+impl RestartBackoff {
+    fn new(config: &OrchestratorConfig) -> Self {
+        Self {
+            initial: Duration::from_millis(config.restart_backoff_ms),
+            max_restarts: config.max_restarts,
+            attempts: 0,
+        }
+    }
+
+    /// The delay before the next restart, or `None` once out of restarts.
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts >= self.max_restarts {
+            return None;
+        }
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(MAX_RESTART_BACKOFF);
+        self.attempts += 1;
+        Some(delay)
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Start the enclave and keep it running, restarting it with backoff whenever it dies.
+pub async fn start(config: OrchestratorConfig) {
+    // The enclave is terminated deliberately once the control plane has drained
+    run(config, || crate::health::IS_DRAINING.get() == Some(&true)).await
+}
+
+async fn run(config: OrchestratorConfig, is_draining: impl Fn() -> bool) {
+    set_boot_state(EnclaveBootState::Starting);
+    let mut backoff = RestartBackoff::new(&config);
+    loop {
+        let reason = match boot(&config).await {
+            Ok(enclave) => {
+                let started_at = Instant::now();
+                let reason = watch(&config, &enclave.enclave_id).await;
+                log::error!("Enclave {} has died - {reason}", enclave.enclave_id);
+                StatsClient::record_enclave_event("exited");
+                if started_at.elapsed() >= STABLE_UPTIME {
+                    backoff.reset();
+                }
+                reason
+            }
+            Err(e) => {
+                log::error!("Failed to start the enclave - {e}");
+                StatsClient::record_enclave_event("start_failed");
+                e.to_string()
+            }
+        };
+
+        if is_draining() {
+            log::info!("Control plane is draining, not restarting the enclave");
+            return;
+        }
+        let Some(delay) = backoff.next_delay() else {
+            log::error!(
+                "Enclave failed after {} restarts in a row, no longer restarting it",
+                config.max_restarts
+            );
+            StatsClient::record_enclave_event("restarts_exhausted");
+            set_boot_state(EnclaveBootState::Failed(reason));
+            return;
+        };
+        log::warn!(
+            "Restarting the enclave in {}ms, attempt {} of {}",
+            delay.as_millis(),
+            backoff.attempts,
+            config.max_restarts
+        );
+        StatsClient::record_enclave_event("restart");
+        set_boot_state(EnclaveBootState::Restarting {
+            attempt: backoff.attempts,
+            reason,
+        });
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Run the enclave, and follow its console if it's in debug mode.
+async fn boot(config: &OrchestratorConfig) -> Result<RunEnclaveOutput> {
+    log::info!(
+        "Starting enclave from {} with {} CPUs and {}MiB of memory",
+        config.eif_path,
+        config.cpu_count,
+        config.memory_mib
+    );
+    let run_config = config.clone();
+    let enclave = tokio::task::spawn_blocking(move || run_enclave(&run_config))
+        .await
+        .unwrap_or_else(|e| Err(ServerError::EnclaveStart(e.to_string())))?;
+    log::info!(
+        "Enclave {} started with CID {}",
+        enclave.enclave_id,
+        enclave.enclave_cid
+    );
+    StatsClient::record_enclave_event("started");
+    set_boot_state(EnclaveBootState::Running {
+        enclave_id: enclave.enclave_id.clone(),
+        enclave_cid: enclave.enclave_cid,
+    });
+
+    if config.debug_mode {
+        let nitro_cli = config.nitro_cli.clone();
+        let enclave_id = enclave.enclave_id.clone();
+        tokio::task::spawn_blocking(move || match follow_console(&nitro_cli, &enclave_id) {
+            Ok(()) => log::warn!("Enclave console closed"),
+            Err(e) => log::error!("Failed to read the enclave's console - {e}"),
+        });
+    }
+    Ok(enclave)
+}
+
+/// Check the enclave on every heartbeat, returning why once it's no longer running. Failures to run nitro-cli are
+/// logged and retried, rather than restarting an enclave which may be healthy.
+async fn watch(config: &OrchestratorConfig, enclave_id: &str) -> String {
+    let heartbeat_interval = Duration::from_millis(config.heartbeat_interval_ms);
+    loop {
+        tokio::time::sleep(heartbeat_interval).await;
+        let nitro_cli = config.nitro_cli.clone();
+        let described = tokio::task::spawn_blocking(move || describe_enclaves(&nitro_cli))
+            .await
+            .unwrap_or_else(|e| Err(ServerError::EnclaveStart(e.to_string())));
+        let enclaves = match described {
+            Ok(enclaves) => enclaves,
+            Err(e) => {
+                log::warn!("Failed to check that the enclave is running - {e}");
+                continue;
+            }
+        };
+        match enclaves
+            .iter()
+            .find(|enclave| enclave.enclave_id == enclave_id)
+        {
+            Some(enclave) if enclave.is_running() => {}
+            Some(enclave) => return format!("Enclave is {}", enclave.state),
+            None => return "Enclave is no longer running".to_string(),
+        }
+    }
+}
+
+pub(crate) fn describe_enclaves(nitro_cli: &str) -> Result<Vec<DescribedEnclave>> {
+    let output = Command::new(nitro_cli).arg("describe-enclaves").output()?;
+    if !output.status.success() {
+        return Err(ServerError::EnclaveStart(format!(
+            "nitro-cli describe-enclaves exited with {}",
+            output.status
+        )));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+fn run_enclave(config: &OrchestratorConfig) -> Result<RunEnclaveOutput> {
+    let output = Command::new(&config.nitro_cli)
+        .args(config.run_enclave_args())
+        .output()?;
+    if !output.status.success() {
+        return Err(ServerError::EnclaveStart(format!(
+            "nitro-cli exited with {} - {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    parse_run_enclave_output(&output.stdout)
+}
+
+pub fn parse_run_enclave_output(stdout: &[u8]) -> Result<RunEnclaveOutput> {
+    // nitro-cli logs its progress before describing the enclave
+    let description = stdout
+        .iter()
+        .position(|byte| *byte == b'{')
+        .map(|start| &stdout[start..])
+        .ok_or_else(|| {
+            ServerError::EnclaveStart("nitro-cli didn't describe the new enclave".to_string())
+        })?;
+    serde_json::from_slice(description).map_err(|e| {
+        ServerError::EnclaveStart(format!(
+            "Unexpected enclave description from nitro-cli - {e}"
+        ))
+    })
+}
+
+/// Log each line of the enclave's console until it closes.
+fn follow_console(nitro_cli: &str, enclave_id: &str) -> Result<()> {
+    let mut console = Command::new(nitro_cli)
+        .args(["console", "--enclave-id", enclave_id])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = console.stdout.take().expect("Infallible - stdout is piped");
+    for line in BufReader::new(stdout).lines() {
+        log::info!(target: CONSOLE_LOG_TARGET, "{}", line?);
+    }
+    console.wait()?;
+    Ok(())
+}
+
+fn set_boot_state(state: EnclaveBootState) {
+    *BOOT_STATE.lock().unwrap_or_else(|e| e.into_inner()) = Some(state);
+}
+
+pub fn boot_state() -> Option<EnclaveBootState> {
+    BOOT_STATE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Whether the enclave has booted, if the control plane is orchestrating it.
+pub fn readiness_check() -> Option<ReadinessCheck> {
+    let check = match boot_state()? {
+        EnclaveBootState::Starting => ReadinessCheck::not_ready("enclave", "Enclave is starting"),
+        EnclaveBootState::Running { .. } => ReadinessCheck::ready("enclave"),
+        EnclaveBootState::Restarting { attempt, reason } => ReadinessCheck::not_ready(
+            "enclave",
+            format!("Enclave is restarting, attempt {attempt} - {reason}"),
+        ),
+        EnclaveBootState::Failed(e) => ReadinessCheck::not_ready(
+            "enclave",
+            format!("Enclave is down and won't be restarted - {e}"),
+        ),
+    };
+    Some(check)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        boot_state, parse_run_enclave_output, readiness_check, run, EnclaveBootState,
+        OrchestratorConfig, RestartBackoff,
+    };
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn config(nitro_cli: &str) -> OrchestratorConfig {
+        OrchestratorConfig {
+            eif_path: "/opt/evervault/enclave.eif".to_string(),
+            cpu_count: 4,
+            memory_mib: 4096,
+            enclave_cid: 16,
+            debug_mode: false,
+            nitro_cli: nitro_cli.to_string(),
+            max_restarts: 0,
+            restart_backoff_ms: 1,
+            heartbeat_interval_ms: 10,
+        }
+    }
+
+    #[test]
+    fn test_run_enclave_args_follow_config() {
+        let mut config = config("nitro-cli");
+        assert_eq!(
+            config.run_enclave_args().join(" "),
+            "run-enclave --eif-path /opt/evervault/enclave.eif --cpu-count 4 --memory 4096 --enclave-cid 16"
+        );
+        config.debug_mode = true;
+        assert!(config.run_enclave_args().ends_with(&[
+            "--enclave-cid".to_string(),
+            "16".to_string(),
+            "--debug-mode".to_string()
+        ]));
+    }
+
+    #[test]
+    fn test_enclave_description_is_parsed_after_progress_logs() {
+        let stdout = br#"Start allocating memory...
+Started enclave with enclave-cid: 16, memory: 4096 MiB, cpu-ids: [1, 3]
+{
+  "EnclaveName": "enclave",
+  "EnclaveID": "i-0123456789abcdef0-enc0123456789abcdef",
+  "ProcessID": 1234,
+  "EnclaveCID": 16,
+  "NumberOfCPUs": 2,
+  "CPUIDs": [1, 3],
+  "MemoryMiB": 4096
+}"#;
+        let enclave = parse_run_enclave_output(stdout).unwrap();
+        assert_eq!(
+            enclave.enclave_id,
+            "i-0123456789abcdef0-enc0123456789abcdef"
+        );
+        assert_eq!(enclave.enclave_cid, 16);
+        assert!(parse_run_enclave_output(b"E39 Enclave boot failure").is_err());
+    }
+
+    #[test]
+    fn test_restart_backoff_doubles_until_out_of_restarts() {
+        let mut config = config("nitro-cli");
+        config.max_restarts = 3;
+        let mut backoff = RestartBackoff::new(&config);
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(1),
+                Duration::from_millis(2),
+                Duration::from_millis(4)
+            ]
+        );
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(1)));
+    }
+
+    /// A fake nitro-cli which starts an enclave, and lists it as running only while `running` exists.
+    fn fake_nitro_cli(dir: &Path) -> String {
+        let nitro_cli = dir.join("nitro-cli");
+        let script = format!(
+            r#"#!/bin/sh
+case "$1" in
+  run-enclave)
+    echo run >> {dir}/runs
+    echo 'Start allocating memory...'
+    echo '{{"EnclaveID": "i-abc-enc123", "EnclaveCID": 16}}' ;;
+  describe-enclaves)
+    if [ -f {dir}/running ]; then
+      echo '[{{"EnclaveID": "i-abc-enc123", "State": "RUNNING"}}]'
+    else
+      echo '[]'
+    fi ;;
+esac
+"#,
+            dir = dir.display()
+        );
+        std::fs::write(&nitro_cli, script).unwrap();
+        std::fs::set_permissions(&nitro_cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+        nitro_cli.to_str().unwrap().to_string()
+    }
+
+    async fn wait_for_state(matches: impl Fn(&EnclaveBootState) -> bool) {
+        for _ in 0..200 {
+            if boot_state().as_ref().is_some_and(&matches) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "Enclave never reached the expected state, it's {:?}",
+            boot_state()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enclave_is_restarted_until_out_of_restarts() {
+        assert_eq!(readiness_check(), None);
+
+        let dir = std::env::temp_dir().join(format!("orchestrator-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("running"), "").unwrap();
+        let mut config = config(&fake_nitro_cli(&dir));
+        config.max_restarts = 2;
+        // Other tests set the control plane draining
+        let orchestrator = tokio::spawn(run(config, || false));
+
+        wait_for_state(|state| matches!(state, EnclaveBootState::Running { .. })).await;
+        assert_eq!(
+            boot_state(),
+            Some(EnclaveBootState::Running {
+                enclave_id: "i-abc-enc123".to_string(),
+                enclave_cid: 16
+            })
+        );
+        assert!(readiness_check().unwrap().ready);
+
+        // The enclave dies, and keeps dying after each restart
+        std::fs::remove_file(dir.join("running")).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), orchestrator)
+            .await
+            .unwrap()
+            .unwrap();
+        let runs = std::fs::read_to_string(dir.join("runs")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(runs.lines().count(), 3);
+
+        let check = readiness_check().unwrap();
+        assert!(!check.ready);
+        assert_eq!(
+            check.message.as_deref(),
+            Some("Enclave is down and won't be restarted - Enclave is no longer running")
+        );
+    }
+}
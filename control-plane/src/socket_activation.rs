@@ -0,0 +1,136 @@
+//! systemd socket activation. When the control plane is started by a socket unit, systemd binds its listeners and
+//! passes them in as file descriptors from 3 up, described by the `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+//! environment variables. The sockets stay open while the control plane restarts, so no connections are refused in
+//! between, and privileged ports such as 443 can be used without running as root.
+//!
+//! Listeners are matched by their `FileDescriptorName=`: `ingress`, `dns` and `egress`. Unnamed descriptors are taken
+//! in that order. Any listener which isn't passed in is bound as usual.
+use std::collections::HashMap;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+
+pub const INGRESS_LISTENER: &str = "ingress";
+pub const DNS_LISTENER: &str = "dns";
+pub const EGRESS_LISTENER: &str = "egress";
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+const LISTENER_ORDER: [&str; 3] = [INGRESS_LISTENER, DNS_LISTENER, EGRESS_LISTENER];
+
+lazy_static::lazy_static! {
+    static ref ACTIVATED_LISTENERS: Mutex<HashMap<String, OwnedFd>> = Mutex::new(activated_listeners());
+}
+
+fn activated_listeners() -> HashMap<String, OwnedFd> {
+    let env = |key: &str| std::env::var(key).ok();
+    let fds = parse_listen_fds(
+        env("LISTEN_PID").as_deref(),
+        env("LISTEN_FDS").as_deref(),
+        env("LISTEN_FDNAMES").as_deref(),
+        std::process::id(),
+    );
+    fds.into_iter()
+        .filter_map(|(name, fd)| {
+            // Safety: systemd hands these descriptors to this process, and nothing else takes ownership of them
+            let passed = unsafe { OwnedFd::from_raw_fd(fd) };
+            // Passed descriptors aren't close-on-exec, and the duplicate is, so enclave processes don't inherit them
+            match passed.try_clone() {
+                Ok(listener) => {
+                    log::info!("Using {name} listener passed in by systemd");
+                    Some((name, listener))
+                }
+                Err(e) => {
+                    log::error!("Couldn't take the {name} listener passed in by systemd — {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The listeners passed to this process, by name. Descriptors which aren't for this process, or have names the
+/// control plane doesn't use, are ignored.
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Vec<(String, RawFd)> {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) != Some(pid) {
+        return Vec::new();
+    }
+    let Some(count) = listen_fds.and_then(|count| count.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    let names: Vec<&str> = listen_fdnames
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+    (0..count)
+        .filter_map(|index| {
+            let name = match names.get(index as usize) {
+                Some(name) if !name.is_empty() && *name != "unknown" => *name,
+                _ => *LISTENER_ORDER.get(index as usize)?,
+            };
+            if LISTENER_ORDER.contains(&name) {
+                Some((name.to_string(), SD_LISTEN_FDS_START + index))
+            } else {
+                log::warn!("Ignoring unknown listener {name} passed in by systemd");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Take ownership of the listeners passed in by systemd, and stop them from being inherited by child processes.
+pub fn init() {
+    lazy_static::initialize(&ACTIVATED_LISTENERS);
+}
+
+/// Take the listener with the given name, if one was passed in. Each listener can only be taken once.
+pub fn take_listener(name: &str) -> Option<OwnedFd> {
+    ACTIVATED_LISTENERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(name)
+}
+
+/// Take a TCP listener passed in by systemd, ready to be used with an async runtime.
+pub fn take_tcp_listener(name: &str) -> std::io::Result<Option<std::net::TcpListener>> {
+    let Some(fd) = take_listener(name) else {
+        return Ok(None);
+    };
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    log::info!(
+        "Accepting {name} connections on {} from systemd",
+        listener.local_addr()?
+    );
+    Ok(Some(listener))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_listen_fds;
+
+    #[test]
+    fn test_listeners_are_named_or_taken_in_order() {
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("3"), Some("egress:ingress:metrics"), 42),
+            vec![("egress".to_string(), 3), ("ingress".to_string(), 4)]
+        );
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), None, 42),
+            vec![("ingress".to_string(), 3), ("dns".to_string(), 4)]
+        );
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), Some("unknown:egress"), 42),
+            vec![("ingress".to_string(), 3), ("egress".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_listeners_for_other_processes_are_ignored() {
+        assert!(parse_listen_fds(Some("41"), Some("1"), Some("ingress"), 42).is_empty());
+        assert!(parse_listen_fds(None, Some("1"), Some("ingress"), 42).is_empty());
+        assert!(parse_listen_fds(Some("42"), None, None, 42).is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+use crate::clients::cert_provisioner;
+#[cfg(not(feature = "mock_provisioner"))]
+use crate::clients::mtls_config;
+use crate::dns::{ExternalAsyncDnsResolver, InternalAsyncDnsResolver};
+use crate::stats_client::StatsClient;
+use crate::stats_proxy::StatsProxy;
+use crate::{config_server, tcp_passthrough, tls_proxy};
+#[cfg(not(feature = "io_uring"))]
+use shared::server::proxy_protocol::build_proxy_protocol_header;
+#[cfg(not(feature = "io_uring"))]
+use shared::utils::pipe_streams;
+use shared::ENCLAVE_CONNECT_PORT;
+#[cfg(not(feature = "io_uring"))]
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process::Command;
+use storage_client_interface::s3;
+#[cfg(not(feature = "io_uring"))]
+use tokio::io::AsyncWriteExt;
+use tokio::time::{sleep, Duration};
+
+#[cfg(not(feature = "io_uring"))]
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+#[cfg(not(feature = "io_uring"))]
+use crate::enclave_connection;
+#[cfg(not(feature = "io_uring"))]
+use crate::socket_activation::INGRESS_LISTENER;
+use crate::{
+    configuration::{self, Environment},
+    e3proxy, enclave_status,
+    error::Result,
+    health, orchestrator, socket_activation,
+};
+
+#[cfg(feature = "enclave")]
+const CONTROL_PLANE_PORT: u16 = 443;
+#[cfg(not(feature = "enclave"))]
+const CONTROL_PLANE_PORT: u16 = 3031;
+
+/// Start the control plane's servers and proxies, running until they all exit.
+pub async fn run() -> Result<()> {
+    log::debug!("Starting control plane on {CONTROL_PLANE_PORT}");
+    let e3_proxy = e3proxy::E3Proxy::new();
+
+    let provisioner_proxy = tls_proxy::TlsProxy::new(
+        vec![configuration::get_cert_provisoner_host()],
+        3000,
+        shared::ENCLAVE_CERT_PORT,
+        InternalAsyncDnsResolver::new_resolver(),
+    );
+
+    let acme_proxy = tls_proxy::TlsProxy::new(
+        configuration::get_acme_hosts(),
+        443,
+        shared::ENCLAVE_ACME_PORT,
+        ExternalAsyncDnsResolver::new_resolver(),
+    );
+
+    StatsClient::init();
+    // Before any enclave processes are spawned, so they don't inherit the listeners
+    socket_activation::init();
+    if let Some(orchestrator_config) = orchestrator::OrchestratorConfig::from_env() {
+        tokio::spawn(orchestrator::start(orchestrator_config));
+    }
+    if enclave_status::is_enabled() {
+        tokio::spawn(enclave_status::poll_enclave_status());
+    }
+    tokio::spawn(health::watch_data_plane_readiness());
+    let tcp_passthrough_ports = configuration::get_tcp_passthrough_ports();
+    if !tcp_passthrough_ports.is_empty() {
+        tokio::spawn(async move {
+            if let Err(err) = tcp_passthrough::run(tcp_passthrough_ports).await {
+                log::error!("Error running TCP passthrough on host: {err:?}");
+            }
+        });
+    }
+
+    #[cfg(feature = "mock_provisioner")]
+    let mtls_config = {
+        let mock_provisioner = std::sync::Arc::new(
+            crate::mock_provisioner::MockProvisioner::generate(
+                &configuration::get_cert_provisoner_host(),
+            )
+            .expect("Failed to generate mock provisioner"),
+        );
+        let mtls_config = mock_provisioner
+            .mtls_certs()
+            .expect("Failed to get mock provisioner mtls certs");
+        tokio::spawn(async move {
+            if let Err(err) = mock_provisioner.listen().await {
+                log::error!("Error running mock provisioner: {err:?}");
+            }
+        });
+        mtls_config
+    };
+    #[cfg(not(feature = "mock_provisioner"))]
+    let mtls_config = mtls_config::CertProvisionerMtlsCerts::from_env_vars()
+        .expect("Couldn't read in env vars for mtls certs");
+
+    log::info!("MTLS Certs loaded for Cert Provisioner");
+
+    let cert_provisioner_client = cert_provisioner::CertProvisionerClient::new(
+        mtls_config.client_key_pair(),
+        mtls_config.root_cert(),
+    );
+
+    let acme_s3_client = s3::StorageClient::new(configuration::get_acme_s3_bucket()).await;
+
+    let config_server = config_server::ConfigServer::new(cert_provisioner_client, acme_s3_client);
+
+    #[cfg(not(feature = "network_egress"))]
+    {
+        listen_for_shutdown_signal();
+        let mut health_check_server = health::HealthCheckServer::new().await?;
+
+        let (
+            tcp_result,
+            e3_result,
+            health_check_result,
+            config_server_result,
+            provisioner_proxy_result,
+            acme_proxy_result,
+            _,
+        ) = tokio::join!(
+            tcp_server(),
+            e3_proxy.listen(),
+            health_check_server.start(),
+            config_server.listen(),
+            provisioner_proxy.listen(),
+            acme_proxy.listen(),
+            StatsProxy::listen()
+        );
+
+        if let Err(err) = tcp_result {
+            log::error!("Error running TCP server on host: {err:?}");
+        };
+
+        if let Err(err) = e3_result {
+            log::error!("Error running E3 proxy on host: {err:?}");
+        }
+
+        if let Err(err) = health_check_result {
+            log::error!("Error running health check server on host: {err:?}");
+        }
+
+        if let Err(err) = config_server_result {
+            log::error!("Error running config server on host: {err:?}");
+        }
+
+        if let Err(err) = provisioner_proxy_result {
+            log::error!("Error running provisioner proxy on host: {err:?}");
+        }
+
+        if let Err(err) = acme_proxy_result {
+            log::error!("Error running acme proxy on host: {err:?}");
+        }
+    }
+
+    #[cfg(feature = "network_egress")]
+    {
+        listen_for_shutdown_signal();
+        let mut health_check_server = health::HealthCheckServer::new().await?;
+        let parsed_ip = crate::dnsproxy::read_dns_server_ips_from_env_var()
+            .unwrap_or_else(|| crate::dnsproxy::DNS_SERVERS.clone());
+
+        let dns_proxy_server = crate::dnsproxy::DnsProxy::new(parsed_ip);
+        let mut egress_policy_server = crate::egress_policy::EgressPolicyAdminServer::new().await?;
+        let (
+            tcp_result,
+            dns_result,
+            egress_result,
+            egress_policy_result,
+            e3_result,
+            health_check_result,
+            config_server_result,
+            provisioner_result,
+            acme_proxy_result,
+            _,
+        ) = tokio::join!(
+            tcp_server(),
+            dns_proxy_server.listen(),
+            crate::egressproxy::EgressProxy::listen(),
+            egress_policy_server.start(),
+            e3_proxy.listen(),
+            health_check_server.start(),
+            config_server.listen(),
+            provisioner_proxy.listen(),
+            acme_proxy.listen(),
+            StatsProxy::listen()
+        );
+
+        if let Err(tcp_err) = tcp_result {
+            log::error!("An error occurred in the tcp server - {tcp_err:?}");
+        }
+
+        if let Err(dns_err) = dns_result {
+            log::error!("An error occurred in the dns server - {dns_err:?}");
+        }
+
+        if let Err(egress_err) = egress_result {
+            log::error!("An error occurred in the egress server - {egress_err:?}");
+        }
+
+        if let Err(err) = egress_policy_result {
+            log::error!("An error occurred in the egress policy admin server - {err:?}");
+        }
+
+        if let Err(e3_err) = e3_result {
+            log::error!("An error occurred in the e3 server - {e3_err:?}");
+        }
+
+        if let Err(err) = health_check_result {
+            log::error!("Error running health check server on host: {err:?}");
+        }
+
+        if let Err(err) = config_server_result {
+            log::error!("Error running config server on host: {err:?}");
+        }
+
+        if let Err(err) = provisioner_result {
+            log::error!("Error running provisioner proxy on host: {err:?}");
+        }
+
+        if let Err(err) = acme_proxy_result {
+            log::error!("Error running acme proxy on host: {err:?}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "io_uring")]
+async fn tcp_server() -> Result<()> {
+    crate::uring_proxy::run_tcp_server(
+        CONTROL_PLANE_PORT,
+        ENCLAVE_CONNECT_PORT,
+        configuration::get_first_byte_timeout(),
+    )
+    .await
+}
+
+#[cfg(not(feature = "io_uring"))]
+async fn tcp_server() -> Result<()> {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), CONTROL_PLANE_PORT);
+
+    let tcp_listener = match socket_activation::take_tcp_listener(INGRESS_LISTENER) {
+        Ok(Some(tcp_listener)) => TcpListener::from_std(tcp_listener),
+        Ok(None) => TcpListener::bind(addr).await,
+        Err(e) => Err(e),
+    };
+    let tcp_listener = match tcp_listener {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            log::error!("Failed to bind to TCP Socket - {e:?}");
+            return Err(e.into());
+        }
+    };
+    let first_byte_timeout = configuration::get_first_byte_timeout();
+    let send_proxy_protocol = configuration::get_send_proxy_protocol();
+
+    loop {
+        let (mut connection, client_socket_addr) = match tcp_listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to accept incoming TCP stream - {:?}", e);
+                continue;
+            }
+        };
+        if !crate::health::is_data_plane_ready() {
+            log::debug!(
+                "Data plane isn't ready, rejecting incoming TCP stream — {client_socket_addr:?}"
+            );
+            drop(connection);
+            continue;
+        }
+        StatsClient::record_request();
+        tokio::spawn(async move {
+            log::debug!("Accepted incoming TCP stream — {client_socket_addr:?}");
+            match enclave_connection::wait_for_first_bytes(&connection, first_byte_timeout).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("No data received from {client_socket_addr:?}, closing connection without connecting to the enclave");
+                    let _ = connection.shutdown().await;
+                    return;
+                }
+                Err(e) => {
+                    log::error!("Failed to read from incoming TCP stream — {e:?}");
+                    return;
+                }
+            }
+
+            let mut enclave_stream =
+                match enclave_connection::get_authenticated_connection_to_enclave(
+                    ENCLAVE_CONNECT_PORT,
+                )
+                .await
+                {
+                    Ok(enclave_stream) => enclave_stream,
+                    Err(e) => {
+                        log::error!("An error occurred while connecting to the enclave — {e:?}");
+                        if let Err(e) = connection.shutdown().await {
+                            log::warn!("Failed to close connection to client — {e}");
+                        }
+                        return;
+                    }
+                };
+
+            // The data plane reads the proxy protocol header straight after the session token
+            if send_proxy_protocol {
+                let header = connection.local_addr().and_then(|local_addr| {
+                    build_proxy_protocol_header(client_socket_addr, local_addr)
+                });
+                let write_result = match header {
+                    Ok(header) => enclave_stream.write_all(&header).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = write_result {
+                    log::error!("Failed to send proxy protocol header to the enclave — {e:?}");
+                    return;
+                }
+            }
+
+            if let Err(e) = pipe_streams(connection, enclave_stream).await {
+                log::error!("An error occurred while piping the connection over vsock - {e:?}");
+            }
+        });
+    }
+}
+
+// Listen for SIGTERM and deregister task before shutting down
+fn listen_for_shutdown_signal() {
+    log::debug!("Setting up listener for SIGTERM");
+    tokio::spawn(async {
+        if configuration::get_rust_env() == Environment::Development || cfg!(feature = "local") {
+            //Don't start ctrl-c listener is running locally
+            return;
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let _ = ctrlc::set_handler(move || {
+            tx.send(()).unwrap_or_else(|err| {
+                log::warn!("Could not broadcast sigterm to channel: {err:?}");
+            })
+        })
+        .map_err(|err| {
+            log::error!("Error setting up Sigterm handler: {err:?}");
+            std::io::Error::new(std::io::ErrorKind::Other, err)
+        });
+
+        match rx.recv().await {
+            Some(_) => {
+                log::info!("SIGTERM received. Setting Enclave draining flag to true and waiting 55 seconds to terminate Enclave.");
+                if let Err(err) = health::IS_DRAINING.set(true) {
+                    log::error!(
+                        "Error setting IS_DRAINING to true: {err:?}, continuing to shutdown"
+                    );
+                }
+
+                // Wait for 55 seconds before terminating enclave - ECS waits 55 seconds to kill the container
+                sleep(Duration::from_millis(55000)).await;
+
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg("nitro-cli terminate-enclave --all")
+                    .output()
+                    .expect("failed to terminate enclave");
+
+                log::info!(
+                    "Terminated enclave: {}",
+                    String::from_utf8_lossy(&output.stdout)
+                );
+            }
+            None => {
+                log::error!("Signal watcher returned None.");
+            }
+        };
+    });
+}
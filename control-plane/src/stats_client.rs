@@ -1,9 +1,10 @@
 use cadence::{BufferedUdpMetricSink, QueuingMetricSink, StatsdClient};
-use cadence_macros::{set_global_default, statsd_count};
-use shared::{publish_count, stats::StatsError};
+use cadence_macros::{set_global_default, statsd_count, statsd_gauge};
+use shared::{publish_count, publish_count_dynamic_label, publish_gauge, stats::StatsError};
 use std::net::{Ipv4Addr, UdpSocket};
 
 use crate::configuration::EnclaveContext;
+use crate::enclave_status::EnclaveStatusReport;
 
 pub struct StatsClient;
 
@@ -32,4 +33,24 @@ impl StatsClient {
         let context = EnclaveContext::from_env_vars();
         publish_count!("request.count", 1, context);
     }
+
+    /// Count an enclave lifecycle event from the orchestrator, e.g. `restart`.
+    pub fn record_enclave_event(event: &str) {
+        // The orchestrator can run without the enclave's context, e.g. when testing it locally
+        let Some(context) = EnclaveContext::try_from_env_vars() else {
+            return;
+        };
+        let key = format!("enclave.{event}.count");
+        publish_count_dynamic_label!(key.as_str(), 1, context);
+    }
+
+    /// Publish the state and resources of the host's running enclaves.
+    pub fn record_enclave_resources(report: &EnclaveStatusReport) {
+        let Some(context) = EnclaveContext::try_from_env_vars() else {
+            return;
+        };
+        publish_gauge!("enclave.running", report.running_count() as f64, context);
+        publish_gauge!("enclave.cpu_count", report.cpu_count() as f64, context);
+        publish_gauge!("enclave.memory_mib", report.memory_mib() as f64, context);
+    }
 }
@@ -0,0 +1,77 @@
+//! Listens on the ports configured for raw TCP passthrough, and pipes each connection into the enclave untouched. The
+//! data plane pipes them on to the same port on the customer process, so any protocol can be served from the enclave.
+use shared::server::tcp_passthrough::write_target_port;
+use shared::utils::pipe_streams;
+use shared::ENCLAVE_TCP_PASSTHROUGH_PORT;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::enclave_connection;
+use crate::error::Result;
+use crate::stats_client::StatsClient;
+
+/// Listen on each of the passthrough ports, running until one of the listeners fails.
+pub async fn run(ports: Vec<u16>) -> Result<()> {
+    let mut listeners = Vec::with_capacity(ports.len());
+    for port in ports {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let listener = TcpListener::bind(addr).await.map_err(|e| {
+            log::error!("Failed to bind to TCP passthrough port {port} - {e:?}");
+            e
+        })?;
+        log::info!("Piping TCP connections on port {port} into the enclave");
+        listeners.push(tokio::spawn(listen(listener, port)));
+    }
+    for listener in listeners {
+        if let Err(e) = listener.await {
+            log::error!("TCP passthrough listener stopped - {e:?}");
+        }
+    }
+    Ok(())
+}
+
+async fn listen(tcp_listener: TcpListener, port: u16) {
+    loop {
+        let (connection, client_socket_addr) = match tcp_listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to accept incoming TCP passthrough stream - {e:?}");
+                continue;
+            }
+        };
+        if !crate::health::is_data_plane_ready() {
+            log::debug!(
+                "Data plane isn't ready, rejecting incoming TCP passthrough stream — {client_socket_addr:?}"
+            );
+            continue;
+        }
+        StatsClient::record_request();
+        // Unlike the TLS port, clients aren't waited on to speak first, as the customer's protocol may have the
+        // server speak first
+        tokio::spawn(async move {
+            log::debug!(
+                "Accepted incoming TCP passthrough stream on {port} — {client_socket_addr:?}"
+            );
+            if let Err(e) = pipe_to_enclave(connection, port).await {
+                log::error!("An error occurred while piping a TCP passthrough connection over vsock - {e:?}");
+            }
+        });
+    }
+}
+
+async fn pipe_to_enclave(mut connection: TcpStream, port: u16) -> std::io::Result<()> {
+    let mut enclave_stream = match enclave_connection::get_authenticated_connection_to_enclave(
+        ENCLAVE_TCP_PASSTHROUGH_PORT,
+    )
+    .await
+    {
+        Ok(enclave_stream) => enclave_stream,
+        Err(e) => {
+            let _ = connection.shutdown().await;
+            return Err(e);
+        }
+    };
+    write_target_port(&mut enclave_stream, port).await?;
+    pipe_streams(connection, enclave_stream).await
+}
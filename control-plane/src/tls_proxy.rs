@@ -1,5 +1,6 @@
 use crate::dns;
 use crate::error::Result;
+use shared::buffer_pool::STREAM_BUFFER_POOL;
 use shared::server::sni::get_hostname;
 use shared::server::CID::Parent;
 use shared::server::{get_vsock_server, Listener};
@@ -60,7 +61,7 @@ impl TlsProxy {
     }
 
     #[cfg(not(feature = "enclave"))]
-    async fn shutdown_conn(mut connection: tokio::net::TcpStream) {
+    async fn shutdown_conn<C: tokio::io::AsyncWrite + Unpin>(mut connection: C) {
         if let Err(e) = connection.shutdown().await {
             log::error!("Failed to shutdown data plane connection — {e:?}");
         }
@@ -78,16 +79,16 @@ impl TlsProxy {
             let (connection, target, initial_bytes) = match enclave_conn.accept().await {
                 Ok(mut conn) => {
                     // Extract SNI header and check it's for the TLS server's valid hostnames
-                    let mut buf = vec![0u8; 4096];
+                    let mut buf = STREAM_BUFFER_POOL.get();
                     let n = conn.read(&mut buf).await?;
                     let initial_slice = &buf[..n];
-                    let hostname = get_hostname(initial_slice.to_vec()).ok(); // Clone the slice into a new Vec
+                    let hostname = get_hostname(initial_slice).ok();
 
                     match hostname {
-                        Some(host) if self.valid_targets().contains(&host.as_str()) => {
+                        Some(host) if self.valid_targets().contains(&host) => {
                             log::info!("SNI header found for {}. Valid host, forwarding traffic from data plane.", host);
                             let initial_bytes = initial_slice.to_vec(); // Clone the slice into a new Vec
-                            (conn, host, initial_bytes)
+                            (conn, host.to_string(), initial_bytes)
                         }
                         Some(host) => {
                             log::error!(
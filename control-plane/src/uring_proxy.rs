@@ -0,0 +1,236 @@
+//! io_uring backed proxying for the control plane's ingress TCP path.
+//!
+//! The ingress server runs on a dedicated `tokio-uring` runtime thread, separate from the main tokio runtime.
+//! Reads and writes are submitted to the kernel through io_uring with owned buffers, cutting the per-transfer
+//! syscall overhead of the epoll path on large transfers.
+use crate::enclave_connection::session_token;
+use crate::error::{Result, ServerError};
+use crate::socket_activation::{take_tcp_listener, INGRESS_LISTENER};
+use crate::stats_client::StatsClient;
+use shared::server::proxy_protocol::build_proxy_protocol_header;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::time::Duration;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+#[cfg(feature = "enclave")]
+use shared::ENCLAVE_CID;
+
+/// io_uring reads are submitted with owned buffers, so a larger buffer than the epoll path is used to
+/// reduce the number of submissions per transfer.
+const URING_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Run the ingress TCP server on a dedicated io_uring runtime, resolving when the server exits.
+pub async fn run_tcp_server(
+    port: u16,
+    enclave_port: u16,
+    first_byte_timeout: Duration,
+) -> Result<()> {
+    let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("uring-tcp-server".to_string())
+        .spawn(move || {
+            let result = tokio_uring::start(serve(port, enclave_port, first_byte_timeout));
+            let _ = result_sender.send(result);
+        })?;
+
+    result_receiver.await.unwrap_or_else(|_| {
+        Err(ServerError::Io(std::io::Error::other(
+            "io_uring TCP server thread exited unexpectedly",
+        )))
+    })
+}
+
+async fn serve(port: u16, enclave_port: u16, first_byte_timeout: Duration) -> Result<()> {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let tcp_listener = match take_tcp_listener(INGRESS_LISTENER) {
+        Ok(Some(tcp_listener)) => Ok(TcpListener::from_std(tcp_listener)),
+        Ok(None) => TcpListener::bind(addr),
+        Err(e) => Err(e),
+    };
+    let tcp_listener = match tcp_listener {
+        Ok(tcp_listener) => tcp_listener,
+        Err(e) => {
+            log::error!("Failed to bind to TCP Socket - {e:?}");
+            return Err(e.into());
+        }
+    };
+    log::info!("Running io_uring TCP server on {addr}");
+    let send_proxy_protocol = crate::configuration::get_send_proxy_protocol();
+
+    loop {
+        let (connection, client_socket_addr) = match tcp_listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to accept incoming TCP stream - {:?}", e);
+                continue;
+            }
+        };
+        if !crate::health::is_data_plane_ready() {
+            log::debug!(
+                "Data plane isn't ready, rejecting incoming TCP stream — {client_socket_addr:?}"
+            );
+            drop(connection);
+            continue;
+        }
+        StatsClient::record_request();
+        tokio_uring::spawn(async move {
+            log::debug!("Accepted incoming TCP stream — {client_socket_addr:?}");
+            let first_bytes = match read_first_bytes(&connection, first_byte_timeout).await {
+                Ok(Some(first_bytes)) => first_bytes,
+                Ok(None) => {
+                    log::debug!("No data received from {client_socket_addr:?}, closing connection without connecting to the enclave");
+                    let _ = connection.shutdown(Shutdown::Both);
+                    return;
+                }
+                Err(e) => {
+                    log::error!("Failed to read from incoming TCP stream — {e:?}");
+                    return;
+                }
+            };
+
+            let enclave_stream = match get_connection_to_enclave(enclave_port).await {
+                Ok(enclave_stream) => enclave_stream,
+                Err(e) => {
+                    log::error!("An error occurred while connecting to the enclave — {e:?}");
+                    if let Err(e) = connection.shutdown(Shutdown::Both) {
+                        log::error!("Failed to close connection to client — {e:?}");
+                    }
+                    return;
+                }
+            };
+
+            // The session token must be the first bytes the enclave receives on the connection, followed by the
+            // proxy protocol header if one is sent
+            let mut initial_bytes = match session_token() {
+                Ok(token) => token.as_bytes().to_vec(),
+                Err(e) => {
+                    log::error!("Failed to connect to enclave — {e}");
+                    return;
+                }
+            };
+            if send_proxy_protocol {
+                match build_proxy_protocol_header(client_socket_addr, addr) {
+                    Ok(header) => initial_bytes.extend_from_slice(&header),
+                    Err(e) => {
+                        log::error!("Failed to build proxy protocol header — {e:?}");
+                        return;
+                    }
+                }
+            }
+            initial_bytes.extend_from_slice(&first_bytes);
+            let (write_result, _) = enclave_stream.write_all(initial_bytes).await;
+            if let Err(e) = write_result {
+                log::error!("Failed to forward initial bytes to the enclave — {e:?}");
+                return;
+            }
+
+            if let Err(e) = pipe_streams(connection, enclave_stream).await {
+                log::error!("An error occurred while piping the connection over vsock - {e:?}");
+            }
+        });
+    }
+}
+
+/// Wait for the client's first bytes before a connection to the enclave is opened for it, so that port scanners
+/// and idle probes don't hold vsock connections. io_uring streams can't be peeked, so the bytes are read here and
+/// must be forwarded to the enclave before piping. Returns None if the client closed the connection or sent
+/// nothing within the timeout.
+async fn read_first_bytes(
+    stream: &TcpStream,
+    timeout: Duration,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let buffer = Vec::with_capacity(URING_BUFFER_SIZE);
+    match tokio::time::timeout(timeout, stream.read(buffer)).await {
+        Ok((read_result, buffer)) => Ok((read_result? > 0).then_some(buffer)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "enclave"))]
+async fn get_connection_to_enclave(port: u16) -> std::io::Result<TcpStream> {
+    let ip_addr = IpAddr::V4(Ipv4Addr::new(172, 20, 0, 7));
+    log::debug!("Connecting to tcp data plane on ({ip_addr},{port})");
+    TcpStream::connect(SocketAddr::new(ip_addr, port)).await
+}
+
+#[cfg(feature = "enclave")]
+async fn get_connection_to_enclave(port: u16) -> std::io::Result<TcpStream> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    // tokio-uring has no vsock stream, but its stream operations are plain reads and writes against the
+    // socket's fd, so the connected vsock socket is adopted as a TcpStream. The connect itself is blocking,
+    // which is acceptable as it is a local connection to the enclave.
+    let vsock_stream = vsock::VsockStream::connect_with_cid_port(ENCLAVE_CID, port.into())?;
+    let std_stream = unsafe { std::net::TcpStream::from_raw_fd(vsock_stream.into_raw_fd()) };
+    Ok(TcpStream::from_std(std_stream))
+}
+
+/// Bidirectionally pipe two io_uring streams until both sides have closed.
+pub async fn pipe_streams(src: TcpStream, dest: TcpStream) -> std::io::Result<()> {
+    match tokio::try_join!(copy(&src, &dest), copy(&dest, &src)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copy from reader to writer until EOF, then shut down the writer to propagate the half-close.
+async fn copy(reader: &TcpStream, writer: &TcpStream) -> std::io::Result<u64> {
+    // tokio-uring reads into the uninitialised capacity of a Vec, so the buffer is cleared between reads
+    let mut buffer = Vec::with_capacity(URING_BUFFER_SIZE);
+    let mut total_bytes = 0;
+    loop {
+        let (read_result, read_buffer) = reader.read(buffer).await;
+        let n = read_result?;
+        if n == 0 {
+            writer.shutdown(Shutdown::Write)?;
+            return Ok(total_bytes);
+        }
+        let (write_result, written_buffer) = writer.write_all(read_buffer).await;
+        write_result?;
+        buffer = written_buffer;
+        buffer.clear();
+        total_bytes += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::pipe_streams;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use tokio_uring::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_pipe_streams_copies_both_directions() {
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let echo_server = std::thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+        });
+
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+            stream.write_all(b"ping").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+
+        tokio_uring::start(async move {
+            let listener = TcpListener::from_std(proxy);
+            let (inbound, _) = listener.accept().await.unwrap();
+            let outbound = TcpStream::connect(upstream_addr).await.unwrap();
+            pipe_streams(inbound, outbound).await.unwrap();
+        });
+
+        echo_server.join().unwrap();
+        assert_eq!(client.join().unwrap(), b"ping");
+    }
+}
@@ -0,0 +1,61 @@
+//! Runs the control plane and data plane in a single process, connected by in-memory streams in place of vsock, so
+//! enclave behaviour (ingress TLS, the crypto API and egress policy) can be exercised without Nitro hardware.
+//! Certs are issued by the control plane's mock provisioner, and encryption is handled by the data plane's mock
+//! crypto.
+use data_plane::FeatureContext;
+use shared::{dry_run, print_version};
+
+fn main() {
+    shared::logging::init_env_logger();
+    print_version!("Local Cage");
+
+    let data_plane_port_arg = dry_run::args().next();
+    let reports = [
+        control_plane::configuration::validate_config(),
+        data_plane::configuration::validate_startup_config(data_plane_port_arg.as_deref()),
+    ];
+    let data_plane_port = data_plane_port_arg
+        .and_then(|port_str| port_str.as_str().parse::<u16>().ok())
+        .unwrap_or(8008);
+    if dry_run::is_dry_run() {
+        let ctx = FeatureContext::set()
+            .and_then(|_| FeatureContext::get())
+            .ok();
+        dry_run::print_and_exit(
+            &reports,
+            serde_json::json!({
+                "control_plane": control_plane::configuration::effective_config(),
+                "data_plane": data_plane::configuration::effective_config(data_plane_port, ctx.as_ref()),
+            }),
+        );
+    }
+    reports.iter().for_each(|report| report.log());
+    if reports.iter().any(|report| report.has_fatal()) {
+        log::error!("Local cage cannot start until the errors above are fixed");
+        std::process::exit(1);
+    }
+
+    let ctx = match FeatureContext::set() {
+        Ok(_) => FeatureContext::get()
+            .expect("Infallible - feature context read after context is set successfully"),
+        Err(e) => {
+            log::error!("Failed to set context for local cage, cannot proceed - {e:?}");
+            return;
+        }
+    };
+
+    let runtime = ctx
+        .runtime
+        .build_multi_thread_runtime()
+        .expect("Failed to build tokio runtime in local cage");
+
+    runtime.block_on(async move {
+        let (control_plane_result, _) = tokio::join!(
+            control_plane::startup::run(),
+            data_plane::startup::run(data_plane_port, ctx)
+        );
+        if let Err(e) = control_plane_result {
+            log::error!("An error occurred within the control plane — {e:?}");
+        }
+    });
+}
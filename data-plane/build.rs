@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc_crypto_api")]
+    {
+        // Use a vendored protoc so the enclave build doesn't depend on the host toolchain
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        // Along with the well known types it ships with, for google.protobuf.Value payloads
+        std::env::set_var("PROTOC_INCLUDE", protoc_bin_vendored::include_path()?);
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/crypto.proto"], &["proto"])?;
+    }
+    Ok(())
+}
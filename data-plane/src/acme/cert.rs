@@ -16,7 +16,7 @@ use crate::{
     e3client::{CryptoRequest, CryptoResponse, E3Api, E3Client},
     server::tls::trusted_cert_container::TRUSTED_CERT_STORE,
     stats_client::StatsClient,
-    EnclaveContext,
+    EnclaveContext, FeatureContext,
 };
 
 use super::{
@@ -311,7 +311,10 @@ impl AcmeCertificateRetreiver {
             self.config_client.clone(),
         );
         if certificate_lock.write_and_check_persisted().await? {
-            let cert_domains = enclave_context.get_trusted_cert_domains();
+            let mut cert_domains = enclave_context.get_trusted_cert_domains();
+            if let Ok(feature_context) = FeatureContext::get() {
+                cert_domains.extend(feature_context.custom_domains);
+            }
 
             //Try twice with LetsEncrypt, then try with ZeroSSL
             let provider = if attempts <= 2 {
@@ -433,7 +436,7 @@ impl AcmeCertificateRetreiver {
                     ))?;
 
             self.config_client
-                .put_object(path.clone(), token_value)
+                .put_object(path.clone(), token_value.into())
                 .await?;
 
             let challenge_validated = challenge.validate().await?;
@@ -477,9 +480,7 @@ impl AcmeCertificateRetreiver {
         encrypted_raw_acme_certificate: &RawAcmeCertificate,
     ) -> Result<RawAcmeCertificate, AcmeError> {
         let e3_response: CryptoResponse = e3_client
-            .decrypt(CryptoRequest {
-                data: json!(encrypted_raw_acme_certificate),
-            })
+            .decrypt(CryptoRequest::new(json!(encrypted_raw_acme_certificate)))
             .await?;
 
         let decrypted_acme_key_pair: RawAcmeCertificate = serde_json::from_value(e3_response.data)?;
@@ -492,12 +493,7 @@ impl AcmeCertificateRetreiver {
         raw_acme_certificate: &RawAcmeCertificate,
     ) -> Result<RawAcmeCertificate, AcmeError> {
         let e3_response: CryptoResponse = e3_client
-            .encrypt(
-                CryptoRequest {
-                    data: json!(raw_acme_certificate),
-                },
-                None,
-            )
+            .encrypt(CryptoRequest::new(json!(raw_acme_certificate)), None)
             .await?;
 
         let encrypted_acme_key_pair: RawAcmeCertificate = serde_json::from_value(e3_response.data)?;
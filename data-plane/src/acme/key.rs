@@ -1,6 +1,7 @@
 use openssl::pkey::{PKey, Private};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use zeroize::Zeroizing;
 
 use crate::{
     config_client::{ConfigClient, StorageConfigClientInterface},
@@ -13,10 +14,19 @@ use shared::acme::helpers::gen_ec_private_key;
 const KEY_PAIR_LOCK_NAME: &str = "keypair";
 
 //Used for encrypting and storing the key pair for the public cert
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct RawAcmeKeyPair {
     pub public_key: String,
-    pub private_key: String,
+    pub private_key: Zeroizing<String>,
+}
+
+impl std::fmt::Debug for RawAcmeKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawAcmeKeyPair")
+            .field("public_key", &self.public_key)
+            .field("private_key", &"<redacted>")
+            .finish()
+    }
 }
 
 const PUBLIC_KEY_OBJECT_KEY: &str = "public_key.pem";
@@ -38,7 +48,7 @@ impl RawAcmeKeyPair {
         let private_key_bytes = key_pair.private_key_to_pem_pkcs8()?;
 
         let public_key_string = String::from_utf8(public_key_bytes)?;
-        let private_key_string = String::from_utf8(private_key_bytes)?;
+        let private_key_string = Zeroizing::new(String::from_utf8(private_key_bytes)?);
 
         let cert_materials = Self {
             public_key: public_key_string,
@@ -60,7 +70,7 @@ impl RawAcmeKeyPair {
             (Some(public_key_res), Some(private_key_res)) => {
                 let raw_acme_key_pair = Self {
                     public_key: public_key_res.body(),
-                    private_key: private_key_res.body(),
+                    private_key: Zeroizing::new(private_key_res.body()),
                 };
                 Ok(Some(raw_acme_key_pair))
             }
@@ -70,7 +80,7 @@ impl RawAcmeKeyPair {
 
     pub async fn persist(&self, config_client: ConfigClient) -> Result<(), AcmeError> {
         config_client
-            .put_object(PUBLIC_KEY_OBJECT_KEY.into(), self.public_key.clone())
+            .put_object(PUBLIC_KEY_OBJECT_KEY.into(), self.public_key.clone().into())
             .await?;
         config_client
             .put_object(PRIVATE_KEY_OBJECT_KEY.into(), self.private_key.clone())
@@ -186,9 +196,7 @@ impl AcmeKeyRetreiver {
         encrypted_raw_acme_key_pair: RawAcmeKeyPair,
     ) -> Result<RawAcmeKeyPair, AcmeError> {
         let e3_response: CryptoResponse = e3_client
-            .decrypt(CryptoRequest {
-                data: json!(encrypted_raw_acme_key_pair),
-            })
+            .decrypt(CryptoRequest::new(json!(encrypted_raw_acme_key_pair)))
             .await?;
 
         let decrypted_acme_key_pair: RawAcmeKeyPair = serde_json::from_value(e3_response.data)?;
@@ -201,12 +209,7 @@ impl AcmeKeyRetreiver {
         raw_acme_key_pair: RawAcmeKeyPair,
     ) -> Result<RawAcmeKeyPair, AcmeError> {
         let e3_response: CryptoResponse = e3_client
-            .encrypt(
-                CryptoRequest {
-                    data: json!(raw_acme_key_pair),
-                },
-                None,
-            )
+            .encrypt(CryptoRequest::new(json!(raw_acme_key_pair)), None)
             .await?;
 
         let encrypted_acme_key_pair: RawAcmeKeyPair = serde_json::from_value(e3_response.data)?;
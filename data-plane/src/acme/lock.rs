@@ -75,7 +75,7 @@ impl StorageLock {
     pub async fn write_lock(&self) -> Result<(), AcmeError> {
         let lock = serde_json::to_string(self)?;
         self.config_client
-            .put_object(self.lock_key_name(), lock)
+            .put_object(self.lock_key_name(), lock.into())
             .await?;
         Ok(())
     }
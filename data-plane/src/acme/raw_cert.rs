@@ -10,6 +10,7 @@ use openssl::{
 };
 
 use crate::config_client::{ConfigClient, StorageConfigClientInterface};
+use crate::utils::audit::AuditedSigningKey;
 
 use super::{error::AcmeError, utils};
 use serde::{Deserialize, Serialize};
@@ -106,7 +107,10 @@ impl RawAcmeCertificate {
         private_key: PKey<Private>,
     ) -> Result<CertifiedKey, AcmeError> {
         let der_encoded_private_key = private_key.private_key_to_der()?;
-        let ecdsa_private_key = sign::any_ecdsa_type(&PrivateKey(der_encoded_private_key))?;
+        let ecdsa_private_key = AuditedSigningKey::wrap(
+            sign::any_ecdsa_type(&PrivateKey(der_encoded_private_key))?,
+            "trusted_cert_key",
+        );
 
         let mut pem_certs = Vec::new();
         for cert in x509s.iter() {
@@ -129,7 +133,7 @@ impl RawAcmeCertificate {
         config_client
             .put_object(
                 utils::CERTIFICATE_OBJECT_KEY.into(),
-                self.certificate.clone(),
+                self.certificate.clone().into(),
             )
             .await?;
 
@@ -0,0 +1,281 @@
+use once_cell::sync::OnceCell;
+use openssl::sha::sha256;
+use openssl::x509::X509;
+use shared::server::config_server::requests::E3Identity;
+use std::time::SystemTime;
+use tokio_rustls::rustls::client::{ServerCertVerifier, WebPkiVerifier};
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerName},
+    Certificate, CertificateError, Error,
+};
+
+use super::tls_client_config::pinned_roots;
+
+static E3_TRUST: OnceCell<E3Trust> = OnceCell::new();
+
+/// Verifies that the E3 endpoint presents a certificate from the CA the provisioner pinned, or a key it has attested
+/// to, so requests carrying ciphertexts and attestation tokens are only ever sent to a genuine E3 instance. Nothing is
+/// sent to E3 until the provisioner has issued an identity for it.
+pub struct E3CertVerifier;
+
+impl E3CertVerifier {
+    /// Set the E3 identity issued by the provisioner. Only the first identity received is used.
+    pub fn set_identity(identity: E3Identity) {
+        if E3_TRUST.set(E3Trust::new(&identity)).is_err() {
+            log::debug!("E3 identity already set, ignoring");
+        }
+    }
+}
+
+/// What E3's certificate is checked against, built from the identity issued by the provisioner.
+struct E3Trust {
+    ca_verifier: Option<WebPkiVerifier>,
+    spki_sha256: Vec<String>,
+}
+
+impl E3Trust {
+    fn new(identity: &E3Identity) -> Self {
+        let ca_verifier = identity
+            .ca_cert_pem
+            .as_deref()
+            .map(|ca_cert_pem| WebPkiVerifier::new(pinned_roots(ca_cert_pem, "E3"), None));
+        Self {
+            ca_verifier,
+            spki_sha256: identity.spki_sha256.clone(),
+        }
+    }
+
+    fn verify(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if self.ca_verifier.is_none() && self.spki_sha256.is_empty() {
+            log::error!("E3 identity from the provisioner pins no CA or keys, refusing to connect");
+            return Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        if let Some(ca_verifier) = &self.ca_verifier {
+            // Checks the chain up to the pinned CA, the validity period and that the cert is for E3's hostname
+            ca_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                &mut std::iter::empty(),
+                &[],
+                now,
+            )?;
+        }
+        if !self.spki_sha256.is_empty() {
+            self.verify_spki(end_entity)?;
+        }
+        Ok(())
+    }
+
+    fn verify_spki(&self, end_entity: &Certificate) -> Result<(), Error> {
+        let spki_hash = spki_sha256(end_entity)?;
+        if self.spki_sha256.contains(&spki_hash) {
+            Ok(())
+        } else {
+            log::error!(
+                "E3 presented a key which was not issued by the provisioner, refusing to connect"
+            );
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+impl ServerCertVerifier for E3CertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        verify_with(E3_TRUST.get(), end_entity, intermediates, server_name, now)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// A chain which is otherwise valid is no evidence that the peer is E3, so without an identity every cert is rejected
+fn verify_with(
+    trust: Option<&E3Trust>,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    server_name: &ServerName,
+    now: SystemTime,
+) -> Result<(), Error> {
+    match trust {
+        Some(trust) => trust.verify(end_entity, intermediates, server_name, now),
+        None => {
+            log::error!("No E3 identity received from provisioner, refusing to connect");
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+pub(crate) fn spki_sha256(cert: &Certificate) -> Result<String, Error> {
+    let bad_encoding = || Error::InvalidCertificate(CertificateError::BadEncoding);
+    let cert = X509::from_der(&cert.0).map_err(|_| bad_encoding())?;
+    let spki = cert
+        .public_key()
+        .and_then(|key| key.public_key_to_der())
+        .map_err(|_| bad_encoding())?;
+    Ok(base64::encode(sha256(&spki)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{spki_sha256, verify_with, E3Trust};
+    use crate::configuration;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use shared::server::config_server::requests::E3Identity;
+    use std::time::SystemTime;
+    use tokio_rustls::rustls::{Certificate, ServerName};
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Issues a cert for E3's hostname, or a self signed CA when no issuer is given
+    fn issue_cert(key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let common_name = match issuer {
+            Some(_) => configuration::get_e3_host(),
+            None => "Test E3 CA".to_string(),
+        };
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", &common_name).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .dns(&common_name)
+                    .build(&builder.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        builder.build()
+    }
+
+    fn verify(identity: &E3Identity, cert: &Certificate) -> bool {
+        let server_name = ServerName::try_from(configuration::get_e3_host().as_str()).unwrap();
+        E3Trust::new(identity)
+            .verify(cert, &[], &server_name, SystemTime::now())
+            .is_ok()
+    }
+
+    fn der(cert: &X509) -> Certificate {
+        Certificate(cert.to_der().unwrap())
+    }
+
+    #[test]
+    fn test_accepts_key_issued_by_provisioner() {
+        let cert = der(&issue_cert(&generate_key(), None));
+        let identity = E3Identity {
+            spki_sha256: vec![spki_sha256(&cert).unwrap()],
+            ..Default::default()
+        };
+        assert!(verify(&identity, &cert));
+    }
+
+    #[test]
+    fn test_rejects_unknown_key() {
+        let identity = E3Identity {
+            spki_sha256: vec![spki_sha256(&der(&issue_cert(&generate_key(), None))).unwrap()],
+            ..Default::default()
+        };
+        assert!(!verify(&identity, &der(&issue_cert(&generate_key(), None))));
+    }
+
+    #[test]
+    fn test_rejects_malformed_cert() {
+        let identity = E3Identity {
+            spki_sha256: vec!["AAAA".to_string()],
+            ..Default::default()
+        };
+        assert!(!verify(&identity, &Certificate(vec![0; 16])));
+    }
+
+    #[test]
+    fn test_accepts_only_certs_issued_by_pinned_ca() {
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let identity = E3Identity {
+            ca_cert_pem: Some(String::from_utf8(ca_cert.to_pem().unwrap()).unwrap()),
+            ..Default::default()
+        };
+        let cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        assert!(verify(&identity, &der(&cert)));
+
+        let rogue_ca_key = generate_key();
+        let rogue_ca_cert = issue_cert(&rogue_ca_key, None);
+        let rogue_cert = issue_cert(&generate_key(), Some((&rogue_ca_cert, &rogue_ca_key)));
+        assert!(!verify(&identity, &der(&rogue_cert)));
+
+        // Pinning a key as well narrows the CA down to that key
+        let pinned_key = E3Identity {
+            spki_sha256: vec![spki_sha256(&der(&rogue_cert)).unwrap()],
+            ..identity
+        };
+        assert!(!verify(&pinned_key, &der(&cert)));
+    }
+
+    #[test]
+    fn test_identity_without_pins_rejects_all_certs() {
+        let cert = der(&issue_cert(&generate_key(), None));
+        assert!(!verify(&E3Identity::default(), &cert));
+    }
+
+    #[test]
+    fn test_missing_identity_rejects_all_certs() {
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let cert = der(&issue_cert(&generate_key(), Some((&ca_cert, &ca_key))));
+        let server_name = ServerName::try_from(configuration::get_e3_host().as_str()).unwrap();
+        assert!(verify_with(None, &cert, &[], &server_name, SystemTime::now()).is_err());
+    }
+}
@@ -1,5 +1,21 @@
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use thiserror::Error;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    Request,
+}
+
+impl std::fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect => write!(f, "connecting to the server"),
+            Self::Request => write!(f, "waiting for a response"),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ClientError {
     #[error("IO Error — {0:?}")]
@@ -10,6 +26,25 @@ pub enum ClientError {
     SerdeError(#[from] serde_json::Error),
     #[error("Request to server failed with status: {0:?}")]
     FailedRequest(hyper::StatusCode),
+    #[error("Response payload could not be verified — {0}")]
+    UnverifiedPayload(String),
+    #[error("Couldn't build request — {0}")]
+    InvalidRequest(#[from] hyper::http::Error),
+    #[error("Timed out {0}")]
+    Timeout(TimeoutKind),
     #[error("Client Error {0:?}")]
     General(String),
 }
+
+impl HasErrorCode for ClientError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::IoError(_) | Self::HyperError(_) => codes::CONNECTION_FAILED,
+            // Pass on E3's status, so clients can tell e.g. a rejected ciphertext from an outage
+            Self::FailedRequest(status) => codes::E3_REQUEST_FAILED.with_status(*status),
+            Self::SerdeError(_) | Self::UnverifiedPayload(_) => codes::E3_REQUEST_FAILED,
+            Self::Timeout(_) => codes::E3_TIMEOUT,
+            Self::InvalidRequest(_) | Self::General(_) => codes::INTERNAL,
+        }
+    }
+}
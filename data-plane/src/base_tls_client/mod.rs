@@ -1,24 +1,33 @@
+pub mod e3_cert_verifier;
 pub mod error;
-pub use error::ClientError;
+pub use error::{ClientError, TimeoutKind};
+pub mod pool;
 pub mod server_cert_verifier;
 pub mod tls_client_config;
+pub use e3_cert_verifier::E3CertVerifier;
 pub use server_cert_verifier::OpenServerCertVerifier;
 
-use hyper::client::conn::{Connection as HyperConnection, SendRequest};
 use hyper::header::HeaderValue;
 use hyper::{Body, HeaderMap, Response};
+use pool::{ConnectionPool, ConnectionPoolConfig, PooledConnection, TokioExecutor};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio_rustls::rustls::ServerName;
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::connection::{self, Connection};
 use crate::crypto::token::AttestationAuth;
 use shared::{CLIENT_MAJOR_VERSION, CLIENT_VERSION};
+use tls_client_config::HTTP2_ALPN_PROTOCOL;
 
 #[derive(Clone)]
 pub struct BaseClient {
     tls_connector: TlsConnector,
     server_name: ServerName,
     port: u16,
+    pool: Option<Arc<ConnectionPool>>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
 }
 
 #[derive(Clone)]
@@ -33,29 +42,62 @@ impl BaseClient {
             tls_connector,
             server_name,
             port,
+            pool: None,
+            connect_timeout: None,
+            request_timeout: None,
         }
     }
 
-    async fn get_conn(
-        &self,
-    ) -> Result<
-        (
-            SendRequest<hyper::Body>,
-            HyperConnection<TlsStream<Connection>, hyper::Body>,
-        ),
-        ClientError,
-    > {
-        let client_connection: Connection = connection::get_socket(self.port).await?;
-        let connection = self
-            .tls_connector
-            .connect(self.server_name.clone(), client_connection)
-            .await?;
+    /// Give up on connecting, including the TLS handshake, after `connect_timeout`, and on getting a response after
+    /// `request_timeout`.
+    pub fn with_timeouts(mut self, connect_timeout: Duration, request_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Reuse connections between requests. Clones of the client share the pool.
+    pub fn with_connection_pool(mut self, config: ConnectionPoolConfig) -> Self {
+        self.pool = Some(Arc::new(ConnectionPool::new(config)));
+        self
+    }
+
+    async fn get_tls_stream(&self) -> Result<TlsStream<Connection>, ClientError> {
+        with_timeout(self.connect_timeout, TimeoutKind::Connect, async {
+            let client_connection: Connection = connection::get_socket(self.port).await?;
+            let connection = self
+                .tls_connector
+                .connect(self.server_name.clone(), client_connection)
+                .await?;
+            Ok(connection)
+        })
+        .await
+    }
 
-        let connection_info = hyper::client::conn::Builder::new()
+    /// Connect to the server, speaking HTTP/2 if it was agreed on with ALPN. HTTP/2 connections are shared with the
+    /// pool, so concurrent requests are multiplexed over them.
+    async fn connect(&self) -> Result<PooledConnection, ClientError> {
+        let connection = self.get_tls_stream().await?;
+        if connection.get_ref().1.alpn_protocol() == Some(HTTP2_ALPN_PROTOCOL) {
+            let (request_sender, connection) =
+                hyper::client::conn::http2::handshake(TokioExecutor, connection).await?;
+            spawn_connection(connection);
+            if let Some(pool) = &self.pool {
+                pool.share(&request_sender);
+            }
+            return Ok(PooledConnection::Multiplexed(request_sender));
+        }
+
+        let (request_sender, connection) = hyper::client::conn::Builder::new()
             .handshake::<TlsStream<Connection>, hyper::Body>(connection)
             .await?;
+        spawn_connection(connection);
+        Ok(PooledConnection::Exclusive(request_sender))
+    }
 
-        Ok(connection_info)
+    /// Connect and complete the TLS handshake without sending a request, to check the server can be reached.
+    pub async fn check_connection(&self) -> Result<(), ClientError> {
+        self.get_tls_stream().await.map(|_| ())
     }
 
     pub async fn send(
@@ -85,25 +127,37 @@ impl BaseClient {
                 format!("application/json;version={}", &*CLIENT_MAJOR_VERSION),
             )
             .method(method)
-            .body(payload)
-            .expect("Failed to create request");
+            .body(payload)?;
 
         auth_type.map(|auth| match auth {
-            AuthType::ApiKey(header_value) => request.headers_mut().insert("api-key", header_value),
-            AuthType::AttestationDoc(auth) => {
+            AuthType::ApiKey(mut header_value) => {
+                header_value.set_sensitive(true);
+                request.headers_mut().insert("api-key", header_value)
+            }
+            AuthType::AttestationDoc(mut auth) => {
+                auth.token.set_sensitive(true);
                 request.headers_mut().insert("attestation-token", auth.doc);
                 request.headers_mut().insert("auth-token", auth.token)
             }
         });
 
-        let (mut request_sender, connection) = self.get_conn().await?;
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                log::error!("Error in client connection: {e}");
-            }
-        });
+        let mut connection = match self.pool.as_ref().and_then(|pool| pool.checkout()) {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
 
-        let response = request_sender.send_request(request).await?;
+        let response = with_timeout(self.request_timeout, TimeoutKind::Request, async {
+            Ok(connection.send_request(request).await?)
+        })
+        .await;
+        // A connection which timed out may still be stuck on the request, so it isn't reused
+        let timed_out = matches!(response, Err(ClientError::Timeout(_)));
+        if let (Some(pool), PooledConnection::Exclusive(sender), false) =
+            (&self.pool, connection, timed_out)
+        {
+            pool.checkin(sender);
+        }
+        let response = response?;
         if !response.status().is_success() {
             return Err(ClientError::FailedRequest(response.status()));
         }
@@ -111,3 +165,50 @@ impl BaseClient {
         Ok(response)
     }
 }
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    kind: TimeoutKind,
+    future: impl std::future::Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ClientError::Timeout(kind))?,
+        None => future.await,
+    }
+}
+
+fn spawn_connection<F>(connection: F)
+where
+    F: std::future::Future<Output = hyper::Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::error!("Error in client connection: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{with_timeout, ClientError, TimeoutKind};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_slow_requests_time_out() {
+        let result = with_timeout(
+            Some(Duration::from_millis(10)),
+            TimeoutKind::Request,
+            std::future::pending::<Result<(), ClientError>>(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Timeout(TimeoutKind::Request))
+        ));
+
+        let result = with_timeout(None, TimeoutKind::Connect, async { Ok(()) }).await;
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,375 @@
+//! Connection pooling for TLS clients. HTTP/1 connections are handed back once their response has been read, so busy
+//! enclaves reuse them instead of paying for a handshake on every request. HTTP/2 connections are shared, with
+//! concurrent requests multiplexed over one connection. Connections left unused for longer than the idle timeout are
+//! closed.
+use futures::FutureExt;
+use hyper::client::conn::{http2, SendRequest};
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Idle connections are checked for reaping at most this often
+const MIN_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionPoolConfig {
+    /// Idle connections kept open for reuse, pooling is off when this is 0
+    pub max_idle_connections: usize,
+    /// Seconds a connection can go unused before it's closed
+    pub idle_timeout_secs: u64,
+    /// Offer HTTP/2 when connecting, so requests can share a connection
+    pub http2: bool,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_connections: 16,
+            idle_timeout_secs: 30,
+            http2: true,
+        }
+    }
+}
+
+struct IdleConnection {
+    sender: SendRequest<Body>,
+    idle_since: Instant,
+}
+
+struct SharedConnection {
+    sender: http2::SendRequest<Body>,
+    idle_since: Instant,
+}
+
+/// A connection to send a request on. Multiplexed connections are shared by the pool, so they aren't handed back.
+pub enum PooledConnection {
+    Exclusive(SendRequest<Body>),
+    Multiplexed(http2::SendRequest<Body>),
+}
+
+impl PooledConnection {
+    pub async fn send_request(&mut self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+        match self {
+            Self::Exclusive(sender) => sender.send_request(request).await,
+            Self::Multiplexed(sender) => sender.send_request(request).await,
+        }
+    }
+
+    pub fn is_multiplexed(&self) -> bool {
+        matches!(self, Self::Multiplexed(_))
+    }
+}
+
+/// Spawns the tasks driving HTTP/2 connections.
+#[derive(Clone, Copy, Debug)]
+pub struct TokioExecutor;
+
+impl<F> hyper::rt::Executor<F> for TokioExecutor
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        tokio::spawn(future);
+    }
+}
+
+pub struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    idle: Mutex<VecDeque<IdleConnection>>,
+    // The HTTP/2 connection requests are multiplexed over, with when it was last used
+    multiplexed: Mutex<Option<SharedConnection>>,
+    reaper_started: AtomicBool,
+}
+
+impl ConnectionPool {
+    pub fn new(config: ConnectionPoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(VecDeque::new()),
+            multiplexed: Mutex::new(None),
+            reaper_started: AtomicBool::new(false),
+        }
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.idle_timeout_secs)
+    }
+
+    fn is_expired(&self, idle_since: Instant) -> bool {
+        idle_since.elapsed() >= self.idle_timeout()
+    }
+
+    /// Take a connection to send a request on. The shared HTTP/2 connection is used if there is one, otherwise the
+    /// most recently used idle connection which is still open. Connections idle for too long are closed.
+    pub fn checkout(&self) -> Option<PooledConnection> {
+        self.checkout_multiplexed()
+            .map(PooledConnection::Multiplexed)
+            .or_else(|| self.checkout_idle().map(PooledConnection::Exclusive))
+    }
+
+    fn checkout_multiplexed(&self) -> Option<http2::SendRequest<Body>> {
+        let mut multiplexed = self
+            .multiplexed
+            .lock()
+            .expect("Connection pool lock poisoned");
+        let connection = multiplexed.as_mut()?;
+        let ready = std::future::poll_fn(|cx| connection.sender.poll_ready(cx)).now_or_never();
+        if self.is_expired(connection.idle_since) || matches!(ready, Some(Err(_))) {
+            *multiplexed = None;
+            return None;
+        }
+        connection.idle_since = Instant::now();
+        Some(connection.sender.clone())
+    }
+
+    fn checkout_idle(&self) -> Option<SendRequest<Body>> {
+        let mut idle = self.idle.lock().expect("Connection pool lock poisoned");
+        while let Some(mut connection) = idle.pop_back() {
+            if self.is_expired(connection.idle_since) {
+                continue;
+            }
+            // Pooled connections were ready when they were returned, so anything else means they've since closed
+            let ready = std::future::poll_fn(|cx| connection.sender.poll_ready(cx)).now_or_never();
+            if let Some(Ok(())) = ready {
+                return Some(connection.sender);
+            }
+        }
+        None
+    }
+
+    /// Hand a connection back once the response to its last request has been read. Connections which close first
+    /// are dropped.
+    pub fn checkin(self: &Arc<Self>, mut sender: SendRequest<Body>) {
+        if self.config.max_idle_connections == 0 {
+            return;
+        }
+        self.start_reaper();
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            if std::future::poll_fn(|cx| sender.poll_ready(cx))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            if let Some(pool) = pool.upgrade() {
+                pool.push(sender);
+            }
+        });
+    }
+
+    /// Share an HTTP/2 connection, so later requests are multiplexed over it rather than opening their own.
+    pub fn share(self: &Arc<Self>, sender: &http2::SendRequest<Body>) {
+        if self.config.max_idle_connections == 0 {
+            return;
+        }
+        self.start_reaper();
+        *self
+            .multiplexed
+            .lock()
+            .expect("Connection pool lock poisoned") = Some(SharedConnection {
+            sender: sender.clone(),
+            idle_since: Instant::now(),
+        });
+    }
+
+    fn push(&self, sender: SendRequest<Body>) {
+        let mut idle = self.idle.lock().expect("Connection pool lock poisoned");
+        if idle.len() >= self.config.max_idle_connections {
+            idle.pop_front();
+        }
+        idle.push_back(IdleConnection {
+            sender,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Close the connections which have been idle too long, returning how many were closed.
+    fn reap(&self) -> usize {
+        let mut multiplexed = self
+            .multiplexed
+            .lock()
+            .expect("Connection pool lock poisoned");
+        let mut reaped = 0;
+        if multiplexed
+            .as_ref()
+            .is_some_and(|connection| self.is_expired(connection.idle_since))
+        {
+            *multiplexed = None;
+            reaped += 1;
+        }
+        let mut idle = self.idle.lock().expect("Connection pool lock poisoned");
+        let before = idle.len();
+        idle.retain(|connection| !self.is_expired(connection.idle_since));
+        reaped + before - idle.len()
+    }
+
+    fn start_reaper(self: &Arc<Self>) {
+        if self.reaper_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let pool = Arc::downgrade(self);
+        let period = self.idle_timeout().max(MIN_REAP_INTERVAL);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    return;
+                };
+                let reaped = pool.reap();
+                if reaped > 0 {
+                    log::debug!("Closed {reaped} idle pooled connection(s)");
+                }
+            }
+        });
+    }
+
+    pub fn idle_connections(&self) -> usize {
+        self.idle
+            .lock()
+            .expect("Connection pool lock poisoned")
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConnectionPool, ConnectionPoolConfig, PooledConnection, TokioExecutor};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn connect() -> hyper::client::conn::SendRequest<Body> {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    async fn connect_http2() -> (
+        hyper::client::conn::http2::SendRequest<Body>,
+        tokio::task::JoinHandle<hyper::Result<()>>,
+    ) {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (sender, connection) = hyper::client::conn::http2::handshake(TokioExecutor, stream)
+            .await
+            .unwrap();
+        (sender, tokio::spawn(connection))
+    }
+
+    async fn send(connection: &mut PooledConnection) {
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = connection.send_request(request).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+
+    async fn wait_for_idle(pool: &ConnectionPool, expected: usize) {
+        for _ in 0..100 {
+            if pool.idle_connections() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("Pool never had {expected} idle connection(s)");
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_reused() {
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig::default()));
+        let mut connection = PooledConnection::Exclusive(connect().await);
+        send(&mut connection).await;
+        let PooledConnection::Exclusive(sender) = connection else {
+            unreachable!()
+        };
+        pool.checkin(sender);
+        wait_for_idle(&pool, 1).await;
+
+        let mut connection = pool.checkout().expect("Pooled connection");
+        assert!(!connection.is_multiplexed());
+        assert_eq!(pool.idle_connections(), 0);
+        send(&mut connection).await;
+        assert!(pool.checkout().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http2_connections_are_shared() {
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig::default()));
+        let (sender, connection) = connect_http2().await;
+        pool.share(&sender);
+
+        let mut first = pool.checkout().expect("Shared connection");
+        let mut second = pool.checkout().expect("Shared connection");
+        assert!(first.is_multiplexed() && second.is_multiplexed());
+        tokio::join!(send(&mut first), send(&mut second));
+        assert_eq!(pool.idle_connections(), 0);
+
+        // Once the connection closes, new requests have to open their own
+        connection.abort();
+        let _ = connection.await;
+        assert!(pool.checkout().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_are_capped() {
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig {
+            max_idle_connections: 1,
+            ..Default::default()
+        }));
+        pool.checkin(connect().await);
+        pool.checkin(connect().await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.idle_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_connections_are_closed() {
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig {
+            idle_timeout_secs: 0,
+            ..Default::default()
+        }));
+        pool.checkin(connect().await);
+        wait_for_idle(&pool, 1).await;
+        assert!(pool.checkout().is_none());
+
+        pool.checkin(connect().await);
+        wait_for_idle(&pool, 1).await;
+        assert_eq!(pool.reap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pooling_can_be_switched_off() {
+        let pool = Arc::new(ConnectionPool::new(ConnectionPoolConfig {
+            max_idle_connections: 0,
+            ..Default::default()
+        }));
+        pool.checkin(connect().await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pool.idle_connections(), 0);
+    }
+}
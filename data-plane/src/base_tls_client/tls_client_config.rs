@@ -1,7 +1,11 @@
+use openssl::x509::X509;
 use std::sync::Arc;
 
 use tokio_rustls::rustls::client::ServerCertVerifier;
-use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor};
+use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+pub const HTTP2_ALPN_PROTOCOL: &[u8] = b"h2";
+const HTTP1_ALPN_PROTOCOL: &[u8] = b"http/1.1";
 
 pub fn get_tls_client_config(verifier: Arc<dyn ServerCertVerifier>) -> ClientConfig {
     let config_builder = tokio_rustls::rustls::ClientConfig::builder().with_safe_defaults();
@@ -20,3 +24,32 @@ pub fn get_tls_client_config(verifier: Arc<dyn ServerCertVerifier>) -> ClientCon
     dangerous.set_certificate_verifier(verifier);
     client_config
 }
+
+/// Offer HTTP/2 during the handshake, falling back to HTTP/1.1 for servers which don't support it.
+pub fn offer_http2(mut client_config: ClientConfig) -> ClientConfig {
+    client_config.alpn_protocols = vec![HTTP2_ALPN_PROTOCOL.to_vec(), HTTP1_ALPN_PROTOCOL.to_vec()];
+    client_config
+}
+
+/// Roots for a CA pinned by PEM, logged against `server`. An unusable CA leaves the root store empty, so every
+/// cert is rejected rather than trusted.
+pub fn pinned_roots(ca_cert_pem: &str, server: &str) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    let ca_certs = match X509::stack_from_pem(ca_cert_pem.as_bytes()) {
+        Ok(ca_certs) => ca_certs,
+        Err(e) => {
+            log::error!("Failed to parse pinned {server} CA - {e}");
+            return roots;
+        }
+    };
+    for ca_cert in ca_certs {
+        let added = ca_cert
+            .to_der()
+            .map_err(|e| e.to_string())
+            .and_then(|der| roots.add(&Certificate(der)).map_err(|e| e.to_string()));
+        if let Err(e) = added {
+            log::error!("Failed to add pinned {server} CA - {e}");
+        }
+    }
+    roots
+}
@@ -1,10 +1,14 @@
-use cached::TimedSizedCache;
-use once_cell::sync::Lazy;
+use cached::{Cached, TimedSizedCache};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use tokio::sync::Mutex;
 
 use crate::crypto::token::AttestationAuth;
 
 const E3_TOKEN_LIFETIME: u64 = 280;
+const APP_PUBLIC_KEY_LIFETIME: u64 = 3600;
 
 pub static E3_TOKEN: Lazy<Mutex<TimedSizedCache<String, AttestationAuth>>> = Lazy::new(|| {
     Mutex::new(TimedSizedCache::with_size_and_lifespan(
@@ -12,3 +16,246 @@ pub static E3_TOKEN: Lazy<Mutex<TimedSizedCache<String, AttestationAuth>>> = Laz
         E3_TOKEN_LIFETIME,
     ))
 });
+
+/// The app's public key from E3, refreshed hourly so rotated keys are picked up.
+pub static APP_PUBLIC_KEY: Lazy<Mutex<TimedSizedCache<String, String>>> = Lazy::new(|| {
+    Mutex::new(TimedSizedCache::with_size_and_lifespan(
+        1,
+        APP_PUBLIC_KEY_LIFETIME,
+    ))
+});
+
+/// Opt-in cache of decrypt results in the Crypto API, only initialized when configured.
+pub static DECRYPT_CACHE: OnceCell<DecryptCache> = OnceCell::new();
+
+/// Opt-in cache of successfully authenticated api keys, only initialized when configured.
+pub static AUTH_CACHE: OnceCell<AuthCache> = OnceCell::new();
+
+/// Random per-process salt mixed into every api key hash held in memory, so cached hashes can't be matched
+/// against precomputed hashes of known keys.
+static API_KEY_SALT: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// Salted hash of an api key. Caches only ever hold these hashes, never the api key itself.
+pub fn hash_api_key(api_key: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(API_KEY_SALT.as_slice());
+    hasher.update(api_key);
+    hasher.finalize().into()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DecryptCacheConfig {
+    pub ttl_seconds: u64,
+    pub max_entries: usize,
+}
+
+/// Bounded, TTL-limited cache of decrypted payloads keyed by a hash of the ciphertext payload and api key.
+/// Plaintexts are only ever held in enclave memory, and expire after the configured TTL.
+pub struct DecryptCache {
+    inner: Mutex<TimedSizedCache<[u8; 32], Value>>,
+}
+
+impl DecryptCache {
+    pub fn new(config: &DecryptCacheConfig) -> Self {
+        Self {
+            inner: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                config.max_entries,
+                config.ttl_seconds,
+            )),
+        }
+    }
+
+    pub fn cache_key(api_key: Option<&[u8]>, payload: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        match api_key {
+            Some(api_key) => {
+                hasher.update([1]);
+                hasher.update(hash_api_key(api_key));
+            }
+            None => hasher.update([0]),
+        }
+        hasher.update(payload);
+        hasher.finalize().into()
+    }
+
+    pub async fn get(&self, key: &[u8; 32]) -> Option<Value> {
+        self.inner.lock().await.cache_get(key).cloned()
+    }
+
+    pub async fn insert(&self, key: [u8; 32], plaintext: Value) {
+        self.inner.lock().await.cache_set(key, plaintext);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AuthCacheConfig {
+    pub ttl_seconds: u64,
+    pub max_entries: usize,
+    /// How long api keys which E3 rejected are rejected for without asking E3 again. Rejections aren't cached unless
+    /// this is set, and it should be kept short so newly created keys work quickly.
+    #[serde(default)]
+    pub negative_ttl_seconds: Option<u64>,
+}
+
+/// Bounded, TTL-limited cache of api keys which recently authenticated successfully, to avoid a round trip to
+/// E3 for every request. Entries are indexed by a prefix of the salted key hash, and the full hash is checked in
+/// constant time so lookups don't leak how closely a presented key matches a cached one. Rejected keys are cached
+/// separately when configured, so a client retrying with a bad key doesn't cost an E3 request each time.
+pub struct AuthCache {
+    inner: Mutex<TimedSizedCache<u64, [u8; 32]>>,
+    rejected: Option<Mutex<TimedSizedCache<u64, [u8; 32]>>>,
+}
+
+impl AuthCache {
+    pub fn new(config: &AuthCacheConfig) -> Self {
+        Self {
+            inner: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                config.max_entries,
+                config.ttl_seconds,
+            )),
+            rejected: config.negative_ttl_seconds.map(|ttl_seconds| {
+                Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                    config.max_entries,
+                    ttl_seconds,
+                ))
+            }),
+        }
+    }
+
+    pub async fn is_authenticated(&self, api_key: &[u8]) -> bool {
+        Self::contains(&self.inner, api_key).await
+    }
+
+    pub async fn insert(&self, api_key: &[u8]) {
+        Self::set(&self.inner, api_key).await;
+    }
+
+    pub async fn is_rejected(&self, api_key: &[u8]) -> bool {
+        match &self.rejected {
+            Some(rejected) => Self::contains(rejected, api_key).await,
+            None => false,
+        }
+    }
+
+    pub async fn insert_rejected(&self, api_key: &[u8]) {
+        if let Some(rejected) = &self.rejected {
+            Self::set(rejected, api_key).await;
+        }
+    }
+
+    async fn contains(cache: &Mutex<TimedSizedCache<u64, [u8; 32]>>, api_key: &[u8]) -> bool {
+        let api_key_hash = hash_api_key(api_key);
+        match cache.lock().await.cache_get(&Self::index(&api_key_hash)) {
+            Some(cached_hash) => openssl::memcmp::eq(cached_hash, &api_key_hash),
+            None => false,
+        }
+    }
+
+    async fn set(cache: &Mutex<TimedSizedCache<u64, [u8; 32]>>, api_key: &[u8]) {
+        let api_key_hash = hash_api_key(api_key);
+        cache
+            .lock()
+            .await
+            .cache_set(Self::index(&api_key_hash), api_key_hash);
+    }
+
+    fn index(api_key_hash: &[u8; 32]) -> u64 {
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&api_key_hash[..8]);
+        u64::from_be_bytes(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash_api_key, AuthCache, AuthCacheConfig, DecryptCache, DecryptCacheConfig};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    fn test_cache() -> DecryptCache {
+        DecryptCache::new(&DecryptCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 2,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_cached_plaintext_returned_for_same_payload_and_key() {
+        let cache = test_cache();
+        let key = DecryptCache::cache_key(Some(b"api-key"), b"ev:ciphertext");
+        cache.insert(key, json!("plaintext")).await;
+        let same_key = DecryptCache::cache_key(Some(b"api-key"), b"ev:ciphertext");
+        assert_eq!(cache.get(&same_key).await, Some(json!("plaintext")));
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_partitioned_by_api_key() {
+        let cache = test_cache();
+        let key = DecryptCache::cache_key(Some(b"api-key"), b"ev:ciphertext");
+        cache.insert(key, json!("plaintext")).await;
+        let other_key = DecryptCache::cache_key(Some(b"other-key"), b"ev:ciphertext");
+        let no_key = DecryptCache::cache_key(None, b"ev:ciphertext");
+        assert!(cache.get(&other_key).await.is_none());
+        assert!(cache.get(&no_key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let cache = test_cache();
+        for i in 0..3u8 {
+            let key = DecryptCache::cache_key(None, &[i]);
+            cache.insert(key, json!(i)).await;
+        }
+        assert!(cache
+            .get(&DecryptCache::cache_key(None, &[0]))
+            .await
+            .is_none());
+        assert!(cache
+            .get(&DecryptCache::cache_key(None, &[2]))
+            .await
+            .is_some());
+    }
+
+    #[test]
+    fn test_api_key_hash_is_salted() {
+        let mut unsalted = Sha256::new();
+        unsalted.update(b"api-key");
+        let unsalted: [u8; 32] = unsalted.finalize().into();
+        assert_eq!(hash_api_key(b"api-key"), hash_api_key(b"api-key"));
+        assert_ne!(hash_api_key(b"api-key"), unsalted);
+    }
+
+    #[tokio::test]
+    async fn test_auth_cache_only_authenticates_cached_keys() {
+        let cache = AuthCache::new(&AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: None,
+        });
+        assert!(!cache.is_authenticated(b"api-key").await);
+        cache.insert(b"api-key").await;
+        assert!(cache.is_authenticated(b"api-key").await);
+        assert!(!cache.is_authenticated(b"other-key").await);
+    }
+
+    #[tokio::test]
+    async fn test_auth_cache_only_rejects_keys_when_configured() {
+        let cache = AuthCache::new(&AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: None,
+        });
+        cache.insert_rejected(b"api-key").await;
+        assert!(!cache.is_rejected(b"api-key").await);
+
+        let cache = AuthCache::new(&AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: Some(5),
+        });
+        cache.insert_rejected(b"api-key").await;
+        assert!(cache.is_rejected(b"api-key").await);
+        assert!(!cache.is_authenticated(b"api-key").await);
+        assert!(!cache.is_rejected(b"other-key").await);
+    }
+}
@@ -1,6 +1,11 @@
+pub mod reattestation;
 mod tls_verifier;
+pub use tls_verifier::ProvisionerIdentityConfig;
 
 use hyper::{Body, Response};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Verifier;
 use serde::de::DeserializeOwned;
 use shared::server::config_server::requests::{
     ConfigServerPayload, GetCertRequestDataPlane, GetCertResponseDataPlane,
@@ -14,11 +19,16 @@ use crate::base_tls_client::{BaseClient, ClientError};
 use crate::configuration;
 #[cfg(feature = "enclave")]
 use crate::crypto::attest;
+use crate::FeatureContext;
 
 type CertProvisionerError = ClientError;
+
+const PAYLOAD_SIGNATURE_HEADER: &str = "x-evervault-signature";
+
 #[derive(Clone)]
 pub struct CertProvisionerClient {
     base_client: BaseClient,
+    payload_signing_key_pem: Option<String>,
 }
 
 impl Default for CertProvisionerClient {
@@ -29,7 +39,12 @@ impl Default for CertProvisionerClient {
 
 impl CertProvisionerClient {
     pub fn new() -> Self {
-        let verifier = std::sync::Arc::new(tls_verifier::CertProvisionerCertVerifier);
+        let identity = FeatureContext::get()
+            .ok()
+            .and_then(|context| context.provisioner_identity);
+        let verifier = std::sync::Arc::new(tls_verifier::CertProvisionerCertVerifier::new(
+            identity.as_ref(),
+        ));
         let tls_connector =
             TlsConnector::from(std::sync::Arc::new(get_tls_client_config(verifier)));
 
@@ -38,6 +53,7 @@ impl CertProvisionerClient {
 
         Self {
             base_client: BaseClient::new(tls_connector, server_name, shared::ENCLAVE_CERT_PORT),
+            payload_signing_key_pem: identity.and_then(|identity| identity.payload_signing_key_pem),
         }
     }
 
@@ -101,12 +117,84 @@ impl CertProvisionerClient {
         self.parse_response(response).await
     }
 
+    /// Submit a fresh attestation doc for the provisioner to check against the enclave's expected measurements. Any
+    /// rejection is returned as a failed request.
+    pub async fn reattest(&self, token: String) -> Result<(), CertProvisionerError> {
+        let attestation_doc = self.get_attestation_doc(token)?;
+
+        let body = GetCertRequestDataPlane::new(attestation_doc)
+            .into_body()
+            .map_err(|err| CertProvisionerError::General(err.to_string()))?;
+
+        self.base_client
+            .send(None, "POST", &self.uri("/attest"), body, None)
+            .await?;
+        Ok(())
+    }
+
     async fn parse_response<T: DeserializeOwned>(
         &self,
         res: Response<Body>,
     ) -> Result<T, CertProvisionerError> {
+        let signature = res.headers().get(PAYLOAD_SIGNATURE_HEADER).cloned();
         let response_body = res.into_body();
         let response_body = hyper::body::to_bytes(response_body).await?;
+        if let Some(signing_key_pem) = &self.payload_signing_key_pem {
+            let signature = signature.ok_or_else(|| {
+                CertProvisionerError::UnverifiedPayload("Missing payload signature".to_string())
+            })?;
+            verify_payload_signature(signing_key_pem, signature.as_bytes(), &response_body)?;
+        }
         Ok(serde_json::from_slice(&response_body)?)
     }
 }
+
+/// Verify the base64 encoded SHA-256 signature the provisioner made over a response body with its pinned key.
+fn verify_payload_signature(
+    signing_key_pem: &str,
+    encoded_signature: &[u8],
+    payload: &[u8],
+) -> Result<(), CertProvisionerError> {
+    let unverified = |reason: String| CertProvisionerError::UnverifiedPayload(reason);
+    let signing_key = PKey::public_key_from_pem(signing_key_pem.as_bytes())
+        .map_err(|e| unverified(format!("Invalid pinned signing key - {e}")))?;
+    let signature = base64::decode(encoded_signature)
+        .map_err(|e| unverified(format!("Invalid payload signature encoding - {e}")))?;
+    let is_valid = Verifier::new(MessageDigest::sha256(), &signing_key)
+        .and_then(|mut verifier| verifier.verify_oneshot(&signature, payload))
+        .unwrap_or(false);
+    if is_valid {
+        Ok(())
+    } else {
+        Err(unverified("Invalid payload signature".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify_payload_signature;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::sign::Signer;
+
+    #[test]
+    fn test_payload_signature_verification() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let signing_key_pem = String::from_utf8(key.public_key_to_pem().unwrap()).unwrap();
+        let payload = br#"{"secrets":[]}"#;
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        let signature = base64::encode(signer.sign_oneshot_to_vec(payload).unwrap());
+        assert!(verify_payload_signature(&signing_key_pem, signature.as_bytes(), payload).is_ok());
+        assert!(verify_payload_signature(
+            &signing_key_pem,
+            signature.as_bytes(),
+            br#"{"secrets":[{"name":"injected"}]}"#
+        )
+        .is_err());
+        assert!(verify_payload_signature(&signing_key_pem, b"not base64!", payload).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use tokio::time::{self, Duration, MissedTickBehavior};
+
+use super::CertProvisionerClient;
+use crate::config_client::ConfigClient;
+use crate::error::Error;
+use crate::stats_client::StatsClient;
+
+/// Re-attest to the cert provisioner on an interval for as long as the enclave runs. The enclave attested when it
+/// fetched its cert on startup, so the first fresh doc is only sent after one interval.
+pub async fn run(interval: Duration) {
+    let config_client = ConfigClient::new();
+    let cert_provisioner_client = CertProvisionerClient::new();
+    let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    log::info!(
+        "Re-attesting to the cert provisioner every {}s",
+        interval.as_secs()
+    );
+    loop {
+        ticker.tick().await;
+        let result = reattest(&config_client, &cert_provisioner_client).await;
+        StatsClient::record_reattestation(result.is_ok());
+        match result {
+            Ok(()) => log::debug!("Re-attested to the cert provisioner"),
+            Err(e) => log::error!("Failed to re-attest to the cert provisioner - {e}"),
+        }
+    }
+}
+
+async fn reattest(
+    config_client: &ConfigClient,
+    cert_provisioner_client: &CertProvisionerClient,
+) -> Result<(), Error> {
+    let token = config_client.get_cert_token().await?.token();
+    cert_provisioner_client.reattest(token).await?;
+    Ok(())
+}
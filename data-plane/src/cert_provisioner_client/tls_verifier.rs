@@ -1,32 +1,255 @@
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "enclave"))]
+use std::sync::Once;
 use std::time::SystemTime;
-use tokio_rustls::rustls::client::ServerCertVerifier;
+use tokio_rustls::rustls::client::{ServerCertVerifier, WebPkiVerifier};
 use tokio_rustls::rustls::{
     client::{ServerCertVerified, ServerName},
     Certificate, CertificateError, Error,
 };
 
+use crate::base_tls_client::e3_cert_verifier::spki_sha256;
+use crate::base_tls_client::tls_client_config::pinned_roots;
 use crate::configuration;
 
-pub struct CertProvisionerCertVerifier;
+#[cfg(not(feature = "enclave"))]
+static MISSING_IDENTITY_WARNING: Once = Once::new();
+
+/// Identity the provisioner must prove before the enclave accepts certs or secrets from it. The pins are part of the
+/// enclave image, so they're covered by its attestation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProvisionerIdentityConfig {
+    /// PEM encoded CA which must issue the provisioner's certificate
+    pub ca_cert_pem: Option<String>,
+    /// Base64 encoded SHA-256 hashes of the provisioner keys to accept
+    #[serde(default)]
+    pub spki_sha256: Vec<String>,
+    /// PEM encoded public key which must sign the provisioner's response bodies
+    pub payload_signing_key_pem: Option<String>,
+}
+
+pub struct CertProvisionerCertVerifier {
+    ca_verifier: Option<WebPkiVerifier>,
+    spki_sha256: Vec<String>,
+}
+
+impl CertProvisionerCertVerifier {
+    pub fn new(identity: Option<&ProvisionerIdentityConfig>) -> Self {
+        let ca_verifier = identity
+            .and_then(|identity| identity.ca_cert_pem.as_deref())
+            .map(|ca_cert_pem| WebPkiVerifier::new(pinned_roots(ca_cert_pem, "provisioner"), None));
+        let spki_sha256 = identity
+            .map(|identity| identity.spki_sha256.clone())
+            .unwrap_or_default();
+        Self {
+            ca_verifier,
+            spki_sha256,
+        }
+    }
+
+    fn verify_spki(&self, end_entity: &Certificate) -> Result<(), Error> {
+        let spki_hash = spki_sha256(end_entity)?;
+        if self.spki_sha256.contains(&spki_hash) {
+            Ok(())
+        } else {
+            log::error!("Provisioner presented a key which is not pinned, refusing to connect");
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
 
 impl ServerCertVerifier for CertProvisionerCertVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &Certificate,
-        _intermediates: &[Certificate],
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
         server_name: &ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: SystemTime,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
     ) -> Result<ServerCertVerified, Error> {
         let cert_provisioner_hostname = configuration::get_cert_provisioner_host();
         let expected_server_name =
             ServerName::try_from(cert_provisioner_hostname.as_str()).expect("Infallible");
 
-        if &expected_server_name == server_name {
-            Ok(ServerCertVerified::assertion())
+        if &expected_server_name != server_name {
+            return Err(Error::InvalidCertificate(CertificateError::NotValidForName));
+        }
+
+        if let Some(ca_verifier) = &self.ca_verifier {
+            ca_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.spki_sha256.is_empty() {
+            self.verify_spki(end_entity)?;
+        }
+        if self.ca_verifier.is_none() && self.spki_sha256.is_empty() {
+            // Certs and secrets can't be taken from a provisioner the enclave can't verify
+            #[cfg(feature = "enclave")]
+            {
+                log::error!("No provisioner identity pinned, refusing to connect");
+                return Err(Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+            // Outside an enclave the provisioner is usually the mock one, whose certs are generated on startup
+            #[cfg(not(feature = "enclave"))]
+            MISSING_IDENTITY_WARNING.call_once(|| {
+                log::warn!(
+                    "No provisioner identity pinned, provisioner connections are unverified"
+                );
+            });
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CertProvisionerCertVerifier, ProvisionerIdentityConfig};
+    use crate::base_tls_client::e3_cert_verifier::spki_sha256;
+    use crate::configuration;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::time::SystemTime;
+    use tokio_rustls::rustls::client::ServerCertVerifier;
+    use tokio_rustls::rustls::{Certificate, ServerName};
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Issues a cert for the given key, self signed when no issuer is given
+    fn issue_cert(key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        let common_name = if issuer.is_some() {
+            configuration::get_cert_provisioner_host()
         } else {
-            Err(Error::InvalidCertificate(CertificateError::NotValidForName))
+            "Test Provisioner CA".to_string()
+        };
+        name.append_entry_by_text("CN", &common_name).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .dns(&common_name)
+                    .build(&builder.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
         }
+        builder.build()
+    }
+
+    fn verify(verifier: &CertProvisionerCertVerifier, cert: &X509) -> bool {
+        let server_name =
+            ServerName::try_from(configuration::get_cert_provisioner_host().as_str()).unwrap();
+        verifier
+            .verify_server_cert(
+                &Certificate(cert.to_der().unwrap()),
+                &[],
+                &server_name,
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn test_accepts_cert_issued_by_pinned_ca() {
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let identity = ProvisionerIdentityConfig {
+            ca_cert_pem: Some(String::from_utf8(ca_cert.to_pem().unwrap()).unwrap()),
+            ..Default::default()
+        };
+        let verifier = CertProvisionerCertVerifier::new(Some(&identity));
+
+        let cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        assert!(verify(&verifier, &cert));
+
+        let rogue_ca_key = generate_key();
+        let rogue_ca_cert = issue_cert(&rogue_ca_key, None);
+        let rogue_cert = issue_cert(&generate_key(), Some((&rogue_ca_cert, &rogue_ca_key)));
+        assert!(!verify(&verifier, &rogue_cert));
+    }
+
+    #[test]
+    fn test_accepts_only_pinned_spki() {
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        let identity = ProvisionerIdentityConfig {
+            spki_sha256: vec![spki_sha256(&Certificate(cert.to_der().unwrap())).unwrap()],
+            ..Default::default()
+        };
+        let verifier = CertProvisionerCertVerifier::new(Some(&identity));
+        assert!(verify(&verifier, &cert));
+
+        let other_cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        assert!(!verify(&verifier, &other_cert));
+    }
+
+    #[test]
+    fn test_invalid_pinned_ca_rejects_all_certs() {
+        let identity = ProvisionerIdentityConfig {
+            ca_cert_pem: Some("not a cert".to_string()),
+            ..Default::default()
+        };
+        let verifier = CertProvisionerCertVerifier::new(Some(&identity));
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        assert!(!verify(&verifier, &cert));
+    }
+
+    #[test]
+    fn test_unpinned_identity_is_only_accepted_outside_enclaves() {
+        let verifier = CertProvisionerCertVerifier::new(None);
+        let ca_key = generate_key();
+        let ca_cert = issue_cert(&ca_key, None);
+        let cert = issue_cert(&generate_key(), Some((&ca_cert, &ca_key)));
+        assert_eq!(verify(&verifier, &cert), cfg!(not(feature = "enclave")));
     }
 }
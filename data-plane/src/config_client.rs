@@ -1,29 +1,52 @@
 use async_trait::async_trait;
 use error::Result;
-use hyper::client::conn::{Connection as HyperConnection, SendRequest};
+use hyper::header::{HeaderValue, RETRY_AFTER};
 use hyper::http::StatusCode;
-use hyper::{Body, Response};
+use hyper::{Body, Client, Response};
 
 use serde::de::DeserializeOwned;
-use shared::logging::TrxContext;
+use shared::logging::{AuditEvent, TrxContext};
 use shared::server::config_server::requests::{
-    ConfigServerPayload, DeleteObjectRequest, GetCertTokenResponseDataPlane, GetClockSyncResponse,
-    GetE3TokenResponseDataPlane, GetObjectRequest, GetObjectResponse, GetTokenRequestDataPlane,
-    JwkResponse, JwsRequest, JwsResponse, PostTrxLogsRequest, PutObjectRequest, SignatureType,
+    ConfigServerPayload, CrashReport, DeleteObjectRequest, EgressPolicyUpdate,
+    GetCertTokenResponseDataPlane, GetClockSyncResponse, GetE3TokenResponseDataPlane,
+    GetObjectRequest, GetObjectResponse, GetTokenRequestDataPlane, JwkResponse, JwsRequest,
+    JwsResponse, OcspFetchRequest, OcspFetchResponse, PostAuditLogsRequest,
+    PostSessionTokenRequest, PostTrxLogsRequest, PutObjectRequest, SignatureType, TrxLogBatch,
+    TRX_LOG_BATCH_CONTENT_TYPE,
 };
 use shared::server::config_server::routes::ConfigServerPath;
+use shared::server::plane_version::{features, PlaneVersion};
+use shared::server::session_token::SessionToken;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 use tokio_retry::strategy::{jitter, ExponentialBackoff};
 use tokio_retry::Retry;
+use zeroize::Zeroizing;
 
-use crate::connection;
+use crate::connection::HostConnector;
 use crate::error::{self, Error};
 
-use connection::Connection;
+/// Connections to the config server are kept alive and reused across requests, rather than reconnecting for
+/// every token request and trx log flush.
+static CONFIG_SERVER_CLIENT: OnceLock<Client<HostConnector, Body>> = OnceLock::new();
+const CONFIG_SERVER_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const CONFIG_SERVER_POOL_MAX_IDLE: usize = 4;
+/// The control plane's version, once negotiated
+static CONTROL_PLANE_VERSION: OnceLock<PlaneVersion> = OnceLock::new();
+
+/// Whether the control plane has advertised a protocol feature. Until versions are negotiated, and for control planes
+/// which predate negotiation, no features are assumed.
+pub fn control_plane_supports(feature: &str) -> bool {
+    CONTROL_PLANE_VERSION
+        .get()
+        .is_some_and(|control_plane| control_plane.supports(feature))
+}
 
 #[async_trait]
 pub trait StorageConfigClientInterface {
     async fn get_object(&self, key: String) -> Result<Option<GetObjectResponse>>;
-    async fn put_object(&self, key: String, object: String) -> Result<()>;
+    /// Objects may hold key material, so are only ever held in zeroizing buffers
+    async fn put_object(&self, key: String, object: Zeroizing<String>) -> Result<()>;
     async fn delete_object(&self, key: String) -> Result<()>;
     async fn get_time_from_host(&self) -> Result<GetClockSyncResponse>;
 }
@@ -46,44 +69,39 @@ impl ConfigClient {
         format!("http://127.0.0.1:{}{}", shared::ENCLAVE_CONFIG_PORT, path)
     }
 
-    async fn get_conn(
+    fn get_client(&self) -> &'static Client<HostConnector, Body> {
+        CONFIG_SERVER_CLIENT.get_or_init(|| {
+            Client::builder()
+                .pool_idle_timeout(CONFIG_SERVER_POOL_IDLE_TIMEOUT)
+                .pool_max_idle_per_host(CONFIG_SERVER_POOL_MAX_IDLE)
+                .build(HostConnector::new(shared::ENCLAVE_CONFIG_PORT))
+        })
+    }
+
+    async fn send(
         &self,
-    ) -> Result<(
-        SendRequest<hyper::Body>,
-        HyperConnection<Connection, hyper::Body>,
-    )> {
-        let client_connection: Connection =
-            connection::get_socket(shared::ENCLAVE_CONFIG_PORT).await?;
-
-        let connection_info = hyper::client::conn::Builder::new()
-            .handshake::<Connection, hyper::Body>(client_connection)
+        path: ConfigServerPath,
+        method: &str,
+        payload: hyper::Body,
+    ) -> Result<Response<Body>> {
+        self.send_with_content_type(path, method, payload, "application/json")
             .await
-            .map_err(Error::Hyper)?;
-
-        Ok(connection_info)
     }
 
-    async fn send(
+    async fn send_with_content_type(
         &self,
         path: ConfigServerPath,
         method: &str,
         payload: hyper::Body,
+        content_type: &str,
     ) -> Result<Response<Body>> {
         let request = hyper::Request::builder()
             .uri(self.get_uri(path))
-            .header("Content-Type", "application/json")
+            .header("Content-Type", content_type)
             .method(method)
-            .body(payload)
-            .expect("Failed to create request");
-
-        let (mut request_sender, connection) = self.get_conn().await?;
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                log::error!("Error with connection to config server in control plane: {e}");
-            }
-        });
+            .body(payload)?;
 
-        let response = request_sender.send_request(request).await?;
+        let response = self.get_client().request(request).await?;
 
         Ok(response)
     }
@@ -126,13 +144,48 @@ impl ConfigClient {
         Ok(result)
     }
 
+    /// Trx logs are sent as protobuf batches once the control plane has advertised support for them, and as JSON
+    /// until then.
     pub async fn post_trx_logs(&self, trx_logs: Vec<TrxContext>) -> Result<()> {
+        if !control_plane_supports(features::TRX_LOG_BATCH) {
+            return self.post_trx_logs_json(trx_logs).await;
+        }
+
+        let batch = TrxLogBatch::new(trx_logs).encode();
+        let response = self
+            .send_with_content_type(
+                ConfigServerPath::PostTrxLogBatch,
+                "POST",
+                Body::from(batch),
+                TRX_LOG_BATCH_CONTENT_TYPE,
+            )
+            .await?;
+
+        if let Some(throttled) = throttled(&response) {
+            return Err(throttled);
+        }
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            status => {
+                log::error!("Error in post_trx_logs request to control plane: {status}");
+                Err(Error::ConfigServer(
+                    "Invalid Response code returned when sending trx logs to control plane "
+                        .to_string(),
+                ))
+            }
+        }
+    }
+
+    async fn post_trx_logs_json(&self, trx_logs: Vec<TrxContext>) -> Result<()> {
         let payload = PostTrxLogsRequest::new(trx_logs).into_body()?;
 
         let response = self
             .send(ConfigServerPath::PostTrxLogs, "POST", payload)
             .await?;
 
+        if let Some(throttled) = throttled(&response) {
+            return Err(throttled);
+        }
         if response.status() == StatusCode::OK {
             Ok(())
         } else {
@@ -147,6 +200,139 @@ impl ConfigClient {
         }
     }
 
+    /// Register the enclave's session token, which the control plane presents on every ingress connection.
+    pub async fn post_session_token(&self, token: &SessionToken) -> Result<()> {
+        let payload = PostSessionTokenRequest::new(token.to_base64()).into_body()?;
+        let response = self
+            .send(ConfigServerPath::SessionToken, "POST", payload)
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Error::ConfigServer(format!(
+                "Unsuccessful response from config server: {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The latest runtime update to the egress policy, or `None` if it hasn't been updated since startup.
+    pub async fn get_egress_policy(&self) -> Result<Option<EgressPolicyUpdate>> {
+        let response = self
+            .send(ConfigServerPath::EgressPolicy, "GET", Body::empty())
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(self.parse_response(response).await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(Error::ConfigServer(format!(
+                "Unsuccessful response from config server: {status}"
+            ))),
+        }
+    }
+
+    /// Have the control plane send a DER encoded OCSP request to a cert's responder, returning the DER response.
+    pub async fn get_ocsp_response(
+        &self,
+        responder_url: String,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let payload = OcspFetchRequest {
+            responder_url,
+            request: base64::encode(request),
+        }
+        .into_body()?;
+        let response = self.send(ConfigServerPath::Ocsp, "POST", payload).await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let result: OcspFetchResponse = self.parse_response(response).await?;
+                base64::decode(result.response).map_err(|e| Error::ConfigServer(e.to_string()))
+            }
+            status => Err(Error::ConfigServer(format!(
+                "Unsuccessful response from config server when fetching OCSP response: {status}"
+            ))),
+        }
+    }
+
+    /// The control plane's version and protocol features, or `None` if it predates version negotiation.
+    pub async fn get_control_plane_version(&self) -> Result<Option<PlaneVersion>> {
+        let response = self
+            .send(ConfigServerPath::Version, "GET", Body::empty())
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(Some(self.parse_response(response).await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(Error::ConfigServer(format!(
+                "Unsuccessful response from config server: {status}"
+            ))),
+        }
+    }
+
+    /// Check the control plane's version against the data plane's, logging any mismatches, and keep it so requests to
+    /// the control plane can be gated on its features.
+    pub async fn negotiate_version(&self) {
+        let retry_strategy = ExponentialBackoff::from_millis(500).map(jitter).take(5);
+        let control_plane = match Retry::spawn(retry_strategy, || async {
+            self.get_control_plane_version().await
+        })
+        .await
+        {
+            Ok(Some(control_plane)) => control_plane,
+            Ok(None) => return log::warn!("Control plane predates version negotiation"),
+            Err(e) => return log::error!("Failed to get the control plane's version - {e}"),
+        };
+
+        PlaneVersion::current(env!("CARGO_PKG_VERSION"))
+            .log_mismatches("control plane", &control_plane);
+        let _ = CONTROL_PLANE_VERSION.set(control_plane);
+    }
+
+    pub async fn post_audit_logs(&self, audit_logs: Vec<AuditEvent>) -> Result<()> {
+        let payload = PostAuditLogsRequest::new(audit_logs).into_body()?;
+
+        let response = self
+            .send(ConfigServerPath::PostAuditLogs, "POST", payload)
+            .await?;
+
+        if response.status() == StatusCode::OK {
+            Ok(())
+        } else {
+            log::error!(
+                "Error in post_audit_logs request to control plane: {}",
+                response.status()
+            );
+            Err(Error::ConfigServer(
+                "Invalid Response code returned when sending audit logs to control plane "
+                    .to_string(),
+            ))
+        }
+    }
+
+    /// Report a crash to the control plane. A new connection is used, as pooled connections belong to the runtime
+    /// the crash may have happened on.
+    pub async fn post_crash_report(&self, report: CrashReport) -> Result<()> {
+        let request = hyper::Request::builder()
+            .uri(self.get_uri(ConfigServerPath::CrashReport))
+            .header("Content-Type", "application/json")
+            .method("POST")
+            .body(report.into_body()?)?;
+        let response = Client::builder()
+            .build(HostConnector::new(shared::ENCLAVE_CONFIG_PORT))
+            .request(request)
+            .await?;
+
+        if response.status() == StatusCode::OK {
+            Ok(())
+        } else {
+            Err(Error::ConfigServer(format!(
+                "Invalid response code {} returned when sending crash report to control plane",
+                response.status()
+            )))
+        }
+    }
+
     pub async fn jws(
         &self,
         signature_type: SignatureType,
@@ -241,7 +427,7 @@ impl ConfigClient {
         }
     }
 
-    async fn base_put_object(&self, key: String, object: String) -> Result<()> {
+    async fn base_put_object(&self, key: String, object: Zeroizing<String>) -> Result<()> {
         let payload = PutObjectRequest::new(key.clone(), object).into_body()?;
         let response = self.send(ConfigServerPath::Storage, "PUT", payload).await?;
 
@@ -302,6 +488,41 @@ impl ConfigClient {
     }
 }
 
+/// A throttled error if the config server is shedding load, with how long it asked us to wait when it said.
+fn throttled(response: &Response<Body>) -> Option<Error> {
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(parse_retry_after);
+            log::warn!(
+                "Config server is shedding load ({}), retry after {retry_after:?}",
+                response.status()
+            );
+            Some(Error::ConfigServerThrottled(retry_after))
+        }
+        _ => None,
+    }
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let retry_at =
+        SystemTime::UNIX_EPOCH + Duration::from_secs(retry_at.timestamp().try_into().ok()?);
+    // A date in the past means we can retry straight away
+    Some(
+        retry_at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
 #[async_trait]
 impl StorageConfigClientInterface for ConfigClient {
     async fn get_object(&self, key: String) -> Result<Option<GetObjectResponse>> {
@@ -313,7 +534,7 @@ impl StorageConfigClientInterface for ConfigClient {
         .await
     }
 
-    async fn put_object(&self, key: String, object: String) -> Result<()> {
+    async fn put_object(&self, key: String, object: Zeroizing<String>) -> Result<()> {
         let retry_strategy = ExponentialBackoff::from_millis(500).map(jitter).take(2);
 
         Retry::spawn(retry_strategy, || async {
@@ -340,3 +561,23 @@ impl StorageConfigClientInterface for ConfigClient {
         .await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::parse_retry_after;
+    use hyper::header::HeaderValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_after_is_parsed_from_seconds_or_dates() {
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after(&HeaderValue::from_static("soon")), None);
+    }
+}
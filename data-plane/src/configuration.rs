@@ -1,3 +1,12 @@
+use crate::crypto::rate_limit::RateLimitConfig;
+use crate::{FeatureContext, FEATURE_CONTEXT_PATH};
+use shared::dry_run;
+use shared::validation::ValidationReport;
+use std::time::Duration;
+use tokio_rustls::rustls::{
+    version, SupportedCipherSuite, SupportedProtocolVersion, ALL_CIPHER_SUITES,
+};
+
 #[cfg(feature = "enclave")]
 pub fn get_cert_provisioner_host() -> String {
     "provisioner.cages.internal".to_string()
@@ -26,6 +35,922 @@ pub fn get_e3_host() -> String {
     "localhost".to_string()
 }
 
+pub const E3_CONNECT_TIMEOUT_ENV: &str = "E3_CONNECT_TIMEOUT_MS";
+pub const E3_REQUEST_TIMEOUT_ENV: &str = "E3_REQUEST_TIMEOUT_MS";
+const DEFAULT_E3_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_E3_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connecting to E3 can take, including the TLS handshake.
+pub fn get_e3_connect_timeout() -> Duration {
+    timeout_from_env(E3_CONNECT_TIMEOUT_ENV).unwrap_or(DEFAULT_E3_CONNECT_TIMEOUT)
+}
+
+/// How long E3 has to respond to each request attempt.
+pub fn get_e3_request_timeout() -> Duration {
+    timeout_from_env(E3_REQUEST_TIMEOUT_ENV).unwrap_or(DEFAULT_E3_REQUEST_TIMEOUT)
+}
+
+fn timeout_from_env(var_name: &str) -> Option<Duration> {
+    parse_timeout_ms(&std::env::var(var_name).ok()?)
+}
+
+fn parse_timeout_ms(value: &str) -> Option<Duration> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|millis| *millis > 0)
+        .map(Duration::from_millis)
+}
+
+pub const CRYPTO_API_PORT_ENV: &str = "CRYPTO_API_PORT";
+const DEFAULT_CRYPTO_API_PORT: u16 = 9999;
+
+/// The loopback port the Crypto API listens on, which can be moved if the customer process already uses the default.
+pub fn get_crypto_api_port() -> u16 {
+    std::env::var(CRYPTO_API_PORT_ENV)
+        .ok()
+        .and_then(|port| parse_port(&port))
+        .unwrap_or(DEFAULT_CRYPTO_API_PORT)
+}
+
+pub const CRYPTO_API_SOCKET_ENV: &str = "CRYPTO_API_SOCKET";
+
+/// A unix socket path for the Crypto API to listen on instead of a TCP port, e.g. `/var/run/dataplane/crypto.sock`.
+pub fn get_crypto_api_socket() -> Option<std::path::PathBuf> {
+    std::env::var_os(CRYPTO_API_SOCKET_ENV)
+        .filter(|path| !path.is_empty())
+        .map(std::path::PathBuf::from)
+}
+
+pub const CRYPTO_API_MAX_BODY_BYTES_ENV: &str = "CRYPTO_API_MAX_BODY_BYTES";
+const DEFAULT_CRYPTO_API_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// The largest body the Crypto API will buffer for a request. Streamed routes aren't buffered, so aren't limited.
+pub fn get_crypto_api_max_body_bytes() -> usize {
+    std::env::var(CRYPTO_API_MAX_BODY_BYTES_ENV)
+        .ok()
+        .and_then(|max| parse_max_body_bytes(&max))
+        .unwrap_or(DEFAULT_CRYPTO_API_MAX_BODY_BYTES)
+}
+
+fn parse_max_body_bytes(value: &str) -> Option<usize> {
+    value.parse::<usize>().ok().filter(|max| *max > 0)
+}
+
+pub const CRYPTO_API_RATE_LIMIT_ENV: &str = "CRYPTO_API_RATE_LIMIT";
+pub const CRYPTO_API_RATE_LIMIT_BURST_ENV: &str = "CRYPTO_API_RATE_LIMIT_BURST";
+
+/// The per api key rate limit on the Crypto API, if one is set. Bursts default to a second's worth of requests.
+pub fn get_crypto_api_rate_limit() -> Option<RateLimitConfig> {
+    let requests_per_second = parse_positive(&std::env::var(CRYPTO_API_RATE_LIMIT_ENV).ok()?)?;
+    let burst = std::env::var(CRYPTO_API_RATE_LIMIT_BURST_ENV)
+        .ok()
+        .and_then(|burst| parse_positive(&burst))
+        .unwrap_or(requests_per_second);
+    Some(RateLimitConfig {
+        requests_per_second,
+        burst,
+    })
+}
+
+pub const ATTESTATION_DOC_CACHE_TTL_ENV: &str = "ATTESTATION_DOC_CACHE_TTL_SECS";
+const DEFAULT_ATTESTATION_DOC_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long attestation docs requested without a nonce are reused for, so clients polling for attestation don't each
+/// need a doc from the NSM. Zero switches off caching.
+pub fn get_attestation_doc_cache_ttl() -> Duration {
+    std::env::var(ATTESTATION_DOC_CACHE_TTL_ENV)
+        .ok()
+        .and_then(|ttl| ttl.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_ATTESTATION_DOC_CACHE_TTL)
+}
+
+pub const REATTESTATION_INTERVAL_ENV: &str = "REATTESTATION_INTERVAL_SECS";
+
+/// How often the data plane re-submits a fresh attestation doc to the cert provisioner, so enclaves whose measurements
+/// drift or whose certs expire can be revoked. Re-attestation is off unless this is set.
+pub fn get_reattestation_interval() -> Option<Duration> {
+    std::env::var(REATTESTATION_INTERVAL_ENV)
+        .ok()
+        .and_then(|interval| parse_positive(&interval))
+        .map(Duration::from_secs)
+}
+
+pub const TLS_SESSION_CACHE_SIZE_ENV: &str = "TLS_SESSION_CACHE_SIZE";
+const DEFAULT_TLS_SESSION_CACHE_SIZE: usize = 1024;
+
+/// How many ingress TLS sessions are kept for resumption by session ID. Zero switches off the cache.
+pub fn get_tls_session_cache_size() -> usize {
+    std::env::var(TLS_SESSION_CACHE_SIZE_ENV)
+        .ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_TLS_SESSION_CACHE_SIZE)
+}
+
+pub const TLS_TICKET_ROTATION_ENV: &str = "TLS_SESSION_TICKET_ROTATION_SECS";
+const DEFAULT_TLS_TICKET_ROTATION: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How often the key ingress TLS session tickets are encrypted with is replaced, or `None` if tickets are switched
+/// off by setting it to zero.
+pub fn get_tls_ticket_rotation() -> Option<Duration> {
+    let rotation = std::env::var(TLS_TICKET_ROTATION_ENV)
+        .ok()
+        .and_then(|rotation| rotation.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TLS_TICKET_ROTATION);
+    (!rotation.is_zero()).then_some(rotation)
+}
+
+pub const WEBSOCKET_IDLE_TIMEOUT_ENV: &str = "WEBSOCKET_IDLE_TIMEOUT_SECS";
+const DEFAULT_WEBSOCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long an upgraded websocket connection can go without traffic in either direction before it's closed, or
+/// `None` if idle connections are kept open by setting it to zero.
+pub fn get_websocket_idle_timeout() -> Option<Duration> {
+    let timeout = std::env::var(WEBSOCKET_IDLE_TIMEOUT_ENV)
+        .ok()
+        .and_then(|timeout| timeout.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_WEBSOCKET_IDLE_TIMEOUT);
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+pub const TLS_MIN_VERSION_ENV: &str = "TLS_MIN_VERSION";
+pub const TLS_CIPHER_SUITES_ENV: &str = "TLS_CIPHER_SUITES";
+
+/// The protocol versions and cipher suites the ingress TLS server accepts.
+pub struct IngressTlsConfig {
+    pub protocol_versions: &'static [&'static SupportedProtocolVersion],
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+}
+
+impl IngressTlsConfig {
+    /// Read the minimum TLS version (`1.2` or `1.3`, defaulting to `1.2`) and a comma separated allow list of cipher
+    /// suites, named as in the TLS registry, e.g. `TLS13_AES_256_GCM_SHA384`. Every suite rustls supports for the
+    /// allowed versions is used when no allow list is given. Bad values are errors rather than falling back to the
+    /// defaults, as they're set to enforce compliance.
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(
+            std::env::var(TLS_MIN_VERSION_ENV).ok().as_deref(),
+            std::env::var(TLS_CIPHER_SUITES_ENV).ok().as_deref(),
+        )
+    }
+
+    fn parse(min_version: Option<&str>, cipher_suites: Option<&str>) -> Result<Self, String> {
+        static TLS12_AND_UP: [&SupportedProtocolVersion; 2] = [&version::TLS13, &version::TLS12];
+        static TLS13_ONLY: [&SupportedProtocolVersion; 1] = [&version::TLS13];
+        let protocol_versions: &'static [&'static SupportedProtocolVersion] =
+            match min_version.map(str::trim) {
+                None | Some("1.2") => &TLS12_AND_UP,
+                Some("1.3") => &TLS13_ONLY,
+                Some(other) => return Err(format!("{other} is not a TLS version, use 1.2 or 1.3")),
+            };
+        let is_allowed_version = |suite: &SupportedCipherSuite| {
+            protocol_versions
+                .iter()
+                .any(|version| version.version == suite.version().version)
+        };
+
+        let cipher_suites = match cipher_suites {
+            None => ALL_CIPHER_SUITES
+                .iter()
+                .copied()
+                .filter(is_allowed_version)
+                .collect(),
+            Some(allow_list) => {
+                let mut allowed = Vec::new();
+                for name in allow_list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    let suite = ALL_CIPHER_SUITES
+                        .iter()
+                        .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("{name} is not a supported cipher suite"))?;
+                    if is_allowed_version(suite) {
+                        allowed.push(*suite);
+                    }
+                }
+                allowed
+            }
+        };
+        if cipher_suites.is_empty() {
+            return Err("no cipher suites are allowed for the allowed TLS versions".to_string());
+        }
+        Ok(Self {
+            protocol_versions,
+            cipher_suites,
+        })
+    }
+
+    fn cipher_suite_names(&self) -> Vec<String> {
+        self.cipher_suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect()
+    }
+}
+
+fn parse_positive(value: &str) -> Option<u64> {
+    value.parse::<u64>().ok().filter(|value| *value > 0)
+}
+
+fn parse_port(value: &str) -> Option<u16> {
+    value.parse::<u16>().ok().filter(|port| *port != 0)
+}
+
 pub fn should_forward_proxy_protocol() -> bool {
     std::env::var("FORWARD_PROXY_PROTOCOL").is_ok()
 }
+
+/// Check the data plane's port argument and feature config before startup, so a bad deployment is reported in full
+/// rather than failing part way through serving requests.
+pub fn validate_config(
+    data_plane_port: Option<&str>,
+    feature_context_json: &str,
+) -> ValidationReport {
+    let mut report = ValidationReport::new("Data plane");
+    validate_port(&mut report, data_plane_port);
+    for var_name in [E3_CONNECT_TIMEOUT_ENV, E3_REQUEST_TIMEOUT_ENV] {
+        validate_timeout_env(
+            &mut report,
+            var_name,
+            std::env::var(var_name).ok().as_deref(),
+        );
+    }
+    if let Ok(max_body_bytes) = std::env::var(CRYPTO_API_MAX_BODY_BYTES_ENV) {
+        if parse_max_body_bytes(&max_body_bytes).is_none() {
+            report.warning(
+                CRYPTO_API_MAX_BODY_BYTES_ENV,
+                format!("{max_body_bytes} is not a positive number of bytes, using the default"),
+            );
+        }
+    }
+    if let Ok(ttl) = std::env::var(ATTESTATION_DOC_CACHE_TTL_ENV) {
+        if ttl.parse::<u64>().is_err() {
+            report.warning(
+                ATTESTATION_DOC_CACHE_TTL_ENV,
+                format!("{ttl} is not a number of seconds, using the default"),
+            );
+        }
+    }
+    if let Err(e) = IngressTlsConfig::from_env() {
+        report.fatal(format!("{TLS_MIN_VERSION_ENV}/{TLS_CIPHER_SUITES_ENV}"), e);
+    }
+    for var_name in [
+        TLS_SESSION_CACHE_SIZE_ENV,
+        TLS_TICKET_ROTATION_ENV,
+        WEBSOCKET_IDLE_TIMEOUT_ENV,
+    ] {
+        if let Ok(value) = std::env::var(var_name) {
+            if value.parse::<u64>().is_err() {
+                report.warning(
+                    var_name,
+                    format!("{value} is not a whole number, using the default"),
+                );
+            }
+        }
+    }
+    for var_name in [
+        CRYPTO_API_RATE_LIMIT_ENV,
+        CRYPTO_API_RATE_LIMIT_BURST_ENV,
+        REATTESTATION_INTERVAL_ENV,
+    ] {
+        if let Ok(value) = std::env::var(var_name) {
+            if parse_positive(&value).is_none() {
+                report.warning(
+                    var_name,
+                    format!("{value} is not a positive whole number, so it will be ignored"),
+                );
+            }
+        }
+    }
+    let crypto_api_port = std::env::var(CRYPTO_API_PORT_ENV).ok();
+    match get_crypto_api_socket() {
+        Some(_) if crypto_api_port.is_some() => report.warning(
+            CRYPTO_API_PORT_ENV,
+            format!(
+                "the Crypto API listens on {CRYPTO_API_SOCKET_ENV} instead, so no port is used"
+            ),
+        ),
+        Some(_) => {}
+        None => validate_crypto_api_port(&mut report, data_plane_port, crypto_api_port.as_deref()),
+    }
+
+    let feature_context = match FeatureContext::from_json(feature_context_json) {
+        Ok(feature_context) => feature_context,
+        Err(e) => {
+            report.fatal(FEATURE_CONTEXT_PATH, e.to_string());
+            return report;
+        }
+    };
+    // The raw config is inspected too, for fields which are dropped or defaulted when deserialized
+    let raw_context: serde_json::Value =
+        serde_json::from_str(feature_context_json).unwrap_or_default();
+
+    let runtime = &feature_context.runtime;
+    for (field, value) in [
+        ("runtime.worker_threads", runtime.worker_threads),
+        ("runtime.max_blocking_threads", runtime.max_blocking_threads),
+        (
+            "runtime.event_interval",
+            runtime.event_interval.map(|interval| interval as usize),
+        ),
+    ] {
+        if value == Some(0) {
+            report.fatal(field, "must be greater than zero");
+        }
+    }
+
+    for (field, max_entries) in [
+        (
+            "auth_cache.max_entries",
+            feature_context
+                .auth_cache
+                .as_ref()
+                .map(|cache| cache.max_entries),
+        ),
+        (
+            "decrypt_cache.max_entries",
+            feature_context
+                .decrypt_cache
+                .as_ref()
+                .map(|cache| cache.max_entries),
+        ),
+    ] {
+        if max_entries == Some(0) {
+            report.fatal(
+                field,
+                "must be greater than zero, leave the cache out to switch off caching",
+            );
+        }
+    }
+    if let Some(quotas) = &feature_context.crypto_api_quotas {
+        if quotas.max_tracked_keys == 0 {
+            report.fatal(
+                "crypto_api_quotas.max_tracked_keys",
+                "must be greater than zero",
+            );
+        }
+    }
+
+    if !cfg!(feature = "tls_termination") {
+        if feature_context.api_key_auth {
+            report.warning(
+                "api_key_auth",
+                "requests can't be authenticated without the tls_termination feature",
+            );
+        }
+        if feature_context.trx_logging_enabled {
+            report.warning(
+                "trx_logging_enabled",
+                "requests can't be logged without the tls_termination feature",
+            );
+        }
+    }
+
+    #[cfg(feature = "tls_termination")]
+    validate_ingress_routes(&mut report, &feature_context.ingress_routes);
+    #[cfg(not(feature = "tls_termination"))]
+    if !raw_context["ingress_routes"].is_null() {
+        report.warning(
+            "ingress_routes",
+            "requests can't be routed by host without the tls_termination feature",
+        );
+    }
+
+    let e3_resilience = &feature_context.e3_resilience;
+    if e3_resilience.retry_budget_percent > 100 {
+        report.fatal("e3_resilience.retry_budget_percent", "must be at most 100");
+    }
+    if let Some(hedging) = &e3_resilience.hedging {
+        if hedging.percentile == 0 || hedging.percentile > 99 {
+            report.fatal(
+                "e3_resilience.hedging.percentile",
+                "must be between 1 and 99",
+            );
+        }
+    }
+    if e3_resilience.retry.max_attempts == 0 {
+        report.fatal(
+            "e3_resilience.retry.max_attempts",
+            "must be at least 1, which switches off retries",
+        );
+    }
+
+    if feature_context.e3_connection_pool.idle_timeout_secs == 0 {
+        report.fatal(
+            "e3_connection_pool.idle_timeout_secs",
+            "must be greater than 0, set max_idle_connections to 0 to switch off pooling",
+        );
+    }
+
+    #[cfg(feature = "network_egress")]
+    {
+        validate_egress_config(&mut report, &feature_context.egress, &raw_context["egress"]);
+        let dns_proxy = &feature_context.dns_proxy;
+        for (field, value) in [
+            ("dns_proxy.max_in_flight", dns_proxy.max_in_flight as u64),
+            ("dns_proxy.max_queued", dns_proxy.max_queued as u64),
+            ("dns_proxy.query_timeout_ms", dns_proxy.query_timeout_ms),
+        ] {
+            if value == 0 {
+                report.fatal(field, "must be greater than zero");
+            }
+        }
+    }
+    #[cfg(not(feature = "network_egress"))]
+    if !raw_context["egress"].is_null() {
+        report.warning(
+            "egress",
+            "egress is configured, but this data plane was built without the network_egress feature",
+        );
+    }
+
+    report
+}
+
+/// Validate the feature config at [`FEATURE_CONTEXT_PATH`] along with the data plane's port argument.
+pub fn validate_startup_config(data_plane_port: Option<&str>) -> ValidationReport {
+    match std::fs::read_to_string(FEATURE_CONTEXT_PATH) {
+        Ok(feature_context_json) => validate_config(data_plane_port, &feature_context_json),
+        Err(e) => {
+            let mut report = ValidationReport::new("Data plane");
+            validate_port(&mut report, data_plane_port);
+            report.fatal(FEATURE_CONTEXT_PATH, format!("could not be read - {e}"));
+            report
+        }
+    }
+}
+
+fn validate_port(report: &mut ValidationReport, data_plane_port: Option<&str>) {
+    if let Some(port) = data_plane_port {
+        if parse_port(port).is_none() {
+            report.fatal(
+                "data plane port",
+                format!("{port} is not a valid port for the customer process"),
+            );
+        }
+    }
+}
+
+fn validate_crypto_api_port(
+    report: &mut ValidationReport,
+    data_plane_port: Option<&str>,
+    crypto_api_port: Option<&str>,
+) {
+    let port = match crypto_api_port {
+        Some(value) => parse_port(value),
+        None => Some(DEFAULT_CRYPTO_API_PORT),
+    };
+    let Some(port) = port else {
+        report.warning(
+            CRYPTO_API_PORT_ENV,
+            format!(
+                "{} is not a valid port, using {DEFAULT_CRYPTO_API_PORT}",
+                crypto_api_port.unwrap_or_default()
+            ),
+        );
+        return;
+    };
+    if data_plane_port.and_then(parse_port) == Some(port) {
+        report.fatal(
+            CRYPTO_API_PORT_ENV,
+            format!("the Crypto API can't listen on {port}, it's the customer process's port"),
+        );
+    }
+}
+
+fn validate_timeout_env(report: &mut ValidationReport, var_name: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        if parse_timeout_ms(value).is_none() {
+            report.warning(
+                var_name,
+                format!("{value} is not a positive number of milliseconds, using the default"),
+            );
+        }
+    }
+}
+
+#[cfg(feature = "tls_termination")]
+fn validate_ingress_routes(
+    report: &mut ValidationReport,
+    routes: &[crate::server::routing::IngressRoute],
+) {
+    let mut names = std::collections::HashSet::new();
+    let mut hosts = std::collections::HashSet::new();
+    for route in routes {
+        if !names.insert(route.name.as_str()) {
+            report.fatal(
+                "ingress_routes",
+                format!("route {} is configured more than once", route.name),
+            );
+        }
+        if route.port == 0 {
+            report.fatal(
+                "ingress_routes",
+                format!("route {} must have a port greater than zero", route.name),
+            );
+        }
+        if route.hosts.is_empty() {
+            report.fatal(
+                "ingress_routes",
+                format!(
+                    "route {} has no hosts, so it would never be used",
+                    route.name
+                ),
+            );
+        }
+        for host in &route.hosts {
+            if !hosts.insert(host.to_ascii_lowercase()) {
+                report.warning(
+                    "ingress_routes",
+                    format!(
+                        "{host} is served by more than one route, so only the first will be used"
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "network_egress")]
+fn validate_egress_config(
+    report: &mut ValidationReport,
+    egress: &shared::server::egress::EgressConfig,
+    raw_egress: &serde_json::Value,
+) {
+    use shared::server::egress::{
+        check_mapped_destination, get_invalid_egress_ports, get_malformed_allow_list_entries,
+    };
+
+    if let Some(raw_ports) = raw_egress["ports"].as_str() {
+        for port in get_invalid_egress_ports(raw_ports) {
+            report.warning(
+                "egress.ports",
+                format!("\"{port}\" is not a valid port, and will be ignored"),
+            );
+        }
+        if egress.ports.as_ref().is_some_and(Vec::is_empty) {
+            report.fatal(
+                "egress.ports",
+                "no valid ports are configured, so all egress would be blocked",
+            );
+        }
+    }
+    if let Some(raw_allow_list) = raw_egress["allow_list"].as_str() {
+        for entry in get_malformed_allow_list_entries(raw_allow_list) {
+            report.warning(
+                "egress.allow_list",
+                format!(
+                    "\"{entry}\" is not a hostname, wildcard or IPv4 address with an optional port, and will never match"
+                ),
+            );
+        }
+    }
+
+    let mut local_ports = std::collections::HashSet::new();
+    for destination in &egress.destination_map {
+        if !local_ports.insert(destination.local_port) {
+            report.fatal(
+                "egress.destination_map",
+                format!(
+                    "local port {} is mapped more than once",
+                    destination.local_port
+                ),
+            );
+        }
+        if let Err(e) = check_mapped_destination(destination, egress) {
+            report.warning(
+                "egress.destination_map",
+                format!(
+                    "{}:{} will be blocked - {e}",
+                    destination.host, destination.port
+                ),
+            );
+        }
+    }
+
+    if let Some(allowed_ports) = &egress.ports {
+        for (entry, ports) in &egress.allow_list.port_restrictions {
+            for port in ports.iter().filter(|port| !allowed_ports.contains(port)) {
+                report.warning(
+                    "egress.allow_list",
+                    format!(
+                        "{entry} is allowed on port {port}, but it is not an allowed egress port"
+                    ),
+                );
+            }
+        }
+
+        for port in egress.protocols.keys() {
+            if !allowed_ports.contains(port) {
+                report.warning(
+                    "egress.protocols",
+                    format!("port {port} has a protocol set, but is not an allowed egress port"),
+                );
+            }
+        }
+    }
+
+    if egress.handshake_timeout_ms == 0 {
+        report.fatal(
+            "egress.handshake_timeout_ms",
+            "must be greater than 0, or every egress connection would time out",
+        );
+    }
+}
+
+/// The data plane's config with defaults resolved, for dry runs. Hosts and features are fixed at compile time, so
+/// they're included alongside the feature context.
+pub fn effective_config(
+    data_plane_port: u16,
+    feature_context: Option<&FeatureContext>,
+) -> serde_json::Value {
+    let runtime_flavor = match feature_context {
+        Some(context) if context.runtime.worker_threads.is_some() => "multi_thread",
+        _ => "current_thread",
+    };
+    let mut config = serde_json::json!({
+        "data_plane_port": data_plane_port,
+        "features": {
+            "enclave": cfg!(feature = "enclave"),
+            "tls_termination": cfg!(feature = "tls_termination"),
+            "network_egress": cfg!(feature = "network_egress"),
+            "grpc_crypto_api": cfg!(feature = "grpc_crypto_api"),
+            "mock_crypto": cfg!(feature = "mock_crypto"),
+            "local": cfg!(feature = "local"),
+        },
+        "cert_provisioner_host": get_cert_provisioner_host(),
+        "e3_host": get_e3_host(),
+        "acme_directory": format!("https://{}{}", get_acme_host(), get_acme_base_path()),
+        "forward_proxy_protocol_env": should_forward_proxy_protocol(),
+        "e3_connect_timeout_ms": get_e3_connect_timeout().as_millis() as u64,
+        "e3_request_timeout_ms": get_e3_request_timeout().as_millis() as u64,
+        "crypto_api_port": get_crypto_api_port(),
+        "crypto_api_socket": get_crypto_api_socket(),
+        "crypto_api_max_body_bytes": get_crypto_api_max_body_bytes(),
+        "attestation_doc_cache_ttl_secs": get_attestation_doc_cache_ttl().as_secs(),
+        "crypto_api_rate_limit": get_crypto_api_rate_limit().map(|limit| serde_json::json!({
+            "requests_per_second": limit.requests_per_second,
+            "burst": limit.burst,
+        })),
+        "reattestation_interval_secs": get_reattestation_interval().map(|interval| interval.as_secs()),
+        "tls_min_version": std::env::var(TLS_MIN_VERSION_ENV).unwrap_or_else(|_| "1.2".to_string()),
+        "tls_cipher_suites": IngressTlsConfig::from_env().ok().map(|config| config.cipher_suite_names()),
+        "tls_session_cache_size": get_tls_session_cache_size(),
+        "tls_session_ticket_rotation_secs": get_tls_ticket_rotation().map(|rotation| rotation.as_secs()),
+        "websocket_idle_timeout_secs": get_websocket_idle_timeout().map(|timeout| timeout.as_secs()),
+        "runtime_flavor": runtime_flavor,
+        "handshake_trace": shared::handshake_trace::is_enabled(),
+        "system_stats_interval_secs": std::env::var("SYSTEM_STATS_INTERVAL")
+            .ok()
+            .and_then(|interval| interval.parse::<u64>().ok())
+            .unwrap_or(60),
+        "feature_context": feature_context,
+    });
+    if cfg!(feature = "mock_crypto") {
+        config["mock_crypto"] = serde_json::json!({
+            "seed": dry_run::redacted_env_value("MOCK_CRYPTO_SEED"),
+            "api_key": dry_run::redacted_env_value("MOCK_CRYPTO_API_KEY"),
+        });
+    }
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        effective_config, parse_timeout_ms, validate_config, validate_crypto_api_port,
+        validate_timeout_env, IngressTlsConfig, CRYPTO_API_PORT_ENV, E3_CONNECT_TIMEOUT_ENV,
+        E3_REQUEST_TIMEOUT_ENV,
+    };
+    use crate::FeatureContext;
+    use shared::validation::ValidationReport;
+    use std::time::Duration;
+
+    #[cfg(not(feature = "network_egress"))]
+    const VALID_CONFIG: &str = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [] }"#;
+    #[cfg(feature = "network_egress")]
+    const VALID_CONFIG: &str = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "egress": { "allow_list": "*.evervault.com", "ports": "443" } }"#;
+
+    #[test]
+    fn test_valid_config_has_no_issues() {
+        let report = validate_config(Some("8008"), VALID_CONFIG);
+        assert!(report.issues().is_empty(), "{:?}", report.issues());
+    }
+
+    #[test]
+    fn test_invalid_port_and_config_are_fatal() {
+        let report = validate_config(Some("80800"), VALID_CONFIG);
+        assert!(report.has_fatal());
+        assert_eq!(report.issues()[0].field, "data plane port");
+
+        let report = validate_config(None, r#"{ "api_key_auth": "yes" }"#);
+        assert!(report.has_fatal());
+    }
+
+    #[test]
+    fn test_zero_runtime_threads_are_fatal() {
+        let mut config: serde_json::Value = serde_json::from_str(VALID_CONFIG).unwrap();
+        config["runtime"] = serde_json::json!({ "worker_threads": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(report.issues()[0].field, "runtime.worker_threads");
+    }
+
+    #[test]
+    fn test_empty_caches_are_fatal() {
+        let mut config: serde_json::Value = serde_json::from_str(VALID_CONFIG).unwrap();
+        config["auth_cache"] = serde_json::json!({ "ttl_seconds": 60, "max_entries": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(report.issues()[0].field, "auth_cache.max_entries");
+
+        config["auth_cache"] = serde_json::Value::Null;
+        config["decrypt_cache"] = serde_json::json!({ "ttl_seconds": 30, "max_entries": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(report.issues()[0].field, "decrypt_cache.max_entries");
+
+        config["decrypt_cache"] = serde_json::Value::Null;
+        config["crypto_api_quotas"] = serde_json::json!({ "max_tracked_keys": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(
+            report.issues()[0].field,
+            "crypto_api_quotas.max_tracked_keys"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_e3_resilience_is_fatal() {
+        let mut config: serde_json::Value = serde_json::from_str(VALID_CONFIG).unwrap();
+        config["e3_resilience"] = serde_json::json!({ "hedging": { "percentile": 100 } });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(report.issues()[0].field, "e3_resilience.hedging.percentile");
+
+        config["e3_resilience"] = serde_json::json!({ "retry": { "max_attempts": 0 } });
+        let report = validate_config(None, &config.to_string());
+        assert_eq!(report.issues()[0].field, "e3_resilience.retry.max_attempts");
+
+        config["e3_resilience"] = serde_json::json!({ "retry_budget_percent": 10, "hedging": {} });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.issues().is_empty(), "{:?}", report.issues());
+    }
+
+    #[test]
+    fn test_invalid_e3_timeouts_fall_back_to_the_default() {
+        assert_eq!(parse_timeout_ms("250"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_timeout_ms("0"), None);
+        assert_eq!(parse_timeout_ms("2s"), None);
+
+        let mut report = ValidationReport::new("Data plane");
+        validate_timeout_env(&mut report, E3_REQUEST_TIMEOUT_ENV, Some("2s"));
+        validate_timeout_env(&mut report, E3_CONNECT_TIMEOUT_ENV, Some("500"));
+        validate_timeout_env(&mut report, E3_CONNECT_TIMEOUT_ENV, None);
+        assert!(!report.has_fatal());
+        assert_eq!(report.issues().len(), 1);
+        assert_eq!(report.issues()[0].field, E3_REQUEST_TIMEOUT_ENV);
+    }
+
+    #[test]
+    fn test_crypto_api_port_cant_clash_with_the_customer_process() {
+        let mut report = ValidationReport::new("Data plane");
+        validate_crypto_api_port(&mut report, Some("8008"), Some("9090"));
+        validate_crypto_api_port(&mut report, Some("8008"), None);
+        assert!(report.issues().is_empty(), "{:?}", report.issues());
+
+        validate_crypto_api_port(&mut report, Some("8008"), Some("http"));
+        assert!(!report.has_fatal());
+        assert_eq!(report.issues()[0].field, CRYPTO_API_PORT_ENV);
+
+        validate_crypto_api_port(&mut report, Some("9999"), None);
+        assert!(report.has_fatal());
+    }
+
+    #[test]
+    fn test_e3_connection_pool_needs_an_idle_timeout() {
+        let mut config: serde_json::Value = serde_json::from_str(VALID_CONFIG).unwrap();
+        config["e3_connection_pool"] = serde_json::json!({ "idle_timeout_secs": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(
+            report.issues()[0].field,
+            "e3_connection_pool.idle_timeout_secs"
+        );
+
+        config["e3_connection_pool"] = serde_json::json!({ "max_idle_connections": 0 });
+        let report = validate_config(None, &config.to_string());
+        assert!(report.issues().is_empty(), "{:?}", report.issues());
+    }
+
+    #[cfg(feature = "network_egress")]
+    #[test]
+    fn test_egress_config_issues_are_reported() {
+        let config = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [],
+            "egress": { "allow_list": "https://api.com,db.internal", "ports": "443,5432,abc",
+                "destination_map": [{ "local_port": 5432, "host": "other.internal", "port": 5432 }],
+                "protocols": { "3306": "mysql" } } }"#;
+        let report = validate_config(None, config);
+        assert!(!report.has_fatal(), "{:?}", report.issues());
+        let fields: Vec<&str> = report
+            .issues()
+            .iter()
+            .map(|issue| issue.field.as_str())
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "egress.ports",
+                "egress.allow_list",
+                "egress.destination_map",
+                "egress.protocols"
+            ]
+        );
+
+        let config = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [],
+            "egress": { "allow_list": "*", "ports": "abc" } }"#;
+        assert!(validate_config(None, config).has_fatal());
+
+        let config = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [],
+            "egress": { "allow_list": "api.example.com:443,db.internal:5432", "ports": "443" } }"#;
+        let report = validate_config(None, config);
+        assert_eq!(report.issues().len(), 1);
+        assert_eq!(report.issues()[0].field, "egress.allow_list");
+    }
+
+    #[cfg(feature = "tls_termination")]
+    #[test]
+    fn test_ingress_route_issues_are_reported() {
+        let mut config: serde_json::Value = serde_json::from_str(VALID_CONFIG).unwrap();
+        config["ingress_routes"] = serde_json::json!([
+            { "name": "api", "hosts": ["api.example.com"], "port": 3000 },
+            { "name": "admin", "hosts": ["API.example.com"], "port": 3001, "trx_logging": false }
+        ]);
+        let report = validate_config(None, &config.to_string());
+        assert!(!report.has_fatal(), "{:?}", report.issues());
+        assert_eq!(report.issues().len(), 1);
+
+        config["ingress_routes"] = serde_json::json!([
+            { "name": "api", "hosts": [], "port": 0 },
+            { "name": "api", "hosts": ["api.example.com"], "port": 3000 }
+        ]);
+        let report = validate_config(None, &config.to_string());
+        assert!(report.has_fatal());
+        assert_eq!(report.issues().len(), 3);
+    }
+
+    #[test]
+    fn test_effective_config_resolves_defaults() {
+        let feature_context = FeatureContext::from_json(VALID_CONFIG).unwrap();
+        let config = effective_config(8008, Some(&feature_context));
+        assert_eq!(config["data_plane_port"], 8008);
+        assert_eq!(config["runtime_flavor"], "current_thread");
+        assert_eq!(config["feature_context"]["api_key_auth"], true);
+        assert!(config["feature_context"]["runtime"].is_object());
+
+        let config = effective_config(8008, None);
+        assert!(config["feature_context"].is_null());
+    }
+
+    #[test]
+    fn test_ingress_tls_config_is_restricted_by_version_and_allow_list() {
+        let config = IngressTlsConfig::parse(None, None).unwrap();
+        assert_eq!(config.protocol_versions.len(), 2);
+        assert!(config
+            .cipher_suite_names()
+            .contains(&"TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string()));
+
+        let config = IngressTlsConfig::parse(Some("1.3"), None).unwrap();
+        assert_eq!(config.protocol_versions.len(), 1);
+        assert!(config
+            .cipher_suite_names()
+            .iter()
+            .all(|name| name.starts_with("TLS13_")));
+
+        let config = IngressTlsConfig::parse(
+            Some("1.3"),
+            Some("TLS13_AES_256_GCM_SHA384, TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.cipher_suite_names(),
+            vec!["TLS13_AES_256_GCM_SHA384".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_invalid_ingress_tls_config_is_rejected() {
+        assert!(IngressTlsConfig::parse(Some("1.1"), None).is_err());
+        assert!(IngressTlsConfig::parse(None, Some("TLS_RSA_WITH_RC4_128_MD5")).is_err());
+        assert!(IngressTlsConfig::parse(
+            Some("1.3"),
+            Some("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256")
+        )
+        .is_err());
+    }
+}
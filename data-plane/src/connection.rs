@@ -1,10 +1,13 @@
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub type Connection = tokio::net::TcpStream;
 
+#[cfg(feature = "local")]
+pub type Connection = tokio::io::DuplexStream;
+
 #[cfg(feature = "enclave")]
 pub type Connection = tokio_vsock::VsockStream;
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub async fn get_socket(port: u16) -> Result<Connection, tokio::io::Error> {
     Connection::connect(std::net::SocketAddr::new(
         std::net::IpAddr::V4(std::net::Ipv4Addr::new(172, 20, 0, 8)),
@@ -13,7 +16,88 @@ pub async fn get_socket(port: u16) -> Result<Connection, tokio::io::Error> {
     .await
 }
 
+#[cfg(feature = "local")]
+pub async fn get_socket(port: u16) -> Result<Connection, tokio::io::Error> {
+    shared::server::get_vsock_client(port, shared::server::CID::Parent).await
+}
+
 #[cfg(feature = "enclave")]
 pub async fn get_socket(port: u16) -> Result<Connection, tokio::io::Error> {
     Connection::connect(shared::PARENT_CID, port.into()).await
 }
+
+/// Connector for hyper clients which opens connections to a port on the host, ignoring the request URI.
+/// Allows hyper's pooled `Client` to be used over the enclave's connection to the host.
+#[derive(Clone, Debug)]
+pub struct HostConnector {
+    port: u16,
+}
+
+impl HostConnector {
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl tower::Service<hyper::Uri> for HostConnector {
+    type Response = HostConnection;
+    type Error = tokio::io::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: hyper::Uri) -> Self::Future {
+        let port = self.port;
+        Box::pin(async move { get_socket(port).await.map(HostConnection) })
+    }
+}
+
+/// Wrapper around a host connection implementing hyper's `Connection` trait
+pub struct HostConnection(Connection);
+
+impl hyper::client::connect::Connection for HostConnection {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
+
+impl tokio::io::AsyncRead for HostConnection {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for HostConnection {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        std::pin::Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::pin::Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,146 @@
+//! Crash telemetry. Panics and fatal errors are reported to the control plane over vsock before the data plane
+//! aborts, so enclave crashes can be diagnosed from the host without console access.
+use sha2::{Digest, Sha256};
+use shared::server::config_server::requests::{CrashKind, CrashReport};
+use std::backtrace::Backtrace;
+use std::panic::PanicHookInfo;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::config_client::ConfigClient;
+
+/// How long a crash report can hold up the abort, in case the control plane is unreachable
+const CRASH_REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+/// Set by the first crash, so a panic while reporting aborts straight away
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Report panics to the control plane, then abort rather than unwinding, so a panicked task can't leave the data
+/// plane running in a broken state.
+pub fn install_panic_hook() {
+    STARTED_AT.get_or_init(Instant::now);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if !CRASHING.swap(true, Ordering::SeqCst) {
+            let backtrace = Backtrace::force_capture().to_string();
+            send_crash_report(panic_report(info, &backtrace));
+        }
+        std::process::abort();
+    }));
+}
+
+/// Report an error the data plane can't recover from, then exit.
+pub fn report_fatal_error(message: impl std::fmt::Display) -> ! {
+    log::error!("Fatal error in data plane — {message}");
+    if !CRASHING.swap(true, Ordering::SeqCst) {
+        let backtrace = Backtrace::force_capture().to_string();
+        send_crash_report(crash_report(
+            CrashKind::Fatal,
+            message.to_string(),
+            None,
+            &backtrace,
+        ));
+    }
+    std::process::exit(1);
+}
+
+fn panic_report(info: &PanicHookInfo<'_>, backtrace: &str) -> CrashReport {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+    let location = info.location().map(|location| {
+        format!(
+            "{}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )
+    });
+    crash_report(CrashKind::Panic, message, location, backtrace)
+}
+
+fn crash_report(
+    kind: CrashKind,
+    message: String,
+    location: Option<String>,
+    backtrace: &str,
+) -> CrashReport {
+    CrashReport {
+        kind,
+        message,
+        location,
+        backtrace_hash: backtrace_hash(backtrace),
+        uptime_secs: STARTED_AT
+            .get()
+            .map_or(0, |started_at| started_at.elapsed().as_secs()),
+        thread: std::thread::current().name().map(str::to_string),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+fn backtrace_hash(backtrace: &str) -> String {
+    Sha256::digest(backtrace.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Send the report from a thread with its own runtime, as the crash may have happened on the data plane's runtime.
+fn send_crash_report(report: CrashReport) {
+    let sender = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .ok()?;
+        runtime.block_on(async {
+            tokio::time::timeout(
+                CRASH_REPORT_TIMEOUT,
+                ConfigClient::new().post_crash_report(report),
+            )
+            .await
+            .ok()
+        })
+    });
+    match sender.join() {
+        Ok(Some(Ok(()))) => {}
+        Ok(Some(Err(e))) => eprintln!("Failed to send crash report to the control plane — {e}"),
+        _ => eprintln!("Couldn't send crash report to the control plane in time"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crash_reports_group_by_backtrace() {
+        let report = crash_report(
+            CrashKind::Panic,
+            "index out of bounds".to_string(),
+            Some("src/lib.rs:1:1".to_string()),
+            "0: data_plane::startup::run",
+        );
+        let same_crash = crash_report(
+            CrashKind::Fatal,
+            "index out of bounds".to_string(),
+            None,
+            "0: data_plane::startup::run",
+        );
+        let other_crash = crash_report(
+            CrashKind::Panic,
+            "index out of bounds".to_string(),
+            None,
+            "0: data_plane::server::run",
+        );
+        assert_eq!(report.backtrace_hash.len(), 16);
+        assert_eq!(report.backtrace_hash, same_crash.backtrace_hash);
+        assert_ne!(report.backtrace_hash, other_crash.backtrace_hash);
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+    }
+}
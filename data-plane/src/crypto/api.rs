@@ -1,26 +1,82 @@
+use hyper::body::HttpBody;
+use hyper::header::HeaderValue;
 use hyper::{self, Body};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_json::{self};
 use shared::server::error::ServerResult;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
 use thiserror::Error;
+use tokio::net::UnixListener;
+use zeroize::Zeroizing;
 
+use bytes::{Bytes, BytesMut};
+use cached::Cached;
+use futures::{StreamExt, TryStreamExt};
 use hyper::{
     service::{make_service_fn, service_fn},
-    Method, Request, Response, Server,
+    Request, Response, Server,
 };
 
 use crate::base_tls_client::ClientError;
-use crate::e3client::{CryptoRequest, CryptoResponse, E3Api, E3Client};
+use crate::cache::{AuthCache, DecryptCache, APP_PUBLIC_KEY, AUTH_CACHE, DECRYPT_CACHE};
+use crate::configuration;
+#[cfg(feature = "tls_termination")]
+use crate::e3client::DecryptedPayload;
+use crate::e3client::{
+    AuthRequest, BinaryRequest, CryptoRequest, CryptoResponse, E3Api, E3Client, SignRequest,
+    VerifyRequest, KEY_VERSION_HEADER,
+};
 use crate::error::Error;
-use crate::ContextError;
+use crate::stats_client::StatsClient;
+use crate::utils::payload_format::{PayloadFormat, PayloadFormatError};
+use crate::{ContextError, EnclaveContext, FeatureContext};
 
+use super::asymmetric::{self, AsymmetricEncryptRequest, AsymmetricEncryptionError};
 #[cfg(feature = "enclave")]
 use super::attest;
+use super::blob::BlobEncryptionError;
+use super::fields::{self, FieldPath, FieldPathError};
+use super::jwt::{JwtError, JwtSigner};
+#[cfg(not(feature = "enclave"))]
+use super::mock_attest;
+use super::ndjson::{self, NDJSON_CONTENT_TYPE};
+use super::quota::{QuotaError, QuotaTracker, CRYPTO_API_QUOTAS};
+use super::rate_limit::{RateLimitError, RateLimiter, CRYPTO_API_RATE_LIMITER};
+use super::routes::{self, ApiVersion, Route, RouteError, VersionsResponse, API_VERSION_HEADER};
+use shared::error_code::{codes, ErrorCode, HasErrorCode, ERROR_CODE_HEADER};
+
+/// Number of records from a stream sent to E3 at once
+const STREAM_CONCURRENCY: usize = 16;
+/// Most values a batch request can hold
+const MAX_BATCH_SIZE: usize = 1000;
+/// Most random bytes returned by a single request
+const MAX_RANDOM_BYTES: usize = 4096;
+/// Values sent to E3 in each request for a batch, so batches up to this size take a single E3 round trip
+const BATCH_CHUNK_SIZE: usize = 100;
+
+/// Set to `true` on decrypt requests to return each ciphertext's metadata alongside the plaintext. Ciphertexts are read
+/// with the same parser as decryption of TLS terminated traffic, so this needs the tls_termination feature.
+#[cfg(feature = "tls_termination")]
+const DECRYPT_METADATA_HEADER: &str = "x-evervault-decrypt-metadata";
+
+#[derive(Clone, Copy)]
+enum BulkOperation {
+    Encrypt,
+    Decrypt,
+}
 
 pub struct CryptoApi {
     e3_client: E3Client,
+    /// Whether api keys presented to the API are checked with E3, as they are on ingress
+    authenticate_api_keys: bool,
+    auth_cache: Option<&'static AuthCache>,
+    decrypt_cache: Option<&'static DecryptCache>,
+    quotas: Option<&'static QuotaTracker>,
+    max_body_bytes: usize,
+    rate_limiter: Option<&'static RateLimiter>,
 }
 
 impl Default for CryptoApi {
@@ -51,15 +107,124 @@ pub enum CryptoApiError {
     ContextError(#[from] ContextError),
     #[error("Error — {0:?}")]
     Error(#[from] Error),
+    #[error("{0}")]
+    QuotaExceeded(#[from] QuotaError),
+    #[error("Failed to encode response — {0}")]
+    PayloadFormat(#[from] PayloadFormatError),
+    #[error("Invalid record — {0}")]
+    InvalidRecord(String),
+    #[error("Invalid batch — {0}")]
+    InvalidBatch(String),
+    #[error("Invalid binary payload — {0}")]
+    InvalidBinary(String),
+    #[error("{0}")]
+    FieldPath(#[from] FieldPathError),
+    #[error("Invalid random request — {0}")]
+    InvalidRandomRequest(String),
+    #[error("Invalid signing request — {0}")]
+    InvalidSigningRequest(String),
+    #[error("Invalid attestation request — {0}")]
+    InvalidAttestationRequest(String),
+    #[error("Invalid key version {0}, expected a ciphertext version tag such as Tk9D")]
+    InvalidKeyVersion(String),
+    #[error("Invalid upload request — {0}")]
+    InvalidUpload(String),
+    #[error("Upload failed — {0}")]
+    UploadFailed(String),
+    #[error("{0}")]
+    BlobEncryption(#[from] BlobEncryptionError),
+    #[error("{0}")]
+    AsymmetricEncryption(#[from] AsymmetricEncryptionError),
+    #[error("{0}")]
+    Jwt(#[from] JwtError),
+    #[error("{0}")]
+    Route(#[from] RouteError),
+    #[error("Request body is larger than the limit of {0} bytes")]
+    BodyTooLarge(usize),
+    #[error("{0}")]
+    RateLimited(#[from] RateLimitError),
+    #[error("Invalid api key provided")]
+    InvalidApiKey,
+}
+
+impl HasErrorCode for CryptoApiError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::MissingEnclaveContext(_) | Self::ContextError(_) => codes::CONFIG_MISSING,
+            Self::SerdeError(_)
+            | Self::SerializationError
+            | Self::InvalidRecord(_)
+            | Self::InvalidBatch(_)
+            | Self::InvalidBinary(_)
+            | Self::InvalidSigningRequest(_)
+            | Self::InvalidAttestationRequest(_)
+            | Self::FieldPath(_)
+            | Self::InvalidKeyVersion(_) => codes::INVALID_PAYLOAD,
+            Self::InvalidRandomRequest(_) => codes::BAD_REQUEST,
+            Self::HyperError(_) => codes::CONNECTION_FAILED,
+            Self::ClientError(e) => e.error_code(),
+            #[cfg(feature = "enclave")]
+            Self::Attestation(e) => e.error_code(),
+            Self::NotFound => codes::NOT_FOUND,
+            Self::Error(e) => e.error_code(),
+            Self::QuotaExceeded(_) => codes::QUOTA_EXCEEDED,
+            Self::PayloadFormat(_) => codes::INTERNAL,
+            Self::InvalidUpload(_) => codes::BAD_REQUEST,
+            Self::UploadFailed(_) => codes::UPSTREAM_FAILED,
+            Self::BlobEncryption(
+                BlobEncryptionError::InvalidBlob(_) | BlobEncryptionError::LengthMismatch,
+            ) => codes::INVALID_PAYLOAD,
+            Self::BlobEncryption(_) | Self::Jwt(JwtError::Openssl(_)) => codes::CRYPTO_FAILED,
+            Self::AsymmetricEncryption(AsymmetricEncryptionError::Openssl(_)) => {
+                codes::CRYPTO_FAILED
+            }
+            Self::AsymmetricEncryption(_) => codes::INVALID_PAYLOAD,
+            Self::Jwt(JwtError::InvalidClaims) => codes::INVALID_PAYLOAD,
+            Self::Jwt(_) => codes::INVALID_TOKEN,
+            Self::Route(RouteError::UnsupportedVersion(_)) => codes::UNSUPPORTED_VERSION,
+            Self::BodyTooLarge(_) => codes::PAYLOAD_TOO_LARGE,
+            Self::RateLimited(_) => codes::RATE_LIMITED,
+            Self::InvalidApiKey => codes::INVALID_API_KEY,
+        }
+    }
 }
 
+/// v1 errors keep their original statuses and plain text bodies, with the error code in a header.
 impl From<CryptoApiError> for hyper::Response<hyper::Body> {
     fn from(err: CryptoApiError) -> Self {
-        match err {
+        let code = err.error_code().to_string();
+        let mut response = match err {
             CryptoApiError::SerdeError(error) => build_response(400, error.to_string()),
             CryptoApiError::SerializationError => build_response(400, err.to_string()),
+            CryptoApiError::QuotaExceeded(_) => build_response(429, err.to_string()),
+            CryptoApiError::InvalidUpload(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidKeyVersion(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidBatch(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidBinary(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidSigningRequest(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidAttestationRequest(_) => build_response(400, err.to_string()),
+            CryptoApiError::InvalidRandomRequest(_) => build_response(400, err.to_string()),
+            CryptoApiError::FieldPath(_) => build_response(400, err.to_string()),
+            CryptoApiError::BlobEncryption(
+                BlobEncryptionError::InvalidBlob(_) | BlobEncryptionError::LengthMismatch,
+            ) => build_response(400, err.to_string()),
+            CryptoApiError::BodyTooLarge(_) => build_response(413, err.to_string()),
+            CryptoApiError::RateLimited(_) => build_response(429, err.to_string()),
+            CryptoApiError::InvalidApiKey => build_response(401, err.to_string()),
+            CryptoApiError::UploadFailed(_) => build_response(502, err.to_string()),
+            CryptoApiError::AsymmetricEncryption(AsymmetricEncryptionError::Openssl(_)) => {
+                build_response(500, err.to_string())
+            }
+            CryptoApiError::AsymmetricEncryption(_) => build_response(400, err.to_string()),
+            CryptoApiError::Jwt(JwtError::Openssl(_)) => build_response(500, err.to_string()),
+            CryptoApiError::Jwt(JwtError::InvalidClaims) => build_response(400, err.to_string()),
+            CryptoApiError::Jwt(_) => build_response(401, err.to_string()),
             _ => build_response(500, err.to_string()),
+        };
+        if let Ok(code) = HeaderValue::from_str(&code) {
+            response.headers_mut().insert(ERROR_CODE_HEADER, code);
         }
+        response
     }
 }
 
@@ -73,110 +238,1504 @@ fn build_response(status: u16, body: String) -> hyper::Response<hyper::Body> {
 
 impl CryptoApi {
     pub fn new() -> Self {
+        let feature_context = FeatureContext::get().ok();
+        let decrypt_cache = feature_context
+            .as_ref()
+            .and_then(|context| context.decrypt_cache.as_ref())
+            .map(|config| DECRYPT_CACHE.get_or_init(|| DecryptCache::new(config)));
+        let quotas = feature_context
+            .as_ref()
+            .and_then(|context| context.crypto_api_quotas.as_ref())
+            .map(|config| CRYPTO_API_QUOTAS.get_or_init(|| QuotaTracker::new(config)));
+        let auth_cache = feature_context
+            .as_ref()
+            .and_then(|context| context.auth_cache.as_ref())
+            .map(|config| AUTH_CACHE.get_or_init(|| AuthCache::new(config)));
         Self {
             e3_client: E3Client::new(),
+            authenticate_api_keys: feature_context
+                .as_ref()
+                .is_some_and(|context| context.api_key_auth),
+            auth_cache,
+            decrypt_cache,
+            quotas,
+            max_body_bytes: configuration::get_crypto_api_max_body_bytes(),
+            rate_limiter: configuration::get_crypto_api_rate_limit()
+                .map(|config| CRYPTO_API_RATE_LIMITER.get_or_init(|| RateLimiter::new(config))),
         }
     }
 
+    /// Serve the Crypto API on a unix socket if one is configured, so no TCP port needs to be open in the enclave,
+    /// or on a loopback port otherwise.
     pub async fn listen() -> ServerResult<()> {
-        log::info!("Crypto API started");
-
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 9999);
+        if let Some(socket_path) = configuration::get_crypto_api_socket() {
+            let listener = Self::bind_unix_socket(&socket_path)?;
+            log::info!("Crypto API started on {}", socket_path.display());
+            let incoming = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+            let service = make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(service_fn(|req| Self::api(CryptoApi::new(), req)))
+            });
+            Server::builder(incoming).serve(service).await?;
+            return Ok(());
+        }
 
+        let addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            configuration::get_crypto_api_port(),
+        );
+        log::info!("Crypto API started on {addr}");
         let service = make_service_fn(|_| async {
             Ok::<_, hyper::Error>(service_fn(|req| Self::api(CryptoApi::new(), req)))
         });
-        let _ = Server::bind(&addr).serve(service).await;
-
-        #[allow(unreachable_code)]
+        Server::bind(&addr).serve(service).await?;
         Ok(())
     }
 
-    async fn api(
+    /// Bind the Crypto API's unix socket, replacing any left behind by a previous run.
+    fn bind_unix_socket(socket_path: &Path) -> std::io::Result<UnixListener> {
+        if let Some(parent) = socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match std::fs::remove_file(socket_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        UnixListener::bind(socket_path)
+    }
+
+    async fn api(self, req: Request<Body>) -> Result<hyper::Response<hyper::Body>, CryptoApiError> {
+        let (version, path) = match routes::negotiate(req.uri().path(), req.headers()) {
+            Ok(negotiated) => negotiated,
+            // Callers asking for a version are new enough to handle structured errors
+            Err(e) => return Ok(CryptoApiError::from(e).to_error_response()),
+        };
+
+        let route = version.route(req.method(), path);
+        let response = match self.check_rate_limit(req.headers()).await {
+            Ok(()) => match self
+                .check_api_key(req.headers().get("api-key").map(|key| key.as_bytes()))
+                .await
+            {
+                Ok(()) => self.dispatch(route, version, req).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let retry_after = match &response {
+            Err(CryptoApiError::RateLimited(e)) => Some(e.retry_after),
+            _ => None,
+        };
+
+        let mut response = match (version, response) {
+            (_, Ok(response)) => response,
+            (ApiVersion::V1, Err(error)) => error.into(),
+            (ApiVersion::V2, Err(error)) => error.to_error_response(),
+        };
+        response
+            .headers_mut()
+            .insert(API_VERSION_HEADER, version.header_value());
+        if let Some(retry_after) = retry_after {
+            // Retry-After is in whole seconds, so round up to avoid clients retrying too soon
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(hyper::header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        Ok(response)
+    }
+
+    // Versions only differ in how errors are returned so far, including errors reported in place in streams.
+    // Handlers which change in a later version should match on it here.
+    async fn dispatch(
         mut self,
+        route: Option<Route>,
+        version: ApiVersion,
         req: Request<Body>,
-    ) -> Result<hyper::Response<hyper::Body>, CryptoApiError> {
-        let response = match (req.method(), req.uri().path()) {
-            (&Method::POST, "/encrypt") => self.encrypt(req).await,
-            (&Method::POST, "/decrypt") => self.decrypt(req).await,
-            (&Method::POST, "/attestation-doc") => self.get_attestation_doc(req).await,
-            _ => Err(CryptoApiError::NotFound),
+    ) -> Result<Response<Body>, CryptoApiError> {
+        match route {
+            Some(Route::Versions) => Self::get_versions(),
+            Some(Route::Encrypt) => match self.enforce_quota(req).await {
+                Ok(req) => self.encrypt(req).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::EncryptAsymmetric) => match self.enforce_quota(req).await {
+                Ok(req) => self.encrypt_asymmetric(req).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::Decrypt) => match self.enforce_quota(req).await {
+                Ok(req) => self.decrypt(req).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::EncryptStream) => self.process_stream(req, BulkOperation::Encrypt, version),
+            Some(Route::DecryptStream) => self.process_stream(req, BulkOperation::Decrypt, version),
+            Some(Route::EncryptBatch) => match self.enforce_quota(req).await {
+                Ok(req) => self.process_batch(req, BulkOperation::Encrypt).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::DecryptBatch) => match self.enforce_quota(req).await {
+                Ok(req) => self.process_batch(req, BulkOperation::Decrypt).await,
+                Err(e) => Err(e),
+            },
+            #[cfg(feature = "network_egress")]
+            Some(Route::EncryptBlob) => self.encrypt_blob(req).await,
+            Some(Route::DecryptBlob) => self.decrypt_blob(req).await,
+            Some(Route::AttestationDoc) => self.get_attestation_doc(req).await,
+            Some(Route::AttestationPcrs) => Self::get_attestation_pcrs(),
+            Some(Route::Random) => Self::get_random(req),
+            Some(Route::Sign) => match self.enforce_quota(req).await {
+                Ok(req) => self.sign(req).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::Verify) => match self.enforce_quota(req).await {
+                Ok(req) => self.verify(req).await,
+                Err(e) => Err(e),
+            },
+            Some(Route::SignToken) => self.sign_token(req).await,
+            Some(Route::VerifyToken) => self.verify_token(req).await,
+            Some(Route::Jwks) => self.get_jwks(),
+            None => Err(CryptoApiError::NotFound),
+        }
+    }
+
+    /// Check a presented api key with E3 when api key auth is enabled, so the keys which scope quotas, rate limits and
+    /// cached plaintexts are genuine. Results are shared with ingress through the auth cache. Requests without a key
+    /// are let through, sharing the keyless quota.
+    pub(crate) async fn check_api_key(&self, api_key: Option<&[u8]>) -> Result<(), CryptoApiError> {
+        let Some(api_key) = api_key.filter(|_| self.authenticate_api_keys) else {
+            return Ok(());
+        };
+        let mut api_key =
+            HeaderValue::from_bytes(api_key).map_err(|_| CryptoApiError::InvalidApiKey)?;
+        api_key.set_sensitive(true);
+        let auth_payload = AuthRequest::from(&EnclaveContext::get()?);
+        match self
+            .e3_client
+            .authenticate_api_key(&api_key, auth_payload, self.auth_cache)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(ClientError::FailedRequest(status)) if status.as_u16() == 401 => {
+                Err(CryptoApiError::InvalidApiKey)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Take a token from the api key's bucket, if rate limiting is configured.
+    async fn check_rate_limit(&self, headers: &hyper::HeaderMap) -> Result<(), CryptoApiError> {
+        let Some(rate_limiter) = self.rate_limiter else {
+            return Ok(());
         };
+        let api_key = headers.get("api-key").map(|key| key.as_bytes());
+        if let Err(e) = rate_limiter.check(api_key).await {
+            StatsClient::record_crypto_api_rate_limited();
+            return Err(e.into());
+        }
+        Ok(())
+    }
 
-        match response {
-            Ok(body) => Ok(Response::builder()
-                .body(body)
-                .expect("Failed to build response")),
-            Err(error) => Ok(error.into()),
+    fn get_versions() -> Result<Response<Body>, CryptoApiError> {
+        let response_body = serde_json::to_vec(&VersionsResponse::default())?;
+        Ok(Self::build_payload_response(
+            PayloadFormat::Json,
+            response_body,
+        ))
+    }
+
+    fn build_raw_response(payload: impl Into<Body>) -> Response<Body> {
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, ndjson::RAW_CONTENT_TYPE)
+            .body(payload.into())
+            .expect("Failed to build response")
+    }
+
+    fn build_payload_response(format: PayloadFormat, payload: Vec<u8>) -> Response<Body> {
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, format.content_type())
+            .body(Body::from(payload))
+            .expect("Failed to build response")
+    }
+
+    /// Check the request against the api key's quota, if quotas are configured. The body is buffered to measure
+    /// it, and handed back in the returned request.
+    async fn enforce_quota(&self, req: Request<Body>) -> Result<Request<Body>, CryptoApiError> {
+        if self.quotas.is_none() {
+            return Ok(req);
+        }
+        let (parts, body) = req.into_parts();
+        let body_bytes = self.read_body(body).await?;
+        let api_key = parts.headers.get("api-key").map(|key| key.as_bytes());
+        self.check_quota(api_key, body_bytes.len() as u64).await?;
+        Ok(Request::from_parts(parts, Body::from(body_bytes)))
+    }
+
+    /// Buffer a request body, rejecting it once it's larger than the max body size so a runaway client can't exhaust
+    /// the enclave's memory. Bodies which declare their length up front are rejected before any of it is read.
+    async fn read_body(&self, mut body: Body) -> Result<Bytes, CryptoApiError> {
+        if body.size_hint().lower() > self.max_body_bytes as u64 {
+            return Err(CryptoApiError::BodyTooLarge(self.max_body_bytes));
         }
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if buffer.len() + chunk.len() > self.max_body_bytes {
+                return Err(CryptoApiError::BodyTooLarge(self.max_body_bytes));
+            }
+            buffer.extend_from_slice(&chunk);
+        }
+        Ok(buffer.freeze())
     }
 
-    async fn build_request(&mut self, req: Request<Body>) -> Result<CryptoRequest, CryptoApiError> {
-        let (_, body) = req.into_parts();
-        let body_bytes = hyper::body::to_bytes(body).await?;
-        let body: Value =
-            serde_json::from_slice(&body_bytes).map_err(|_| CryptoApiError::SerializationError)?;
+    pub(crate) async fn check_quota(
+        &self,
+        api_key: Option<&[u8]>,
+        request_bytes: u64,
+    ) -> Result<(), CryptoApiError> {
+        let Some(quotas) = self.quotas else {
+            return Ok(());
+        };
+        if let Err(e) = quotas.check_and_record(api_key, request_bytes).await {
+            StatsClient::record_crypto_api_quota_exceeded();
+            return Err(e.into());
+        }
+        StatsClient::record_crypto_api_usage(request_bytes);
+        Ok(())
+    }
+
+    fn parse_request_body(
+        format: PayloadFormat,
+        body_bytes: &[u8],
+    ) -> Result<CryptoRequest, CryptoApiError> {
+        let body: Value = format
+            .decode(body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
         let payload = CryptoRequest::new(body);
         Ok(payload)
     }
 
-    async fn encrypt(&mut self, req: Request<Body>) -> Result<Body, CryptoApiError> {
+    /// The key version a request pins encryption to, if any.
+    fn key_version(headers: &hyper::HeaderMap) -> Result<Option<String>, CryptoApiError> {
+        let Some(key_version) = headers.get(KEY_VERSION_HEADER) else {
+            return Ok(None);
+        };
+        let key_version = String::from_utf8_lossy(key_version.as_bytes()).into_owned();
+        let is_version_tag =
+            key_version.len() == 4 && key_version.bytes().all(|byte| byte.is_ascii_alphanumeric());
+        if !is_version_tag {
+            return Err(CryptoApiError::InvalidKeyVersion(key_version));
+        }
+        Ok(Some(key_version))
+    }
+
+    async fn encrypt(&mut self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
         let data_role = req
             .headers()
             .get("x-evervault-data-role")
             .and_then(|role| role.to_str().ok())
             .map(|role_str| role_str.to_string());
-        let request = self.build_request(req).await?;
+        let key_version = Self::key_version(req.headers())?;
+        let fields = fields::from_query(req.uri().query())?;
+        if ndjson::is_raw_request(req.headers()) {
+            if fields.is_some() {
+                return Err(CryptoApiError::InvalidBinary(
+                    "fields can't be selected in a binary payload".to_string(),
+                ));
+            }
+            let body_bytes = self.read_body(req.into_body()).await?;
+            return self
+                .encrypt_binary(&body_bytes, data_role, key_version)
+                .await;
+        }
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let response_body = match fields {
+            Some(fields) => {
+                self.encrypt_fields(
+                    &body_bytes,
+                    request_format,
+                    response_format,
+                    data_role,
+                    key_version,
+                    &fields,
+                )
+                .await?
+            }
+            None => {
+                self.encrypt_bytes(
+                    &body_bytes,
+                    request_format,
+                    response_format,
+                    data_role,
+                    key_version,
+                )
+                .await?
+            }
+        };
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// Encrypt a raw body as a single value, returning its ciphertext as the raw response body.
+    async fn encrypt_binary(
+        &self,
+        body_bytes: &[u8],
+        data_role: Option<String>,
+        key_version: Option<String>,
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let request = BinaryRequest::new(body_bytes).with_key_version(key_version);
         let e3_response: CryptoResponse = self
             .e3_client
             .encrypt_with_retries(2, request, data_role)
             .await?;
-        Ok(hyper::Body::from(serde_json::to_vec(&e3_response.data)?))
+        let Value::String(ciphertext) = e3_response.data else {
+            return Err(ClientError::General("E3 returned an unexpected ciphertext".into()).into());
+        };
+        Ok(Self::build_raw_response(ciphertext))
     }
 
-    async fn decrypt(&mut self, req: Request<Body>) -> Result<Body, CryptoApiError> {
-        let request = self.build_request(req).await?;
-        let e3_response: CryptoResponse = self.e3_client.decrypt_with_retries(2, request).await?;
-        Ok(hyper::Body::from(serde_json::to_vec(&e3_response.data)?))
+    /// Decrypt the ciphertext of a raw body encrypted by `encrypt_binary`, returning the original bytes.
+    async fn decrypt_binary(
+        &self,
+        api_key: Option<&[u8]>,
+        body_bytes: &[u8],
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let ciphertext = std::str::from_utf8(body_bytes)
+            .map_err(|_| CryptoApiError::InvalidBinary("Ciphertext isn't UTF-8".to_string()))?;
+        let request_body = serde_json::to_vec(ciphertext.trim())?;
+        let plaintext = self
+            .decrypt_value(api_key, &request_body, PayloadFormat::Json)
+            .await?;
+        let bytes = BinaryRequest::decode_plaintext(&plaintext).ok_or_else(|| {
+            CryptoApiError::InvalidBinary("Ciphertext isn't a binary payload".to_string())
+        })?;
+        Ok(Self::build_raw_response(bytes))
     }
 
-    #[cfg(feature = "enclave")]
-    async fn get_attestation_doc(self, mut req: Request<Body>) -> Result<Body, CryptoApiError> {
-        use hyper::body;
+    /// Encrypt only the fields of a document selected by the given paths, leaving the rest in plaintext. The
+    /// selected fields are sent to E3 together in a single request.
+    async fn encrypt_fields(
+        &self,
+        body_bytes: &[u8],
+        request_format: PayloadFormat,
+        response_format: PayloadFormat,
+        data_role: Option<String>,
+        key_version: Option<String>,
+        fields: &[FieldPath],
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        let mut document: Value = request_format
+            .decode(body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        let pointers = fields::select(&document, fields);
+        if pointers.is_empty() {
+            return Ok(response_format.encode(&document)?);
+        }
+        let selected = pointers
+            .iter()
+            .filter_map(|pointer| document.pointer_mut(pointer).map(Value::take))
+            .collect();
+        let request = CryptoRequest::new(Value::Array(selected)).with_key_version(key_version);
+        let e3_response: CryptoResponse = self
+            .e3_client
+            .encrypt_with_retries(2, request, data_role)
+            .await?;
+        let encrypted = match e3_response.data {
+            Value::Array(encrypted) if encrypted.len() == pointers.len() => encrypted,
+            _ => return Err(ClientError::General("E3 returned unexpected fields".into()).into()),
+        };
+        for (pointer, value) in pointers.iter().zip(encrypted) {
+            if let Some(field) = document.pointer_mut(pointer) {
+                *field = value;
+            }
+        }
+        Ok(response_format.encode(&document)?)
+    }
 
-        let body = req.body_mut();
-        let bytes = body::to_bytes(body).await?;
-        let body: Value = serde_json::from_slice(&bytes)?;
-        let ad_request: AttestationRequest = serde_json::from_value(body)?;
-        let challenge = ad_request.challenge.map(|chal| chal.as_bytes().to_vec());
-        let nonce = ad_request.nonce.map(|non| non.as_bytes().to_vec());
-        let doc = attest::get_attestation_doc(challenge, nonce)?;
-        Ok(Body::from(doc))
+    /// Encrypt the request's data for the given recipients' public keys, or the app's public key if none are given,
+    /// so only holders of the matching private keys can decrypt it.
+    async fn encrypt_asymmetric(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let request: AsymmetricEncryptRequest = request_format
+            .decode(&body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        let recipients = if request.recipients.is_empty() {
+            vec![self.app_public_key().await?]
+        } else {
+            request.recipients
+        };
+        let plaintext = serde_json::to_vec(&request.data)?;
+        let sealed = asymmetric::seal(&plaintext, &recipients)?;
+        let response_body = response_format.encode(&sealed)?;
+        Ok(Self::build_payload_response(response_format, response_body))
     }
 
-    #[cfg(not(feature = "enclave"))]
-    async fn get_attestation_doc(self, _: Request<Body>) -> Result<Body, CryptoApiError> {
-        #[derive(Serialize, Deserialize, Debug)]
-        struct TestData {
-            pcr0: String,
-            pcr1: String,
-            pcr2: String,
-            pcr8: String,
+    async fn app_public_key(&self) -> Result<String, CryptoApiError> {
+        let cache_key = "app_public_key".to_string();
+        let mut cache = APP_PUBLIC_KEY.lock().await;
+        if let Some(public_key) = cache.cache_get(&cache_key) {
+            return Ok(public_key.clone());
+        }
+        let app_key = self.e3_client.get_app_public_key().await?;
+        cache.cache_set(cache_key, app_key.public_key.clone());
+        Ok(app_key.public_key)
+    }
+
+    async fn decrypt(&mut self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let (parts, body) = req.into_parts();
+        let request_format = PayloadFormat::of_request(&parts.headers);
+        let response_format = PayloadFormat::accepted(&parts.headers);
+        let body_bytes = self.read_body(body).await?;
+        let api_key = parts.headers.get("api-key").map(|key| key.as_bytes());
+        if ndjson::is_raw_request(&parts.headers) {
+            return self.decrypt_binary(api_key, &body_bytes).await;
+        }
+        #[cfg(feature = "tls_termination")]
+        if parts
+            .headers
+            .get(DECRYPT_METADATA_HEADER)
+            .is_some_and(|include| include.as_bytes().eq_ignore_ascii_case(b"true"))
+        {
+            let response_body = self
+                .decrypt_bytes_with_metadata(api_key, &body_bytes, request_format, response_format)
+                .await?;
+            return Ok(Self::build_decrypt_response(
+                response_format,
+                response_body,
+                request_format,
+                &body_bytes,
+            ));
+        }
+        let response_body = self
+            .decrypt_bytes(api_key, &body_bytes, request_format, response_format)
+            .await?;
+        Ok(Self::build_decrypt_response(
+            response_format,
+            response_body,
+            request_format,
+            &body_bytes,
+        ))
+    }
+
+    /// Decrypt responses report the key versions of the decrypted ciphertexts.
+    fn build_decrypt_response(
+        response_format: PayloadFormat,
+        response_body: Vec<u8>,
+        request_format: PayloadFormat,
+        request_body: &[u8],
+    ) -> Response<Body> {
+        let mut response = Self::build_payload_response(response_format, response_body);
+        if let Some(key_versions) = Self::key_versions(request_format, request_body) {
+            response
+                .headers_mut()
+                .insert(KEY_VERSION_HEADER, key_versions);
+        }
+        response
+    }
+
+    /// The distinct key versions of the ciphertexts in a request. Ciphertexts are read with the same parser as
+    /// decryption of TLS terminated traffic.
+    #[cfg(feature = "tls_termination")]
+    fn key_versions(request_format: PayloadFormat, request_body: &[u8]) -> Option<HeaderValue> {
+        let payload: Value = request_format.decode(request_body).ok()?;
+        let key_versions = super::parser::key_versions(&payload);
+        if key_versions.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&key_versions.join(",")).ok()
+    }
+
+    #[cfg(not(feature = "tls_termination"))]
+    fn key_versions(_: PayloadFormat, _: &[u8]) -> Option<HeaderValue> {
+        None
+    }
+
+    /// Process a stream of newline delimited JSON records, streaming back a result line for each record in order.
+    /// Quotas are applied per record, as the stream isn't buffered. v2 reports failed records with an error code.
+    fn process_stream(
+        self,
+        req: Request<Body>,
+        operation: BulkOperation,
+        version: ApiVersion,
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let (parts, body) = req.into_parts();
+        let api_key = parts
+            .headers
+            .get("api-key")
+            .map(|key| key.as_bytes().to_vec());
+        let data_role = parts
+            .headers
+            .get("x-evervault-data-role")
+            .and_then(|role| role.to_str().ok())
+            .map(|role_str| role_str.to_string());
+        let key_version = Self::key_version(&parts.headers)?;
+
+        let api = Arc::new(self);
+        match operation {
+            BulkOperation::Encrypt if ndjson::is_raw_request(&parts.headers) => {
+                return Ok(api.encrypt_frames(body, api_key, data_role, key_version, version));
+            }
+            BulkOperation::Decrypt if ndjson::accepts_raw(&parts.headers) => {
+                return Ok(api.decrypt_frames(body, api_key));
+            }
+            _ => {}
+        }
+        let results = ndjson::records(body)
+            .map(move |record| {
+                let api = api.clone();
+                let api_key = api_key.clone();
+                let data_role = data_role.clone();
+                let key_version = key_version.clone();
+                async move {
+                    let record =
+                        record.map_err(|e| CryptoApiError::InvalidRecord(e.to_string()))?;
+                    api.check_quota(api_key.as_deref(), record.len() as u64)
+                        .await?;
+                    match operation {
+                        BulkOperation::Encrypt => {
+                            api.encrypt_bytes(
+                                record.as_bytes(),
+                                PayloadFormat::Json,
+                                PayloadFormat::Json,
+                                data_role,
+                                key_version,
+                            )
+                            .await
+                        }
+                        BulkOperation::Decrypt => {
+                            api.decrypt_bytes(
+                                api_key.as_deref(),
+                                record.as_bytes(),
+                                PayloadFormat::Json,
+                                PayloadFormat::Json,
+                            )
+                            .await
+                        }
+                    }
+                }
+            })
+            .buffered(STREAM_CONCURRENCY)
+            .map(move |result| {
+                Ok::<_, std::io::Error>(match version {
+                    ApiVersion::V1 => ndjson::to_line(result),
+                    ApiVersion::V2 => ndjson::to_structured_line(result),
+                })
+            });
+
+        Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .body(Body::wrap_stream(results))
+            .expect("Failed to build response"))
+    }
+
+    /// Encrypt a raw body in fixed size frames, streaming back a line with each frame's ciphertext in order, so
+    /// payloads of any size can be encrypted without being buffered. Quotas are applied per frame.
+    fn encrypt_frames(
+        self: Arc<Self>,
+        body: Body,
+        api_key: Option<Vec<u8>>,
+        data_role: Option<String>,
+        key_version: Option<String>,
+        version: ApiVersion,
+    ) -> Response<Body> {
+        let results = ndjson::frames(body)
+            .map(move |frame| {
+                let api = self.clone();
+                let api_key = api_key.clone();
+                let data_role = data_role.clone();
+                let key_version = key_version.clone();
+                async move {
+                    let frame = frame?;
+                    api.check_quota(api_key.as_deref(), frame.len() as u64)
+                        .await?;
+                    let request = BinaryRequest::new(&frame).with_key_version(key_version);
+                    let e3_response: CryptoResponse = api
+                        .e3_client
+                        .encrypt_with_retries(2, request, data_role)
+                        .await?;
+                    Ok::<_, CryptoApiError>(serde_json::to_vec(&e3_response.data)?)
+                }
+            })
+            .buffered(STREAM_CONCURRENCY)
+            .map(move |result| {
+                Ok::<_, std::io::Error>(match version {
+                    ApiVersion::V1 => ndjson::to_line(result),
+                    ApiVersion::V2 => ndjson::to_structured_line(result),
+                })
+            });
+
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .body(Body::wrap_stream(results))
+            .expect("Failed to build response")
+    }
+
+    /// Decrypt the lines of an encrypted raw payload, streaming the frames back as raw bytes in order. A frame which
+    /// fails to decrypt can't be reported in place, so the response is cut short instead.
+    fn decrypt_frames(self: Arc<Self>, body: Body, api_key: Option<Vec<u8>>) -> Response<Body> {
+        let frames = ndjson::records(body)
+            .map(move |record| {
+                let api = self.clone();
+                let api_key = api_key.clone();
+                async move {
+                    let record =
+                        record.map_err(|e| CryptoApiError::InvalidRecord(e.to_string()))?;
+                    api.check_quota(api_key.as_deref(), record.len() as u64)
+                        .await?;
+                    let plaintext = api
+                        .decrypt_value(api_key.as_deref(), record.as_bytes(), PayloadFormat::Json)
+                        .await?;
+                    BinaryRequest::decode_plaintext(&plaintext)
+                        .map(bytes::Bytes::from)
+                        .ok_or_else(|| {
+                            CryptoApiError::InvalidRecord(
+                                "Record isn't an encrypted frame".to_string(),
+                            )
+                        })
+                }
+            })
+            .buffered(STREAM_CONCURRENCY)
+            .map(|frame| {
+                frame.map_err(|e| {
+                    log::error!("Failed to decrypt raw stream frame - {e}");
+                    std::io::Error::other(e.to_string())
+                })
+            });
+
+        Self::build_raw_response(Body::wrap_stream(frames))
+    }
+
+    /// Encrypt or decrypt an array of values, returning the results in the same order. Values are sent to E3 in
+    /// concurrent chunks, and the batch fails if any chunk does.
+    async fn process_batch(
+        &self,
+        req: Request<Body>,
+        operation: BulkOperation,
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let (parts, body) = req.into_parts();
+        let request_format = PayloadFormat::of_request(&parts.headers);
+        let response_format = PayloadFormat::accepted(&parts.headers);
+        let api_key = parts.headers.get("api-key").map(|key| key.as_bytes());
+        let data_role = parts
+            .headers
+            .get("x-evervault-data-role")
+            .and_then(|role| role.to_str().ok())
+            .map(|role_str| role_str.to_string());
+        let key_version = Self::key_version(&parts.headers)?;
+        let body_bytes = self.read_body(body).await?;
+        let values = Self::batch_values(
+            request_format
+                .decode(&body_bytes)
+                .map_err(|_| CryptoApiError::SerializationError)?,
+        )?;
+
+        let batch_size = values.len();
+        let chunks: Vec<Value> = values
+            .chunks(BATCH_CHUNK_SIZE)
+            .map(|chunk| Value::Array(chunk.to_vec()))
+            .collect();
+        let chunks = chunks.into_iter().map(|chunk| {
+            let data_role = data_role.clone();
+            let key_version = key_version.clone();
+            async move {
+                match operation {
+                    BulkOperation::Encrypt => {
+                        let request = CryptoRequest::new(chunk).with_key_version(key_version);
+                        let e3_response: CryptoResponse = self
+                            .e3_client
+                            .encrypt_with_retries(2, request, data_role)
+                            .await?;
+                        Ok(e3_response.data)
+                    }
+                    BulkOperation::Decrypt => {
+                        let chunk_bytes = serde_json::to_vec(&chunk)?;
+                        self.decrypt_value(api_key, &chunk_bytes, PayloadFormat::Json)
+                            .await
+                    }
+                }
+            }
+        });
+        let chunk_results: Vec<Value> = futures::stream::iter(chunks)
+            .buffered(STREAM_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let mut results = Vec::with_capacity(batch_size);
+        for chunk_result in chunk_results {
+            let Value::Array(chunk_result) = chunk_result else {
+                return Err(ClientError::General("E3 returned an unexpected batch".into()).into());
+            };
+            results.extend(chunk_result);
         }
-        let test = TestData {
-            pcr0: "000".to_string(),
-            pcr1: "000".to_string(),
-            pcr2: "000".to_string(),
-            pcr8: "000".to_string(),
+        let response_body = response_format.encode(&Value::Array(results))?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// The values in a batch request, which must be an array of at most [`MAX_BATCH_SIZE`] values.
+    fn batch_values(body: Value) -> Result<Vec<Value>, CryptoApiError> {
+        let Value::Array(values) = body else {
+            return Err(CryptoApiError::InvalidBatch(
+                "Expected an array of values".to_string(),
+            ));
         };
-        let res: Vec<u8> = serde_cbor::to_vec(&test).unwrap();
+        if values.len() > MAX_BATCH_SIZE {
+            return Err(CryptoApiError::InvalidBatch(format!(
+                "A batch can hold at most {MAX_BATCH_SIZE} values"
+            )));
+        }
+        Ok(values)
+    }
+
+    /// Encrypt a streamed blob under a fresh data key and upload it through the egress proxy to the destination in
+    /// the `x-evervault-upload-url` header, e.g. an S3 presigned URL. The E3 encrypted data key is embedded in the
+    /// blob, and returned once the upload completes.
+    #[cfg(feature = "network_egress")]
+    async fn encrypt_blob(self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        use super::blob::{generate_data_key, BlobEncryptor};
+
+        let (parts, body) = req.into_parts();
+        let upload_uri = parts
+            .headers
+            .get("x-evervault-upload-url")
+            .and_then(|url| url.to_str().ok())
+            .and_then(|url| url.parse::<hyper::Uri>().ok())
+            .filter(|uri| uri.scheme() == Some(&hyper::http::uri::Scheme::HTTPS))
+            .ok_or_else(|| {
+                CryptoApiError::InvalidUpload("An https x-evervault-upload-url is required".into())
+            })?;
+        let upload_method = match parts.headers.get("x-evervault-upload-method") {
+            Some(method) => hyper::Method::from_bytes(method.as_bytes())
+                .map_err(|_| CryptoApiError::InvalidUpload("Invalid upload method".into()))?,
+            None => hyper::Method::PUT,
+        };
+        let plaintext_len = match parts.headers.get(hyper::header::CONTENT_LENGTH) {
+            Some(len) => Some(
+                len.to_str()
+                    .ok()
+                    .and_then(|len| len.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        CryptoApiError::InvalidUpload("Invalid content length".into())
+                    })?,
+            ),
+            None => None,
+        };
+        let api_key = parts
+            .headers
+            .get("api-key")
+            .map(|key| key.as_bytes().to_vec());
+
+        let data_key = generate_data_key();
+        let wrapped: CryptoResponse = self
+            .e3_client
+            .encrypt_with_retries(2, BinaryRequest::new(data_key.as_slice()), None)
+            .await?;
+        let Value::String(encrypted_data_key) = wrapped.data else {
+            return Err(CryptoApiError::UploadFailed(
+                "E3 returned an unexpected data key".into(),
+            ));
+        };
+
+        let encryptor = BlobEncryptor::new(data_key, &encrypted_data_key, plaintext_len)?;
+        let mut upload = Request::builder()
+            .method(upload_method)
+            .uri(upload_uri)
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream");
+        // Presigned uploads generally require a content length, which is known if the plaintext's is
+        if let Some(encrypted_len) = encryptor.encrypted_len() {
+            upload = upload.header(hyper::header::CONTENT_LENGTH, encrypted_len);
+        }
+        let body = Arc::new(self).metered_body(body, api_key);
+        let upload = upload
+            .body(Body::wrap_stream(encryptor.encrypt_stream(body)))
+            .map_err(|e| CryptoApiError::InvalidUpload(e.to_string()))?;
 
-        Ok(Body::from(res))
+        let response = get_upload_client()
+            .request(upload)
+            .await
+            .map_err(|e| CryptoApiError::UploadFailed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(CryptoApiError::UploadFailed(format!(
+                "Destination responded with {}",
+                response.status()
+            )));
+        }
+
+        let response_body = serde_json::to_vec(&serde_json::json!({
+            "status": response.status().as_u16(),
+            "encryptedDataKey": encrypted_data_key,
+        }))?;
+        Ok(Self::build_payload_response(
+            PayloadFormat::Json,
+            response_body,
+        ))
+    }
+
+    /// Decrypt a streamed blob made by `/blob/encrypt`, streaming back the plaintext as raw bytes. The data key is
+    /// decrypted by E3 once the header has been read. A chunk which fails to decrypt can't be reported once the
+    /// response has started, so the response is cut short instead.
+    async fn decrypt_blob(self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        use super::blob::{BlobDecryptor, BlobHeader};
+
+        let (parts, body) = req.into_parts();
+        let api_key = parts
+            .headers
+            .get("api-key")
+            .map(|key| key.as_bytes().to_vec());
+        let api = Arc::new(self);
+        let mut body = Box::pin(api.clone().metered_body(body, api_key));
+        let mut pending = BytesMut::new();
+        let header = BlobHeader::read(&mut body, &mut pending).await?;
+
+        let request = CryptoRequest::new(Value::String(header.encrypted_data_key.clone()));
+        let e3_response: CryptoResponse = api.e3_client.decrypt_with_retries(2, request).await?;
+        let data_key = BinaryRequest::decode_plaintext(&e3_response.data)
+            .map(Zeroizing::new)
+            .and_then(|data_key| <[u8; 32]>::try_from(data_key.as_slice()).ok())
+            .map(Zeroizing::new)
+            .ok_or_else(|| CryptoApiError::InvalidBinary("Blob has an invalid data key".into()))?;
+
+        let plaintext = BlobDecryptor::new(data_key, header)
+            .decrypt_stream(body, pending)
+            .map_err(|e: CryptoApiError| {
+                log::error!("Failed to decrypt blob - {e}");
+                std::io::Error::other(e.to_string())
+            });
+        Ok(Self::build_raw_response(Body::wrap_stream(plaintext)))
+    }
+
+    /// A request body which applies the api key's quota to each frame as it's read, as streamed bodies have no
+    /// length to check up front.
+    fn metered_body(
+        self: Arc<Self>,
+        body: Body,
+        api_key: Option<Vec<u8>>,
+    ) -> impl futures::Stream<Item = Result<Bytes, CryptoApiError>> + Send + Unpin {
+        TryStreamExt::map_err(body, CryptoApiError::from).and_then(move |frame| {
+            let api = self.clone();
+            let api_key = api_key.clone();
+            Box::pin(async move {
+                api.check_quota(api_key.as_deref(), frame.len() as u64)
+                    .await?;
+                Ok(frame)
+            })
+        })
+    }
+
+    /// Encrypt an encoded payload, returning the result in the response format
+    pub(crate) async fn encrypt_bytes(
+        &self,
+        body_bytes: &[u8],
+        request_format: PayloadFormat,
+        response_format: PayloadFormat,
+        data_role: Option<String>,
+        key_version: Option<String>,
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        let data = Self::parse_request_body(request_format, body_bytes)?.data;
+        let encrypted = self.encrypt_value(data, data_role, key_version).await?;
+        Ok(response_format.encode(&encrypted)?)
+    }
+
+    /// Encrypt a decoded payload
+    pub(crate) async fn encrypt_value(
+        &self,
+        data: Value,
+        data_role: Option<String>,
+        key_version: Option<String>,
+    ) -> Result<Value, CryptoApiError> {
+        let request = CryptoRequest::new(data).with_key_version(key_version);
+        let e3_response: CryptoResponse = self
+            .e3_client
+            .encrypt_with_retries(2, request, data_role)
+            .await?;
+        Ok(e3_response.data)
+    }
+
+    /// Decrypt an encoded payload, returning the result in the response format. Served from the decrypt cache when
+    /// enabled.
+    pub(crate) async fn decrypt_bytes(
+        &self,
+        api_key: Option<&[u8]>,
+        body_bytes: &[u8],
+        request_format: PayloadFormat,
+        response_format: PayloadFormat,
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        let plaintext = self
+            .decrypt_value(api_key, body_bytes, request_format)
+            .await?;
+        Ok(response_format.encode(&plaintext)?)
+    }
+
+    /// Decrypt an encoded payload, returning the plaintext as `data` and the metadata of each ciphertext in the
+    /// payload as `metadata`, in the response format.
+    #[cfg(feature = "tls_termination")]
+    async fn decrypt_bytes_with_metadata(
+        &self,
+        api_key: Option<&[u8]>,
+        body_bytes: &[u8],
+        request_format: PayloadFormat,
+        response_format: PayloadFormat,
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        let request = Self::parse_request_body(request_format, body_bytes)?;
+        let decrypted = match self.decrypt_cache {
+            Some(_) => {
+                let plaintext = self
+                    .decrypt_value(api_key, body_bytes, request_format)
+                    .await?;
+                DecryptedPayload::new(plaintext, request.data())
+            }
+            None => self.e3_client.decrypt_with_metadata(2, request).await?,
+        };
+        Ok(response_format.encode(&decrypted)?)
+    }
+
+    pub(crate) async fn decrypt_value(
+        &self,
+        api_key: Option<&[u8]>,
+        body_bytes: &[u8],
+        request_format: PayloadFormat,
+    ) -> Result<Value, CryptoApiError> {
+        let Some(decrypt_cache) = self.decrypt_cache else {
+            let request = Self::parse_request_body(request_format, body_bytes)?;
+            let e3_response: CryptoResponse =
+                self.e3_client.decrypt_with_retries(2, request).await?;
+            return Ok(e3_response.data);
+        };
+
+        let cache_key = DecryptCache::cache_key(api_key, body_bytes);
+        if let Some(plaintext) = decrypt_cache.get(&cache_key).await {
+            log::debug!("Serving decrypt request from cache");
+            return Ok(plaintext);
+        }
+
+        let request = Self::parse_request_body(request_format, body_bytes)?;
+        let e3_response: CryptoResponse = self.e3_client.decrypt_with_retries(2, request).await?;
+        decrypt_cache
+            .insert(cache_key, e3_response.data.clone())
+            .await;
+        Ok(e3_response.data)
+    }
+
+    /// Random bytes from the Nitro Security Module, so customer processes don't have to trust the guest's RNG. Outside
+    /// an enclave they come from OpenSSL's RNG instead. Returned raw if the request accepts application/octet-stream,
+    /// or base64 encoded otherwise.
+    fn get_random(req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let len = Self::random_len(req.uri().query())?;
+        let mut random = vec![0u8; len];
+        super::rand::rand_bytes(&mut random)?;
+        if ndjson::accepts_raw(req.headers()) {
+            return Ok(Self::build_raw_response(random));
+        }
+        let response_format = PayloadFormat::accepted(req.headers());
+        let response_body = response_format.encode(&RandomResponse {
+            bytes: base64::encode(random),
+        })?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// The number of bytes asked for in the `bytes` query parameter, which must be between 1 and
+    /// [`MAX_RANDOM_BYTES`].
+    fn random_len(query: Option<&str>) -> Result<usize, CryptoApiError> {
+        query
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|param| param.strip_prefix("bytes="))
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|len| (1..=MAX_RANDOM_BYTES).contains(len))
+            .ok_or_else(|| {
+                CryptoApiError::InvalidRandomRequest(format!(
+                    "bytes must be between 1 and {MAX_RANDOM_BYTES}"
+                ))
+            })
+    }
+
+    /// Sign a base64 encoded message with the app's signing key, which stays in E3 rather than the enclave.
+    async fn sign(&self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let sign_request: SignRequest = request_format
+            .decode(&body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        check_base64("data", &sign_request.data)?;
+        let signed = self.e3_client.sign(sign_request).await?;
+        let response_body = response_format.encode(&signed)?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// Check a signature from `sign` against its message. A signature which doesn't match is reported as invalid
+    /// rather than as an error.
+    async fn verify(&self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let verify_request: VerifyRequest = request_format
+            .decode(&body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        check_base64("data", &verify_request.data)?;
+        check_base64("signature", &verify_request.signature)?;
+        let verified = self.e3_client.verify(verify_request).await?;
+        let response_body = response_format.encode(&verified)?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// Sign the request's claims with the enclave's token key. The body is the claims object itself.
+    async fn sign_token(self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let claims: Value = request_format
+            .decode(&body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        let token = JwtSigner::get()?.sign(claims)?;
+        let response_body = response_format.encode(&SignTokenResponse { token })?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    async fn verify_token(self, req: Request<Body>) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let body_bytes = self.read_body(req.into_body()).await?;
+        let verify_request: VerifyTokenRequest = request_format
+            .decode(&body_bytes)
+            .map_err(|_| CryptoApiError::SerializationError)?;
+        let claims = JwtSigner::get()?.verify(&verify_request.token)?;
+        let response_body = response_format.encode(&VerifyTokenResponse { claims })?;
+        Ok(Self::build_payload_response(response_format, response_body))
+    }
+
+    /// The public signing key, with an attestation doc over its key ID so verifiers can tie the key to this enclave.
+    fn get_jwks(self) -> Result<Response<Body>, CryptoApiError> {
+        let signer = JwtSigner::get()?;
+        let attestation_doc = Self::attestation_doc(Some(signer.kid().as_bytes().to_vec()), None)?;
+        let mut jwks = signer.jwks();
+        jwks["attestationDoc"] = Value::String(base64::encode(attestation_doc));
+        let response_body = serde_json::to_vec(&jwks)?;
+        Ok(Self::build_payload_response(
+            PayloadFormat::Json,
+            response_body,
+        ))
+    }
+
+    // The attestation doc is a COSE_Sign1 structure, so it's returned as raw CBOR unless the request asks for it to be
+    // wrapped in an envelope with its metadata
+    async fn get_attestation_doc(
+        self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, CryptoApiError> {
+        let request_format = PayloadFormat::of_request(req.headers());
+        let response_format = PayloadFormat::accepted(req.headers());
+        let bytes = self.read_body(req.into_body()).await?;
+        let ad_request: AttestationRequest = if bytes.is_empty() {
+            AttestationRequest::default()
+        } else {
+            request_format
+                .decode(&bytes)
+                .map_err(|_| CryptoApiError::SerializationError)?
+        };
+        let challenge = ad_request
+            .encoding
+            .decode("challenge", ad_request.challenge)?;
+        let nonce = ad_request.encoding.decode("nonce", ad_request.nonce)?;
+        let doc = Self::attestation_doc(challenge, nonce)?;
+        let envelope = ad_request
+            .envelope
+            .then(|| AttestationDocEnvelope::new(&doc));
+
+        #[cfg(feature = "enclave")]
+        let metadata = attest::AttestationDocMetadata::from_doc(&doc)
+            .map_err(|e| log::warn!("Failed to read attestation doc metadata - {e}"))
+            .ok();
+        #[cfg(feature = "enclave")]
+        let envelope = envelope.map(|envelope| envelope.with_metadata(metadata.as_ref()));
+
+        let response = match envelope {
+            Some(envelope) => {
+                let payload = response_format
+                    .encode(&envelope)
+                    .map_err(|_| CryptoApiError::SerializationError)?;
+                Self::build_payload_response(response_format, payload)
+            }
+            None => Self::build_payload_response(PayloadFormat::Cbor, doc),
+        };
+        #[cfg(feature = "enclave")]
+        let response = {
+            let mut response = response;
+            if let Some(metadata) = metadata {
+                metadata.add_headers(response.headers_mut());
+            }
+            response
+        };
+        Ok(response)
+    }
+
+    /// The enclave's PCRs, read from a fresh attestation doc so tooling can check measurements without parsing CBOR.
+    fn get_attestation_pcrs() -> Result<Response<Body>, CryptoApiError> {
+        let doc = Self::attestation_doc(None, None)?;
+        let pcrs = AttestationPcrs::from_doc(&doc)?;
+        let response_body = serde_json::to_vec(&pcrs)?;
+        Ok(Self::build_payload_response(
+            PayloadFormat::Json,
+            response_body,
+        ))
+    }
+
+    #[cfg(feature = "enclave")]
+    pub(crate) fn attestation_doc(
+        challenge: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        let doc = match nonce {
+            Some(nonce) => attest::get_attestation_doc(challenge, Some(nonce))?,
+            None => attest::get_cached_attestation_doc(challenge)?,
+        };
+        Ok(doc)
+    }
+
+    #[cfg(not(feature = "enclave"))]
+    pub(crate) fn attestation_doc(
+        challenge: Option<Vec<u8>>,
+        nonce: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, CryptoApiError> {
+        Ok(mock_attest::get_attestation_doc(challenge, nonce)?)
     }
 }
 
-#[derive(Deserialize, Serialize)]
+/// Uploads resolve and connect like any other process in the enclave, so they're subject to the egress allow list.
+#[cfg(feature = "network_egress")]
+fn get_upload_client(
+) -> &'static hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>, Body> {
+    use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+    static UPLOAD_CLIENT: std::sync::OnceLock<
+        hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>, Body>,
+    > = std::sync::OnceLock::new();
+    UPLOAD_CLIENT.get_or_init(|| {
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        let https_connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_only()
+            .enable_http1()
+            .build();
+        hyper::Client::builder().build(https_connector)
+    })
+}
+
+/// Malformed messages and signatures are rejected here, rather than by E3.
+fn check_base64(field: &str, value: &str) -> Result<(), CryptoApiError> {
+    base64::decode(value)
+        .map(|_| ())
+        .map_err(|_| CryptoApiError::InvalidSigningRequest(format!("{field} must be base64")))
+}
+
+#[derive(Serialize)]
+struct RandomResponse {
+    bytes: String,
+}
+
+#[derive(Serialize)]
+struct SignTokenResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyTokenRequest {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct VerifyTokenResponse {
+    claims: serde_json::Map<String, Value>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
 pub struct AttestationRequest {
     nonce: Option<String>,
     challenge: Option<String>,
+    #[serde(default)]
+    encoding: AttestationEncoding,
+    #[serde(default)]
+    envelope: bool,
+}
+
+/// How the challenge and nonce of an attestation request are encoded. Plain strings are embedded as their UTF-8 bytes,
+/// so binary challenges have to be sent as base64 or hex.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationEncoding {
+    #[default]
+    Utf8,
+    Base64,
+    Hex,
+}
+
+impl AttestationEncoding {
+    pub fn decode(
+        self,
+        field: &str,
+        value: Option<String>,
+    ) -> Result<Option<Vec<u8>>, CryptoApiError> {
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let decoded = match self {
+            Self::Utf8 => Ok(value.into_bytes()),
+            Self::Base64 => base64::decode(value).map_err(|_| "base64"),
+            Self::Hex => hex::decode(value).map_err(|_| "hex"),
+        };
+        decoded.map(Some).map_err(|encoding| {
+            CryptoApiError::InvalidAttestationRequest(format!("{field} must be {encoding}"))
+        })
+    }
+}
+
+impl std::str::FromStr for AttestationEncoding {
+    type Err = CryptoApiError;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding {
+            "utf8" => Ok(Self::Utf8),
+            "base64" => Ok(Self::Base64),
+            "hex" => Ok(Self::Hex),
+            _ => Err(CryptoApiError::InvalidAttestationRequest(format!(
+                "unsupported encoding {encoding}, expected utf8, base64 or hex"
+            ))),
+        }
+    }
+}
+
+/// An attestation doc returned with its issued and expiry times, so clients know how long they can cache it for.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct AttestationDocEnvelope {
+    pub attestation_doc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+impl AttestationDocEnvelope {
+    pub fn new(attestation_doc: &[u8]) -> Self {
+        Self {
+            attestation_doc: base64::encode(attestation_doc),
+            issued_at: None,
+            expires_at: None,
+        }
+    }
+
+    #[cfg(feature = "enclave")]
+    pub fn with_metadata(self, metadata: Option<&attest::AttestationDocMetadata>) -> Self {
+        Self {
+            issued_at: metadata.map(attest::AttestationDocMetadata::issued_at_rfc3339),
+            expires_at: metadata.map(attest::AttestationDocMetadata::expires_at_rfc3339),
+            ..self
+        }
+    }
+}
+
+/// The PCRs in an attestation doc which identify the enclave, hex encoded.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct AttestationPcrs {
+    pub pcr0: String,
+    pub pcr1: String,
+    pub pcr2: String,
+    pub pcr8: String,
+}
+
+impl AttestationPcrs {
+    #[cfg(feature = "enclave")]
+    pub fn from_doc(attestation_doc: &[u8]) -> Result<Self, attest::AttestationError> {
+        Ok(Self::from_pcrs(&attest::get_pcrs(attestation_doc)?))
+    }
+
+    #[cfg(not(feature = "enclave"))]
+    pub fn from_doc(attestation_doc: &[u8]) -> Result<Self, Error> {
+        Ok(Self::from_pcrs(&mock_attest::get_pcrs(attestation_doc)?))
+    }
+
+    fn from_pcrs(pcrs: &std::collections::BTreeMap<usize, Vec<u8>>) -> Self {
+        use shared::utils::HexSlice;
+
+        let pcr = |index: usize| {
+            pcrs.get(&index)
+                .map(|value| format!("{:x}", HexSlice::from(value.as_slice())))
+                .unwrap_or_default()
+        };
+        Self {
+            pcr0: pcr(0),
+            pcr1: pcr(1),
+            pcr2: pcr(2),
+            pcr8: pcr(8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_base64, AttestationEncoding, AttestationRequest, CryptoApi, CryptoApiError,
+        MAX_BATCH_SIZE, MAX_RANDOM_BYTES,
+    };
+    use crate::cache::{AuthCache, AuthCacheConfig};
+    use hyper::{Body, Request};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_batches_must_be_bounded_arrays() {
+        let values = CryptoApi::batch_values(json!(["a", 1, { "b": true }])).unwrap();
+        assert_eq!(values.len(), 3);
+        assert!(matches!(
+            CryptoApi::batch_values(json!({ "values": [] })),
+            Err(CryptoApiError::InvalidBatch(_))
+        ));
+        let oversized = Value::Array(vec![json!("a"); MAX_BATCH_SIZE + 1]);
+        assert!(matches!(
+            CryptoApi::batch_values(oversized),
+            Err(CryptoApiError::InvalidBatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_signing_requests_must_be_base64() {
+        assert!(check_base64("data", &base64::encode(b"\x00\xffmessage")).is_ok());
+        assert!(check_base64("data", "").is_ok());
+        assert!(matches!(
+            check_base64("signature", "not base64!"),
+            Err(CryptoApiError::InvalidSigningRequest(message)) if message == "signature must be base64"
+        ));
+    }
+
+    #[test]
+    fn test_attestation_challenges_are_decoded_with_their_encoding() {
+        let request: AttestationRequest =
+            serde_json::from_value(json!({ "challenge": "00ff", "encoding": "hex" })).unwrap();
+        assert_eq!(request.encoding, AttestationEncoding::Hex);
+        assert_eq!(
+            request
+                .encoding
+                .decode("challenge", request.challenge)
+                .unwrap(),
+            Some(vec![0x00, 0xff])
+        );
+        let request: AttestationRequest =
+            serde_json::from_value(json!({ "nonce": "abc" })).unwrap();
+        assert_eq!(
+            request.encoding.decode("nonce", request.nonce).unwrap(),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(
+            AttestationEncoding::Base64
+                .decode("nonce", Some(base64::encode(b"\x00\x01")))
+                .unwrap(),
+            Some(vec![0x00, 0x01])
+        );
+        assert!(matches!(
+            AttestationEncoding::Hex.decode("nonce", Some("xyz".to_string())),
+            Err(CryptoApiError::InvalidAttestationRequest(message)) if message == "nonce must be hex"
+        ));
+        assert!("rot13".parse::<AttestationEncoding>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_are_checked_through_the_auth_cache() {
+        // The context is process-wide, so match the one the cert resolver tests rely on
+        crate::EnclaveContext::set(crate::EnclaveContext::new(
+            "app_123".into(),
+            "team_456".into(),
+            "enclave_123".into(),
+            "my-sick-enclave".into(),
+        ));
+        let auth_cache: &'static AuthCache =
+            Box::leak(Box::new(AuthCache::new(&AuthCacheConfig {
+                ttl_seconds: 60,
+                max_entries: 10,
+                negative_ttl_seconds: Some(60),
+            })));
+        auth_cache.insert(b"cached-key").await;
+        auth_cache.insert_rejected(b"rejected-key").await;
+
+        // Cached keys are answered without reaching E3
+        let api = CryptoApi {
+            authenticate_api_keys: true,
+            auth_cache: Some(auth_cache),
+            ..CryptoApi::new()
+        };
+        assert!(api.check_api_key(None).await.is_ok());
+        assert!(api.check_api_key(Some(b"cached-key")).await.is_ok());
+        assert!(matches!(
+            api.check_api_key(Some(b"bad\nkey")).await,
+            Err(CryptoApiError::InvalidApiKey)
+        ));
+        let request = Request::post("/encrypt")
+            .header("api-key", "rejected-key")
+            .body(Body::from(r#""plaintext""#))
+            .unwrap();
+        let response = api.api(request).await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let api = CryptoApi {
+            authenticate_api_keys: false,
+            auth_cache: Some(auth_cache),
+            ..CryptoApi::new()
+        };
+        assert!(api.check_api_key(Some(b"rejected-key")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bodies_over_the_limit_are_rejected() {
+        let api = CryptoApi {
+            max_body_bytes: 4,
+            ..CryptoApi::new()
+        };
+        let body = api.read_body(Body::from("abcd")).await.unwrap();
+        assert_eq!(&body[..], b"abcd");
+        assert!(matches!(
+            api.read_body(Body::from("abcde")).await,
+            Err(CryptoApiError::BodyTooLarge(4))
+        ));
+
+        let chunks = futures::stream::iter(["ab", "cd", "e"].map(Ok::<_, std::io::Error>));
+        assert!(matches!(
+            api.read_body(Body::wrap_stream(chunks)).await,
+            Err(CryptoApiError::BodyTooLarge(4))
+        ));
+    }
+
+    #[test]
+    fn test_random_length_must_be_bounded() {
+        assert_eq!(CryptoApi::random_len(Some("bytes=32")).unwrap(), 32);
+        assert_eq!(
+            CryptoApi::random_len(Some("format=raw&bytes=4096")).unwrap(),
+            MAX_RANDOM_BYTES
+        );
+        for query in [
+            None,
+            Some("bytes=0"),
+            Some("bytes=4097"),
+            Some("bytes=-1"),
+            Some("size=32"),
+        ] {
+            assert!(matches!(
+                CryptoApi::random_len(query),
+                Err(CryptoApiError::InvalidRandomRequest(_))
+            ));
+        }
+    }
 }
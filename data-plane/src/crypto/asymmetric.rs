@@ -0,0 +1,239 @@
+//! Public key encryption to one or more recipients, so data can be encrypted in the enclave for whoever holds the
+//! matching private keys, e.g. a third party the data is shared with, without the enclave being able to decrypt it.
+//!
+//! The payload is sealed once with AES-256-GCM under a fresh data key, and the data key is sealed for each recipient's
+//! P-256 public key with ECIES: an ephemeral key pair is generated, and the ECDH shared secret is run through the
+//! ANSI X9.63 KDF (SHA-256, with the ephemeral public key as the shared info) to derive the key which seals the data
+//! key. Public keys are base64 encoded SEC1 points, compressed or not. Ephemeral keys are returned compressed, and
+//! every GCM tag is appended to its ciphertext.
+use openssl::bn::BigNumContext;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sha::Sha256;
+use openssl::symm::{encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::blob::generate_data_key;
+
+pub const MAX_RECIPIENTS: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum AsymmetricEncryptionError {
+    #[error("Failed to encrypt for recipients — {0}")]
+    Openssl(#[from] ErrorStack),
+    #[error("Invalid recipient public key {0}")]
+    InvalidPublicKey(String),
+    #[error("At most {MAX_RECIPIENTS} recipients can be given")]
+    TooManyRecipients,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AsymmetricEncryptRequest {
+    pub data: Value,
+    /// Public keys of the recipients. The app's public key is used if none are given.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+}
+
+/// The encrypted payload, with the data key sealed for each recipient.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedPayload {
+    pub iv: String,
+    pub ciphertext: String,
+    pub recipients: Vec<SealedKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedKey {
+    /// The recipient's public key, as given, so recipients can find their sealed key
+    pub public_key: String,
+    pub ephemeral_public_key: String,
+    pub iv: String,
+    pub encrypted_key: String,
+}
+
+/// Encrypt the plaintext so only the holders of the recipients' private keys can decrypt it.
+pub fn seal(
+    plaintext: &[u8],
+    recipients: &[String],
+) -> Result<SealedPayload, AsymmetricEncryptionError> {
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(AsymmetricEncryptionError::TooManyRecipients);
+    }
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let recipient_keys = recipients
+        .iter()
+        .map(|public_key| parse_public_key(&group, public_key))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let data_key = generate_data_key();
+    let (iv, ciphertext) = seal_with_key(data_key.as_slice(), plaintext)?;
+    let recipients = recipients
+        .iter()
+        .zip(recipient_keys)
+        .map(|(public_key, recipient_key)| {
+            seal_data_key(&group, &recipient_key, data_key.as_slice()).map(
+                |(ephemeral_public_key, iv, encrypted_key)| SealedKey {
+                    public_key: public_key.clone(),
+                    ephemeral_public_key,
+                    iv,
+                    encrypted_key,
+                },
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(SealedPayload {
+        iv,
+        ciphertext,
+        recipients,
+    })
+}
+
+fn parse_public_key(
+    group: &EcGroup,
+    public_key: &str,
+) -> Result<PKey<Public>, AsymmetricEncryptionError> {
+    let invalid = || AsymmetricEncryptionError::InvalidPublicKey(public_key.to_string());
+    let point_bytes = base64::decode(public_key).map_err(|_| invalid())?;
+    let mut ctx = BigNumContext::new()?;
+    let point = EcPoint::from_bytes(group, &point_bytes, &mut ctx).map_err(|_| invalid())?;
+    let ec_key = EcKey::from_public_key(group, &point).map_err(|_| invalid())?;
+    ec_key.check_key().map_err(|_| invalid())?;
+    Ok(PKey::from_ec_key(ec_key)?)
+}
+
+/// Seal the data key for a recipient, returning the ephemeral public key, IV and encrypted data key.
+fn seal_data_key(
+    group: &EcGroup,
+    recipient_key: &PKey<Public>,
+    data_key: &[u8],
+) -> Result<(String, String, String), AsymmetricEncryptionError> {
+    let ephemeral_key = EcKey::generate(group)?;
+    let mut ctx = BigNumContext::new()?;
+    let ephemeral_public_key =
+        ephemeral_key
+            .public_key()
+            .to_bytes(group, PointConversionForm::COMPRESSED, &mut ctx)?;
+    let ephemeral_key: PKey<Private> = PKey::from_ec_key(ephemeral_key)?;
+
+    let mut deriver = Deriver::new(&ephemeral_key)?;
+    deriver.set_peer(recipient_key)?;
+    let shared_secret = Zeroizing::new(deriver.derive_to_vec()?);
+    let wrapping_key = derive_wrapping_key(&shared_secret, &ephemeral_public_key);
+
+    let (iv, encrypted_key) = seal_with_key(wrapping_key.as_slice(), data_key)?;
+    Ok((base64::encode(ephemeral_public_key), iv, encrypted_key))
+}
+
+/// ANSI X9.63 KDF with SHA-256, for a single 32 byte block.
+fn derive_wrapping_key(shared_secret: &[u8], shared_info: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(shared_secret);
+    hasher.update(&1u32.to_be_bytes());
+    hasher.update(shared_info);
+    Zeroizing::new(hasher.finish())
+}
+
+/// AES-256-GCM under a random IV, returning the base64 IV and ciphertext.
+fn seal_with_key(key: &[u8], plaintext: &[u8]) -> Result<(String, String), ErrorStack> {
+    let iv: [u8; IV_LEN] = rand::random();
+    let mut tag = [0u8; TAG_LEN];
+    let mut ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&iv),
+        &[],
+        plaintext,
+        &mut tag,
+    )?;
+    ciphertext.extend_from_slice(&tag);
+    Ok((base64::encode(iv), base64::encode(ciphertext)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::symm::decrypt_aead;
+
+    fn open_with_key(key: &[u8], iv: &str, ciphertext: &str) -> Vec<u8> {
+        let iv = base64::decode(iv).unwrap();
+        let ciphertext = base64::decode(ciphertext).unwrap();
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+        decrypt_aead(Cipher::aes_256_gcm(), key, Some(&iv), &[], ciphertext, tag).unwrap()
+    }
+
+    /// What a recipient does with their private key.
+    fn open(
+        recipient: &EcKey<Private>,
+        payload: &SealedPayload,
+        sealed_key: &SealedKey,
+    ) -> Vec<u8> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ephemeral_public_key = base64::decode(&sealed_key.ephemeral_public_key).unwrap();
+        let ephemeral_key = parse_public_key(&group, &sealed_key.ephemeral_public_key).unwrap();
+        let recipient = PKey::from_ec_key(recipient.clone()).unwrap();
+        let mut deriver = Deriver::new(&recipient).unwrap();
+        deriver.set_peer(&ephemeral_key).unwrap();
+        let shared_secret = deriver.derive_to_vec().unwrap();
+        let wrapping_key = derive_wrapping_key(&shared_secret, &ephemeral_public_key);
+        let data_key = open_with_key(
+            wrapping_key.as_slice(),
+            &sealed_key.iv,
+            &sealed_key.encrypted_key,
+        );
+        open_with_key(&data_key, &payload.iv, &payload.ciphertext)
+    }
+
+    fn recipient(form: PointConversionForm) -> (EcKey<Private>, String) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let public_key = key.public_key().to_bytes(&group, form, &mut ctx).unwrap();
+        (key, base64::encode(public_key))
+    }
+
+    #[test]
+    fn test_each_recipient_can_decrypt_the_payload() {
+        let (first, first_public_key) = recipient(PointConversionForm::COMPRESSED);
+        let (second, second_public_key) = recipient(PointConversionForm::UNCOMPRESSED);
+        let recipients = vec![first_public_key.clone(), second_public_key.clone()];
+
+        let sealed = seal(b"{\"card\":\"4242\"}", &recipients).unwrap();
+        assert_eq!(sealed.recipients.len(), 2);
+        assert_eq!(sealed.recipients[0].public_key, first_public_key);
+        assert_eq!(sealed.recipients[1].public_key, second_public_key);
+        assert_eq!(
+            open(&first, &sealed, &sealed.recipients[0]),
+            b"{\"card\":\"4242\"}"
+        );
+        assert_eq!(
+            open(&second, &sealed, &sealed.recipients[1]),
+            b"{\"card\":\"4242\"}"
+        );
+    }
+
+    #[test]
+    fn test_invalid_recipients_are_rejected() {
+        assert!(matches!(
+            seal(b"data", &["bm90IGEga2V5".to_string()]),
+            Err(AsymmetricEncryptionError::InvalidPublicKey(_))
+        ));
+        let (_, public_key) = recipient(PointConversionForm::COMPRESSED);
+        assert!(matches!(
+            seal(b"data", &vec![public_key; MAX_RECIPIENTS + 1]),
+            Err(AsymmetricEncryptionError::TooManyRecipients)
+        ));
+    }
+}
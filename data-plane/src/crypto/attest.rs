@@ -1,14 +1,40 @@
+use crate::configuration;
 use crate::utils::nsm::{NsmConnection, NsmConnectionError};
 use aws_nitro_enclaves_cose as cose;
 use aws_nitro_enclaves_nsm_api as nitro;
-use chrono::{DateTime, NaiveDateTime};
+use cached::{Cached, TimedSizedCache};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use hyper::header::{HeaderMap, HeaderValue, CACHE_CONTROL};
+use once_cell::sync::Lazy;
 use openssl::x509::X509;
 use serde_bytes::ByteBuf;
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
+/// Most distinct challenges cached attestation docs are held for at once.
+const MAX_CACHED_ATTESTATION_DOCS: usize = 16;
+
+type AttestationDocCache = Mutex<TimedSizedCache<Option<Vec<u8>>, Vec<u8>>>;
+
+/// Attestation docs requested without a nonce, keyed by their challenge. Only initialized if caching is enabled.
+static ATTESTATION_DOC_CACHE: Lazy<Option<AttestationDocCache>> = Lazy::new(|| {
+    let ttl = configuration::get_attestation_doc_cache_ttl();
+    (!ttl.is_zero()).then(|| {
+        Mutex::new(TimedSizedCache::with_size_and_lifespan(
+            MAX_CACHED_ATTESTATION_DOCS,
+            ttl.as_secs(),
+        ))
+    })
+});
+
+pub const ATTESTATION_ISSUED_AT_HEADER: &str = "x-evervault-attestation-issued-at";
+pub const ATTESTATION_EXPIRES_AT_HEADER: &str = "x-evervault-attestation-expires-at";
+
 #[derive(Clone, Debug)]
 pub enum DriverCalls {
     GetAttestationDocument,
@@ -36,6 +62,8 @@ pub enum AttestationError {
     UnexpectedResponse(DriverCalls, nitro::api::Response),
     #[error("Could not parse CoseSign1 structure: {0}")]
     CoseSign1ParseFailed(serde_cbor::Error),
+    #[error("Could not read CoseSign1 payload: {0}")]
+    CosePayloadFailed(String),
     #[error("Could not parse attestation document: {0}")]
     AttestationDocParseFailed(serde_cbor::Error),
     #[error("Could not parse signing cert: {0}")]
@@ -48,18 +76,15 @@ pub enum AttestationError {
     InvalidTimeError(String),
 }
 
+impl HasErrorCode for AttestationError {
+    fn error_code(&self) -> ErrorCode {
+        codes::ATTESTATION_FAILED
+    }
+}
+
 impl std::convert::From<AttestationError> for hyper::Response<hyper::Body> {
     fn from(value: AttestationError) -> Self {
-        let err_payload = serde_json::json!({
-          "message": value.to_string()
-        })
-        .to_string();
-        hyper::Response::builder()
-            .status(500)
-            .header("content-type", "application/json")
-            .header("content-length", err_payload.len())
-            .body(hyper::Body::from(err_payload))
-            .expect("Infallible: failed to build error response")
+        value.to_error_response()
     }
 }
 
@@ -86,6 +111,26 @@ pub fn get_attestation_doc(
     }
 }
 
+/// An attestation doc without a caller supplied nonce, reused from the cache while it's fresh. Docs with a nonce are
+/// never cached, so callers who need a fresh doc should send one.
+pub fn get_cached_attestation_doc(challenge: Option<Vec<u8>>) -> Result<Vec<u8>, AttestationError> {
+    let Some(cache) = ATTESTATION_DOC_CACHE.as_ref() else {
+        return get_attestation_doc(challenge, None);
+    };
+    if let Some(doc) = cache
+        .lock()
+        .ok()
+        .and_then(|mut cache| cache.cache_get(&challenge).cloned())
+    {
+        return Ok(doc);
+    }
+    let doc = get_attestation_doc(challenge.clone(), None)?;
+    if let Ok(mut cache) = cache.lock() {
+        cache.cache_set(challenge, doc.clone());
+    }
+    Ok(doc)
+}
+
 fn get_nonce(nonce: Option<Vec<u8>>, nsm_fd: i32) -> Result<Vec<u8>, AttestationError> {
     match nonce {
         Some(nonce) => Ok(nonce),
@@ -101,21 +146,90 @@ fn get_nonce(nonce: Option<Vec<u8>>, nsm_fd: i32) -> Result<Vec<u8>, Attestation
     }
 }
 
-pub fn get_expiry_time(cose_sign_1_bytes: &[u8]) -> Result<SystemTime, AttestationError> {
+/// Read the attestation doc out of its COSE_Sign1 structure. The signature isn't checked, as the doc is our own.
+fn parse_attestation_doc(
+    cose_sign_1_bytes: &[u8],
+) -> Result<nitro::api::AttestationDoc, AttestationError> {
     let cose_sign_1: cose::CoseSign1 = serde_cbor::from_slice(cose_sign_1_bytes)
         .map_err(AttestationError::CoseSign1ParseFailed)?;
-    // Can only return an error if verification fails, and we aren't doing verification
     let attestation_doc_bytes = cose_sign_1
         .get_payload::<cose::crypto::Openssl>(None)
-        .unwrap();
-    let attestation_doc: nitro::api::AttestationDoc =
-        serde_cbor::from_slice(&attestation_doc_bytes)
-            .map_err(AttestationError::AttestationDocParseFailed)?;
-    let signing_cert = X509::from_der(&attestation_doc.certificate[..])
-        .map_err(AttestationError::SigningCertParseFailed)?;
-    let not_after = signing_cert.not_after().to_string();
-    let utc_date_time = parse_not_after_date_time(&not_after)?;
-    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(utc_date_time.timestamp() as u64))
+        .map_err(|e| AttestationError::CosePayloadFailed(e.to_string()))?;
+    serde_cbor::from_slice(&attestation_doc_bytes)
+        .map_err(AttestationError::AttestationDocParseFailed)
+}
+
+/// The PCR values measured into an attestation doc, by index.
+pub fn get_pcrs(cose_sign_1_bytes: &[u8]) -> Result<BTreeMap<usize, Vec<u8>>, AttestationError> {
+    let attestation_doc = parse_attestation_doc(cose_sign_1_bytes)?;
+    Ok(attestation_doc
+        .pcrs
+        .into_iter()
+        .map(|(index, value)| (index, value.into_vec()))
+        .collect())
+}
+
+pub fn get_expiry_time(cose_sign_1_bytes: &[u8]) -> Result<SystemTime, AttestationError> {
+    AttestationDocMetadata::from_doc(cose_sign_1_bytes).map(|metadata| metadata.expires_at)
+}
+
+/// When an attestation doc was issued, and when the cert it's signed with expires. Clients can cache a doc until it
+/// expires, after which it can no longer be verified and the enclave has to be attested again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AttestationDocMetadata {
+    pub issued_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+impl AttestationDocMetadata {
+    pub fn from_doc(cose_sign_1_bytes: &[u8]) -> Result<Self, AttestationError> {
+        let attestation_doc = parse_attestation_doc(cose_sign_1_bytes)?;
+        let signing_cert = X509::from_der(&attestation_doc.certificate[..])
+            .map_err(AttestationError::SigningCertParseFailed)?;
+        let not_after = signing_cert.not_after().to_string();
+        let utc_date_time = parse_not_after_date_time(&not_after)?;
+        Ok(Self {
+            issued_at: SystemTime::UNIX_EPOCH + Duration::from_millis(attestation_doc.timestamp),
+            expires_at: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(utc_date_time.timestamp() as u64),
+        })
+    }
+
+    pub fn issued_at_rfc3339(&self) -> String {
+        to_rfc3339(self.issued_at)
+    }
+
+    pub fn expires_at_rfc3339(&self) -> String {
+        to_rfc3339(self.expires_at)
+    }
+
+    /// How much longer the doc can be verified for.
+    pub fn remaining_validity(&self) -> Duration {
+        self.expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// Set the issued and expiry times on a response, and let clients cache it until it expires.
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        let values = [
+            (ATTESTATION_ISSUED_AT_HEADER, self.issued_at_rfc3339()),
+            (ATTESTATION_EXPIRES_AT_HEADER, self.expires_at_rfc3339()),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        let cache_control = format!("private, max-age={}", self.remaining_validity().as_secs());
+        if let Ok(value) = HeaderValue::from_str(&cache_control) {
+            headers.insert(CACHE_CONTROL, value);
+        }
+    }
+}
+
+fn to_rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
 }
 
 fn parse_not_after_date_time(not_after: &str) -> Result<DateTime<chrono_tz::Tz>, AttestationError> {
@@ -130,7 +244,112 @@ fn parse_not_after_date_time(not_after: &str) -> Result<DateTime<chrono_tz::Tz>,
 
 #[cfg(test)]
 mod test {
-    use super::parse_not_after_date_time;
+    use super::{
+        cose, get_pcrs, nitro, parse_not_after_date_time, AttestationDocMetadata, AttestationError,
+    };
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime};
+
+    const ISSUED_AT_MILLIS: u64 = 1_700_000_000_123;
+    const EXPIRES_AT_SECS: u64 = 1_700_010_800;
+
+    fn signed_attestation_doc() -> Vec<u8> {
+        signed_attestation_doc_with_pcrs(BTreeMap::new())
+    }
+
+    fn signed_attestation_doc_with_pcrs(pcrs: BTreeMap<usize, Vec<u8>>) -> Vec<u8> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::from_unix(1_700_000_000).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::from_unix(EXPIRES_AT_SECS as i64).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build().to_der().unwrap();
+
+        let doc = nitro::api::AttestationDoc::new(
+            "test-module".to_string(),
+            nitro::api::Digest::SHA384,
+            ISSUED_AT_MILLIS,
+            pcrs,
+            cert,
+            vec![],
+            None,
+            None,
+            None,
+        );
+        cose::CoseSign1::new::<cose::crypto::Openssl>(
+            &doc.to_binary(),
+            &cose::header_map::HeaderMap::new(),
+            &key,
+        )
+        .unwrap()
+        .as_bytes(false)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_metadata_is_read_from_attestation_doc() {
+        let metadata = AttestationDocMetadata::from_doc(&signed_attestation_doc()).unwrap();
+        assert_eq!(
+            metadata.issued_at,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(ISSUED_AT_MILLIS)
+        );
+        assert_eq!(metadata.issued_at_rfc3339(), "2023-11-14T22:13:20Z");
+        assert_eq!(metadata.expires_at_rfc3339(), "2023-11-15T01:13:20Z");
+        // The signing cert expired long ago, so the doc can't be cached
+        assert_eq!(metadata.remaining_validity(), Duration::ZERO);
+
+        let mut headers = hyper::HeaderMap::new();
+        metadata.add_headers(&mut headers);
+        assert_eq!(
+            headers.get(super::ATTESTATION_EXPIRES_AT_HEADER).unwrap(),
+            "2023-11-15T01:13:20Z"
+        );
+        assert_eq!(
+            headers.get(hyper::header::CACHE_CONTROL).unwrap(),
+            "private, max-age=0"
+        );
+    }
+
+    #[test]
+    fn test_pcrs_are_read_from_attestation_doc() {
+        let pcrs = BTreeMap::from([(0, vec![0xab; 48]), (8, vec![0; 48])]);
+        let doc = signed_attestation_doc_with_pcrs(pcrs.clone());
+        assert_eq!(get_pcrs(&doc).unwrap(), pcrs);
+        assert!(get_pcrs(b"not a doc").is_err());
+    }
+
+    #[test]
+    fn test_metadata_is_not_read_from_invalid_doc() {
+        assert!(AttestationDocMetadata::from_doc(b"not a doc").is_err());
+    }
+
+    #[test]
+    fn test_metadata_is_not_read_from_signed_non_attestation_payload() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let signed = cose::CoseSign1::new::<cose::crypto::Openssl>(
+            b"not an attestation doc",
+            &cose::header_map::HeaderMap::new(),
+            &key,
+        )
+        .unwrap()
+        .as_bytes(false)
+        .unwrap();
+        assert!(matches!(
+            AttestationDocMetadata::from_doc(&signed),
+            Err(AttestationError::AttestationDocParseFailed(_))
+        ));
+    }
 
     #[test]
     fn test_parse_valid_utc_date() {
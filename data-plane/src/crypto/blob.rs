@@ -0,0 +1,437 @@
+//! Chunked envelope encryption of streamed blobs, so large files can be encrypted on their way out of the enclave, and
+//! decrypted on their way back in, without being buffered.
+//!
+//! Each blob is encrypted under a fresh AES-256-GCM data key, which is itself encrypted by E3 and embedded in the
+//! blob header. The plaintext is split into fixed size chunks, each sealed with a nonce made of a random prefix, the
+//! chunk's index and a flag marking the final chunk, so chunks can't be reordered, dropped or truncated unnoticed.
+//!
+//! Layout: `EVBLOB | version (u8) | chunk size (u32) | nonce prefix (7) | key len (u16) | encrypted data key`,
+//! followed by `length (u32) | ciphertext | tag (16)` for every chunk. Integers are big endian. Every blob has at
+//! least one chunk, and only the last is sealed with the final flag, which may be empty.
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use openssl::error::ErrorStack;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const BLOB_MAGIC: &[u8] = b"EVBLOB";
+const BLOB_VERSION: u8 = 1;
+pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const CHUNK_OVERHEAD: u64 = (4 + TAG_LEN) as u64;
+/// Magic, version, chunk size, nonce prefix and key length
+const FIXED_HEADER_LEN: usize = BLOB_MAGIC.len() + 1 + 4 + NONCE_PREFIX_LEN + 2;
+
+#[derive(Debug, Error)]
+pub enum BlobEncryptionError {
+    #[error("Failed to encrypt or decrypt blob chunk — {0}")]
+    Openssl(#[from] ErrorStack),
+    #[error("Failed to read blob — {0}")]
+    Body(#[from] hyper::Error),
+    #[error("Blob exceeds the maximum number of chunks")]
+    TooManyChunks,
+    #[error("Encrypted data key is too long")]
+    DataKeyTooLong,
+    #[error("Body length doesn't match its content length")]
+    LengthMismatch,
+    #[error("Invalid blob — {0}")]
+    InvalidBlob(&'static str),
+}
+
+pub fn generate_data_key() -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(rand::random())
+}
+
+fn chunk_nonce(nonce_prefix: &[u8; NONCE_PREFIX_LEN], index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(nonce_prefix);
+    nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce
+}
+
+pub struct BlobEncryptor {
+    data_key: Zeroizing<[u8; 32]>,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    next_chunk: u32,
+    header: Vec<u8>,
+    plaintext_len: Option<u64>,
+}
+
+impl BlobEncryptor {
+    /// When the plaintext's length is given, a body of any other length fails the stream.
+    pub fn new(
+        data_key: Zeroizing<[u8; 32]>,
+        encrypted_data_key: &str,
+        plaintext_len: Option<u64>,
+    ) -> Result<Self, BlobEncryptionError> {
+        let nonce_prefix: [u8; NONCE_PREFIX_LEN] = rand::random();
+        let key_len = u16::try_from(encrypted_data_key.len())
+            .map_err(|_| BlobEncryptionError::DataKeyTooLong)?;
+
+        let mut header = Vec::with_capacity(FIXED_HEADER_LEN + encrypted_data_key.len());
+        header.extend_from_slice(BLOB_MAGIC);
+        header.push(BLOB_VERSION);
+        header.extend_from_slice(&(BLOB_CHUNK_SIZE as u32).to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        header.extend_from_slice(&key_len.to_be_bytes());
+        header.extend_from_slice(encrypted_data_key.as_bytes());
+
+        Ok(Self {
+            data_key,
+            nonce_prefix,
+            next_chunk: 0,
+            header,
+            plaintext_len,
+        })
+    }
+
+    /// Size of the encrypted blob, if the plaintext's length is known, so uploads can declare a content length up
+    /// front.
+    pub fn encrypted_len(&self) -> Option<u64> {
+        let plaintext_len = self.plaintext_len?;
+        let chunks = plaintext_len.div_ceil(BLOB_CHUNK_SIZE as u64).max(1);
+        Some(self.header.len() as u64 + plaintext_len + chunks * CHUNK_OVERHEAD)
+    }
+
+    fn seal_chunk(&mut self, plaintext: &[u8], last: bool) -> Result<Bytes, BlobEncryptionError> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.next_chunk, last);
+        self.next_chunk = self
+            .next_chunk
+            .checked_add(1)
+            .ok_or(BlobEncryptionError::TooManyChunks)?;
+
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            self.data_key.as_slice(),
+            Some(&nonce),
+            &[],
+            plaintext,
+            &mut tag,
+        )?;
+
+        let mut chunk = BytesMut::with_capacity(4 + ciphertext.len() + TAG_LEN);
+        chunk.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(&ciphertext);
+        chunk.extend_from_slice(&tag);
+        Ok(chunk.freeze())
+    }
+
+    /// Encrypt a body as it's read, yielding the blob header followed by each sealed chunk.
+    pub fn encrypt_stream<S, E>(self, body: S) -> impl Stream<Item = Result<Bytes, E>> + Send
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin,
+        E: From<BlobEncryptionError> + Send,
+    {
+        let header = Bytes::from(self.header.clone());
+        let state = Some((self, body, BytesMut::new(), 0u64));
+        let chunks = futures::stream::unfold(state, |state| async move {
+            let (mut encryptor, mut body, mut pending, mut read) = state?;
+            // A full chunk is only sealed once more data follows it, so the final chunk is always flagged
+            while pending.len() <= BLOB_CHUNK_SIZE {
+                match body.next().await {
+                    Some(Ok(data)) => {
+                        read += data.len() as u64;
+                        if encryptor.plaintext_len.is_some_and(|len| read > len) {
+                            return Some((Err(BlobEncryptionError::LengthMismatch.into()), None));
+                        }
+                        pending.extend_from_slice(&data);
+                    }
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        if encryptor.plaintext_len.is_some_and(|len| read != len) {
+                            return Some((Err(BlobEncryptionError::LengthMismatch.into()), None));
+                        }
+                        let chunk = encryptor.seal_chunk(&pending, true).map_err(E::from);
+                        return Some((chunk, None));
+                    }
+                }
+            }
+            let plaintext = pending.split_to(BLOB_CHUNK_SIZE);
+            match encryptor.seal_chunk(&plaintext, false) {
+                Ok(chunk) => Some((Ok(chunk), Some((encryptor, body, pending, read)))),
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        });
+        futures::stream::once(async move { Ok(header) }).chain(chunks)
+    }
+}
+
+/// The header of an encrypted blob, read before its chunks.
+pub struct BlobHeader {
+    chunk_size: usize,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    pub encrypted_data_key: String,
+}
+
+/// Read from a body until there are at least `len` bytes pending, returning false if it ends first.
+async fn fill<S, E>(body: &mut S, pending: &mut BytesMut, len: usize) -> Result<bool, E>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    while pending.len() < len {
+        match body.next().await {
+            Some(data) => pending.extend_from_slice(&data?),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+impl BlobHeader {
+    /// Read the header from the start of a blob. Bytes read past it are left in `pending` for the decryptor.
+    pub async fn read<S, E>(body: &mut S, pending: &mut BytesMut) -> Result<Self, E>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: From<BlobEncryptionError>,
+    {
+        let truncated = || E::from(BlobEncryptionError::InvalidBlob("truncated header"));
+        if !fill(body, pending, FIXED_HEADER_LEN).await? {
+            return Err(truncated());
+        }
+        if !pending.starts_with(BLOB_MAGIC) {
+            return Err(BlobEncryptionError::InvalidBlob("missing EVBLOB magic").into());
+        }
+        pending.advance(BLOB_MAGIC.len());
+        if pending.get_u8() != BLOB_VERSION {
+            return Err(BlobEncryptionError::InvalidBlob("unsupported version").into());
+        }
+        let chunk_size = pending.get_u32() as usize;
+        // Chunks are buffered whole, so only the size this version writes is accepted
+        if chunk_size != BLOB_CHUNK_SIZE {
+            return Err(BlobEncryptionError::InvalidBlob("unsupported chunk size").into());
+        }
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        pending.copy_to_slice(&mut nonce_prefix);
+        let key_len = pending.get_u16() as usize;
+        if !fill(body, pending, key_len).await? {
+            return Err(truncated());
+        }
+        let encrypted_data_key = String::from_utf8(pending.split_to(key_len).to_vec())
+            .map_err(|_| BlobEncryptionError::InvalidBlob("data key isn't UTF-8"))?;
+        Ok(Self {
+            chunk_size,
+            nonce_prefix,
+            encrypted_data_key,
+        })
+    }
+}
+
+pub struct BlobDecryptor {
+    data_key: Zeroizing<[u8; 32]>,
+    header: BlobHeader,
+    next_chunk: u32,
+}
+
+impl BlobDecryptor {
+    pub fn new(data_key: Zeroizing<[u8; 32]>, header: BlobHeader) -> Self {
+        Self {
+            data_key,
+            header,
+            next_chunk: 0,
+        }
+    }
+
+    fn open_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Bytes, BlobEncryptionError> {
+        let nonce = chunk_nonce(&self.header.nonce_prefix, self.next_chunk, last);
+        self.next_chunk = self
+            .next_chunk
+            .checked_add(1)
+            .ok_or(BlobEncryptionError::TooManyChunks)?;
+        let (ciphertext, tag) = chunk.split_at(chunk.len() - TAG_LEN);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            self.data_key.as_slice(),
+            Some(&nonce),
+            &[],
+            ciphertext,
+            tag,
+        )?;
+        Ok(Bytes::from(plaintext))
+    }
+
+    /// Decrypt the chunks following the header as they're read, yielding each chunk's plaintext. A chunk is only
+    /// treated as the last if the body ends after it, so a truncated or extended blob fails to decrypt.
+    pub fn decrypt_stream<S, E>(
+        self,
+        body: S,
+        pending: BytesMut,
+    ) -> impl Stream<Item = Result<Bytes, E>> + Send
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Unpin,
+        E: From<BlobEncryptionError> + Send,
+    {
+        let state = Some((self, body, pending));
+        futures::stream::unfold(state, |state| async move {
+            let (mut decryptor, mut body, mut pending) = state?;
+            let truncated = || E::from(BlobEncryptionError::InvalidBlob("truncated chunk"));
+            match fill(&mut body, &mut pending, 4).await {
+                Ok(true) => {}
+                Ok(false) => return Some((Err(truncated()), None)),
+                Err(e) => return Some((Err(e), None)),
+            }
+            let len = u32::from_be_bytes([pending[0], pending[1], pending[2], pending[3]]) as usize;
+            if len > decryptor.header.chunk_size {
+                let e = BlobEncryptionError::InvalidBlob("chunk is larger than the chunk size");
+                return Some((Err(e.into()), None));
+            }
+            match fill(&mut body, &mut pending, 4 + len + TAG_LEN + 1).await {
+                Ok(more) if more || pending.len() == 4 + len + TAG_LEN => {
+                    let last = !more;
+                    pending.advance(4);
+                    let chunk = pending.split_to(len + TAG_LEN);
+                    match decryptor.open_chunk(&chunk, last) {
+                        Ok(plaintext) if last => Some((Ok(plaintext), None)),
+                        Ok(plaintext) => Some((Ok(plaintext), Some((decryptor, body, pending)))),
+                        Err(e) => Some((Err(e.into()), None)),
+                    }
+                }
+                Ok(_) => Some((Err(truncated()), None)),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        generate_data_key, BlobDecryptor, BlobEncryptionError, BlobEncryptor, BlobHeader,
+        BLOB_CHUNK_SIZE, BLOB_MAGIC, TAG_LEN,
+    };
+    use bytes::{Bytes, BytesMut};
+    use futures::{Stream, TryStreamExt};
+    use openssl::error::ErrorStack;
+    use openssl::symm::{decrypt_aead, Cipher};
+
+    fn read_u32(bytes: &[u8]) -> usize {
+        u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize
+    }
+
+    // Returns the encrypted data key and the plaintext, checking every chunk's nonce and the final chunk flag
+    fn open_blob(data_key: &[u8], blob: &[u8]) -> Result<(String, Vec<u8>), ErrorStack> {
+        assert_eq!(&blob[..BLOB_MAGIC.len()], BLOB_MAGIC);
+        let mut offset = BLOB_MAGIC.len() + 1 + 4;
+        let nonce_prefix = &blob[offset..offset + 7];
+        offset += 7;
+        let key_len = u16::from_be_bytes(blob[offset..offset + 2].try_into().unwrap()) as usize;
+        offset += 2;
+        let encrypted_data_key =
+            String::from_utf8(blob[offset..offset + key_len].to_vec()).unwrap();
+        offset += key_len;
+
+        let mut plaintext = Vec::new();
+        let mut index = 0u32;
+        while offset < blob.len() {
+            let len = read_u32(&blob[offset..]);
+            offset += 4;
+            let ciphertext = &blob[offset..offset + len];
+            let tag = &blob[offset + len..offset + len + TAG_LEN];
+            offset += len + TAG_LEN;
+
+            let mut nonce = nonce_prefix.to_vec();
+            nonce.extend_from_slice(&index.to_be_bytes());
+            nonce.push(u8::from(offset == blob.len()));
+            let chunk = decrypt_aead(
+                Cipher::aes_256_gcm(),
+                data_key,
+                Some(&nonce),
+                &[],
+                ciphertext,
+                tag,
+            )?;
+            plaintext.extend_from_slice(&chunk);
+            index += 1;
+        }
+        Ok((encrypted_data_key, plaintext))
+    }
+
+    fn body(
+        data: &[u8],
+        chunk_size: usize,
+    ) -> impl Stream<Item = Result<Bytes, BlobEncryptionError>> {
+        let chunks: Vec<_> = data
+            .chunks(chunk_size.max(1))
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    async fn encrypt(plaintext: &[u8], input_chunk_size: usize) -> (Vec<u8>, Vec<u8>, u64) {
+        let data_key = generate_data_key();
+        let key_bytes = data_key.to_vec();
+        let encryptor =
+            BlobEncryptor::new(data_key, "ev:encrypted-key", Some(plaintext.len() as u64)).unwrap();
+        let expected_len = encryptor.encrypted_len().unwrap();
+
+        let blob: Vec<u8> = encryptor
+            .encrypt_stream(body(plaintext, input_chunk_size))
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        (key_bytes, blob, expected_len)
+    }
+
+    async fn decrypt(data_key: &[u8], blob: &[u8]) -> Result<Vec<u8>, BlobEncryptionError> {
+        let mut body = body(blob, 1000);
+        let mut pending = BytesMut::new();
+        let header = BlobHeader::read(&mut body, &mut pending).await?;
+        assert_eq!(header.encrypted_data_key, "ev:encrypted-key");
+        let data_key = zeroize::Zeroizing::new(data_key.try_into().unwrap());
+        BlobDecryptor::new(data_key, header)
+            .decrypt_stream(body, pending)
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_blob_round_trips_across_chunks() {
+        for len in [
+            0,
+            10,
+            BLOB_CHUNK_SIZE,
+            BLOB_CHUNK_SIZE + 1,
+            3 * BLOB_CHUNK_SIZE + 100,
+        ] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let (data_key, blob, expected_len) = encrypt(&plaintext, 10_000).await;
+            assert_eq!(blob.len() as u64, expected_len);
+            let (encrypted_data_key, decrypted) = open_blob(&data_key, &blob).unwrap();
+            assert_eq!(encrypted_data_key, "ev:encrypted-key");
+            assert_eq!(decrypted, plaintext);
+            assert_eq!(decrypt(&data_key, &blob).await.unwrap(), plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_must_match_its_content_length() {
+        for len in [10, 30] {
+            let encryptor =
+                BlobEncryptor::new(generate_data_key(), "ev:encrypted-key", Some(20)).unwrap();
+            let result: Result<Vec<Bytes>, _> = encryptor
+                .encrypt_stream(body(&vec![0u8; len], 7))
+                .try_collect()
+                .await;
+            assert!(matches!(result, Err(BlobEncryptionError::LengthMismatch)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_blob_is_rejected() {
+        let plaintext = vec![1u8; 2 * BLOB_CHUNK_SIZE + 5];
+        let (data_key, blob, _) = encrypt(&plaintext, BLOB_CHUNK_SIZE).await;
+        // Dropping the final chunk leaves a chunk which wasn't sealed as the last one
+        let truncated_len = blob.len() - (4 + 5 + TAG_LEN);
+        assert!(open_blob(&data_key, &blob[..truncated_len]).is_err());
+        assert!(decrypt(&data_key, &blob[..truncated_len]).await.is_err());
+        assert!(decrypt(&data_key, &blob[..blob.len() - 1]).await.is_err());
+        let mut extended = blob.clone();
+        extended.extend_from_slice(&blob[blob.len() - (4 + 5 + TAG_LEN)..]);
+        assert!(decrypt(&data_key, &extended).await.is_err());
+    }
+}
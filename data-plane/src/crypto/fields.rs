@@ -0,0 +1,249 @@
+//! Selection of fields in a JSON document by path, so only part of a document is encrypted. Paths are given in dot
+//! notation, e.g. `user.card.number`, or as a subset of JSONPath, e.g. `$.users[*].card['number']`. Keys, array
+//! indices and `*` wildcards over objects or arrays are supported.
+use serde_json::Value;
+use std::collections::HashSet;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Query parameter listing the paths to select, separated by commas. It can also be repeated.
+const FIELDS_PARAM: &str = "fields";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FieldPathError {
+    #[error("Invalid field path {path} — {reason}")]
+    InvalidPath { path: String, reason: &'static str },
+    #[error("The fields parameter isn't valid percent encoding")]
+    InvalidEncoding,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldPath(Vec<Segment>);
+
+impl FromStr for FieldPath {
+    type Err = FieldPathError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| FieldPathError::InvalidPath {
+            path: path.to_string(),
+            reason,
+        };
+        let trimmed = path.trim();
+        let mut rest = trimmed.strip_prefix('$').unwrap_or(trimmed);
+        // Dot notation starts with a bare key, where JSONPath starts with `$.` or `$[`
+        let mut bare = rest.len() == trimmed.len();
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('[') {
+                let (selector, after) =
+                    after.split_once(']').ok_or_else(|| invalid("unclosed ["))?;
+                segments.push(parse_selector(selector).ok_or_else(|| invalid("invalid selector"))?);
+                rest = after;
+            } else {
+                let after = match bare {
+                    true => rest,
+                    false => rest
+                        .strip_prefix('.')
+                        .ok_or_else(|| invalid("expected . or ["))?,
+                };
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let segment = match &after[..end] {
+                    "" => return Err(invalid("empty key")),
+                    "*" => Segment::Wildcard,
+                    key => Segment::Key(key.to_string()),
+                };
+                segments.push(segment);
+                rest = &after[end..];
+            }
+            bare = false;
+        }
+        if segments.is_empty() {
+            return Err(invalid("the path must select a field"));
+        }
+        Ok(Self(segments))
+    }
+}
+
+/// A bracketed selector: `*`, an index, or a quoted key.
+fn parse_selector(selector: &str) -> Option<Segment> {
+    let selector = selector.trim();
+    if selector == "*" {
+        return Some(Segment::Wildcard);
+    }
+    if let Ok(index) = selector.parse() {
+        return Some(Segment::Index(index));
+    }
+    ['\'', '"'].into_iter().find_map(|quote| {
+        selector
+            .strip_prefix(quote)?
+            .strip_suffix(quote)
+            .map(|key| Segment::Key(key.to_string()))
+    })
+}
+
+/// The paths listed in a query string, or `None` if it doesn't list any.
+pub fn from_query(query: Option<&str>) -> Result<Option<Vec<FieldPath>>, FieldPathError> {
+    let mut paths = Vec::new();
+    let params = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|param| param.split_once('='))
+        .filter(|(name, _)| *name == FIELDS_PARAM);
+    for (_, value) in params {
+        let value = percent_decode(value).ok_or(FieldPathError::InvalidEncoding)?;
+        for path in value.split(',').filter(|path| !path.trim().is_empty()) {
+            paths.push(path.parse()?);
+        }
+    }
+    Ok((!paths.is_empty()).then_some(paths))
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// JSON pointers to the fields in the document selected by any of the paths, in the order of the paths. Fields within
+/// another selected field are left out, as they're covered by it.
+pub fn select(document: &Value, paths: &[FieldPath]) -> Vec<String> {
+    let mut pointers = Vec::new();
+    for path in paths {
+        collect(document, &path.0, String::new(), &mut pointers);
+    }
+    let selected: HashSet<String> = pointers.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    pointers.retain(|pointer| {
+        let has_selected_ancestor = pointer
+            .match_indices('/')
+            .any(|(end, _)| end > 0 && selected.contains(&pointer[..end]));
+        !has_selected_ancestor && seen.insert(pointer.clone())
+    });
+    pointers
+}
+
+fn collect(value: &Value, segments: &[Segment], pointer: String, pointers: &mut Vec<String>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        pointers.push(pointer);
+        return;
+    };
+    match (segment, value) {
+        (Segment::Key(key), Value::Object(object)) => {
+            if let Some(child) = object.get(key) {
+                collect(child, rest, child_pointer(&pointer, key), pointers);
+            }
+        }
+        (Segment::Index(index), Value::Array(array)) => {
+            if let Some(child) = array.get(*index) {
+                collect(child, rest, format!("{pointer}/{index}"), pointers);
+            }
+        }
+        (Segment::Wildcard, Value::Object(object)) => {
+            for (key, child) in object {
+                collect(child, rest, child_pointer(&pointer, key), pointers);
+            }
+        }
+        (Segment::Wildcard, Value::Array(array)) => {
+            for (index, child) in array.iter().enumerate() {
+                collect(child, rest, format!("{pointer}/{index}"), pointers);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn child_pointer(pointer: &str, key: &str) -> String {
+    format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_query, select, FieldPath, FieldPathError, Segment};
+    use serde_json::json;
+
+    #[test]
+    fn test_dot_notation_and_jsonpath_parse_alike() {
+        let expected = FieldPath(vec![
+            Segment::Key("users".into()),
+            Segment::Wildcard,
+            Segment::Key("card.number".into()),
+        ]);
+        assert_eq!("$.users[*]['card.number']".parse(), Ok(expected.clone()));
+        assert_eq!("users.*[\"card.number\"]".parse(), Ok(expected));
+        assert_eq!(
+            "$.items[2].sku".parse(),
+            Ok(FieldPath(vec![
+                Segment::Key("items".into()),
+                Segment::Index(2),
+                Segment::Key("sku".into())
+            ]))
+        );
+        for invalid in ["", "$", "a..b", "a[", "a[b]", "$a"] {
+            assert!(
+                invalid.parse::<FieldPath>().is_err(),
+                "{invalid} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_only_selected_fields_are_returned() {
+        let document = json!({
+            "name": "alice",
+            "card": { "number": "4242", "expiry": "12/30" },
+            "a/b": 1,
+            "items": [{ "sku": 1 }, { "sku": 2 }, { "name": "no sku" }],
+        });
+        let paths: Vec<FieldPath> = ["card.number", "$.items[*].sku", "['a/b']", "missing.field"]
+            .iter()
+            .map(|path| path.parse().unwrap())
+            .collect();
+        assert_eq!(
+            select(&document, &paths),
+            vec!["/card/number", "/items/0/sku", "/items/1/sku", "/a~1b"]
+        );
+
+        // Fields within another selected field, and repeats, are covered by the first selection
+        let paths: Vec<FieldPath> = ["card.number", "card", "card"]
+            .iter()
+            .map(|path| path.parse().unwrap())
+            .collect();
+        assert_eq!(select(&document, &paths), vec!["/card"]);
+    }
+
+    #[test]
+    fn test_fields_are_read_from_the_query() {
+        assert_eq!(from_query(None), Ok(None));
+        assert_eq!(from_query(Some("other=1")), Ok(None));
+        let paths = from_query(Some("fields=card.number,%24.items%5B*%5D.sku&fields=name"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[1], "$.items[*].sku".parse().unwrap());
+        assert_eq!(
+            from_query(Some("fields=%zz")),
+            Err(FieldPathError::InvalidEncoding)
+        );
+        assert!(matches!(
+            from_query(Some("fields=a..b")),
+            Err(FieldPathError::InvalidPath { .. })
+        ));
+    }
+}
@@ -0,0 +1,315 @@
+//! gRPC counterpart of the HTTP Crypto API, for processes in the enclave built on gRPC-first stacks.
+//!
+//! Payloads are `google.protobuf.Value`s, handled as the equivalent JSON documents accepted by the HTTP Crypto API.
+//! Requests share its E3 client, decrypt cache and per api key quotas.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "enclave")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{Stream, StreamExt};
+use prost_types::value::Kind;
+use prost_types::{ListValue, Struct};
+use serde_json::Value;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
+
+use super::api::{AttestationEncoding, CryptoApi, CryptoApiError};
+#[cfg(feature = "enclave")]
+use super::attest::AttestationDocMetadata;
+use crate::utils::payload_format::PayloadFormat;
+
+mod proto {
+    tonic::include_proto!("evervault.enclaves.crypto.v1");
+}
+
+pub use proto::crypto_service_server::{CryptoService, CryptoServiceServer};
+pub use proto::{
+    AttestationDocRequest, AttestationDocResponse, CryptoResponse, DecryptRequest, EncryptRequest,
+};
+
+const CRYPTO_GRPC_API_PORT: u16 = 9998;
+
+impl From<CryptoApiError> for Status {
+    fn from(err: CryptoApiError) -> Self {
+        match err {
+            CryptoApiError::SerdeError(_)
+            | CryptoApiError::SerializationError
+            | CryptoApiError::InvalidAttestationRequest(_) => {
+                Status::invalid_argument(err.to_string())
+            }
+            CryptoApiError::QuotaExceeded(_) => Status::resource_exhausted(err.to_string()),
+            CryptoApiError::InvalidApiKey => Status::unauthenticated(err.to_string()),
+            _ => Status::internal(err.to_string()),
+        }
+    }
+}
+
+/// Convert a request's value to JSON. Protobuf numbers are all doubles, so whole numbers become JSON integers, to be
+/// encrypted with the same type as they would be through the HTTP API.
+/// Values without a kind, and numbers which aren't finite, have no JSON equivalent and are rejected.
+fn to_json(value: prost_types::Value) -> Result<Value, CryptoApiError> {
+    let Some(kind) = value.kind else {
+        return Err(CryptoApiError::SerializationError);
+    };
+    Ok(match kind {
+        Kind::NullValue(_) => Value::Null,
+        Kind::BoolValue(boolean) => Value::Bool(boolean),
+        Kind::NumberValue(number) if number.fract() == 0.0 && number.abs() < 2f64.powi(53) => {
+            Value::from(number as i64)
+        }
+        Kind::NumberValue(number) => serde_json::Number::from_f64(number)
+            .map(Value::Number)
+            .ok_or(CryptoApiError::SerializationError)?,
+        Kind::StringValue(string) => Value::String(string),
+        Kind::ListValue(list) => Value::Array(
+            list.values
+                .into_iter()
+                .map(to_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        Kind::StructValue(fields) => Value::Object(
+            fields
+                .fields
+                .into_iter()
+                .map(|(key, value)| Ok((key, to_json(value)?)))
+                .collect::<Result<_, CryptoApiError>>()?,
+        ),
+    })
+}
+
+fn from_json(value: Value) -> prost_types::Value {
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(boolean) => Kind::BoolValue(boolean),
+        Value::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or_default()),
+        Value::String(string) => Kind::StringValue(string),
+        Value::Array(items) => Kind::ListValue(ListValue {
+            values: items.into_iter().map(from_json).collect(),
+        }),
+        Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| (key, from_json(value)))
+                .collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+/// A request's value as JSON, along with its encoded size which quotas are counted in.
+fn request_payload(data: Option<prost_types::Value>) -> Result<(Value, Vec<u8>), CryptoApiError> {
+    let data = to_json(data.ok_or(CryptoApiError::SerializationError)?)?;
+    let encoded = serde_json::to_vec(&data).map_err(|_| CryptoApiError::SerializationError)?;
+    Ok((data, encoded))
+}
+
+fn api_key(metadata: &MetadataMap) -> Option<Vec<u8>> {
+    metadata
+        .get("api-key")
+        .map(|api_key| api_key.as_bytes().to_vec())
+}
+
+pub struct CryptoGrpcApi {
+    api: Arc<CryptoApi>,
+}
+
+impl Default for CryptoGrpcApi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CryptoGrpcApi {
+    pub fn new() -> Self {
+        Self {
+            api: Arc::new(CryptoApi::new()),
+        }
+    }
+
+    pub async fn listen() -> Result<(), tonic::transport::Error> {
+        log::info!("Crypto gRPC API started");
+
+        let addr = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            CRYPTO_GRPC_API_PORT,
+        );
+        tonic::transport::Server::builder()
+            .add_service(CryptoServiceServer::new(Self::new()))
+            .serve(addr)
+            .await
+    }
+
+    async fn encrypt_request(
+        api: &CryptoApi,
+        api_key: Option<&[u8]>,
+        request: EncryptRequest,
+    ) -> Result<CryptoResponse, Status> {
+        api.check_api_key(api_key).await?;
+        let (data, encoded) = request_payload(request.data)?;
+        api.check_quota(api_key, encoded.len() as u64).await?;
+        let data = api
+            .encrypt_value(data, request.data_role, request.key_version)
+            .await?;
+        Ok(CryptoResponse {
+            data: Some(from_json(data)),
+        })
+    }
+
+    /// When the doc was issued and when it expires, in seconds since the epoch.
+    #[cfg(feature = "enclave")]
+    fn attestation_doc_times(attestation_doc: &[u8]) -> (Option<u64>, Option<u64>) {
+        let epoch_secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .ok()
+        };
+        match AttestationDocMetadata::from_doc(attestation_doc) {
+            Ok(metadata) => (
+                epoch_secs(metadata.issued_at),
+                epoch_secs(metadata.expires_at),
+            ),
+            Err(_) => (None, None),
+        }
+    }
+
+    #[cfg(not(feature = "enclave"))]
+    fn attestation_doc_times(_: &[u8]) -> (Option<u64>, Option<u64>) {
+        (None, None)
+    }
+}
+
+#[tonic::async_trait]
+impl CryptoService for CryptoGrpcApi {
+    async fn encrypt(
+        &self,
+        request: Request<EncryptRequest>,
+    ) -> Result<Response<CryptoResponse>, Status> {
+        let api_key = api_key(request.metadata());
+        let response =
+            Self::encrypt_request(&self.api, api_key.as_deref(), request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn decrypt(
+        &self,
+        request: Request<DecryptRequest>,
+    ) -> Result<Response<CryptoResponse>, Status> {
+        let api_key = api_key(request.metadata());
+        self.api.check_api_key(api_key.as_deref()).await?;
+        let (_, encoded) = request_payload(request.into_inner().data)?;
+        self.api
+            .check_quota(api_key.as_deref(), encoded.len() as u64)
+            .await?;
+        // Decrypted through the encoded payload, which the decrypt cache is keyed by
+        let data = self
+            .api
+            .decrypt_value(api_key.as_deref(), &encoded, PayloadFormat::Json)
+            .await?;
+        Ok(Response::new(CryptoResponse {
+            data: Some(from_json(data)),
+        }))
+    }
+
+    type BatchEncryptStream = Pin<Box<dyn Stream<Item = Result<CryptoResponse, Status>> + Send>>;
+
+    async fn batch_encrypt(
+        &self,
+        request: Request<Streaming<EncryptRequest>>,
+    ) -> Result<Response<Self::BatchEncryptStream>, Status> {
+        let api_key = api_key(request.metadata());
+        let api = self.api.clone();
+        let responses = request.into_inner().then(move |request| {
+            let api = api.clone();
+            let api_key = api_key.clone();
+            async move { Self::encrypt_request(&api, api_key.as_deref(), request?).await }
+        });
+        Ok(Response::new(Box::pin(responses)))
+    }
+
+    async fn get_attestation_doc(
+        &self,
+        request: Request<AttestationDocRequest>,
+    ) -> Result<Response<AttestationDocResponse>, Status> {
+        let request = request.into_inner();
+        let encoding = request
+            .encoding
+            .as_deref()
+            .map(str::parse::<AttestationEncoding>)
+            .transpose()?
+            .unwrap_or_default();
+        let challenge = encoding.decode("challenge", request.challenge)?;
+        let nonce = encoding.decode("nonce", request.nonce)?;
+        let attestation_doc = CryptoApi::attestation_doc(challenge, nonce)?;
+        let (issued_at, expires_at) = Self::attestation_doc_times(&attestation_doc);
+        Ok(Response::new(AttestationDocResponse {
+            attestation_doc,
+            issued_at,
+            expires_at,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_json, to_json, AttestationDocRequest, CryptoGrpcApi, CryptoService};
+    use crate::crypto::api::CryptoApiError;
+    use crate::crypto::quota::QuotaError;
+    use tonic::{Code, Request, Status};
+
+    #[tokio::test]
+    async fn test_get_attestation_doc() {
+        let response = CryptoGrpcApi::new()
+            .get_attestation_doc(Request::new(AttestationDocRequest {
+                challenge: Some("challenge".to_string()),
+                nonce: None,
+                encoding: None,
+            }))
+            .await
+            .unwrap();
+        assert!(!response.into_inner().attestation_doc.is_empty());
+    }
+
+    #[test]
+    fn test_values_convert_to_and_from_json() {
+        let json = serde_json::json!({
+            "card": { "number": "4242", "cvv": 123, "amount": 10.5 },
+            "tags": [true, null, "a"],
+        });
+        let value = from_json(json.clone());
+        assert_eq!(to_json(value).unwrap(), json);
+
+        let unset = prost_types::Value { kind: None };
+        assert!(matches!(
+            to_json(unset),
+            Err(CryptoApiError::SerializationError)
+        ));
+        let infinite = prost_types::Value {
+            kind: Some(prost_types::value::Kind::NumberValue(f64::INFINITY)),
+        };
+        assert!(matches!(
+            to_json(infinite),
+            Err(CryptoApiError::SerializationError)
+        ));
+    }
+
+    #[test]
+    fn test_errors_map_to_grpc_status_codes() {
+        assert_eq!(
+            Status::from(CryptoApiError::SerializationError).code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            Status::from(CryptoApiError::QuotaExceeded(
+                QuotaError::RequestsPerMinute(10)
+            ))
+            .code(),
+            Code::ResourceExhausted
+        );
+        assert_eq!(
+            Status::from(CryptoApiError::NotFound).code(),
+            Code::Internal
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! ES256 service tokens signed with a key that is generated inside the enclave and never leaves it.
+//!
+//! The key is created on first use and lives for the lifetime of the data plane, so tokens can only be minted by
+//! this enclave. Verifiers fetch the public key from the JWKS endpoint, which is served alongside an attestation
+//! document binding the key ID to the enclave's measurements. Tokens signed before a restart won't verify after it.
+use once_cell::sync::Lazy;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::Private;
+use openssl::sha::sha256;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+const JWT_ALGORITHM: &str = "ES256";
+const COORDINATE_LEN: usize = 32;
+/// Lifetime of tokens signed without an `exp` claim
+pub const DEFAULT_TOKEN_TTL_SECS: i64 = 60 * 60;
+/// Allowance for clock drift between the signer and verifier when checking `exp` and `nbf`
+const CLOCK_SKEW_LEEWAY_SECS: i64 = 60;
+
+static JWT_SIGNER: Lazy<Result<JwtSigner, ErrorStack>> = Lazy::new(JwtSigner::generate);
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("Failed to process token — {0}")]
+    Openssl(#[from] ErrorStack),
+    #[error("Token claims must be a JSON object")]
+    InvalidClaims,
+    #[error("Malformed token — {0}")]
+    Malformed(String),
+    #[error("Token was not signed by this enclave")]
+    UnknownKey,
+    #[error("Invalid token signature")]
+    InvalidSignature,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token is not yet valid")]
+    NotYetValid,
+}
+
+fn encode_segment(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, JwtError> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| JwtError::Malformed(e.to_string()))
+}
+
+fn decode_json_segment(segment: &str) -> Result<Map<String, Value>, JwtError> {
+    match serde_json::from_slice(&decode_segment(segment)?) {
+        Ok(Value::Object(object)) => Ok(object),
+        Ok(_) => Err(JwtError::Malformed(
+            "segment is not a JSON object".to_string(),
+        )),
+        Err(e) => Err(JwtError::Malformed(e.to_string())),
+    }
+}
+
+/// Read a NumericDate claim, which may be fractional. A present claim which isn't a number is rejected rather than
+/// skipped, so a token can't dodge its expiry by quoting it.
+fn numeric_date_claim(claims: &Map<String, Value>, name: &str) -> Result<Option<f64>, JwtError> {
+    match claims.get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .map(Some)
+            .ok_or_else(|| JwtError::Malformed(format!("{name} must be a number of seconds"))),
+    }
+}
+
+fn now() -> i64 {
+    chrono::DateTime::<chrono::Utc>::from(shared::clock::now()).timestamp()
+}
+
+pub struct JwtSigner {
+    key: EcKey<Private>,
+    jwk: Value,
+    kid: String,
+}
+
+impl JwtSigner {
+    /// The signer for this enclave, generating its key on first use.
+    pub fn get() -> Result<&'static Self, JwtError> {
+        JWT_SIGNER
+            .as_ref()
+            .map_err(|e| JwtError::Openssl(e.clone()))
+    }
+
+    fn generate() -> Result<Self, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        Self::from_key(EcKey::generate(&group)?)
+    }
+
+    fn from_key(key: EcKey<Private>) -> Result<Self, ErrorStack> {
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        key.public_key()
+            .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)?;
+        let x = encode_segment(&x.to_vec_padded(COORDINATE_LEN as i32)?);
+        let y = encode_segment(&y.to_vec_padded(COORDINATE_LEN as i32)?);
+
+        // RFC 7638 thumbprint, over the required members in lexicographic order
+        let thumbprint_input = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let kid = encode_segment(&sha256(thumbprint_input.as_bytes()));
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": x,
+            "y": y,
+            "kid": kid,
+            "use": "sig",
+            "alg": JWT_ALGORITHM,
+        });
+        Ok(Self { key, jwk, kid })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": [self.jwk] })
+    }
+
+    /// Sign the claims as a compact JWS. `iat` and `exp` are filled in when absent.
+    pub fn sign(&self, claims: Value) -> Result<String, JwtError> {
+        let Value::Object(mut claims) = claims else {
+            return Err(JwtError::InvalidClaims);
+        };
+        let issued_at = now();
+        claims.entry("iat").or_insert_with(|| json!(issued_at));
+        claims
+            .entry("exp")
+            .or_insert_with(|| json!(issued_at + DEFAULT_TOKEN_TTL_SECS));
+
+        let header = json!({ "alg": JWT_ALGORITHM, "typ": "JWT", "kid": self.kid });
+        let signing_input = format!(
+            "{}.{}",
+            encode_segment(header.to_string().as_bytes()),
+            encode_segment(Value::Object(claims).to_string().as_bytes())
+        );
+
+        // JWS uses the fixed width r || s encoding rather than DER
+        let signature = EcdsaSig::sign(&sha256(signing_input.as_bytes()), &self.key)?;
+        let mut raw_signature = signature.r().to_vec_padded(COORDINATE_LEN as i32)?;
+        raw_signature.extend(signature.s().to_vec_padded(COORDINATE_LEN as i32)?);
+        Ok(format!(
+            "{signing_input}.{}",
+            encode_segment(&raw_signature)
+        ))
+    }
+
+    /// Verify a token signed by this enclave, returning its claims.
+    pub fn verify(&self, token: &str) -> Result<Map<String, Value>, JwtError> {
+        let mut segments = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) = (
+            segments.next(),
+            segments.next(),
+            segments.next(),
+            segments.next(),
+        ) else {
+            return Err(JwtError::Malformed(
+                "expected three dot separated segments".to_string(),
+            ));
+        };
+
+        let parsed_header = decode_json_segment(header)?;
+        if parsed_header.get("alg").and_then(Value::as_str) != Some(JWT_ALGORITHM) {
+            return Err(JwtError::Malformed("unsupported algorithm".to_string()));
+        }
+        if parsed_header.get("kid").and_then(Value::as_str) != Some(self.kid.as_str()) {
+            return Err(JwtError::UnknownKey);
+        }
+
+        let raw_signature = decode_segment(signature)?;
+        if raw_signature.len() != 2 * COORDINATE_LEN {
+            return Err(JwtError::InvalidSignature);
+        }
+        let (r, s) = raw_signature.split_at(COORDINATE_LEN);
+        let signature =
+            EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+        let signing_input = &token[..header.len() + 1 + claims.len()];
+        if !signature.verify(&sha256(signing_input.as_bytes()), &self.key)? {
+            return Err(JwtError::InvalidSignature);
+        }
+
+        let claims = decode_json_segment(claims)?;
+        let now = now() as f64;
+        let leeway = CLOCK_SKEW_LEEWAY_SECS as f64;
+        if let Some(exp) = numeric_date_claim(&claims, "exp")? {
+            if now > exp + leeway {
+                return Err(JwtError::Expired);
+            }
+        }
+        if let Some(nbf) = numeric_date_claim(&claims, "nbf")? {
+            if now + leeway < nbf {
+                return Err(JwtError::NotYetValid);
+            }
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_segment, JwtError, JwtSigner};
+    use openssl::bn::BigNum;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::sha::sha256;
+    use serde_json::json;
+
+    #[test]
+    fn test_signed_tokens_verify_against_the_jwks() {
+        let signer = JwtSigner::generate().unwrap();
+        let token = signer
+            .sign(json!({ "sub": "service-a", "aud": "service-b" }))
+            .unwrap();
+        let claims = signer.verify(&token).unwrap();
+        assert_eq!(claims["sub"], "service-a");
+        assert!(claims["exp"].as_i64().unwrap() > claims["iat"].as_i64().unwrap());
+
+        // Verify independently, using only the published key
+        let jwk = &signer.jwks()["keys"][0];
+        assert_eq!(jwk["kid"], signer.kid());
+        let decode = |member: &str| {
+            let bytes =
+                base64::decode_config(jwk[member].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                    .unwrap();
+            BigNum::from_slice(&bytes).unwrap()
+        };
+        let public_key = openssl::ec::EcKey::from_public_key_affine_coordinates(
+            signer.key.group(),
+            &decode("x"),
+            &decode("y"),
+        )
+        .unwrap();
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        assert!(signature
+            .verify(&sha256(signing_input.as_bytes()), &public_key)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_invalid_tokens_are_rejected() {
+        let signer = JwtSigner::generate().unwrap();
+        assert!(matches!(
+            signer.sign(json!(["not", "an", "object"])),
+            Err(JwtError::InvalidClaims)
+        ));
+
+        let expired = signer.sign(json!({ "exp": 1_000 })).unwrap();
+        assert!(matches!(signer.verify(&expired), Err(JwtError::Expired)));
+        let fractional_expiry = signer.sign(json!({ "exp": 1_000.5 })).unwrap();
+        assert!(matches!(
+            signer.verify(&fractional_expiry),
+            Err(JwtError::Expired)
+        ));
+        let quoted_expiry = signer.sign(json!({ "exp": "1000" })).unwrap();
+        assert!(matches!(
+            signer.verify(&quoted_expiry),
+            Err(JwtError::Malformed(_))
+        ));
+
+        let not_yet_valid = signer.sign(json!({ "nbf": super::now() + 3_600 })).unwrap();
+        assert!(matches!(
+            signer.verify(&not_yet_valid),
+            Err(JwtError::NotYetValid)
+        ));
+
+        let token = signer.sign(json!({ "sub": "service-a" })).unwrap();
+        let (header, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let tampered = format!(
+            "{header}.{}.{signature}",
+            encode_segment(json!({ "sub": "admin" }).to_string().as_bytes())
+        );
+        assert!(matches!(
+            signer.verify(&tampered),
+            Err(JwtError::InvalidSignature)
+        ));
+
+        let other_signer = JwtSigner::generate().unwrap();
+        assert!(matches!(
+            other_signer.verify(&token),
+            Err(JwtError::UnknownKey)
+        ));
+        assert!(matches!(
+            signer.verify("not-a-token"),
+            Err(JwtError::Malformed(_))
+        ));
+    }
+}
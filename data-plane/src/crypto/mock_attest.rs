@@ -0,0 +1,174 @@
+//! Stand in attestation docs for running outside a Nitro Enclave. They have the same COSE_Sign1 and CBOR shape as docs
+//! from the NSM, with zeroed PCRs like a debug mode enclave, but chain up to a self-signed root generated on first use
+//! rather than the AWS root.
+use aws_nitro_enclaves_cose as cose;
+use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest};
+use once_cell::sync::OnceCell;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, KeyUsage};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+const MOCK_MODULE_ID: &str = "mock-enclave";
+/// The NSM measures PCRs 0 to 15 with SHA-384.
+const PCR_COUNT: usize = 16;
+const PCR_LENGTH: usize = 48;
+/// Nitro signing certs are only valid for a few hours, so mock ones are too.
+const SIGNING_CERT_LIFETIME_SECS: i64 = 3 * 60 * 60;
+const ROOT_CERT_LIFETIME_DAYS: u32 = 365;
+
+static MOCK_ROOT: OnceCell<(X509, PKey<Private>)> = OnceCell::new();
+
+/// The self-signed root mock attestation docs chain up to, for verifying them locally.
+pub fn mock_root_cert() -> Result<X509, Error> {
+    Ok(mock_root()?.0.clone())
+}
+
+fn mock_root() -> Result<&'static (X509, PKey<Private>), Error> {
+    MOCK_ROOT
+        .get_or_try_init(|| {
+            let key = generate_key()?;
+            let not_after = Asn1Time::days_from_now(ROOT_CERT_LIFETIME_DAYS)?;
+            let cert = build_cert("mock.aws.nitro-enclaves", &key, None, &not_after)?;
+            Ok((cert, key))
+        })
+        .map_err(|e: ErrorStack| Error::Crypto(e.to_string()))
+}
+
+/// Sign a mock attestation doc with a fresh signing cert, embedding the challenge as user data like the NSM does.
+pub fn get_attestation_doc(
+    challenge: Option<Vec<u8>>,
+    nonce: Option<Vec<u8>>,
+) -> Result<Vec<u8>, Error> {
+    let (root_cert, root_key) = mock_root()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+
+    let (signing_key, signing_cert) = build_signing_cert(root_cert, root_key, now.as_secs())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    let root_cert = root_cert
+        .to_der()
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+
+    let pcrs = (0..PCR_COUNT)
+        .map(|index| (index, vec![0; PCR_LENGTH]))
+        .collect();
+    let doc = AttestationDoc::new(
+        MOCK_MODULE_ID.to_string(),
+        Digest::SHA384,
+        now.as_millis() as u64,
+        pcrs,
+        signing_cert,
+        vec![root_cert],
+        challenge,
+        nonce,
+        None,
+    );
+    cose::CoseSign1::new::<cose::crypto::Openssl>(
+        &doc.to_binary(),
+        &cose::header_map::HeaderMap::new(),
+        &signing_key,
+    )
+    .and_then(|cose_sign_1| cose_sign_1.as_bytes(false))
+    .map_err(|e| Error::Crypto(e.to_string()))
+}
+
+/// The PCR values measured into a mock attestation doc, by index.
+pub fn get_pcrs(cose_sign_1_bytes: &[u8]) -> Result<BTreeMap<usize, Vec<u8>>, Error> {
+    let (_, attestation_doc) = shared::attestation::parse_attestation_doc(cose_sign_1_bytes)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok(attestation_doc
+        .pcrs
+        .into_iter()
+        .map(|(index, value)| (index, value.into_vec()))
+        .collect())
+}
+
+fn build_signing_cert(
+    root_cert: &X509,
+    root_key: &PKey<Private>,
+    now_secs: u64,
+) -> Result<(PKey<Private>, Vec<u8>), ErrorStack> {
+    let key = generate_key()?;
+    let not_after = Asn1Time::from_unix(now_secs as i64 + SIGNING_CERT_LIFETIME_SECS)?;
+    let cert = build_cert(
+        MOCK_MODULE_ID,
+        &key,
+        Some((root_cert, root_key)),
+        &not_after,
+    )?;
+    Ok((key, cert.to_der()?))
+}
+
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+fn build_cert(
+    common_name: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    not_after: &Asn1Time,
+) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("O", "Evervault")?;
+    name.append_entry_by_text("CN", common_name)?;
+    let name = name.build();
+
+    let mut cert = X509Builder::new()?;
+    cert.set_version(2)?;
+    let serial_number = {
+        let mut serial = BigNum::new()?;
+        serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+        serial.to_asn1_integer()?
+    };
+    cert.set_serial_number(&serial_number)?;
+    cert.set_subject_name(&name)?;
+    cert.set_pubkey(key)?;
+    cert.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    cert.set_not_after(not_after)?;
+    match issuer {
+        Some((issuer_cert, issuer_key)) => {
+            cert.set_issuer_name(issuer_cert.subject_name())?;
+            cert.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+            cert.sign(issuer_key, MessageDigest::sha384())?;
+        }
+        None => {
+            cert.set_issuer_name(&name)?;
+            cert.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+            cert.append_extension(KeyUsage::new().critical().key_cert_sign().build()?)?;
+            cert.sign(key, MessageDigest::sha384())?;
+        }
+    }
+    Ok(cert.build())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{get_attestation_doc, get_pcrs, mock_root_cert, PCR_COUNT};
+    use shared::attestation::AttestationVerifier;
+
+    #[test]
+    fn test_mock_docs_verify_against_the_mock_root() {
+        let doc =
+            get_attestation_doc(Some(b"challenge".to_vec()), Some(b"nonce".to_vec())).unwrap();
+        let verified = AttestationVerifier::new(mock_root_cert().unwrap())
+            .with_expected_pcr(0, vec![0; 48])
+            .verify(&doc)
+            .unwrap();
+        assert_eq!(verified.user_data.unwrap().as_slice(), b"challenge");
+        assert_eq!(verified.nonce.unwrap().as_slice(), b"nonce");
+        assert_eq!(get_pcrs(&doc).unwrap().len(), PCR_COUNT);
+    }
+}
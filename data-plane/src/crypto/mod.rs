@@ -1,11 +1,23 @@
 pub mod api;
+pub mod asymmetric;
 #[cfg(feature = "enclave")]
 pub mod attest;
+pub mod blob;
 #[cfg(feature = "enclave")]
 pub mod common;
+pub mod fields;
+#[cfg(feature = "grpc_crypto_api")]
+pub mod grpc;
+pub mod jwt;
+#[cfg(not(feature = "enclave"))]
+pub mod mock_attest;
+pub mod ndjson;
 #[cfg(feature = "tls_termination")]
 pub mod parser;
+pub mod quota;
 pub mod rand;
+pub mod rate_limit;
+pub mod routes;
 #[cfg(feature = "tls_termination")]
 pub mod stream;
 pub mod token;
@@ -0,0 +1,166 @@
+//! Newline delimited JSON framing for the Crypto API's streaming endpoints, so bulk payloads are processed record
+//! by record rather than buffered in full. Raw payloads are split into fixed size frames, each encrypted as its own
+//! record.
+use std::fmt::Display;
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, Stream, StreamExt, TryStreamExt};
+use hyper::header::{HeaderMap, ACCEPT, CONTENT_TYPE};
+use hyper::Body;
+use serde_json::json;
+use shared::error_code::HasErrorCode;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tokio_util::io::StreamReader;
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+pub const RAW_CONTENT_TYPE: &str = "application/octet-stream";
+const MAX_RECORD_LENGTH: usize = 1024 * 1024;
+/// Size of the frames raw payloads are split into. Base64 encoded and encrypted, each frame stays well under the
+/// maximum record length, so encrypted frames can be streamed back for decryption.
+pub const FRAME_SIZE: usize = 64 * 1024;
+
+/// Split a request body into its records, skipping blank lines.
+pub fn records(body: Body) -> impl Stream<Item = Result<String, LinesCodecError>> {
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_RECORD_LENGTH))
+        .try_filter(|record| future::ready(!record.trim().is_empty()))
+}
+
+/// Split a raw request body into frames of [`FRAME_SIZE`], with only the last frame shorter.
+pub fn frames(body: Body) -> impl Stream<Item = Result<Bytes, hyper::Error>> + Send {
+    futures::stream::unfold(Some((body, BytesMut::new())), |state| async move {
+        let (mut body, mut pending) = state?;
+        while pending.len() < FRAME_SIZE {
+            match body.next().await {
+                Some(Ok(data)) => pending.extend_from_slice(&data),
+                Some(Err(e)) => return Some((Err(e), None)),
+                None if pending.is_empty() => return None,
+                None => return Some((Ok(pending.freeze()), None)),
+            }
+        }
+        let frame = pending.split_to(FRAME_SIZE).freeze();
+        Some((Ok(frame), Some((body, pending))))
+    })
+}
+
+/// Whether the request body is a raw payload, rather than records.
+pub fn is_raw_request(headers: &HeaderMap) -> bool {
+    is_raw(headers.get(CONTENT_TYPE).into_iter())
+}
+
+/// Whether the client wants a raw payload back, rather than records.
+pub fn accepts_raw(headers: &HeaderMap) -> bool {
+    is_raw(headers.get_all(ACCEPT).iter())
+}
+
+fn is_raw<'a>(values: impl Iterator<Item = &'a hyper::header::HeaderValue>) -> bool {
+    values
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_type| media_type.split(';').next())
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(RAW_CONTENT_TYPE))
+}
+
+/// Frame a processed record as a response line. Failed records are reported in place, so results stay aligned
+/// with the records sent.
+pub fn to_line<E: Display>(result: Result<Vec<u8>, E>) -> Bytes {
+    let mut line = match result {
+        Ok(record) => record,
+        Err(e) => json!({ "error": e.to_string() }).to_string().into_bytes(),
+    };
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+/// Frame a processed record as a response line, reporting failed records in place with a structured error body
+/// carrying the error code, so callers can tell failures apart without parsing messages.
+pub fn to_structured_line<E: HasErrorCode>(result: Result<Vec<u8>, E>) -> Bytes {
+    let mut line = match result {
+        Ok(record) => record,
+        Err(e) => json!({ "error": e.error_body() }).to_string().into_bytes(),
+    };
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        accepts_raw, frames, is_raw_request, records, to_line, to_structured_line, FRAME_SIZE,
+    };
+    use crate::crypto::api::CryptoApiError;
+    use futures::StreamExt;
+    use hyper::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+    use hyper::Body;
+
+    #[tokio::test]
+    async fn test_records_are_split_across_chunks() {
+        let chunks: Vec<Result<&'static str, std::io::Error>> = vec![
+            Ok("{\"name\":\"one\"}\n{\"na"),
+            Ok("me\":\"two\"}\n\n"),
+            Ok("{\"name\":\"three\"}"),
+        ];
+        let records: Vec<String> = records(Body::wrap_stream(futures::stream::iter(chunks)))
+            .map(|record| record.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            records,
+            vec![
+                r#"{"name":"one"}"#,
+                r#"{"name":"two"}"#,
+                r#"{"name":"three"}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_records_are_reported_in_place() {
+        assert_eq!(
+            to_line::<String>(Ok(br#""ev:abc""#.to_vec())),
+            &b"\"ev:abc\"\n"[..]
+        );
+        assert_eq!(
+            to_line::<String>(Err("Could not deserialize your payload".to_string())),
+            &b"{\"error\":\"Could not deserialize your payload\"}\n"[..]
+        );
+        assert_eq!(
+            to_structured_line(Err(CryptoApiError::SerializationError)),
+            &b"{\"error\":{\"category\":\"request\",\"code\":\"request.invalid_payload\",\"message\":\"Could not deserialize your payload\"}}\n"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_bodies_are_split_into_frames() {
+        let payload: Vec<u8> = (0..FRAME_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = payload
+            .chunks(1000)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect();
+        let split: Vec<_> = frames(Body::wrap_stream(futures::stream::iter(chunks)))
+            .map(|frame| frame.unwrap())
+            .collect()
+            .await;
+        let lengths: Vec<_> = split.iter().map(|frame| frame.len()).collect();
+        assert_eq!(lengths, vec![FRAME_SIZE, FRAME_SIZE, 10]);
+        assert_eq!(split.concat(), payload);
+
+        assert_eq!(frames(Body::empty()).count().await, 0);
+    }
+
+    #[test]
+    fn test_raw_payloads_are_negotiated_by_media_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_raw_request(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("Application/Octet-Stream; charset=binary"),
+        );
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/json, application/octet-stream"),
+        );
+        assert!(is_raw_request(&headers));
+        assert!(accepts_raw(&headers));
+    }
+}
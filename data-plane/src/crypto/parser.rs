@@ -5,6 +5,8 @@ use nom::{
     sequence::{delimited, pair, terminated, tuple},
     IResult,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::fmt::Formatter;
 
 // Type is generated by parse_ciphertexts, which is currently unused
@@ -26,6 +28,71 @@ pub fn parse_ciphertexts(input: &[u8]) -> IResult<&[u8], Option<Ciphertext>> {
     opt(context_aware_ciphertext)(input)
 }
 
+/// Parse a whole string as a single ciphertext, without surrounding quotes.
+pub fn parse_ciphertext(input: &str) -> Option<Ciphertext> {
+    match ciphertext(input.as_bytes()) {
+        Ok(([], parsed)) => Some(parsed),
+        _ => None,
+    }
+}
+
+/// Mirror a payload, replacing each ciphertext with its metadata. Object fields without ciphertexts are left out,
+/// and array elements which aren't ciphertexts are null so indexes still line up with the payload.
+pub fn ciphertext_metadata(value: &Value) -> Value {
+    match value {
+        Value::String(string) => parse_ciphertext(string)
+            .and_then(|parsed| serde_json::to_value(parsed.metadata()).ok())
+            .unwrap_or(Value::Null),
+        Value::Array(elements) => Value::Array(elements.iter().map(ciphertext_metadata).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, field)| (key.clone(), ciphertext_metadata(field)))
+                .filter(|(_, metadata)| contains_metadata(metadata))
+                .collect::<Map<String, Value>>(),
+        ),
+        _ => Value::Null,
+    }
+}
+
+/// The distinct key versions of the ciphertexts in a payload.
+pub fn key_versions(value: &Value) -> Vec<String> {
+    let mut key_versions = Vec::new();
+    collect_key_versions(value, &mut key_versions);
+    key_versions
+}
+
+fn collect_key_versions(value: &Value, key_versions: &mut Vec<String>) {
+    match value {
+        Value::String(string) => {
+            let key_version = parse_ciphertext(string)
+                .and_then(|parsed| parsed.version)
+                .map(|version| version.to_string());
+            if let Some(key_version) = key_version {
+                if !key_versions.contains(&key_version) {
+                    key_versions.push(key_version);
+                }
+            }
+        }
+        Value::Array(elements) => elements
+            .iter()
+            .for_each(|element| collect_key_versions(element, key_versions)),
+        Value::Object(fields) => fields
+            .values()
+            .for_each(|field| collect_key_versions(field, key_versions)),
+        _ => {}
+    }
+}
+
+fn contains_metadata(metadata: &Value) -> bool {
+    match metadata {
+        Value::Null => false,
+        Value::Array(elements) => elements.iter().any(contains_metadata),
+        Value::Object(fields) => !fields.is_empty(),
+        _ => true,
+    }
+}
+
 fn ciphertext(input: &[u8]) -> IResult<&[u8], Ciphertext> {
     map(
         delimited(
@@ -62,6 +129,17 @@ fn context_aware_ciphertext(input: &[u8]) -> IResult<&[u8], Ciphertext> {
     )(input)
 }
 
+/// What a ciphertext's header says about how it was encrypted. Ciphertexts don't record when they were encrypted, so
+/// there's no timestamp.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CiphertextMetadata {
+    /// The version tag of the key and scheme used, absent for the oldest ciphertexts
+    pub key_version: Option<String>,
+    pub data_type: String,
+    pub debug: bool,
+}
+
 #[derive(Debug)]
 pub struct Ciphertext {
     debug: bool,
@@ -96,6 +174,19 @@ impl Ciphertext {
         }
     }
 
+    pub fn metadata(&self) -> CiphertextMetadata {
+        CiphertextMetadata {
+            key_version: self.version.as_ref().map(|version| version.to_string()),
+            data_type: self
+                .datatype
+                .as_ref()
+                .unwrap_or(&Datatype::String)
+                .name()
+                .to_string(),
+            debug: self.debug,
+        }
+    }
+
     pub fn set_leading_quote(&mut self, has_leading_quote: bool) {
         self.has_leading_quote = has_leading_quote;
     }
@@ -294,6 +385,15 @@ impl Datatype {
     fn is_string(&self) -> bool {
         matches!(self, Self::String)
     }
+
+    // Untyped ciphertexts are strings, so Display leaves the string type out of the ciphertext
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Boolean => "boolean",
+            Self::Number => "number",
+            Self::String => "string",
+        }
+    }
 }
 
 impl std::convert::TryFrom<&[u8]> for Datatype {
@@ -426,6 +526,34 @@ mod test {
         build_ciphertext_test!(s0ls, Datatype::Number, true);
     }
 
+    #[test]
+    fn test_ciphertext_metadata_mirrors_the_payload() {
+        let number_ciphertext = String::from_utf8(build_ciphertext(
+            Some(&CiphertextVersion::Qljv),
+            Some(&Datatype::Number),
+            false,
+        ))
+        .unwrap();
+        let string_ciphertext = String::from_utf8(build_ciphertext(None, None, true)).unwrap();
+        let payload = serde_json::json!({
+            "age": number_ciphertext,
+            "name": "plaintext",
+            "cards": ["plaintext", string_ciphertext],
+            "address": { "city": "Dublin" },
+        });
+
+        assert_eq!(
+            ciphertext_metadata(&payload),
+            serde_json::json!({
+                "age": { "keyVersion": "QlJV", "dataType": "number", "debug": false },
+                "cards": [null, { "keyVersion": null, "dataType": "string", "debug": true }],
+            })
+        );
+        assert_eq!(ciphertext_metadata(&Value::from("ev:abc")), Value::Null);
+        assert_eq!(key_versions(&payload), vec!["QlJV".to_string()]);
+        assert!(parse_ciphertext(&format!("\"{number_ciphertext}\"")).is_none());
+    }
+
     #[test]
     fn test_ciphertext_serialization() {
         let ciphertext_bytes = build_ciphertext(None, None, false);
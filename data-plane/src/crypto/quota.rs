@@ -0,0 +1,176 @@
+use cached::{Cached, TimedSizedCache};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::cache::hash_api_key;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Opt-in per api key quotas in the Crypto API, only initialized when configured.
+pub static CRYPTO_API_QUOTAS: OnceCell<QuotaTracker> = OnceCell::new();
+
+fn default_max_tracked_keys() -> usize {
+    10_000
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct QuotaConfig {
+    pub requests_per_minute: Option<u64>,
+    pub bytes_per_day: Option<u64>,
+    /// Upper bound on the number of api keys tracked at once
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_tracked_keys: usize,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QuotaError {
+    #[error("Request quota of {0} requests per minute exceeded")]
+    RequestsPerMinute(u64),
+    #[error("Data quota of {0} bytes per day exceeded")]
+    BytesPerDay(u64),
+}
+
+struct KeyUsage {
+    minute_started_at: Instant,
+    requests_this_minute: u64,
+    day_started_at: Instant,
+    bytes_today: u64,
+}
+
+impl KeyUsage {
+    fn new(now: Instant) -> Self {
+        Self {
+            minute_started_at: now,
+            requests_this_minute: 0,
+            day_started_at: now,
+            bytes_today: 0,
+        }
+    }
+
+    fn reset_elapsed_windows(&mut self, now: Instant) {
+        if now.duration_since(self.minute_started_at) >= MINUTE {
+            self.minute_started_at = now;
+            self.requests_this_minute = 0;
+        }
+        if now.duration_since(self.day_started_at) >= DAY {
+            self.day_started_at = now;
+            self.bytes_today = 0;
+        }
+    }
+}
+
+/// Tracks Crypto API usage per api key in fixed minute and day windows, so that each key's use of E3 can be
+/// limited independently. Requests without an api key share a single quota.
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    usage: Mutex<TimedSizedCache<[u8; 32], KeyUsage>>,
+}
+
+impl QuotaTracker {
+    pub fn new(config: &QuotaConfig) -> Self {
+        Self {
+            config: config.clone(),
+            usage: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                config.max_tracked_keys,
+                DAY.as_secs(),
+            )),
+        }
+    }
+
+    /// Record a request of the given size against the key's quota, rejecting it if the quota is exhausted.
+    /// Rejected requests aren't counted against the quota.
+    pub async fn check_and_record(
+        &self,
+        api_key: Option<&[u8]>,
+        request_bytes: u64,
+    ) -> Result<(), QuotaError> {
+        self.check_and_record_at(api_key, request_bytes, Instant::now())
+            .await
+    }
+
+    async fn check_and_record_at(
+        &self,
+        api_key: Option<&[u8]>,
+        request_bytes: u64,
+        now: Instant,
+    ) -> Result<(), QuotaError> {
+        let key_hash = hash_api_key(api_key.unwrap_or_default());
+        let mut usage_cache = self.usage.lock().await;
+        let usage = usage_cache.cache_get_or_set_with(key_hash, || KeyUsage::new(now));
+        usage.reset_elapsed_windows(now);
+
+        if let Some(limit) = self.config.requests_per_minute {
+            if usage.requests_this_minute >= limit {
+                return Err(QuotaError::RequestsPerMinute(limit));
+            }
+        }
+        if let Some(limit) = self.config.bytes_per_day {
+            if usage.bytes_today.saturating_add(request_bytes) > limit {
+                return Err(QuotaError::BytesPerDay(limit));
+            }
+        }
+
+        usage.requests_this_minute += 1;
+        usage.bytes_today = usage.bytes_today.saturating_add(request_bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{QuotaConfig, QuotaError, QuotaTracker, DAY, MINUTE};
+    use std::time::Instant;
+
+    fn tracker(requests_per_minute: Option<u64>, bytes_per_day: Option<u64>) -> QuotaTracker {
+        QuotaTracker::new(&QuotaConfig {
+            requests_per_minute,
+            bytes_per_day,
+            max_tracked_keys: 10,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_requests_per_minute_are_limited_per_key() {
+        let quotas = tracker(Some(2), None);
+        let now = Instant::now();
+        let key = Some(b"key-one".as_slice());
+        assert!(quotas.check_and_record_at(key, 10, now).await.is_ok());
+        assert!(quotas.check_and_record_at(key, 10, now).await.is_ok());
+        assert_eq!(
+            quotas.check_and_record_at(key, 10, now).await,
+            Err(QuotaError::RequestsPerMinute(2))
+        );
+        // Other keys have their own quota
+        assert!(quotas
+            .check_and_record_at(Some(b"key-two"), 10, now)
+            .await
+            .is_ok());
+        // The quota resets once the minute has passed
+        assert!(quotas
+            .check_and_record_at(key, 10, now + MINUTE)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bytes_per_day_are_limited() {
+        let quotas = tracker(None, Some(100));
+        let now = Instant::now();
+        assert!(quotas.check_and_record_at(None, 60, now).await.is_ok());
+        assert_eq!(
+            quotas.check_and_record_at(None, 60, now).await,
+            Err(QuotaError::BytesPerDay(100))
+        );
+        // Rejected requests don't use up the quota
+        assert!(quotas.check_and_record_at(None, 40, now).await.is_ok());
+        assert!(quotas.check_and_record_at(None, 1, now).await.is_err());
+        assert!(quotas
+            .check_and_record_at(None, 60, now + DAY)
+            .await
+            .is_ok());
+    }
+}
@@ -5,21 +5,46 @@ pub fn rand_bytes(buffer: &mut [u8]) -> Result<()> {
     use crate::utils::nsm::NsmConnection;
     use aws_nitro_enclaves_nsm_api as nitro;
     let nsm_conn = NsmConnection::try_new()?;
-    match nitro::driver::nsm_process_request(nsm_conn.fd(), nitro::api::Request::GetRandom) {
-        nitro::api::Response::GetRandom { random } => {
-            buffer.copy_from_slice(&random);
-            Ok(())
+    // The NSM returns a fixed amount of entropy per request, so larger buffers are filled over several
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match nitro::driver::nsm_process_request(nsm_conn.fd(), nitro::api::Request::GetRandom) {
+            nitro::api::Response::GetRandom { random } if !random.is_empty() => {
+                let len = random.len().min(buffer.len() - filled);
+                buffer[filled..filled + len].copy_from_slice(&random[..len]);
+                filled += len;
+            }
+            nitro::api::Response::Error(e) => {
+                return Err(Error::Crypto(format!(
+                    "Could not get entropy from the Nitro Secure Module! {e:?}"
+                )))
+            }
+            _ => {
+                return Err(Error::Crypto(
+                    "Received unknown response from Nitro Secure Module".to_string(),
+                ))
+            }
         }
-        nitro::api::Response::Error(e) => Err(Error::Crypto(format!(
-            "Could not get entropy from the Nitro Secure Module! {e:?}"
-        ))),
-        _ => Err(Error::Crypto(
-            "Received unknown response from Nitro Secure Module".to_string(),
-        )),
     }
+    Ok(())
 }
 
 #[cfg(not(feature = "enclave"))]
 pub fn rand_bytes(buffer: &mut [u8]) -> Result<()> {
     openssl::rand::rand_bytes(buffer).map_err(|e| Error::Crypto(e.to_string()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::rand_bytes;
+
+    #[test]
+    fn test_buffers_are_filled() {
+        let mut first = [0u8; 1000];
+        let mut second = [0u8; 1000];
+        rand_bytes(&mut first).unwrap();
+        rand_bytes(&mut second).unwrap();
+        assert_ne!(first, second);
+        assert!(first.iter().any(|byte| *byte != 0));
+    }
+}
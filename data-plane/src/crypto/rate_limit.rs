@@ -0,0 +1,111 @@
+use cached::{Cached, TimedSizedCache};
+use once_cell::sync::OnceCell;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::cache::hash_api_key;
+
+/// Buckets which haven't been used for this long are full again, so they're dropped rather than tracked.
+const IDLE_BUCKET_LIFETIME: Duration = Duration::from_secs(300);
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Opt-in per api key rate limiting in the Crypto API, only initialized when configured.
+pub static CRYPTO_API_RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub requests_per_second: u64,
+    pub burst: u64,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Rate limit of {limit} requests per second exceeded, retry after {}ms", retry_after.as_millis())]
+pub struct RateLimitError {
+    pub limit: u64,
+    pub retry_after: Duration,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket rate limiter for Crypto API requests, with a bucket per api key. Unlike quotas, which allow a
+/// minute's requests at once, this smooths out bursts from a runaway client before they reach E3. Requests without
+/// an api key share a single bucket.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<TimedSizedCache<[u8; 32], TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                MAX_TRACKED_KEYS,
+                IDLE_BUCKET_LIFETIME.as_secs(),
+            )),
+        }
+    }
+
+    /// Take a token from the key's bucket, rejecting the request if the bucket is empty.
+    pub async fn check(&self, api_key: Option<&[u8]>) -> Result<(), RateLimitError> {
+        self.check_at(api_key, Instant::now()).await
+    }
+
+    async fn check_at(&self, api_key: Option<&[u8]>, now: Instant) -> Result<(), RateLimitError> {
+        let capacity = self.config.burst.max(1) as f64;
+        let rate = self.config.requests_per_second as f64;
+        let key_hash = hash_api_key(api_key.unwrap_or_default());
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.cache_get_or_set_with(key_hash, || TokenBucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(RateLimitError {
+                limit: self.config.requests_per_second,
+                retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+            });
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RateLimitConfig, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_bursts_are_limited_per_key_and_refill_over_time() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_second: 10,
+            burst: 2,
+        });
+        let now = Instant::now();
+        let key = Some(b"key-one".as_slice());
+        assert!(limiter.check_at(key, now).await.is_ok());
+        assert!(limiter.check_at(key, now).await.is_ok());
+        let error = limiter.check_at(key, now).await.unwrap_err();
+        assert_eq!(error.retry_after, Duration::from_millis(100));
+        // Other keys have their own bucket
+        assert!(limiter.check_at(Some(b"key-two"), now).await.is_ok());
+        // A token is added every 100ms, up to the burst size
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.check_at(key, later).await.is_ok());
+        assert!(limiter.check_at(key, later).await.is_err());
+        let much_later = now + Duration::from_secs(10);
+        assert!(limiter.check_at(key, much_later).await.is_ok());
+        assert!(limiter.check_at(key, much_later).await.is_ok());
+        assert!(limiter.check_at(key, much_later).await.is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Versioned routing for the Crypto API. Every route is served under a version prefix, e.g. `/v1/encrypt`, and the
+//! original unversioned paths remain as aliases. Breaking changes ship as a new version with its own route table, so
+//! existing callers keep the behaviour they were built against.
+//!
+//! v2 has the same routes as v1, but returns errors as structured bodies with an error code.
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Method};
+use serde::Serialize;
+use thiserror::Error;
+
+/// Lets callers of the unversioned paths pick a version, and tells every caller which version served them.
+pub const API_VERSION_HEADER: &str = "x-evervault-crypto-api-version";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    pub const SUPPORTED: &'static [Self] = &[Self::V1, Self::V2];
+    pub const LATEST: Self = Self::V2;
+    /// The version served on the unversioned paths when the caller doesn't ask for one. This stays at v1 so callers
+    /// which predate versioning are never moved to a breaking version.
+    pub const DEFAULT: Self = Self::V1;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+        }
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+
+    /// The route serving a path, with any version prefix removed.
+    pub fn route(&self, method: &Method, path: &str) -> Option<Route> {
+        match self {
+            Self::V1 | Self::V2 => v1_route(method, path),
+        }
+    }
+}
+
+impl std::str::FromStr for ApiVersion {
+    type Err = RouteError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        Self::SUPPORTED
+            .iter()
+            .find(|supported| supported.as_str().eq_ignore_ascii_case(version.trim()))
+            .copied()
+            .ok_or_else(|| RouteError::UnsupportedVersion(version.to_string()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    Versions,
+    Encrypt,
+    EncryptAsymmetric,
+    Decrypt,
+    EncryptStream,
+    DecryptStream,
+    EncryptBatch,
+    DecryptBatch,
+    #[cfg(feature = "network_egress")]
+    EncryptBlob,
+    DecryptBlob,
+    AttestationDoc,
+    AttestationPcrs,
+    Random,
+    Sign,
+    Verify,
+    SignToken,
+    VerifyToken,
+    Jwks,
+}
+
+fn v1_route(method: &Method, path: &str) -> Option<Route> {
+    let route = match (method, path) {
+        (&Method::POST, "/encrypt") => Route::Encrypt,
+        (&Method::POST, "/encrypt/asymmetric") => Route::EncryptAsymmetric,
+        (&Method::POST, "/decrypt") => Route::Decrypt,
+        (&Method::POST, "/encrypt/stream") => Route::EncryptStream,
+        (&Method::POST, "/decrypt/stream") => Route::DecryptStream,
+        (&Method::POST, "/encrypt/batch") => Route::EncryptBatch,
+        (&Method::POST, "/decrypt/batch") => Route::DecryptBatch,
+        #[cfg(feature = "network_egress")]
+        (&Method::POST, "/blob/encrypt") => Route::EncryptBlob,
+        (&Method::POST, "/blob/decrypt") => Route::DecryptBlob,
+        (&Method::POST, "/attestation-doc") => Route::AttestationDoc,
+        (&Method::GET, "/attestation/pcrs") => Route::AttestationPcrs,
+        (&Method::GET, "/random") => Route::Random,
+        (&Method::POST, "/sign") => Route::Sign,
+        (&Method::POST, "/verify") => Route::Verify,
+        (&Method::POST, "/token/sign") => Route::SignToken,
+        (&Method::POST, "/token/verify") => Route::VerifyToken,
+        (&Method::GET, "/token/jwks") => Route::Jwks,
+        (&Method::GET, "/versions") => Route::Versions,
+        _ => return None,
+    };
+    Some(route)
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RouteError {
+    #[error(
+        "Unsupported Crypto API version {0}, supported versions are {}",
+        supported_versions()
+    )]
+    UnsupportedVersion(String),
+}
+
+fn supported_versions() -> String {
+    ApiVersion::SUPPORTED
+        .iter()
+        .map(ApiVersion::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Pick the version which should serve a request, and the path to route within it. A version prefix in the path takes
+/// precedence, then the version header, then the default version for unversioned callers.
+pub fn negotiate<'a>(
+    path: &'a str,
+    headers: &HeaderMap,
+) -> Result<(ApiVersion, &'a str), RouteError> {
+    let (version, path) = match split_version_prefix(path) {
+        Some((version, path)) => (version.parse()?, path),
+        None => match headers.get(API_VERSION_HEADER) {
+            Some(version) => (
+                version
+                    .to_str()
+                    .map_err(|_| RouteError::UnsupportedVersion("<invalid>".to_string()))?
+                    .parse()?,
+                path,
+            ),
+            None => (ApiVersion::DEFAULT, path),
+        },
+    };
+    Ok((version, path))
+}
+
+/// Split `/v<N>/rest` into `v<N>` and `/rest`.
+fn split_version_prefix(path: &str) -> Option<(&str, &str)> {
+    let (version, _) = path.strip_prefix('/')?.split_once('/')?;
+    let is_version = version.len() > 1
+        && version.starts_with(['v', 'V'])
+        && version[1..].bytes().all(|byte| byte.is_ascii_digit());
+    is_version.then_some((version, &path[version.len() + 1..]))
+}
+
+#[derive(Serialize)]
+pub struct VersionsResponse {
+    pub versions: &'static [ApiVersion],
+    pub latest: ApiVersion,
+    pub default: ApiVersion,
+}
+
+impl Default for VersionsResponse {
+    fn default() -> Self {
+        Self {
+            versions: ApiVersion::SUPPORTED,
+            latest: ApiVersion::LATEST,
+            default: ApiVersion::DEFAULT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate, ApiVersion, Route, RouteError, API_VERSION_HEADER};
+    use hyper::{HeaderMap, Method};
+
+    fn resolve(
+        method: Method,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Result<(ApiVersion, Option<Route>), RouteError> {
+        let (version, path) = negotiate(path, headers)?;
+        Ok((version, version.route(&method, path)))
+    }
+
+    #[test]
+    fn test_versioned_and_legacy_paths_resolve_to_the_same_route() {
+        let headers = HeaderMap::new();
+        assert_eq!(
+            resolve(Method::POST, "/v1/encrypt", &headers),
+            Ok((ApiVersion::V1, Some(Route::Encrypt)))
+        );
+        assert_eq!(
+            resolve(Method::POST, "/encrypt", &headers),
+            Ok((ApiVersion::V1, Some(Route::Encrypt)))
+        );
+        assert_eq!(
+            resolve(Method::POST, "/v2/encrypt/stream", &headers),
+            Ok((ApiVersion::V2, Some(Route::EncryptStream)))
+        );
+        assert_eq!(
+            resolve(Method::POST, "/decrypt/batch", &headers),
+            Ok((ApiVersion::V1, Some(Route::DecryptBatch)))
+        );
+        assert_eq!(
+            resolve(Method::POST, "/v2/encrypt/asymmetric", &headers),
+            Ok((ApiVersion::V2, Some(Route::EncryptAsymmetric)))
+        );
+        assert_eq!(
+            resolve(Method::GET, "/v1/random", &headers),
+            Ok((ApiVersion::V1, Some(Route::Random)))
+        );
+        assert_eq!(
+            resolve(Method::POST, "/v2/verify", &headers),
+            Ok((ApiVersion::V2, Some(Route::Verify)))
+        );
+        assert_eq!(
+            resolve(Method::GET, "/v1/token/jwks", &headers),
+            Ok((ApiVersion::V1, Some(Route::Jwks)))
+        );
+        assert_eq!(
+            resolve(Method::GET, "/v2/attestation/pcrs", &headers),
+            Ok((ApiVersion::V2, Some(Route::AttestationPcrs)))
+        );
+        assert_eq!(
+            resolve(Method::GET, "/v1/encrypt", &headers),
+            Ok((ApiVersion::V1, None))
+        );
+        assert_eq!(
+            resolve(Method::GET, "/versions", &headers),
+            Ok((ApiVersion::V1, Some(Route::Versions)))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_versions_are_rejected() {
+        assert_eq!(
+            resolve(Method::POST, "/v3/encrypt", &HeaderMap::new()),
+            Err(RouteError::UnsupportedVersion("v3".to_string()))
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_VERSION_HEADER, "v3".parse().unwrap());
+        assert_eq!(
+            resolve(Method::POST, "/encrypt", &headers),
+            Err(RouteError::UnsupportedVersion("v3".to_string()))
+        );
+        // A version in the path takes precedence over the header
+        assert_eq!(
+            resolve(Method::POST, "/v1/encrypt", &headers),
+            Ok((ApiVersion::V1, Some(Route::Encrypt)))
+        );
+
+        headers.insert(API_VERSION_HEADER, "V2".parse().unwrap());
+        assert_eq!(
+            resolve(Method::POST, "/decrypt", &headers),
+            Ok((ApiVersion::V2, Some(Route::Decrypt)))
+        );
+    }
+}
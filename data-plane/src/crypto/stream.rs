@@ -62,14 +62,13 @@ impl IncomingStreamDecoder {
         if src.is_empty() {
             return Ok(None);
         }
-        let next_candidate = Self::find_next_ciphertext_candidate(src.as_ref())?;
-        if next_candidate.is_none() {
+        let Some((potential_ciphertext, prefix)) =
+            Self::find_next_ciphertext_candidate(src.as_ref())?
+        else {
             // no ciphertext candidate
             let plaintext_bytes = self.create_slice(src, src.len());
             return Ok(Some(IncomingFrame::Plaintext(plaintext_bytes)));
-        }
-
-        let (potential_ciphertext, prefix) = next_candidate.unwrap();
+        };
         let prefix_len = prefix.len();
         // leave the quote in the buffer to match it in the ciphertext parser
         let quote_precedes_cipher = (prefix_len > 0) && (&prefix[prefix_len - 1..] == b"\"");
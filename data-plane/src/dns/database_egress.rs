@@ -0,0 +1,265 @@
+//! Preamble handling for database wire protocols which negotiate TLS after a plaintext exchange, so the Client Hello
+//! (and the SNI the egress policy checks) only arrives once the preamble is done.
+//!
+//! PostgreSQL clients send an SSLRequest and wait for the server to accept it before starting TLS. The request is
+//! accepted on the server's behalf, so the destination can be checked before any connection is made. MySQL servers
+//! speak first, so the connection is opened on its original destination address and the server's handshake is
+//! relayed until the client asks to switch to TLS.
+use shared::buffer_pool::STREAM_BUFFER_POOL;
+use shared::server::sni::is_client_hello;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length 8, code 80877103
+pub const POSTGRES_SSL_REQUEST: [u8; 8] = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f];
+/// Length 8, code 80877104
+const POSTGRES_GSSENC_REQUEST: [u8; 8] = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x30];
+const MYSQL_CLIENT_SSL: u32 = 0x0800;
+const MYSQL_SSL_REQUEST_LENGTH: usize = 32;
+const MYSQL_HEADER_LENGTH: usize = 4;
+/// Upper bound on the handshake packets read during the preamble
+const MAX_MYSQL_PREAMBLE_PACKET: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum DatabaseEgressError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Connection closed during the {0} preamble")]
+    ConnectionClosed(&'static str),
+    #[error("Invalid MySQL packet — {0}")]
+    InvalidMysqlPacket(String),
+    #[error("The PostgreSQL server refused to negotiate TLS")]
+    TlsRefused,
+}
+
+/// How a PostgreSQL client continued once its preamble was handled
+#[derive(Debug, PartialEq, Eq)]
+pub enum PostgresPreamble {
+    /// The client's SSLRequest was accepted, and it sent its Client Hello
+    SslRequest(Vec<u8>),
+    /// The client started TLS straight away, without an SSLRequest
+    DirectTls(Vec<u8>),
+    /// The client continued in plaintext
+    Plaintext(Vec<u8>),
+}
+
+/// How a MySQL client continued once the server's handshake was relayed to it
+#[derive(Debug, PartialEq, Eq)]
+pub enum MysqlPreamble {
+    /// The client asked to switch to TLS, and sent its Client Hello
+    Tls {
+        ssl_request: Vec<u8>,
+        client_hello: Vec<u8>,
+    },
+    /// The client sent its handshake response in plaintext
+    Plaintext(Vec<u8>),
+}
+
+async fn read_some<C: AsyncRead + Unpin>(
+    client: &mut C,
+    protocol: &'static str,
+) -> Result<Vec<u8>, DatabaseEgressError> {
+    let mut buf = STREAM_BUFFER_POOL.get();
+    match client.read(&mut buf).await? {
+        0 => Err(DatabaseEgressError::ConnectionClosed(protocol)),
+        n => Ok(buf[..n].to_vec()),
+    }
+}
+
+/// Handle a PostgreSQL client's preamble, starting from the first bytes it sent. SSLRequests are accepted on the
+/// server's behalf, and GSSAPI encryption is declined so the client falls back to TLS.
+pub async fn negotiate_postgres_preamble<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    mut data: Vec<u8>,
+) -> Result<PostgresPreamble, DatabaseEgressError> {
+    loop {
+        if data == POSTGRES_SSL_REQUEST {
+            client.write_all(b"S").await?;
+            let client_hello = read_some(client, "PostgreSQL").await?;
+            return Ok(PostgresPreamble::SslRequest(client_hello));
+        } else if data == POSTGRES_GSSENC_REQUEST {
+            client.write_all(b"N").await?;
+            data = read_some(client, "PostgreSQL").await?;
+        } else if is_client_hello(&data) {
+            return Ok(PostgresPreamble::DirectTls(data));
+        } else {
+            return Ok(PostgresPreamble::Plaintext(data));
+        }
+    }
+}
+
+/// Wait for a PostgreSQL server to accept the SSLRequest sent when connecting. The client has already been told TLS
+/// was accepted, so a refusal ends the connection.
+pub async fn await_postgres_tls<U: AsyncRead + Unpin>(
+    upstream: &mut U,
+) -> Result<(), DatabaseEgressError> {
+    let mut response = [0u8; 1];
+    if upstream.read(&mut response).await? == 0 {
+        return Err(DatabaseEgressError::ConnectionClosed("PostgreSQL"));
+    }
+    match &response {
+        b"S" => Ok(()),
+        _ => Err(DatabaseEgressError::TlsRefused),
+    }
+}
+
+async fn read_mysql_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, DatabaseEgressError> {
+    let mut packet = vec![0u8; MYSQL_HEADER_LENGTH];
+    reader
+        .read_exact(&mut packet)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => DatabaseEgressError::ConnectionClosed("MySQL"),
+            _ => e.into(),
+        })?;
+    let length = u32::from_le_bytes([packet[0], packet[1], packet[2], 0]) as usize;
+    if length > MAX_MYSQL_PREAMBLE_PACKET {
+        return Err(DatabaseEgressError::InvalidMysqlPacket(format!(
+            "handshake packet of {length} bytes"
+        )));
+    }
+    packet.resize(MYSQL_HEADER_LENGTH + length, 0);
+    reader
+        .read_exact(&mut packet[MYSQL_HEADER_LENGTH..])
+        .await
+        .map_err(|_| DatabaseEgressError::ConnectionClosed("MySQL"))?;
+    Ok(packet)
+}
+
+/// An SSLRequest is a truncated handshake response, carrying only the capability flags, max packet size and
+/// character set.
+fn is_mysql_ssl_request(packet: &[u8]) -> bool {
+    let payload = &packet[MYSQL_HEADER_LENGTH..];
+    payload.len() == MYSQL_SSL_REQUEST_LENGTH
+        && u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) & MYSQL_CLIENT_SSL
+            != 0
+}
+
+/// Relay a MySQL server's handshake to the client, and read the client's reply to it.
+pub async fn relay_mysql_preamble<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+) -> Result<MysqlPreamble, DatabaseEgressError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + Unpin,
+{
+    let handshake = read_mysql_packet(upstream).await?;
+    client.write_all(&handshake).await?;
+
+    let response = read_mysql_packet(client).await?;
+    if !is_mysql_ssl_request(&response) {
+        return Ok(MysqlPreamble::Plaintext(response));
+    }
+    let client_hello = read_some(client, "MySQL").await?;
+    Ok(MysqlPreamble::Tls {
+        ssl_request: response,
+        client_hello,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        await_postgres_tls, negotiate_postgres_preamble, relay_mysql_preamble, DatabaseEgressError,
+        MysqlPreamble, PostgresPreamble, POSTGRES_GSSENC_REQUEST, POSTGRES_SSL_REQUEST,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CLIENT_HELLO: [u8; 9] = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+
+    fn mysql_packet(sequence: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        packet.push(sequence);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_postgres_ssl_request_is_accepted_before_the_client_hello() {
+        let (mut proxy_side, mut client) = tokio::io::duplex(1024);
+        let client_task = tokio::spawn(async move {
+            let mut response = [0u8; 1];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"N");
+            client.write_all(&POSTGRES_SSL_REQUEST).await.unwrap();
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"S");
+            client.write_all(&CLIENT_HELLO).await.unwrap();
+        });
+
+        let preamble =
+            negotiate_postgres_preamble(&mut proxy_side, POSTGRES_GSSENC_REQUEST.to_vec())
+                .await
+                .unwrap();
+        assert_eq!(
+            preamble,
+            PostgresPreamble::SslRequest(CLIENT_HELLO.to_vec())
+        );
+        client_task.await.unwrap();
+
+        let (mut proxy_side, _client) = tokio::io::duplex(1024);
+        let startup = b"\x00\x00\x00\x09\x00\x03\x00\x00\x00".to_vec();
+        let preamble = negotiate_postgres_preamble(&mut proxy_side, startup.clone())
+            .await
+            .unwrap();
+        assert_eq!(preamble, PostgresPreamble::Plaintext(startup));
+        let preamble = negotiate_postgres_preamble(&mut proxy_side, CLIENT_HELLO.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(preamble, PostgresPreamble::DirectTls(CLIENT_HELLO.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_postgres_tls_refusal_is_an_error() {
+        assert!(await_postgres_tls(&mut &b"S"[..]).await.is_ok());
+        assert!(matches!(
+            await_postgres_tls(&mut &b"N"[..]).await,
+            Err(DatabaseEgressError::TlsRefused)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mysql_handshake_is_relayed_until_the_client_hello() {
+        let handshake = mysql_packet(0, b"\x0a8.0.36\x00rest of the server handshake");
+        let mut ssl_request_payload = vec![0u8; 32];
+        ssl_request_payload[..4].copy_from_slice(&0x0008_aa05u32.to_le_bytes());
+        let ssl_request = mysql_packet(1, &ssl_request_payload);
+
+        let (mut proxy_side, mut client) = tokio::io::duplex(1024);
+        let expected_handshake = handshake.clone();
+        let client_ssl_request = ssl_request.clone();
+        let client_task = tokio::spawn(async move {
+            let mut received = vec![0u8; expected_handshake.len()];
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected_handshake);
+            client.write_all(&client_ssl_request).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            client.write_all(&CLIENT_HELLO).await.unwrap();
+        });
+
+        let preamble = relay_mysql_preamble(&mut proxy_side, &mut &handshake[..])
+            .await
+            .unwrap();
+        assert_eq!(
+            preamble,
+            MysqlPreamble::Tls {
+                ssl_request,
+                client_hello: CLIENT_HELLO.to_vec()
+            }
+        );
+        client_task.await.unwrap();
+
+        // A full handshake response means the client is continuing without TLS
+        let (mut proxy_side, mut client) = tokio::io::duplex(1024);
+        let response = mysql_packet(1, &[0x05, 0xa2, 0x00, 0x00].repeat(12));
+        client.write_all(&response).await.unwrap();
+        let preamble = relay_mysql_preamble(&mut proxy_side, &mut &handshake[..])
+            .await
+            .unwrap();
+        assert_eq!(preamble, MysqlPreamble::Plaintext(response));
+    }
+}
@@ -0,0 +1,647 @@
+//! Policy stage for plaintext HTTP egress which encrypts configured JSON body fields before requests leave the
+//! enclave, so downstream processors (e.g. webhook receivers) only ever receive ciphertext.
+//!
+//! Rules match the hostnames the connection's IP was resolved for, rather than the Host header, which the customer
+//! process controls, and a prefix of the request-line path, which is what the upstream routes on. Requests are parsed
+//! one at a time, so every request on a kept-alive connection is checked. A request matched by a rule whose body
+//! can't be encrypted is blocked rather than forwarded in plaintext.
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::server::egress::{hostnames_resolved_to, EgressError};
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::base_tls_client::ClientError;
+use crate::e3client::{CryptoRequest, CryptoResponse, E3Api};
+
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+const MAX_HEADERS: usize = 128;
+/// Largest body buffered for encryption
+const MAX_ENCRYPTED_BODY_LENGTH: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum EgressEncryptionError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid HTTP request — {0}")]
+    InvalidRequest(String),
+    #[error(
+        "Request to {0} matched a field encryption rule but its body can't be encrypted — {1}"
+    )]
+    UnsupportedBody(String, String),
+    #[error("Request to {0} matched a field encryption rule but its body exceeds {MAX_ENCRYPTED_BODY_LENGTH} bytes")]
+    BodyTooLarge(String),
+    #[error("Failed to encrypt request fields — {0}")]
+    E3(#[from] ClientError),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EgressFieldEncryptionConfig {
+    pub rules: Vec<FieldEncryptionRule>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FieldEncryptionRule {
+    /// Destination hostname, either exact or a `*.` wildcard, or an IP for destinations reached by address
+    pub host: String,
+    /// Path prefix the rule applies to
+    #[serde(default = "default_path_prefix")]
+    pub path: String,
+    /// Dot separated paths of the JSON body fields to encrypt. Arrays along a path are traversed.
+    pub fields: Vec<String>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl FieldEncryptionRule {
+    fn matches(&self, destination: &EgressDestination, path: &str) -> bool {
+        let host_matches = destination
+            .names()
+            .any(|name| match self.host.strip_prefix('*') {
+                Some(suffix) => name.ends_with(&suffix.to_ascii_lowercase()),
+                None => self.host.eq_ignore_ascii_case(name),
+            });
+        host_matches && path.starts_with(&self.path)
+    }
+}
+
+impl EgressFieldEncryptionConfig {
+    /// Fields to encrypt in a request to a destination, across every rule it matches
+    pub fn fields_for(&self, destination: &EgressDestination, path: &str) -> Vec<&str> {
+        let mut fields: Vec<&str> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(destination, path))
+            .flat_map(|rule| rule.fields.iter().map(String::as_str))
+            .collect();
+        fields.sort_unstable();
+        fields.dedup();
+        fields
+    }
+}
+
+/// Where an egress connection is going, as known to the enclave rather than claimed by the customer process.
+#[derive(Clone, Debug)]
+pub struct EgressDestination {
+    ip: String,
+    hostnames: Vec<String>,
+}
+
+impl EgressDestination {
+    /// The destination of a connection to `ip`, named by every hostname the DNS proxy resolved to it
+    pub fn resolve(ip: IpAddr) -> Result<Self, EgressError> {
+        let ip = ip.to_string();
+        let hostnames = hostnames_resolved_to(&ip)?;
+        Ok(Self { ip, hostnames })
+    }
+
+    /// Rules may also name destinations which are reached by address
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.hostnames
+            .iter()
+            .chain(std::iter::once(&self.ip))
+            .map(String::as_str)
+    }
+}
+
+impl std::fmt::Display for EgressDestination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.hostnames.first() {
+            Some(hostname) => write!(f, "{hostname} ({})", self.ip),
+            None => f.write_str(&self.ip),
+        }
+    }
+}
+
+/// Whether the data starts like an HTTP request, so it can be parsed by the policy stage
+pub fn is_http_request(data: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    matches!(
+        httparse::Request::new(&mut headers).parse(data),
+        Ok(_) | Err(httparse::Error::TooManyHeaders)
+    )
+}
+
+/// How the body following a request head is delimited
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyFraming {
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+/// A request ready to forward. When it was encrypted the whole request is included, otherwise its body still has to
+/// be relayed.
+pub struct EgressRequest {
+    pub data: Vec<u8>,
+    pub remaining_body: BodyFraming,
+}
+
+struct RequestHead {
+    method: String,
+    path: String,
+    version: u8,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl RequestHead {
+    fn parse(head: &[u8]) -> Result<Self, EgressEncryptionError> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(EgressEncryptionError::InvalidRequest(
+                    "incomplete request head".to_string(),
+                ))
+            }
+            Err(e) => return Err(EgressEncryptionError::InvalidRequest(e.to_string())),
+        }
+        Ok(Self {
+            method: request.method.unwrap_or_default().to_string(),
+            path: request.path.unwrap_or_default().to_string(),
+            version: request.version.unwrap_or(1),
+            headers: request
+                .headers
+                .iter()
+                .map(|header| (header.name.to_string(), header.value.to_vec()))
+                .collect(),
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+            .map(str::trim)
+    }
+
+    fn body_framing(&self) -> Result<BodyFraming, EgressEncryptionError> {
+        let chunked = self
+            .header("transfer-encoding")
+            .and_then(|encodings| encodings.rsplit(',').next())
+            .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Ok(BodyFraming::Chunked);
+        }
+        match self.header("content-length") {
+            Some(length) => length.parse::<u64>().map(BodyFraming::Length).map_err(|_| {
+                EgressEncryptionError::InvalidRequest(format!("invalid content length {length}"))
+            }),
+            None => Ok(BodyFraming::Empty),
+        }
+    }
+
+    /// Serialize the head with the content length of the rewritten body
+    fn to_bytes(&self, content_length: usize) -> Vec<u8> {
+        let mut head =
+            format!("{} {} HTTP/1.{}\r\n", self.method, self.path, self.version).into_bytes();
+        for (name, value) in &self.headers {
+            // The body has already been received, so a 100 Continue from the destination would never be used
+            let rewritten =
+                name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("expect");
+            if rewritten {
+                continue;
+            }
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(format!("Content-Length: {content_length}\r\n").as_bytes());
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/// JSON pointers to the values selected by a dot separated field path
+fn select_field(value: &Value, path: &[&str], pointer: String, selected: &mut Vec<String>) {
+    match (value, path.split_first()) {
+        (Value::Array(items), _) => items.iter().enumerate().for_each(|(index, item)| {
+            select_field(item, path, format!("{pointer}/{index}"), selected)
+        }),
+        (_, None) => selected.push(pointer),
+        (Value::Object(object), Some((key, rest))) => {
+            if let Some(field) = object.get(*key) {
+                let pointer = format!("{pointer}/{}", escape_pointer_token(key));
+                select_field(field, rest, pointer, selected)
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_encrypted(value: &Value) -> bool {
+    matches!(value, Value::String(string) if string.starts_with("ev:"))
+}
+
+pub struct EgressFieldEncryptor<T: E3Api> {
+    config: EgressFieldEncryptionConfig,
+    e3_client: Arc<T>,
+}
+
+impl<T: E3Api + Send + Sync> EgressFieldEncryptor<T> {
+    pub fn new(config: EgressFieldEncryptionConfig, e3_client: Arc<T>) -> Self {
+        Self { config, e3_client }
+    }
+
+    /// Read the next request from the customer process, encrypting its body fields if its destination matches a
+    /// rule. Returns `None` once the connection is closed.
+    pub async fn next_request<R: AsyncBufRead + Unpin>(
+        &self,
+        reader: &mut R,
+        destination: &EgressDestination,
+    ) -> Result<Option<EgressRequest>, EgressEncryptionError> {
+        let Some(head_bytes) = read_head(reader).await? else {
+            return Ok(None);
+        };
+        let head = RequestHead::parse(&head_bytes)?;
+        let framing = head.body_framing()?;
+        let path = head.path.split('?').next().unwrap_or_default();
+        let fields = self.config.fields_for(destination, path);
+        if fields.is_empty() || framing == BodyFraming::Empty {
+            return Ok(Some(EgressRequest {
+                data: head_bytes,
+                remaining_body: framing,
+            }));
+        }
+
+        let host = destination.to_string();
+        let length = match framing {
+            BodyFraming::Length(length) if length > MAX_ENCRYPTED_BODY_LENGTH => {
+                return Err(EgressEncryptionError::BodyTooLarge(host))
+            }
+            BodyFraming::Length(length) => length,
+            _ => {
+                return Err(EgressEncryptionError::UnsupportedBody(
+                    host,
+                    "chunked bodies aren't supported".to_string(),
+                ))
+            }
+        };
+        let mut body = vec![0u8; length as usize];
+        reader.read_exact(&mut body).await?;
+        let body = self
+            .encrypt_fields(&body, &fields)
+            .await
+            .map_err(|e| match e {
+                EgressEncryptionError::UnsupportedBody(_, reason) => {
+                    EgressEncryptionError::UnsupportedBody(host.clone(), reason)
+                }
+                e => e,
+            })?;
+
+        log::debug!(
+            "Encrypted {} field(s) in egress request to {host}",
+            fields.len()
+        );
+        let mut data = head.to_bytes(body.len());
+        data.extend_from_slice(&body);
+        Ok(Some(EgressRequest {
+            data,
+            remaining_body: BodyFraming::Empty,
+        }))
+    }
+
+    async fn encrypt_fields(
+        &self,
+        body: &[u8],
+        fields: &[&str],
+    ) -> Result<Vec<u8>, EgressEncryptionError> {
+        let mut body: Value = serde_json::from_slice(body).map_err(|e| {
+            EgressEncryptionError::UnsupportedBody(String::new(), format!("invalid JSON — {e}"))
+        })?;
+
+        let mut pointers = Vec::new();
+        for field in fields {
+            let path: Vec<&str> = field.split('.').collect();
+            select_field(&body, &path, String::new(), &mut pointers);
+        }
+        pointers.retain(|pointer| {
+            body.pointer(pointer)
+                .is_some_and(|value| !value.is_null() && !is_encrypted(value))
+        });
+        if pointers.is_empty() {
+            return Ok(serde_json::to_vec(&body).expect("Infallible"));
+        }
+
+        let plaintexts: Vec<Value> = pointers
+            .iter()
+            .filter_map(|pointer| body.pointer(pointer).cloned())
+            .collect();
+        let response: CryptoResponse = self
+            .e3_client
+            .encrypt_with_retries(2, CryptoRequest::new(Value::Array(plaintexts)), None)
+            .await?;
+        let Value::Array(ciphertexts) = response.data else {
+            return Err(ClientError::General("Unexpected encrypt response".to_string()).into());
+        };
+        if ciphertexts.len() != pointers.len() {
+            return Err(ClientError::General("Unexpected encrypt response".to_string()).into());
+        }
+        for (pointer, ciphertext) in pointers.iter().zip(ciphertexts) {
+            if let Some(value) = body.pointer_mut(pointer) {
+                *value = ciphertext;
+            }
+        }
+        Ok(serde_json::to_vec(&body).expect("Infallible"))
+    }
+}
+
+async fn read_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<u8>>, EgressEncryptionError> {
+    let mut head = Vec::new();
+    loop {
+        let read = (&mut *reader)
+            .take((MAX_HEAD_LENGTH - head.len()) as u64 + 1)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if read == 0 {
+            return match head.is_empty() {
+                true => Ok(None),
+                false => Err(EgressEncryptionError::InvalidRequest(
+                    "connection closed mid request".to_string(),
+                )),
+            };
+        }
+        if head.len() > MAX_HEAD_LENGTH {
+            return Err(EgressEncryptionError::InvalidRequest(
+                "request head too large".to_string(),
+            ));
+        }
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Relay the rest of a request's body without buffering it
+pub async fn relay_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    framing: BodyFraming,
+) -> Result<(), EgressEncryptionError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match framing {
+        BodyFraming::Empty => Ok(()),
+        BodyFraming::Length(length) => {
+            let copied = tokio::io::copy(&mut (&mut *reader).take(length), writer).await?;
+            if copied < length {
+                return Err(EgressEncryptionError::InvalidRequest(
+                    "connection closed mid body".to_string(),
+                ));
+            }
+            Ok(())
+        }
+        BodyFraming::Chunked => relay_chunked_body(reader, writer).await,
+    }
+}
+
+async fn relay_chunked_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<(), EgressEncryptionError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let closed = || EgressEncryptionError::InvalidRequest("connection closed mid body".to_string());
+    loop {
+        let mut size_line = Vec::new();
+        if (&mut *reader)
+            .take(MAX_HEAD_LENGTH as u64)
+            .read_until(b'\n', &mut size_line)
+            .await?
+            == 0
+        {
+            return Err(closed());
+        }
+        writer.write_all(&size_line).await?;
+        let size = std::str::from_utf8(&size_line)
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| {
+                EgressEncryptionError::InvalidRequest("invalid chunk size".to_string())
+            })?;
+
+        if size == 0 {
+            // Trailers, ending with an empty line
+            loop {
+                let mut line = Vec::new();
+                if (&mut *reader)
+                    .take(MAX_HEAD_LENGTH as u64)
+                    .read_until(b'\n', &mut line)
+                    .await?
+                    == 0
+                {
+                    return Err(closed());
+                }
+                writer.write_all(&line).await?;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(());
+                }
+            }
+        }
+
+        // Chunk data is followed by a CRLF
+        let length = size + 2;
+        if tokio::io::copy(&mut (&mut *reader).take(length), writer).await? < length {
+            return Err(closed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_http_request, relay_body, BodyFraming, EgressDestination, EgressEncryptionError,
+        EgressFieldEncryptionConfig, EgressFieldEncryptor, FieldEncryptionRule,
+    };
+    use crate::e3client::mock::MockE3TestClient;
+    use crate::e3client::{CryptoRequest, CryptoResponse};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    fn encryptor(client: MockE3TestClient) -> EgressFieldEncryptor<MockE3TestClient> {
+        let config = EgressFieldEncryptionConfig {
+            rules: vec![FieldEncryptionRule {
+                host: "*.webhooks.example.com".to_string(),
+                path: "/events".to_string(),
+                fields: vec!["card.number".to_string(), "items.ssn".to_string()],
+            }],
+        };
+        EgressFieldEncryptor::new(config, Arc::new(client))
+    }
+
+    fn destination(hostnames: &[&str]) -> EgressDestination {
+        EgressDestination {
+            ip: "203.0.113.7".to_string(),
+            hostnames: hostnames
+                .iter()
+                .map(|hostname| hostname.to_string())
+                .collect(),
+        }
+    }
+
+    fn webhooks() -> EgressDestination {
+        destination(&["api.webhooks.example.com"])
+    }
+
+    fn request(host: &str, path: &str, body: &str) -> Vec<u8> {
+        format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_matching_requests_have_fields_encrypted() {
+        let mut client = MockE3TestClient::new();
+        client
+            .expect_encrypt_with_retries::<CryptoResponse, CryptoRequest>()
+            .times(1)
+            .returning(|_, request: CryptoRequest, _| {
+                assert_eq!(request.data, json!(["4242424242424242", "123", "456"]));
+                Ok(CryptoResponse {
+                    data: json!(["ev:card", "ev:ssn1", "ev:ssn2"]),
+                })
+            });
+        let encryptor = encryptor(client);
+
+        let body = json!({
+            "card": { "number": "4242424242424242", "name": "Jane Doe" },
+            "items": [{ "ssn": "123" }, { "ssn": "456" }, { "ssn": "ev:already" }]
+        })
+        .to_string();
+        // The Host header is chosen by the customer process, so it doesn't decide which rules apply
+        let mut input = request("elsewhere.example.org", "/events/1?retry=1", &body);
+        input.extend(request("api.webhooks.example.com", "/health", "{}"));
+        let mut reader = tokio::io::BufReader::new(&input[..]);
+
+        let encrypted = encryptor
+            .next_request(&mut reader, &webhooks())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encrypted.remaining_body, BodyFraming::Empty);
+        let (head, body) = std::str::from_utf8(&encrypted.data)
+            .unwrap()
+            .split_once("\r\n\r\n")
+            .unwrap();
+        assert!(head.ends_with(&format!("Content-Length: {}", body.len())));
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "card": { "number": "ev:card", "name": "Jane Doe" },
+                "items": [{ "ssn": "ev:ssn1" }, { "ssn": "ev:ssn2" }, { "ssn": "ev:already" }]
+            })
+        );
+
+        // Requests on the same connection to paths which don't match a rule are forwarded untouched
+        let passthrough = encryptor
+            .next_request(&mut reader, &webhooks())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(passthrough.remaining_body, BodyFraming::Length(2));
+        let mut relayed = passthrough.data;
+        relay_body(&mut reader, &mut relayed, passthrough.remaining_body)
+            .await
+            .unwrap();
+        assert_eq!(
+            relayed,
+            request("api.webhooks.example.com", "/health", "{}")
+        );
+        assert!(encryptor
+            .next_request(&mut reader, &webhooks())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_requests_to_other_destinations_are_untouched() {
+        let encryptor = encryptor(MockE3TestClient::new());
+        let input = request(
+            "api.webhooks.example.com",
+            "/events",
+            r#"{"card":{"number":"4242424242424242"}}"#,
+        );
+        let mut reader = &input[..];
+        let destination = destination(&["collector.example.org"]);
+        let request = encryptor
+            .next_request(&mut reader, &destination)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut relayed = request.data;
+        relay_body(&mut reader, &mut relayed, request.remaining_body)
+            .await
+            .unwrap();
+        assert_eq!(relayed, input);
+    }
+
+    #[tokio::test]
+    async fn test_matching_requests_which_cant_be_encrypted_are_blocked() {
+        let encryptor = encryptor(MockE3TestClient::new());
+        let input = request(
+            "hooks.webhooks.example.com",
+            "/events",
+            "card=4242424242424242",
+        );
+        let result = encryptor.next_request(&mut &input[..], &webhooks()).await;
+        assert!(matches!(
+            result,
+            Err(EgressEncryptionError::UnsupportedBody(..))
+        ));
+
+        let input = b"POST /events HTTP/1.1\r\nHost: hooks.webhooks.example.com\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n";
+        let result = encryptor.next_request(&mut &input[..], &webhooks()).await;
+        assert!(matches!(
+            result,
+            Err(EgressEncryptionError::UnsupportedBody(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_bodies_are_relayed() {
+        let encryptor = encryptor(MockE3TestClient::new());
+        let input = b"POST /upload HTTP/1.1\r\nHost: files.example.com\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nTrailer: 1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = &input[..];
+        let destination = destination(&["files.example.com"]);
+        let request = encryptor
+            .next_request(&mut reader, &destination)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut relayed = request.data;
+        relay_body(&mut reader, &mut relayed, request.remaining_body)
+            .await
+            .unwrap();
+        assert_eq!(relayed, &input[..input.len() - 18]);
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_http_requests_are_detected() {
+        assert!(is_http_request(b"POST /events HTTP/1.1\r\nHo"));
+        assert!(!is_http_request(&[0x16, 0x03, 0x01, 0x02, 0x00]));
+    }
+}
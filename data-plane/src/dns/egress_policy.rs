@@ -0,0 +1,179 @@
+//! The enclave's live egress policy. It starts as the egress config attested in the enclave's feature context, and is
+//! narrowed by updates polled from the control plane's config server. Updates which allow anything the attested
+//! config doesn't are rejected, so the host can restrict the enclave's egress at runtime but never widen it.
+use crate::config_client::{control_plane_supports, ConfigClient};
+use serde::Serialize;
+use shared::server::config_server::requests::EgressPolicyUpdate;
+use shared::server::egress::{
+    dns_cache_snapshot, narrow_egress_config, DnsCacheSnapshot, EgressConfig, EgressDestinations,
+    EgressError, LivePolicy,
+};
+use shared::server::plane_version::features;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+const EGRESS_POLICY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+static EGRESS_POLICY: OnceLock<EgressPolicy> = OnceLock::new();
+
+pub struct EgressPolicy {
+    baseline: EgressConfig,
+    live: LivePolicy<EgressConfig>,
+    version: AtomicU64,
+}
+
+/// The egress policy being enforced and the DNS cache behind it, served by the debug endpoint.
+#[derive(Serialize)]
+pub struct EgressDebugReport {
+    /// The latest policy update seen, 0 if the attested config hasn't been updated
+    pub policy_version: u64,
+    pub allow_list: EgressDestinations,
+    /// Null when egress is allowed on any port
+    pub ports: Option<Vec<u16>>,
+    pub tls_only: bool,
+    pub dns_cache: DnsCacheSnapshot,
+}
+
+impl EgressPolicy {
+    pub fn new(baseline: EgressConfig) -> Self {
+        Self {
+            live: LivePolicy::new(baseline.clone()),
+            baseline,
+            version: AtomicU64::new(0),
+        }
+    }
+
+    /// The enclave's egress policy, starting from the given attested config. Only the first call sets the baseline.
+    pub fn init(baseline: EgressConfig) -> &'static Self {
+        EGRESS_POLICY.get_or_init(|| Self::new(baseline))
+    }
+
+    /// The enclave's egress policy, if egress has been set up.
+    pub fn get() -> Option<&'static Self> {
+        EGRESS_POLICY.get()
+    }
+
+    /// The config to enforce for a new connection or lookup.
+    pub fn current(&self) -> Arc<EgressConfig> {
+        self.live.load()
+    }
+
+    pub fn debug_report(&self) -> Result<EgressDebugReport, EgressError> {
+        let current = self.current();
+        Ok(EgressDebugReport {
+            policy_version: self.version.load(Ordering::Acquire),
+            allow_list: current.allow_list.clone(),
+            ports: current.ports.clone(),
+            tls_only: current.tls_only,
+            dns_cache: dns_cache_snapshot()?,
+        })
+    }
+
+    /// Narrow the policy to an update. Returns whether it was applied, i.e. it hadn't been seen before.
+    pub fn apply(&self, update: &EgressPolicyUpdate) -> Result<bool, EgressError> {
+        if update.version == self.version.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        let narrowed = narrow_egress_config(&self.baseline, update);
+        // Record rejected versions too, so they're only reported once
+        self.version.store(update.version, Ordering::Release);
+        self.live.store(narrowed?);
+        Ok(true)
+    }
+
+    /// Poll the config server for updates to the policy for as long as the enclave runs. Control planes which haven't
+    /// advertised policy updates aren't polled, so the policy set at startup is kept.
+    pub async fn poll_for_updates(&self) {
+        let config_client = ConfigClient::new();
+        loop {
+            if !control_plane_supports(features::EGRESS_POLICY_UPDATES) {
+                tokio::time::sleep(EGRESS_POLICY_POLL_INTERVAL).await;
+                continue;
+            }
+            match config_client.get_egress_policy().await {
+                Ok(Some(update)) => match self.apply(&update) {
+                    Ok(true) => log::info!(
+                        "Egress policy updated to version {}: allow list {:?}, ports {:?}",
+                        update.version,
+                        update.allow_list,
+                        update.ports
+                    ),
+                    Ok(false) => {}
+                    Err(e) => log::error!(
+                        "Rejected egress policy version {}, keeping the current policy - {e}",
+                        update.version
+                    ),
+                },
+                Ok(None) => {}
+                Err(e) => log::warn!("Failed to check for egress policy updates - {e}"),
+            }
+            tokio::time::sleep(EGRESS_POLICY_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EgressPolicy;
+    use shared::server::config_server::requests::EgressPolicyUpdate;
+    use shared::server::egress::{get_egress_allow_list, EgressConfig};
+    use std::sync::Arc;
+
+    fn update(version: u64, allow_list: &str, ports: Option<&str>) -> EgressPolicyUpdate {
+        EgressPolicyUpdate {
+            version,
+            allow_list: allow_list.to_string(),
+            ports: ports.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_policy_is_only_narrowed_by_new_updates() {
+        let policy = EgressPolicy::new(EgressConfig {
+            allow_list: get_egress_allow_list("*.evervault.com,1.1.1.1".to_string()),
+            ports: Some(vec![443, 5432]),
+            tls_only: false,
+            destination_map: vec![],
+            protocols: Default::default(),
+            handshake_timeout_ms: 5000,
+        });
+
+        assert!(policy
+            .apply(&update(1, "api.evervault.com", Some("443")))
+            .unwrap());
+        let current = policy.current();
+        assert_eq!(current.allow_list.exact, vec!["api.evervault.com"]);
+        assert!(current.allow_list.ips.is_empty());
+        assert_eq!(current.ports, Some(vec![443]));
+
+        assert!(!policy
+            .apply(&update(1, "api.evervault.com", Some("443")))
+            .unwrap());
+        assert!(policy.apply(&update(2, "evervault.io", None)).is_err());
+        assert!(!policy.apply(&update(2, "evervault.io", None)).unwrap());
+        assert!(Arc::ptr_eq(&policy.current(), &current));
+
+        assert!(policy.apply(&update(3, "1.1.1.1", None)).unwrap());
+        assert_eq!(policy.current().ports, Some(vec![443, 5432]));
+    }
+
+    #[test]
+    fn test_debug_report_shows_the_current_policy() {
+        let policy = EgressPolicy::new(EgressConfig {
+            allow_list: get_egress_allow_list("*.evervault.com".to_string()),
+            ports: Some(vec![443]),
+            tls_only: true,
+            destination_map: vec![],
+            protocols: Default::default(),
+            handshake_timeout_ms: 5000,
+        });
+        policy.apply(&update(4, "api.evervault.com", None)).unwrap();
+
+        let report = policy.debug_report().unwrap();
+        assert_eq!(report.policy_version, 4);
+        assert_eq!(report.allow_list.exact, vec!["api.evervault.com"]);
+        assert_eq!(report.ports, Some(vec![443]));
+        assert!(report.tls_only);
+    }
+}
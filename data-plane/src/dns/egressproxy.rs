@@ -1,19 +1,41 @@
+use super::database_egress::{
+    await_postgres_tls, negotiate_postgres_preamble, relay_mysql_preamble, MysqlPreamble,
+    PostgresPreamble, POSTGRES_SSL_REQUEST,
+};
+use super::egress_encryption::{
+    is_http_request, relay_body, EgressDestination, EgressFieldEncryptor,
+};
+use super::egress_policy::EgressPolicy;
 use super::error::DNSError;
+use super::starttls::{StartTlsDialect, StartTlsTracker};
+use crate::config_client::control_plane_supports;
+use crate::e3client::E3Client;
 use crate::FeatureContext;
+use shared::buffer_pool::STREAM_BUFFER_POOL;
 use shared::rpc::request::ExternalRequest;
-use shared::server::egress::check_ip_allow_list;
-use shared::server::egress::EgressDestinations;
+use shared::server::egress::check_egress_destination;
+use shared::server::egress::check_egress_ip;
+use shared::server::egress::check_mapped_destination;
+use shared::server::egress::check_port_allow_list;
+use shared::server::egress::check_tls_only;
+use shared::server::egress::peek_client_data;
+use shared::server::egress::EgressConfig;
+use shared::server::egress::EgressProtocol;
+use shared::server::egress::MappedDestination;
 use shared::server::error::ServerError;
 use shared::server::get_vsock_client;
+use shared::server::plane_version::features;
+use shared::server::sni::is_client_hello;
 use shared::server::CID::Parent;
-use shared::utils::pipe_streams;
+use shared::utils::{pipe_streams, PeekableStream};
 use shared::EGRESS_PROXY_PORT;
 use shared::EGRESS_PROXY_VSOCK_PORT;
 use std::net::{IpAddr, Ipv4Addr};
 use std::os::fd::AsRawFd;
 use std::os::fd::RawFd;
+use std::sync::Arc;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 
@@ -32,14 +54,51 @@ pub struct EgressProxy;
 impl EgressProxy {
     pub async fn listen() -> Result<(), EgressProxyError> {
         log::info!("Egress proxy started on port {EGRESS_PROXY_PORT}");
-        let allowed_domains = FeatureContext::get()?.egress.allow_list;
+        let feature_context = FeatureContext::get()?;
+        let policy = EgressPolicy::init(feature_context.egress);
+        let egress_config = policy.current();
+        let field_encryptor = feature_context
+            .egress_field_encryption
+            .filter(|config| !config.rules.is_empty())
+            .map(|config| {
+                log::info!(
+                    "Encrypting fields in plaintext HTTP egress matching {} rule(s)",
+                    config.rules.len()
+                );
+                Arc::new(EgressFieldEncryptor::new(config, Arc::new(E3Client::new())))
+            });
+        match &egress_config.ports {
+            Some(ports) => log::info!("Egress allowed on ports {ports:?}"),
+            None => log::info!("Egress allowed on any port"),
+        }
+        if egress_config.tls_only {
+            log::info!("Only TLS egress is allowed, plaintext connections will be blocked");
+        }
+
+        for destination in &egress_config.destination_map {
+            if let Err(e) = check_mapped_destination(destination, &egress_config) {
+                log::error!(
+                    "Ignoring egress mapping for local port {} — {e}",
+                    destination.local_port
+                );
+                continue;
+            }
+            let destination = destination.clone();
+            tokio::spawn(async move {
+                let local_port = destination.local_port;
+                if let Err(e) = Self::listen_mapped(destination, policy).await {
+                    log::error!("Egress listener for local port {local_port} failed — {e}");
+                }
+            });
+        }
 
         let listener = TcpListener::bind(format!("[::]:{EGRESS_PROXY_PORT}")).await?;
         loop {
             if let Ok((stream, _)) = listener.accept().await {
                 tokio::spawn(Self::handle_egress_connection(
                     stream,
-                    allowed_domains.clone(),
+                    policy.current(),
+                    field_encryptor.clone(),
                 ));
             }
         }
@@ -48,33 +107,377 @@ impl EgressProxy {
     }
 
     async fn handle_egress_connection(
-        mut external_stream: TcpStream,
-        allowed_domains: EgressDestinations,
+        external_stream: TcpStream,
+        egress_config: Arc<EgressConfig>,
+        field_encryptor: Option<Arc<EgressFieldEncryptor<E3Client>>>,
     ) -> Result<(), DNSError> {
-        let mut buf = vec![0u8; 4096];
-        let n = external_stream.read(&mut buf).await?;
-        let customer_data = &mut buf[..n];
+        let fd = external_stream.as_raw_fd();
+        let (ip, port) = Self::get_destination(fd)?;
+        if let Some(protocol) = egress_config.protocols.get(&port).copied() {
+            if let Err(e) = check_port_allow_list(port, egress_config.ports.as_deref()) {
+                log::warn!("Egress policy violation, blocking request to {ip}:{port} — {e}");
+                return Err(e.into());
+            }
+            return Self::handle_protocol_egress(
+                protocol,
+                external_stream,
+                (ip, port),
+                &egress_config,
+            )
+            .await;
+        }
+
+        let mut external_stream = PeekableStream::new(external_stream);
+        let customer_data =
+            peek_first_bytes(&mut external_stream, egress_config.handshake_timeout()).await?;
 
         let mut data_plane_stream = get_vsock_client(EGRESS_PROXY_VSOCK_PORT, Parent).await?;
 
-        let fd = external_stream.as_raw_fd();
-        let (ip, port) = Self::get_destination(fd)?;
-        check_ip_allow_list(ip.to_string(), &allowed_domains)?;
+        check_egress_destination(
+            ip.to_string(),
+            port,
+            customer_data,
+            &egress_config.allow_list,
+        )?;
+        if let Err(e) = check_port_allow_list(port, egress_config.ports.as_deref())
+            .and_then(|_| check_tls_only(customer_data, port, egress_config.tls_only))
+        {
+            log::warn!("Egress policy violation, blocking request to {ip}:{port} — {e}");
+            return Err(e.into());
+        }
+
+        let is_plaintext_http = !is_client_hello(customer_data) && is_http_request(customer_data);
+        if let Some(field_encryptor) = field_encryptor.filter(|_| is_plaintext_http) {
+            return Self::handle_http_egress(
+                external_stream,
+                data_plane_stream,
+                (ip, port),
+                &field_encryptor,
+            )
+            .await;
+        }
+
+        let external_request =
+            inspecting_request(ip, port, || external_stream.take_peeked()).to_bytes()?;
+
+        data_plane_stream.write_all(&external_request).await?;
+
+        pipe_streams(external_stream, data_plane_stream).await?;
+        Ok(())
+    }
+
+    /// Forward connections to a local port to its mapped destination. The destination is known up front, so the
+    /// connection is opened without waiting on the client, which lets protocols where the server speaks first work.
+    async fn listen_mapped(
+        destination: MappedDestination,
+        policy: &'static EgressPolicy,
+    ) -> Result<(), EgressProxyError> {
+        let listener = TcpListener::bind(("127.0.0.1", destination.local_port)).await?;
+        log::info!(
+            "Forwarding egress from local port {} to {}:{}",
+            destination.local_port,
+            destination.host,
+            destination.port
+        );
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                let destination = destination.clone();
+                let egress_config = policy.current();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        Self::handle_mapped_connection(stream, &destination, &egress_config).await
+                    {
+                        log::warn!(
+                            "Failed to forward egress from local port {} — {e}",
+                            destination.local_port
+                        );
+                    }
+                });
+            }
+        }
+    }
+
+    async fn handle_mapped_connection(
+        external_stream: TcpStream,
+        destination: &MappedDestination,
+        egress_config: &EgressConfig,
+    ) -> Result<(), DNSError> {
+        // Resolving through the enclave's DNS proxy records the addresses for the allow list
+        let ip = tokio::net::lookup_host((destination.host.as_str(), destination.port))
+            .await?
+            .map(|addr| addr.ip())
+            .min_by_key(|ip| ip.is_ipv6())
+            .ok_or_else(|| {
+                DNSError::MissingIP(format!("No addresses found for {}", destination.host))
+            })?;
+        check_egress_ip(ip.to_string(), destination.port, &egress_config.allow_list)?;
+        if let Some(protocol) = egress_config.protocols.get(&destination.port).copied() {
+            return Self::handle_protocol_egress(
+                protocol,
+                external_stream,
+                (ip, destination.port),
+                egress_config,
+            )
+            .await;
+        }
+
+        // A Client Hello can only be checked for once the client has sent it
+        let mut external_stream = PeekableStream::new(external_stream);
+        if egress_config.tls_only {
+            let timeout = egress_config.handshake_timeout();
+            let customer_data = peek_first_bytes(&mut external_stream, timeout).await?;
+            check_tls_only(customer_data, destination.port, true)?;
+        }
+
+        let mut data_plane_stream = get_vsock_client(EGRESS_PROXY_VSOCK_PORT, Parent).await?;
+        let external_request = if egress_config.tls_only {
+            inspecting_request(ip, destination.port, || external_stream.take_peeked())
+        } else {
+            ExternalRequest::new(ip, destination.port, false)
+        }
+        .to_bytes()?;
+        data_plane_stream.write_all(&external_request).await?;
+
+        pipe_streams(external_stream, data_plane_stream).await?;
+        Ok(())
+    }
 
-        let external_request = ExternalRequest {
-            ip,
-            data: customer_data.to_vec(),
+    /// Egress for protocols which negotiate TLS after a plaintext preamble. The destination is checked against the
+    /// Client Hello the client sends once the preamble is done.
+    async fn handle_protocol_egress(
+        protocol: EgressProtocol,
+        external_stream: TcpStream,
+        (ip, port): (IpAddr, u16),
+        egress_config: &EgressConfig,
+    ) -> Result<(), DNSError> {
+        let result = match protocol {
+            EgressProtocol::Postgres => {
+                Self::handle_postgres_egress(external_stream, (ip, port), egress_config).await
+            }
+            EgressProtocol::Mysql => {
+                Self::handle_mysql_egress(external_stream, (ip, port), egress_config).await
+            }
+            EgressProtocol::Smtp => {
+                let tracker = StartTlsTracker::new(StartTlsDialect::Smtp, egress_config.tls_only);
+                Self::handle_starttls_egress(external_stream, (ip, port), egress_config, tracker)
+                    .await
+            }
+            EgressProtocol::Starttls => {
+                let tracker =
+                    StartTlsTracker::new(StartTlsDialect::Generic, egress_config.tls_only);
+                Self::handle_starttls_egress(external_stream, (ip, port), egress_config, tracker)
+                    .await
+            }
+        };
+        if let Err(e @ (DNSError::EgressError(_) | DNSError::StartTls(_))) = &result {
+            log::warn!("Egress policy violation, blocking request to {ip}:{port} — {e}");
+        }
+        result
+    }
+
+    async fn handle_postgres_egress(
+        mut external_stream: TcpStream,
+        (ip, port): (IpAddr, u16),
+        egress_config: &EgressConfig,
+    ) -> Result<(), DNSError> {
+        let mut buf = STREAM_BUFFER_POOL.get();
+        let n = external_stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        let preamble = negotiate_postgres_preamble(&mut external_stream, buf[..n].to_vec()).await?;
+        let (mut customer_data, client_hello) = match preamble {
+            PostgresPreamble::SslRequest(client_hello) => {
+                (POSTGRES_SSL_REQUEST.to_vec(), Some(client_hello))
+            }
+            PostgresPreamble::DirectTls(data) | PostgresPreamble::Plaintext(data) => (data, None),
+        };
+        let first_message = client_hello.as_deref().unwrap_or(&customer_data);
+        check_egress_destination(
+            ip.to_string(),
             port,
+            first_message,
+            &egress_config.allow_list,
+        )?;
+        check_tls_only(first_message, port, egress_config.tls_only)?;
+
+        let mut data_plane_stream = get_vsock_client(EGRESS_PROXY_VSOCK_PORT, Parent).await?;
+        let external_request = if client_hello.is_none() {
+            inspecting_request(ip, port, || std::mem::take(&mut customer_data))
+        } else {
+            ExternalRequest::new(ip, port, false)
         }
         .to_bytes()?;
+        data_plane_stream.write_all(&external_request).await?;
+        data_plane_stream.write_all(&customer_data).await?;
+        if let Some(client_hello) = client_hello {
+            await_postgres_tls(&mut data_plane_stream).await?;
+            data_plane_stream.write_all(&client_hello).await?;
+        }
 
+        pipe_streams(external_stream, data_plane_stream).await?;
+        Ok(())
+    }
+
+    async fn handle_mysql_egress(
+        mut external_stream: TcpStream,
+        (ip, port): (IpAddr, u16),
+        egress_config: &EgressConfig,
+    ) -> Result<(), DNSError> {
+        // The server speaks first, so only its address can be checked before connecting
+        check_egress_ip(ip.to_string(), port, &egress_config.allow_list)?;
+        let mut data_plane_stream = get_vsock_client(EGRESS_PROXY_VSOCK_PORT, Parent).await?;
+        let external_request = ExternalRequest::new(ip, port, false).to_bytes()?;
         data_plane_stream.write_all(&external_request).await?;
 
+        match relay_mysql_preamble(&mut external_stream, &mut data_plane_stream).await? {
+            MysqlPreamble::Tls {
+                ssl_request,
+                client_hello,
+            } => {
+                check_egress_destination(
+                    ip.to_string(),
+                    port,
+                    &client_hello,
+                    &egress_config.allow_list,
+                )?;
+                data_plane_stream.write_all(&ssl_request).await?;
+                data_plane_stream.write_all(&client_hello).await?;
+            }
+            MysqlPreamble::Plaintext(handshake_response) => {
+                check_tls_only(&handshake_response, port, egress_config.tls_only)?;
+                data_plane_stream.write_all(&handshake_response).await?;
+            }
+        }
+
         pipe_streams(external_stream, data_plane_stream).await?;
         Ok(())
     }
 
-    #[cfg(not(feature = "enclave"))]
+    /// Egress for protocols which upgrade to TLS in band. The server usually speaks first, so the connection is
+    /// opened once its address is allowed, and the client's plaintext is inspected until it starts the handshake.
+    async fn handle_starttls_egress(
+        external_stream: TcpStream,
+        (ip, port): (IpAddr, u16),
+        egress_config: &EgressConfig,
+        mut tracker: StartTlsTracker,
+    ) -> Result<(), DNSError> {
+        check_egress_ip(ip.to_string(), port, &egress_config.allow_list)?;
+        let mut data_plane_stream = get_vsock_client(EGRESS_PROXY_VSOCK_PORT, Parent).await?;
+        let external_request = ExternalRequest::new(ip, port, false).to_bytes()?;
+        data_plane_stream.write_all(&external_request).await?;
+
+        let (mut client_read, mut client_write) = external_stream.into_split();
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(data_plane_stream);
+        let responses = tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut upstream_read, &mut client_write).await;
+            let _ = client_write.shutdown().await;
+        });
+
+        let result = async {
+            let mut buf = STREAM_BUFFER_POOL.get();
+            loop {
+                let n = client_read.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                let data = &buf[..n];
+                let upgraded = tracker.inspect(data)?;
+                if upgraded {
+                    check_egress_destination(
+                        ip.to_string(),
+                        port,
+                        data,
+                        &egress_config.allow_list,
+                    )?;
+                    log::debug!("Egress connection to {ip}:{port} upgraded to TLS");
+                }
+                upstream_write.write_all(data).await?;
+                if upgraded {
+                    tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+                    break;
+                }
+            }
+            upstream_write.shutdown().await?;
+            Ok::<_, DNSError>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                let _ = responses.await;
+                Ok(())
+            }
+            Err(e) => {
+                responses.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// Relay plaintext HTTP egress request by request, so each one can have fields encrypted when the connection's
+    /// destination matches a field encryption rule. Responses are piped back untouched.
+    async fn handle_http_egress<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        external_stream: PeekableStream<TcpStream>,
+        mut data_plane_stream: S,
+        (ip, port): (IpAddr, u16),
+        field_encryptor: &EgressFieldEncryptor<E3Client>,
+    ) -> Result<(), DNSError> {
+        let destination = EgressDestination::resolve(ip)?;
+        let (client_read, mut client_write) = tokio::io::split(external_stream);
+        let mut requests = BufReader::new(client_read);
+        let Some(first_request) = field_encryptor
+            .next_request(&mut requests, &destination)
+            .await?
+        else {
+            return Ok(());
+        };
+
+        let mut first_data = first_request.data;
+        let external_request =
+            inspecting_request(ip, port, || std::mem::take(&mut first_data)).to_bytes()?;
+        data_plane_stream.write_all(&external_request).await?;
+        data_plane_stream.write_all(&first_data).await?;
+
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(data_plane_stream);
+        let responses = tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut upstream_read, &mut client_write).await;
+            let _ = client_write.shutdown().await;
+        });
+
+        let mut remaining_body = first_request.remaining_body;
+        let result = async {
+            loop {
+                relay_body(&mut requests, &mut upstream_write, remaining_body).await?;
+                match field_encryptor
+                    .next_request(&mut requests, &destination)
+                    .await?
+                {
+                    Some(request) => {
+                        upstream_write.write_all(&request.data).await?;
+                        remaining_body = request.remaining_body;
+                    }
+                    None => return Ok::<_, DNSError>(()),
+                }
+            }
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                upstream_write.shutdown().await?;
+                let _ = responses.await;
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("Egress policy violation, blocking request to {ip}:{port} — {e}");
+                responses.abort();
+                Err(e)
+            }
+        }
+    }
+
+    #[cfg(not(any(feature = "enclave", feature = "local")))]
     fn get_destination(_: RawFd) -> Result<(IpAddr, u16), DNSError> {
         // Hardcode egress IP for docker setup as SO_ORIGINAL_DST is not supported
         let addr = std::env::var("TEST_EGRESS_IP")
@@ -84,7 +487,7 @@ impl EgressProxy {
         Ok((IpAddr::V4(addr), 443))
     }
 
-    #[cfg(feature = "enclave")]
+    #[cfg(any(feature = "enclave", feature = "local"))]
     fn get_destination(fd: RawFd) -> Result<(IpAddr, u16), DNSError> {
         match Self::get_destination_ipv4(fd) {
             Ok(ip) => Ok(ip),
@@ -92,7 +495,7 @@ impl EgressProxy {
         }
     }
 
-    #[cfg(feature = "enclave")]
+    #[cfg(any(feature = "enclave", feature = "local"))]
     fn get_destination_ipv4(fd: RawFd) -> Result<(IpAddr, u16), DNSError> {
         use libc::sockaddr_in;
         use libc::socklen_t;
@@ -120,7 +523,7 @@ impl EgressProxy {
         }
     }
 
-    #[cfg(feature = "enclave")]
+    #[cfg(any(feature = "enclave", feature = "local"))]
     fn get_destination_ipv6(fd: RawFd) -> Result<(IpAddr, u16), DNSError> {
         println!("Getting original destination ipv6!");
         use libc::sockaddr_in6;
@@ -150,14 +553,48 @@ impl EgressProxy {
     }
 }
 
+/// The request for a connection whose destination is checked against the client's first bytes. Control planes which
+/// predate peeked client data need the bytes in the request, so `take_client_data` removes them from what's piped
+/// after it.
+fn inspecting_request(
+    ip: IpAddr,
+    port: u16,
+    take_client_data: impl FnOnce() -> Vec<u8>,
+) -> ExternalRequest {
+    if control_plane_supports(features::EGRESS_PEEKED_CLIENT_DATA) {
+        ExternalRequest::new(ip, port, true)
+    } else {
+        ExternalRequest::with_client_data(ip, port, take_client_data())
+    }
+}
+
+/// Peek at the client's first bytes, giving up if it doesn't send any in time so clients which connect but never send
+/// a Client Hello don't hold a task open forever. The bytes are left in the stream, so they're piped on verbatim.
+async fn peek_first_bytes<S: AsyncRead + Unpin>(
+    stream: &mut PeekableStream<S>,
+    timeout: std::time::Duration,
+) -> Result<&[u8], DNSError> {
+    match tokio::time::timeout(timeout, peek_client_data(stream)).await {
+        Ok(peeked) => Ok(peeked?),
+        Err(_) => {
+            log::debug!("Egress client didn't send its first bytes within {timeout:?}, closing");
+            Err(DNSError::HandshakeTimeout(timeout))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::dns::egressproxy::EgressDestinations;
+    use super::peek_first_bytes;
+    use crate::dns::error::DNSError;
     use shared::server::egress::check_domain_allow_list;
     use shared::server::egress::check_ip_allow_list;
+    use shared::server::egress::EgressDestinations;
     use shared::server::egress::{
         EgressError::EgressDomainNotAllowed, EgressError::EgressIpNotAllowed,
     };
+    use shared::utils::PeekableStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     fn test_valid_all_domains() {
@@ -166,6 +603,7 @@ mod tests {
             wildcard: vec![],
             allow_all: true,
             ips: vec![],
+            ..Default::default()
         };
         assert_eq!(
             check_domain_allow_list("app.evervault.com".to_string(), &egress_domains).unwrap(),
@@ -179,6 +617,7 @@ mod tests {
             wildcard: vec![],
             allow_all: false,
             ips: vec![],
+            ..Default::default()
         };
         assert_eq!(
             check_domain_allow_list("app.evervault.com".to_string(), &egress_domains).unwrap(),
@@ -192,6 +631,7 @@ mod tests {
             wildcard: vec!["evervault.com".to_string()],
             allow_all: false,
             ips: vec![],
+            ..Default::default()
         };
         assert_eq!(
             check_domain_allow_list("app.evervault.com".to_string(), &egress_domains).unwrap(),
@@ -205,6 +645,7 @@ mod tests {
             wildcard: vec!["evervault.com".to_string()],
             allow_all: false,
             ips: vec![],
+            ..Default::default()
         };
         let result = check_domain_allow_list("google.com".to_string(), &egress_domains);
         assert!(matches!(result, Err(EgressDomainNotAllowed(_))));
@@ -217,8 +658,28 @@ mod tests {
             wildcard: vec![],
             allow_all: false,
             ips: vec!["2.2.2.2".to_string()],
+            ..Default::default()
         };
         let result = check_ip_allow_list("1.1.1.1".to_string(), &egress_domains);
         assert!(matches!(result, Err(EgressIpNotAllowed(_))));
     }
+
+    #[tokio::test]
+    async fn test_silent_clients_time_out_before_sending_first_bytes() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = PeekableStream::new(server);
+        let timeout = std::time::Duration::from_millis(20);
+        let result = peek_first_bytes(&mut server, timeout).await;
+        assert!(matches!(result, Err(DNSError::HandshakeTimeout(_))));
+
+        client.write_all(b"hello").await.unwrap();
+        let peeked = peek_first_bytes(&mut server, timeout).await.unwrap();
+        assert_eq!(peeked, b"hello");
+
+        // Peeked bytes are still piped on to the destination
+        drop(client);
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
 }
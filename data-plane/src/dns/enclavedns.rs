@@ -1,5 +1,8 @@
+use super::egress_policy::EgressPolicy;
 use super::error::DNSError;
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use shared::buffer_pool::DNS_BUFFER_POOL;
 use shared::server::egress::check_dns_allowed_for_domain;
 use shared::server::egress::{cache_ip_for_allowlist, EgressDestinations};
 use shared::server::get_vsock_client;
@@ -10,33 +13,65 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc::Receiver, Semaphore};
-use tokio::time::timeout;
+use tokio::time::{timeout_at, Instant};
+
+const DNS_HEADER_LEN: usize = 12;
+const SERVFAIL: u8 = 2;
+
+/// Limits on the queries the DNS proxy handles at once, so a resolution storm from the customer process can't
+/// exhaust the enclave's memory or vsock connections.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DnsProxyConfig {
+    /// Queries forwarded to the host at once
+    pub max_in_flight: usize,
+    /// Queries waiting for an in flight query to finish. Queries beyond this are answered with SERVFAIL.
+    pub max_queued: usize,
+    /// How long a query has to be answered, including the time spent queued
+    pub query_timeout_ms: u64,
+}
+
+impl Default for DnsProxyConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 250,
+            max_queued: 500,
+            query_timeout_ms: 10000,
+        }
+    }
+}
+
+/// A query waiting for the driver, which is dropped if it isn't answered by its deadline.
+struct QueuedQuery {
+    packet: Bytes,
+    src: SocketAddr,
+    deadline: Instant,
+}
 
 /// Empty struct for the DNS proxy that runs in the data plane
 pub struct EnclaveDnsProxy;
 
 impl EnclaveDnsProxy {
-    pub async fn bind_server(allowed_destinations: EgressDestinations) -> Result<(), DNSError> {
+    pub async fn bind_server(
+        policy: &'static EgressPolicy,
+        config: DnsProxyConfig,
+    ) -> Result<(), DNSError> {
         log::info!("Starting DNS proxy");
         let socket = UdpSocket::bind("127.0.0.1:53").await?;
         let shared_socket = std::sync::Arc::new(socket);
-        let dns_dispatch_timeout = std::time::Duration::from_secs(1);
+        let query_timeout = Duration::from_millis(config.query_timeout_ms);
 
-        let max_concurrent_requests = 250;
-        // Create a bounded sync channel to send DNS lookups between the Proxy and Driver
+        // Create a bounded channel to queue DNS lookups between the Proxy and Driver
         let (dns_lookup_sender, dns_lookup_receiver) =
-            tokio::sync::mpsc::channel::<(Bytes, SocketAddr)>(500);
+            tokio::sync::mpsc::channel::<QueuedQuery>(config.max_queued);
 
-        let dns_driver_socket = shared_socket.clone();
-
-        let dns_request_upper_bound = std::time::Duration::from_secs(10);
         let dns_driver = EnclaveDnsDriver::new(
-            dns_driver_socket,
+            shared_socket.clone(),
             dns_lookup_receiver,
-            dns_request_upper_bound,
-            max_concurrent_requests,
-            allowed_destinations,
+            config.max_in_flight,
+            policy,
         );
         tokio::spawn(async move {
             log::info!("Starting DNS request driver");
@@ -44,78 +79,128 @@ impl EnclaveDnsProxy {
             log::info!("Enclave DNS Driver exiting");
         });
 
+        let mut queue_full = false;
         loop {
-            let mut buffer = [0; 512];
+            let mut buffer = DNS_BUFFER_POOL.get();
             if let Ok((amt, src)) = shared_socket.recv_from(&mut buffer).await {
-                let buf = Bytes::copy_from_slice(&buffer[..amt]);
-                let dispatch_result =
-                    timeout(dns_dispatch_timeout, dns_lookup_sender.send((buf, src))).await;
-
-                match dispatch_result {
-                    Ok(Err(e)) => log::error!("Error dispatching DNS request: {e:?}"),
-                    Err(e) => log::error!("Timeout dispatching DNS request: {e:?}"),
-                    _ => {}
+                let query = QueuedQuery {
+                    packet: Bytes::copy_from_slice(&buffer[..amt]),
+                    src,
+                    deadline: Instant::now() + query_timeout,
+                };
+                // Queries are rejected rather than waited on when the queue is full, so the proxy keeps answering
+                match dns_lookup_sender.try_send(query) {
+                    Ok(()) if queue_full => {
+                        log::info!("DNS proxy queue has drained, accepting queries");
+                        queue_full = false;
+                    }
+                    Ok(()) => {}
+                    Err(TrySendError::Full(query)) => {
+                        if !queue_full {
+                            log::warn!(
+                                "DNS proxy queue is full with {} queries, failing new queries",
+                                config.max_queued
+                            );
+                            queue_full = true;
+                        }
+                        send_servfail(&shared_socket, &query).await;
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        log::error!("Error dispatching DNS request: the DNS driver has exited")
+                    }
                 };
             }
         }
     }
 }
 
+/// Answer a query with SERVFAIL, so the client fails fast rather than waiting out its own timeout.
+async fn send_servfail(socket: &UdpSocket, query: &QueuedQuery) {
+    let Some(response) = servfail_response(&query.packet) else {
+        return;
+    };
+    if let Err(e) = socket.send_to(&response, &query.src).await {
+        log::error!("Failed to send DNS Response: {e}");
+    }
+}
+
+/// The query with its header changed to a SERVFAIL response, or `None` if it's too short to be a query.
+fn servfail_response(query: &[u8]) -> Option<Bytes> {
+    if query.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let mut response = query.to_vec();
+    // Set QR, keeping the opcode and RD while clearing AA and TC
+    response[2] = (response[2] | 0x80) & !0x06;
+    // Set RA and the response code
+    response[3] = 0x80 | SERVFAIL;
+    Some(Bytes::from(response))
+}
+
 struct EnclaveDnsDriver {
     inner: Arc<UdpSocket>,
-    dns_lookup_receiver: Receiver<(Bytes, SocketAddr)>,
-    dns_request_upper_bound: Duration,
+    dns_lookup_receiver: Receiver<QueuedQuery>,
     concurrency_gate: Arc<Semaphore>,
-    allowed_destinations: EgressDestinations,
+    policy: &'static EgressPolicy,
 }
 
 impl EnclaveDnsDriver {
     fn new(
         socket: Arc<UdpSocket>,
-        dns_lookup_receiver: Receiver<(Bytes, SocketAddr)>,
-        dns_request_upper_bound: Duration,
+        dns_lookup_receiver: Receiver<QueuedQuery>,
         concurrency_limit: usize,
-        allowed_destinations: EgressDestinations,
+        policy: &'static EgressPolicy,
     ) -> Self {
         let concurrency_gate = Arc::new(Semaphore::new(concurrency_limit));
         Self {
             inner: socket,
             dns_lookup_receiver,
-            dns_request_upper_bound,
             concurrency_gate,
-            allowed_destinations,
+            policy,
         }
     }
 
     async fn start_driver(mut self) {
-        while let Some((dns_packet, src_addr)) = self.dns_lookup_receiver.recv().await {
-            let request_upper_bound = self.dns_request_upper_bound;
+        while let Some(query) = self.dns_lookup_receiver.recv().await {
             let udp_socket = self.inner.clone();
 
-            let permit = match self.concurrency_gate.clone().acquire_owned().await {
-                Ok(permit) => permit,
-                Err(e) => {
+            let permit = match timeout_at(
+                query.deadline,
+                self.concurrency_gate.clone().acquire_owned(),
+            )
+            .await
+            {
+                Ok(Ok(permit)) => permit,
+                Ok(Err(e)) => {
                     log::error!("Failed to acquire permit from Semaphore, dropping lookup. {e:?}");
                     continue;
                 }
+                Err(_) => {
+                    log::warn!("DNS query timed out waiting for an in flight query to finish");
+                    send_servfail(&udp_socket, &query).await;
+                    continue;
+                }
             };
-            let destinations = self.allowed_destinations.clone();
+            let destinations = self.policy.current().allow_list.clone();
             // Create task per DNS lookup
             tokio::spawn(async move {
                 // move permit into task to drop when lookup is complete
                 let _lookup_permit = permit;
-                let dns_response =
-                    match Self::perform_dns_lookup(dns_packet, request_upper_bound, destinations)
-                        .await
-                    {
-                        Ok(dns_response) => dns_response,
-                        Err(e) => {
-                            log::error!("Failed to perform DNS Lookup: {e}");
-                            return;
-                        }
-                    };
+                let dns_response = match Self::perform_dns_lookup(
+                    query.packet,
+                    query.deadline,
+                    destinations,
+                )
+                .await
+                {
+                    Ok(dns_response) => dns_response,
+                    Err(e) => {
+                        log::error!("Failed to perform DNS Lookup: {e}");
+                        return;
+                    }
+                };
 
-                if let Err(e) = udp_socket.send_to(&dns_response, &src_addr).await {
+                if let Err(e) = udp_socket.send_to(&dns_response, &query.src).await {
                     log::error!("Failed to send DNS Response: {e}");
                 }
             });
@@ -125,14 +210,13 @@ impl EnclaveDnsDriver {
     /// Perform a DNS lookup using the proxy running on the Host
     async fn perform_dns_lookup(
         dns_packet: Bytes,
-        request_upper_bound: Duration,
+        deadline: Instant,
         allowed_destinations: EgressDestinations,
     ) -> Result<Bytes, DNSError> {
         // Check domain is allowed before proxying lookup
         check_dns_allowed_for_domain(&dns_packet.clone(), &allowed_destinations)?;
         // Attempt DNS lookup wth a timeout, flatten timeout errors into a DNS Error
-        let dns_response =
-            timeout(request_upper_bound, Self::forward_dns_lookup(dns_packet)).await??;
+        let dns_response = timeout_at(deadline, Self::forward_dns_lookup(dns_packet)).await??;
         cache_ip_for_allowlist(&dns_response.clone())?;
         Ok(dns_response)
     }
@@ -142,9 +226,72 @@ impl EnclaveDnsDriver {
     async fn forward_dns_lookup(bytes: Bytes) -> Result<Bytes, DNSError> {
         let mut stream = get_vsock_client(DNS_PROXY_VSOCK_PORT, Parent).await?;
         stream.write_all(&bytes).await?;
-        let mut buffer = [0; 512];
+        let mut buffer = DNS_BUFFER_POOL.get();
         let packet_size = stream.read(&mut buffer).await?;
 
         Ok(Bytes::copy_from_slice(&buffer[..packet_size]))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{servfail_response, EnclaveDnsDriver, QueuedQuery, SERVFAIL};
+    use crate::dns::egress_policy::EgressPolicy;
+    use bytes::Bytes;
+    use shared::server::egress::{get_egress_allow_list, EgressConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::time::Instant;
+
+    // A query for evervault.com with the RD flag set
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, b'e', b'v',
+        b'e', b'r', b'v', b'a', b'u', b'l', b't', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00,
+        0x01,
+    ];
+
+    #[test]
+    fn test_servfail_response_keeps_the_query() {
+        let response = servfail_response(QUERY).unwrap();
+        assert_eq!(&response[..2], &QUERY[..2]);
+        assert_eq!(response[2], 0x81);
+        assert_eq!(response[3] & 0x0f, SERVFAIL);
+        assert_eq!(&response[4..], &QUERY[4..]);
+        assert!(servfail_response(&QUERY[..4]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queries_fail_once_they_time_out_in_the_queue() {
+        let proxy_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let policy: &'static EgressPolicy = Box::leak(Box::new(EgressPolicy::new(EgressConfig {
+            allow_list: get_egress_allow_list("evervault.com".to_string()),
+            ports: Some(vec![443]),
+            tls_only: false,
+            destination_map: vec![],
+            protocols: Default::default(),
+            handshake_timeout_ms: 5000,
+        })));
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        // No queries can be in flight, so queued queries wait until their deadline
+        let driver = EnclaveDnsDriver::new(proxy_socket, receiver, 0, policy);
+        tokio::spawn(driver.start_driver());
+
+        sender
+            .send(QueuedQuery {
+                packet: Bytes::from_static(QUERY),
+                src: client.local_addr().unwrap(),
+                deadline: Instant::now() + Duration::from_millis(20),
+            })
+            .await
+            .unwrap();
+        let mut buf = [0; 512];
+        let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf[3] & 0x0f, SERVFAIL);
+        assert_eq!(&buf[12..n], &QUERY[12..]);
+    }
+}
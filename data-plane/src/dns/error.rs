@@ -1,3 +1,7 @@
+use super::database_egress::DatabaseEgressError;
+use super::egress_encryption::EgressEncryptionError;
+use super::starttls::StartTlsError;
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use shared::server::egress::EgressError;
 use thiserror::Error;
 
@@ -17,6 +21,33 @@ pub enum DNSError {
     NoHostnameFound,
     #[error("Egress error {0}")]
     EgressError(#[from] EgressError),
+    #[error("Egress field encryption error — {0}")]
+    EgressEncryption(#[from] EgressEncryptionError),
+    #[error("Database egress error — {0}")]
+    DatabaseEgress(#[from] DatabaseEgressError),
+    #[error("STARTTLS egress error — {0}")]
+    StartTls(#[from] StartTlsError),
     #[error("DNS lookup failed due to a timeout after: {0}")]
     DNSTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("Client didn't send its first bytes within {0:?}")]
+    HandshakeTimeout(std::time::Duration),
+}
+
+impl HasErrorCode for DNSError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::Io(_) => codes::CONNECTION_FAILED,
+            Self::DNSNoQuestionsFound => codes::DNS_INVALID_QUERY,
+            Self::RpcError(e) => e.error_code(),
+            Self::MissingIP(_) => codes::DNS_LOOKUP_FAILED,
+            Self::DNSTimeout(_) => codes::DNS_TIMEOUT,
+            Self::HandshakeTimeout(_) => codes::TIMEOUT,
+            Self::EgressError(e) => e.error_code(),
+            Self::TlsParseError(_)
+            | Self::NoHostnameFound
+            | Self::EgressEncryption(_)
+            | Self::DatabaseEgress(_)
+            | Self::StartTls(_) => codes::EGRESS_FAILED,
+        }
+    }
 }
@@ -1,6 +1,14 @@
 #[cfg(feature = "network_egress")]
+pub mod database_egress;
+#[cfg(feature = "network_egress")]
+pub mod egress_encryption;
+#[cfg(feature = "network_egress")]
+pub mod egress_policy;
+#[cfg(feature = "network_egress")]
 pub mod egressproxy;
 #[cfg(feature = "network_egress")]
 pub mod enclavedns;
 #[cfg(feature = "network_egress")]
 pub mod error;
+#[cfg(feature = "network_egress")]
+pub mod starttls;
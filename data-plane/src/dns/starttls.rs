@@ -0,0 +1,141 @@
+//! Tracking for protocols which upgrade a plaintext connection to TLS in band (SMTP's STARTTLS and the like), so
+//! mail relays and similar services can be reached without giving up the egress policy.
+//!
+//! The plaintext phase is permitted, and the client's side of it is inspected until it starts the TLS handshake.
+//! When only TLS egress is allowed, SMTP clients are limited to the commands needed to negotiate the upgrade, and
+//! other protocols to a short plaintext preamble, so nothing of substance is sent before the upgrade.
+use shared::server::sni::is_client_hello;
+use thiserror::Error;
+
+/// RFC 5321 limit on the length of a command line, including the CRLF
+const MAX_SMTP_LINE_LENGTH: usize = 512;
+const SMTP_PREAMBLE_COMMANDS: [&str; 6] = ["EHLO", "HELO", "STARTTLS", "NOOP", "RSET", "QUIT"];
+/// Plaintext the client may send before upgrading, for protocols without a known command set
+const MAX_PLAINTEXT_PREAMBLE: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum StartTlsError {
+    #[error("The {0} command isn't allowed before the connection is upgraded to TLS")]
+    CommandNotAllowed(String),
+    #[error("Command line exceeds {MAX_SMTP_LINE_LENGTH} bytes")]
+    LineTooLong,
+    #[error("More than {MAX_PLAINTEXT_PREAMBLE} bytes were sent before the connection was upgraded to TLS")]
+    PreambleTooLong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartTlsDialect {
+    Smtp,
+    Generic,
+}
+
+pub struct StartTlsTracker {
+    dialect: StartTlsDialect,
+    tls_only: bool,
+    plaintext_bytes: usize,
+    pending_line: Vec<u8>,
+}
+
+impl StartTlsTracker {
+    pub fn new(dialect: StartTlsDialect, tls_only: bool) -> Self {
+        Self {
+            dialect,
+            tls_only,
+            plaintext_bytes: 0,
+            pending_line: Vec::new(),
+        }
+    }
+
+    /// Inspect data sent by the client before the upgrade, returning whether it starts the TLS handshake.
+    pub fn inspect(&mut self, data: &[u8]) -> Result<bool, StartTlsError> {
+        if self.pending_line.is_empty() && is_client_hello(data) {
+            return Ok(true);
+        }
+        self.plaintext_bytes += data.len();
+        if !self.tls_only {
+            return Ok(false);
+        }
+        match self.dialect {
+            StartTlsDialect::Smtp => self.check_smtp_commands(data)?,
+            StartTlsDialect::Generic if self.plaintext_bytes > MAX_PLAINTEXT_PREAMBLE => {
+                return Err(StartTlsError::PreambleTooLong)
+            }
+            StartTlsDialect::Generic => {}
+        }
+        Ok(false)
+    }
+
+    fn check_smtp_commands(&mut self, data: &[u8]) -> Result<(), StartTlsError> {
+        self.pending_line.extend_from_slice(data);
+        while let Some(end) = self.pending_line.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending_line.drain(..=end).collect();
+            check_smtp_command(&line)?;
+        }
+        if self.pending_line.len() > MAX_SMTP_LINE_LENGTH {
+            return Err(StartTlsError::LineTooLong);
+        }
+        Ok(())
+    }
+}
+
+fn check_smtp_command(line: &[u8]) -> Result<(), StartTlsError> {
+    if line.len() > MAX_SMTP_LINE_LENGTH {
+        return Err(StartTlsError::LineTooLong);
+    }
+    let line = String::from_utf8_lossy(line);
+    let verb = line
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if SMTP_PREAMBLE_COMMANDS.contains(&verb.as_str()) {
+        Ok(())
+    } else {
+        Err(StartTlsError::CommandNotAllowed(verb))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StartTlsDialect, StartTlsError, StartTlsTracker};
+
+    const CLIENT_HELLO: [u8; 9] = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+
+    #[test]
+    fn test_smtp_upgrade_is_tracked() {
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Smtp, true);
+        assert!(!tracker.inspect(b"EHLO enclave.local\r\n").unwrap());
+        // Commands can be split across reads
+        assert!(!tracker.inspect(b"START").unwrap());
+        assert!(!tracker.inspect(b"TLS\r\n").unwrap());
+        assert!(tracker.inspect(&CLIENT_HELLO).unwrap());
+    }
+
+    #[test]
+    fn test_smtp_commands_are_restricted_before_the_upgrade() {
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Smtp, true);
+        assert!(!tracker.inspect(b"ehlo enclave.local\r\n").unwrap());
+        let result = tracker.inspect(b"AUTH PLAIN dXNlcgB1c2VyAHBhc3M=\r\n");
+        assert!(matches!(result, Err(StartTlsError::CommandNotAllowed(verb)) if verb == "AUTH"));
+
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Smtp, true);
+        let result = tracker.inspect(&vec![b'a'; 600]);
+        assert!(matches!(result, Err(StartTlsError::LineTooLong)));
+
+        // Plaintext mail is permitted when TLS isn't required
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Smtp, false);
+        assert!(!tracker.inspect(b"MAIL FROM:<a@example.com>\r\n").unwrap());
+    }
+
+    #[test]
+    fn test_generic_preamble_is_bounded() {
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Generic, true);
+        assert!(!tracker.inspect(b"a1 STARTTLS\r\n").unwrap());
+        assert!(tracker.inspect(&CLIENT_HELLO).unwrap());
+
+        let mut tracker = StartTlsTracker::new(StartTlsDialect::Generic, true);
+        assert!(!tracker.inspect(&[b'a'; 4000]).unwrap());
+        let result = tracker.inspect(&[b'a'; 100]);
+        assert!(matches!(result, Err(StartTlsError::PreambleTooLong)));
+    }
+}
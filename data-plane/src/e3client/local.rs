@@ -0,0 +1,462 @@
+//! A stand-in for E3 which encrypts and decrypts in process, so the data plane can run outside an enclave without
+//! access to the real service.
+//!
+//! Values are sealed with AES-256-GCM under a key derived from `MOCK_CRYPTO_SEED` (or a fixed default), and the IV
+//! is an HMAC of the value, so a given seed always produces the same ciphertext for the same value. This makes
+//! local runs reproducible, and is only acceptable because nothing encrypted here is meant to be secret.
+//!
+//! Ciphertexts follow the E3 layout, `ev:Tk9D:[<type>:]<iv>:<key id>:<ciphertext>:$`, so they're picked up by the
+//! same decryption paths as real ones. If `MOCK_CRYPTO_API_KEY` is set, only that API key will authenticate.
+//!
+//! Messages are signed with an HMAC under another key derived from the seed.
+//!
+//! The app's public key is a P-256 key derived from the same seed. There's only one key version, so encryption pinned
+//! to any other is rejected, as E3 does for unknown versions.
+use super::{
+    AppPublicKey, AuthRequest, E3Api, E3Error, E3Payload, SignRequest, SignResponse,
+    SignatureAlgorithm, VerifyRequest, VerifyResponse,
+};
+use async_trait::async_trait;
+use hyper::header::HeaderValue;
+use hyper::StatusCode;
+use once_cell::sync::Lazy;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcPoint, PointConversionForm};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha512};
+
+const DEFAULT_SEED: &str = "evervault-mock-crypto";
+const CIPHERTEXT_VERSION: &str = "Tk9D";
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+static LOCAL_KEYS: Lazy<LocalKeys> = Lazy::new(|| {
+    let seed = std::env::var("MOCK_CRYPTO_SEED").unwrap_or_else(|_| DEFAULT_SEED.to_string());
+    LocalKeys::derive(&seed)
+});
+
+struct LocalKeys {
+    encryption_key: [u8; 32],
+    iv_key: [u8; 32],
+    signing_key: [u8; 32],
+    key_id: String,
+    app_public_key: String,
+}
+
+impl LocalKeys {
+    fn derive(seed: &str) -> Self {
+        let encryption_key = sha256(format!("mock-crypto:encryption:{seed}").as_bytes());
+        let iv_key = sha256(format!("mock-crypto:iv:{seed}").as_bytes());
+        let signing_key = sha256(format!("mock-crypto:signing:{seed}").as_bytes());
+        // Shaped like a compressed P-256 public key, which is what ciphertexts are parsed as carrying
+        let mut key_id = vec![0x02];
+        key_id.extend_from_slice(&sha256(&encryption_key));
+        let key_id = base64::encode(key_id);
+        let app_public_key = Self::derive_app_public_key(seed).expect("Valid P-256 key");
+        Self {
+            encryption_key,
+            iv_key,
+            signing_key,
+            key_id,
+            app_public_key,
+        }
+    }
+
+    fn derive_app_public_key(seed: &str) -> Result<String, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let mut ctx = BigNumContext::new()?;
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+        let seed_hash = BigNum::from_slice(&sha256(format!("mock-crypto:app:{seed}").as_bytes()))?;
+        let mut private_key = BigNum::new()?;
+        private_key.nnmod(&seed_hash, &order, &mut ctx)?;
+        let mut public_key = EcPoint::new(&group)?;
+        public_key.mul_generator(&group, &private_key, &ctx)?;
+        let public_key = public_key.to_bytes(&group, PointConversionForm::COMPRESSED, &mut ctx)?;
+        Ok(base64::encode(public_key))
+    }
+
+    fn derive_iv(&self, value_type: &str, plaintext: &[u8]) -> Result<[u8; IV_LEN], ErrorStack> {
+        let key = PKey::hmac(&self.iv_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(value_type.as_bytes())?;
+        signer.update(plaintext)?;
+        let mut iv = [0u8; IV_LEN];
+        iv.copy_from_slice(&signer.sign_to_vec()?[..IV_LEN]);
+        Ok(iv)
+    }
+
+    fn sign(&self, algorithm: SignatureAlgorithm, message: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        let digest = match algorithm {
+            SignatureAlgorithm::HmacSha256 => MessageDigest::sha256(),
+            SignatureAlgorithm::HmacSha512 => MessageDigest::sha512(),
+        };
+        let key = PKey::hmac(&self.signing_key)?;
+        let mut signer = Signer::new(digest, &key)?;
+        signer.update(message)?;
+        signer.sign_to_vec()
+    }
+
+    fn encrypt_value(&self, value: &Value) -> Result<String, ErrorStack> {
+        let (value_type, plaintext) = match value {
+            Value::String(string) => ("string", string.clone()),
+            Value::Number(number) => ("number", number.to_string()),
+            Value::Bool(boolean) => ("boolean", boolean.to_string()),
+            _ => unreachable!("Only primitives are encrypted"),
+        };
+        let iv = self.derive_iv(value_type, plaintext.as_bytes())?;
+        let mut tag = [0u8; TAG_LEN];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.encryption_key,
+            Some(&iv),
+            value_type.as_bytes(),
+            plaintext.as_bytes(),
+            &mut tag,
+        )?;
+        ciphertext.extend_from_slice(&tag);
+        // As with E3, the type is left out for strings
+        let type_field = match value_type {
+            "string" => String::new(),
+            value_type => format!("{value_type}:"),
+        };
+        Ok(format!(
+            "ev:{CIPHERTEXT_VERSION}:{type_field}{}:{}:{}:$",
+            base64::encode(iv),
+            self.key_id,
+            base64::encode(ciphertext)
+        ))
+    }
+
+    /// Decrypt a ciphertext produced by `encrypt_value`, returning `None` for anything else.
+    fn decrypt_value(&self, ciphertext: &str) -> Option<Value> {
+        let fields: Vec<&str> = ciphertext.split(':').collect();
+        let (value_type, iv, key_id, sealed) = match fields[..] {
+            ["ev", CIPHERTEXT_VERSION, iv, key_id, sealed, "$"]
+            | ["ev", CIPHERTEXT_VERSION, "", iv, key_id, sealed, "$"] => {
+                ("string", iv, key_id, sealed)
+            }
+            ["ev", CIPHERTEXT_VERSION, value_type, iv, key_id, sealed, "$"] => {
+                (value_type, iv, key_id, sealed)
+            }
+            _ => return None,
+        };
+        if key_id != self.key_id {
+            return None;
+        }
+        let iv = base64::decode(iv).ok()?;
+        let sealed = base64::decode(sealed).ok()?;
+        if sealed.len() < TAG_LEN {
+            return None;
+        }
+        let (sealed, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.encryption_key,
+            Some(&iv),
+            value_type.as_bytes(),
+            sealed,
+            tag,
+        )
+        .ok()?;
+        let plaintext = String::from_utf8(plaintext).ok()?;
+        match value_type {
+            "string" => Some(Value::String(plaintext)),
+            "number" | "boolean" => serde_json::from_str(&plaintext).ok(),
+            _ => None,
+        }
+    }
+
+    fn encrypt_in_place(&self, value: &mut Value) -> Result<(), ErrorStack> {
+        match value {
+            Value::Object(object) => object
+                .values_mut()
+                .try_for_each(|value| self.encrypt_in_place(value)),
+            Value::Array(array) => array
+                .iter_mut()
+                .try_for_each(|value| self.encrypt_in_place(value)),
+            Value::Null => Ok(()),
+            _ => {
+                *value = Value::String(self.encrypt_value(value)?);
+                Ok(())
+            }
+        }
+    }
+
+    fn decrypt_in_place(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => object
+                .values_mut()
+                .for_each(|value| self.decrypt_in_place(value)),
+            Value::Array(array) => array
+                .iter_mut()
+                .for_each(|value| self.decrypt_in_place(value)),
+            Value::String(ciphertext) => {
+                if let Some(plaintext) = self.decrypt_value(ciphertext) {
+                    *value = plaintext;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn decode_message(data: &str) -> Result<Vec<u8>, E3Error> {
+    base64::decode(data).map_err(|_| E3Error::FailedRequest(StatusCode::BAD_REQUEST))
+}
+
+fn hash_api_key(api_key: &str) -> String {
+    base64::encode(Sha512::digest(api_key.as_bytes()))
+}
+
+#[derive(Clone, Default)]
+pub struct LocalE3Client;
+
+impl LocalE3Client {
+    pub fn new() -> Self {
+        log::warn!("Using local mock crypto, values are not encrypted by E3");
+        Self
+    }
+}
+
+#[async_trait]
+impl E3Api for LocalE3Client {
+    async fn decrypt<T: DeserializeOwned + 'static, P: E3Payload + Send + Sync + 'static>(
+        &self,
+        payload: P,
+    ) -> Result<T, E3Error> {
+        let mut payload = serde_json::to_value(payload)?;
+        LOCAL_KEYS.decrypt_in_place(&mut payload);
+        Ok(serde_json::from_value(payload)?)
+    }
+
+    async fn encrypt<T: DeserializeOwned + 'static, P: E3Payload + Send + Sync + 'static>(
+        &self,
+        payload: P,
+        _data_role: Option<String>,
+    ) -> Result<T, E3Error> {
+        if payload
+            .key_version()
+            .is_some_and(|version| version != CIPHERTEXT_VERSION)
+        {
+            return Err(E3Error::FailedRequest(StatusCode::BAD_REQUEST));
+        }
+        let mut payload = serde_json::to_value(payload)?;
+        let mut data = payload.get_mut("data").map(Value::take).unwrap_or_default();
+        LOCAL_KEYS
+            .encrypt_in_place(&mut data)
+            .map_err(|e| E3Error::General(format!("Mock encryption failed — {e}")))?;
+        Ok(serde_json::from_value(json!({ "data": data }))?)
+    }
+
+    async fn get_app_public_key(&self) -> Result<AppPublicKey, E3Error> {
+        Ok(AppPublicKey {
+            public_key: LOCAL_KEYS.app_public_key.clone(),
+        })
+    }
+
+    async fn sign(&self, payload: SignRequest) -> Result<SignResponse, E3Error> {
+        let message = decode_message(&payload.data)?;
+        let signature = LOCAL_KEYS
+            .sign(payload.algorithm, &message)
+            .map_err(|e| E3Error::General(format!("Mock signing failed — {e}")))?;
+        Ok(SignResponse {
+            signature: base64::encode(signature),
+        })
+    }
+
+    async fn verify(&self, payload: VerifyRequest) -> Result<VerifyResponse, E3Error> {
+        let message = decode_message(&payload.data)?;
+        let expected = LOCAL_KEYS
+            .sign(payload.algorithm, &message)
+            .map_err(|e| E3Error::General(format!("Mock signing failed — {e}")))?;
+        let valid = base64::decode(&payload.signature).is_ok_and(|signature| {
+            signature.len() == expected.len() && openssl::memcmp::eq(&signature, &expected)
+        });
+        Ok(VerifyResponse { valid })
+    }
+
+    async fn authenticate(
+        &self,
+        api_key: &HeaderValue,
+        _payload: AuthRequest,
+    ) -> Result<(), E3Error> {
+        let Ok(expected_key) = std::env::var("MOCK_CRYPTO_API_KEY") else {
+            return Ok(());
+        };
+        if api_key.as_bytes() == hash_api_key(&expected_key).as_bytes() {
+            Ok(())
+        } else {
+            Err(E3Error::FailedRequest(StatusCode::UNAUTHORIZED))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LocalE3Client, LocalKeys, DEFAULT_SEED, LOCAL_KEYS};
+    use crate::crypto::stream::{IncomingFrame, IncomingStreamDecoder};
+    use crate::e3client::{
+        BinaryRequest, CryptoRequest, CryptoResponse, E3Api, SignRequest, SignatureAlgorithm,
+        VerifyRequest,
+    };
+    use futures::StreamExt;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_values_round_trip_deterministically() {
+        let client = LocalE3Client::new();
+        let data = json!({ "name": "alice", "age": 42, "admin": false, "tags": ["a", null] });
+        let encrypted: CryptoResponse = client
+            .encrypt(CryptoRequest::new(data.clone()), None)
+            .await
+            .unwrap();
+        assert!(!encrypted.data["name"].as_str().unwrap().contains("string"));
+        assert!(encrypted.data["age"]
+            .as_str()
+            .unwrap()
+            .starts_with("ev:Tk9D:number:"));
+        assert_eq!(encrypted.data["tags"][1], json!(null));
+        // Ciphertexts must be found by the same parser as real ones when they arrive in a request body
+        let body = format!("{{\"name\":{}}}", encrypted.data["name"]);
+        assert_eq!(
+            IncomingStreamDecoder::create_reader(body.as_bytes())
+                .filter(|frame| std::future::ready(matches!(
+                    frame,
+                    Ok(IncomingFrame::Ciphertext(_))
+                )))
+                .count()
+                .await,
+            1
+        );
+
+        let again: CryptoResponse = client
+            .encrypt(CryptoRequest::new(data.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(encrypted.data, again.data);
+
+        let decrypted: CryptoResponse = client
+            .decrypt(CryptoRequest::new(encrypted.data))
+            .await
+            .unwrap();
+        assert_eq!(decrypted.data, data);
+    }
+
+    #[tokio::test]
+    async fn test_binary_payloads_round_trip() {
+        let client = LocalE3Client::new();
+        let bytes = [0u8, 159, 146, 150, 255];
+        let encrypted: CryptoResponse = client
+            .encrypt(BinaryRequest::new(&bytes), None)
+            .await
+            .unwrap();
+        assert!(encrypted.data.as_str().unwrap().starts_with("ev:Tk9D:"));
+        let decrypted: CryptoResponse = client
+            .decrypt(CryptoRequest::new(encrypted.data))
+            .await
+            .unwrap();
+        assert_eq!(
+            BinaryRequest::decode_plaintext(&decrypted.data),
+            Some(bytes.to_vec())
+        );
+        assert_eq!(BinaryRequest::decode_plaintext(&json!({ "a": 1 })), None);
+    }
+
+    #[test]
+    fn test_ciphertexts_from_other_seeds_are_left_alone() {
+        let other_keys = LocalKeys::derive("another-seed");
+        let ciphertext = other_keys.encrypt_value(&json!("secret")).unwrap();
+        assert_eq!(LOCAL_KEYS.decrypt_value(&ciphertext), None);
+        assert_eq!(other_keys.decrypt_value(&ciphertext), Some(json!("secret")));
+
+        // The value type is authenticated, so it can't be swapped
+        let tampered = ciphertext.replacen("Tk9D:", "Tk9D:number:", 1);
+        assert_eq!(other_keys.decrypt_value(&tampered), None);
+    }
+
+    #[tokio::test]
+    async fn test_app_public_key_is_a_valid_recipient() {
+        let app_key = LocalE3Client::new().get_app_public_key().await.unwrap();
+        assert_eq!(
+            app_key.public_key,
+            LocalKeys::derive(DEFAULT_SEED).app_public_key
+        );
+        assert_ne!(
+            app_key.public_key,
+            LocalKeys::derive("another-seed").app_public_key
+        );
+        assert!(crate::crypto::asymmetric::seal(b"data", &[app_key.public_key]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_encryption_can_only_be_pinned_to_the_mock_key_version() {
+        let client = LocalE3Client::new();
+        let request = CryptoRequest::new(json!("secret"));
+        let pinned: CryptoResponse = client
+            .encrypt(request.clone().with_key_version(Some("Tk9D".into())), None)
+            .await
+            .unwrap();
+        let unpinned: CryptoResponse = client.encrypt(request.clone(), None).await.unwrap();
+        assert_eq!(pinned.data, unpinned.data);
+
+        let result: Result<CryptoResponse, _> = client
+            .encrypt(request.with_key_version(Some("QlJV".into())), None)
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signatures_verify_only_for_their_message_and_algorithm() {
+        let client = LocalE3Client::new();
+        let data = base64::encode("message");
+        let signed = client
+            .sign(SignRequest {
+                data: data.clone(),
+                algorithm: SignatureAlgorithm::HmacSha256,
+            })
+            .await
+            .unwrap();
+        assert_eq!(base64::decode(&signed.signature).unwrap().len(), 32);
+
+        let verify = |data: String, algorithm| {
+            client.verify(VerifyRequest {
+                data,
+                signature: signed.signature.clone(),
+                algorithm,
+            })
+        };
+        assert!(
+            verify(data.clone(), SignatureAlgorithm::HmacSha256)
+                .await
+                .unwrap()
+                .valid
+        );
+        assert!(
+            !verify(
+                base64::encode("another message"),
+                SignatureAlgorithm::HmacSha256
+            )
+            .await
+            .unwrap()
+            .valid
+        );
+        assert!(
+            !verify(data, SignatureAlgorithm::HmacSha512)
+                .await
+                .unwrap()
+                .valid
+        );
+        assert!(verify("not base64!".into(), SignatureAlgorithm::HmacSha256)
+            .await
+            .is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use super::{AuthRequest, E3Api, E3Error, E3Payload};
+use super::{
+    AppPublicKey, AuthRequest, E3Api, E3Error, E3Payload, SignRequest, SignResponse, VerifyRequest,
+    VerifyResponse,
+};
 use async_trait::async_trait;
 use hyper::http::HeaderValue;
 use mockall::mock;
@@ -20,6 +23,14 @@ mock! {
 
     async fn authenticate(&self, api_key: &HeaderValue, payload: AuthRequest) -> Result<(), E3Error>;
 
+    async fn get_app_public_key(&self) -> Result<AppPublicKey, E3Error>;
+
+    async fn sign(&self, payload: SignRequest) -> Result<SignResponse, E3Error>;
+
+    async fn verify(&self, payload: VerifyRequest) -> Result<VerifyResponse, E3Error>;
+
+    async fn check_reachable(&self) -> Result<(), E3Error>;
+
     async fn decrypt_with_retries<T: DeserializeOwned + 'static, P: E3Payload + Clone + Send + Sync + 'static>(
         &self,
         retries: usize,
@@ -1,20 +1,39 @@
 use async_trait::async_trait;
 use hyper::header::HeaderValue;
-use hyper::{Body, Response};
+#[cfg(not(feature = "mock_crypto"))]
+use hyper::{body::Bytes, Body, Response};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::value::Value;
+use sha2::Digest;
 use std::ops::Deref;
+#[cfg(not(feature = "mock_crypto"))]
 use tokio_rustls::rustls::ServerName;
+#[cfg(not(feature = "mock_crypto"))]
 use tokio_rustls::TlsConnector;
+use zeroize::Zeroizing;
 
-type E3Error = ClientError;
+pub(crate) type E3Error = ClientError;
 
-use tokio_retry::strategy::{jitter, ExponentialBackoff};
-use tokio_retry::Retry;
+/// Pins encryption to a key version, given as its ciphertext version tag, e.g. `Tk9D`.
+pub const KEY_VERSION_HEADER: &str = "x-evervault-key-version";
 
+use crate::cache::AuthCache;
+#[cfg(feature = "tls_termination")]
+use crate::crypto::parser::ciphertext_metadata;
+use resilience::E3Resilience;
+
+#[cfg(all(feature = "mock_crypto", feature = "enclave"))]
+compile_error!("The mock_crypto feature can't be used in an enclave");
+
+#[cfg(feature = "mock_crypto")]
+mod local;
 #[cfg(test)]
 pub mod mock;
+pub mod resilience;
+
+#[cfg(feature = "mock_crypto")]
+pub use local::LocalE3Client as E3Client;
 
 #[async_trait]
 pub trait E3Api {
@@ -35,32 +54,93 @@ pub trait E3Api {
         payload: AuthRequest,
     ) -> Result<(), E3Error>;
 
+    /// The app's public key, for encrypting data that only holders of the app's private key can decrypt.
+    async fn get_app_public_key(&self) -> Result<AppPublicKey, E3Error>;
+
+    /// Sign a message with the app's signing key, which never leaves E3.
+    async fn sign(&self, payload: SignRequest) -> Result<SignResponse, E3Error>;
+
+    /// Check a signature produced by `sign` against its message.
+    async fn verify(&self, payload: VerifyRequest) -> Result<VerifyResponse, E3Error>;
+
+    /// Check that E3 can be reached, without making a request.
+    async fn check_reachable(&self) -> Result<(), E3Error> {
+        Ok(())
+    }
+
+    /// Authenticate an api key, answering from the auth cache when it holds a recent result for the key. The key is
+    /// sent to E3 as a hash, and rejected keys are only remembered when the cache is configured to.
+    async fn authenticate_api_key(
+        &self,
+        api_key: &HeaderValue,
+        payload: AuthRequest,
+        auth_cache: Option<&AuthCache>,
+    ) -> Result<(), E3Error> {
+        if let Some(auth_cache) = auth_cache {
+            if auth_cache.is_authenticated(api_key.as_bytes()).await {
+                log::debug!("Api key authenticated from cache");
+                return Ok(());
+            }
+            if auth_cache.is_rejected(api_key.as_bytes()).await {
+                log::debug!("Api key rejected from cache");
+                return Err(ClientError::FailedRequest(hyper::StatusCode::UNAUTHORIZED));
+            }
+        }
+
+        log::debug!("Authenticating api key");
+        let result = self.authenticate(&hash_api_key(api_key), payload).await;
+        if let Some(auth_cache) = auth_cache {
+            match &result {
+                Ok(()) => auth_cache.insert(api_key.as_bytes()).await,
+                Err(ClientError::FailedRequest(status))
+                    if *status == hyper::StatusCode::UNAUTHORIZED =>
+                {
+                    auth_cache.insert_rejected(api_key.as_bytes()).await
+                }
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
     async fn decrypt_with_retries<
-        T: DeserializeOwned + 'static,
+        T: DeserializeOwned + Send + 'static,
         P: E3Payload + Clone + Send + Sync + 'static,
     >(
         &self,
         retries: usize,
         payload: P,
     ) -> Result<T, E3Error> {
-        let retry_strategy = ExponentialBackoff::from_millis(10)
-            .map(jitter)
-            .take(retries);
-
-        Retry::spawn(retry_strategy, || {
-            let owned_payload = payload.clone();
-            async {
-                self.decrypt(owned_payload).await.map_err(|e| {
-                    log::error!("Error attempting decryption {e:?}");
-                    e
-                })
-            }
+        E3Resilience::get()
+            .call(retries, || {
+                let owned_payload = payload.clone();
+                async {
+                    self.decrypt(owned_payload).await.map_err(|e| {
+                        log::error!("Error attempting decryption {e:?}");
+                        e
+                    })
+                }
+            })
+            .await
+    }
+
+    /// Decrypt the payload, returning the metadata of each ciphertext in it alongside the plaintext.
+    #[cfg(feature = "tls_termination")]
+    async fn decrypt_with_metadata(
+        &self,
+        retries: usize,
+        payload: CryptoRequest,
+    ) -> Result<DecryptedPayload, E3Error> {
+        let metadata = ciphertext_metadata(payload.data());
+        let response: CryptoResponse = self.decrypt_with_retries(retries, payload).await?;
+        Ok(DecryptedPayload {
+            data: response.data,
+            metadata,
         })
-        .await
     }
 
     async fn encrypt_with_retries<
-        T: DeserializeOwned + 'static,
+        T: DeserializeOwned + Send + 'static,
         P: E3Payload + Clone + Send + Sync + 'static,
     >(
         &self,
@@ -68,50 +148,97 @@ pub trait E3Api {
         payload: P,
         data_role: Option<String>,
     ) -> Result<T, E3Error> {
-        let retry_strategy = ExponentialBackoff::from_millis(10)
-            .map(jitter)
-            .take(retries);
-
-        Retry::spawn(retry_strategy, || {
-            let owned_payload = payload.clone();
-            let owned_role = data_role.clone();
-            async move { self.encrypt(owned_payload, owned_role).await }
-        })
-        .await
+        E3Resilience::get()
+            .call(retries, || {
+                let owned_payload = payload.clone();
+                let owned_role = data_role.clone();
+                async move { self.encrypt(owned_payload, owned_role).await }
+            })
+            .await
     }
 }
 
+#[cfg(not(feature = "mock_crypto"))]
 #[derive(Clone)]
 pub struct E3Client {
     base_client: BaseClient,
     token_client: TokenClient,
 }
 
+#[cfg(not(feature = "mock_crypto"))]
 impl std::default::Default for E3Client {
     fn default() -> Self {
         Self::new()
     }
 }
 
-use crate::base_tls_client::tls_client_config::get_tls_client_config;
-use crate::base_tls_client::{AuthType, BaseClient, ClientError, OpenServerCertVerifier};
+#[cfg(not(feature = "mock_crypto"))]
+use crate::base_tls_client::tls_client_config::{get_tls_client_config, offer_http2};
+use crate::base_tls_client::ClientError;
+#[cfg(not(feature = "mock_crypto"))]
+use crate::base_tls_client::{AuthType, BaseClient, E3CertVerifier};
+#[cfg(not(feature = "mock_crypto"))]
 use crate::configuration;
+#[cfg(not(feature = "mock_crypto"))]
 use crate::crypto::token::TokenClient;
+#[cfg(not(feature = "mock_crypto"))]
 use crate::stats_client::StatsClient;
+#[cfg(not(feature = "mock_crypto"))]
+use crate::FeatureContext;
+
+/// Shared by every E3 client, so the connection pool covers all E3 traffic from the enclave.
+#[cfg(not(feature = "mock_crypto"))]
+static E3_BASE_CLIENT: std::sync::OnceLock<BaseClient> = std::sync::OnceLock::new();
 
+#[cfg(not(feature = "mock_crypto"))]
 impl E3Client {
     pub fn new() -> Self {
-        let verifier = std::sync::Arc::new(OpenServerCertVerifier);
-        let tls_connector =
-            TlsConnector::from(std::sync::Arc::new(get_tls_client_config(verifier)));
+        Self {
+            base_client: E3_BASE_CLIENT.get_or_init(Self::base_client).clone(),
+            token_client: TokenClient::new(),
+        }
+    }
+
+    fn base_client() -> BaseClient {
+        let pool_config = FeatureContext::get()
+            .map(|context| context.e3_connection_pool)
+            .unwrap_or_default();
+        let verifier = std::sync::Arc::new(E3CertVerifier);
+        let mut tls_client_config = get_tls_client_config(verifier);
+        if pool_config.http2 {
+            tls_client_config = offer_http2(tls_client_config);
+        }
+        let tls_connector = TlsConnector::from(std::sync::Arc::new(tls_client_config));
 
         let server_name = ServerName::try_from(configuration::get_e3_host().as_str())
             .expect("Hardcoded hostname");
 
-        Self {
-            base_client: BaseClient::new(tls_connector, server_name, shared::ENCLAVE_CRYPTO_PORT),
-            token_client: TokenClient::new(),
-        }
+        BaseClient::new(tls_connector, server_name, shared::ENCLAVE_CRYPTO_PORT)
+            .with_connection_pool(pool_config)
+            .with_timeouts(
+                configuration::get_e3_connect_timeout(),
+                configuration::get_e3_request_timeout(),
+            )
+    }
+
+    /// Send a request to E3. Failures are retried by the caller, through [`E3Resilience::call`].
+    async fn send(
+        &self,
+        auth_type: AuthType,
+        method: &str,
+        path: &str,
+        body: Bytes,
+        headers: Option<hyper::HeaderMap>,
+    ) -> Result<Response<Body>, E3Error> {
+        self.base_client
+            .send(
+                Some(auth_type),
+                method,
+                &self.uri(path),
+                Body::from(body),
+                headers,
+            )
+            .await
     }
 
     fn uri(&self, path: &str) -> String {
@@ -130,8 +257,13 @@ impl E3Client {
     }
 }
 
+#[cfg(not(feature = "mock_crypto"))]
 #[async_trait]
 impl E3Api for E3Client {
+    async fn check_reachable(&self) -> Result<(), E3Error> {
+        self.base_client.check_connection().await
+    }
+
     async fn decrypt<T: DeserializeOwned + 'static, P: E3Payload + Send + Sync + 'static>(
         &self,
         payload: P,
@@ -142,12 +274,11 @@ impl E3Api for E3Client {
             .await
             .map_err(|e| E3Error::General(format!("Couldn't get E3 token {e}")))?;
         let response = self
-            .base_client
             .send(
-                Some(AuthType::AttestationDoc(token)),
+                AuthType::AttestationDoc(token),
                 "POST",
-                &self.uri("/decrypt"),
-                payload.try_into_body()?,
+                "/decrypt",
+                payload.try_into_bytes()?,
                 None,
             )
             .await?;
@@ -160,26 +291,31 @@ impl E3Api for E3Client {
         payload: P,
         data_role: Option<String>,
     ) -> Result<T, E3Error> {
-        let request_headers = data_role
+        let mut header_map = hyper::HeaderMap::new();
+        if let Some(role) = data_role
             .as_ref()
             .and_then(|role| hyper::header::HeaderValue::from_str(role).ok())
-            .map(|role| {
-                let mut header_map = hyper::HeaderMap::new();
-                header_map.insert("x-evervault-data-role", role);
-                header_map
-            });
+        {
+            header_map.insert("x-evervault-data-role", role);
+        }
+        if let Some(key_version) = payload
+            .key_version()
+            .and_then(|version| hyper::header::HeaderValue::from_str(version).ok())
+        {
+            header_map.insert(KEY_VERSION_HEADER, key_version);
+        }
+        let request_headers = (!header_map.is_empty()).then_some(header_map);
         let token = self
             .token_client
             .get_token()
             .await
             .map_err(|e| E3Error::General(format!("Couldn't get E3 token {e}")))?;
         let response = self
-            .base_client
             .send(
-                Some(AuthType::AttestationDoc(token)),
+                AuthType::AttestationDoc(token),
                 "POST",
-                &self.uri("/encrypt"),
-                payload.try_into_body()?,
+                "/encrypt",
+                payload.try_into_bytes()?,
                 request_headers,
             )
             .await?;
@@ -187,18 +323,71 @@ impl E3Api for E3Client {
         self.parse_response(response).await
     }
 
+    async fn get_app_public_key(&self) -> Result<AppPublicKey, E3Error> {
+        let token = self
+            .token_client
+            .get_token()
+            .await
+            .map_err(|e| E3Error::General(format!("Couldn't get E3 token {e}")))?;
+        let response = self
+            .send(
+                AuthType::AttestationDoc(token),
+                "GET",
+                "/public-key",
+                Bytes::new(),
+                None,
+            )
+            .await?;
+        self.parse_response(response).await
+    }
+
+    async fn sign(&self, payload: SignRequest) -> Result<SignResponse, E3Error> {
+        let token = self
+            .token_client
+            .get_token()
+            .await
+            .map_err(|e| E3Error::General(format!("Couldn't get E3 token {e}")))?;
+        let response = self
+            .send(
+                AuthType::AttestationDoc(token),
+                "POST",
+                "/sign",
+                payload.try_into_bytes()?,
+                None,
+            )
+            .await?;
+        self.parse_response(response).await
+    }
+
+    async fn verify(&self, payload: VerifyRequest) -> Result<VerifyResponse, E3Error> {
+        let token = self
+            .token_client
+            .get_token()
+            .await
+            .map_err(|e| E3Error::General(format!("Couldn't get E3 token {e}")))?;
+        let response = self
+            .send(
+                AuthType::AttestationDoc(token),
+                "POST",
+                "/verify",
+                payload.try_into_bytes()?,
+                None,
+            )
+            .await?;
+        self.parse_response(response).await
+    }
+
     async fn authenticate(
         &self,
         api_key: &HeaderValue,
         payload: AuthRequest,
     ) -> Result<(), E3Error> {
         let response = self
-            .base_client
             .send(
-                Some(AuthType::ApiKey(api_key.clone())),
+                AuthType::ApiKey(api_key.clone()),
                 "POST",
-                &self.uri("/authenticate"),
-                payload.try_into_body()?,
+                "/authenticate",
+                payload.try_into_bytes()?,
                 None,
             )
             .await?;
@@ -207,6 +396,15 @@ impl E3Api for E3Client {
     }
 }
 
+/// E3 is sent the base64 SHA-512 of an api key rather than the key itself
+fn hash_api_key(api_key: &HeaderValue) -> HeaderValue {
+    let hash = base64::encode(sha2::Sha512::digest(api_key.as_bytes()));
+    let mut hashed_api_key =
+        HeaderValue::from_str(&hash).expect("Infallible - base64 is a valid header value");
+    hashed_api_key.set_sensitive(true);
+    hashed_api_key
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AuthRequest {
     pub team_uuid: String,
@@ -271,6 +469,10 @@ impl AutoDecryptRequest {
     pub fn header_data(&self) -> &Vec<EncryptedHeader> {
         &self.header_data
     }
+
+    pub fn into_parts(self) -> (Vec<EncryptedDataEntry>, Vec<EncryptedHeader>) {
+        (self.body_data, self.header_data)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -296,15 +498,30 @@ impl EncryptedHeader {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CryptoRequest {
     pub data: Value,
+    /// Sent to E3 as a header, not in the body
+    #[serde(skip)]
+    key_version: Option<String>,
 }
 
 impl CryptoRequest {
     pub fn new(data: Value) -> CryptoRequest {
-        CryptoRequest { data }
+        CryptoRequest {
+            data,
+            key_version: None,
+        }
+    }
+
+    pub fn with_key_version(mut self, key_version: Option<String>) -> CryptoRequest {
+        self.key_version = key_version;
+        self
     }
 }
 
-impl E3Payload for CryptoRequest {}
+impl E3Payload for CryptoRequest {
+    fn key_version(&self) -> Option<&str> {
+        self.key_version.as_deref()
+    }
+}
 impl CryptoRequest {
     pub fn data(&self) -> &Value {
         &self.data
@@ -315,13 +532,118 @@ impl CryptoRequest {
     }
 }
 
+/// Raw bytes, sent to E3 as a single base64 string value so they decrypt back to the same bytes.
+#[derive(Serialize, Clone, Debug)]
+pub struct BinaryRequest {
+    data: Zeroizing<String>,
+    /// Sent to E3 as a header, not in the body
+    #[serde(skip)]
+    key_version: Option<String>,
+}
+
+impl BinaryRequest {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self {
+            data: Zeroizing::new(base64::encode(bytes)),
+            key_version: None,
+        }
+    }
+
+    pub fn with_key_version(mut self, key_version: Option<String>) -> Self {
+        self.key_version = key_version;
+        self
+    }
+
+    /// The bytes of a decrypted binary payload, or `None` if the plaintext isn't one.
+    pub fn decode_plaintext(plaintext: &Value) -> Option<Vec<u8>> {
+        base64::decode(plaintext.as_str()?).ok()
+    }
+}
+
+impl E3Payload for BinaryRequest {
+    fn key_version(&self) -> Option<&str> {
+        self.key_version.as_deref()
+    }
+}
+
 pub trait E3Payload: Sized + Serialize {
-    fn try_into_body(self) -> Result<hyper::Body, E3Error> {
-        Ok(hyper::Body::from(serde_json::to_vec(&self)?))
+    /// The key version to encrypt the payload with, or `None` for the app's current key.
+    fn key_version(&self) -> Option<&str> {
+        None
+    }
+
+    fn try_into_bytes(self) -> Result<hyper::body::Bytes, E3Error> {
+        Ok(serde_json::to_vec(&self)?.into())
     }
 }
 
+/// A base64 encoded SEC1 P-256 point
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AppPublicKey {
+    pub public_key: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CryptoResponse {
     pub data: Value,
 }
+
+/// The MAC used to sign messages. Names follow JWS, e.g. `HS256`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    #[default]
+    #[serde(rename = "HS256")]
+    HmacSha256,
+    #[serde(rename = "HS512")]
+    HmacSha512,
+}
+
+/// A base64 encoded message to sign, so any bytes can be signed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignRequest {
+    pub data: String,
+    #[serde(default)]
+    pub algorithm: SignatureAlgorithm,
+}
+
+impl E3Payload for SignRequest {}
+
+/// A base64 encoded signature
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignResponse {
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerifyRequest {
+    pub data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub algorithm: SignatureAlgorithm,
+}
+
+impl E3Payload for VerifyRequest {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerifyResponse {
+    pub valid: bool,
+}
+
+/// Decrypted data, with the metadata of each ciphertext at the same position in `metadata`.
+#[cfg(feature = "tls_termination")]
+#[derive(Serialize, Deserialize)]
+pub struct DecryptedPayload {
+    pub data: Value,
+    pub metadata: Value,
+}
+
+#[cfg(feature = "tls_termination")]
+impl DecryptedPayload {
+    pub fn new(data: Value, ciphertexts: &Value) -> Self {
+        Self {
+            data,
+            metadata: ciphertext_metadata(ciphertexts),
+        }
+    }
+}
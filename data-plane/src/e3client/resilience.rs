@@ -0,0 +1,520 @@
+//! Retry budgets, transient failure retries and hedged requests for E3 calls.
+//!
+//! Requests which couldn't reach E3, or which E3 failed with a 5xx, are retried with jittered exponential backoff.
+//! Retries are limited to a percentage of recent E3 requests, so a failing E3 isn't hit with several times its usual
+//! load. Hedging sends a second attempt when the first is slower than most recent requests, to cut tail latency. Hedges
+//! are paid for from the same budget, so they stop too once E3 is struggling.
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_retry::strategy::jitter;
+use tokio_retry::RetryIf;
+
+use super::E3Error;
+use crate::base_tls_client::TimeoutKind;
+use crate::FeatureContext;
+
+/// Requests and retries are counted over this many seconds when checking the budget
+const BUDGET_WINDOW_SECS: usize = 10;
+/// Number of recent E3 latencies kept to pick the hedge delay from
+const LATENCY_SAMPLES: usize = 256;
+/// Hedging only starts once enough latencies are known for the percentile to be meaningful
+const MIN_SAMPLES_FOR_HEDGING: usize = 20;
+
+static E3_RESILIENCE: OnceCell<E3Resilience> = OnceCell::new();
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct E3ResilienceConfig {
+    /// Retries allowed, as a percentage of E3 requests in the last 10 seconds
+    pub retry_budget_percent: u32,
+    /// Retries always allowed per second, so retries still happen when there's little traffic
+    pub min_retries_per_second: u32,
+    /// Send a second attempt for E3 requests slower than most, off by default
+    pub hedging: Option<HedgingConfig>,
+    /// Retries of requests which fail to reach E3 or get a 5xx
+    pub retry: E3RetryConfig,
+}
+
+impl Default for E3ResilienceConfig {
+    fn default() -> Self {
+        Self {
+            retry_budget_percent: 20,
+            min_retries_per_second: 5,
+            hedging: None,
+            retry: E3RetryConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct E3RetryConfig {
+    /// Attempts made at each request, including the first
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubling for each retry after it
+    pub initial_backoff_ms: u64,
+    /// Upper bound on the backoff between attempts
+    pub max_backoff_ms: u64,
+}
+
+impl Default for E3RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 20,
+            max_backoff_ms: 500,
+        }
+    }
+}
+
+impl E3RetryConfig {
+    /// Jittered delays before each retry.
+    fn backoff(&self) -> impl Iterator<Item = Duration> {
+        let initial = self.initial_backoff_ms;
+        let max = self.max_backoff_ms;
+        (0..self.max_attempts.saturating_sub(1))
+            .map(move |retry| {
+                Duration::from_millis(initial.saturating_mul(1 << retry.min(32)).min(max))
+            })
+            .map(jitter)
+    }
+}
+
+/// Failures worth retrying: E3 couldn't be reached, the connection closed before the request was sent, or E3 failed
+/// with a server error. Requests which timed out waiting for a response aren't retried, as E3 may still be working on
+/// them, and anything else would fail the same way again.
+pub fn is_transient(error: &E3Error) -> bool {
+    match error {
+        E3Error::IoError(_) | E3Error::Timeout(TimeoutKind::Connect) => true,
+        E3Error::HyperError(e) => e.is_connect() || e.is_canceled(),
+        E3Error::FailedRequest(status) => status.is_server_error(),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HedgingConfig {
+    /// Percentile of recent E3 latencies to wait for before sending a second attempt
+    pub percentile: u8,
+    /// Lower bound on the wait, so fast requests aren't doubled
+    pub min_delay_ms: u64,
+}
+
+impl Default for HedgingConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 95,
+            min_delay_ms: 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct BudgetBucket {
+    requests: u64,
+    retries: u64,
+}
+
+struct BudgetWindow {
+    started_at: Instant,
+    current_second: u64,
+    buckets: [BudgetBucket; BUDGET_WINDOW_SECS],
+}
+
+impl BudgetWindow {
+    /// Move the window up to now, clearing buckets for the seconds which have passed.
+    fn advance(&mut self) -> &mut BudgetBucket {
+        let now = self.started_at.elapsed().as_secs();
+        let elapsed = now.saturating_sub(self.current_second);
+        for second in 1..=elapsed.min(BUDGET_WINDOW_SECS as u64) {
+            self.buckets[((self.current_second + second) as usize) % BUDGET_WINDOW_SECS] =
+                BudgetBucket::default();
+        }
+        self.current_second = now;
+        &mut self.buckets[(now as usize) % BUDGET_WINDOW_SECS]
+    }
+}
+
+pub struct RetryBudget {
+    percent: u64,
+    min_retries_per_window: u64,
+    window: Mutex<BudgetWindow>,
+}
+
+impl RetryBudget {
+    pub fn new(config: &E3ResilienceConfig) -> Self {
+        Self {
+            percent: config.retry_budget_percent as u64,
+            min_retries_per_window: config.min_retries_per_second as u64
+                * BUDGET_WINDOW_SECS as u64,
+            window: Mutex::new(BudgetWindow {
+                started_at: Instant::now(),
+                current_second: 0,
+                buckets: [BudgetBucket::default(); BUDGET_WINDOW_SECS],
+            }),
+        }
+    }
+
+    pub fn record_request(&self) {
+        let mut window = self.window.lock().expect("Retry budget lock poisoned");
+        window.advance().requests += 1;
+    }
+
+    /// Spend a retry if the budget has any left.
+    pub fn try_spend(&self) -> bool {
+        let mut window = self.window.lock().expect("Retry budget lock poisoned");
+        window.advance();
+        let (requests, retries) = window
+            .buckets
+            .iter()
+            .fold((0, 0), |(requests, retries), bucket| {
+                (requests + bucket.requests, retries + bucket.retries)
+            });
+        let allowed = (requests * self.percent / 100).max(self.min_retries_per_window);
+        if retries >= allowed {
+            return false;
+        }
+        window.advance().retries += 1;
+        true
+    }
+}
+
+/// Latencies of recent successful E3 requests.
+pub struct LatencyTracker {
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self {
+            samples: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().expect("Latency tracker lock poisoned");
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// The given percentile of recent latencies, once there are enough of them.
+    pub fn percentile(&self, percentile: u8) -> Option<Duration> {
+        let mut samples: Vec<_> = self
+            .samples
+            .lock()
+            .expect("Latency tracker lock poisoned")
+            .iter()
+            .copied()
+            .collect();
+        if samples.len() < MIN_SAMPLES_FOR_HEDGING {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = (samples.len() * percentile.min(100) as usize).div_ceil(100);
+        samples.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+impl Default for LatencyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct E3Resilience {
+    budget: RetryBudget,
+    latency: LatencyTracker,
+    hedging: Option<HedgingConfig>,
+    retry: E3RetryConfig,
+}
+
+impl E3Resilience {
+    pub fn new(config: &E3ResilienceConfig) -> Self {
+        Self {
+            budget: RetryBudget::new(config),
+            latency: LatencyTracker::new(),
+            hedging: config.hedging.clone(),
+            retry: config.retry.clone(),
+        }
+    }
+
+    /// Shared by every E3 client, so the budget covers all E3 traffic from the enclave.
+    pub fn get() -> &'static Self {
+        E3_RESILIENCE.get_or_init(|| {
+            let config = FeatureContext::get()
+                .map(|context| context.e3_resilience)
+                .unwrap_or_default();
+            Self::new(&config)
+        })
+    }
+
+    /// Run an E3 operation, retrying transient failures with backoff while the retry budget allows, and hedging slow
+    /// attempts if enabled. This is the only place E3 requests are retried, so `retries` is capped by the configured
+    /// maximum attempts.
+    pub async fn call<T, F, Fut>(&self, retries: usize, operation: F) -> Result<T, E3Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E3Error>>,
+    {
+        self.budget.record_request();
+        RetryIf::spawn(
+            self.retry.backoff().take(retries),
+            || self.attempt(&operation),
+            |e: &E3Error| {
+                if !is_transient(e) {
+                    return false;
+                }
+                let within_budget = self.budget.try_spend();
+                if within_budget {
+                    log::warn!("Retrying E3 request after transient failure - {e}");
+                } else {
+                    log::warn!("Not retrying E3 request, the retry budget is spent - {e}");
+                }
+                within_budget
+            },
+        )
+        .await
+    }
+
+    async fn attempt<T, F, Fut>(&self, operation: &F) -> Result<T, E3Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E3Error>>,
+    {
+        let Some(delay) = self.hedge_delay() else {
+            return self.timed(operation()).await;
+        };
+        let primary = self.timed(operation());
+        tokio::pin!(primary);
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = tokio::time::sleep(delay) => {}
+        }
+        if !self.budget.try_spend() {
+            return primary.await;
+        }
+
+        log::debug!("Hedging E3 request after {}ms", delay.as_millis());
+        let hedge = self.timed(operation());
+        tokio::pin!(hedge);
+        // Take whichever attempt succeeds first, and only fail if both do
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) => Ok(response),
+                Err(_) => hedge.await,
+            },
+            result = &mut hedge => match result {
+                Ok(response) => Ok(response),
+                Err(_) => primary.await,
+            },
+        }
+    }
+
+    fn hedge_delay(&self) -> Option<Duration> {
+        let hedging = self.hedging.as_ref()?;
+        let delay = self.latency.percentile(hedging.percentile)?;
+        Some(delay.max(Duration::from_millis(hedging.min_delay_ms)))
+    }
+
+    async fn timed<T>(
+        &self,
+        attempt: impl Future<Output = Result<T, E3Error>>,
+    ) -> Result<T, E3Error> {
+        let started_at = Instant::now();
+        let result = attempt.await;
+        if result.is_ok() {
+            self.latency.record(started_at.elapsed());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_transient, E3Resilience, E3ResilienceConfig, E3RetryConfig, HedgingConfig,
+        LatencyTracker, RetryBudget,
+    };
+    use crate::base_tls_client::{ClientError, TimeoutKind};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn config(retry_budget_percent: u32, min_retries_per_second: u32) -> E3ResilienceConfig {
+        E3ResilienceConfig {
+            retry_budget_percent,
+            min_retries_per_second,
+            hedging: None,
+            retry: E3RetryConfig {
+                initial_backoff_ms: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_retries_are_limited_to_a_share_of_requests() {
+        let budget = RetryBudget::new(&config(10, 0));
+        assert!(!budget.try_spend());
+        for _ in 0..20 {
+            budget.record_request();
+        }
+        assert!(budget.try_spend());
+        assert!(budget.try_spend());
+        assert!(!budget.try_spend());
+
+        // Requests and retries age out of the window together
+        budget.window.lock().unwrap().started_at -= Duration::from_secs(11);
+        assert!(!budget.try_spend());
+
+        let budget = RetryBudget::new(&config(0, 1));
+        for _ in 0..10 {
+            assert!(budget.try_spend());
+        }
+        assert!(!budget.try_spend());
+    }
+
+    #[test]
+    fn test_percentile_needs_enough_samples() {
+        let tracker = LatencyTracker::new();
+        for millis in 1..=10 {
+            tracker.record(Duration::from_millis(millis));
+        }
+        assert_eq!(tracker.percentile(95), None);
+        for millis in 11..=100 {
+            tracker.record(Duration::from_millis(millis));
+        }
+        assert_eq!(tracker.percentile(95), Some(Duration::from_millis(95)));
+        assert_eq!(tracker.percentile(50), Some(Duration::from_millis(50)));
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_retried_once_the_budget_is_spent() {
+        let resilience = E3Resilience::new(&config(0, 0));
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = resilience
+            .call(2, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::IoError(
+                    std::io::ErrorKind::ConnectionRefused.into(),
+                ))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let resilience = E3Resilience::new(&config(0, 1));
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = resilience
+            .call(2, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::IoError(
+                    std::io::ErrorKind::ConnectionRefused.into(),
+                ))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_slow_requests_are_hedged() {
+        let resilience = E3Resilience::new(&E3ResilienceConfig {
+            hedging: Some(HedgingConfig::default()),
+            ..config(20, 5)
+        });
+        for _ in 0..20 {
+            resilience.latency.record(Duration::from_millis(20));
+        }
+
+        let attempts = AtomicUsize::new(0);
+        let started_at = Instant::now();
+        let result = resilience
+            .call(0, || async {
+                // The first attempt hangs, and the hedge returns straight away
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Ok("primary")
+                } else {
+                    Ok("hedge")
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "hedge");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_only_transient_failures_are_retried() {
+        let resilience = E3Resilience::new(&config(0, 5));
+        let attempts = AtomicUsize::new(0);
+        let result = resilience
+            .call(5, || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(ClientError::IoError(
+                        std::io::ErrorKind::ConnectionRefused.into(),
+                    )),
+                    1 => Err(ClientError::FailedRequest(hyper::StatusCode::BAD_GATEWAY)),
+                    _ => Ok("encrypted"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "encrypted");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = resilience
+            .call(5, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::FailedRequest(
+                    hyper::StatusCode::UNPROCESSABLE_ENTITY,
+                ))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // Attempts stop at the configured maximum
+        let attempts = AtomicUsize::new(0);
+        let result: Result<(), _> = resilience
+            .call(5, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(ClientError::FailedRequest(
+                    hyper::StatusCode::SERVICE_UNAVAILABLE,
+                ))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(!is_transient(&ClientError::General("bad".to_string())));
+        assert!(is_transient(&ClientError::Timeout(TimeoutKind::Connect)));
+        assert!(!is_transient(&ClientError::Timeout(TimeoutKind::Request)));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let retry = E3RetryConfig {
+            max_attempts: 6,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 500,
+        };
+        let ceilings = [100, 200, 400, 500, 500];
+        let delays: Vec<_> = retry.backoff().collect();
+        assert_eq!(delays.len(), ceilings.len());
+        for (delay, ceiling) in delays.into_iter().zip(ceilings) {
+            assert!(delay <= Duration::from_millis(ceiling));
+        }
+        let single_attempt = E3RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        assert_eq!(single_attempt.backoff().count(), 0);
+    }
+}
@@ -10,10 +10,13 @@ use crate::config_client::ConfigClient;
 use crate::{base_tls_client::ClientError, ContextError};
 use hyper::header::InvalidHeaderValue;
 use serde_json::json;
+use shared::logging::AuditEventType;
 use shared::server::config_server::requests::Secret;
 use thiserror::Error;
+use zeroize::Zeroizing;
 
 use crate::e3client::{CryptoRequest, CryptoResponse, E3Api, E3Client};
+use crate::utils::audit::record_audit_event;
 
 #[derive(Debug, Error)]
 pub enum EnvError {
@@ -66,9 +69,7 @@ impl Environment {
         if !encrypted_env.is_empty() {
             let e3_response: CryptoResponse = self
                 .e3_client
-                .decrypt(CryptoRequest {
-                    data: json!(encrypted_env.clone()),
-                })
+                .decrypt(CryptoRequest::new(json!(encrypted_env.clone())))
                 .await?;
             let mut decrypted_env: Vec<Secret> = serde_json::from_value(e3_response.data)?;
             decrypted_env.append(&mut plaintext_env);
@@ -88,23 +89,38 @@ impl Environment {
         let token = self.config_client.get_cert_token().await.unwrap().token();
         let secrets_response = self.cert_provisioner_client.get_secrets(token).await?;
         EnclaveContext::set(secrets_response.context.clone().into());
+        if let Some(e3_identity) = secrets_response.e3_identity.clone() {
+            crate::base_tls_client::E3CertVerifier::set_identity(e3_identity);
+        }
 
         self.init(secrets_response.clone().secrets).await?;
 
         //Write vars to indicate enclave is initialised
         let _ = Self::write_startup_complete_env_vars();
+        crate::health::mark_initialized();
 
         Ok(())
     }
 
     fn write_env_file(self, secrets: Vec<Secret>) -> Result<(), EnvError> {
         let mut file = File::create("/etc/customer-env")?;
+        secrets.iter().for_each(|env| {
+            record_audit_event(AuditEventType::SecretRead, &env.name, "environment_init")
+        });
 
-        let env_string = secrets
+        // Sized up front, so the string is never reallocated and doesn't leave unzeroized copies of secrets behind
+        let env_len = secrets
             .iter()
-            .map(|env| format!("export {}={}  ", env.name, env.secret))
-            .collect::<Vec<String>>()
-            .join("");
+            .map(|env| "export =  ".len() + env.name.len() + env.secret.len())
+            .sum();
+        let mut env_string = Zeroizing::new(String::with_capacity(env_len));
+        for env in &secrets {
+            env_string.push_str("export ");
+            env_string.push_str(&env.name);
+            env_string.push('=');
+            env_string.push_str(&env.secret);
+            env_string.push_str("  ");
+        }
 
         file.write_all(env_string.as_bytes())?;
         Ok(())
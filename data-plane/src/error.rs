@@ -1,4 +1,5 @@
 use hyper::header::{InvalidHeaderName, InvalidHeaderValue};
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use shared::{logging::TrxContextBuilderError, server::error::ServerError};
 use thiserror::Error;
 
@@ -12,14 +13,18 @@ pub enum AuthError {
     FailedToAuthenticateApiKey,
 }
 
+impl HasErrorCode for AuthError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::NoApiKeyGiven => codes::MISSING_API_KEY,
+            Self::FailedToAuthenticateApiKey => codes::INVALID_API_KEY,
+        }
+    }
+}
+
 impl From<AuthError> for hyper::Response<hyper::Body> {
     fn from(err: AuthError) -> Self {
-        let msg = err.to_string();
-        hyper::Response::builder()
-            .status(401)
-            .header("content-length", msg.len())
-            .body(msg.into())
-            .expect("Failed to build auth error to response")
+        err.to_error_response()
     }
 }
 
@@ -43,6 +48,8 @@ pub enum Error {
     Hyper(#[from] hyper::Error),
     #[error("An error occurred — {0}")]
     ConfigServer(String),
+    #[error("Config server is shedding load, retry after {0:?}")]
+    ConfigServerThrottled(Option<std::time::Duration>),
     #[error("An error occurred requesting intermediate cert from the cert provisioner — {0}")]
     CertServer(String),
     #[error("Could not create header value — {0}")]
@@ -81,3 +88,35 @@ pub enum Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl HasErrorCode for Error {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::Crypto(_) => codes::CRYPTO_FAILED,
+            Self::Network(e) => e.error_code(),
+            Self::Io(_) | Self::Hyper(_) => codes::CONNECTION_FAILED,
+            #[cfg(feature = "network_egress")]
+            Self::DNS(e) => e.error_code(),
+            Self::Auth(e) => e.error_code(),
+            #[cfg(feature = "tls_termination")]
+            Self::ParseError(_) => codes::INVALID_CIPHERTEXT,
+            Self::ConfigServer(_) | Self::ConfigServerThrottled(_) | Self::CertServer(_) => {
+                codes::UPSTREAM_FAILED
+            }
+            Self::ClientError(e) => e.error_code(),
+            Self::SerdeError(_) | Self::FromUtf8Error(_) => codes::INVALID_PAYLOAD,
+            Self::EnvError(_) | Self::ContextError(_) => codes::CONFIG_MISSING,
+            Self::MissingApiKey => codes::MISSING_API_KEY,
+            Self::ApiKeyInvalid | Self::NonHttpAuthError => codes::INVALID_API_KEY,
+            Self::AttestationRequestError(_) => codes::ATTESTATION_FAILED,
+            #[cfg(feature = "enclave")]
+            Self::NsmConnectionError(_) => codes::ATTESTATION_FAILED,
+            Self::RequestTimeout(_) => codes::TIMEOUT,
+            Self::InvalidHeaderValue(_)
+            | Self::InvalidHeaderName(_)
+            | Self::HyperError(_)
+            | Self::TrxContextBuilderError(_)
+            | Self::FailedToSendTrxLog(_) => codes::INTERNAL,
+        }
+    }
+}
@@ -31,6 +31,10 @@ impl HealthcheckStatusRequest {
         let (sender, receiver) = oneshot_channel();
         (Self { sender }, receiver)
     }
+
+    pub fn respond(self, health: UserProcessHealth) {
+        let _ = self.sender.send(health);
+    }
 }
 
 pub type UserProcessHealthcheckSender = UnboundedSender<HealthcheckStatusRequest>;
@@ -184,7 +188,7 @@ impl<C: Connect + Clone + Send + Sync + 'static> HealthcheckAgent<C> {
 
     fn serve_healthcheck_request(&mut self, request: HealthcheckStatusRequest) {
         if self.buffer.is_empty() {
-            let _ = request.sender.send(UserProcessHealth::Unknown(
+            request.respond(UserProcessHealth::Unknown(
                 "Enclave is not initialized yet".to_string(),
             ));
             return;
@@ -192,7 +196,7 @@ impl<C: Connect + Clone + Send + Sync + 'static> HealthcheckAgent<C> {
 
         // Safety: Iterator checked to be non-empty at start of func
         let max_result = self.buffer.iter().max().unwrap();
-        let _ = request.sender.send(max_result.to_owned());
+        request.respond(max_result.to_owned());
     }
 
     pub async fn run(mut self) {
@@ -219,12 +223,18 @@ impl<C: Connect + Clone + Send + Sync + 'static> HealthcheckAgent<C> {
 
     async fn probe_user_process(&self, healthcheck_path: &str) -> UserProcessHealth {
         let healthcheck_uri = self.build_healthcheck_uri(healthcheck_path);
-        let req = Request::builder()
+        let req = match Request::builder()
             .method(Method::GET)
             .uri(&healthcheck_uri)
             .header(header::USER_AGENT, "Evervault-Healthcheck-Agent")
             .body(Body::empty())
-            .expect("Failed to create user process healthcheck request");
+        {
+            Ok(req) => req,
+            Err(e) => {
+                log::error!("Couldn't build user process healthcheck request - {e}");
+                return UserProcessHealth::Error(e.to_string());
+            }
+        };
         log::debug!("Probing user process from healthcheck agent - {healthcheck_uri}");
         match self.client.request(req).await {
             Ok(res) => {
@@ -3,14 +3,31 @@ mod agent;
 use agent::UserProcessHealthcheckSender;
 
 use hyper::header;
-use hyper::{service::service_fn, Body, Response};
+use hyper::{service::service_fn, Body, Request, Response};
+use shared::error_code::{codes, ErrorBody};
 use shared::server::get_vsock_server;
-use shared::server::health::{DataPlaneDiagnostic, DataPlaneState, UserProcessHealth};
+use shared::server::health::{
+    DataPlaneDiagnostic, DataPlaneState, HealthCheck, HealthProbe, LivenessReport, ReadinessCheck,
+    ReadinessReport, UserProcessHealth, EGRESS_DEBUG_PATH,
+};
+use shared::server::plane_version::{PlaneVersion, VERSION_PATH};
 use shared::server::CID::Enclave;
 use shared::{server::Listener, ENCLAVE_HEALTH_CHECK_PORT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::e3client::{E3Api, E3Client};
 use crate::health::agent::{HealthcheckAgent, HealthcheckStatusRequest};
 
+/// Set once the enclave's secrets and certs have been provisioned
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+const E3_READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn mark_initialized() {
+    INITIALIZED.store(true, Ordering::Release);
+}
+
 fn spawn_customer_healthcheck_agent(
     customer_process_port: u16,
     healthcheck: Option<String>,
@@ -37,6 +54,7 @@ pub async fn start_health_check_server(
 ) {
     let user_process_healthcheck_channel =
         spawn_customer_healthcheck_agent(customer_process_port, healthcheck, use_tls);
+    let e3_client = Arc::new(E3Client::new());
     let mut health_check_server = get_vsock_server(ENCLAVE_HEALTH_CHECK_PORT, Enclave)
         .await
         .unwrap();
@@ -52,19 +70,37 @@ pub async fn start_health_check_server(
         };
 
         let user_process_channel = user_process_healthcheck_channel.clone();
-        let service = service_fn(move |_| {
+        let e3_client = e3_client.clone();
+        let service = service_fn(move |request: Request<Body>| {
             let user_process_channel = user_process_channel.clone();
+            let e3_client = e3_client.clone();
             async move {
+                if request.uri().path() == EGRESS_DEBUG_PATH {
+                    return egress_debug_response();
+                }
+                if request.uri().path() == VERSION_PATH {
+                    let version = PlaneVersion::current(env!("CARGO_PKG_VERSION"));
+                    return serialized_response(200, "application/json", &version);
+                }
+                match HealthProbe::from_path(request.uri().path()) {
+                    Some(HealthProbe::Liveness) => {
+                        return json_response(&LivenessReport { alive: true });
+                    }
+                    Some(HealthProbe::Readiness) => {
+                        let report =
+                            check_readiness(&user_process_channel, e3_client.as_ref()).await;
+                        return json_response(&report);
+                    }
+                    None => {}
+                }
+
                 let user_process_health = check_user_process_health(&user_process_channel).await;
 
                 let result = DataPlaneState::Initialized(DataPlaneDiagnostic {
                     user_process: user_process_health,
                 });
 
-                Response::builder()
-                    .status(200)
-                    .header(header::CONTENT_TYPE, "application/json;version=1")
-                    .body(Body::from(serde_json::to_string(&result).unwrap()))
+                serialized_response(200, "application/json;version=1", &result)
             }
         });
 
@@ -78,6 +114,86 @@ pub async fn start_health_check_server(
     }
 }
 
+fn json_response<T: serde::Serialize + HealthCheck>(
+    report: &T,
+) -> Result<Response<Body>, hyper::http::Error> {
+    serialized_response(report.status_code(), "application/json", report)
+}
+
+/// Serializes the body of a response, falling back to a 500 if it can't be serialized.
+fn serialized_response<T: serde::Serialize + ?Sized>(
+    status: u16,
+    content_type: &str,
+    body: &T,
+) -> Result<Response<Body>, hyper::http::Error> {
+    match serde_json::to_string(body) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body)),
+        Err(e) => {
+            log::error!("Failed to serialize health check response - {e}");
+            Ok(
+                ErrorBody::new(codes::INTERNAL, "Failed to serialize response")
+                    .into_response(codes::INTERNAL.status),
+            )
+        }
+    }
+}
+
+/// The live egress policy and DNS cache, so operators can check what egress is allowed.
+#[cfg(feature = "network_egress")]
+fn egress_debug_response() -> Result<Response<Body>, hyper::http::Error> {
+    use crate::dns::egress_policy::EgressPolicy;
+    use shared::error_code::HasErrorCode;
+
+    match EgressPolicy::get().map(EgressPolicy::debug_report) {
+        Some(Ok(report)) => serialized_response(200, "application/json", &report),
+        Some(Err(e)) => Ok(e.to_error_response()),
+        None => Ok(
+            ErrorBody::new(codes::NOT_FOUND, "Egress hasn't been set up yet")
+                .into_response(codes::NOT_FOUND.status),
+        ),
+    }
+}
+
+#[cfg(not(feature = "network_egress"))]
+fn egress_debug_response() -> Result<Response<Body>, hyper::http::Error> {
+    Ok(ErrorBody::new(
+        codes::NOT_FOUND,
+        "This data plane was built without the network_egress feature",
+    )
+    .into_response(codes::NOT_FOUND.status))
+}
+
+/// The data plane is ready once it's been provisioned, E3 can be reached and the user process is healthy.
+async fn check_readiness<E: E3Api + Sync>(
+    user_process_channel: &UserProcessHealthcheckSender,
+    e3_client: &E,
+) -> ReadinessReport {
+    let initialized = INITIALIZED.load(Ordering::Acquire);
+    let e3 = if !initialized {
+        // E3's identity isn't known until the enclave has been provisioned
+        ReadinessCheck::not_ready("e3", "Not checked until the enclave is initialized")
+    } else {
+        match tokio::time::timeout(E3_READINESS_TIMEOUT, e3_client.check_reachable()).await {
+            Ok(Ok(())) => ReadinessCheck::ready("e3"),
+            Ok(Err(e)) => ReadinessCheck::not_ready("e3", format!("E3 is unreachable - {e}")),
+            Err(_) => ReadinessCheck::not_ready("e3", "Timed out connecting to E3"),
+        }
+    };
+    let initialized = if initialized {
+        ReadinessCheck::ready("initialized")
+    } else {
+        ReadinessCheck::not_ready("initialized", "Secrets and certs are being provisioned")
+    };
+    let user_process = match check_user_process_health(user_process_channel).await {
+        health if health.is_healthy() => ReadinessCheck::ready("user_process"),
+        health => ReadinessCheck::not_ready("user_process", format!("{health:?}")),
+    };
+    ReadinessReport::new(vec![initialized, e3, user_process])
+}
+
 async fn check_user_process_health(channel: &UserProcessHealthcheckSender) -> UserProcessHealth {
     let (request, receiver) = HealthcheckStatusRequest::new();
     if let Err(e) = channel.send(request) {
@@ -93,3 +209,41 @@ async fn check_user_process_health(channel: &UserProcessHealthcheckSender) -> Us
         )),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_readiness, mark_initialized};
+    use crate::base_tls_client::ClientError;
+    use crate::e3client::mock::MockE3TestClient;
+    use crate::health::agent::HealthcheckStatusRequest;
+    use shared::server::health::UserProcessHealth;
+
+    #[tokio::test]
+    async fn test_readiness_reports_each_unready_check() {
+        let (channel, mut requests) =
+            tokio::sync::mpsc::unbounded_channel::<HealthcheckStatusRequest>();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                request.respond(UserProcessHealth::Response {
+                    status_code: 200,
+                    body: None,
+                });
+            }
+        });
+        let mut e3_client = MockE3TestClient::new();
+        e3_client
+            .expect_check_reachable()
+            .returning(|| Err(ClientError::General("connection refused".to_string())));
+
+        mark_initialized();
+        let report = check_readiness(&channel, &e3_client).await;
+        assert!(!report.ready);
+        let unready: Vec<_> = report
+            .checks
+            .iter()
+            .filter(|check| !check.ready)
+            .map(|check| check.name.as_str())
+            .collect();
+        assert_eq!(unready, vec!["e3"]);
+    }
+}
@@ -14,27 +14,45 @@ pub mod cert_provisioner_client;
 pub mod config_client;
 pub mod configuration;
 pub mod connection;
+pub mod crash;
 pub mod crypto;
 pub mod dns;
 pub mod e3client;
 pub mod env;
 pub mod error;
 pub mod health;
+pub mod session;
+pub mod startup;
 pub mod stats;
 pub mod stats_client;
+pub mod tcp_passthrough;
 pub mod time;
 pub mod utils;
 #[cfg(feature = "network_egress")]
+use dns::egress_encryption::EgressFieldEncryptionConfig;
+#[cfg(feature = "network_egress")]
+use dns::enclavedns::DnsProxyConfig;
+#[cfg(feature = "network_egress")]
 use shared::server::egress::EgressConfig;
 #[cfg(feature = "tls_termination")]
 pub mod server;
 
+use base_tls_client::pool::ConnectionPoolConfig;
+use cache::{AuthCacheConfig, DecryptCacheConfig};
+use cert_provisioner_client::ProvisionerIdentityConfig;
+use crypto::quota::QuotaConfig;
+use e3client::resilience::E3ResilienceConfig;
+#[cfg(feature = "tls_termination")]
+use server::routing::IngressRoute;
+use shared::runtime::RuntimeConfig;
 use shared::server::config_server::requests::ProvisionerContext;
 use thiserror::Error;
 
 static ENCLAVE_CONTEXT: OnceCell<EnclaveContext> = OnceCell::new();
 static FEATURE_CONTEXT: OnceCell<FeatureContext> = OnceCell::new();
 
+pub const FEATURE_CONTEXT_PATH: &str = "/etc/dataplane-config.json";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EnclaveContext {
     team_uuid: String,
@@ -148,7 +166,7 @@ impl From<ProvisionerContext> for EnclaveContext {
     }
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct FeatureContext {
     pub api_key_auth: bool,
     pub healthcheck: Option<String>,
@@ -159,6 +177,45 @@ pub struct FeatureContext {
     pub trusted_headers: Vec<String>,
     #[cfg(feature = "network_egress")]
     pub egress: EgressConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub decrypt_cache: Option<DecryptCacheConfig>,
+    #[serde(default)]
+    pub auth_cache: Option<AuthCacheConfig>,
+    #[serde(default)]
+    pub crypto_api_quotas: Option<QuotaConfig>,
+    #[serde(default)]
+    pub provisioner_identity: Option<ProvisionerIdentityConfig>,
+    #[cfg(feature = "network_egress")]
+    #[serde(default)]
+    pub egress_field_encryption: Option<EgressFieldEncryptionConfig>,
+    #[serde(default)]
+    pub e3_resilience: E3ResilienceConfig,
+    #[serde(default)]
+    pub e3_connection_pool: ConnectionPoolConfig,
+    #[cfg(feature = "network_egress")]
+    #[serde(default)]
+    pub dns_proxy: DnsProxyConfig,
+    #[cfg(feature = "tls_termination")]
+    #[serde(default)]
+    pub ingress_routes: Vec<IngressRoute>,
+    /// Customer domains to order publicly trusted certs for alongside the enclave's own domains. They need to point at
+    /// the enclave, and be allowed by the control plane's `EV_CUSTOM_DOMAINS`.
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+    /// Send requests received over HTTP/2 to the customer process over HTTP/2 with prior knowledge, rather than
+    /// downgrading them to HTTP/1.1.
+    #[serde(default)]
+    pub forward_http2: bool,
+    /// Stream gRPC requests received over HTTP/2 to the customer process over HTTP/2 untouched, skipping decryption of
+    /// their bodies, so streaming RPCs and trailers work end to end.
+    #[serde(default)]
+    pub grpc_passthrough: bool,
+    /// Ports whose connections are piped to the same port on the customer process as raw TCP, without terminating
+    /// TLS. The control plane has to listen on them too, through `EV_TCP_PASSTHROUGH_PORTS`.
+    #[serde(default)]
+    pub tcp_passthrough_ports: Vec<u16>,
 }
 
 impl FeatureContext {
@@ -176,15 +233,23 @@ impl FeatureContext {
     }
 
     fn read_dataplane_context() -> Result<FeatureContext, ContextError> {
-        let feature_context_file_contents = fs::read_to_string("/etc/dataplane-config.json")?;
-        let mut feature_context: FeatureContext =
-            serde_json::from_str(&feature_context_file_contents)?;
+        let feature_context_file_contents = fs::read_to_string(FEATURE_CONTEXT_PATH)?;
+        Self::from_json(&feature_context_file_contents)
+    }
+
+    pub fn from_json(feature_context_json: &str) -> Result<FeatureContext, ContextError> {
+        let mut feature_context: FeatureContext = serde_json::from_str(feature_context_json)?;
         // map trusted headers to lowercase
         feature_context.trusted_headers = feature_context
             .trusted_headers
             .iter()
             .map(|header| header.to_lowercase())
             .collect();
+        feature_context.custom_domains = feature_context
+            .custom_domains
+            .iter()
+            .map(|domain| domain.to_lowercase())
+            .collect();
         Ok(feature_context)
     }
 }
@@ -203,6 +268,66 @@ mod test {
         assert_eq!(feature_context.trx_logging_enabled, false);
         assert_eq!(feature_context.forward_proxy_protocol, false);
         assert!(feature_context.healthcheck.is_none());
+        assert_eq!(feature_context.runtime, Default::default());
+    }
+
+    #[cfg(not(feature = "network_egress"))]
+    #[test]
+    fn test_config_deserialization_with_runtime_config() {
+        let raw_feature_context = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "runtime": { "worker_threads": 4, "event_interval": 31 } }"#;
+        let parsed = serde_json::from_str(raw_feature_context);
+        assert!(parsed.is_ok());
+        let feature_context: FeatureContext = parsed.unwrap();
+        assert_eq!(feature_context.runtime.worker_threads, Some(4));
+        assert_eq!(feature_context.runtime.max_blocking_threads, None);
+        assert_eq!(feature_context.runtime.event_interval, Some(31));
+        assert!(feature_context.decrypt_cache.is_none());
+    }
+
+    #[cfg(not(feature = "network_egress"))]
+    #[test]
+    fn test_config_deserialization_with_decrypt_cache() {
+        let raw_feature_context = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "decrypt_cache": { "ttl_seconds": 30, "max_entries": 500 } }"#;
+        let parsed = serde_json::from_str(raw_feature_context);
+        assert!(parsed.is_ok());
+        let feature_context: FeatureContext = parsed.unwrap();
+        let decrypt_cache = feature_context.decrypt_cache.unwrap();
+        assert_eq!(decrypt_cache.ttl_seconds, 30);
+        assert_eq!(decrypt_cache.max_entries, 500);
+        assert!(feature_context.auth_cache.is_none());
+    }
+
+    #[cfg(not(feature = "network_egress"))]
+    #[test]
+    fn test_config_deserialization_with_crypto_api_quotas() {
+        let raw_feature_context = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "crypto_api_quotas": { "requests_per_minute": 600 } }"#;
+        let feature_context: FeatureContext = serde_json::from_str(raw_feature_context).unwrap();
+        let quotas = feature_context.crypto_api_quotas.unwrap();
+        assert_eq!(quotas.requests_per_minute, Some(600));
+        assert_eq!(quotas.bytes_per_day, None);
+        assert_eq!(quotas.max_tracked_keys, 10_000);
+    }
+
+    #[cfg(not(feature = "network_egress"))]
+    #[test]
+    fn test_config_deserialization_with_auth_cache() {
+        let raw_feature_context = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "auth_cache": { "ttl_seconds": 60, "max_entries": 100 } }"#;
+        let feature_context: FeatureContext = serde_json::from_str(raw_feature_context).unwrap();
+        let auth_cache = feature_context.auth_cache.unwrap();
+        assert_eq!(auth_cache.ttl_seconds, 60);
+        assert_eq!(auth_cache.max_entries, 100);
+        assert_eq!(auth_cache.negative_ttl_seconds, None);
+    }
+
+    #[cfg(not(feature = "network_egress"))]
+    #[test]
+    fn test_config_deserialization_with_custom_domains() {
+        let raw_feature_context = r#"{ "api_key_auth": true, "trx_logging_enabled": false, "forward_proxy_protocol": false, "trusted_headers": [], "custom_domains": ["Payments.Example.com"] }"#;
+        let feature_context = FeatureContext::from_json(raw_feature_context).unwrap();
+        assert_eq!(
+            feature_context.custom_domains,
+            vec!["payments.example.com".to_string()]
+        );
     }
 
     #[cfg(not(feature = "network_egress"))]
@@ -237,6 +362,7 @@ mod test {
             feature_context.egress.allow_list.wildcard,
             vec![".stripe.com".to_string()]
         );
+        assert_eq!(feature_context.egress.ports, Some(vec![443, 8080]));
     }
 
     #[cfg(feature = "network_egress")]
@@ -3,6 +3,7 @@ use error::Result;
 use mockall::mock;
 use shared::server::config_server::requests::GetClockSyncResponse;
 use shared::server::config_server::requests::GetObjectResponse;
+use zeroize::Zeroizing;
 
 use crate::{config_client::StorageConfigClientInterface, error};
 
@@ -13,7 +14,7 @@ mock! {
   #[async_trait]
   impl StorageConfigClientInterface for StorageConfigClientInterface {
       async fn get_object(&self, key: String) -> Result<Option<GetObjectResponse>>;
-      async fn put_object(&self, key: String, object: String) -> Result<()>;
+      async fn put_object(&self, key: String, object: Zeroizing<String>) -> Result<()>;
       async fn delete_object(&self, key: String) -> Result<()>;
       async fn get_time_from_host(&self) -> Result<GetClockSyncResponse>;
   }
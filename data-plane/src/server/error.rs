@@ -19,6 +19,7 @@ pub enum TlsError {
     SignError(#[from] SignError),
     PemError(#[from] pem::PemError),
     CertProvisionerError(String),
+    InvalidTlsConfig(String),
     ContextError(#[from] ContextError),
     SystemTimeError(#[from] SystemTimeError),
     TryFromIntError(#[from] TryFromIntError),
@@ -2,13 +2,22 @@ pub mod parse;
 
 use bytes::Bytes;
 use hyper::{
-    http::header::{HeaderName, HeaderValue, InvalidHeaderName},
+    http::header::{HeaderName, HeaderValue},
     Body, HeaderMap, Request, Response,
 };
 use std::str::FromStr;
 
 pub struct RemoteIp(pub String);
 
+/// Marks requests to be sent to the customer process over HTTP/2 with prior knowledge, rather than HTTP/1.1.
+#[derive(Clone, Copy, Debug)]
+pub struct ForwardHttp2;
+
+/// Marks gRPC requests to be streamed to the customer process untouched, skipping body decryption so streaming RPCs
+/// aren't buffered and trailers make it through.
+#[derive(Clone, Copy, Debug)]
+pub struct GrpcPassthrough;
+
 pub enum EncodingError {
     UnknownEncoding,
 }
@@ -90,35 +99,108 @@ pub async fn response_to_bytes(response: Response<Body>) -> Vec<u8> {
     bytes
 }
 
-fn build_header_value_from_str(header_val: &str) -> HeaderValue {
-    HeaderValue::from_str(header_val).expect("Unable to create HeaderValue from str")
-}
-
 pub fn append_or_insert_header(
     header: &str,
     header_map: &mut HeaderMap,
     value: &str,
-) -> std::result::Result<(), InvalidHeaderName> {
+) -> std::result::Result<(), hyper::http::Error> {
     let header_name = HeaderName::from_str(header)?;
     if let Some(header_val) = header_map
         .get(&header_name)
         .and_then(|header_val| header_val.to_str().ok())
     {
         let updated_header = format!("{header_val}, {value}");
-        header_map.insert(header_name, build_header_value_from_str(&updated_header));
+        header_map.insert(header_name, HeaderValue::from_str(&updated_header)?);
     } else {
-        header_map.insert(header_name, build_header_value_from_str(value));
+        header_map.insert(header_name, HeaderValue::from_str(value)?);
     }
     Ok(())
 }
 
-fn add_remote_ip_to_forwarded_for_header(header_map: &mut HeaderMap, remote_ip: &str) {
-    let _ = append_or_insert_header("X-Forwarded-For", header_map, remote_ip);
+/// Record the hop from the client to the enclave in `X-Forwarded-For`, `X-Forwarded-Proto` and `Forwarded`, appending
+/// to any values the client sent as proxies conventionally do. The client's address is only known when the connection
+/// carried a proxy protocol header, so without it only the protocol is recorded.
+fn add_forwarded_headers(header_map: &mut HeaderMap, remote_ip: Option<&str>) {
     let _ = append_or_insert_header("X-Forwarded-Proto", header_map, "https");
-    let forwarded_header = format!("for={remote_ip};proto=https");
+    let forwarded_header = match remote_ip {
+        Some(remote_ip) => {
+            let _ = append_or_insert_header("X-Forwarded-For", header_map, remote_ip);
+            format!("for={};proto=https", forwarded_node(remote_ip))
+        }
+        None => "proto=https".to_string(),
+    };
     let _ = append_or_insert_header("Forwarded", header_map, &forwarded_header);
 }
 
+/// IPv6 addresses have to be bracketed and quoted in a `Forwarded` node, as their colons aren't valid in a token.
+fn forwarded_node(remote_ip: &str) -> String {
+    if remote_ip.contains(':') {
+        format!("\"[{remote_ip}]\"")
+    } else {
+        remote_ip.to_string()
+    }
+}
+
+/// Give a request received over HTTP/2 the same shape as one parsed from an HTTP/1.1 stream, so it can go through the
+/// same layers. The `:authority` pseudo header becomes the Host header, the URI points at the customer process and the
+/// request is downgraded to HTTP/1.1 unless it's to be forwarded over HTTP/2. gRPC requests are always forwarded over
+/// HTTP/2 when gRPC passthrough is on.
+pub fn prepare_http2_request(
+    request: &mut Request<Body>,
+    target_port: u16,
+    remote_ip: Option<&str>,
+    forward_http2: bool,
+    grpc_passthrough: bool,
+) {
+    if !request.headers().contains_key(hyper::header::HOST) {
+        if let Some(authority) = request
+            .uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        {
+            request.headers_mut().insert(hyper::header::HOST, authority);
+        }
+    }
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    if let Ok(uri) = format!("http://127.0.0.1:{target_port}{path_and_query}").parse() {
+        *request.uri_mut() = uri;
+    }
+    let passthrough = grpc_passthrough && is_grpc_request(request);
+    if passthrough {
+        request.extensions_mut().insert(GrpcPassthrough);
+    }
+    if forward_http2 || passthrough {
+        request.extensions_mut().insert(ForwardHttp2);
+    } else {
+        *request.version_mut() = hyper::Version::HTTP_11;
+    }
+    add_forwarded_headers(request.headers_mut(), remote_ip);
+    if let Some(remote_ip) = remote_ip {
+        request
+            .extensions_mut()
+            .insert(RemoteIp(remote_ip.to_string()));
+    }
+}
+
+/// gRPC requests are identified by their content type, which is `application/grpc` with an optional `+proto` style
+/// suffix naming the message encoding.
+pub fn is_grpc_request(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            let content_type = content_type.to_ascii_lowercase();
+            content_type == "application/grpc"
+                || content_type.starts_with("application/grpc+")
+                || content_type.starts_with("application/grpc;")
+        })
+}
+
 pub fn build_internal_error_response(msg: Option<String>) -> hyper::Response<hyper::Body> {
     let response_body = serde_json::json!({
       "message": msg.unwrap_or_else(|| "An internal error occurred. Please contact support.".into())
@@ -131,3 +213,111 @@ pub fn build_internal_error_response(msg: Option<String>) -> hyper::Response<hyp
         .body(Body::from(response_body))
         .expect("Infallible - hardcoded response")
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        add_forwarded_headers, append_or_insert_header, prepare_http2_request, ForwardHttp2,
+        GrpcPassthrough, RemoteIp,
+    };
+    use hyper::{Body, HeaderMap, Request, Version};
+
+    #[test]
+    fn test_header_values_are_appended() {
+        let mut headers = HeaderMap::new();
+        append_or_insert_header("X-Forwarded-For", &mut headers, "1.1.1.1").unwrap();
+        append_or_insert_header("X-Forwarded-For", &mut headers, "2.2.2.2").unwrap();
+        assert_eq!(headers.get("X-Forwarded-For").unwrap(), "1.1.1.1, 2.2.2.2");
+    }
+
+    #[test]
+    fn test_invalid_header_values_are_rejected() {
+        let mut headers = HeaderMap::new();
+        assert!(append_or_insert_header("Forwarded", &mut headers, "for=\r\n").is_err());
+        assert!(append_or_insert_header("Bad Header", &mut headers, "value").is_err());
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_forwarded_headers_record_the_client_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        add_forwarded_headers(&mut headers, Some("1.1.1.1"));
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "10.0.0.1, 1.1.1.1");
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "https");
+        assert_eq!(headers.get("forwarded").unwrap(), "for=1.1.1.1;proto=https");
+
+        let mut headers = HeaderMap::new();
+        add_forwarded_headers(&mut headers, Some("2001:db8::1"));
+        assert_eq!(
+            headers.get("forwarded").unwrap(),
+            "for=\"[2001:db8::1]\";proto=https"
+        );
+
+        let mut headers = HeaderMap::new();
+        add_forwarded_headers(&mut headers, None);
+        assert!(headers.get("x-forwarded-for").is_none());
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "https");
+        assert_eq!(headers.get("forwarded").unwrap(), "proto=https");
+    }
+
+    #[test]
+    fn test_http2_requests_are_downgraded_and_pointed_at_the_customer_process() {
+        let mut request = Request::builder()
+            .uri("https://api.example.com/payments?id=1")
+            .version(Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap();
+        prepare_http2_request(&mut request, 3000, Some("1.1.1.1"), false, false);
+        assert_eq!(
+            request.uri().to_string(),
+            "http://127.0.0.1:3000/payments?id=1"
+        );
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(request.headers().get("host").unwrap(), "api.example.com");
+        assert_eq!(request.headers().get("x-forwarded-for").unwrap(), "1.1.1.1");
+        assert!(request.extensions().get::<RemoteIp>().is_some());
+        assert!(request.extensions().get::<ForwardHttp2>().is_none());
+
+        let mut request = Request::builder()
+            .uri("https://api.example.com/")
+            .version(Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap();
+        prepare_http2_request(&mut request, 3000, None, true, false);
+        assert_eq!(request.version(), Version::HTTP_2);
+        assert!(request.extensions().get::<ForwardHttp2>().is_some());
+    }
+
+    #[test]
+    fn test_grpc_requests_are_passed_through_over_http2() {
+        let grpc_request = || {
+            Request::builder()
+                .uri("https://api.example.com/payments.Payments/Stream")
+                .version(Version::HTTP_2)
+                .header("content-type", "application/grpc+proto")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut request = grpc_request();
+        prepare_http2_request(&mut request, 3000, None, false, true);
+        assert_eq!(request.version(), Version::HTTP_2);
+        assert!(request.extensions().get::<ForwardHttp2>().is_some());
+        assert!(request.extensions().get::<GrpcPassthrough>().is_some());
+
+        let mut request = grpc_request();
+        prepare_http2_request(&mut request, 3000, None, false, false);
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert!(request.extensions().get::<GrpcPassthrough>().is_none());
+
+        let mut request = Request::builder()
+            .uri("https://api.example.com/")
+            .version(Version::HTTP_2)
+            .header("content-type", "application/grpc-web")
+            .body(Body::empty())
+            .unwrap();
+        prepare_http2_request(&mut request, 3000, None, false, true);
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert!(request.extensions().get::<GrpcPassthrough>().is_none());
+    }
+}
@@ -20,6 +20,8 @@ pub enum ParseError {
     Timeout(#[from] tokio::time::error::Elapsed),
     #[error(transparent)]
     Hyper(#[from] hyper::http::Error),
+    #[error("Invalid HTTP response - {0}")]
+    InvalidResponse(#[from] httparse::Error),
 }
 
 async fn read_from_stream<T: AsyncRead + Unpin>(
@@ -37,7 +39,31 @@ async fn read_from_stream<T: AsyncRead + Unpin>(
 pub fn is_websocket_request(req: &hyper::Request<hyper::Body>) -> bool {
     req.headers()
         .get("upgrade")
-        .is_some_and(|upgrade_proto| upgrade_proto == "websocket")
+        .is_some_and(|upgrade_proto| upgrade_proto.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+/// Read the head of a response from the customer process, returning its status code and every byte read so far. A
+/// websocket server can send its first frames straight after the `101` response, so bytes past the head are kept to be
+/// relayed along with it.
+pub async fn read_response_head<T: AsyncRead + Unpin>(
+    stream: &mut T,
+) -> Result<(u16, Vec<u8>), ParseError> {
+    let mut buffer = Vec::new();
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut res = httparse::Response::new(&mut headers);
+        let mut temp = [0u8; 1024];
+        let bytes_read = tokio::time::timeout(
+            std::time::Duration::from_secs(READ_TIMEOUT as u64),
+            read_from_stream(stream, &mut temp),
+        )
+        .await??;
+        buffer.extend_from_slice(&temp[..bytes_read]);
+
+        if let Status::Complete(_) = res.parse(&buffer)? {
+            return Ok((res.code.unwrap_or_default(), buffer));
+        }
+    }
 }
 
 pub enum Incoming {
@@ -112,9 +138,7 @@ fn build_header_map_for_request(
             hyper::http::HeaderValue::from_bytes(header.value)?,
         );
     }
-    if let Some(remote_ip) = remote_ip {
-        super::add_remote_ip_to_forwarded_for_header(&mut header_map, remote_ip);
-    }
+    super::add_forwarded_headers(&mut header_map, remote_ip);
     Ok(header_map)
 }
 
@@ -148,7 +172,7 @@ async fn read_incoming_body_from_stream<T: AsyncRead + Unpin>(
 
 #[cfg(test)]
 mod test {
-    use super::read_incoming_body_from_stream;
+    use super::{read_incoming_body_from_stream, read_response_head};
     use hyper;
 
     // Test to simulate repeated reads from the accepted connection
@@ -174,4 +198,16 @@ mod test {
         assert_eq!(payload.len(), content_length);
         assert_eq!(&payload, &[1u8, 2u8, 3u8, 4u8, 5u8]);
     }
+
+    #[tokio::test]
+    async fn test_reading_a_response_head_keeps_the_bytes_after_it() {
+        let mut io_mock = tokio_test::io::Builder::new()
+            .read(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: web")
+            .read(b"socket\r\nConnection: Upgrade\r\n\r\n\x81\x02hi")
+            .build();
+
+        let (status, bytes) = read_response_head(&mut io_mock).await.unwrap();
+        assert_eq!(status, 101);
+        assert!(bytes.ends_with(b"\r\n\r\n\x81\x02hi"));
+    }
 }
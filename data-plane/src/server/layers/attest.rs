@@ -1,13 +1,14 @@
 use hyper::http::{Request, Response};
 use hyper::Body;
-use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::pin::Pin;
 use tower::{Layer, Service};
 
-use crate::crypto::attest;
+use crate::crypto::api::{AttestationDocEnvelope, AttestationEncoding, CryptoApiError};
+use crate::crypto::attest::{self, AttestationDocMetadata};
 use crate::server::http::build_internal_error_response;
 use crate::server::tls::TRUSTED_PUB_CERT;
+use crate::utils::payload_format::PayloadFormat;
 
 #[derive(Clone)]
 pub struct AttestLayer;
@@ -20,11 +21,6 @@ impl<S> Layer<S> for AttestLayer {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct AttestationResponse {
-    attestation_doc: String,
-}
-
 #[derive(Clone)]
 pub struct AttestService<S> {
     inner: S,
@@ -57,27 +53,47 @@ where
             return Box::pin(inner.call(req));
         }
 
+        let response_format = PayloadFormat::accepted(req.headers());
+        let nonce = attestation_nonce(req.uri().query());
         Box::pin(async move {
-            let challenge = TRUSTED_PUB_CERT.get();
+            let challenge = TRUSTED_PUB_CERT.get().cloned();
 
-            let attestation_doc = match attest::get_attestation_doc(challenge.cloned(), None) {
+            let attestation_doc = match nonce {
+                Ok(Some(nonce)) => attest::get_attestation_doc(challenge, Some(nonce)),
+                Ok(None) => attest::get_cached_attestation_doc(challenge),
+                Err(e) => return Ok(e.into()),
+            };
+            let attestation_doc = match attestation_doc {
                 Ok(attestation_doc) => attestation_doc,
                 Err(e) => return Ok(e.into()),
             };
 
-            let base64_doc = base64::encode(attestation_doc);
-
-            let response = AttestationResponse {
-                attestation_doc: base64_doc,
+            let metadata = AttestationDocMetadata::from_doc(&attestation_doc)
+                .map_err(|e| log::warn!("Failed to read attestation doc metadata - {e}"))
+                .ok();
+
+            // CBOR clients receive the raw COSE document, rather than base64 wrapped in JSON
+            let response_payload = match response_format {
+                PayloadFormat::Cbor => attestation_doc,
+                PayloadFormat::Json => {
+                    let response = AttestationDocEnvelope::new(&attestation_doc)
+                        .with_metadata(metadata.as_ref());
+                    serde_json::to_vec(&response).expect("Infallible")
+                }
             };
 
-            let response_payload = serde_json::to_string(&response).expect("Infallible");
-            let attestation_response = Response::builder()
+            let mut attestation_response = Response::builder()
                 .status(200)
-                .header(hyper::http::header::CONTENT_TYPE, "application/json")
+                .header(
+                    hyper::http::header::CONTENT_TYPE,
+                    response_format.content_type(),
+                )
                 .header(hyper::http::header::CONTENT_LENGTH, response_payload.len())
                 .body(Body::from(response_payload))
                 .unwrap_or_else(|e| build_internal_error_response(Some(e.to_string())));
+            if let Some(metadata) = metadata {
+                metadata.add_headers(attestation_response.headers_mut());
+            }
 
             Ok(attestation_response)
         })
@@ -88,11 +104,26 @@ fn is_attestation_request(req: &Request<Body>) -> bool {
     req.uri().path() == "/.well-known/attestation"
 }
 
+/// A `nonce` query param asks for a fresh doc with the nonce embedded, decoded according to the `encoding` param in the
+/// same way as the Crypto API's attestation requests.
+fn attestation_nonce(query: Option<&str>) -> Result<Option<Vec<u8>>, CryptoApiError> {
+    let mut nonce = None;
+    let mut encoding = AttestationEncoding::default();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "nonce" => nonce = Some(value.into_owned()),
+            "encoding" => encoding = value.parse()?,
+            _ => {}
+        }
+    }
+    encoding.decode("nonce", nonce)
+}
+
 #[cfg(test)]
 mod test {
     use hyper::Body;
 
-    use super::is_attestation_request;
+    use super::{attestation_nonce, is_attestation_request};
 
     #[test]
     fn correctly_identifies_attestation_requests() {
@@ -111,4 +142,24 @@ mod test {
             .unwrap();
         assert!(!is_attestation_request(&req));
     }
+
+    #[test]
+    fn nonces_are_read_from_the_query() {
+        assert_eq!(attestation_nonce(None).unwrap(), None);
+        assert_eq!(attestation_nonce(Some("format=cbor")).unwrap(), None);
+        assert_eq!(
+            attestation_nonce(Some("nonce=abc%2B")).unwrap(),
+            Some(b"abc+".to_vec())
+        );
+        assert_eq!(
+            attestation_nonce(Some("nonce=AP8%3D&encoding=base64")).unwrap(),
+            Some(vec![0x00, 0xff])
+        );
+        assert_eq!(
+            attestation_nonce(Some("encoding=hex&nonce=00ff")).unwrap(),
+            Some(vec![0x00, 0xff])
+        );
+        assert!(attestation_nonce(Some("nonce=zz&encoding=hex")).is_err());
+        assert!(attestation_nonce(Some("nonce=abc&encoding=rot13")).is_err());
+    }
 }
@@ -1,7 +1,7 @@
 use hyper::header::InvalidHeaderValue;
 use hyper::http::{HeaderValue, Request, Response};
 use hyper::Body;
-use sha2::Digest;
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use shared::logging::TrxContextBuilder;
 use std::future::Future;
 use std::pin::Pin;
@@ -10,6 +10,7 @@ use thiserror::Error;
 use tower::{Layer, Service};
 
 use crate::base_tls_client::ClientError;
+use crate::cache::{AuthCache, AUTH_CACHE};
 use crate::{
     e3client::{AuthRequest, E3Api},
     EnclaveContext,
@@ -27,28 +28,19 @@ pub enum AuthError {
     InternalError(#[from] ClientError),
 }
 
-impl AuthError {
-    fn to_status(&self) -> u16 {
+impl HasErrorCode for AuthError {
+    fn error_code(&self) -> ErrorCode {
         match self {
-            Self::InternalError(_) => 500,
-            _ => 401,
+            Self::NoApiKeyGiven => codes::MISSING_API_KEY,
+            Self::FailedToAuthenticateApiKey | Self::DecodingError(_) => codes::INVALID_API_KEY,
+            Self::InternalError(e) => e.error_code(),
         }
     }
 }
 
 impl std::convert::From<AuthError> for Response<Body> {
     fn from(err: AuthError) -> Self {
-        let msg = err.to_string();
-        let body = serde_json::json!({
-          "message": msg
-        })
-        .to_string();
-        Response::builder()
-            .status(err.to_status())
-            .header("content-type", "application/json")
-            .header("content-length", body.len())
-            .body(body.into())
-            .expect("Failed to build auth error to response")
+        err.to_error_response()
     }
 }
 
@@ -109,6 +101,10 @@ where
         let e3_client = self.e3_client.clone();
         let enclave_context = self.context.clone();
         Box::pin(async move {
+            // Keep the api key out of any Debug output of the request
+            if let Some(api_key) = req.headers_mut().get_mut("api-key") {
+                api_key.set_sensitive(true);
+            }
             let Some(api_key) = req.headers().get("api-key") else {
                 let mut error_response: Response<Body> = AuthError::NoApiKeyGiven.into();
                 if let Some(context) = req.extensions_mut().remove::<TrxContextBuilder>() {
@@ -130,13 +126,6 @@ where
     }
 }
 
-fn compute_base64_sha512(input: impl AsRef<[u8]>) -> Vec<u8> {
-    let mut hasher = sha2::Sha512::new();
-    hasher.update(input.as_ref());
-    let hash_digest = base64::encode(hasher.finalize().as_slice());
-    hash_digest.as_bytes().to_vec()
-}
-
 pub async fn auth_request<
     C: std::ops::Deref<Target = EnclaveContext>,
     T: E3Api + Send + Sync + 'static,
@@ -145,24 +134,29 @@ pub async fn auth_request<
     enclave_context: C,
     e3_client: Arc<T>,
 ) -> Result<(), AuthError> {
-    log::debug!("Authenticating request");
-    let hashed_api_key = HeaderValue::from_bytes(&compute_base64_sha512(api_key.as_bytes()))?;
+    auth_request_with_cache(api_key, enclave_context, e3_client, AUTH_CACHE.get()).await
+}
 
+async fn auth_request_with_cache<
+    C: std::ops::Deref<Target = EnclaveContext>,
+    T: E3Api + Send + Sync + 'static,
+>(
+    api_key: &HeaderValue,
+    enclave_context: C,
+    e3_client: Arc<T>,
+    auth_cache: Option<&AuthCache>,
+) -> Result<(), AuthError> {
     let auth_payload = AuthRequest::from(enclave_context);
-    // matching on error to handle retry with alternative key
-    let Err(err) = e3_client
-        .authenticate(&hashed_api_key, auth_payload.clone())
+    match e3_client
+        .authenticate_api_key(api_key, auth_payload, auth_cache)
         .await
-    else {
-        return Ok(());
-    };
-
-    match err {
-        ClientError::FailedRequest(status) if status.as_u16() == 401 => {
+    {
+        Ok(()) => Ok(()),
+        Err(ClientError::FailedRequest(status)) if status.as_u16() == 401 => {
             log::debug!("Failed to auth with scoped api key hash");
             Err(AuthError::FailedToAuthenticateApiKey)
         }
-        e => Err(e.into()),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -251,4 +245,102 @@ mod test {
             _returned_err
         ));
     }
+
+    #[tokio::test]
+    async fn test_successful_auth_is_cached() {
+        let mut e3_test_client = MockE3TestClient::new();
+        e3_test_client
+            .expect_authenticate()
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let e3_client = Arc::new(e3_test_client);
+        let auth_cache = AuthCache::new(&crate::cache::AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: None,
+        });
+
+        let api_key = HeaderValue::from_str("my-api-key").unwrap();
+        let context = EnclaveContext::new(
+            "team_uuid".into(),
+            "app_uuid".into(),
+            "enclave_uuid".into(),
+            "enclave_name".into(),
+        );
+
+        for _ in 0..2 {
+            let result =
+                auth_request_with_cache(&api_key, &context, e3_client.clone(), Some(&auth_cache))
+                    .await;
+            assert!(result.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_is_not_cached() {
+        let mut e3_test_client = MockE3TestClient::new();
+        e3_test_client
+            .expect_authenticate()
+            .times(2)
+            .returning(|_, _| {
+                Err(ClientError::FailedRequest(
+                    StatusCode::from_u16(401).unwrap(),
+                ))
+            });
+        let e3_client = Arc::new(e3_test_client);
+        let auth_cache = AuthCache::new(&crate::cache::AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: None,
+        });
+
+        let api_key = HeaderValue::from_str("my-api-key").unwrap();
+        let context = EnclaveContext::new(
+            "team_uuid".into(),
+            "app_uuid".into(),
+            "enclave_uuid".into(),
+            "enclave_name".into(),
+        );
+
+        for _ in 0..2 {
+            let result =
+                auth_request_with_cache(&api_key, &context, e3_client.clone(), Some(&auth_cache))
+                    .await;
+            assert!(matches!(result, Err(AuthError::FailedToAuthenticateApiKey)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_is_cached_when_configured() {
+        let mut e3_test_client = MockE3TestClient::new();
+        e3_test_client
+            .expect_authenticate()
+            .times(1)
+            .returning(|_, _| {
+                Err(ClientError::FailedRequest(
+                    StatusCode::from_u16(401).unwrap(),
+                ))
+            });
+        let e3_client = Arc::new(e3_test_client);
+        let auth_cache = AuthCache::new(&crate::cache::AuthCacheConfig {
+            ttl_seconds: 60,
+            max_entries: 10,
+            negative_ttl_seconds: Some(5),
+        });
+
+        let api_key = HeaderValue::from_str("my-api-key").unwrap();
+        let context = EnclaveContext::new(
+            "team_uuid".into(),
+            "app_uuid".into(),
+            "enclave_uuid".into(),
+            "enclave_name".into(),
+        );
+
+        for _ in 0..2 {
+            let result =
+                auth_request_with_cache(&api_key, &context, e3_client.clone(), Some(&auth_cache))
+                    .await;
+            assert!(matches!(result, Err(AuthError::FailedToAuthenticateApiKey)));
+        }
+    }
 }
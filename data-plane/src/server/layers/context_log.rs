@@ -1,4 +1,5 @@
 use crate::server::http::RemoteIp;
+use crate::server::routing::IngressRoute;
 use crate::utils::trx_handler::LogHandlerMessage;
 use crate::EnclaveContext;
 use crate::FeatureContext;
@@ -79,7 +80,7 @@ where
         let feature_context = self.feature_context.clone();
         let log_tx_sender = self.tx_sender.clone();
         Box::pin(async move {
-            let timer = std::time::SystemTime::now();
+            let timer = TrxContextBuilder::get_timer();
             let mut base_context =
                 init_request_context(&req, enclave_context, feature_context.clone());
             // add context id as request header
@@ -89,13 +90,21 @@ where
             if let Some(RemoteIp(remote)) = req.extensions_mut().remove::<RemoteIp>() {
                 base_context.remote_ip(Some(remote));
             }
+            let route = req.extensions_mut().remove::<IngressRoute>();
+            let trx_logging_enabled = route
+                .as_ref()
+                .map_or(feature_context.trx_logging_enabled, |route| {
+                    route.trx_logging_enabled(feature_context.trx_logging_enabled)
+                });
+            base_context.service(route.map(|route| route.name));
 
             let _ = req.extensions_mut().insert(base_context);
             let mut response = inner.call(req).await?;
-            let mut context = response
-                .extensions_mut()
-                .remove::<TrxContextBuilder>()
-                .expect("Context not preserved on data plane response");
+            let Some(mut context) = response.extensions_mut().remove::<TrxContextBuilder>() else {
+                log::error!("Context not preserved on data plane response");
+                add_ev_ctx_to_headers(response.headers_mut(), &request_id);
+                return Ok(response);
+            };
             context.add_res_to_trx_context(&response, &feature_context.trusted_headers);
             add_ev_ctx_to_headers(response.headers_mut(), &request_id);
             let Ok(built_context) = context.stop_timer_and_build(timer) else {
@@ -103,7 +112,7 @@ where
                 return Ok(response);
             };
 
-            if feature_context.trx_logging_enabled {
+            if trx_logging_enabled {
                 //Send trx to config server in data plane
                 if let Err(e) =
                     log_tx_sender.send(LogHandlerMessage::new_log_message(built_context))
@@ -16,6 +16,8 @@ use crate::base_tls_client::ClientError;
 use crate::e3client::EncryptedDataEntry;
 use crate::e3client::EncryptedHeader;
 use crate::e3client::{AutoDecryptRequest, E3Api};
+use crate::server::http::GrpcPassthrough;
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use shared::logging::TrxContextBuilder;
 
 #[derive(Debug, Error)]
@@ -26,32 +28,31 @@ pub enum DecryptError {
     CiphertextStreamError(#[from] crate::crypto::stream::IncomingStreamError),
     #[error("Error communicating with e3 during decrypt - {0}")]
     E3Error(#[from] crate::base_tls_client::ClientError),
+    #[error("No context set on received request")]
+    MissingContext,
 }
 
-impl DecryptError {
-    fn to_status(&self) -> u16 {
+impl HasErrorCode for DecryptError {
+    fn error_code(&self) -> ErrorCode {
         match self {
-            Self::E3Error(ClientError::FailedRequest(code)) => code.as_u16(),
-            _ => 500,
+            Self::FailedToSerializeRequest | Self::MissingContext => codes::INTERNAL,
+            Self::CiphertextStreamError(_) => codes::INVALID_CIPHERTEXT,
+            Self::E3Error(e) => e.error_code(),
         }
     }
 }
 
 impl std::convert::From<DecryptError> for Response<Body> {
     fn from(err: DecryptError) -> Self {
-        let msg = err.to_string();
-        let body = serde_json::json!({
-          "message": msg
-        })
-        .to_string();
-        Response::builder()
-            .status(err.to_status())
-            .header("content-length", body.len())
-            .body(body.into())
-            .expect("Failed to build auth error to response")
+        err.to_error_response()
     }
 }
 
+/// Maximum number of body ciphertexts sent to E3 in a single decrypt request
+const DECRYPT_BATCH_SIZE: usize = 50;
+/// Maximum number of decrypt batches in flight to E3 for a single request
+const MAX_CONCURRENT_DECRYPT_BATCHES: usize = 4;
+
 lazy_static! {
     static ref CIPHERTEXT_VERSION_REGEX: regex::Regex = get_ciphertext_regex();
 }
@@ -109,10 +110,13 @@ where
         let mut inner = std::mem::replace(&mut self.inner, clone);
         let e3_client = self.e3_client.clone();
         Box::pin(async move {
-            let mut context = req
-                .extensions_mut()
-                .remove::<TrxContextBuilder>()
-                .expect("No context set on received request");
+            // gRPC bodies are streamed to the customer process as they arrive, so can't be buffered for decryption
+            if req.extensions().get::<GrpcPassthrough>().is_some() {
+                return inner.call(req).await;
+            }
+            let Some(mut context) = req.extensions_mut().remove::<TrxContextBuilder>() else {
+                return Ok(DecryptError::MissingContext.into());
+            };
             let (mut req_info, req_body) = req.into_parts();
             let encrypted_headers: Vec<EncryptedHeader> = req_info
                 .headers
@@ -160,18 +164,21 @@ where
 
             let mut bytes_vec = request_bytes.to_vec();
             if !decryption_payload.is_empty() || !encrypted_headers.is_empty() {
-                let request_payload =
-                    AutoDecryptRequest::new(decryption_payload, encrypted_headers);
-                let decrypted: AutoDecryptRequest =
-                    match e3_client.decrypt_with_retries(2, request_payload).await {
-                        Ok(decrypted) => decrypted,
-                        Err(e) => {
-                            log::error!("Failed to decrypt — {e}");
-                            let mut error_response: Response<Body> = DecryptError::from(e).into();
-                            error_response.extensions_mut().insert(context);
-                            return Ok(error_response);
-                        }
-                    };
+                let decrypted = match decrypt_in_batches(
+                    e3_client,
+                    decryption_payload,
+                    encrypted_headers,
+                )
+                .await
+                {
+                    Ok(decrypted) => decrypted,
+                    Err(e) => {
+                        log::error!("Failed to decrypt — {e}");
+                        let mut error_response: Response<Body> = DecryptError::from(e).into();
+                        error_response.extensions_mut().insert(context);
+                        return Ok(error_response);
+                    }
+                };
 
                 log::info!("Decryption complete, rebuilding request");
                 inject_decrypted_values_into_request_vec(&decrypted, &mut bytes_vec);
@@ -204,6 +211,53 @@ where
     }
 }
 
+/// Split the ciphertexts into batches and decrypt them concurrently, with bounded parallelism, so that large
+/// documents don't wait on a single E3 request covering every field. Batches are reassembled in order, as
+/// injecting the decrypted values relies on the body entries being sorted by range.
+async fn decrypt_in_batches<T: E3Api + Send + Sync>(
+    e3_client: Arc<T>,
+    body_data: Vec<EncryptedDataEntry>,
+    header_data: Vec<EncryptedHeader>,
+) -> Result<AutoDecryptRequest, ClientError> {
+    // Headers are decrypted alongside the first batch of body ciphertexts
+    let mut header_data = Some(header_data);
+    let mut batches: Vec<AutoDecryptRequest> = body_data
+        .chunks(DECRYPT_BATCH_SIZE)
+        .map(|entries| {
+            AutoDecryptRequest::new(entries.to_vec(), header_data.take().unwrap_or_default())
+        })
+        .collect();
+    if let Some(header_data) = header_data {
+        batches.push(AutoDecryptRequest::new(vec![], header_data));
+    }
+
+    let decrypted_batches: Vec<AutoDecryptRequest> = futures::stream::iter(batches)
+        .map(|batch| {
+            let e3_client = e3_client.clone();
+            async move {
+                e3_client
+                    .decrypt_with_retries::<AutoDecryptRequest, _>(2, batch)
+                    .await
+            }
+        })
+        .buffered(MAX_CONCURRENT_DECRYPT_BATCHES)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    let (body_data, header_data) = decrypted_batches.into_iter().fold(
+        (vec![], vec![]),
+        |(mut body_data, mut header_data), batch| {
+            let (batch_body, batch_headers) = batch.into_parts();
+            body_data.extend(batch_body);
+            header_data.extend(batch_headers);
+            (body_data, header_data)
+        },
+    );
+    Ok(AutoDecryptRequest::new(body_data, header_data))
+}
+
 fn inject_decrypted_values_into_request_vec(
     decrypted_values: &AutoDecryptRequest,
     request_bytes: &mut Vec<u8>,
@@ -319,6 +373,20 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_request_without_context_is_rejected() {
+        let mock_service = service_fn(|_: Request<Body>| async {
+            Ok::<_, hyper::Error>(Response::new(Body::empty()))
+        });
+        let mut service = DecryptService {
+            e3_client: Arc::new(MockE3TestClient::new()),
+            inner: mock_service,
+        };
+
+        let response = service.call(Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), codes::INTERNAL.status);
+    }
+
     #[tokio::test]
     async fn test_decrypt_header() {
         let mut e3_test_client = MockE3TestClient::new();
@@ -419,6 +487,47 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_decrypt_large_body_in_batches() {
+        let mut e3_test_client = MockE3TestClient::new();
+        let n_fields = DECRYPT_BATCH_SIZE * 2 + 1;
+
+        e3_test_client
+            .expect_decrypt_with_retries::<AutoDecryptRequest, AutoDecryptRequest>()
+            .times(3)
+            .returning(move |_, request: AutoDecryptRequest| {
+                assert!(request.body_data().len() <= DECRYPT_BATCH_SIZE);
+                let decrypted_entries = request
+                    .body_data()
+                    .iter()
+                    .map(|entry| {
+                        EncryptedDataEntry::new(entry.range(), Value::String("plaintext".into()))
+                    })
+                    .collect();
+                Ok(AutoDecryptRequest::new(decrypted_entries, vec![]))
+            });
+
+        let mock_service = service_fn(|req: Request<Body>| async {
+            let (_, body) = req.into_parts();
+            Ok::<_, hyper::Error>(Response::new(body))
+        });
+
+        let mut service = DecryptService {
+            e3_client: Arc::new(e3_test_client),
+            inner: mock_service,
+        };
+
+        let ciphertext = "ev:Tk9D:string:YGJVktHhdj3ds3wC:A6rkaTU8lez7NSBT8nTqbhBIu3tX4/lyH3aJVBUcGmLh:8hI5qEp32kWcVK367yaC09bDRbk:$";
+        let mut request = Request::new(Body::from(json!(vec![ciphertext; n_fields]).to_string()));
+        request.extensions_mut().insert(get_test_trx());
+
+        let response = service.call(request).await.unwrap();
+        let bytes = to_bytes(response.into_body()).await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(json!(vec!["plaintext"; n_fields]), json);
+    }
+
     #[tokio::test]
     async fn test_ciphertext_regex() {
         let regex = get_ciphertext_regex();
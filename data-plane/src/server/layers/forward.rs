@@ -1,6 +1,7 @@
 use hyper::client::{Client, HttpConnector};
 use hyper::http::{header, Request, Response};
 use hyper::Body;
+use shared::error_code::{codes, ErrorCode, HasErrorCode};
 use shared::logging::TrxContextBuilder;
 use std::future::Future;
 use std::pin::Pin;
@@ -8,26 +9,35 @@ use std::sync::OnceLock;
 use thiserror::Error;
 use tower::Service;
 
+use crate::server::http::ForwardHttp2;
+
 static HTTP_CLIENT: OnceLock<Client<HttpConnector, hyper::Body>> = OnceLock::new();
+static HTTP2_CLIENT: OnceLock<Client<HttpConnector, hyper::Body>> = OnceLock::new();
 
 #[derive(Debug, Error)]
 enum ForwardError {
     #[error("Failed to request user process - {0}")]
     FailedToRequestUserProcess(#[from] hyper::Error),
+    #[error("Failed to read response from user process - {0}")]
+    FailedToReadResponse(hyper::Error),
+    #[error("No context set on received request")]
+    MissingContext,
+}
+
+impl HasErrorCode for ForwardError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::FailedToRequestUserProcess(_) | Self::FailedToReadResponse(_) => {
+                codes::UPSTREAM_FAILED
+            }
+            Self::MissingContext => codes::INTERNAL,
+        }
+    }
 }
 
 impl std::convert::From<ForwardError> for Response<Body> {
     fn from(value: ForwardError) -> Self {
-        let error_response = serde_json::json!({
-          "message": value.to_string()
-        })
-        .to_string();
-        Response::builder()
-            .status(500)
-            .header("content-type", "application/json")
-            .header("content-length", error_response.len())
-            .body(Body::from(error_response))
-            .expect("Infallible: hardcoded response")
+        value.to_error_response()
     }
 }
 
@@ -50,11 +60,16 @@ impl Service<Request<Body>> for ForwardService {
 
     fn call(&mut self, mut req: Request<Body>) -> Self::Future {
         Box::pin(async move {
-            let mut http_client = HTTP_CLIENT.get_or_init(Client::new).clone();
-            let context_builder = req
-                .extensions_mut()
-                .remove::<TrxContextBuilder>()
-                .expect("No context set on received request");
+            let mut http_client = if req.extensions().get::<ForwardHttp2>().is_some() {
+                HTTP2_CLIENT
+                    .get_or_init(|| Client::builder().http2_only(true).build_http())
+                    .clone()
+            } else {
+                HTTP_CLIENT.get_or_init(Client::new).clone()
+            };
+            let Some(context_builder) = req.extensions_mut().remove::<TrxContextBuilder>() else {
+                return Ok(ForwardError::MissingContext.into());
+            };
             match http_client.call(req).await {
                 Ok(mut response) => {
                     response.extensions_mut().insert(context_builder);
@@ -65,9 +80,15 @@ impl Service<Request<Body>> for ForwardService {
                         .is_some()
                     {
                         let (mut parts, body) = response.into_parts();
-                        let body_bytes = hyper::body::to_bytes(body)
-                            .await
-                            .expect("Failed to read entire response body");
+                        let body_bytes = match hyper::body::to_bytes(body).await {
+                            Ok(body_bytes) => body_bytes,
+                            Err(e) => {
+                                let mut error_response: Response<Body> =
+                                    ForwardError::FailedToReadResponse(e).into();
+                                *error_response.extensions_mut() = parts.extensions;
+                                return Ok(error_response);
+                            }
+                        };
                         parts
                             .headers
                             .append("content-length", body_bytes.len().into());
@@ -4,6 +4,8 @@ pub mod http;
 #[cfg(feature = "tls_termination")]
 pub mod layers;
 #[cfg(feature = "tls_termination")]
+pub mod routing;
+#[cfg(feature = "tls_termination")]
 #[allow(clippy::module_inception)]
 pub mod server;
 #[cfg(feature = "tls_termination")]
@@ -0,0 +1,145 @@
+//! Routing of ingress traffic to services on different local ports, so one enclave can host several small services
+//! behind a single ingress. HTTP requests are routed by their Host header, and other traffic by the TLS SNI. Traffic
+//! for hosts without a route goes to the customer process port the data plane was started with.
+use hyper::{Body, Request};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IngressRoute {
+    /// Recorded on the trx logs of requests sent to this route
+    pub name: String,
+    /// Host names served by this route. `*.example.com` matches any subdomain of example.com.
+    pub hosts: Vec<String>,
+    pub port: u16,
+    /// Overrides `trx_logging_enabled` for requests sent to this route
+    #[serde(default)]
+    pub trx_logging: Option<bool>,
+}
+
+impl IngressRoute {
+    fn matches(&self, host: &str) -> bool {
+        self.hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .len()
+                    .checked_sub(domain.len() + 1)
+                    .filter(|&dot| host.as_bytes()[dot] == b'.')
+                    .is_some_and(|dot| host[dot + 1..].eq_ignore_ascii_case(domain)),
+                None => pattern.eq_ignore_ascii_case(host),
+            })
+    }
+
+    pub fn trx_logging_enabled(&self, default: bool) -> bool {
+        self.trx_logging.unwrap_or(default)
+    }
+}
+
+/// The first route serving the host, ignoring any port on it.
+pub fn find_route<'a>(routes: &'a [IngressRoute], host: &str) -> Option<&'a IngressRoute> {
+    let host = strip_port(host);
+    routes.iter().find(|route| route.matches(host))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // Colons are only part of the host itself in bracketed IPv6 addresses
+        Some((name, port))
+            if (!name.contains(':') || name.ends_with(']'))
+                && port.bytes().all(|byte| byte.is_ascii_digit()) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+/// Point the request at the port of the route serving its Host header, and add the route to its extensions for trx
+/// logging. Returns the port the request will be sent to.
+pub fn route_request(
+    routes: &[IngressRoute],
+    default_port: u16,
+    request: &mut Request<Body>,
+) -> u16 {
+    let route = request
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| find_route(routes, host))
+        .cloned();
+    let Some(route) = route else {
+        return default_port;
+    };
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    if let Ok(uri) = format!("http://127.0.0.1:{}{path_and_query}", route.port).parse() {
+        *request.uri_mut() = uri;
+    }
+    let port = route.port;
+    request.extensions_mut().insert(route);
+    port
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn routes() -> Vec<IngressRoute> {
+        vec![
+            IngressRoute {
+                name: "api".into(),
+                hosts: vec!["api.example.com".into()],
+                port: 3000,
+                trx_logging: Some(false),
+            },
+            IngressRoute {
+                name: "tenants".into(),
+                hosts: vec!["*.tenants.example.com".into()],
+                port: 3001,
+                trx_logging: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_hosts_are_matched_exactly_or_by_wildcard() {
+        let routes = routes();
+        let route_name = |host: &str| find_route(&routes, host).map(|route| route.name.clone());
+        assert_eq!(route_name("api.example.com"), Some("api".into()));
+        assert_eq!(route_name("API.example.com:443"), Some("api".into()));
+        assert_eq!(route_name("a.tenants.example.com"), Some("tenants".into()));
+        assert_eq!(
+            route_name("a.b.tenants.example.com"),
+            Some("tenants".into())
+        );
+        assert_eq!(route_name("tenants.example.com"), None);
+        assert_eq!(route_name("xtenants.example.com"), None);
+        assert_eq!(route_name("web.example.com"), None);
+    }
+
+    #[test]
+    fn test_requests_are_sent_to_their_route_port() {
+        let routes = routes();
+        let mut request = Request::builder()
+            .uri("http://127.0.0.1:8008/users?page=2")
+            .header("host", "api.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(route_request(&routes, 8008, &mut request), 3000);
+        assert_eq!(request.uri(), "http://127.0.0.1:3000/users?page=2");
+        let route = request.extensions().get::<IngressRoute>().unwrap();
+        assert!(!route.trx_logging_enabled(true));
+
+        let mut request = Request::builder()
+            .uri("http://127.0.0.1:8008/")
+            .header("host", "web.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(route_request(&routes, 8008, &mut request), 8008);
+        assert_eq!(request.uri(), "http://127.0.0.1:8008/");
+        assert!(request.extensions().get::<IngressRoute>().is_none());
+    }
+}
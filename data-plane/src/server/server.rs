@@ -1,18 +1,25 @@
 use super::error::TlsError;
 use super::http::parse::{try_parse_http_request_from_stream, Incoming};
 use super::http::{request_to_bytes, response_to_bytes};
+use super::routing::{find_route, route_request};
+use super::tls::client_auth::{set_client_cert_headers, ClientCertDetails};
 use super::tls::TlsServerBuilder;
 
+use crate::cache::{AuthCache, AUTH_CACHE};
+use crate::configuration;
 use crate::e3client::E3Client;
-use crate::server::http::{build_internal_error_response, parse};
+use crate::server::http::{build_internal_error_response, parse, prepare_http2_request};
 use crate::{EnclaveContext, FeatureContext};
 
 use crate::utils::trx_handler::{start_log_handler, LogHandlerMessage};
 
-use hyper::{Body, Request};
+use hyper::{Body, Request, Response};
 use shared::logging::{RequestType, TrxContextBuilder};
 use shared::server::proxy_protocol::ProxiedConnection;
 use shared::server::Listener;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -47,7 +54,11 @@ where
     ) = unbounded_channel();
 
     let feature_context = Arc::new(context);
-    if feature_context.trx_logging_enabled {
+    let any_route_logged = feature_context
+        .ingress_routes
+        .iter()
+        .any(|route| route.trx_logging == Some(true));
+    if feature_context.trx_logging_enabled || any_route_logged {
         let tx_for_handler = tx.clone();
         tokio::spawn(async move {
             start_log_handler(tx_for_handler, rx).await;
@@ -62,26 +73,15 @@ where
             return;
         }
     };
-    let service_builder = tower::ServiceBuilder::new();
-
-    // Only apply attestation layer in enclave mode
-    #[cfg(feature = "enclave")]
-    let service_builder = service_builder.layer(AttestLayer);
-
-    // layers are invoked in the order that they're registered to the service
-    let service = service_builder
-        .layer(ContextLogLayer::new(
-            enclave_context.clone(),
-            feature_context.clone(),
-            tx.clone(),
-        ))
-        .option_layer(
-            feature_context
-                .api_key_auth
-                .then(|| AuthLayer::new(e3_client.clone(), enclave_context.clone())),
-        )
-        .layer(DecryptLayer::new(e3_client.clone()))
-        .service(ForwardService);
+    if let Some(auth_cache_config) = feature_context.auth_cache.as_ref() {
+        AUTH_CACHE.get_or_init(|| AuthCache::new(auth_cache_config));
+    }
+    let service = build_service(
+        enclave_context.clone(),
+        feature_context.clone(),
+        e3_client.clone(),
+        tx.clone(),
+    );
     loop {
         let mut stream = match server.accept().await {
             Ok(stream) => stream,
@@ -100,9 +100,39 @@ where
         let feature_context_clone = feature_context.clone();
         let e3_client_clone = e3_client.clone();
         tokio::spawn(async move {
+            let client_cert =
+                ClientCertDetails::from_peer_certificates(stream.get_ref().1.peer_certificates());
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2".as_slice()) {
+                shared::handshake_trace!(
+                    "Serving connection from {} over HTTP/2",
+                    remote_ip.as_deref().unwrap_or("unknown")
+                );
+                return serve_http2(
+                    stream,
+                    port,
+                    remote_ip,
+                    client_cert,
+                    feature_context_clone,
+                    data_plane_service,
+                )
+                .await;
+            }
             loop {
                 match try_parse_http_request_from_stream(&mut stream, port).await {
-                    Ok(Incoming::HttpRequest(request)) if parse::is_websocket_request(&request) => {
+                    Ok(Incoming::HttpRequest(mut request))
+                        if parse::is_websocket_request(&request) =>
+                    {
+                        set_client_cert_headers(request.headers_mut(), client_cert.as_ref());
+                        let target_port = route_request(
+                            &feature_context_clone.ingress_routes,
+                            port,
+                            &mut request,
+                        );
+                        shared::handshake_trace!(
+                            "Framed connection from {} as a websocket upgrade to {}",
+                            remote_ip.as_deref().unwrap_or("unknown"),
+                            request.uri().path()
+                        );
                         return handle_websocket_request(
                             &mut stream,
                             request,
@@ -111,11 +141,19 @@ where
                             enclave_context_clone.clone(),
                             feature_context_clone.clone(),
                             e3_client_clone.clone(),
-                            port,
+                            target_port,
                         )
                         .await;
                     }
-                    Ok(Incoming::HttpRequest(request)) => {
+                    Ok(Incoming::HttpRequest(mut request)) => {
+                        set_client_cert_headers(request.headers_mut(), client_cert.as_ref());
+                        route_request(&feature_context_clone.ingress_routes, port, &mut request);
+                        shared::handshake_trace!(
+                            "Framed request from {} as HTTP {} {}",
+                            remote_ip.as_deref().unwrap_or("unknown"),
+                            request.method(),
+                            request.uri().path()
+                        );
                         let response = data_plane_service.call(request).await.unwrap_or_else(|e| {
                             log::error!("Failed to handle incoming request in data plane - {e:?}");
                             build_internal_error_response(None)
@@ -124,7 +162,12 @@ where
                         let _ = stream.write_all(&response_bytes).await;
                         continue;
                     }
-                    Ok(Incoming::NonHttpRequest(_)) if feature_context_clone.api_key_auth => {
+                    Ok(Incoming::NonHttpRequest(bytes)) if feature_context_clone.api_key_auth => {
+                        shared::handshake_trace!(
+                            "Framed connection from {} as non-HTTP ({} bytes), rejecting as API key auth is enabled",
+                            remote_ip.as_deref().unwrap_or("unknown"),
+                            bytes.len()
+                        );
                         log::info!(
                             "Non http request received with auth enabled, closing connection"
                         );
@@ -133,11 +176,23 @@ where
                         return;
                     }
                     Ok(Incoming::NonHttpRequest(bytes)) => {
+                        shared::handshake_trace!(
+                            "Framed connection from {} as non-HTTP ({} bytes), piping to the customer process",
+                            remote_ip.as_deref().unwrap_or("unknown"),
+                            bytes.len()
+                        );
                         log::info!(
                             "Non http request received with auth enabled, closing connection"
                         );
                         log_non_http_trx(&tx_for_connection, true, remote_ip, None);
-                        let _ = pipe_to_customer_process(&mut stream, &bytes, port).await;
+                        // Without a Host header, non-HTTP traffic is routed by SNI
+                        let target_port = stream
+                            .get_ref()
+                            .1
+                            .server_name()
+                            .and_then(|sni| find_route(&feature_context_clone.ingress_routes, sni))
+                            .map_or(port, |route| route.port);
+                        let _ = pipe_to_customer_process(&mut stream, &bytes, target_port).await;
                         return;
                     }
                     Err(e) => {
@@ -151,6 +206,90 @@ where
     }
 }
 
+pub type ServiceFuture =
+    Pin<Box<dyn Future<Output = Result<Response<Body>, tower::BoxError>> + Send>>;
+
+/// The stack of layers each HTTP request passes through on its way to the customer process.
+pub fn build_service(
+    enclave_context: Arc<EnclaveContext>,
+    feature_context: Arc<FeatureContext>,
+    e3_client: Arc<E3Client>,
+    tx: UnboundedSender<LogHandlerMessage>,
+) -> impl Service<
+    Request<Body>,
+    Response = Response<Body>,
+    Error = tower::BoxError,
+    Future = ServiceFuture,
+> + Clone
+       + Send {
+    let service_builder = tower::ServiceBuilder::new();
+
+    // Only apply attestation layer in enclave mode
+    #[cfg(feature = "enclave")]
+    let service_builder = service_builder.layer(AttestLayer);
+
+    // layers are invoked in the order that they're registered to the service
+    service_builder
+        .layer(ContextLogLayer::new(
+            enclave_context.clone(),
+            feature_context.clone(),
+            tx,
+        ))
+        .option_layer(
+            feature_context
+                .api_key_auth
+                .then(|| AuthLayer::new(e3_client.clone(), enclave_context)),
+        )
+        .layer(DecryptLayer::new(e3_client))
+        .service(ForwardService)
+}
+
+/// Serve a connection which negotiated h2, passing each of its streams through the same layers as HTTP/1.1 requests.
+async fn serve_http2<S, T>(
+    stream: S,
+    port: u16,
+    remote_ip: Option<String>,
+    client_cert: Option<ClientCertDetails>,
+    feature_context: Arc<FeatureContext>,
+    service: T,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: Service<
+            Request<Body>,
+            Response = Response<Body>,
+            Error = tower::BoxError,
+            Future = ServiceFuture,
+        > + Clone
+        + Send
+        + 'static,
+{
+    let http2_service = hyper::service::service_fn(move |mut request: Request<Body>| {
+        prepare_http2_request(
+            &mut request,
+            port,
+            remote_ip.as_deref(),
+            feature_context.forward_http2,
+            feature_context.grpc_passthrough,
+        );
+        set_client_cert_headers(request.headers_mut(), client_cert.as_ref());
+        route_request(&feature_context.ingress_routes, port, &mut request);
+        let response = service.clone().call(request);
+        async move {
+            Ok::<_, Infallible>(response.await.unwrap_or_else(|e| {
+                log::error!("Failed to handle incoming HTTP/2 request in data plane - {e:?}");
+                build_internal_error_response(None)
+            }))
+        }
+    });
+    if let Err(e) = hyper::server::conn::Http::new()
+        .http2_only(true)
+        .serve_connection(stream, http2_service)
+        .await
+    {
+        log::error!("HTTP/2 connection error - {e:?}");
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn handle_websocket_request<S: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut TlsStream<S>,
@@ -185,7 +324,39 @@ async fn handle_websocket_request<S: AsyncRead + AsyncWrite + Unpin>(
     }
     log_non_http_trx(tx_for_connection, true, remote_ip, Some(context_builder));
     let serialized_request = request_to_bytes(request).await;
-    let _ = pipe_to_customer_process(stream, &serialized_request, port).await;
+    if let Err(e) = pipe_websocket_to_customer_process(stream, &serialized_request, port).await {
+        log::info!("Websocket connection closed — {e}");
+    }
+}
+
+/// Relay a websocket upgrade to the customer process and its response back to the client. Once the customer process
+/// switches protocols, the connection is piped through untouched until either side closes it or it goes idle.
+async fn pipe_websocket_to_customer_process<L>(
+    stream: &mut TlsStream<L>,
+    upgrade_request: &[u8],
+    port: u16,
+) -> Result<(), tokio::io::Error>
+where
+    TlsStream<L>: AsyncRead + Unpin + AsyncWrite,
+{
+    let mut customer_stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    customer_stream.write_all(upgrade_request).await?;
+    let (status, response) = parse::read_response_head(&mut customer_stream)
+        .await
+        .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e))?;
+    stream.write_all(&response).await?;
+    if status != 101 {
+        // The upgrade was refused, so relay the rest of the response as any other non-websocket traffic
+        log::info!("Customer process refused websocket upgrade with status {status}");
+        return shared::utils::pipe_streams(stream, customer_stream).await;
+    }
+    match configuration::get_websocket_idle_timeout() {
+        Some(idle_timeout) => {
+            shared::utils::pipe_streams_with_idle_timeout(stream, customer_stream, idle_timeout)
+                .await
+        }
+        None => shared::utils::pipe_streams(stream, customer_stream).await,
+    }
 }
 
 async fn shutdown_conn<L>(stream: &mut TlsStream<L>)
@@ -217,20 +388,34 @@ fn log_non_http_trx(
     remote_ip: Option<String>,
     context_builder: Option<TrxContextBuilder>,
 ) {
-    let enclave_context = EnclaveContext::get().unwrap();
     let mut context_builder = match context_builder {
         Some(context_builder) => context_builder,
-        _ => TrxContextBuilder::init_trx_context_with_enclave_details(
-            &enclave_context.uuid,
-            &enclave_context.name,
-            &enclave_context.app_uuid,
-            &enclave_context.team_uuid,
-            RequestType::TCP,
-        ),
+        _ => {
+            let enclave_context = match EnclaveContext::get() {
+                Ok(enclave_context) => enclave_context,
+                Err(e) => {
+                    log::error!("Couldn't get enclave context to log non-http request — {e}");
+                    return;
+                }
+            };
+            TrxContextBuilder::init_trx_context_with_enclave_details(
+                &enclave_context.uuid,
+                &enclave_context.name,
+                &enclave_context.app_uuid,
+                &enclave_context.team_uuid,
+                RequestType::TCP,
+            )
+        }
     };
     context_builder.add_httparse_to_trx(authorized, None, remote_ip);
-    let trx_context = context_builder.build().unwrap();
-    tx_sender
-        .send(LogHandlerMessage::new_log_message(trx_context))
-        .unwrap()
+    let trx_context = match context_builder.build() {
+        Ok(trx_context) => trx_context,
+        Err(e) => {
+            log::error!("Failed to build trx context for non-http request — {e}");
+            return;
+        }
+    };
+    if let Err(e) = tx_sender.send(LogHandlerMessage::new_log_message(trx_context)) {
+        log::error!("Failed to send transaction context to log handler. err: {e}");
+    }
 }
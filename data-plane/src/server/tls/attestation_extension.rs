@@ -0,0 +1,80 @@
+use openssl::asn1::{Asn1Object, Asn1OctetString};
+use openssl::error::ErrorStack;
+use openssl::x509::X509Extension;
+
+/// Allocated under the UUID arc (2.25), so it can't clash with a registered OID.
+pub const ATTESTATION_DOC_EXTENSION_OID: &str = "2.25.300549451013095182847219097754928218335";
+
+/// X.509 extension carrying the attestation doc of an ingress cert, so clients can verify the enclave during the TLS
+/// handshake instead of via a separate request. The doc's user data is the cert's public key, which binds it to the
+/// cert. The extension isn't critical, so clients which don't know it still accept the cert.
+pub fn attestation_doc_extension(attestation_doc: &[u8]) -> Result<X509Extension, ErrorStack> {
+    let oid = Asn1Object::from_str(ATTESTATION_DOC_EXTENSION_OID)?;
+    let contents = Asn1OctetString::new_from_bytes(&der_octet_string(attestation_doc))?;
+    X509Extension::new_from_der(&oid, false, &contents)
+}
+
+/// The extension's value has to be DER, so the COSE_Sign1 doc is wrapped in an OCTET STRING.
+fn der_octet_string(value: &[u8]) -> Vec<u8> {
+    let mut der = vec![0x04];
+    let len = value.len();
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        der.push(0x80 | len_bytes.len() as u8);
+        der.extend(len_bytes);
+    }
+    der.extend_from_slice(value);
+    der
+}
+
+#[cfg(test)]
+mod test {
+    use super::{attestation_doc_extension, der_octet_string, ATTESTATION_DOC_EXTENSION_OID};
+    use openssl::asn1::{Asn1Object, Asn1Time};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+
+    #[test]
+    fn test_octet_strings_use_long_form_lengths_for_large_values() {
+        assert_eq!(der_octet_string(b"ab"), vec![0x04, 0x02, b'a', b'b']);
+        let encoded = der_octet_string(&[0; 4500]);
+        assert_eq!(&encoded[..4], &[0x04, 0x82, 0x11, 0x94]);
+        assert_eq!(encoded.len(), 4504);
+    }
+
+    #[test]
+    fn test_attestation_doc_is_embedded_in_cert() {
+        let attestation_doc = vec![0xd2; 4500];
+        let key = EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap();
+        let key = PKey::from_ec_key(key).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(attestation_doc_extension(&attestation_doc).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let der = builder.build().to_der().unwrap();
+
+        let oid = Asn1Object::from_str(ATTESTATION_DOC_EXTENSION_OID).unwrap();
+        let contains = |needle: &[u8]| der.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(oid.as_slice()));
+        assert!(contains(&der_octet_string(&attestation_doc)));
+        assert!(X509::from_der(&der).is_ok());
+    }
+}
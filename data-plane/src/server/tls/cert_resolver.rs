@@ -10,6 +10,7 @@ use openssl::x509::extension::{
     SubjectKeyIdentifier,
 };
 use openssl::x509::{X509NameBuilder, X509Ref, X509Req, X509ReqBuilder, X509};
+use shared::logging::AuditEventType;
 
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -21,8 +22,10 @@ use tokio_rustls::rustls::sign::{self, CertifiedKey};
 use tokio_rustls::rustls::{Certificate, PrivateKey};
 
 use crate::server::error::{ServerResult, TlsError};
-use crate::EnclaveContext;
+use crate::utils::audit::{record_audit_event, AuditedSigningKey};
+use crate::{EnclaveContext, FeatureContext};
 
+use super::customer_cert_store;
 use super::trusted_cert_container::TRUSTED_CERT_STORE;
 
 /// Shared struct to implement cert expiry checks and refreshes
@@ -47,6 +50,10 @@ impl CertContainer {
         self.inner.read().unwrap().1.clone()
     }
 
+    fn replace(&self, expiry: SystemTime, cert: CertifiedKey) {
+        *self.inner.write().unwrap() = (expiry, Arc::new(cert));
+    }
+
     /// Regenerate the cert with a fresh attestation document.
     /// Takes out an exlusive write lock on the resolver's cert attribute, which is freed before exiting.
     fn rotate_cert<F>(&self, create_new_cert: F) -> ServerResult<Arc<CertifiedKey>>
@@ -86,13 +93,16 @@ impl CertContainer {
 /// Implementor of rustls server cert resolver
 /// If the request includes a nonce (by hitting <nonce>.attest.<enclave_domain>), then this
 /// resolver will attempt to serve a fresh, attestable cert with the nonce embedded in the attestation document
+/// Requests for a server name with a customer supplied cert are served that cert
 /// Standard requests will be served a standard attestable cert as a fallback
 pub struct AttestableCertResolver {
     enclave_context: EnclaveContext,
-    internal_ca: X509,
-    internal_pk: PKey<Private>,
+    // Swapped out when the intermediate CA is renewed. Handshakes in progress keep the CA they started with.
+    intermediate_ca: RwLock<Arc<(X509, PKey<Private>)>>,
     // if we don't receive a nonce, we should return a generic, attestable cert
     base_cert_container: CertContainer,
+    // served the ACME cert, like the trusted cert domains
+    custom_domains: Vec<String>,
 }
 
 impl AttestableCertResolver {
@@ -108,12 +118,37 @@ impl AttestableCertResolver {
 
         Ok(Self {
             enclave_context,
-            internal_ca,
-            internal_pk,
+            intermediate_ca: RwLock::new(Arc::new((internal_ca, internal_pk))),
             base_cert_container: CertContainer::new(created_at, cert_and_key),
+            custom_domains: FeatureContext::get()
+                .map(|context| context.custom_domains)
+                .unwrap_or_default(),
         })
     }
 
+    /// The intermediate CA certs are currently signed with.
+    pub fn intermediate_ca(&self) -> Arc<(X509, PKey<Private>)> {
+        self.intermediate_ca.read().unwrap().clone()
+    }
+
+    /// Start signing certs with a renewed intermediate CA. The base cert is regenerated with the new CA before
+    /// anything is swapped, so a failure leaves the current CA and cert in place.
+    pub fn rotate_intermediate_ca(
+        &self,
+        internal_ca: X509,
+        internal_pk: PKey<Private>,
+    ) -> ServerResult<()> {
+        let (expiry, base_cert) = Self::generate_self_signed_cert(
+            internal_ca.as_ref(),
+            internal_pk.as_ref(),
+            self.enclave_context.get_cert_names(),
+            None,
+        )?;
+        *self.intermediate_ca.write().unwrap() = Arc::new((internal_ca, internal_pk));
+        self.base_cert_container.replace(expiry, base_cert);
+        Ok(())
+    }
+
     fn extract_nonce_from_servername(received_servername: &str) -> Option<Vec<u8>> {
         let tokens: Vec<&str> = received_servername.split('.').collect();
         if tokens.len() > 2 {
@@ -139,6 +174,14 @@ impl AttestableCertResolver {
         }
     }
 
+    fn is_custom_domain(&self, received_servername: Option<&str>) -> bool {
+        received_servername.is_some_and(|servername| {
+            self.custom_domains
+                .iter()
+                .any(|domain| domain.eq_ignore_ascii_case(servername))
+        })
+    }
+
     /// Make a X509 request with the given private key
     fn generate_csr(key_pair: &PKey<Private>, hostnames: &[String]) -> Result<X509Req, ErrorStack> {
         let mut req_builder = X509ReqBuilder::new()?;
@@ -224,6 +267,7 @@ impl AttestableCertResolver {
             Some(key_pair.public_key_to_der()?),
             nonce,
             &mut san_ext,
+            &mut cert_builder,
         )?;
         #[cfg(not(feature = "enclave"))]
         let expiry_time = SystemTime::now() + Duration::from_secs(60 * 60 * 24);
@@ -243,6 +287,11 @@ impl AttestableCertResolver {
             .build(&ctx)?;
         cert_builder.append_extension(auth_key_id_ext)?;
 
+        record_audit_event(
+            AuditEventType::PrivateKeyUse,
+            "intermediate_ca_key",
+            "cert_generation",
+        );
         cert_builder.sign(signing_key, MessageDigest::sha256())?;
 
         let generated_cert_and_key = Self::convert_openssl_cert_chain_to_certified_key(
@@ -259,11 +308,14 @@ impl AttestableCertResolver {
         challenge: Option<Vec<u8>>,
         nonce: Option<Vec<u8>>,
         san_ext: &mut SubjectAlternativeName,
+        cert_builder: &mut openssl::x509::X509Builder,
     ) -> ServerResult<SystemTime> {
+        use super::attestation_extension::attestation_doc_extension;
         use crate::crypto::attest;
 
-        let attestation_doc = attest::get_attestation_doc(challenge, nonce).unwrap();
+        let attestation_doc = attest::get_attestation_doc(challenge, nonce)?;
         let expiry = attest::get_expiry_time(&attestation_doc)?;
+        cert_builder.append_extension(attestation_doc_extension(&attestation_doc)?)?;
         let hex_encoded_ad = shared::utils::HexSlice::from(attestation_doc.as_slice());
         for hostname in hostnames {
             let attestable_san = format!("{hex_encoded_ad:x}.{hostname}");
@@ -278,7 +330,10 @@ impl AttestableCertResolver {
         private_key: PKey<Private>,
     ) -> ServerResult<CertifiedKey> {
         let der_encoded_private_key = private_key.private_key_to_der()?;
-        let ecdsa_private_key = sign::any_ecdsa_type(&PrivateKey(der_encoded_private_key))?;
+        let ecdsa_private_key = AuditedSigningKey::wrap(
+            sign::any_ecdsa_type(&PrivateKey(der_encoded_private_key))?,
+            "attestable_cert_key",
+        );
         let pem_encoded_leaf_cert: Vec<u8> = leaf_cert.to_pem()?;
         let pem_encoded_intermediate_cert: Vec<u8> = intermediate_cert.to_pem()?;
         let combined_pem_encoded_certs: Vec<u8> =
@@ -298,11 +353,13 @@ impl AttestableCertResolver {
             .map(String::from)
             .unwrap_or_else(|| self.enclave_context.get_cert_name());
         let maybe_decoded_nonce = server_name.and_then(Self::extract_nonce_from_servername);
+        let intermediate_ca = self.intermediate_ca();
+        let (internal_ca, internal_pk) = intermediate_ca.as_ref();
         // if nonce is set, we need to generate a fresh cert
         if let Some(nonce) = maybe_decoded_nonce {
             let certified_key = Self::generate_self_signed_cert(
-                self.internal_ca.as_ref(),
-                self.internal_pk.as_ref(),
+                internal_ca.as_ref(),
+                internal_pk.as_ref(),
                 vec![sni_header],
                 Some(nonce),
             )
@@ -313,7 +370,11 @@ impl AttestableCertResolver {
             .ok()
             .map(|(_expiry, cert)| Arc::new(cert))?;
             Some(certified_key)
-        } else if Self::is_trusted_cert_domain(server_name) {
+        } else if let Some(customer_cert) =
+            server_name.and_then(customer_cert_store::get_customer_cert)
+        {
+            Some(customer_cert)
+        } else if Self::is_trusted_cert_domain(server_name) || self.is_custom_domain(server_name) {
             if let Ok(cert_ref) = TRUSTED_CERT_STORE.try_read() {
                 if let Some(cert) = &*cert_ref {
                     return Some(Arc::new(cert.clone()));
@@ -324,8 +385,8 @@ impl AttestableCertResolver {
             self.base_cert_container.resolve_cert(|| {
                 let enclave_hostnames = self.enclave_context.get_cert_names();
                 Self::generate_self_signed_cert(
-                    self.internal_ca.as_ref(),
-                    self.internal_pk.as_ref(),
+                    internal_ca.as_ref(),
+                    internal_pk.as_ref(),
                     enclave_hostnames,
                     None,
                 )
@@ -335,8 +396,8 @@ impl AttestableCertResolver {
             self.base_cert_container.resolve_cert(|| {
                 let enclave_hostnames = self.enclave_context.get_cert_names();
                 Self::generate_self_signed_cert(
-                    self.internal_ca.as_ref(),
-                    self.internal_pk.as_ref(),
+                    internal_ca.as_ref(),
+                    internal_pk.as_ref(),
                     enclave_hostnames,
                     None,
                 )
@@ -650,6 +711,32 @@ mod tests {
         assert_eq!(get_digest!(&first_x509), get_digest!(&second_x509));
     }
 
+    #[test]
+    #[serial]
+    fn test_base_cert_is_reissued_when_intermediate_ca_rotates() {
+        init_context();
+        let (cert, key) = generate_ca().unwrap();
+        let resolver = AttestableCertResolver::new(cert.clone(), key).unwrap();
+        let first_cert = resolver.resolve_cert_using_sni(None).unwrap();
+        let first_x509 = parse_x509_from_rustls_certified_key(&first_cert);
+        assert!(first_x509.verify(&cert.public_key().unwrap()).unwrap());
+
+        let (renewed_cert, renewed_key) = generate_ca().unwrap();
+        resolver
+            .rotate_intermediate_ca(renewed_cert.clone(), renewed_key)
+            .unwrap();
+        let second_cert = resolver.resolve_cert_using_sni(None).unwrap();
+        let second_x509 = parse_x509_from_rustls_certified_key(&second_cert);
+        assert_ne!(get_digest!(&first_x509), get_digest!(&second_x509));
+        assert!(second_x509
+            .verify(&renewed_cert.public_key().unwrap())
+            .unwrap());
+        assert_eq!(
+            get_digest!(&resolver.intermediate_ca().0),
+            get_digest!(&renewed_cert)
+        );
+    }
+
     #[test]
     #[serial]
     fn test_checking_for_trusted_hostname_true() {
@@ -664,6 +751,17 @@ mod tests {
         assert!(!AttestableCertResolver::is_trusted_cert_domain(hostname));
     }
 
+    #[test]
+    #[serial]
+    fn test_checking_for_custom_domain() {
+        let (cert, key) = generate_ca().unwrap();
+        let mut resolver = AttestableCertResolver::new(cert, key).unwrap();
+        resolver.custom_domains = vec!["payments.example.com".to_string()];
+        assert!(resolver.is_custom_domain(Some("Payments.Example.com")));
+        assert!(!resolver.is_custom_domain(Some("other.example.com")));
+        assert!(!resolver.is_custom_domain(None));
+    }
+
     pub fn generate_ca() -> Result<(X509, PKey<Private>), ErrorStack> {
         let ec_group = EcGroup::from_curve_name(Nid::SECP384R1)?;
         let ec_key = EcKey::generate(ec_group.as_ref())?;
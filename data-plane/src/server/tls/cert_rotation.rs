@@ -0,0 +1,112 @@
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::error::ErrorStack;
+use openssl::x509::X509Ref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cert_resolver::AttestableCertResolver;
+use super::inter_ca_retreiver::InterCaRetreiver;
+
+/// How long to wait before trying again when a renewal fails.
+const RENEWAL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Certs are never renewed more often than this, however short their lifetime.
+const MIN_RENEWAL_DELAY: Duration = Duration::from_secs(60);
+
+/// Renew the intermediate CA once two thirds of its lifetime has passed, leaving the last third to retry in if the
+/// provisioner is unavailable. New certs are signed with the renewed CA straight away, while connections which
+/// completed their handshake with the old one carry on untouched.
+pub async fn run(resolver: Arc<AttestableCertResolver>) {
+    let retriever = InterCaRetreiver::new();
+    loop {
+        let delay = match time_until_renewal(&resolver.intermediate_ca().0) {
+            Ok(delay) => delay,
+            Err(e) => {
+                log::error!(
+                    "Couldn't read the intermediate CA's validity, so it won't be renewed - {e}"
+                );
+                return;
+            }
+        };
+        log::info!("Renewing the intermediate CA in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+
+        loop {
+            let renewed = match retriever.renew_intermediate_ca().await {
+                Ok((cert, key)) => resolver
+                    .rotate_intermediate_ca(cert, key)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match renewed {
+                Ok(()) => break log::info!("Renewed the intermediate CA"),
+                Err(e) => {
+                    log::error!(
+                        "Failed to renew the intermediate CA, retrying in {}s - {e}",
+                        RENEWAL_RETRY_INTERVAL.as_secs()
+                    );
+                    tokio::time::sleep(RENEWAL_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+fn time_until_renewal(cert: &X509Ref) -> Result<Duration, ErrorStack> {
+    let not_before = unix_time(cert.not_before())?;
+    let not_after = unix_time(cert.not_after())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    Ok(renewal_delay(not_before, not_after, now))
+}
+
+fn unix_time(time: &Asn1TimeRef) -> Result<i64, ErrorStack> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    Ok(i64::from(diff.days) * 24 * 60 * 60 + i64::from(diff.secs))
+}
+
+fn renewal_delay(not_before: i64, not_after: i64, now: i64) -> Duration {
+    let renew_at = not_before + (not_after - not_before) * 2 / 3;
+    let delay = Duration::from_secs((renew_at - now).max(0) as u64);
+    delay.max(MIN_RENEWAL_DELAY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{renewal_delay, time_until_renewal, MIN_RENEWAL_DELAY};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+    use std::time::Duration;
+
+    #[test]
+    fn test_certs_are_renewed_two_thirds_of_the_way_through_their_lifetime() {
+        assert_eq!(renewal_delay(0, 3000, 0), Duration::from_secs(2000));
+        assert_eq!(renewal_delay(0, 3000, 1500), Duration::from_secs(500));
+        // Overdue and short lived certs are renewed without spinning
+        assert_eq!(renewal_delay(0, 3000, 2999), MIN_RENEWAL_DELAY);
+        assert_eq!(renewal_delay(0, 30, 0), MIN_RENEWAL_DELAY);
+    }
+
+    #[test]
+    fn test_renewal_time_is_read_from_the_cert() {
+        let key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let delay = time_until_renewal(&cert.build()).unwrap();
+        let twenty_days = Duration::from_secs(20 * 24 * 60 * 60);
+        assert!(delay <= twenty_days && delay > twenty_days - Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,202 @@
+//! Mutual TLS for ingress. When the provisioner supplies client CAs, clients must present a cert issued by one of them,
+//! and the verified cert is described to the customer process in request headers.
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use once_cell::sync::OnceCell;
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509NameRef, X509};
+use shared::utils::HexSlice;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerifier};
+use tokio_rustls::rustls::{Certificate, RootCertStore};
+
+use crate::server::error::{ServerResult, TlsError};
+
+/// The client cert, base64 encoded DER wrapped in colons as in RFC 9440's `Client-Cert` header.
+pub const CLIENT_CERT_HEADER: &str = "x-client-cert";
+pub const CLIENT_CERT_SUBJECT_HEADER: &str = "x-client-cert-subject";
+pub const CLIENT_CERT_ISSUER_HEADER: &str = "x-client-cert-issuer";
+pub const CLIENT_CERT_SERIAL_HEADER: &str = "x-client-cert-serial";
+pub const CLIENT_CERT_FINGERPRINT_HEADER: &str = "x-client-cert-sha256";
+
+const CLIENT_CERT_HEADERS: [&str; 5] = [
+    CLIENT_CERT_HEADER,
+    CLIENT_CERT_SUBJECT_HEADER,
+    CLIENT_CERT_ISSUER_HEADER,
+    CLIENT_CERT_SERIAL_HEADER,
+    CLIENT_CERT_FINGERPRINT_HEADER,
+];
+
+/// Base64 encoded PEM bundle of client CAs from the first provisioner response. The verifier is built once at startup,
+/// so CAs supplied on renewal only take effect when the enclave restarts.
+static CLIENT_CA_CERTS: OnceCell<Option<String>> = OnceCell::new();
+
+pub fn set_client_ca_certs(client_ca_certs: Option<&str>) {
+    let _ = CLIENT_CA_CERTS.set(client_ca_certs.map(String::from));
+}
+
+/// A verifier requiring certs issued by the provisioned client CAs, or `None` if none were provisioned.
+pub fn client_verifier() -> ServerResult<Option<Arc<dyn ClientCertVerifier>>> {
+    let Some(Some(client_ca_certs)) = CLIENT_CA_CERTS.get() else {
+        return Ok(None);
+    };
+    let client_ca_certs = base64::decode(client_ca_certs)
+        .map_err(|e| TlsError::CertProvisionerError(e.to_string()))?;
+    let mut roots = RootCertStore::empty();
+    for ca_cert in pem::parse_many(client_ca_certs)?
+        .into_iter()
+        .filter(|p| p.tag == "CERTIFICATE")
+    {
+        roots.add(&Certificate(ca_cert.contents))?;
+    }
+    // An empty store would reject every client, so treat it as a provisioning error rather than serving nothing
+    if roots.is_empty() {
+        return Err(TlsError::CertProvisionerError(
+            "No client CA certs found in provisioner response".to_string(),
+        ));
+    }
+    Ok(Some(AllowAnyAuthenticatedClient::new(roots).boxed()))
+}
+
+/// Details of a verified client cert, formatted as the headers sent to the customer process.
+#[derive(Clone, Debug)]
+pub struct ClientCertDetails {
+    cert: String,
+    subject: String,
+    issuer: String,
+    serial: String,
+    fingerprint: String,
+}
+
+impl ClientCertDetails {
+    /// Describe the leaf of a connection's peer certs, if the client presented any.
+    pub fn from_peer_certificates(peer_certificates: Option<&[Certificate]>) -> Option<Self> {
+        let leaf = peer_certificates?.first()?;
+        match Self::from_der(&leaf.0) {
+            Ok(details) => Some(details),
+            Err(e) => {
+                log::error!("Failed to parse verified client cert — {e}");
+                None
+            }
+        }
+    }
+
+    fn from_der(der: &[u8]) -> Result<Self, openssl::error::ErrorStack> {
+        let cert = X509::from_der(der)?;
+        let fingerprint = cert.digest(MessageDigest::sha256())?;
+        Ok(Self {
+            cert: format!(":{}:", base64::encode(der)),
+            subject: format_name(cert.subject_name()),
+            issuer: format_name(cert.issuer_name()),
+            serial: cert.serial_number().to_bn()?.to_hex_str()?.to_string(),
+            fingerprint: format!("{:x}", HexSlice::from(fingerprint.as_ref())),
+        })
+    }
+}
+
+/// Replace any client cert headers sent by the client with the details of the cert verified in the handshake, so the
+/// customer process can trust them.
+pub fn set_client_cert_headers(headers: &mut HeaderMap, client_cert: Option<&ClientCertDetails>) {
+    for header in CLIENT_CERT_HEADERS {
+        headers.remove(header);
+    }
+    let Some(client_cert) = client_cert else {
+        return;
+    };
+    for (header, value) in [
+        (CLIENT_CERT_HEADER, &client_cert.cert),
+        (CLIENT_CERT_SUBJECT_HEADER, &client_cert.subject),
+        (CLIENT_CERT_ISSUER_HEADER, &client_cert.issuer),
+        (CLIENT_CERT_SERIAL_HEADER, &client_cert.serial),
+        (CLIENT_CERT_FINGERPRINT_HEADER, &client_cert.fingerprint),
+    ] {
+        // Names can hold characters which aren't valid in headers, in which case that header is left out
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(HeaderName::from_static(header), value);
+        }
+    }
+}
+
+/// Format a distinguished name as comma separated `type=value` pairs, in the order they appear in the cert.
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let field = entry.object().nid().short_name().unwrap_or("UNKNOWN");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{field}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{set_client_cert_headers, ClientCertDetails};
+    use hyper::header::HeaderMap;
+    use openssl::asn1::{Asn1Integer, Asn1Time};
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio_rustls::rustls::Certificate;
+
+    fn client_cert() -> Certificate {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Acme").unwrap();
+        name.append_entry_by_text("CN", "payments-service").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = Asn1Integer::from_bn(&BigNum::from_u32(0x1234).unwrap()).unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        Certificate(cert.build().to_der().unwrap())
+    }
+
+    #[test]
+    fn test_client_cert_details_are_set_as_headers() {
+        let cert = client_cert();
+        let details = ClientCertDetails::from_peer_certificates(Some(&[cert.clone()])).unwrap();
+        let mut headers = HeaderMap::new();
+        set_client_cert_headers(&mut headers, Some(&details));
+
+        assert_eq!(
+            headers.get("x-client-cert").unwrap(),
+            &format!(":{}:", base64::encode(&cert.0))
+        );
+        assert_eq!(
+            headers.get("x-client-cert-subject").unwrap(),
+            "O=Acme,CN=payments-service"
+        );
+        assert_eq!(
+            headers.get("x-client-cert-issuer").unwrap(),
+            "O=Acme,CN=payments-service"
+        );
+        assert_eq!(headers.get("x-client-cert-serial").unwrap(), "1234");
+        assert_eq!(headers.get("x-client-cert-sha256").unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_client_cert_headers_from_the_client_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-client-cert-subject", "CN=admin".parse().unwrap());
+        headers.insert("x-client-cert-sha256", "spoofed".parse().unwrap());
+        set_client_cert_headers(&mut headers, None);
+        assert!(headers.is_empty());
+        assert!(ClientCertDetails::from_peer_certificates(None).is_none());
+    }
+}
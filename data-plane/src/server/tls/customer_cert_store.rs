@@ -0,0 +1,176 @@
+use once_cell::sync::Lazy;
+use shared::server::config_server::requests::CustomerCert;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+use zeroize::Zeroizing;
+
+use crate::server::error::{ServerResult, TlsError};
+use crate::utils::audit::AuditedSigningKey;
+
+/// Customer supplied certs by lowercased server name, including any `*.` wildcard prefix.
+static CUSTOMER_CERT_STORE: Lazy<RwLock<HashMap<String, Arc<CertifiedKey>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Replace the customer certs with the ones from the latest provisioner response. Certs which can't be parsed are
+/// logged and skipped, so connections for their server names fall back to the provisioned certs.
+pub fn set_customer_certs(customer_certs: &[CustomerCert]) {
+    let mut certs = HashMap::new();
+    for customer_cert in customer_certs {
+        match to_certified_key(customer_cert) {
+            Ok(certified_key) => {
+                let certified_key = Arc::new(certified_key);
+                for server_name in &customer_cert.server_names {
+                    certs.insert(server_name.to_lowercase(), certified_key.clone());
+                }
+            }
+            Err(e) => log::error!(
+                "Failed to load customer cert for {:?}: {e}",
+                customer_cert.server_names
+            ),
+        }
+    }
+    *CUSTOMER_CERT_STORE.write().unwrap() = certs;
+}
+
+/// The customer cert for a server name, matching it exactly before trying a wildcard for its parent domain.
+pub fn get_customer_cert(server_name: &str) -> Option<Arc<CertifiedKey>> {
+    let certs = CUSTOMER_CERT_STORE.read().unwrap();
+    if certs.is_empty() {
+        return None;
+    }
+    let server_name = server_name.to_lowercase();
+    certs.get(&server_name).cloned().or_else(|| {
+        let (_, parent) = server_name.split_once('.')?;
+        certs.get(&format!("*.{parent}")).cloned()
+    })
+}
+
+/// The distinct cert chains of the customer certs, for refreshing their OCSP staples.
+pub fn customer_cert_chains() -> Vec<Vec<Certificate>> {
+    let certs = CUSTOMER_CERT_STORE.read().unwrap();
+    let mut chains: Vec<Vec<Certificate>> = Vec::new();
+    for cert in certs.values() {
+        if !chains.contains(&cert.cert) {
+            chains.push(cert.cert.clone());
+        }
+    }
+    chains
+}
+
+/// Staple an OCSP response to the customer cert with the given leaf, under each of its server names.
+pub fn staple(leaf: &Certificate, ocsp_response: Vec<u8>) {
+    let mut certs = CUSTOMER_CERT_STORE.write().unwrap();
+    let mut stapled: Option<Arc<CertifiedKey>> = None;
+    for cert in certs.values_mut() {
+        if cert.cert.first() == Some(leaf) {
+            let stapled = stapled.get_or_insert_with(|| {
+                Arc::new(CertifiedKey {
+                    ocsp: Some(ocsp_response.clone()),
+                    ..cert.as_ref().clone()
+                })
+            });
+            *cert = stapled.clone();
+        }
+    }
+}
+
+fn to_certified_key(customer_cert: &CustomerCert) -> ServerResult<CertifiedKey> {
+    let cert_chain = base64::decode(&customer_cert.cert_chain)
+        .map_err(|e| TlsError::CertProvisionerError(e.to_string()))?;
+    let cert_chain: Vec<Certificate> = pem::parse_many(cert_chain)?
+        .into_iter()
+        .filter(|p| p.tag == "CERTIFICATE")
+        .map(|p| Certificate(p.contents))
+        .collect();
+    if cert_chain.is_empty() {
+        return Err(TlsError::NoCertFound);
+    }
+
+    let key = Zeroizing::new(
+        base64::decode(customer_cert.key().as_bytes())
+            .map_err(|e| TlsError::CertProvisionerError(e.to_string()))?,
+    );
+    let key = openssl::pkey::PKey::private_key_from_pem(&key).map_err(|_| TlsError::NoKeyFound)?;
+    let signing_key = AuditedSigningKey::wrap(
+        sign::any_supported_type(&PrivateKey(key.private_key_to_pkcs8()?))?,
+        "customer_cert_key",
+    );
+    Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{customer_cert_chains, get_customer_cert, set_customer_certs, staple};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+    use serial_test::serial;
+    use shared::server::config_server::requests::CustomerCert;
+
+    fn customer_cert(server_names: &[&str]) -> CustomerCert {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", server_names[0]).unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        CustomerCert::new(
+            server_names.iter().map(|name| name.to_string()).collect(),
+            base64::encode(cert.build().to_pem().unwrap()),
+            base64::encode(key.private_key_to_pem_pkcs8().unwrap()),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_customer_certs_are_matched_by_server_name() {
+        set_customer_certs(&[
+            customer_cert(&["api.example.com"]),
+            customer_cert(&["*.apps.example.com"]),
+        ]);
+        assert!(get_customer_cert("API.example.com").is_some());
+        assert!(get_customer_cert("billing.apps.example.com").is_some());
+        assert!(get_customer_cert("a.billing.apps.example.com").is_none());
+        assert!(get_customer_cert("www.example.com").is_none());
+        set_customer_certs(&[]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_invalid_customer_certs_are_skipped() {
+        let mut invalid = customer_cert(&["broken.example.com"]);
+        invalid.cert_chain = base64::encode("not a cert");
+        set_customer_certs(&[invalid, customer_cert(&["api.example.com"])]);
+        assert!(get_customer_cert("broken.example.com").is_none());
+        assert!(get_customer_cert("api.example.com").is_some());
+        set_customer_certs(&[]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_staples_are_shared_by_each_server_name() {
+        set_customer_certs(&[customer_cert(&["api.example.com", "www.example.com"])]);
+        let chains = customer_cert_chains();
+        assert_eq!(chains.len(), 1);
+        staple(&chains[0][0], b"ocsp".to_vec());
+        for server_name in ["api.example.com", "www.example.com"] {
+            let cert = get_customer_cert(server_name).unwrap();
+            assert_eq!(cert.ocsp.as_deref(), Some(b"ocsp".as_slice()));
+        }
+        set_customer_certs(&[]);
+    }
+}
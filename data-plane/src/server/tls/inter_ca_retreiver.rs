@@ -1,6 +1,10 @@
 use openssl::pkey::{PKey, Private};
 use openssl::x509::X509;
+use shared::server::config_server::requests::GetCertResponseDataPlane;
+use zeroize::Zeroizing;
 
+use super::{client_auth, customer_cert_store};
+use crate::base_tls_client::E3CertVerifier;
 use crate::e3client::E3Client;
 use crate::env::Environment;
 use crate::error::{Error, Result};
@@ -28,16 +32,13 @@ impl InterCaRetreiver {
     }
 
     pub async fn get_intermediate_ca(&self) -> Result<(X509, PKey<Private>)> {
-        log::info!("Sending request to control plane for cert provisioner token.");
-        let token = self.config_client.get_cert_token().await?.token();
-
-        log::info!("Received token for cert provisioner. Requesting intermediate CA.");
-        let cert_response = self
-            .cert_provisioner_client
-            .get_cert(token)
-            .await
-            .map_err(|err| Error::CertServer(err.to_string()))?;
+        let cert_response = self.get_cert_response().await?;
         EnclaveContext::set(cert_response.context.clone().into());
+        if let Some(e3_identity) = cert_response.e3_identity.clone() {
+            E3CertVerifier::set_identity(e3_identity);
+        }
+        customer_cert_store::set_customer_certs(&cert_response.customer_certs);
+        client_auth::set_client_ca_certs(cert_response.client_ca_certs.as_deref());
         self.env
             .clone()
             .init(cert_response.clone().secrets.unwrap())
@@ -48,6 +49,27 @@ impl InterCaRetreiver {
 
         Ok((inter_ca_cert, inter_ca_key_pair))
     }
+
+    /// Request a new intermediate CA ahead of the current one expiring. The enclave's context and secrets were set up
+    /// with the first one, so only the cert and key are used, along with any updated customer certs.
+    pub async fn renew_intermediate_ca(&self) -> Result<(X509, PKey<Private>)> {
+        let cert_response = self.get_cert_response().await?;
+        customer_cert_store::set_customer_certs(&cert_response.customer_certs);
+        let inter_ca_cert = parse_cert(cert_response.cert())?;
+        let inter_ca_key_pair = parse_key(cert_response.key_pair())?;
+        Ok((inter_ca_cert, inter_ca_key_pair))
+    }
+
+    async fn get_cert_response(&self) -> Result<GetCertResponseDataPlane> {
+        log::info!("Sending request to control plane for cert provisioner token.");
+        let token = self.config_client.get_cert_token().await?.token();
+
+        log::info!("Received token for cert provisioner. Requesting intermediate CA.");
+        self.cert_provisioner_client
+            .get_cert(token)
+            .await
+            .map_err(|err| Error::CertServer(err.to_string()))
+    }
 }
 
 fn parse_cert(raw_cert: String) -> Result<X509> {
@@ -55,7 +77,9 @@ fn parse_cert(raw_cert: String) -> Result<X509> {
     X509::from_pem(&decoded_cert).map_err(|err| Error::Crypto(err.to_string()))
 }
 
-fn parse_key(raw_key: String) -> Result<PKey<Private>> {
-    let decoded_key = base64::decode(raw_key).map_err(|err| Error::Crypto(err.to_string()))?;
+fn parse_key(raw_key: Zeroizing<String>) -> Result<PKey<Private>> {
+    let decoded_key = Zeroizing::new(
+        base64::decode(raw_key.as_bytes()).map_err(|err| Error::Crypto(err.to_string()))?,
+    );
     PKey::private_key_from_pem(&decoded_key).map_err(|err| Error::Crypto(err.to_string()))
 }
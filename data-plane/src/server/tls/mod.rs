@@ -1,5 +1,12 @@
+#[cfg(feature = "enclave")]
+pub mod attestation_extension;
 mod cert_resolver;
+mod cert_rotation;
+pub(crate) mod client_auth;
+pub(crate) mod customer_cert_store;
 pub(crate) mod inter_ca_retreiver;
+mod ocsp_stapling;
+mod session_resumption;
 mod tls_server;
 pub mod trusted_cert_container;
 
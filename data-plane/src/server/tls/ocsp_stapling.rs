@@ -0,0 +1,283 @@
+//! Staples OCSP responses to the publicly trusted ingress certs, so clients can check they haven't been revoked
+//! without a lookup of their own. Responses are fetched through the control plane, and only stapled once they've been
+//! checked against the cert's issuer.
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::X509;
+use std::time::Duration;
+use tokio_rustls::rustls::Certificate;
+
+use super::customer_cert_store;
+use super::trusted_cert_container::TRUSTED_CERT_STORE;
+use crate::config_client::ConfigClient;
+use crate::error::{Error, Result};
+
+/// Responses are valid for days, so refreshing hourly keeps a fresh one stapled, and staples a renewed cert soon
+/// after it's swapped in.
+const OCSP_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Leeway for clock differences between the enclave and the responder when checking a response's validity period.
+const OCSP_VALIDITY_LEEWAY_SECS: u32 = 5 * 60;
+
+pub async fn run() {
+    let config_client = ConfigClient::new();
+    let mut interval = tokio::time::interval(OCSP_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let trusted_cert_chain = TRUSTED_CERT_STORE
+            .read()
+            .ok()
+            .and_then(|store| store.as_ref().map(|cert| cert.cert.clone()));
+        let cert_chains = trusted_cert_chain
+            .into_iter()
+            .chain(customer_cert_store::customer_cert_chains());
+
+        for cert_chain in cert_chains {
+            match fetch_ocsp_response(&config_client, &cert_chain).await {
+                Ok(Some(ocsp_response)) => staple(&cert_chain[0], ocsp_response),
+                Ok(None) => {}
+                // The previous response is kept until the next refresh, as it's likely still valid
+                Err(e) => log::warn!("Failed to refresh OCSP response for ingress cert — {e}"),
+            }
+        }
+    }
+}
+
+fn staple(leaf: &Certificate, ocsp_response: Vec<u8>) {
+    if let Ok(mut store) = TRUSTED_CERT_STORE.write() {
+        if let Some(cert) = store
+            .as_mut()
+            .filter(|cert| cert.cert.first() == Some(leaf))
+        {
+            cert.ocsp = Some(ocsp_response.clone());
+        }
+    }
+    customer_cert_store::staple(leaf, ocsp_response);
+}
+
+/// Fetch a verified, good OCSP response for the leaf of a cert chain, or `None` if the leaf doesn't name a responder
+/// or the chain doesn't include its issuer.
+async fn fetch_ocsp_response(
+    config_client: &ConfigClient,
+    cert_chain: &[Certificate],
+) -> Result<Option<Vec<u8>>> {
+    let Some(request) = build_ocsp_request(cert_chain)? else {
+        return Ok(None);
+    };
+    let response = config_client
+        .get_ocsp_response(request.responder_url, request.request)
+        .await?;
+    verify_ocsp_response(&response, &request.leaf, &request.issuer)?;
+    Ok(Some(response))
+}
+
+struct CertOcspRequest {
+    responder_url: String,
+    /// DER encoded OCSP request
+    request: Vec<u8>,
+    leaf: X509,
+    issuer: X509,
+}
+
+fn build_ocsp_request(cert_chain: &[Certificate]) -> Result<Option<CertOcspRequest>> {
+    let (Some(leaf), Some(issuer)) = (cert_chain.first(), cert_chain.get(1)) else {
+        return Ok(None);
+    };
+    let leaf = X509::from_der(&leaf.0).map_err(crypto_error)?;
+    let issuer = X509::from_der(&issuer.0).map_err(crypto_error)?;
+    // Certs without an authority information access extension have no responders, which is an error to openssl
+    let responder_url = leaf
+        .ocsp_responders()
+        .ok()
+        .and_then(|responders| responders.iter().next().map(|url| url.to_string()));
+    let Some(responder_url) = responder_url else {
+        return Ok(None);
+    };
+
+    let cert_id =
+        OcspCertId::from_cert(MessageDigest::sha1(), &leaf, &issuer).map_err(crypto_error)?;
+    let mut request = OcspRequest::new().map_err(crypto_error)?;
+    request.add_id(cert_id).map_err(crypto_error)?;
+    let request = request.to_der().map_err(crypto_error)?;
+    Ok(Some(CertOcspRequest {
+        responder_url,
+        request,
+        leaf,
+        issuer,
+    }))
+}
+
+fn verify_ocsp_response(response: &[u8], leaf: &X509, issuer: &X509) -> Result<()> {
+    let response = OcspResponse::from_der(response).map_err(crypto_error)?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(Error::Crypto(format!(
+            "OCSP responder returned status {:?}",
+            response.status()
+        )));
+    }
+    let basic_response = response.basic().map_err(crypto_error)?;
+
+    // The response must be signed by the issuer, or a responder the issuer has delegated to
+    let mut store = X509StoreBuilder::new().map_err(crypto_error)?;
+    store.add_cert(issuer.clone()).map_err(crypto_error)?;
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(crypto_error)?;
+    let store = store.build();
+    let mut certs = Stack::new().map_err(crypto_error)?;
+    certs.push(issuer.clone()).map_err(crypto_error)?;
+    basic_response
+        .verify(&certs, &store, OcspFlag::empty())
+        .map_err(crypto_error)?;
+
+    let cert_id =
+        OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).map_err(crypto_error)?;
+    let status = basic_response
+        .find_status(&cert_id)
+        .ok_or_else(|| Error::Crypto("OCSP response doesn't cover the cert".to_string()))?;
+    if status.status != OcspCertStatus::GOOD {
+        return Err(Error::Crypto(format!(
+            "OCSP response has cert status {:?}",
+            status.status
+        )));
+    }
+    status
+        .check_validity(OCSP_VALIDITY_LEEWAY_SECS, None)
+        .map_err(crypto_error)
+}
+
+fn crypto_error(e: openssl::error::ErrorStack) -> Error {
+    Error::Crypto(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_ocsp_request, verify_ocsp_response};
+    use openssl::asn1::Asn1Time;
+    use openssl::asn1::{Asn1Object, Asn1OctetString};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::ocsp::{OcspResponse, OcspResponseStatus};
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Extension, X509NameBuilder, X509};
+    use tokio_rustls::rustls::Certificate;
+
+    fn build_cert(
+        common_name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        ocsp_responder: Option<&str>,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if let Some(responder) = ocsp_responder {
+            cert.append_extension(ocsp_responder_extension(responder))
+                .unwrap();
+        }
+        let (issuer_name, signing_key) = match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                (issuer_cert.subject_name().to_owned().unwrap(), issuer_key)
+            }
+            None => (name, key),
+        };
+        cert.set_issuer_name(&issuer_name).unwrap();
+        cert.sign(signing_key, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    /// An authority information access extension naming an OCSP responder, encoded by hand as the openssl crate has
+    /// no builder for it. Only short form lengths are used, so the URL must be under 100 bytes.
+    fn ocsp_responder_extension(responder: &str) -> X509Extension {
+        let id_ad_ocsp = [0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+        let mut access_description = id_ad_ocsp.to_vec();
+        access_description.extend([0x86, responder.len() as u8]);
+        access_description.extend(responder.as_bytes());
+        let mut access_descriptions = vec![0x30, access_description.len() as u8];
+        access_descriptions.extend(access_description);
+        let mut der = vec![0x30, access_descriptions.len() as u8];
+        der.extend(access_descriptions);
+        X509Extension::new_from_der(
+            &Asn1Object::from_str("1.3.6.1.5.5.7.1.1").unwrap(),
+            false,
+            &Asn1OctetString::new_from_bytes(&der).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn chain(certs: &[&X509]) -> Vec<Certificate> {
+        certs
+            .iter()
+            .map(|cert| Certificate(cert.to_der().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_ocsp_requests_are_built_for_certs_naming_a_responder() {
+        let issuer_key = generate_key();
+        let issuer = build_cert("issuer", &issuer_key, None, None);
+        let leaf = build_cert(
+            "api.example.com",
+            &generate_key(),
+            Some((&issuer, &issuer_key)),
+            Some("http://ocsp.example.com"),
+        );
+
+        let request = build_ocsp_request(&chain(&[&leaf, &issuer]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.responder_url, "http://ocsp.example.com");
+        assert!(!request.request.is_empty());
+        assert!(build_ocsp_request(&chain(&[&leaf])).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ocsp_requests_are_skipped_for_certs_without_a_responder() {
+        let issuer_key = generate_key();
+        let issuer = build_cert("issuer", &issuer_key, None, None);
+        let leaf = build_cert(
+            "api.example.com",
+            &generate_key(),
+            Some((&issuer, &issuer_key)),
+            None,
+        );
+        assert!(build_ocsp_request(&chain(&[&leaf, &issuer]))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_unsuccessful_ocsp_responses_are_rejected() {
+        let issuer_key = generate_key();
+        let issuer = build_cert("issuer", &issuer_key, None, None);
+        let leaf = build_cert(
+            "api.example.com",
+            &generate_key(),
+            Some((&issuer, &issuer_key)),
+            None,
+        );
+        let response = OcspResponse::create(OcspResponseStatus::TRY_LATER, None)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        assert!(verify_ocsp_response(&response, &leaf, &issuer).is_err());
+    }
+}
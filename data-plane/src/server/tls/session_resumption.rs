@@ -0,0 +1,176 @@
+//! TLS session resumption for ingress connections, so clients which reconnect often can skip the full handshake.
+//! Sessions are resumed from an in memory session ID cache, or from tickets encrypted with a key which is rotated on
+//! a schedule. Tickets from the previous key are still accepted, so clients aren't all forced into full handshakes at
+//! once, but anything older is unreadable, limiting what a leaked ticket key exposes.
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use shared::locked_secret::LockedSecret;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::server::{
+    NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache,
+};
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::configuration;
+
+const TICKET_KEY_LENGTH: usize = 32;
+const TICKET_NONCE_LENGTH: usize = 12;
+const TICKET_TAG_LENGTH: usize = 16;
+
+// Shared rather than copied for each ticket, so the key only ever lives in its locked pages
+type TicketKey = Arc<LockedSecret<TICKET_KEY_LENGTH>>;
+
+/// Set up session resumption on an ingress TLS config, as configured by the environment.
+pub fn configure(tls_config: &mut ServerConfig) {
+    let cache_size = configuration::get_tls_session_cache_size();
+    tls_config.session_storage = if cache_size > 0 {
+        ServerSessionMemoryCache::new(cache_size)
+    } else {
+        Arc::new(NoServerSessionStorage {})
+    };
+    if let Some(rotation) = configuration::get_tls_ticket_rotation() {
+        tls_config.ticketer = Arc::new(RotatingTicketer::new(rotation));
+    }
+}
+
+/// Encrypts session tickets with AES-256-GCM, rotating the key once it's been in use for the rotation interval.
+struct RotatingTicketer {
+    rotation: Duration,
+    keys: Mutex<TicketKeys>,
+}
+
+struct TicketKeys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    rotated_at: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> Self {
+        Self {
+            rotation,
+            keys: Mutex::new(TicketKeys {
+                current: generate_key(),
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// The current and previous keys, rotating them first if the current key is due to be replaced.
+    fn keys_at(&self, now: Instant) -> (TicketKey, Option<TicketKey>) {
+        let mut keys = self.keys.lock().unwrap();
+        if now.saturating_duration_since(keys.rotated_at) >= self.rotation {
+            // Keys are only rotated when tickets are in use, so a key which has been idle for more than one rotation
+            // is dropped rather than kept as the previous key
+            let previous = std::mem::replace(&mut keys.current, generate_key());
+            keys.previous = (now.saturating_duration_since(keys.rotated_at) < self.rotation * 2)
+                .then_some(previous);
+            keys.rotated_at = now;
+        }
+        (keys.current.clone(), keys.previous.clone())
+    }
+
+    fn encrypt_at(&self, plain: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let (key, _) = self.keys_at(now);
+        let mut nonce = [0; TICKET_NONCE_LENGTH];
+        openssl::rand::rand_bytes(&mut nonce).ok()?;
+        let mut tag = [0; TICKET_TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key.as_slice(),
+            Some(&nonce),
+            &[],
+            plain,
+            &mut tag,
+        )
+        .ok()?;
+        Some([nonce.as_slice(), &ciphertext, &tag].concat())
+    }
+
+    fn decrypt_at(&self, cipher: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if cipher.len() < TICKET_NONCE_LENGTH + TICKET_TAG_LENGTH {
+            return None;
+        }
+        let (nonce, rest) = cipher.split_at(TICKET_NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TICKET_TAG_LENGTH);
+        let (current, previous) = self.keys_at(now);
+        std::iter::once(current).chain(previous).find_map(|key| {
+            decrypt_aead(
+                Cipher::aes_256_gcm(),
+                key.as_slice(),
+                Some(nonce),
+                &[],
+                ciphertext,
+                tag,
+            )
+            .ok()
+        })
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.encrypt_at(plain, Instant::now())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_at(cipher, Instant::now())
+    }
+}
+
+fn generate_key() -> TicketKey {
+    let mut key = LockedSecret::zeroed();
+    openssl::rand::rand_bytes(key.as_mut_slice()).expect("Failed to generate session ticket key");
+    Arc::new(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::RotatingTicketer;
+    use std::time::{Duration, Instant};
+
+    const ROTATION: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_tickets_are_readable_until_their_key_is_rotated_twice() {
+        let ticketer = RotatingTicketer::new(ROTATION);
+        let start = Instant::now();
+        let ticket = ticketer.encrypt_at(b"session", start).unwrap();
+        assert_eq!(
+            ticketer.decrypt_at(&ticket, start).as_deref(),
+            Some(b"session".as_slice())
+        );
+
+        let first_rotation = start + ROTATION;
+        assert!(ticketer.decrypt_at(&ticket, first_rotation).is_some());
+        let second_rotation = first_rotation + ROTATION;
+        assert!(ticketer.decrypt_at(&ticket, second_rotation).is_none());
+    }
+
+    #[test]
+    fn test_tickets_from_an_idle_key_are_dropped() {
+        let ticketer = RotatingTicketer::new(ROTATION);
+        let start = Instant::now();
+        let ticket = ticketer.encrypt_at(b"session", start).unwrap();
+        assert!(ticketer.decrypt_at(&ticket, start + ROTATION * 3).is_none());
+    }
+
+    #[test]
+    fn test_tampered_tickets_are_rejected() {
+        let ticketer = RotatingTicketer::new(ROTATION);
+        let now = Instant::now();
+        let mut ticket = ticketer.encrypt_at(b"session", now).unwrap();
+        ticket[20] ^= 0xff;
+        assert!(ticketer.decrypt_at(&ticket, now).is_none());
+        assert!(ticketer.decrypt_at(&[0; 8], now).is_none());
+    }
+}
@@ -8,39 +8,85 @@ use tokio_rustls::rustls::sign::CertifiedKey;
 use openssl::pkey::PKey;
 use openssl::pkey::Private;
 use openssl::x509::X509;
+use shared::handshake_trace::{self, format_alpn};
 use shared::server::proxy_protocol::ProxiedConnection;
 use shared::server::Listener;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use tokio_rustls::rustls::server::WantsServerCert;
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::server::{Acceptor, WantsServerCert};
 use tokio_rustls::rustls::ConfigBuilder;
 use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::server::TlsStream;
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
 
 use super::inter_ca_retreiver;
 
 #[cfg(feature = "enclave")]
 use crate::acme;
 
+use crate::configuration::IngressTlsConfig;
 use crate::env::Environment;
 use crate::server::error::ServerResult;
 use crate::server::error::TlsError;
 use rand::Rng;
 
 pub struct TlsServer<S: Listener + Send + Sync> {
+    server_config: Arc<ServerConfig>,
     tls_acceptor: TlsAcceptor,
     inner: S,
 }
 
 impl<S: Listener + Send + Sync> TlsServer<S> {
     fn new(server_config: ServerConfig, tcp_server: S) -> Self {
+        let server_config = Arc::new(server_config);
         Self {
-            tls_acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            tls_acceptor: TlsAcceptor::from(server_config.clone()),
+            server_config,
             inner: tcp_server,
         }
     }
+
+    /// Accept a connection in two steps, so the Client Hello can be traced even if the handshake fails.
+    async fn accept_with_trace(
+        &self,
+        conn: <S as Listener>::Connection,
+    ) -> Result<TlsStream<<S as Listener>::Connection>, TlsError> {
+        let started = Instant::now();
+        let handshake = LazyConfigAcceptor::new(Acceptor::default(), conn)
+            .await
+            .map_err(|e| {
+                shared::handshake_trace!("TLS handshake failed before the Client Hello - {e}");
+                e
+            })?;
+        let client_hello = handshake.client_hello();
+        let server_name = client_hello.server_name().unwrap_or("none").to_string();
+        shared::handshake_trace!(
+            "TLS Client Hello: SNI {server_name}, ALPN offered {}, {} cipher suites offered",
+            format_alpn(client_hello.alpn().into_iter().flatten()),
+            client_hello.cipher_suites().len()
+        );
+
+        let stream = handshake
+            .into_stream(self.server_config.clone())
+            .await
+            .map_err(|e| {
+                shared::handshake_trace!(
+                    "TLS handshake with SNI {server_name} failed after {}ms - {e}",
+                    started.elapsed().as_millis()
+                );
+                e
+            })?;
+        let (_, session) = stream.get_ref();
+        shared::handshake_trace!(
+            "TLS handshake with SNI {server_name} completed in {}ms: version {:?}, cipher {:?}, ALPN {}",
+            started.elapsed().as_millis(),
+            session.protocol_version(),
+            session.negotiated_cipher_suite().map(|suite| suite.suite()),
+            format_alpn(session.alpn_protocol())
+        );
+        Ok(stream)
+    }
 }
 
 /// Mini state machine for wrapping a TCP server with the logic to terminate TLS
@@ -73,11 +119,22 @@ pub struct WantsCert<S: Listener> {
 pub static TRUSTED_PUB_CERT: OnceCell<Vec<u8>> = OnceCell::new();
 
 impl<S: Listener + Send + Sync> WantsCert<S> {
-    /// Get sane defaults for TLS Server config
-    fn get_base_config() -> ConfigBuilder<ServerConfig, WantsServerCert> {
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+    /// Get sane defaults for TLS Server config, restricted to the configured TLS versions and cipher suites, and
+    /// requiring client certs if client CAs were provisioned
+    fn get_base_config() -> ServerResult<ConfigBuilder<ServerConfig, WantsServerCert>> {
+        let ingress_tls_config =
+            IngressTlsConfig::from_env().map_err(TlsError::InvalidTlsConfig)?;
+        let config_builder = ServerConfig::builder()
+            .with_cipher_suites(&ingress_tls_config.cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(ingress_tls_config.protocol_versions)?;
+        Ok(match super::client_auth::client_verifier()? {
+            Some(client_verifier) => {
+                log::info!("Requiring client certs issued by the provisioned client CAs");
+                config_builder.with_client_cert_verifier(client_verifier)
+            }
+            None => config_builder.with_no_client_auth(),
+        })
     }
 
     pub async fn with_attestable_cert(self) -> ServerResult<TlsServer<S>> {
@@ -90,13 +147,19 @@ impl<S: Listener + Send + Sync> WantsCert<S> {
 
         //Once intermediate cert and trusted cert retrieved, write cage initialised vars
         Environment::write_startup_complete_env_vars()?;
-
-        let attestable_cert_resolver =
-            super::cert_resolver::AttestableCertResolver::new(ca_cert, ca_private_key)?;
-        let mut tls_config =
-            Self::get_base_config().with_cert_resolver(Arc::new(attestable_cert_resolver));
-        tls_config.alpn_protocols.push(b"http/1.1".to_vec());
+        crate::health::mark_initialized();
+
+        let attestable_cert_resolver = Arc::new(super::cert_resolver::AttestableCertResolver::new(
+            ca_cert,
+            ca_private_key,
+        )?);
+        tokio::spawn(super::cert_rotation::run(attestable_cert_resolver.clone()));
+        tokio::spawn(super::ocsp_stapling::run());
+        let mut tls_config = Self::get_base_config()?.with_cert_resolver(attestable_cert_resolver);
+        // Servers choose the first of their protocols the client offers, so h2 goes first
         tls_config.alpn_protocols.push(b"h2".to_vec());
+        tls_config.alpn_protocols.push(b"http/1.1".to_vec());
+        super::session_resumption::configure(&mut tls_config);
         Ok(TlsServer::new(tls_config, self.tcp_server))
     }
 
@@ -153,6 +216,9 @@ where
     type Error = TlsError;
     async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
         let conn = self.inner.accept().await?;
+        if handshake_trace::is_enabled() {
+            return self.accept_with_trace(conn).await;
+        }
         let accepted_tls_conn = self.tls_acceptor.accept(conn).await?;
         Ok(accepted_tls_conn)
     }
@@ -0,0 +1,213 @@
+//! Authenticates ingress connections from the control plane before any client traffic is accepted.
+//!
+//! The session token is generated inside the enclave and registered with the control plane, which must present it at
+//! the start of each connection. It is re-registered periodically so a restarted control plane is picked up.
+//!
+//! Connections are authenticated on their own tasks, so a client which opens a connection and sends nothing only
+//! holds its own task until the read times out, rather than stalling every connection behind it.
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use shared::server::error::{ServerError, ServerResult};
+use shared::server::proxy_protocol::{try_parse_proxy_protocol, AcceptedConn};
+use shared::server::session_token::{read_session_token, SessionToken};
+use shared::server::Listener;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::config_client::ConfigClient;
+
+const SESSION_TOKEN_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const SESSION_TOKEN_REGISTRATION_INTERVAL: Duration = Duration::from_secs(30);
+const SESSION_TOKEN_REGISTRATION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Authenticated connections waiting to be picked up by the server
+const READY_CONNECTION_QUEUE_LEN: usize = 64;
+
+static SESSION_TOKEN: Lazy<SessionToken> = Lazy::new(SessionToken::generate);
+
+/// Register the session token with the control plane, retrying until it succeeds, then periodically re-register it so
+/// a restarted control plane learns it again.
+pub async fn register_session_token() {
+    let config_client = ConfigClient::new();
+    loop {
+        match config_client.post_session_token(&SESSION_TOKEN).await {
+            Ok(()) => tokio::time::sleep(SESSION_TOKEN_REGISTRATION_INTERVAL).await,
+            Err(e) => {
+                log::warn!("Failed to register session token with control plane - {e}");
+                tokio::time::sleep(SESSION_TOKEN_REGISTRATION_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Read the token from the start of a connection, returning the connection only if it matches.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+    token: &SessionToken,
+) -> Option<S> {
+    let presented = match read_session_token(&mut conn, SESSION_TOKEN_READ_TIMEOUT).await {
+        Ok(presented) => presented,
+        Err(e) => {
+            log::warn!("Rejecting connection without a session token - {e}");
+            let _ = conn.shutdown().await;
+            return None;
+        }
+    };
+    if token.matches(presented.as_slice()) {
+        return Some(conn);
+    }
+    log::warn!("Rejecting connection with an invalid session token");
+    let _ = conn.shutdown().await;
+    None
+}
+
+/// Wraps a listener so that only connections presenting the session token are accepted. Connections are accepted
+/// from the inner listener on a background task, and each is authenticated on its own task before being handed out.
+pub struct AuthenticatedListener<C> {
+    ready: mpsc::Receiver<ServerResult<C>>,
+}
+
+impl<C: Send + 'static> AuthenticatedListener<C> {
+    fn spawn<L, F, Fut>(inner: L, token: &'static SessionToken, handshake: F) -> Self
+    where
+        L: Listener<Error = ServerError> + Send + 'static,
+        L::Connection: 'static,
+        F: Fn(L::Connection) -> Fut + Copy + Send + 'static,
+        Fut: Future<Output = ServerResult<C>> + Send,
+    {
+        let (sender, ready) = mpsc::channel(READY_CONNECTION_QUEUE_LEN);
+        tokio::spawn(async move {
+            let mut inner = inner;
+            loop {
+                let conn = match inner.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        if sender.send(Err(e)).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if let Some(conn) = authenticate(conn, token).await {
+                        let _ = sender.send(handshake(conn).await).await;
+                    }
+                });
+            }
+        });
+        Self { ready }
+    }
+
+    /// Authenticate connections without parsing a proxy protocol header after the token.
+    pub fn without_proxy_protocol<L>(inner: L) -> Self
+    where
+        L: Listener<Connection = C, Error = ServerError> + Send + 'static,
+    {
+        Self::spawn(inner, &SESSION_TOKEN, |conn| async move { Ok(conn) })
+    }
+}
+
+impl<C> AuthenticatedListener<AcceptedConn<C>>
+where
+    C: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    /// Authenticate connections, then parse the proxy protocol header, if any, which follows the token.
+    pub fn new<L>(inner: L) -> Self
+    where
+        L: Listener<Connection = C, Error = ServerError> + Send + 'static,
+    {
+        Self::spawn(inner, &SESSION_TOKEN, try_parse_proxy_protocol::<C>)
+    }
+}
+
+#[async_trait]
+impl<C> Listener for AuthenticatedListener<C>
+where
+    C: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+{
+    type Connection = C;
+    type Error = ServerError;
+
+    async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
+        self.ready.recv().await.unwrap_or_else(|| {
+            Err(ServerError::IoError(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Authenticated listener closed",
+            )))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuthenticatedListener;
+    use async_trait::async_trait;
+    use once_cell::sync::Lazy;
+    use shared::server::error::ServerError;
+    use shared::server::session_token::{write_session_token, SessionToken};
+    use shared::server::Listener;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::sync::mpsc;
+
+    static TOKEN: Lazy<SessionToken> = Lazy::new(SessionToken::generate);
+
+    struct TestListener(mpsc::Receiver<DuplexStream>);
+
+    #[async_trait]
+    impl Listener for TestListener {
+        type Connection = DuplexStream;
+        type Error = ServerError;
+
+        async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
+            self.0.recv().await.ok_or(ServerError::UnexpectedEOF)
+        }
+    }
+
+    fn listener() -> (
+        mpsc::Sender<DuplexStream>,
+        AuthenticatedListener<DuplexStream>,
+    ) {
+        let (sender, receiver) = mpsc::channel(8);
+        let listener = AuthenticatedListener::spawn(
+            TestListener(receiver),
+            &TOKEN,
+            |conn| async move { Ok(conn) },
+        );
+        (sender, listener)
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_does_not_block_authenticated_ones() {
+        let (connections, mut listener) = listener();
+        let (_silent_client, silent_conn) = tokio::io::duplex(64);
+        connections.send(silent_conn).await.unwrap();
+
+        let (mut client, conn) = tokio::io::duplex(64);
+        connections.send(conn).await.unwrap();
+        write_session_token(&mut client, &TOKEN).await.unwrap();
+
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_secs(1), listener.accept()).await;
+        assert!(accepted.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_is_rejected() {
+        let (connections, mut listener) = listener();
+        let (mut client, conn) = tokio::io::duplex(64);
+        connections.send(conn).await.unwrap();
+        write_session_token(&mut client, &SessionToken::generate())
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(50), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use shared::server::get_vsock_server;
+#[cfg(not(feature = "tls_termination"))]
+use shared::server::Listener;
+use shared::server::CID::Enclave;
+
+#[cfg(feature = "network_egress")]
+use crate::dns::egress_policy::EgressPolicy;
+#[cfg(feature = "network_egress")]
+use crate::dns::egressproxy::EgressProxy;
+#[cfg(feature = "network_egress")]
+use crate::dns::enclavedns::EnclaveDnsProxy;
+#[cfg(not(feature = "tls_termination"))]
+use crate::env::Environment;
+use crate::health::start_health_check_server;
+use crate::session::AuthenticatedListener;
+use crate::stats_client::StatsClient;
+#[cfg(not(feature = "local"))]
+use crate::time::ClockSync;
+use crate::FeatureContext;
+use shared::ENCLAVE_CONNECT_PORT;
+#[cfg(not(feature = "local"))]
+use tokio::time::Duration;
+
+#[cfg(not(feature = "local"))]
+const ENCLAVE_CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Start the data plane's servers and proxies, forwarding traffic to the customer process on `data_plane_port`.
+pub async fn run(data_plane_port: u16, ctx: FeatureContext) {
+    tokio::join!(
+        start(data_plane_port),
+        start_health_check_server(
+            ctx.healthcheck_port.unwrap_or(data_plane_port),
+            ctx.healthcheck,
+            ctx.healthcheck_use_tls.unwrap_or(false)
+        )
+    );
+}
+
+#[cfg(not(feature = "network_egress"))]
+async fn start(data_plane_port: u16) {
+    use crate::{crypto::api::CryptoApi, stats::StatsProxy};
+
+    StatsClient::init();
+    #[cfg(feature = "grpc_crypto_api")]
+    tokio::spawn(async {
+        if let Err(e) = crate::crypto::grpc::CryptoGrpcApi::listen().await {
+            log::error!("An error occurred within the Crypto gRPC API server — {e:?}");
+        }
+    });
+
+    let context = match FeatureContext::get() {
+        Ok(context) => context,
+        Err(e) => {
+            log::error!("Failed to access context in enclave - {e}");
+            return;
+        }
+    };
+
+    log::info!("Running data plane with egress disabled");
+    let (_, e3_api_result, stats_result, _) = tokio::join!(
+        start_data_plane(data_plane_port, context),
+        CryptoApi::listen(),
+        StatsProxy::listen(),
+        sync_clock()
+    );
+
+    if let Err(e) = e3_api_result {
+        log::error!("An error occurred within the E3 API server — {e:?}");
+    }
+
+    if let Err(e) = stats_result {
+        log::error!("An error occurred within the stats proxy — {e:?}");
+    }
+}
+
+#[cfg(feature = "network_egress")]
+async fn start(data_plane_port: u16) {
+    use crate::{crypto::api::CryptoApi, stats::StatsProxy};
+
+    StatsClient::init();
+    #[cfg(feature = "grpc_crypto_api")]
+    tokio::spawn(async {
+        if let Err(e) = crate::crypto::grpc::CryptoGrpcApi::listen().await {
+            log::error!("An error occurred within the Crypto gRPC API server — {e:?}");
+        }
+    });
+    let context = match FeatureContext::get() {
+        Ok(context) => context,
+        Err(e) => {
+            log::error!("Failed to access context in enclave - {e}");
+            return;
+        }
+    };
+
+    let egress_policy = EgressPolicy::init(context.egress.clone());
+    tokio::spawn(egress_policy.poll_for_updates());
+    let dns_proxy_config = context.dns_proxy.clone();
+
+    let (_, dns_result, e3_api_result, egress_result, stats_result, _) = tokio::join!(
+        start_data_plane(data_plane_port, context),
+        EnclaveDnsProxy::bind_server(egress_policy, dns_proxy_config),
+        CryptoApi::listen(),
+        EgressProxy::listen(),
+        StatsProxy::listen(),
+        sync_clock()
+    );
+
+    if let Err(e) = dns_result {
+        log::error!("An error occurred within the dns server — {e:?}");
+    }
+
+    if let Err(e) = egress_result {
+        log::error!("An error occurred within the egress server — {e:?}");
+    }
+
+    if let Err(e) = e3_api_result {
+        log::error!("An error occurred within the E3 API server — {e:?}");
+    }
+
+    if let Err(e) = stats_result {
+        log::error!("An error occurred within the Stats proxy — {e:?}");
+    }
+}
+
+#[cfg(not(feature = "local"))]
+async fn sync_clock() {
+    ClockSync::run(ENCLAVE_CLOCK_SYNC_INTERVAL).await
+}
+
+// The data plane shares the host's clock when both run in one process
+#[cfg(feature = "local")]
+async fn sync_clock() {}
+
+#[allow(unused_variables)]
+async fn start_data_plane(data_plane_port: u16, context: FeatureContext) {
+    log::info!("Data plane starting up. Forwarding traffic to {data_plane_port}");
+    tokio::spawn(crate::session::register_session_token());
+    let server = match get_vsock_server(ENCLAVE_CONNECT_PORT, Enclave).await {
+        Ok(server) => AuthenticatedListener::new(server),
+        Err(error) => return log::error!("Error creating server: {error}"),
+    };
+    log::debug!("Data plane TCP server created");
+    tokio::spawn(crate::utils::audit::start_audit_log_handler());
+    tokio::spawn(async {
+        crate::config_client::ConfigClient::new()
+            .negotiate_version()
+            .await
+    });
+    if let Some(interval) = crate::configuration::get_reattestation_interval() {
+        tokio::spawn(crate::cert_provisioner_client::reattestation::run(interval));
+    }
+    if !context.tcp_passthrough_ports.is_empty() {
+        tokio::spawn(crate::tcp_passthrough::run(
+            context.tcp_passthrough_ports.clone(),
+        ));
+    }
+
+    #[cfg(feature = "tls_termination")]
+    {
+        log::info!("TLS Termination enabled in dataplane. Running tls server.");
+        crate::server::server::run(server, data_plane_port, context).await;
+    }
+    #[cfg(not(feature = "tls_termination"))]
+    run_tcp_passthrough(server, data_plane_port).await;
+}
+
+#[cfg(not(feature = "tls_termination"))]
+use shared::server::proxy_protocol::ProxiedConnection;
+#[cfg(not(feature = "tls_termination"))]
+async fn run_tcp_passthrough<L: Listener>(mut server: L, port: u16)
+where
+    <L as Listener>::Connection: ProxiedConnection + 'static,
+{
+    use shared::utils::pipe_streams;
+    use tokio::io::AsyncWriteExt;
+    log::info!("Piping TCP streams directly to user process");
+    let should_forward_proxy_protocol = match FeatureContext::get() {
+        Ok(context) => context.forward_proxy_protocol,
+        Err(e) => {
+            log::error!("Failed to access context in TCP Passthrough - {e}");
+            return;
+        }
+    };
+
+    let env_result = Environment::new().init_without_certs().await;
+    if let Err(e) = env_result {
+        log::error!(
+            "An error occurred initializing the enclave environment — {:?}",
+            e
+        );
+    }
+
+    loop {
+        let incoming_conn = match server.accept().await {
+            Ok(incoming_conn) => incoming_conn,
+            Err(e) => {
+                log::error!(
+                    "An error occurred while accepting the incoming connection — {}",
+                    e
+                );
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let mut customer_stream = match tokio::net::TcpStream::connect(("0.0.0.0", port)).await
+            {
+                Ok(customer_stream) => customer_stream,
+                Err(e) => {
+                    log::error!(
+                        "An error occurred while connecting to the customer process — {}",
+                        e
+                    );
+                    return;
+                }
+            };
+
+            if let Some(proxy_protocol) = incoming_conn
+                .proxy_protocol()
+                .filter(|_| should_forward_proxy_protocol)
+            {
+                // flush proxy protocol bytes to customer process
+                if let Err(e) = customer_stream.write_all(proxy_protocol.as_bytes()).await {
+                    log::error!(
+                      "An error occurred while forwarding the proxy protocol to the customer process — {}",
+                      e
+                  );
+                    return;
+                }
+            }
+
+            if let Err(e) = pipe_streams(incoming_conn, customer_stream).await {
+                log::error!("An error occurred piping between the incoming connection and the customer process — {}", e);
+                return;
+            }
+        });
+    }
+}
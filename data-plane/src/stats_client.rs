@@ -37,6 +37,57 @@ impl StatsClient {
         }
     }
 
+    pub fn record_crypto_api_usage(request_bytes: u64) {
+        if let Ok(context) = EnclaveContext::get() {
+            publish_count!("evervault.enclaves.crypto_api.requests.count", 1, context);
+            publish_count!(
+                "evervault.enclaves.crypto_api.bytes.count",
+                request_bytes as i64,
+                context
+            );
+        }
+    }
+
+    pub fn record_crypto_api_quota_exceeded() {
+        if let Ok(context) = EnclaveContext::get() {
+            publish_count!(
+                "evervault.enclaves.crypto_api.quota_exceeded.count",
+                1,
+                context
+            );
+        }
+    }
+
+    pub fn record_crypto_api_rate_limited() {
+        if let Ok(context) = EnclaveContext::get() {
+            publish_count!(
+                "evervault.enclaves.crypto_api.rate_limited.count",
+                1,
+                context
+            );
+        }
+    }
+
+    pub fn record_audit_events_dropped(count: u64) {
+        if let Ok(context) = EnclaveContext::get() {
+            publish_count!(
+                "evervault.enclaves.audit.dropped.count",
+                count as i64,
+                context
+            );
+        }
+    }
+
+    pub fn record_reattestation(success: bool) {
+        if let Ok(context) = EnclaveContext::get() {
+            if success {
+                publish_count!("evervault.enclaves.reattestation.success.count", 1, context);
+            } else {
+                publish_count!("evervault.enclaves.reattestation.failure.count", 1, context);
+            }
+        }
+    }
+
     pub fn record_cert_order(provider: &str, success: bool) {
         if let Ok(context) = EnclaveContext::get() {
             let success_key = if success { "success" } else { "failure" };
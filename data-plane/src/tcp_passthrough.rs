@@ -0,0 +1,100 @@
+//! Pipes connections for the configured passthrough ports straight to the same port on the customer process, without
+//! terminating TLS or parsing anything, so customers can serve protocols other than HTTP.
+use shared::server::tcp_passthrough::read_target_port;
+use shared::server::CID::Enclave;
+use shared::server::{get_vsock_server, Listener};
+use shared::ENCLAVE_TCP_PASSTHROUGH_PORT;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::session::AuthenticatedListener;
+
+const TARGET_PORT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn run(ports: Vec<u16>) {
+    log::info!("Piping TCP connections on ports {ports:?} directly to the customer process");
+    let server = match get_vsock_server(ENCLAVE_TCP_PASSTHROUGH_PORT, Enclave).await {
+        Ok(server) => server,
+        Err(e) => return log::error!("Error creating TCP passthrough server: {e}"),
+    };
+    serve(AuthenticatedListener::without_proxy_protocol(server), ports).await;
+}
+
+async fn serve<L: Listener>(mut server: L, ports: Vec<u16>)
+where
+    L::Connection: 'static,
+{
+    let ports = Arc::new(ports);
+    loop {
+        let conn = match server.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("An error occurred while accepting a TCP passthrough connection — {e}");
+                continue;
+            }
+        };
+        let ports = ports.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pipe_to_customer_process(conn, &ports).await {
+                log::error!("An error occurred piping a TCP passthrough connection — {e}");
+            }
+        });
+    }
+}
+
+async fn pipe_to_customer_process<S>(mut conn: S, ports: &[u16]) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let port = read_target_port(&mut conn, TARGET_PORT_READ_TIMEOUT).await?;
+    // The control plane and data plane are configured separately, so only pipe to ports the enclave opted into
+    if !ports.contains(&port) {
+        log::warn!("Rejecting TCP passthrough connection for port {port}, which isn't configured for passthrough");
+        return conn.shutdown().await;
+    }
+    shared::handshake_trace!("Piping TCP passthrough connection to the customer process on {port}");
+    let customer_stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    shared::utils::pipe_streams(conn, customer_stream).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::pipe_to_customer_process;
+    use shared::server::tcp_passthrough::write_target_port;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connections_are_piped_to_the_configured_port() {
+        let customer_process = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = customer_process.local_addr().unwrap().port();
+        let (mut client, enclave_side) = tokio::io::duplex(64);
+        let pipe =
+            tokio::spawn(async move { pipe_to_customer_process(enclave_side, &[port]).await });
+
+        write_target_port(&mut client, port).await.unwrap();
+        client.write_all(b"\x10\x00").await.unwrap();
+        let (mut customer_conn, _) = customer_process.accept().await.unwrap();
+        let mut received = [0u8; 2];
+        customer_conn.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"\x10\x00");
+
+        drop(client);
+        drop(customer_conn);
+        assert!(pipe.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connections_for_other_ports_are_rejected() {
+        let (mut client, enclave_side) = tokio::io::duplex(64);
+        write_target_port(&mut client, 22).await.unwrap();
+        assert!(pipe_to_customer_process(enclave_side, &[1883])
+            .await
+            .is_ok());
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+//! Audit stream for accesses to provisioned secrets and private keys inside the enclave.
+//!
+//! Events are queued on a process wide channel as soon as they are recorded, so accesses made before the handler
+//! starts (e.g. while the environment is initialised) are still shipped. The handler batches events and posts them
+//! to the control plane, separately from trx logs. The queue is bounded, and events recorded while it's full are
+//! dropped and counted rather than held, so a stalled handler can't grow the enclave's memory without limit.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use shared::logging::{AuditEvent, AuditEventType};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::interval;
+use tokio_rustls::rustls::sign::{Signer, SigningKey};
+use tokio_rustls::rustls::{Error, SignatureAlgorithm, SignatureScheme};
+
+use crate::config_client::ConfigClient;
+use crate::stats_client::StatsClient;
+use crate::EnclaveContext;
+
+const AUDIT_LOG_BATCH_SIZE: usize = 15;
+const AUDIT_LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// Events waiting to be shipped. Beyond this new events are dropped.
+const AUDIT_LOG_QUEUE_SIZE: usize = 1000;
+
+struct AuditLogChannel {
+    sender: Sender<AuditEvent>,
+    receiver: Mutex<Option<Receiver<AuditEvent>>>,
+}
+
+static AUDIT_LOG_CHANNEL: Lazy<AuditLogChannel> = Lazy::new(|| {
+    let (sender, receiver) = channel(AUDIT_LOG_QUEUE_SIZE);
+    AuditLogChannel {
+        sender,
+        receiver: Mutex::new(Some(receiver)),
+    }
+});
+
+/// Record an access to a secret or private key in the enclave's audit stream.
+pub fn record_audit_event(event_type: AuditEventType, resource: &str, accessor: &str) {
+    let context = match EnclaveContext::get() {
+        Ok(context) => context,
+        Err(e) => {
+            log::error!(
+                "Failed to read enclave context, dropping audit event for {resource} - {e}"
+            );
+            return;
+        }
+    };
+    let event = AuditEvent::new(
+        event_type,
+        resource,
+        accessor,
+        context.name(),
+        context.uuid(),
+        context.app_uuid(),
+        context.team_uuid(),
+    );
+    queue_audit_event(&AUDIT_LOG_CHANNEL.sender, event);
+}
+
+static DROPPED_AUDIT_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of audit events dropped because the queue was full.
+pub fn dropped_audit_events() -> u64 {
+    DROPPED_AUDIT_EVENTS.load(Ordering::Relaxed)
+}
+
+fn queue_audit_event(sender: &Sender<AuditEvent>, event: AuditEvent) {
+    match sender.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED_AUDIT_EVENTS.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Closed(_)) => log::error!("Failed to queue audit event, channel closed"),
+    }
+}
+
+/// Ship queued audit events to the control plane. Only the first call starts a handler, later calls return
+/// immediately.
+pub async fn start_audit_log_handler() {
+    let receiver = match AUDIT_LOG_CHANNEL.receiver.lock() {
+        Ok(mut receiver) => receiver.take(),
+        Err(_) => None,
+    };
+    let Some(mut receiver) = receiver else {
+        log::debug!("Audit log handler already started");
+        return;
+    };
+
+    let config_client = ConfigClient::new();
+    let mut buffer = Vec::with_capacity(AUDIT_LOG_BATCH_SIZE);
+    let mut flush_interval = interval(AUDIT_LOG_FLUSH_INTERVAL);
+    let mut reported_drops = 0;
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(event) => {
+                    buffer.push(event);
+                    if buffer.len() >= AUDIT_LOG_BATCH_SIZE {
+                        send_audit_logs(&config_client, &mut buffer).await;
+                    }
+                }
+                None => break,
+            },
+            _ = flush_interval.tick() => {
+                if !buffer.is_empty() {
+                    send_audit_logs(&config_client, &mut buffer).await;
+                }
+                // Drops are reported once per flush, so a full queue doesn't also flood the logs
+                let dropped = dropped_audit_events();
+                if dropped > reported_drops {
+                    let newly_dropped = dropped - reported_drops;
+                    log::warn!(
+                        "Audit event queue was full, {newly_dropped} events dropped since the last flush"
+                    );
+                    StatsClient::record_audit_events_dropped(newly_dropped);
+                    reported_drops = dropped;
+                }
+            }
+        }
+    }
+}
+
+async fn send_audit_logs(config_client: &ConfigClient, buffer: &mut Vec<AuditEvent>) {
+    let audit_logs = std::mem::take(buffer);
+    if let Err(err) = config_client.post_audit_logs(audit_logs).await {
+        log::error!("Failed to ship audit logs to control plane. {err:?}");
+    }
+}
+
+/// Wraps a TLS signing key so that every signature made with it is recorded in the audit stream.
+pub struct AuditedSigningKey {
+    inner: Arc<dyn SigningKey>,
+    resource: &'static str,
+}
+
+impl AuditedSigningKey {
+    pub fn wrap(inner: Arc<dyn SigningKey>, resource: &'static str) -> Arc<dyn SigningKey> {
+        Arc::new(Self { inner, resource })
+    }
+}
+
+impl SigningKey for AuditedSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        self.inner.choose_scheme(offered).map(|inner| {
+            Box::new(AuditedSigner {
+                inner,
+                resource: self.resource,
+            }) as Box<dyn Signer>
+        })
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.inner.algorithm()
+    }
+}
+
+struct AuditedSigner {
+    inner: Box<dyn Signer>,
+    resource: &'static str,
+}
+
+impl Signer for AuditedSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        record_audit_event(
+            AuditEventType::PrivateKeyUse,
+            self.resource,
+            "tls_handshake",
+        );
+        self.inner.sign(message)
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.inner.scheme()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        dropped_audit_events, queue_audit_event, record_audit_event, AuditedSigningKey,
+        AUDIT_LOG_CHANNEL,
+    };
+    use crate::EnclaveContext;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use serial_test::serial;
+    use shared::logging::{AuditEvent, AuditEventType};
+    use tokio::sync::mpsc::Receiver;
+    use tokio_rustls::rustls::sign::any_ecdsa_type;
+    use tokio_rustls::rustls::{PrivateKey, SignatureScheme};
+
+    // Other tests may record events concurrently, so only events for the given resource are considered
+    fn next_event_for(receiver: &mut Receiver<AuditEvent>, resource: &str) -> Option<AuditEvent> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).find(|event| event.resource == resource)
+    }
+
+    #[test]
+    #[serial]
+    fn test_audit_events_are_queued_and_signatures_recorded() {
+        EnclaveContext::set(EnclaveContext::new(
+            "team_456".to_string(),
+            "app_123".to_string(),
+            "enclave_123".to_string(),
+            "my-sick-enclave".to_string(),
+        ));
+        let mut receiver = AUDIT_LOG_CHANNEL.receiver.lock().unwrap().take().unwrap();
+
+        record_audit_event(AuditEventType::SecretRead, "API_KEY", "environment_init");
+        let event = next_event_for(&mut receiver, "API_KEY").unwrap();
+        assert_eq!(event.event_type, AuditEventType::SecretRead);
+        assert_eq!(event.resource, "API_KEY");
+        assert_eq!(event.accessor, "environment_init");
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let signing_key = any_ecdsa_type(&PrivateKey(key.private_key_to_der().unwrap())).unwrap();
+        let audited_key = AuditedSigningKey::wrap(signing_key, "test_cert_key");
+        let signer = audited_key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .unwrap();
+        assert!(next_event_for(&mut receiver, "test_cert_key").is_none());
+        assert!(!signer.sign(b"handshake").unwrap().is_empty());
+
+        let event = next_event_for(&mut receiver, "test_cert_key").unwrap();
+        assert_eq!(event.event_type, AuditEventType::PrivateKeyUse);
+        assert_eq!(event.resource, "test_cert_key");
+        assert_eq!(event.accessor, "tls_handshake");
+    }
+
+    #[test]
+    fn test_events_are_dropped_and_counted_when_the_queue_is_full() {
+        let event = |resource: &str| {
+            AuditEvent::new(
+                AuditEventType::SecretRead,
+                resource,
+                "environment_init",
+                "my-sick-enclave",
+                "enclave_123",
+                "app_123",
+                "team_456",
+            )
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let dropped = dropped_audit_events();
+        queue_audit_event(&sender, event("first"));
+        queue_audit_event(&sender, event("second"));
+        assert!(dropped_audit_events() > dropped);
+        assert_eq!(receiver.try_recv().unwrap().resource, "first");
+        assert!(receiver.try_recv().is_err());
+    }
+}
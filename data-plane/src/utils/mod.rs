@@ -1,4 +1,8 @@
+pub mod audit;
 #[cfg(feature = "enclave")]
 pub mod nsm;
+pub mod payload_format;
 #[cfg(feature = "tls_termination")]
 pub mod trx_handler;
+#[cfg(feature = "tls_termination")]
+pub mod trx_log_sink;
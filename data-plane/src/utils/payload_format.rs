@@ -0,0 +1,119 @@
+use hyper::header::{HeaderMap, ACCEPT, CONTENT_TYPE};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+#[derive(Debug, Error)]
+pub enum PayloadFormatError {
+    #[error("JSON Error — {0:?}")]
+    Json(#[from] serde_json::Error),
+    #[error("CBOR Error — {0:?}")]
+    Cbor(#[from] serde_cbor::Error),
+}
+
+/// Encoding of request and response payloads. CBOR lets clients exchange binary payloads without base64 and JSON
+/// inflation, JSON remains the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl PayloadFormat {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        if essence.eq_ignore_ascii_case(CBOR_CONTENT_TYPE) {
+            Some(Self::Cbor)
+        } else if essence.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+            Some(Self::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Format of the request body, from its Content-Type
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(Self::from_media_type)
+            .unwrap_or_default()
+    }
+
+    /// Format the client accepts for the response, from the first supported type in its Accept header. Falls back
+    /// to the request's format so CBOR clients get CBOR back without setting both headers.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+            .find_map(Self::from_media_type)
+            .unwrap_or_else(|| Self::of_request(headers))
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => JSON_CONTENT_TYPE,
+            Self::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, PayloadFormatError> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(payload)?),
+            Self::Cbor => Ok(serde_cbor::from_slice(payload)?),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, PayloadFormatError> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(value)?),
+            Self::Cbor => Ok(serde_cbor::to_vec(value)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PayloadFormat;
+    use hyper::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_format_is_negotiated_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(PayloadFormat::of_request(&headers), PayloadFormat::Json);
+        assert_eq!(PayloadFormat::accepted(&headers), PayloadFormat::Json);
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/cbor"));
+        assert_eq!(PayloadFormat::of_request(&headers), PayloadFormat::Cbor);
+        assert_eq!(PayloadFormat::accepted(&headers), PayloadFormat::Cbor);
+
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/html, application/json;q=0.9, application/cbor"),
+        );
+        assert_eq!(PayloadFormat::accepted(&headers), PayloadFormat::Json);
+
+        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+        assert_eq!(PayloadFormat::accepted(&headers), PayloadFormat::Cbor);
+    }
+
+    #[test]
+    fn test_payloads_round_trip() {
+        let value = json!({ "name": "test", "age": 42, "tags": ["a", "b"] });
+        for format in [PayloadFormat::Json, PayloadFormat::Cbor] {
+            let encoded = format.encode(&value).unwrap();
+            let decoded: Value = format.decode(&encoded).unwrap();
+            assert_eq!(decoded, value);
+        }
+        assert!(PayloadFormat::Cbor
+            .decode::<Value>(br#"{"name":"test"}"#)
+            .is_err());
+    }
+}
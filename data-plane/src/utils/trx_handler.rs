@@ -1,10 +1,22 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use shared::logging::TrxContext;
 use tokio::time::interval;
 
-use crate::config_client::ConfigClient;
+use super::trx_log_sink::{get_trx_log_sink, TrxLogSink};
+use crate::error::Error;
+
+/// Logs held while the config server is throttling us. Beyond this the oldest logs are dropped.
+const MAX_BUFFERED_TRX_LOGS: usize = 1000;
+/// Backoff when the config server throttles without saying how long to wait, doubling up to the max
+const INITIAL_THROTTLE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_THROTTLE_BACKOFF: Duration = Duration::from_secs(60);
+/// Longest `Retry-After` we'll respect, so a bad header can't stop logs being shipped for good
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
 
 enum LogHandlerMessageType {
     TickMsg,
@@ -24,6 +36,10 @@ impl LogHandlerMessage {
         }
     }
 
+    pub fn trx_log(&self) -> Option<&TrxContext> {
+        self.trx_log.as_ref()
+    }
+
     pub fn new_tick_message() -> Self {
         Self {
             trx_log: None,
@@ -32,16 +48,52 @@ impl LogHandlerMessage {
     }
 }
 
+/// Tracks when the config server last asked us to back off.
+#[derive(Default)]
+struct ThrottleBackoff {
+    until: Option<Instant>,
+    consecutive: u32,
+}
+
+impl ThrottleBackoff {
+    fn is_active(&self) -> bool {
+        self.until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Back off for the server's `Retry-After` if it gave one, otherwise exponentially.
+    fn throttled(&mut self, retry_after: Option<Duration>) -> Duration {
+        let backoff = match retry_after {
+            Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+            None => INITIAL_THROTTLE_BACKOFF
+                .saturating_mul(2u32.saturating_pow(self.consecutive))
+                .min(MAX_THROTTLE_BACKOFF),
+        };
+        self.consecutive = self.consecutive.saturating_add(1);
+        self.until = Some(Instant::now() + backoff);
+        backoff
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
 struct LogHandlerBuffer {
-    config_client: ConfigClient,
+    sink: Box<dyn TrxLogSink>,
     buffer: VecDeque<TrxContext>,
+    backoff: ThrottleBackoff,
 }
 
 impl LogHandlerBuffer {
     pub fn new(capacity: usize) -> Self {
+        Self::with_sink(get_trx_log_sink(), capacity)
+    }
+
+    fn with_sink(sink: Box<dyn TrxLogSink>, capacity: usize) -> Self {
         Self {
-            config_client: ConfigClient::new(),
+            sink,
             buffer: VecDeque::with_capacity(capacity),
+            backoff: ThrottleBackoff::default(),
         }
     }
 
@@ -50,15 +102,42 @@ impl LogHandlerBuffer {
     }
 
     pub fn add_log(&mut self, log: TrxContext) {
+        if self.buffer.len() >= MAX_BUFFERED_TRX_LOGS {
+            log::warn!("Trx log buffer is full while the config server is throttling, dropping the oldest log");
+            self.buffer.pop_front();
+        }
         self.buffer.push_back(log)
     }
 
-    // Removes logs from the buffer and sends them to the control plane
+    // Removes logs from the buffer and sends them to the control plane, or the local sink outside an enclave. While
+    // the config server is throttling, logs are kept and shipped together as one batch once the backoff is over.
     pub async fn send_logs(&mut self) {
-        let trx_logs: Vec<TrxContext> = self.buffer.drain(..).collect();
-        if let Err(err) = self.config_client.post_trx_logs(trx_logs).await {
-            log::error!("Failed to ship trx logs to control plane. {err:?}");
-        };
+        if self.backoff.is_active() {
+            log::debug!(
+                "Config server is throttling, holding {} trx logs",
+                self.buffer.len()
+            );
+            return;
+        }
+        let trx_logs: Vec<TrxContext> = self.buffer.iter().cloned().collect();
+        match self.sink.ship(trx_logs).await {
+            Ok(()) => {
+                self.buffer.clear();
+                self.backoff.reset();
+            }
+            Err(Error::ConfigServerThrottled(retry_after)) => {
+                let backoff = self.backoff.throttled(retry_after);
+                log::warn!(
+                    "Config server is throttling trx logs, holding {} logs for {}ms",
+                    self.buffer.len(),
+                    backoff.as_millis()
+                );
+            }
+            Err(err) => {
+                self.buffer.clear();
+                log::error!("Failed to ship trx logs to control plane. {err:?}");
+            }
+        }
     }
 }
 
@@ -111,3 +190,68 @@ fn start_log_timer(tx: UnboundedSender<LogHandlerMessage>) {
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::{LogHandlerBuffer, TrxLogSink};
+    use crate::error::{Error, Result};
+    use async_trait::async_trait;
+    use shared::logging::{RequestType, TrxContext, TrxContextBuilder};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Throttles the first batch, then accepts everything.
+    struct ThrottlingSink {
+        throttle: Option<Duration>,
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait]
+    impl TrxLogSink for ThrottlingSink {
+        async fn ship(&mut self, trx_logs: Vec<TrxContext>) -> Result<()> {
+            if let Some(retry_after) = self.throttle.take() {
+                return Err(Error::ConfigServerThrottled(Some(retry_after)));
+            }
+            self.batches.lock().unwrap().push(trx_logs.len());
+            Ok(())
+        }
+    }
+
+    fn trx_log() -> TrxContext {
+        let mut trx = TrxContextBuilder::init_trx_context_with_enclave_details(
+            "enclave_123",
+            "my-enclave",
+            "app_123",
+            "team_456",
+            RequestType::HTTP,
+        );
+        trx.uri(Some("/hello".to_string()));
+        trx.request_method(Some("POST".to_string()));
+        trx.build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_throttled_logs_are_held_and_coalesced() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let sink = ThrottlingSink {
+            throttle: Some(Duration::from_millis(50)),
+            batches: batches.clone(),
+        };
+        let mut buffer = LogHandlerBuffer::with_sink(Box::new(sink), 20);
+
+        buffer.add_log(trx_log());
+        buffer.send_logs().await;
+        assert_eq!(buffer.get_size(), 1);
+
+        // Nothing is sent while backing off
+        buffer.add_log(trx_log());
+        buffer.send_logs().await;
+        assert!(batches.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        buffer.add_log(trx_log());
+        buffer.send_logs().await;
+        assert_eq!(*batches.lock().unwrap(), vec![3]);
+        assert_eq!(buffer.get_size(), 0);
+    }
+}
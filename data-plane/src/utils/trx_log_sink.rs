@@ -0,0 +1,193 @@
+//! Destinations for batches of trx logs. Enclaves always ship them to the control plane's config server. Outside an
+//! enclave, they can be written to stdout or a file instead, as the same `PostTrxLogsRequest` payload the config
+//! server would receive, so developers can see exactly what would be shipped in production.
+use async_trait::async_trait;
+use shared::logging::TrxContext;
+
+use crate::config_client::ConfigClient;
+use crate::error::Result;
+
+#[cfg(not(feature = "enclave"))]
+pub use local::{FallbackTrxLogSink, LocalTrxLogSink};
+
+#[async_trait]
+pub trait TrxLogSink: Send {
+    async fn ship(&mut self, trx_logs: Vec<TrxContext>) -> Result<()>;
+}
+
+#[async_trait]
+impl TrxLogSink for ConfigClient {
+    async fn ship(&mut self, trx_logs: Vec<TrxContext>) -> Result<()> {
+        self.post_trx_logs(trx_logs).await
+    }
+}
+
+#[cfg(feature = "enclave")]
+pub fn get_trx_log_sink() -> Box<dyn TrxLogSink> {
+    Box::new(ConfigClient::new())
+}
+
+/// The sink named by `EV_TRX_LOG_SINK` if set, otherwise the config server, falling back to stdout whenever it
+/// can't be reached.
+#[cfg(not(feature = "enclave"))]
+pub fn get_trx_log_sink() -> Box<dyn TrxLogSink> {
+    match LocalTrxLogSink::from_env() {
+        Ok(Some(sink)) => Box::new(sink),
+        Ok(None) => Box::new(FallbackTrxLogSink::new(
+            ConfigClient::new(),
+            LocalTrxLogSink::stdout(),
+        )),
+        Err(e) => {
+            log::error!("Failed to open trx log sink, writing trx logs to stdout instead - {e}");
+            Box::new(LocalTrxLogSink::stdout())
+        }
+    }
+}
+
+#[cfg(not(feature = "enclave"))]
+mod local {
+    use super::TrxLogSink;
+    use crate::error::{Error, Result};
+    use async_trait::async_trait;
+    use shared::logging::TrxContext;
+    use shared::server::config_server::requests::{ConfigServerPayload, PostTrxLogsRequest};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    const TRX_LOG_SINK_ENV_VAR: &str = "EV_TRX_LOG_SINK";
+
+    /// Writes each batch of trx logs as a line of JSON. Writes block, so are made off the async runtime.
+    pub struct LocalTrxLogSink {
+        writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    }
+
+    impl LocalTrxLogSink {
+        pub fn stdout() -> Self {
+            Self::new(Box::new(std::io::stdout()))
+        }
+
+        pub fn new(writer: Box<dyn Write + Send>) -> Self {
+            Self {
+                writer: Arc::new(Mutex::new(writer)),
+            }
+        }
+
+        /// Appends to the file at the given path, creating it if needed.
+        pub fn file(path: &str) -> Result<Self> {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            Ok(Self::new(Box::new(file)))
+        }
+
+        /// Read the sink from `EV_TRX_LOG_SINK`, which is either `stdout` or the path of a file to append to.
+        pub fn from_env() -> Result<Option<Self>> {
+            match std::env::var(TRX_LOG_SINK_ENV_VAR) {
+                Ok(sink) if sink == "stdout" => Ok(Some(Self::stdout())),
+                Ok(path) if !path.is_empty() => Self::file(&path).map(Some),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TrxLogSink for LocalTrxLogSink {
+        async fn ship(&mut self, trx_logs: Vec<TrxContext>) -> Result<()> {
+            let mut payload = PostTrxLogsRequest::new(trx_logs).into_bytes()?;
+            payload.push(b'\n');
+            let writer = self.writer.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut writer = writer
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                writer.write_all(&payload)?;
+                writer.flush()
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))?;
+            Ok(())
+        }
+    }
+
+    /// Ships to the primary sink, and to the fallback when the primary fails, e.g. when no config server is running.
+    pub struct FallbackTrxLogSink<P, F> {
+        primary: P,
+        fallback: F,
+    }
+
+    impl<P: TrxLogSink, F: TrxLogSink> FallbackTrxLogSink<P, F> {
+        pub fn new(primary: P, fallback: F) -> Self {
+            Self { primary, fallback }
+        }
+    }
+
+    #[async_trait]
+    impl<P: TrxLogSink, F: TrxLogSink> TrxLogSink for FallbackTrxLogSink<P, F> {
+        async fn ship(&mut self, trx_logs: Vec<TrxContext>) -> Result<()> {
+            // The primary consumes the batch, so a copy is kept for the fallback
+            match self.primary.ship(trx_logs.clone()).await {
+                Ok(()) => Ok(()),
+                // The config server is up, it's just asked us to slow down
+                Err(e @ Error::ConfigServerThrottled(_)) => Err(e),
+                Err(e) => {
+                    log::warn!("Failed to ship trx logs, using the fallback sink - {e}");
+                    self.fallback.ship(trx_logs).await
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "enclave")))]
+mod test {
+    use super::{FallbackTrxLogSink, LocalTrxLogSink, TrxLogSink};
+    use crate::error::{Error, Result};
+    use async_trait::async_trait;
+    use shared::logging::{RequestType, TrxContext, TrxContextBuilder};
+    use shared::server::config_server::requests::PostTrxLogsRequest;
+
+    struct UnreachableSink;
+
+    #[async_trait]
+    impl TrxLogSink for UnreachableSink {
+        async fn ship(&mut self, _: Vec<TrxContext>) -> Result<()> {
+            Err(Error::ConfigServer("connection refused".to_string()))
+        }
+    }
+
+    fn trx_logs() -> Vec<TrxContext> {
+        let mut trx = TrxContextBuilder::init_trx_context_with_enclave_details(
+            "enclave_123",
+            "my-enclave",
+            "app_123",
+            "team_456",
+            RequestType::HTTP,
+        );
+        trx.uri(Some("/hello".to_string()));
+        trx.request_method(Some("POST".to_string()));
+        vec![trx.build().unwrap(), trx.build().unwrap()]
+    }
+
+    #[tokio::test]
+    async fn test_trx_logs_fall_back_to_file_as_config_server_payload() {
+        let path = std::env::temp_dir().join(format!("trx-log-sink-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let trx_logs = trx_logs();
+        let mut sink =
+            FallbackTrxLogSink::new(UnreachableSink, LocalTrxLogSink::file(path).unwrap());
+        sink.ship(trx_logs.clone()).await.unwrap();
+        sink.ship(trx_logs.clone()).await.unwrap();
+
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let batches: Vec<PostTrxLogsRequest> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].trx_logs(), trx_logs);
+    }
+}
@@ -0,0 +1,172 @@
+//! Drives requests through the full ingress pipeline under the `test_harness` feature: a client connects to a stand-in
+//! for the control plane's TCP server, which forwards to the data plane over the loopback "vsock" transport. The data
+//! plane authenticates and decrypts the request with the mock E3, and forwards it on to a customer process.
+//!
+//! The clock is frozen and the mock E3 keys are fixed, so ciphertexts and transaction logs are reproducible. TLS
+//! termination isn't covered, as the data plane's certificate is issued at runtime.
+//!
+//! Run with `cargo test -p data-plane --features test_harness --test pipeline`.
+#![cfg(feature = "test_harness")]
+
+use data_plane::e3client::{CryptoRequest, CryptoResponse, E3Api, E3Client};
+use data_plane::server::http::parse::{try_parse_http_request_from_stream, Incoming};
+use data_plane::server::http::response_to_bytes;
+use data_plane::server::server::build_service;
+use data_plane::utils::trx_handler::LogHandlerMessage;
+use data_plane::{EnclaveContext, FeatureContext};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::json;
+use shared::server::{get_vsock_client, get_vsock_server, Listener, CID};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tower::Service;
+
+const API_KEY: &str = "harness-api-key";
+
+/// A customer process which echoes each request body back, and reports what it received.
+async fn start_customer_process() -> (u16, UnboundedReceiver<serde_json::Value>) {
+    let (tx, rx) = unbounded_channel();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let make_service = make_service_fn(move |_| {
+        let tx = tx.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let tx = tx.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    let _ = tx.send(serde_json::from_slice(&body).unwrap_or_default());
+                    Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::from_tcp(listener)
+        .unwrap()
+        .serve(make_service);
+    tokio::spawn(server);
+    (port, rx)
+}
+
+/// The data plane's HTTP handling, without TLS, listening on the enclave side of the loopback transport.
+async fn start_data_plane(customer_port: u16) -> UnboundedReceiver<LogHandlerMessage> {
+    let enclave_context = Arc::new(EnclaveContext::new(
+        "team_123".to_string(),
+        "app_12345678".to_string(),
+        "enclave_123".to_string(),
+        "test-enclave".to_string(),
+    ));
+    let feature_context: FeatureContext = serde_json::from_value(json!({
+        "api_key_auth": true,
+        "healthcheck": null,
+        "healthcheck_port": null,
+        "healthcheck_use_tls": null,
+        "trx_logging_enabled": true,
+        "forward_proxy_protocol": false,
+        "trusted_headers": [],
+    }))
+    .unwrap();
+    let (tx, rx) = unbounded_channel();
+    let service = build_service(
+        enclave_context,
+        Arc::new(feature_context),
+        Arc::new(E3Client::new()),
+        tx,
+    );
+
+    let mut listener = get_vsock_server(shared::ENCLAVE_CONNECT_PORT, CID::Enclave)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        loop {
+            let mut stream = listener.accept().await.unwrap();
+            let mut service = service.clone();
+            tokio::spawn(async move {
+                while let Ok(Incoming::HttpRequest(request)) =
+                    try_parse_http_request_from_stream(&mut stream, customer_port).await
+                {
+                    let response = service.call(request).await.unwrap();
+                    let _ = stream.write_all(&response_to_bytes(response).await).await;
+                }
+            });
+        }
+    });
+    rx
+}
+
+/// Stands in for the control plane's TCP server, forwarding each connection to the data plane.
+async fn start_control_plane() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (connection, _) = listener.accept().await.unwrap();
+            let enclave_stream = get_vsock_client(shared::ENCLAVE_CONNECT_PORT, CID::Enclave)
+                .await
+                .unwrap();
+            tokio::spawn(shared::utils::pipe_streams(connection, enclave_stream));
+        }
+    });
+    addr
+}
+
+async fn send(addr: SocketAddr, api_key: &str, body: &serde_json::Value) -> Response<Body> {
+    let request = Request::post(format!("http://{addr}/"))
+        .header("api-key", api_key)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    hyper::Client::new().request(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_request_is_decrypted_on_its_way_to_the_customer_process() {
+    std::env::set_var("MOCK_CRYPTO_API_KEY", API_KEY);
+    let (customer_port, mut customer_requests) = start_customer_process().await;
+    let mut trx_logs = start_data_plane(customer_port).await;
+    let control_plane_addr = start_control_plane().await;
+
+    let encrypted: CryptoResponse = E3Client::new()
+        .encrypt(
+            CryptoRequest::new(json!({ "card": "4242424242424242" })),
+            None,
+        )
+        .await
+        .unwrap();
+    // Ciphertexts are fixed for a given seed, so they can be recorded in fixtures
+    assert_eq!(
+        encrypted.data["card"],
+        E3Client::new()
+            .encrypt::<CryptoResponse, _>(
+                CryptoRequest::new(json!({ "card": "4242424242424242" })),
+                None
+            )
+            .await
+            .unwrap()
+            .data["card"]
+    );
+    let body = json!({ "card": encrypted.data["card"], "amount": 100 });
+
+    let response = send(control_plane_addr, API_KEY, &body).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        customer_requests.recv().await.unwrap(),
+        json!({ "card": "4242424242424242", "amount": 100 })
+    );
+
+    let trx_log = trx_logs.recv().await.unwrap();
+    let trx_log = serde_json::to_value(trx_log.trx_log().unwrap()).unwrap();
+    assert_eq!(trx_log["ts"], "2023-11-14T22:13:20.000Z");
+    assert_eq!(trx_log["elapsed"], 0.0);
+    assert_eq!(trx_log["nDecryptedFields"], 1);
+    assert_eq!(trx_log["responseCode"], "200");
+
+    // Requests with the wrong API key never reach the customer process
+    let response = send(control_plane_addr, "wrong-api-key", &body).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(customer_requests.try_recv().is_err());
+}
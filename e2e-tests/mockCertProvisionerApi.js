@@ -1,4 +1,5 @@
 const fs = require('fs');
+const crypto = require('crypto');
 const https = require('https')
 const express = require('express')
 const app = express()
@@ -30,6 +31,10 @@ const mutualTlsMiddleware = () => (req, res, next) => {
 
 app.use(mutualTlsMiddleware());
 
+// Pin the mock E3's key, as the data plane won't connect to E3 without an identity from the provisioner
+const mockE3Key = new crypto.X509Certificate(process.env.MOCK_CRYPTO_CERT).publicKey.export({type: 'spki', format: 'der'});
+const e3Identity = {spki_sha256: [crypto.createHash('sha256').update(mockE3Key).digest('base64')]};
+
 
 app.get('/cert/token', async (req, res) => {
   try {
@@ -82,6 +87,7 @@ tlsApp.post('/cert', async (req, res) => {
       key_pair: ca_key_pair,
       secrets: [{name: "ANOTHER_ENV_VAR", secret: "123"}, {name: "ENCRYPTED_ENV", secret: "ev:123"}],
       context: {team_uuid: "team_123", cage_uuid: "enclave_123", app_uuid: "app_12345678", cage_name: "test-enclave"},
+      e3_identity: e3Identity,
     };
     res.status(200)
     res.send(result) 
@@ -96,7 +102,8 @@ tlsApp.post('/secrets', async (req, res) => {
     
     var result = {
       context: {team_uuid: "team_123", cage_uuid: "enclave_123", app_uuid: "app_12345678", cage_name: "test-enclave"},
-      secrets: [{name: "ANOTHER_ENV_VAR", secret: "123"}, {name: "ENCRYPTED_ENV", secret: "ev:123"}]
+      secrets: [{name: "ANOTHER_ENV_VAR", secret: "123"}, {name: "ENCRYPTED_ENV", secret: "ev:123"}],
+      e3_identity: e3Identity,
     };
     res.status(200)
     res.send(result) 
@@ -1,7 +1,12 @@
 #!/bin/sh
 
-echo {\"api_key_auth\":${EV_API_KEY_AUTH},\"egress\":{\"allow_list\": \"jsonplaceholder.typicode.com\"},\"trx_logging_enabled\":true,\"forward_proxy_protocol\":false,\"trusted_headers\": []} > /etc/dataplane-config.json
+EGRESS_PORTS=${EGRESS_PORTS:-443}
 
-iptables -A OUTPUT -t nat -p tcp --dport 443 ! -d 127.0.0.1  -j DNAT --to-destination 127.0.0.1:4444
+echo {\"api_key_auth\":${EV_API_KEY_AUTH},\"egress\":{\"allow_list\": \"jsonplaceholder.typicode.com\", \"ports\": \"${EGRESS_PORTS}\"},\"trx_logging_enabled\":true,\"forward_proxy_protocol\":false,\"trusted_headers\": []} > /etc/dataplane-config.json
+
+# The egress proxy reads each connection's original port, so every configured egress port is redirected to it
+for port in $(echo "$EGRESS_PORTS" | tr ',' ' '); do
+  iptables -A OUTPUT -t nat -p tcp --dport "$port" ! -d 127.0.0.1  -j DNAT --to-destination 127.0.0.1:4444
+done
 
 SYSTEM_STATS_INTERVAL=1 exec $DATA_PLANE_EXECUTABLE_PATH
@@ -0,0 +1,90 @@
+//! Compares a single mutex-protected TTL cache against the sharded cache under concurrent, read-heavy access,
+//! mirroring egress connections checking their destination IP against the DNS cache.
+//!
+//! Run with `cargo bench -p shared --features network_egress`.
+use shared::server::dns_cache::{ShardedTtlCache, DEFAULT_SHARD_COUNT};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ttl_cache::TtlCache;
+
+const CACHED_IPS: usize = 1000;
+const OPERATIONS_PER_THREAD: usize = 200_000;
+// One write for every WRITE_RATIO operations, the rest are lookups
+const WRITE_RATIO: usize = 100;
+const THREAD_COUNTS: [usize; 4] = [1, 4, 8, 16];
+
+trait IpCache: Send + Sync + 'static {
+    fn insert(&self, ip: String, hostname: String);
+    fn contains(&self, ip: &str) -> bool;
+}
+
+impl IpCache for Mutex<TtlCache<String, String>> {
+    fn insert(&self, ip: String, hostname: String) {
+        self.lock()
+            .unwrap()
+            .insert(ip, hostname, Duration::from_secs(300));
+    }
+
+    fn contains(&self, ip: &str) -> bool {
+        self.lock().unwrap().get(ip).is_some()
+    }
+}
+
+impl IpCache for ShardedTtlCache<String, String> {
+    fn insert(&self, ip: String, hostname: String) {
+        ShardedTtlCache::insert(self, ip, hostname, Duration::from_secs(300)).unwrap();
+    }
+
+    fn contains(&self, ip: &str) -> bool {
+        self.contains_key(ip).unwrap()
+    }
+}
+
+fn ip_for(i: usize) -> String {
+    format!("10.0.{}.{}", (i / 256) % 256, i % 256)
+}
+
+fn run<C: IpCache>(cache: Arc<C>, threads: usize) -> Duration {
+    (0..CACHED_IPS).for_each(|i| cache.insert(ip_for(i), "example.com".to_string()));
+    let ips: Arc<Vec<String>> = Arc::new((0..CACHED_IPS).map(ip_for).collect());
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let cache = cache.clone();
+            let ips = ips.clone();
+            std::thread::spawn(move || {
+                let mut hits = 0;
+                for op in 0..OPERATIONS_PER_THREAD {
+                    let ip = &ips[(op * 7 + thread * 31) % CACHED_IPS];
+                    if op % WRITE_RATIO == 0 {
+                        cache.insert(ip.clone(), "example.com".to_string());
+                    } else if cache.contains(ip) {
+                        hits += 1;
+                    }
+                }
+                hits
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|handle| {
+        handle.join().unwrap();
+    });
+    start.elapsed()
+}
+
+fn report(label: &str, threads: usize, elapsed: Duration) {
+    let ops = (threads * OPERATIONS_PER_THREAD) as f64;
+    let mops = ops / elapsed.as_secs_f64() / 1_000_000.0;
+    println!("{label:<8} {threads:>2} threads {elapsed:>10.2?} — {mops:.2} Mops/s");
+}
+
+fn main() {
+    for threads in THREAD_COUNTS {
+        let mutex = Arc::new(Mutex::new(TtlCache::new(CACHED_IPS)));
+        report("mutex", threads, run(mutex, threads));
+        // Headroom so uneven hashing across shards does not evict any of the cached IPs
+        let sharded = Arc::new(ShardedTtlCache::new(CACHED_IPS * 2, DEFAULT_SHARD_COUNT));
+        report("sharded", threads, run(sharded, threads));
+    }
+}
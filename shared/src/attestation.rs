@@ -0,0 +1,306 @@
+//! Verification of Nitro attestation docs, for anything which needs to check an enclave before trusting it. A doc is
+//! only accepted once its COSE_Sign1 signature checks out against its signing cert, that cert chains up to the
+//! given root through the doc's CA bundle, and its PCRs match the expected measurements.
+use aws_nitro_enclaves_cose as cose;
+use openssl::error::ErrorStack;
+use openssl::pkey::{PKey, Public};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyParam;
+use openssl::x509::{X509StoreContext, X509};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+pub use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
+
+#[derive(Debug, Error)]
+pub enum AttestationVerificationError {
+    #[error("Could not parse CoseSign1 structure: {0}")]
+    CoseSign1ParseFailed(serde_cbor::Error),
+    #[error("Could not read CoseSign1 payload: {0}")]
+    CosePayloadFailed(String),
+    #[error("Could not parse attestation document: {0}")]
+    AttestationDocParseFailed(serde_cbor::Error),
+    #[error("Attestation document signature is invalid")]
+    InvalidSignature,
+    #[error("Attestation document cert chain is invalid: {0}")]
+    InvalidCertChain(String),
+    #[error("PCR{0} doesn't match the expected value")]
+    PcrMismatch(usize),
+    #[error("PCR{0} is missing from the attestation document")]
+    MissingPcr(usize),
+    #[error(transparent)]
+    Openssl(#[from] ErrorStack),
+}
+
+/// Parse an attestation doc from its COSE_Sign1 structure, without verifying it.
+pub fn parse_attestation_doc(
+    cose_sign_1_bytes: &[u8],
+) -> Result<(cose::CoseSign1, AttestationDoc), AttestationVerificationError> {
+    let cose_sign_1: cose::CoseSign1 = serde_cbor::from_slice(cose_sign_1_bytes)
+        .map_err(AttestationVerificationError::CoseSign1ParseFailed)?;
+    let payload = cose_sign_1
+        .get_payload::<cose::crypto::Openssl>(None)
+        .map_err(|e| AttestationVerificationError::CosePayloadFailed(e.to_string()))?;
+    let attestation_doc = serde_cbor::from_slice(&payload)
+        .map_err(AttestationVerificationError::AttestationDocParseFailed)?;
+    Ok((cose_sign_1, attestation_doc))
+}
+
+/// Checks attestation docs against a root cert and the PCRs expected of the enclave. The AWS Nitro Enclaves root cert
+/// isn't bundled, so callers should load the one AWS publishes and pin it.
+pub struct AttestationVerifier {
+    root_cert: X509,
+    expected_pcrs: BTreeMap<usize, Vec<u8>>,
+    verification_time: Option<i64>,
+}
+
+impl AttestationVerifier {
+    pub fn new(root_cert: X509) -> Self {
+        Self {
+            root_cert,
+            expected_pcrs: BTreeMap::new(),
+            verification_time: None,
+        }
+    }
+
+    pub fn from_root_pem(root_pem: &[u8]) -> Result<Self, AttestationVerificationError> {
+        Ok(Self::new(X509::from_pem(root_pem)?))
+    }
+
+    /// Require a PCR to have the given value. PCRs which aren't given can hold anything.
+    pub fn with_expected_pcr(mut self, index: usize, value: Vec<u8>) -> Self {
+        self.expected_pcrs.insert(index, value);
+        self
+    }
+
+    /// Check cert validity at a fixed unix time rather than now, such as when checking a doc recorded earlier.
+    pub fn at_time(mut self, unix_seconds: i64) -> Self {
+        self.verification_time = Some(unix_seconds);
+        self
+    }
+
+    /// Verify a COSE_Sign1 attestation doc, returning the parsed doc if it can be trusted.
+    pub fn verify(
+        &self,
+        cose_sign_1_bytes: &[u8],
+    ) -> Result<AttestationDoc, AttestationVerificationError> {
+        let (cose_sign_1, attestation_doc) = parse_attestation_doc(cose_sign_1_bytes)?;
+        let signing_cert = X509::from_der(&attestation_doc.certificate)?;
+        let signing_key = signing_cert.public_key()?;
+        self.verify_signature(&cose_sign_1, &signing_key)?;
+        self.verify_cert_chain(&signing_cert, &attestation_doc)?;
+        self.verify_pcrs(&attestation_doc)?;
+        Ok(attestation_doc)
+    }
+
+    fn verify_signature(
+        &self,
+        cose_sign_1: &cose::CoseSign1,
+        signing_key: &PKey<Public>,
+    ) -> Result<(), AttestationVerificationError> {
+        match cose_sign_1.verify_signature::<cose::crypto::Openssl>(signing_key) {
+            Ok(true) => Ok(()),
+            _ => Err(AttestationVerificationError::InvalidSignature),
+        }
+    }
+
+    fn verify_cert_chain(
+        &self,
+        signing_cert: &X509,
+        attestation_doc: &AttestationDoc,
+    ) -> Result<(), AttestationVerificationError> {
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(self.root_cert.clone())?;
+        if let Some(time) = self.verification_time {
+            let mut param = X509VerifyParam::new()?;
+            param.set_time(time);
+            store.set_param(&param)?;
+        }
+        let store = store.build();
+
+        // The CA bundle starts with the root, but only the pinned root is trusted
+        let mut chain = Stack::new()?;
+        for cert in &attestation_doc.cabundle {
+            chain.push(X509::from_der(cert)?)?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        let verified = context.init(&store, signing_cert, &chain, |context| {
+            if context.verify_cert()? {
+                Ok(Ok(()))
+            } else {
+                Ok(Err(context.error().error_string().to_string()))
+            }
+        })?;
+        verified.map_err(AttestationVerificationError::InvalidCertChain)
+    }
+
+    fn verify_pcrs(
+        &self,
+        attestation_doc: &AttestationDoc,
+    ) -> Result<(), AttestationVerificationError> {
+        for (index, expected) in &self.expected_pcrs {
+            let actual = attestation_doc
+                .pcrs
+                .get(index)
+                .ok_or(AttestationVerificationError::MissingPcr(*index))?;
+            if actual.as_slice() != expected.as_slice() {
+                return Err(AttestationVerificationError::PcrMismatch(*index));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AttestationVerificationError, AttestationVerifier};
+    use aws_nitro_enclaves_cose as cose;
+    use aws_nitro_enclaves_nsm_api::api::{AttestationDoc, Digest};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+    use std::collections::BTreeMap;
+
+    const NOT_BEFORE_SECS: i64 = 1_700_000_000;
+    const NOT_AFTER_SECS: i64 = 1_700_010_800;
+    const VERIFICATION_TIME_SECS: i64 = 1_700_000_100;
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn build_cert(
+        common_name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::from_unix(NOT_BEFORE_SECS).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::from_unix(NOT_AFTER_SECS).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha384()).unwrap();
+            }
+            None => {
+                cert.set_issuer_name(&name).unwrap();
+                cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                cert.sign(key, MessageDigest::sha384()).unwrap();
+            }
+        }
+        cert.build()
+    }
+
+    struct SignedDoc {
+        root: X509,
+        doc: Vec<u8>,
+    }
+
+    fn signed_doc(pcrs: BTreeMap<usize, Vec<u8>>) -> SignedDoc {
+        let root_key = generate_key();
+        let root = build_cert("root", &root_key, None);
+        let signing_key = generate_key();
+        let signing_cert = build_cert("enclave", &signing_key, Some((&root, &root_key)));
+        let doc = AttestationDoc::new(
+            "test-module".to_string(),
+            Digest::SHA384,
+            NOT_BEFORE_SECS as u64 * 1000,
+            pcrs,
+            signing_cert.to_der().unwrap(),
+            vec![root.to_der().unwrap()],
+            None,
+            None,
+            None,
+        );
+        let doc = cose::CoseSign1::new::<cose::crypto::Openssl>(
+            &doc.to_binary(),
+            &cose::header_map::HeaderMap::new(),
+            &signing_key,
+        )
+        .unwrap()
+        .as_bytes(true)
+        .unwrap();
+        SignedDoc { root, doc }
+    }
+
+    #[test]
+    fn test_docs_chaining_to_the_root_with_expected_pcrs_are_verified() {
+        let SignedDoc { root, doc } = signed_doc(BTreeMap::from([(0, vec![0xab; 48])]));
+        let verified = AttestationVerifier::new(root)
+            .with_expected_pcr(0, vec![0xab; 48])
+            .at_time(VERIFICATION_TIME_SECS)
+            .verify(&doc)
+            .unwrap();
+        assert_eq!(verified.module_id, "test-module");
+    }
+
+    #[test]
+    fn test_docs_from_another_root_are_rejected() {
+        let SignedDoc { doc, .. } = signed_doc(BTreeMap::new());
+        let other_root = build_cert("other root", &generate_key(), None);
+        assert!(matches!(
+            AttestationVerifier::new(other_root)
+                .at_time(VERIFICATION_TIME_SECS)
+                .verify(&doc),
+            Err(AttestationVerificationError::InvalidCertChain(_))
+        ));
+    }
+
+    #[test]
+    fn test_docs_with_expired_certs_are_rejected() {
+        let SignedDoc { root, doc } = signed_doc(BTreeMap::new());
+        assert!(matches!(
+            AttestationVerifier::new(root)
+                .at_time(NOT_AFTER_SECS + 1)
+                .verify(&doc),
+            Err(AttestationVerificationError::InvalidCertChain(_))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_docs_are_rejected() {
+        let SignedDoc { root, mut doc } = signed_doc(BTreeMap::new());
+        let last = doc.len() - 1;
+        doc[last] ^= 0xff;
+        assert!(matches!(
+            AttestationVerifier::new(root)
+                .at_time(VERIFICATION_TIME_SECS)
+                .verify(&doc),
+            Err(AttestationVerificationError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_unexpected_pcrs_are_rejected() {
+        let SignedDoc { root, doc } = signed_doc(BTreeMap::from([(0, vec![0xab; 48])]));
+        let verifier = AttestationVerifier::new(root).at_time(VERIFICATION_TIME_SECS);
+        assert!(matches!(
+            verifier.with_expected_pcr(0, vec![0; 48]).verify(&doc),
+            Err(AttestationVerificationError::PcrMismatch(0))
+        ));
+        let SignedDoc { root, doc } = signed_doc(BTreeMap::new());
+        assert!(matches!(
+            AttestationVerifier::new(root)
+                .with_expected_pcr(8, vec![0; 48])
+                .at_time(VERIFICATION_TIME_SECS)
+                .verify(&doc),
+            Err(AttestationVerificationError::MissingPcr(8))
+        ));
+    }
+}
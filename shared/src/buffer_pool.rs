@@ -0,0 +1,143 @@
+//! Pooled, fixed-size byte buffers for the proxy hot paths.
+//!
+//! Every proxied connection needs scratch space to read into. Allocating a fresh buffer per connection puts
+//! a lot of pressure on the allocator at high concurrency, so buffers are instead checked out of a shared pool
+//! and returned to it when dropped.
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Size of the buffers used when reading from or piping between streams.
+pub const STREAM_BUFFER_SIZE: usize = 4096;
+/// Size of the buffers used for DNS packets. UDP DNS messages are capped at 512 bytes.
+pub const DNS_BUFFER_SIZE: usize = 512;
+
+const MAX_POOLED_STREAM_BUFFERS: usize = 1024;
+const MAX_POOLED_DNS_BUFFERS: usize = 256;
+
+lazy_static::lazy_static! {
+  pub static ref STREAM_BUFFER_POOL: BufferPool = BufferPool::new(STREAM_BUFFER_SIZE, MAX_POOLED_STREAM_BUFFERS);
+  pub static ref DNS_BUFFER_POOL: BufferPool = BufferPool::new(DNS_BUFFER_SIZE, MAX_POOLED_DNS_BUFFERS);
+}
+
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    buffer_size: usize,
+    max_pooled: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_pooled: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            buffer_size,
+            max_pooled,
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of idle buffers currently held by the pool
+    pub fn available(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Check out a zeroed buffer of `buffer_size` bytes, allocating one if the pool is empty.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buffer = self
+            .lock()
+            .pop()
+            .unwrap_or_else(|| BytesMut::zeroed(self.buffer_size));
+        PooledBuffer {
+            inner: Some(buffer),
+            pool: self,
+        }
+    }
+
+    fn release(&self, mut buffer: BytesMut) {
+        // Clear out any connection data before the buffer is handed to another connection
+        buffer.fill(0);
+        let mut buffers = self.lock();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buffer);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<BytesMut>> {
+        // The pool holds no invariants that a panicking holder could break, so recover from poisoning
+        self.buffers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A buffer checked out of a `BufferPool`, returned to the pool on drop.
+pub struct PooledBuffer<'a> {
+    inner: Option<BytesMut>,
+    pool: &'a BufferPool,
+}
+
+impl<'a> Deref for PooledBuffer<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: inner is only taken in drop
+        self.inner.as_deref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for PooledBuffer<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.inner.as_deref_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledBuffer<'a> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.inner.take() {
+            self.pool.release(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferPool;
+
+    #[test]
+    fn test_buffers_are_returned_to_pool() {
+        let pool = BufferPool::new(16, 4);
+        {
+            let first = pool.get();
+            let second = pool.get();
+            assert_eq!(first.len(), 16);
+            assert_eq!(second.len(), 16);
+            assert_eq!(pool.available(), 0);
+        }
+        assert_eq!(pool.available(), 2);
+        let _reused = pool.get();
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_returned_buffers_are_zeroed() {
+        let pool = BufferPool::new(8, 4);
+        {
+            let mut buffer = pool.get();
+            buffer.copy_from_slice(&[1; 8]);
+        }
+        let buffer = pool.get();
+        assert_eq!(&buffer[..], &[0; 8]);
+    }
+
+    #[test]
+    fn test_pool_does_not_exceed_max_size() {
+        let pool = BufferPool::new(8, 2);
+        {
+            let _buffers: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        }
+        assert_eq!(pool.available(), 2);
+    }
+}
@@ -0,0 +1,26 @@
+//! The wall clock used for logs and tokens. Under the `test_harness` feature it's frozen, so output from a run can
+//! be compared against a recorded one.
+use std::time::SystemTime;
+
+/// Unix time the clock is frozen at when `HARNESS_FIXED_TIME` isn't set (2023-11-14T22:13:20Z)
+#[cfg(feature = "test_harness")]
+pub const DEFAULT_HARNESS_TIME: u64 = 1_700_000_000;
+
+#[cfg(not(feature = "test_harness"))]
+pub fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(feature = "test_harness")]
+pub fn now() -> SystemTime {
+    let seconds = std::env::var("HARNESS_FIXED_TIME")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(DEFAULT_HARNESS_TIME);
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds)
+}
+
+/// Time elapsed since an earlier reading of the clock, which is always zero under the `test_harness` feature.
+pub fn elapsed_since(earlier: SystemTime) -> std::time::Duration {
+    now().duration_since(earlier).unwrap_or_default()
+}
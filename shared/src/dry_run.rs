@@ -0,0 +1,66 @@
+//! Dry runs load and validate a plane's configuration, print the effective config with defaults resolved, and exit
+//! without starting, so a deployment can be checked before any traffic is routed to it.
+use crate::validation::ValidationReport;
+use serde_json::Value;
+
+pub const DRY_RUN_FLAG: &str = "--dry-run";
+pub const DRY_RUN_ENV_VAR: &str = "EV_DRY_RUN";
+
+const REDACTED: &str = "<redacted>";
+
+/// Whether the process was started with `--dry-run`, or with `EV_DRY_RUN` set to `true` or `1`.
+pub fn is_dry_run() -> bool {
+    std::env::args().skip(1).any(|arg| arg == DRY_RUN_FLAG)
+        || std::env::var(DRY_RUN_ENV_VAR)
+            .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+/// Command line arguments, without the executable path or the dry run flag.
+pub fn args() -> impl Iterator<Item = String> {
+    std::env::args().skip(1).filter(|arg| arg != DRY_RUN_FLAG)
+}
+
+/// An env var's value for display, or null when it's unset.
+pub fn env_value(var_name: &str) -> Value {
+    std::env::var(var_name).map_or(Value::Null, Value::String)
+}
+
+/// Whether a secret env var is set, without revealing its value.
+pub fn redacted_env_value(var_name: &str) -> Value {
+    std::env::var(var_name).map_or(Value::Null, |_| Value::String(REDACTED.to_string()))
+}
+
+/// Log the validation reports and print the effective config to stdout, then exit. The exit code is non-zero if
+/// any report has a fatal issue.
+pub fn print_and_exit(reports: &[ValidationReport], effective_config: Value) -> ! {
+    reports.iter().for_each(ValidationReport::log);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&effective_config)
+            .expect("Infallible - serializing a JSON value")
+    );
+    let exit_code = if reports.iter().any(ValidationReport::has_fatal) {
+        1
+    } else {
+        0
+    };
+    std::process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{env_value, redacted_env_value};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_secret_env_vars_are_redacted() {
+        std::env::set_var("DRY_RUN_TEST_SECRET", "hunter2");
+        std::env::remove_var("DRY_RUN_TEST_UNSET");
+        assert_eq!(env_value("DRY_RUN_TEST_SECRET"), json!("hunter2"));
+        assert_eq!(
+            redacted_env_value("DRY_RUN_TEST_SECRET"),
+            json!("<redacted>")
+        );
+        assert_eq!(redacted_env_value("DRY_RUN_TEST_UNSET"), Value::Null);
+    }
+}
@@ -0,0 +1,302 @@
+//! Error codes shared by every error surfaced to clients. A code is a category and a name within it, e.g.
+//! `auth.invalid_api_key`, along with the HTTP status it maps to. Error enums implement [`HasErrorCode`], so the same
+//! failure gets the same status and body whether it's returned by the Crypto API, ingress or the config server.
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// Carries the error code on responses whose body can't change shape, e.g. Crypto API v1 errors.
+pub const ERROR_CODE_HEADER: &str = "x-evervault-error-code";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The request was malformed or can't be served
+    Request,
+    /// The request didn't carry valid credentials
+    Auth,
+    /// A request limit was hit
+    Quota,
+    /// Encryption, decryption or signing failed
+    Crypto,
+    /// The enclave couldn't be attested
+    Attestation,
+    /// A connection, or a request over one, failed
+    Network,
+    /// A DNS lookup failed
+    Dns,
+    /// Egress was blocked or failed
+    Egress,
+    /// The enclave or its host is misconfigured
+    Config,
+    /// Anything else, which isn't the client's fault
+    Internal,
+}
+
+impl ErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Request => "request",
+            Self::Auth => "auth",
+            Self::Quota => "quota",
+            Self::Crypto => "crypto",
+            Self::Attestation => "attestation",
+            Self::Network => "network",
+            Self::Dns => "dns",
+            Self::Egress => "egress",
+            Self::Config => "config",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorCode {
+    pub category: ErrorCategory,
+    pub name: &'static str,
+    pub status: StatusCode,
+}
+
+impl ErrorCode {
+    pub const fn new(category: ErrorCategory, name: &'static str, status: StatusCode) -> Self {
+        Self {
+            category,
+            name,
+            status,
+        }
+    }
+
+    /// The same code with a different status, for errors which pass on an upstream status.
+    pub const fn with_status(self, status: StatusCode) -> Self {
+        Self { status, ..self }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.category.as_str(), self.name)
+    }
+}
+
+pub mod codes {
+    use super::{ErrorCategory, ErrorCode};
+    use hyper::StatusCode;
+
+    pub const BAD_REQUEST: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "bad_request",
+        StatusCode::BAD_REQUEST,
+    );
+    pub const INVALID_PAYLOAD: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "invalid_payload",
+        StatusCode::BAD_REQUEST,
+    );
+    pub const NOT_FOUND: ErrorCode =
+        ErrorCode::new(ErrorCategory::Request, "not_found", StatusCode::NOT_FOUND);
+    pub const UNSUPPORTED_VERSION: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "unsupported_version",
+        StatusCode::BAD_REQUEST,
+    );
+    pub const UNSUPPORTED_MEDIA_TYPE: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "unsupported_media_type",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+    );
+    pub const PAYLOAD_TOO_LARGE: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "payload_too_large",
+        StatusCode::PAYLOAD_TOO_LARGE,
+    );
+    pub const TIMEOUT: ErrorCode = ErrorCode::new(
+        ErrorCategory::Request,
+        "timeout",
+        StatusCode::GATEWAY_TIMEOUT,
+    );
+
+    pub const MISSING_API_KEY: ErrorCode = ErrorCode::new(
+        ErrorCategory::Auth,
+        "missing_api_key",
+        StatusCode::UNAUTHORIZED,
+    );
+    pub const INVALID_API_KEY: ErrorCode = ErrorCode::new(
+        ErrorCategory::Auth,
+        "invalid_api_key",
+        StatusCode::UNAUTHORIZED,
+    );
+    pub const INVALID_TOKEN: ErrorCode = ErrorCode::new(
+        ErrorCategory::Auth,
+        "invalid_token",
+        StatusCode::UNAUTHORIZED,
+    );
+
+    pub const QUOTA_EXCEEDED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Quota,
+        "exceeded",
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+    pub const RATE_LIMITED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Quota,
+        "rate_limited",
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+
+    pub const CRYPTO_FAILED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Crypto,
+        "failed",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    );
+    pub const INVALID_CIPHERTEXT: ErrorCode = ErrorCode::new(
+        ErrorCategory::Crypto,
+        "invalid_ciphertext",
+        StatusCode::BAD_REQUEST,
+    );
+    pub const E3_REQUEST_FAILED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Crypto,
+        "e3_request_failed",
+        StatusCode::BAD_GATEWAY,
+    );
+    pub const E3_TIMEOUT: ErrorCode = ErrorCode::new(
+        ErrorCategory::Crypto,
+        "e3_timeout",
+        StatusCode::GATEWAY_TIMEOUT,
+    );
+
+    pub const ATTESTATION_FAILED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Attestation,
+        "failed",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    );
+
+    pub const CONNECTION_FAILED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Network,
+        "connection_failed",
+        StatusCode::BAD_GATEWAY,
+    );
+    pub const UPSTREAM_FAILED: ErrorCode = ErrorCode::new(
+        ErrorCategory::Network,
+        "upstream_failed",
+        StatusCode::BAD_GATEWAY,
+    );
+
+    pub const DNS_LOOKUP_FAILED: ErrorCode =
+        ErrorCode::new(ErrorCategory::Dns, "lookup_failed", StatusCode::BAD_GATEWAY);
+    pub const DNS_TIMEOUT: ErrorCode =
+        ErrorCode::new(ErrorCategory::Dns, "timeout", StatusCode::GATEWAY_TIMEOUT);
+    pub const DNS_INVALID_QUERY: ErrorCode =
+        ErrorCode::new(ErrorCategory::Dns, "invalid_query", StatusCode::BAD_REQUEST);
+
+    pub const EGRESS_BLOCKED: ErrorCode =
+        ErrorCode::new(ErrorCategory::Egress, "blocked", StatusCode::FORBIDDEN);
+    pub const EGRESS_FAILED: ErrorCode =
+        ErrorCode::new(ErrorCategory::Egress, "failed", StatusCode::BAD_GATEWAY);
+
+    pub const CONFIG_MISSING: ErrorCode = ErrorCode::new(
+        ErrorCategory::Config,
+        "missing",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    );
+    pub const CONFIG_INVALID: ErrorCode = ErrorCode::new(
+        ErrorCategory::Config,
+        "invalid",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    );
+
+    pub const INTERNAL: ErrorCode = ErrorCode::new(
+        ErrorCategory::Internal,
+        "error",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    );
+}
+
+/// Implemented by errors which can be surfaced to clients.
+pub trait HasErrorCode: std::fmt::Display {
+    fn error_code(&self) -> ErrorCode;
+
+    fn error_body(&self) -> ErrorBody {
+        ErrorBody::new(self.error_code(), self.to_string())
+    }
+
+    /// A JSON response with the error's status and structured body.
+    fn to_error_response(&self) -> Response<Body> {
+        self.error_body().into_response(self.error_code().status)
+    }
+}
+
+/// The body of an error response. `message` is kept from the original unstructured bodies, so existing clients which
+/// only read it are unaffected.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            category: code.category,
+            message: message.into(),
+        }
+    }
+
+    pub fn into_response(self, status: StatusCode) -> Response<Body> {
+        let body =
+            serde_json::to_string(&self).expect("Infallible - error bodies always serialize");
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, body.len())
+            .header(ERROR_CODE_HEADER, &self.code)
+            .body(Body::from(body))
+            .expect("Infallible - hardcoded response")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{codes, ErrorBody, ErrorCategory, HasErrorCode, ERROR_CODE_HEADER};
+    use hyper::StatusCode;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Invalid api key provided")]
+    struct InvalidApiKey;
+
+    impl HasErrorCode for InvalidApiKey {
+        fn error_code(&self) -> super::ErrorCode {
+            codes::INVALID_API_KEY
+        }
+    }
+
+    #[test]
+    fn test_codes_are_namespaced_by_category() {
+        assert_eq!(codes::INVALID_API_KEY.to_string(), "auth.invalid_api_key");
+        assert_eq!(
+            codes::E3_REQUEST_FAILED
+                .with_status(StatusCode::FORBIDDEN)
+                .status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_have_structured_bodies() {
+        let response = InvalidApiKey.to_error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(ERROR_CODE_HEADER).unwrap(),
+            "auth.invalid_api_key"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            ErrorBody {
+                code: "auth.invalid_api_key".to_string(),
+                category: ErrorCategory::Auth,
+                message: "Invalid api key provided".to_string(),
+            }
+        );
+    }
+}
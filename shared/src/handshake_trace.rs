@@ -0,0 +1,79 @@
+//! Handshake tracing logs the details of each connection as it's set up: TLS handshake parameters, vsock connect
+//! attempts, and how incoming bytes were framed. It's off by default, as it logs every connection, and is enabled
+//! with `EV_HANDSHAKE_TRACE=true` when investigating why a client can't connect.
+use crate::env_var_present_and_true;
+use crate::server::CID;
+use chrono::SecondsFormat;
+use std::time::Duration;
+
+pub const HANDSHAKE_TRACE_ENV_VAR: &str = "EV_HANDSHAKE_TRACE";
+
+lazy_static::lazy_static! {
+    static ref HANDSHAKE_TRACE_ENABLED: bool = env_var_present_and_true!(HANDSHAKE_TRACE_ENV_VAR);
+}
+
+pub fn is_enabled() -> bool {
+    *HANDSHAKE_TRACE_ENABLED
+}
+
+/// The current time with millisecond precision, as handshakes rarely take more than a second.
+pub fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Log a handshake event under the `handshake` target, if tracing is enabled.
+#[macro_export]
+macro_rules! handshake_trace {
+    ($($arg:tt)+) => {
+        if $crate::handshake_trace::is_enabled() {
+            log::info!(
+                target: "handshake",
+                "[{}] {}",
+                $crate::handshake_trace::timestamp(),
+                format_args!($($arg)+)
+            );
+        }
+    };
+}
+
+/// ALPN protocol names for display, e.g. `h2,http/1.1`.
+pub fn format_alpn<'a>(protocols: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let protocols: Vec<_> = protocols.into_iter().map(String::from_utf8_lossy).collect();
+    if protocols.is_empty() {
+        "none".to_string()
+    } else {
+        protocols.join(",")
+    }
+}
+
+pub fn trace_vsock_connect<T>(
+    port: u16,
+    cid: &CID,
+    elapsed: Duration,
+    result: &Result<T, std::io::Error>,
+) {
+    match result {
+        Ok(_) => handshake_trace!(
+            "vsock connect to {cid:?}:{port} succeeded in {}ms",
+            elapsed.as_millis()
+        ),
+        Err(e) => handshake_trace!(
+            "vsock connect to {cid:?}:{port} failed after {}ms - {e}",
+            elapsed.as_millis()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::format_alpn;
+
+    #[test]
+    fn test_alpn_protocols_are_formatted_for_display() {
+        assert_eq!(
+            format_alpn([b"h2".as_slice(), b"http/1.1".as_slice()]),
+            "h2,http/1.1"
+        );
+        assert_eq!(format_alpn(Vec::<&[u8]>::new()), "none");
+    }
+}
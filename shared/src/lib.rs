@@ -8,25 +8,39 @@ pub const EGRESS_PROXY_PORT: u16 = 4444;
 pub const DNS_PROXY_VSOCK_PORT: u16 = 8585;
 pub const STATS_VSOCK_PORT: u16 = 8129;
 pub const ENCLAVE_ACME_PORT: u16 = 7780;
+pub const ENCLAVE_TCP_PASSTHROUGH_PORT: u16 = 7781;
 #[cfg(not(feature = "enclave"))]
 pub const ENCLAVE_STATSD_PORT: u16 = 8122;
 #[cfg(feature = "enclave")]
 pub const ENCLAVE_STATSD_PORT: u16 = 8125;
-#[cfg(feature = "enclave")]
 pub const ENCLAVE_CID: u32 = 2021;
 #[cfg(feature = "enclave")]
 pub const PARENT_CID: u32 = 3;
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "test_harness")))]
 pub const ENCLAVE_IP: &str = "172.20.0.7";
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "test_harness")))]
 pub const PARENT_IP: &str = "172.20.0.8";
+// The test harness runs both sides on one host, with a loopback address each so their ports can't collide
+#[cfg(all(feature = "test_harness", not(feature = "enclave")))]
+pub const ENCLAVE_IP: &str = "127.0.0.2";
+#[cfg(all(feature = "test_harness", not(feature = "enclave")))]
+pub const PARENT_IP: &str = "127.0.0.1";
 
 pub mod acme;
+pub mod attestation;
+pub mod buffer_pool;
+pub mod clock;
+pub mod dry_run;
+pub mod error_code;
+pub mod handshake_trace;
+pub mod locked_secret;
 pub mod logging;
 pub mod rpc;
+pub mod runtime;
 pub mod server;
 pub mod stats;
 pub mod utils;
+pub mod validation;
 
 lazy_static::lazy_static! {
   pub static ref CLIENT_VERSION: String = option_env!("CARGO_PKG_VERSION").map(|version| version.to_string()).unwrap_or_else(|| "unknown".to_string());
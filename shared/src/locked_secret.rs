@@ -0,0 +1,116 @@
+//! Fixed size secrets held in their own locked pages, so they're never paged out. Only the pages holding secrets are
+//! locked, as locking every allocation of the process would pin all of its memory, and fail allocations once the
+//! memlock limit is reached.
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+const FALLBACK_PAGE_SIZE: usize = 4096;
+
+/// A secret of `N` bytes on pages of its own, which are locked into memory while it's held, and zeroed before being
+/// unlocked and freed. No other data shares its pages, so unlocking them on drop can't unlock anything else.
+pub struct LockedSecret<const N: usize> {
+    secret: NonNull<[u8; N]>,
+    layout: Layout,
+}
+
+// The pages are owned by the secret, and are only reachable through it
+unsafe impl<const N: usize> Send for LockedSecret<N> {}
+unsafe impl<const N: usize> Sync for LockedSecret<N> {}
+
+impl<const N: usize> LockedSecret<N> {
+    /// A zeroed secret, to be filled in place. Locking is best effort, and a failure to lock is logged.
+    pub fn zeroed() -> Self {
+        let page_size = page_size();
+        let layout = Layout::from_size_align(N.max(1).next_multiple_of(page_size), page_size)
+            .expect("Secret sizes are rounded up to whole pages");
+        // Safety: the layout is at least one page long
+        let pages = unsafe { alloc_zeroed(layout) };
+        let secret =
+            NonNull::new(pages.cast::<[u8; N]>()).unwrap_or_else(|| handle_alloc_error(layout));
+        // Safety: the pages were just allocated with this layout
+        if unsafe { libc::mlock(pages.cast(), layout.size()) } != 0 {
+            log::warn!(
+                "Failed to lock secret into memory - {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Self { secret, layout }
+    }
+
+    /// A secret holding a copy of the given bytes, or None if they aren't `N` bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != N {
+            return None;
+        }
+        let mut secret = Self::zeroed();
+        secret.copy_from_slice(bytes);
+        Some(secret)
+    }
+}
+
+impl<const N: usize> Deref for LockedSecret<N> {
+    type Target = [u8; N];
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: the pointer is valid and initialized for as long as the secret is held
+        unsafe { self.secret.as_ref() }
+    }
+}
+
+impl<const N: usize> DerefMut for LockedSecret<N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: the pointer is valid and initialized for as long as the secret is held, and is borrowed mutably
+        unsafe { self.secret.as_mut() }
+    }
+}
+
+impl<const N: usize> Drop for LockedSecret<N> {
+    fn drop(&mut self) {
+        let pages = self.secret.as_ptr().cast::<u8>();
+        // Safety: the pages were allocated with this layout, and nothing else refers to them
+        unsafe {
+            std::slice::from_raw_parts_mut(pages, self.layout.size()).zeroize();
+            libc::munlock(pages.cast(), self.layout.size());
+            dealloc(pages, self.layout);
+        }
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for LockedSecret<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LockedSecret([REDACTED])")
+    }
+}
+
+fn page_size() -> usize {
+    // Safety: sysconf has no preconditions
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => FALLBACK_PAGE_SIZE,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{page_size, LockedSecret};
+
+    #[test]
+    fn test_secret_is_held_on_its_own_pages() {
+        let mut secret = LockedSecret::<32>::zeroed();
+        assert_eq!(*secret, [0; 32]);
+        secret[0] = 1;
+        assert_eq!(secret[0], 1);
+        assert_eq!(secret.secret.as_ptr() as usize % page_size(), 0);
+        assert_eq!(secret.layout.size(), page_size());
+        assert_eq!(format!("{secret:?}"), "LockedSecret([REDACTED])");
+    }
+
+    #[test]
+    fn test_secret_from_slice_must_match_its_length() {
+        let secret = LockedSecret::<4>::from_slice(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(*secret, [1, 2, 3, 4]);
+        assert!(LockedSecret::<4>::from_slice(&[1, 2, 3]).is_none());
+    }
+}
@@ -12,6 +12,8 @@ use hyper::{
 
 use rand::{thread_rng, Rng};
 
+use crate::server::config_server::requests::TrxLogRecord;
+
 use env_logger::Env;
 pub fn init_env_logger() {
     let env = Env::default().filter_or("EV_CAGE_LOG", "info");
@@ -53,6 +55,9 @@ pub struct TrxContext {
     #[builder(default)]
     elapsed: Option<f64>,
     request_type: String,
+    /// The ingress route which served the request, when the data plane routes to several services
+    #[builder(default)]
+    service: Option<String>,
 }
 
 impl TrxContext {
@@ -68,6 +73,120 @@ impl TrxContext {
     }
 }
 
+impl From<TrxContext> for TrxLogRecord {
+    fn from(trx: TrxContext) -> Self {
+        Self {
+            txid: trx.txid,
+            ts: trx.ts,
+            msg: trx.msg,
+            uri: trx.uri,
+            r#type: trx.r#type,
+            request_method: trx.request_method,
+            remote_ip: trx.remote_ip,
+            request_headers: trx.request_headers,
+            user_agent: trx.user_agent,
+            response_headers: trx.response_headers,
+            response_code: trx.response_code,
+            status_group: trx.status_group,
+            resource_name: trx.resource_name,
+            resource_uuid: trx.resource_uuid,
+            app_uuid: trx.app_uuid,
+            team_uuid: trx.team_uuid,
+            n_decrypted_fields: trx.n_decrypted_fields,
+            content_type: trx.content_type,
+            response_content_type: trx.response_content_type,
+            elapsed: trx.elapsed,
+            request_type: trx.request_type,
+            service: trx.service,
+        }
+    }
+}
+
+impl From<TrxLogRecord> for TrxContext {
+    fn from(record: TrxLogRecord) -> Self {
+        Self {
+            txid: record.txid,
+            ts: record.ts,
+            msg: record.msg,
+            uri: record.uri,
+            r#type: record.r#type,
+            request_method: record.request_method,
+            remote_ip: record.remote_ip,
+            request_headers: record.request_headers,
+            user_agent: record.user_agent,
+            response_headers: record.response_headers,
+            response_code: record.response_code,
+            status_group: record.status_group,
+            resource_name: record.resource_name,
+            resource_uuid: record.resource_uuid,
+            app_uuid: record.app_uuid,
+            team_uuid: record.team_uuid,
+            n_decrypted_fields: record.n_decrypted_fields,
+            content_type: record.content_type,
+            response_content_type: record.response_content_type,
+            elapsed: record.elapsed,
+            request_type: record.request_type,
+            service: record.service,
+        }
+    }
+}
+
+/// Kinds of access to sensitive material recorded in the enclave's audit stream
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    SecretRead,
+    PrivateKeyUse,
+}
+
+/// A record of a provisioned secret being read, or a private key being used, inside the enclave. Audit events are
+/// shipped to the control plane alongside trx logs, but on their own stream.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    ts: String,
+    log_type: String,
+    pub event_type: AuditEventType,
+    /// Name of the secret or key which was accessed
+    pub resource: String,
+    /// What accessed the secret or key e.g. the environment initialization or a TLS handshake
+    pub accessor: String,
+    pub resource_name: String,
+    pub resource_uuid: String,
+    pub app_uuid: String,
+    pub team_uuid: String,
+}
+
+impl AuditEvent {
+    pub fn new(
+        event_type: AuditEventType,
+        resource: impl Into<String>,
+        accessor: impl Into<String>,
+        enclave_name: &str,
+        enclave_uuid: &str,
+        app_uuid: &str,
+        team_uuid: &str,
+    ) -> Self {
+        Self {
+            ts: get_iso_timestamp(),
+            log_type: "audit".to_string(),
+            event_type,
+            resource: resource.into(),
+            accessor: accessor.into(),
+            resource_name: enclave_name.to_string(),
+            resource_uuid: enclave_uuid.to_string(),
+            app_uuid: app_uuid.to_string(),
+            team_uuid: team_uuid.to_string(),
+        }
+    }
+
+    pub fn record(self) {
+        if let Ok(log) = serde_json::to_string(&self) {
+            println!("{log}");
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TrxContextId {
     txid: u128,
@@ -136,18 +255,19 @@ impl TrxContextBuilder {
             elapsed: None,
             remote_ip: None,
             request_type: Some(request_type.into()),
+            service: None,
         }
     }
 
     pub fn get_timer() -> SystemTime {
-        std::time::SystemTime::now()
+        crate::clock::now()
     }
 
     pub fn stop_timer_and_build(
         &mut self,
         started: SystemTime,
     ) -> Result<TrxContext, TrxContextBuilderError> {
-        let elapsed = started.elapsed().unwrap().as_millis() as f64;
+        let elapsed = crate::clock::elapsed_since(started).as_millis() as f64;
         self.elapsed(Some(elapsed));
         self.build()
     }
@@ -185,19 +305,13 @@ impl TrxContextBuilder {
         self.add_headers_to_request(req.headers(), trusted_headers);
 
         //Pull out content type
-        if let Some(content_type) = req.headers().get(CONTENT_TYPE) {
-            let content_type_str = content_type
-                .to_str()
-                .expect("Infallible - Failed to convert HeaderValue of ContentType to String");
-            self.content_type(Some(content_type_str.to_string()));
+        if let Some(content_type) = header_str(req.headers(), CONTENT_TYPE) {
+            self.content_type(Some(content_type.to_string()));
         }
 
         //Pull out user agent
-        if let Some(user_agent) = req.headers().get(USER_AGENT) {
-            let user_agent_str = user_agent
-                .to_str()
-                .expect("Infallible - Failed to convert HeaderValue of ContentType to String");
-            self.user_agent(Some(user_agent_str.into()));
+        if let Some(user_agent) = header_str(req.headers(), USER_AGENT) {
+            self.user_agent(Some(user_agent.into()));
         }
     }
 
@@ -206,11 +320,8 @@ impl TrxContextBuilder {
         self.add_headers_to_response(res.headers(), trusted_headers);
 
         //Pull out content type
-        if let Some(content_type) = res.headers().get(CONTENT_TYPE) {
-            let content_type_str = content_type
-                .to_str()
-                .expect("Infallible - Failed to convert HeaderValue of ContentType to String");
-            self.response_content_type(Some(content_type_str.into()));
+        if let Some(content_type) = header_str(res.headers(), CONTENT_TYPE) {
+            self.response_content_type(Some(content_type.into()));
         }
     }
 
@@ -274,10 +385,16 @@ impl TrxContextBuilder {
 }
 
 fn get_iso_timestamp() -> String {
-    let timestamp: chrono::DateTime<chrono::Utc> = std::time::SystemTime::now().into();
+    let timestamp: chrono::DateTime<chrono::Utc> = crate::clock::now().into();
     timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
 }
 
+/// The value of a header, if it's set and is visible ASCII. Clients can send arbitrary bytes, which are left out of
+/// the log rather than failing the request.
+fn header_str(headers: &HeaderMap<HeaderValue>, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
 fn is_trusted_header(trusted_headers: &[String], header_key: &str) -> bool {
     // Prevent sensitive headers from being logged
     if SENSITIVE_HEADERS.contains(header_key) {
@@ -476,7 +593,7 @@ impl std::fmt::Display for StatusGroup {
 
 #[cfg(test)]
 mod test {
-    use super::{is_trusted_header, TrxContext, TrxContextBuilder};
+    use super::{is_trusted_header, AuditEvent, AuditEventType, TrxContext, TrxContextBuilder};
 
     #[test]
     fn test_create_non_http_log() {
@@ -527,6 +644,7 @@ mod test {
             response_content_type: None,
             elapsed: None,
             request_type: super::RequestType::Websocket.into(),
+            service: None,
         };
         assert_eq!(log, expected_log);
     }
@@ -575,6 +693,23 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_non_ascii_headers_are_left_out_of_the_log() {
+        let request = hyper::Request::builder()
+            .uri("/hello")
+            .header(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_bytes(b"text/plain; charset=\xff").unwrap(),
+            )
+            .header(hyper::header::USER_AGENT, "curl/8.0")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let mut trx = TrxContextBuilder::new(super::RequestType::HTTP);
+        trx.add_req_to_trx_context(&request, &[]);
+        assert_eq!(trx.content_type, None);
+        assert_eq!(trx.user_agent, Some(Some("curl/8.0".to_string())));
+    }
+
     use super::{build_log_uri, Uri};
     #[test]
     fn test_uri_formatting() {
@@ -614,4 +749,48 @@ mod test {
             .unwrap();
         assert_eq!(format!("/{}", base_query), build_log_uri(&uri));
     }
+
+    #[test]
+    fn test_audit_event_serialization() {
+        let event = AuditEvent::new(
+            AuditEventType::PrivateKeyUse,
+            "trusted_cert_key",
+            "tls_handshake",
+            "my-enclave",
+            "enclave_123",
+            "app_123",
+            "team_456",
+        );
+        let serialized = serde_json::to_value(&event).unwrap();
+        assert_eq!(serialized["logType"], "audit");
+        assert_eq!(serialized["eventType"], "private_key_use");
+        assert_eq!(serialized["resourceUuid"], "enclave_123");
+        let deserialized: AuditEvent = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized, event);
+    }
+
+    #[test]
+    fn test_trx_log_batch_round_trips_through_protobuf() {
+        use crate::server::config_server::requests::{PostTrxLogsRequest, TrxLogBatch};
+
+        let mut trx = TrxContextBuilder::init_trx_context_with_enclave_details(
+            "enclave_123",
+            "my-enclave",
+            "app_123",
+            "team_456",
+            super::RequestType::HTTP,
+        );
+        trx.uri(Some("/hello".to_string()));
+        trx.request_method(Some("POST".to_string()));
+        trx.elapsed(Some(12.5));
+        trx.n_decrypted_fields(Some(3));
+        let trx_logs = vec![trx.build().unwrap(), trx.build().unwrap()];
+
+        let encoded = TrxLogBatch::new(trx_logs.clone()).encode();
+        let decoded = TrxLogBatch::decode(&encoded).unwrap();
+        assert_eq!(decoded.trx_logs(), trx_logs);
+
+        let json = serde_json::to_vec(&PostTrxLogsRequest::new(trx_logs)).unwrap();
+        assert!(encoded.len() < json.len());
+    }
 }
@@ -1,3 +1,4 @@
+use crate::error_code::{codes, ErrorCode, HasErrorCode};
 use thiserror::Error;
 extern crate rmp_serde as rmps;
 
@@ -8,3 +9,9 @@ pub enum RpcError {
     #[error("An error occured while encoding the message - {0:?}")]
     EncodeError(#[from] rmps::encode::Error),
 }
+
+impl HasErrorCode for RpcError {
+    fn error_code(&self) -> ErrorCode {
+        codes::INTERNAL
+    }
+}
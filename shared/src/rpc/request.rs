@@ -11,11 +11,38 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, PartialEq, Deserialize, Serialize, Eq)]
 pub struct ExternalRequest {
     pub ip: IpAddr,
+    /// The client's first bytes, sent by data planes talking to control planes which predate peeked client data. The
+    /// destination is checked against them, and they're written to it before the rest of the connection is piped.
+    #[serde(default)]
     pub data: Vec<u8>,
     pub port: u16,
+    /// Whether the destination should be checked against the client's first bytes, which follow the request on the
+    /// connection. Connections where the server speaks first can only be checked by IP. Only serialized when set, so
+    /// requests which don't use it can still be read by older control planes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inspect_client_data: bool,
 }
 
 impl ExternalRequest {
+    pub fn new(ip: IpAddr, port: u16, inspect_client_data: bool) -> Self {
+        Self {
+            ip,
+            data: Vec::new(),
+            port,
+            inspect_client_data,
+        }
+    }
+
+    /// A request carrying the client's first bytes, for control planes which don't peek at them on the connection.
+    pub fn with_client_data(ip: IpAddr, port: u16, data: Vec<u8>) -> Self {
+        Self {
+            ip,
+            data,
+            port,
+            inspect_client_data: false,
+        }
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>, RpcError> {
         let mut buf = Vec::new();
         self.serialize(&mut Serializer::new(&mut buf))?;
@@ -27,4 +54,58 @@ impl ExternalRequest {
         let res = Deserialize::deserialize(&mut deserializer)?;
         Ok(res)
     }
+
+    /// Deserialize a request from the start of a buffer, also returning the bytes which followed it. The client's
+    /// stream is piped on verbatim after the request, so its first bytes can arrive in the same read.
+    pub fn from_bytes_with_remainder(bytes: &[u8]) -> Result<(ExternalRequest, &[u8]), RpcError> {
+        let mut deserializer = Deserializer::new(std::io::Cursor::new(bytes));
+        let res = Deserialize::deserialize(&mut deserializer)?;
+        let consumed = deserializer.position() as usize;
+        Ok((res, &bytes[consumed..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExternalRequest;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_trailing_bytes_are_returned() {
+        let request = ExternalRequest::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5432, false);
+        let mut bytes = request.to_bytes().unwrap();
+        bytes.extend_from_slice(b"client data");
+
+        let (parsed, remainder) = ExternalRequest::from_bytes_with_remainder(&bytes).unwrap();
+        assert_eq!(parsed, request);
+        assert_eq!(remainder, b"client data");
+    }
+
+    #[derive(Debug, serde::Deserialize, serde::Serialize)]
+    struct LegacyExternalRequest {
+        ip: IpAddr,
+        data: Vec<u8>,
+        port: u16,
+    }
+
+    #[test]
+    fn test_requests_are_compatible_with_older_planes() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let request = ExternalRequest::with_client_data(ip, 443, b"hello".to_vec());
+        let legacy: LegacyExternalRequest =
+            rmp_serde::from_slice(&request.to_bytes().unwrap()).unwrap();
+        assert_eq!(legacy.data, b"hello");
+        assert_eq!(legacy.port, 443);
+
+        let legacy = LegacyExternalRequest {
+            ip,
+            data: b"hello".to_vec(),
+            port: 443,
+        };
+        let parsed = ExternalRequest::from_bytes(rmp_serde::to_vec(&legacy).unwrap()).unwrap();
+        assert_eq!(
+            parsed,
+            ExternalRequest::with_client_data(ip, 443, b"hello".to_vec())
+        );
+    }
 }
@@ -0,0 +1,99 @@
+//! Tuning options for the tokio runtimes used by the control plane and data plane.
+//!
+//! Enclave vCPU allocations vary widely between deployments, so the worker thread count, blocking pool size
+//! and scheduler event interval can be overridden rather than relying on tokio's defaults.
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Builder, Runtime};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Number of worker threads for a multi-threaded runtime. Defaults to the number of cores.
+    pub worker_threads: Option<usize>,
+    /// Upper limit on the number of threads spawned for blocking operations.
+    pub max_blocking_threads: Option<usize>,
+    /// Number of scheduler ticks between polls for external events (I/O and timers).
+    pub event_interval: Option<u32>,
+}
+
+impl RuntimeConfig {
+    /// Read the runtime config from env vars, using the given prefix e.g. `CONTROL_PLANE` reads
+    /// `CONTROL_PLANE_WORKER_THREADS`, `CONTROL_PLANE_MAX_BLOCKING_THREADS` and `CONTROL_PLANE_EVENT_INTERVAL`.
+    pub fn from_env_vars(prefix: &str) -> Self {
+        Self {
+            worker_threads: read_env_var(prefix, "WORKER_THREADS"),
+            max_blocking_threads: read_env_var(prefix, "MAX_BLOCKING_THREADS"),
+            event_interval: read_env_var(prefix, "EVENT_INTERVAL"),
+        }
+    }
+
+    pub fn build_multi_thread_runtime(&self) -> std::io::Result<Runtime> {
+        let mut builder = Builder::new_multi_thread();
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        self.apply(&mut builder).build()
+    }
+
+    pub fn build_current_thread_runtime(&self) -> std::io::Result<Runtime> {
+        let mut builder = Builder::new_current_thread();
+        self.apply(&mut builder).build()
+    }
+
+    fn apply<'a>(&self, builder: &'a mut Builder) -> &'a mut Builder {
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads);
+        }
+        if let Some(event_interval) = self.event_interval {
+            builder.event_interval(event_interval);
+        }
+        builder.enable_all()
+    }
+}
+
+fn read_env_var<T: std::str::FromStr + PartialOrd + From<u8>>(
+    prefix: &str,
+    name: &str,
+) -> Option<T> {
+    let var_name = format!("{prefix}_{name}");
+    let value = std::env::var(&var_name).ok()?;
+    match value.parse::<T>() {
+        // tokio panics when given zero threads or a zero event interval
+        Ok(parsed) if parsed > T::from(0) => Some(parsed),
+        _ => {
+            log::warn!("Ignoring invalid value for {var_name}: {value}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RuntimeConfig;
+
+    #[test]
+    fn test_runtime_config_read_from_env_vars() {
+        std::env::set_var("TEST_RUNTIME_WORKER_THREADS", "2");
+        std::env::set_var("TEST_RUNTIME_MAX_BLOCKING_THREADS", "00");
+        std::env::set_var("TEST_RUNTIME_EVENT_INTERVAL", "not-a-number");
+        let config = RuntimeConfig::from_env_vars("TEST_RUNTIME");
+        assert_eq!(
+            config,
+            RuntimeConfig {
+                worker_threads: Some(2),
+                max_blocking_threads: None,
+                event_interval: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_runtime_built_from_config() {
+        let config = RuntimeConfig {
+            worker_threads: Some(1),
+            max_blocking_threads: Some(4),
+            event_interval: Some(31),
+        };
+        let runtime = config.build_multi_thread_runtime().unwrap();
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+    }
+}
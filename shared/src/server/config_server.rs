@@ -10,11 +10,18 @@ pub mod routes {
     pub enum ConfigServerPath {
         GetCertToken,
         PostTrxLogs,
+        PostTrxLogBatch,
+        PostAuditLogs,
         GetE3Token,
         Storage,
         AcmeSign,
         AcmeJWK,
         Time,
+        SessionToken,
+        EgressPolicy,
+        CrashReport,
+        Ocsp,
+        Version,
     }
 
     impl FromStr for ConfigServerPath {
@@ -25,10 +32,17 @@ pub mod routes {
                 "/cert/token" => Ok(Self::GetCertToken),
                 "/e3/token" => Ok(Self::GetE3Token),
                 "/trx/logs" => Ok(Self::PostTrxLogs),
+                "/trx/logs/batch" => Ok(Self::PostTrxLogBatch),
+                "/audit/logs" => Ok(Self::PostAuditLogs),
                 "/storage" => Ok(Self::Storage),
                 "/acme/sign" => Ok(Self::AcmeSign),
                 "/acme/jwk" => Ok(Self::AcmeJWK),
                 "/time" => Ok(Self::Time),
+                "/session/token" => Ok(Self::SessionToken),
+                "/egress/policy" => Ok(Self::EgressPolicy),
+                "/crash/report" => Ok(Self::CrashReport),
+                "/ocsp" => Ok(Self::Ocsp),
+                crate::server::plane_version::VERSION_PATH => Ok(Self::Version),
                 _ => Err(ServerError::InvalidPath(input.to_string())),
             }
         }
@@ -40,24 +54,39 @@ pub mod routes {
                 Self::GetCertToken => write!(f, "/cert/token"),
                 Self::GetE3Token => write!(f, "/e3/token"),
                 Self::PostTrxLogs => write!(f, "/trx/logs"),
+                Self::PostTrxLogBatch => write!(f, "/trx/logs/batch"),
+                Self::PostAuditLogs => write!(f, "/audit/logs"),
                 Self::Storage => write!(f, "/storage"),
                 Self::AcmeSign => write!(f, "/acme/sign"),
                 Self::AcmeJWK => write!(f, "/acme/jwk"),
                 Self::Time => write!(f, "/time"),
+                Self::SessionToken => write!(f, "/session/token"),
+                Self::EgressPolicy => write!(f, "/egress/policy"),
+                Self::CrashReport => write!(f, "/crash/report"),
+                Self::Ocsp => write!(f, "/ocsp"),
+                Self::Version => write!(f, "{}", crate::server::plane_version::VERSION_PATH),
             }
         }
     }
 }
 
 pub mod requests {
-    use crate::{acme::jws::JwsResult, logging::TrxContext};
+    use crate::{
+        acme::jws::JwsResult,
+        logging::{AuditEvent, TrxContext},
+    };
 
     use super::error::ServerResult;
     use serde::{Deserialize, Serialize};
+    use zeroize::Zeroizing;
 
     pub trait ConfigServerPayload: Sized + Serialize {
+        fn into_bytes(self) -> ServerResult<Vec<u8>> {
+            Ok(serde_json::to_vec(&self)?)
+        }
+
         fn into_body(self) -> ServerResult<hyper::Body> {
-            Ok(hyper::Body::from(serde_json::to_vec(&self)?))
+            Ok(hyper::Body::from(self.into_bytes()?))
         }
     }
 
@@ -137,10 +166,19 @@ pub mod requests {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Clone)]
     pub struct Secret {
         pub name: String,
-        pub secret: String,
+        pub secret: Zeroizing<String>,
+    }
+
+    impl std::fmt::Debug for Secret {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Secret")
+                .field("name", &self.name)
+                .field("secret", &"<redacted>")
+                .finish()
+        }
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -155,12 +193,89 @@ pub mod requests {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    /// The session token generated by the enclave, registered with the control plane so it can present it on ingress
+    /// connections.
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct PostSessionTokenRequest {
+        token: Zeroizing<String>,
+    }
+
+    impl ConfigServerPayload for PostSessionTokenRequest {}
+
+    impl PostSessionTokenRequest {
+        pub fn new(token: String) -> Self {
+            Self {
+                token: Zeroizing::new(token),
+            }
+        }
+
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+    }
+
+    /// Identity the E3 TLS endpoint must present, issued by the provisioner over the attested provisioning
+    /// channel so it can't be substituted by the host.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub struct E3Identity {
+        /// Base64 encoded SHA-256 hashes of the DER encoded SubjectPublicKeyInfo of each valid E3 TLS key
+        #[serde(default)]
+        pub spki_sha256: Vec<String>,
+        /// PEM encoded CA which must issue E3's certificate for its hostname
+        #[serde(default)]
+        pub ca_cert_pem: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
     pub struct GetCertResponseDataPlane {
         intermediate_cert: String,
-        key_pair: String,
+        key_pair: Zeroizing<String>,
         pub secrets: Option<Vec<Secret>>,
         pub context: ProvisionerContext,
+        #[serde(default)]
+        pub e3_identity: Option<E3Identity>,
+        #[serde(default)]
+        pub customer_certs: Vec<CustomerCert>,
+        /// Base64 encoded PEM bundle of CAs for ingress client certs. When set, clients must present a cert issued by
+        /// one of them.
+        #[serde(default)]
+        pub client_ca_certs: Option<String>,
+    }
+
+    /// A cert and key uploaded by the customer, served instead of a provisioned cert to clients connecting with one of
+    /// its server names.
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct CustomerCert {
+        /// Hostnames to serve the cert for. A leading `*.` matches a single label.
+        pub server_names: Vec<String>,
+        /// Base64 encoded PEM chain, starting with the leaf cert
+        pub cert_chain: String,
+        /// Base64 encoded PEM private key
+        key: Zeroizing<String>,
+    }
+
+    impl CustomerCert {
+        pub fn new(server_names: Vec<String>, cert_chain: String, key: String) -> Self {
+            Self {
+                server_names,
+                cert_chain,
+                key: Zeroizing::new(key),
+            }
+        }
+
+        pub fn key(&self) -> Zeroizing<String> {
+            self.key.clone()
+        }
+    }
+
+    impl std::fmt::Debug for CustomerCert {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("CustomerCert")
+                .field("server_names", &self.server_names)
+                .field("cert_chain", &self.cert_chain)
+                .field("key", &"<redacted>")
+                .finish()
+        }
     }
 
     // TODO: remove "cage" usages in provisioner
@@ -176,6 +291,8 @@ pub mod requests {
     pub struct GetSecretsResponseDataPlane {
         pub secrets: Vec<Secret>,
         pub context: ProvisionerContext,
+        #[serde(default)]
+        pub e3_identity: Option<E3Identity>,
     }
 
     impl ConfigServerPayload for GetCertResponseDataPlane {}
@@ -185,11 +302,25 @@ pub mod requests {
             self.intermediate_cert.clone()
         }
 
-        pub fn key_pair(&self) -> String {
+        pub fn key_pair(&self) -> Zeroizing<String> {
             self.key_pair.clone()
         }
     }
 
+    impl std::fmt::Debug for GetCertResponseDataPlane {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("GetCertResponseDataPlane")
+                .field("intermediate_cert", &self.intermediate_cert)
+                .field("key_pair", &"<redacted>")
+                .field("secrets", &self.secrets)
+                .field("context", &self.context)
+                .field("e3_identity", &self.e3_identity)
+                .field("customer_certs", &self.customer_certs)
+                .field("client_ca_certs", &self.client_ca_certs)
+                .finish()
+        }
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct PostTrxLogsRequest {
         trx_logs: Vec<TrxContext>,
@@ -207,6 +338,141 @@ pub mod requests {
         }
     }
 
+    /// An update to the egress allow list and ports, in the same comma separated formats as `EV_EGRESS_ALLOW_LIST`
+    /// and the data plane's egress config. Ports are left unchanged when omitted.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct EgressPolicyUpdate {
+        /// Increases with every update, including across control plane restarts, so the data plane can tell which it
+        /// has already applied
+        #[serde(default)]
+        pub version: u64,
+        pub allow_list: String,
+        #[serde(default)]
+        pub ports: Option<String>,
+    }
+
+    impl ConfigServerPayload for EgressPolicyUpdate {}
+
+    /// Content type of protobuf encoded trx log batches. Batches are only sent to control planes which advertise the
+    /// `trx_log_batch` feature, so breaking schema changes need a new feature rather than a new content type.
+    pub const TRX_LOG_BATCH_CONTENT_TYPE: &str = "application/x-protobuf";
+
+    /// Compact binary encoding of [`PostTrxLogsRequest`], for cages shipping high volumes of trx logs.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TrxLogBatch {
+        #[prost(message, repeated, tag = "1")]
+        pub trx_logs: Vec<TrxLogRecord>,
+    }
+
+    impl TrxLogBatch {
+        pub fn new(trx_logs: Vec<TrxContext>) -> Self {
+            Self {
+                trx_logs: trx_logs.into_iter().map(TrxLogRecord::from).collect(),
+            }
+        }
+
+        pub fn encode(&self) -> Vec<u8> {
+            prost::Message::encode_to_vec(self)
+        }
+
+        pub fn decode(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+            prost::Message::decode(bytes)
+        }
+
+        pub fn trx_logs(self) -> Vec<TrxContext> {
+            self.trx_logs.into_iter().map(TrxContext::from).collect()
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TrxLogRecord {
+        #[prost(string, tag = "1")]
+        pub txid: String,
+        #[prost(string, tag = "2")]
+        pub ts: String,
+        #[prost(string, tag = "3")]
+        pub msg: String,
+        #[prost(string, optional, tag = "4")]
+        pub uri: Option<String>,
+        #[prost(string, tag = "5")]
+        pub r#type: String,
+        #[prost(string, optional, tag = "6")]
+        pub request_method: Option<String>,
+        #[prost(string, optional, tag = "7")]
+        pub remote_ip: Option<String>,
+        #[prost(string, optional, tag = "8")]
+        pub request_headers: Option<String>,
+        #[prost(string, optional, tag = "9")]
+        pub user_agent: Option<String>,
+        #[prost(string, optional, tag = "10")]
+        pub response_headers: Option<String>,
+        #[prost(string, optional, tag = "11")]
+        pub response_code: Option<String>,
+        #[prost(string, optional, tag = "12")]
+        pub status_group: Option<String>,
+        #[prost(string, tag = "13")]
+        pub resource_name: String,
+        #[prost(string, tag = "14")]
+        pub resource_uuid: String,
+        #[prost(string, tag = "15")]
+        pub app_uuid: String,
+        #[prost(string, tag = "16")]
+        pub team_uuid: String,
+        #[prost(uint32, optional, tag = "17")]
+        pub n_decrypted_fields: Option<u32>,
+        #[prost(string, optional, tag = "18")]
+        pub content_type: Option<String>,
+        #[prost(string, optional, tag = "19")]
+        pub response_content_type: Option<String>,
+        #[prost(double, optional, tag = "20")]
+        pub elapsed: Option<f64>,
+        #[prost(string, tag = "21")]
+        pub request_type: String,
+        #[prost(string, optional, tag = "22")]
+        pub service: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PostAuditLogsRequest {
+        audit_logs: Vec<AuditEvent>,
+    }
+
+    impl ConfigServerPayload for PostAuditLogsRequest {}
+
+    impl PostAuditLogsRequest {
+        pub fn new(audit_logs: Vec<AuditEvent>) -> Self {
+            Self { audit_logs }
+        }
+
+        pub fn audit_logs(self) -> Vec<AuditEvent> {
+            self.audit_logs
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum CrashKind {
+        Panic,
+        /// An error the data plane can't recover from, reported before it exits
+        Fatal,
+    }
+
+    /// Sent by the data plane as it crashes, so enclave crashes can be diagnosed from the host without console access.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct CrashReport {
+        pub kind: CrashKind,
+        pub message: String,
+        /// Where the panic happened, as `file:line:column`
+        pub location: Option<String>,
+        /// A short hash of the backtrace, so reports of the same crash can be grouped without shipping the backtrace
+        pub backtrace_hash: String,
+        pub uptime_secs: u64,
+        pub thread: Option<String>,
+        pub version: String,
+    }
+
+    impl ConfigServerPayload for CrashReport {}
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct GetObjectRequest {
         key: String,
@@ -241,16 +507,25 @@ pub mod requests {
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Clone)]
     pub struct PutObjectRequest {
         key: String,
-        object: String,
+        object: Zeroizing<String>,
+    }
+
+    impl std::fmt::Debug for PutObjectRequest {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("PutObjectRequest")
+                .field("key", &self.key)
+                .field("object", &"<redacted>")
+                .finish()
+        }
     }
 
     impl ConfigServerPayload for PutObjectRequest {}
 
     impl PutObjectRequest {
-        pub fn new(key: String, object: String) -> Self {
+        pub fn new(key: String, object: Zeroizing<String>) -> Self {
             Self { key, object }
         }
 
@@ -258,8 +533,8 @@ pub mod requests {
             self.key.clone()
         }
 
-        pub fn object(&self) -> String {
-            self.object.clone()
+        pub fn object(&self) -> &str {
+            &self.object
         }
     }
 
@@ -347,4 +622,52 @@ pub mod requests {
     }
 
     impl ConfigServerPayload for JwkResponse {}
+
+    /// An OCSP request for the control plane to send to a cert's responder, as enclaves can't reach it themselves.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct OcspFetchRequest {
+        pub responder_url: String,
+        /// Base64 encoded DER OCSP request
+        pub request: String,
+    }
+
+    impl ConfigServerPayload for OcspFetchRequest {}
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct OcspFetchResponse {
+        /// Base64 encoded DER OCSP response
+        pub response: String,
+    }
+
+    impl ConfigServerPayload for OcspFetchResponse {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::requests::{GetCertResponseDataPlane, PutObjectRequest, Secret};
+
+    #[test]
+    fn test_key_material_is_redacted_from_debug_output() {
+        let raw_response = r#"{ "intermediate_cert": "cert", "key_pair": "super-secret-key", "secrets": [{ "name": "API_KEY", "secret": "super-secret-value" }], "context": { "cage_uuid": "uuid", "cage_name": "name", "team_uuid": "team", "app_uuid": "app" }, "customer_certs": [{ "server_names": ["api.example.com"], "cert_chain": "chain", "key": "super-secret-customer-key" }] }"#;
+        let response: GetCertResponseDataPlane = serde_json::from_str(raw_response).unwrap();
+        let debug_output = format!("{response:?}");
+        assert!(!debug_output.contains("super-secret"));
+        assert_eq!(response.key_pair().as_str(), "super-secret-key");
+        assert_eq!(
+            response.customer_certs[0].key().as_str(),
+            "super-secret-customer-key"
+        );
+        assert!(response.client_ca_certs.is_none());
+
+        let secret = response.secrets.unwrap().remove(0);
+        assert!(!format!("{secret:?}").contains("super-secret-value"));
+        let serialized: Secret =
+            serde_json::from_value(serde_json::to_value(&secret).unwrap()).unwrap();
+        assert_eq!(serialized.secret.as_str(), "super-secret-value");
+
+        let put_object =
+            PutObjectRequest::new("key".to_string(), "super-secret-object".to_string().into());
+        assert!(!format!("{put_object:?}").contains("super-secret"));
+        assert_eq!(put_object.object(), "super-secret-object");
+    }
 }
@@ -0,0 +1,264 @@
+//! A sharded TTL cache for IPs resolved through the egress DNS proxy.
+//!
+//! Every egress connection checks its destination IP against this cache, so a single global lock serialises all
+//! concurrent egress. Entries are instead spread across independently locked shards, and lookups only take a
+//! read lock on the shard the key hashes to. Each shard evicts its own least recently inserted entries once full.
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use ttl_cache::TtlCache;
+
+pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// Returned when a shard's lock has been poisoned by a panicking writer
+#[derive(Debug)]
+pub struct PoisonedShard;
+
+/// A value along with when it expires, so the time it has left can be reported.
+struct Expiring<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+/// A live entry, with the time left before it expires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheEntry<K, V> {
+    pub key: K,
+    pub value: V,
+    pub ttl: Duration,
+}
+
+/// Lookups since the cache was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The share of lookups which hit, or `None` before any lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+pub struct ShardedTtlCache<K: Eq + Hash, V> {
+    shards: Vec<RwLock<TtlCache<K, Expiring<V>>>>,
+    hash_builder: RandomState,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Eq + Hash, V> ShardedTtlCache<K, V> {
+    /// Create a cache holding roughly `capacity` entries in total, split evenly across `shard_count` shards.
+    pub fn new(capacity: usize, shard_count: usize) -> Self {
+        let shard_count = shard_count.max(1);
+        let shard_capacity = capacity.div_ceil(shard_count).max(1);
+        Self {
+            shards: (0..shard_count)
+                .map(|_| RwLock::new(TtlCache::new(shard_capacity)))
+                .collect(),
+            hash_builder: RandomState::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V, ttl: Duration) -> Result<Option<V>, PoisonedShard> {
+        let mut shard = self.write_shard(&key)?;
+        let value = Expiring {
+            value,
+            expires_at: Instant::now() + ttl,
+        };
+        Ok(shard.insert(key, value, ttl).map(|previous| previous.value))
+    }
+
+    /// Returns true if the key is cached and has not expired
+    pub fn contains_key<Q>(&self, key: &Q) -> Result<bool, PoisonedShard>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.read_shard(key)?;
+        Ok(self.record_lookup(shard.get(key)).is_some())
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Result<Option<V>, PoisonedShard>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let shard = self.read_shard(key)?;
+        Ok(self
+            .record_lookup(shard.get(key))
+            .map(|cached| cached.value.clone()))
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Result<Option<V>, PoisonedShard>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.write_shard(key)?;
+        Ok(shard.remove(key).map(|removed| removed.value))
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Every live entry across all shards. Each shard is locked in turn, so this is for diagnostics rather than the
+    /// request path.
+    pub fn entries(&self) -> Result<Vec<CacheEntry<K, V>>, PoisonedShard>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let now = Instant::now();
+        let mut entries = vec![];
+        for shard in &self.shards {
+            let mut shard = shard.write().map_err(|_| PoisonedShard)?;
+            entries.extend(shard.iter().map(|(key, cached)| CacheEntry {
+                key: key.clone(),
+                value: cached.value.clone(),
+                ttl: cached.expires_at.saturating_duration_since(now),
+            }));
+        }
+        Ok(entries)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_lookup<'a>(&self, cached: Option<&'a Expiring<V>>) -> Option<&'a Expiring<V>> {
+        let counter = if cached.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    fn shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        (self.hash_builder.hash_one(key) as usize) % self.shards.len()
+    }
+
+    fn read_shard<Q: Hash + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Result<RwLockReadGuard<'_, TtlCache<K, Expiring<V>>>, PoisonedShard> {
+        self.shards[self.shard_index(key)]
+            .read()
+            .map_err(|_| PoisonedShard)
+    }
+
+    fn write_shard<Q: Hash + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Result<RwLockWriteGuard<'_, TtlCache<K, Expiring<V>>>, PoisonedShard> {
+        self.shards[self.shard_index(key)]
+            .write()
+            .map_err(|_| PoisonedShard)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShardedTtlCache;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_insert_and_get() {
+        let cache = ShardedTtlCache::new(100, 4);
+        cache
+            .insert(
+                "1.1.1.1".to_string(),
+                "one.one.one.one".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        assert!(cache.contains_key("1.1.1.1").unwrap());
+        assert_eq!(
+            cache.get("1.1.1.1").unwrap().as_deref(),
+            Some("one.one.one.one")
+        );
+        assert!(!cache.contains_key("8.8.8.8").unwrap());
+        cache.remove("1.1.1.1").unwrap();
+        assert!(!cache.contains_key("1.1.1.1").unwrap());
+    }
+
+    #[test]
+    fn test_entries_and_stats_are_reported() {
+        let cache = ShardedTtlCache::new(100, 4);
+        cache
+            .insert("1.1.1.1", "one.one.one.one", Duration::from_secs(60))
+            .unwrap();
+        cache
+            .insert("8.8.8.8", "dns.google", Duration::from_millis(1))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "1.1.1.1");
+        assert!(entries[0].ttl > Duration::from_secs(50));
+
+        assert_eq!(cache.stats().hit_rate(), None);
+        assert!(cache.contains_key("1.1.1.1").unwrap());
+        assert!(!cache.contains_key("8.8.8.8").unwrap());
+        assert_eq!(cache.stats().hit_rate(), Some(0.5));
+    }
+
+    #[test]
+    fn test_expired_entries_are_not_returned() {
+        let cache = ShardedTtlCache::new(100, 4);
+        cache
+            .insert("1.1.1.1", "one.one.one.one", Duration::from_millis(1))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!cache.contains_key("1.1.1.1").unwrap());
+    }
+
+    #[test]
+    fn test_shards_evict_independently_when_full() {
+        // A single entry per shard, so every shard only ever holds its latest insert
+        let cache = ShardedTtlCache::new(2, 2);
+        for i in 0..100 {
+            cache.insert(i, i, Duration::from_secs(60)).unwrap();
+        }
+        let cached = (0..100).filter(|i| cache.contains_key(i).unwrap()).count();
+        assert!(cached <= cache.shard_count());
+        assert!(cache.contains_key(&99).unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        let cache = Arc::new(ShardedTtlCache::new(1000, 16));
+        let handles: Vec<_> = (0..8)
+            .map(|thread| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = thread * 100 + i;
+                        cache.insert(key, thread, Duration::from_secs(60)).unwrap();
+                        assert!(cache.contains_key(&key).unwrap());
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .for_each(|handle| handle.join().unwrap());
+    }
+}
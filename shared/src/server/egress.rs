@@ -2,11 +2,18 @@ use dns_parser::RData;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use serde::Deserializer;
-use std::net::Ipv4Addr;
-use std::sync::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
-use ttl_cache::TtlCache;
+
+use super::config_server::requests::EgressPolicyUpdate;
+use super::dns_cache::{ShardedTtlCache, DEFAULT_SHARD_COUNT};
+use super::sni::{client_hello_record_len, get_hostname, is_client_hello};
+use crate::error_code::{codes, ErrorCode, HasErrorCode};
+use crate::utils::PeekableStream;
 
 #[derive(Debug, Error)]
 pub enum EgressError {
@@ -16,6 +23,12 @@ pub enum EgressError {
     EgressDomainNotAllowed(String),
     #[error("Attempted request to banned ip. hostname: {0}")]
     EgressIpNotAllowed(String),
+    #[error("Attempted request to banned port {0}")]
+    EgressPortNotAllowed(u16),
+    #[error("Attempted request to {destination} on port {port}, which it is not allowed on")]
+    DestinationPortNotAllowed { destination: String, port: u16 },
+    #[error("Attempted plaintext request to port {0}, only TLS egress is allowed")]
+    PlaintextEgressNotAllowed(u16),
     #[error("Client Hello not found")]
     ClientHelloMissing,
     #[error("TLS extension missing")]
@@ -24,20 +37,104 @@ pub enum EgressError {
     DNSParseError(#[from] dns_parser::Error),
     #[error("Could not obtain lock for IP cache")]
     CouldntObtainLock,
+    #[error("Attempted request to ip {ip} which was not resolved for hostname {hostname}")]
+    IpNotResolvedForHostname { ip: String, hostname: String },
+    #[error("Invalid egress policy - {0}")]
+    InvalidPolicy(String),
+    #[error("Egress policy update would allow {0}, which the enclave's egress config does not")]
+    PolicyNotNarrower(String),
+}
+
+impl HasErrorCode for EgressError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::EgressDomainNotAllowed(_)
+            | Self::EgressIpNotAllowed(_)
+            | Self::EgressPortNotAllowed(_)
+            | Self::DestinationPortNotAllowed { .. }
+            | Self::PlaintextEgressNotAllowed(_)
+            | Self::IpNotResolvedForHostname { .. } => codes::EGRESS_BLOCKED,
+            Self::InvalidPolicy(_) | Self::PolicyNotNarrower(_) => codes::CONFIG_INVALID,
+            Self::HostnameError(_)
+            | Self::ClientHelloMissing
+            | Self::ExtensionMissing
+            | Self::DNSParseError(_) => codes::EGRESS_FAILED,
+            Self::CouldntObtainLock => codes::INTERNAL,
+        }
+    }
+}
+
+/// An egress policy which can be swapped at runtime. Each connection takes a snapshot of the policy when it starts,
+/// so an update applies to new connections without changing the rules partway through an existing one.
+pub struct LivePolicy<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> LivePolicy<T> {
+    pub fn new(policy: T) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(policy)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn store(&self, policy: T) {
+        let policy = Arc::new(policy);
+        match self.current.write() {
+            Ok(mut current) => *current = policy,
+            Err(poisoned) => *poisoned.into_inner() = policy,
+        }
+    }
 }
 
-pub static ALLOWED_IPS_FROM_DNS: Lazy<Mutex<TtlCache<String, String>>> =
-    Lazy::new(|| Mutex::new(TtlCache::new(1000)));
+pub static ALLOWED_IPS_FROM_DNS: Lazy<ShardedTtlCache<String, String>> =
+    Lazy::new(|| ShardedTtlCache::new(1000, DEFAULT_SHARD_COUNT));
+
+/// The hostnames each IP was resolved for, keyed by (ip, hostname). An IP can be shared by many hostnames
+/// (e.g. behind a CDN), so every resolution is tracked rather than only the latest.
+static DNS_RESOLUTIONS: Lazy<ShardedTtlCache<(String, String), ()>> =
+    Lazy::new(|| ShardedTtlCache::new(4000, DEFAULT_SHARD_COUNT));
 
 pub fn get_egress_allow_list_from_env() -> EgressDestinations {
     let domain_str = std::env::var("EV_EGRESS_ALLOW_LIST").unwrap_or("".to_string());
     get_egress_allow_list(domain_str)
 }
 
+/// Parse a comma separated allow list. Entries can be restricted to a port with a `:port` suffix, e.g.
+/// `db.internal:5432`, and are allowed on every port of the egress config when they aren't.
 pub fn get_egress_allow_list(domain_str: String) -> EgressDestinations {
-    let (ips, domains): (Vec<String>, Vec<String>) = domain_str
-        .split(',')
-        .map(|destination| destination.to_string())
+    let mut destinations: Vec<String> = Vec::new();
+    // None for destinations allowed on any port
+    let mut entry_ports: BTreeMap<String, Option<Vec<u16>>> = BTreeMap::new();
+    for entry in domain_str.split(',') {
+        let (destination, port) = split_allow_list_port(entry);
+        match entry_ports.get_mut(destination) {
+            Some(Some(ports)) => match port {
+                Some(port) => ports.push(port),
+                None => {
+                    entry_ports.insert(destination.to_string(), None);
+                }
+            },
+            Some(None) => {}
+            None => {
+                entry_ports.insert(destination.to_string(), port.map(|port| vec![port]));
+                destinations.push(destination.to_string());
+            }
+        }
+    }
+    let port_restrictions = entry_ports
+        .into_iter()
+        .filter_map(|(destination, ports)| ports.map(|ports| (destination, ports)))
+        .collect();
+
+    let (ips, domains): (Vec<String>, Vec<String>) = destinations
+        .into_iter()
         .partition(|destination| destination.parse::<Ipv4Addr>().is_ok());
     let (wildcard, exact): (Vec<String>, Vec<String>) = domains
         .iter()
@@ -52,6 +149,103 @@ pub fn get_egress_allow_list(domain_str: String) -> EgressDestinations {
         exact: exact.clone(),
         allow_all: exact == vec![""] || exact.contains(&"*".to_string()),
         ips,
+        port_restrictions,
+    }
+}
+
+/// Split an allow list entry into its destination and the port it's restricted to, if any.
+fn split_allow_list_port(entry: &str) -> (&str, Option<u16>) {
+    match entry.rsplit_once(':') {
+        Some((destination, port)) => match port.parse::<u16>() {
+            Ok(port) if port != 0 => (destination, Some(port)),
+            _ => (entry, None),
+        },
+        None => (entry, None),
+    }
+}
+
+/// The allow list entries which match a destination, as they were written, e.g. `*.example.com`. Wildcard entries
+/// can be matched with a suffix such as `.db.example.com` too.
+fn matching_entries(destination: &str, allowed_destinations: &EgressDestinations) -> Vec<String> {
+    let exact = allowed_destinations
+        .exact
+        .iter()
+        .chain(&allowed_destinations.ips)
+        .filter(|entry| entry.as_str() == destination)
+        .cloned();
+    let wildcard = allowed_destinations
+        .wildcard
+        .iter()
+        .filter(|suffix| destination.ends_with(suffix.as_str()))
+        .map(|suffix| format!("*{suffix}"));
+    exact.chain(wildcard).collect()
+}
+
+/// The ports a destination is allowed on by its allow list entries, or None if it's allowed on any port.
+fn permitted_ports(
+    destination: &str,
+    allowed_destinations: &EgressDestinations,
+) -> Option<BTreeSet<u16>> {
+    if allowed_destinations.allow_all {
+        return None;
+    }
+    let mut ports = BTreeSet::new();
+    for entry in matching_entries(destination, allowed_destinations) {
+        match allowed_destinations.port_restrictions.get(&entry) {
+            Some(entry_ports) => ports.extend(entry_ports),
+            None => return None,
+        }
+    }
+    Some(ports)
+}
+
+/// Check that a hostname or IP in the allow list may be reached on the port. This is on top of the egress config's
+/// ports, which apply to every destination.
+pub fn check_destination_port(
+    destination: &str,
+    port: u16,
+    allowed_destinations: &EgressDestinations,
+) -> Result<(), EgressError> {
+    if allowed_destinations.port_restrictions.is_empty() {
+        return Ok(());
+    }
+    match permitted_ports(destination, allowed_destinations) {
+        Some(ports) if !ports.contains(&port) => Err(EgressError::DestinationPortNotAllowed {
+            destination: destination.to_string(),
+            port,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check that an IP may be reached on the port, when the hostname it's for isn't known. IPs which aren't in the allow
+/// list themselves are allowed on the port if any hostname they were resolved for is.
+fn check_ip_port(
+    ip: &str,
+    port: u16,
+    allowed_destinations: &EgressDestinations,
+) -> Result<(), EgressError> {
+    if allowed_destinations.port_restrictions.is_empty()
+        || allowed_destinations.ips.iter().any(|allowed| allowed == ip)
+    {
+        return check_destination_port(ip, port, allowed_destinations);
+    }
+    let resolutions = DNS_RESOLUTIONS
+        .entries()
+        .map_err(|_| EgressError::CouldntObtainLock)?;
+    let allowed = resolutions
+        .iter()
+        .filter(|resolution| resolution.key.0 == ip)
+        .any(|resolution| {
+            check_destination_port(&resolution.key.1, port, allowed_destinations).is_ok()
+        });
+    if allowed {
+        Ok(())
+    } else {
+        Err(EgressError::DestinationPortNotAllowed {
+            destination: ip.to_string(),
+            port,
+        })
     }
 }
 
@@ -88,23 +282,41 @@ pub fn cache_ip_for_allowlist(packet: &[u8]) -> Result<(), EgressError> {
     let packet = dns_parser::Packet::parse(packet)?;
     packet.answers.iter().try_for_each(|ans| {
         if let RData::A(ip) = ans.data {
-            cache_ip(ip.0.to_string(), ans)
+            cache_ip(ip.0.to_string(), ans)?;
+            // Answers may be for a CNAME target, so provenance is recorded against the hostnames queried
+            packet
+                .questions
+                .iter()
+                .try_for_each(|q| cache_resolution(ip.0.to_string(), &q.qname.to_string(), ans.ttl))
         } else {
             Ok(())
         }
     })
 }
 
+fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn cache_resolution(ip: String, hostname: &str, ttl: u32) -> Result<(), EgressError> {
+    DNS_RESOLUTIONS
+        .insert(
+            (ip, normalize_hostname(hostname)),
+            (),
+            Duration::from_secs(ttl.into()),
+        )
+        .map_err(|_| EgressError::CouldntObtainLock)?;
+    Ok(())
+}
+
 fn cache_ip(ip: String, answer: &dns_parser::ResourceRecord<'_>) -> Result<(), EgressError> {
-    let mut cache = match ALLOWED_IPS_FROM_DNS.lock() {
-        Ok(cache) => cache,
-        Err(_) => return Err(EgressError::CouldntObtainLock),
-    };
-    cache.insert(
-        ip,
-        answer.name.to_string(),
-        Duration::from_secs(answer.ttl.into()),
-    );
+    ALLOWED_IPS_FROM_DNS
+        .insert(
+            ip,
+            answer.name.to_string(),
+            Duration::from_secs(answer.ttl.into()),
+        )
+        .map_err(|_| EgressError::CouldntObtainLock)?;
     Ok(())
 }
 
@@ -122,20 +334,370 @@ pub fn check_ip_allow_list(
     }
 }
 
+/// Check that an egress connection is to an allowed destination. When the connection starts with a Client Hello
+/// carrying SNI, the hostname is re-checked against the allow list and the IP must have come from a DNS answer for
+/// that hostname, so an allowed hostname can't be used to reach an IP resolved for another (e.g. DNS rebinding).
+pub fn check_egress_destination(
+    ip: String,
+    port: u16,
+    data: &[u8],
+    allowed_destinations: &EgressDestinations,
+) -> Result<(), EgressError> {
+    check_ip_allow_list(ip.clone(), allowed_destinations)?;
+    if allowed_destinations.allow_all {
+        return Ok(());
+    }
+    if allowed_destinations.ips.contains(&ip) {
+        return check_destination_port(&ip, port, allowed_destinations);
+    }
+    match get_hostname(data) {
+        Ok(hostname) => {
+            check_ip_resolved_for_hostname(ip, hostname, allowed_destinations)?;
+            check_destination_port(&normalize_hostname(hostname), port, allowed_destinations)
+        }
+        Err(_) => check_ip_port(&ip, port, allowed_destinations),
+    }
+}
+
+/// Check an egress connection's IP and port before the client has sent anything to check its hostname with.
+pub fn check_egress_ip(
+    ip: String,
+    port: u16,
+    allowed_destinations: &EgressDestinations,
+) -> Result<(), EgressError> {
+    check_ip_allow_list(ip.clone(), allowed_destinations)?;
+    check_ip_port(&ip, port, allowed_destinations)
+}
+
+fn check_ip_resolved_for_hostname(
+    ip: String,
+    hostname: &str,
+    allowed_destinations: &EgressDestinations,
+) -> Result<(), EgressError> {
+    let hostname = normalize_hostname(hostname);
+    check_domain_allow_list(hostname.clone(), allowed_destinations)?;
+    let resolution = (ip, hostname);
+    if DNS_RESOLUTIONS
+        .contains_key(&resolution)
+        .map_err(|_| EgressError::CouldntObtainLock)?
+    {
+        Ok(())
+    } else {
+        let (ip, hostname) = resolution;
+        Err(EgressError::IpNotResolvedForHostname { ip, hostname })
+    }
+}
+
+/// The hostnames an IP has been resolved for by the DNS proxy, sorted. Unlike a Host header, these can't be chosen
+/// by the customer process for a connection to the IP.
+pub fn hostnames_resolved_to(ip: &str) -> Result<Vec<String>, EgressError> {
+    let mut hostnames: Vec<String> = DNS_RESOLUTIONS
+        .entries()
+        .map_err(|_| EgressError::CouldntObtainLock)?
+        .into_iter()
+        .filter_map(|resolution| {
+            let (resolved_ip, hostname) = resolution.key;
+            (resolved_ip == ip).then_some(hostname)
+        })
+        .collect();
+    hostnames.sort();
+    Ok(hostnames)
+}
+
+/// An IP in the DNS cache, with every hostname it's been resolved for.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DnsCacheEntry {
+    pub ip: String,
+    pub hostnames: Vec<String>,
+    pub ttl_secs: u64,
+}
+
+/// The DNS cache's live entries, and how often egress connections have found their IP in it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DnsCacheSnapshot {
+    pub entries: Vec<DnsCacheEntry>,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: Option<f64>,
+}
+
+/// Snapshot the IPs cached from DNS answers, for diagnostics.
+pub fn dns_cache_snapshot() -> Result<DnsCacheSnapshot, EgressError> {
+    let mut hostnames: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for resolution in DNS_RESOLUTIONS
+        .entries()
+        .map_err(|_| EgressError::CouldntObtainLock)?
+    {
+        let (ip, hostname) = resolution.key;
+        hostnames.entry(ip).or_default().push(hostname);
+    }
+    let mut entries: Vec<DnsCacheEntry> = ALLOWED_IPS_FROM_DNS
+        .entries()
+        .map_err(|_| EgressError::CouldntObtainLock)?
+        .into_iter()
+        .map(|entry| {
+            let mut resolved_for = hostnames.remove(&entry.key).unwrap_or_default();
+            if resolved_for.is_empty() {
+                resolved_for.push(normalize_hostname(&entry.value));
+            }
+            resolved_for.sort();
+            DnsCacheEntry {
+                ip: entry.key,
+                hostnames: resolved_for,
+                ttl_secs: entry.ttl.as_secs(),
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.ip.cmp(&b.ip));
+    let stats = ALLOWED_IPS_FROM_DNS.stats();
+    Ok(DnsCacheSnapshot {
+        entries,
+        hits: stats.hits,
+        misses: stats.misses,
+        hit_rate: stats.hit_rate(),
+    })
+}
+
 fn is_valid_ip_from_dns(ip: String) -> Result<bool, EgressError> {
-    let cache = match ALLOWED_IPS_FROM_DNS.lock() {
-        Ok(cache) => cache,
-        Err(_) => return Err(EgressError::CouldntObtainLock),
+    ALLOWED_IPS_FROM_DNS
+        .contains_key(&ip)
+        .map_err(|_| EgressError::CouldntObtainLock)
+}
+
+/// Parse a comma separated list of ports that egress traffic is allowed to, skipping any invalid entries.
+pub fn get_egress_ports(port_str: &str) -> Vec<u16> {
+    port_str
+        .split(',')
+        .map(str::trim)
+        .filter(|port| !port.is_empty())
+        .filter_map(|port| match port.parse::<u16>() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                log::warn!("Ignoring invalid egress port: {port}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Entries in a comma separated list of ports which aren't valid ports, and would be skipped by `get_egress_ports`.
+pub fn get_invalid_egress_ports(port_str: &str) -> Vec<String> {
+    port_str
+        .split(',')
+        .map(str::trim)
+        .filter(|port| !port.is_empty() && port.parse::<u16>().is_err())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Entries in a comma separated allow list which can never match a destination, such as URLs, invalid ports or stray
+/// whitespace.
+pub fn get_malformed_allow_list_entries(domain_str: &str) -> Vec<String> {
+    domain_str
+        .split(',')
+        .filter(|destination| !is_valid_allow_list_entry(destination))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_valid_allow_list_entry(destination: &str) -> bool {
+    if destination.is_empty() || destination == "*" {
+        return true;
+    }
+    let destination = match destination.rsplit_once(':') {
+        Some((destination, port)) if destination != "*" && !destination.is_empty() => {
+            match port.parse::<u16>() {
+                Ok(port) if port != 0 => destination,
+                _ => return false,
+            }
+        }
+        Some(_) => return false,
+        None => destination,
+    };
+    if destination.parse::<Ipv4Addr>().is_ok() {
+        return true;
+    }
+    let hostname = destination.strip_prefix("*.").unwrap_or(destination);
+    hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Check a port against the configured egress ports. When no ports are configured, every port is allowed.
+pub fn check_port_allow_list(port: u16, allowed_ports: Option<&[u16]>) -> Result<(), EgressError> {
+    if allowed_ports.is_none_or(|allowed_ports| allowed_ports.contains(&port)) {
+        Ok(())
+    } else {
+        Err(EgressError::EgressPortNotAllowed(port))
+    }
+}
+
+/// When only TLS egress is allowed, reject connections whose first bytes are not the start of a Client Hello.
+pub fn check_tls_only(data: &[u8], port: u16, tls_only: bool) -> Result<(), EgressError> {
+    if !tls_only || is_client_hello(data) {
+        Ok(())
+    } else {
+        Err(EgressError::PlaintextEgressNotAllowed(port))
+    }
+}
+
+/// Peek at the first bytes a client sends. If they start a Client Hello, reading continues until its whole record
+/// has arrived, so the hostname can be checked even when the client splits the record across writes.
+pub async fn peek_client_data<S: tokio::io::AsyncRead + Unpin>(
+    stream: &mut PeekableStream<S>,
+) -> Result<&[u8], std::io::Error> {
+    stream.peek().await?;
+    while let Some(record_len) = client_hello_record_len(stream.peeked()) {
+        if stream.peeked().len() >= record_len || stream.peek_more().await? == 0 {
+            break;
+        }
+    }
+    Ok(stream.peeked())
+}
+
+/// Check that a mapped destination's host and port are allowed. IP hosts must be in the allow list directly.
+pub fn check_mapped_destination(
+    destination: &MappedDestination,
+    config: &EgressConfig,
+) -> Result<(), EgressError> {
+    check_port_allow_list(destination.port, config.ports.as_deref())?;
+    if destination.host.parse::<IpAddr>().is_err() {
+        let host = normalize_hostname(&destination.host);
+        check_domain_allow_list(host.clone(), &config.allow_list)?;
+        return check_destination_port(&host, destination.port, &config.allow_list);
+    }
+    if config.allow_list.allow_all || config.allow_list.ips.contains(&destination.host) {
+        check_destination_port(&destination.host, destination.port, &config.allow_list)
+    } else {
+        Err(EgressError::EgressIpNotAllowed(destination.host.clone()))
+    }
+}
+
+/// Parse the allow list and ports of a policy update. Unlike at startup, malformed entries are rejected rather than
+/// skipped, so a typo can't silently block traffic that was meant to be allowed.
+pub fn parse_policy_update(
+    update: &EgressPolicyUpdate,
+) -> Result<(EgressDestinations, Option<Vec<u16>>), EgressError> {
+    let malformed = get_malformed_allow_list_entries(&update.allow_list);
+    if !malformed.is_empty() {
+        return Err(EgressError::InvalidPolicy(format!(
+            "malformed allow list entries {malformed:?}"
+        )));
+    }
+    let ports = match &update.ports {
+        Some(ports) => {
+            let invalid = get_invalid_egress_ports(ports);
+            if !invalid.is_empty() {
+                return Err(EgressError::InvalidPolicy(format!(
+                    "invalid ports {invalid:?}"
+                )));
+            }
+            Some(get_egress_ports(ports))
+        }
+        None => None,
     };
-    Ok(cache.get(&ip).is_some())
+    Ok((get_egress_allow_list(update.allow_list.clone()), ports))
+}
+
+/// Apply a policy update to the enclave's egress config. The config is part of the attested enclave image, so
+/// updates can only narrow it, and anything it doesn't allow is rejected rather than trusted from the host.
+pub fn narrow_egress_config(
+    baseline: &EgressConfig,
+    update: &EgressPolicyUpdate,
+) -> Result<EgressConfig, EgressError> {
+    let (allow_list, ports) = parse_policy_update(update)?;
+    if let Some(destination) = find_destination_not_allowed(&allow_list, &baseline.allow_list) {
+        return Err(EgressError::PolicyNotNarrower(destination));
+    }
+    let ports = ports.or_else(|| baseline.ports.clone());
+    if let (Some(ports), Some(baseline_ports)) = (&ports, &baseline.ports) {
+        if let Some(port) = ports.iter().find(|port| !baseline_ports.contains(port)) {
+            return Err(EgressError::PolicyNotNarrower(format!("port {port}")));
+        }
+    }
+    Ok(EgressConfig {
+        allow_list,
+        ports,
+        ..baseline.clone()
+    })
 }
 
-#[derive(Clone, PartialEq, Debug, Deserialize)]
+/// The first destination allowed by `narrowed` that isn't allowed by `baseline`, if any.
+fn find_destination_not_allowed(
+    narrowed: &EgressDestinations,
+    baseline: &EgressDestinations,
+) -> Option<String> {
+    if baseline.allow_all {
+        return None;
+    }
+    if narrowed.allow_all {
+        return Some("all destinations".to_string());
+    }
+    let ip = narrowed.ips.iter().find(|ip| !baseline.ips.contains(ip));
+    let exact = narrowed.exact.iter().find(|domain| {
+        !domain.is_empty() && check_domain_allow_list(domain.to_string(), baseline).is_err()
+    });
+    let wildcard = narrowed
+        .wildcard
+        .iter()
+        .find(|suffix| {
+            !baseline
+                .wildcard
+                .iter()
+                .any(|allowed| suffix.ends_with(allowed.as_str()))
+        })
+        .map(|suffix| format!("*{suffix}"));
+    ip.or(exact)
+        .cloned()
+        .or(wildcard)
+        .or_else(|| find_port_not_allowed(narrowed, baseline))
+}
+
+/// The first destination and port allowed by `narrowed` that `baseline` restricts the destination from.
+fn find_port_not_allowed(
+    narrowed: &EgressDestinations,
+    baseline: &EgressDestinations,
+) -> Option<String> {
+    let destinations = narrowed
+        .ips
+        .iter()
+        .chain(narrowed.exact.iter().filter(|domain| !domain.is_empty()))
+        .map(|destination| (destination.clone(), destination.clone()))
+        .chain(
+            narrowed
+                .wildcard
+                .iter()
+                .map(|suffix| (format!("*{suffix}"), suffix.clone())),
+        );
+    for (entry, destination) in destinations {
+        let Some(baseline_ports) = permitted_ports(&destination, baseline) else {
+            continue;
+        };
+        match narrowed.port_restrictions.get(&entry) {
+            Some(ports) => {
+                if let Some(port) = ports.iter().find(|port| !baseline_ports.contains(port)) {
+                    return Some(format!("{entry}:{port}"));
+                }
+            }
+            None => return Some(format!("{entry} on every port")),
+        }
+    }
+    None
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Deserialize, Serialize)]
 pub struct EgressDestinations {
     pub wildcard: Vec<String>,
     pub exact: Vec<String>,
     pub allow_all: bool,
     pub ips: Vec<String>,
+    /// The ports allow list entries are restricted to, keyed by the entry without its port
+    #[serde(default)]
+    pub port_restrictions: BTreeMap<String, Vec<u16>>,
 }
 
 fn deserialize_allowlist<'de, D>(deserializer: D) -> Result<EgressDestinations, D::Error>
@@ -146,19 +708,98 @@ where
     Ok(get_egress_allow_list(allow_list))
 }
 
-#[derive(Clone, Deserialize, Debug)]
+fn deserialize_ports<'de, D>(deserializer: D) -> Result<Option<Vec<u16>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ports: String = Deserialize::deserialize(deserializer)?;
+    Ok(Some(get_egress_ports(&ports)))
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct EgressConfig {
     #[serde(deserialize_with = "deserialize_allowlist")]
     pub allow_list: EgressDestinations,
+    /// Ports egress is allowed to. When unset every port is allowed, as it was before ports could be configured.
+    #[serde(default, deserialize_with = "deserialize_ports")]
+    pub ports: Option<Vec<u16>>,
+    /// Block any egress which doesn't start with a TLS Client Hello, even on allowed ports
+    #[serde(default)]
+    pub tls_only: bool,
+    /// Local ports forwarded to fixed destinations
+    #[serde(default)]
+    pub destination_map: Vec<MappedDestination>,
+    /// Protocols spoken on egress ports, for those which negotiate TLS after a plaintext preamble
+    #[serde(default)]
+    pub protocols: HashMap<u16, EgressProtocol>,
+    /// How long a client has to send its first bytes, e.g. a TLS Client Hello, before its connection is dropped
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+}
+
+impl EgressConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.handshake_timeout_ms)
+    }
+}
+
+/// Protocols whose TLS negotiation egress has to take part in to reach the Client Hello
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressProtocol {
+    Postgres,
+    Mysql,
+    Smtp,
+    /// Any other protocol which upgrades to TLS in band after a short plaintext exchange
+    Starttls,
+}
+
+/// A local port forwarded to a fixed destination, for protocols which don't identify their destination (older TLS
+/// without SNI, or plain TCP) so databases and brokers reachable only by address still work.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct MappedDestination {
+    pub local_port: u16,
+    pub host: String,
+    pub port: u16,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::server::config_server::requests::EgressPolicyUpdate;
+    use crate::server::egress::cache_resolution;
+    use crate::server::egress::check_destination_port;
     use crate::server::egress::check_domain_allow_list;
+    use crate::server::egress::check_egress_destination;
+    use crate::server::egress::check_egress_ip;
     use crate::server::egress::check_ip_allow_list;
+    use crate::server::egress::check_ip_resolved_for_hostname;
+    use crate::server::egress::check_mapped_destination;
+    use crate::server::egress::check_port_allow_list;
+    use crate::server::egress::check_tls_only;
+    use crate::server::egress::dns_cache_snapshot;
+    use crate::server::egress::get_egress_allow_list;
     use crate::server::egress::get_egress_allow_list_from_env;
+    use crate::server::egress::get_egress_ports;
+    use crate::server::egress::get_invalid_egress_ports;
+    use crate::server::egress::get_malformed_allow_list_entries;
+    use crate::server::egress::hostnames_resolved_to;
+    use crate::server::egress::narrow_egress_config;
+    use crate::server::egress::peek_client_data;
+    use crate::server::egress::EgressConfig;
     use crate::server::egress::EgressDestinations;
-    use crate::server::egress::EgressError::{EgressDomainNotAllowed, EgressIpNotAllowed};
+    use crate::server::egress::EgressError::{
+        DestinationPortNotAllowed, EgressDomainNotAllowed, EgressIpNotAllowed,
+        EgressPortNotAllowed, InvalidPolicy, IpNotResolvedForHostname, PlaintextEgressNotAllowed,
+        PolicyNotNarrower,
+    };
+    use crate::server::egress::EgressProtocol;
+    use crate::server::egress::LivePolicy;
+    use crate::server::egress::ALLOWED_IPS_FROM_DNS;
+    use std::time::Duration;
 
     #[test]
     fn test_sequentially() {
@@ -181,7 +822,8 @@ mod tests {
                 exact: vec!["*".to_string()],
                 wildcard: vec![],
                 allow_all: true,
-                ips: vec![]
+                ips: vec![],
+                ..Default::default()
             }
         );
         std::env::remove_var("EV_EGRESS_ALLOW_LIST");
@@ -196,7 +838,8 @@ mod tests {
                 exact: vec!["google.com".to_string()],
                 wildcard: vec![".evervault.com".to_string()],
                 allow_all: false,
-                ips: vec!["1.1.1.1".to_string()]
+                ips: vec!["1.1.1.1".to_string()],
+                ..Default::default()
             }
         );
         std::env::remove_var("EV_EGRESS_ALLOW_LIST");
@@ -211,7 +854,8 @@ mod tests {
                 exact: vec!["".to_string()],
                 wildcard: vec![],
                 allow_all: true,
-                ips: vec![]
+                ips: vec![],
+                ..Default::default()
             }
         );
         std::env::remove_var("EV_EGRESS_ALLOW_LIST")
@@ -223,6 +867,7 @@ mod tests {
             wildcard: vec![],
             allow_all: false,
             ips: vec![],
+            ..Default::default()
         };
         let result = check_domain_allow_list("invalid.domain.com".to_string(), &destinations);
         assert!(matches!(result, Err(EgressDomainNotAllowed(_))));
@@ -234,6 +879,7 @@ mod tests {
             wildcard: vec![],
             allow_all: false,
             ips: vec!["2.2.2.2".to_string()],
+            ..Default::default()
         };
         let result = check_ip_allow_list("1.1.1.1".to_string(), &destinations);
         assert!(matches!(result, Err(EgressIpNotAllowed(_))));
@@ -245,6 +891,7 @@ mod tests {
             wildcard: vec![],
             allow_all: false,
             ips: vec!["1.1.1.1".to_string()],
+            ..Default::default()
         };
         let result = check_ip_allow_list("1.1.1.1".to_string(), &destinations);
         assert!(result.is_ok());
@@ -256,6 +903,7 @@ mod tests {
             wildcard: vec![],
             allow_all: true,
             ips: vec![],
+            ..Default::default()
         };
         let result = check_ip_allow_list("1.1.1.1".to_string(), &destinations);
         assert!(result.is_ok());
@@ -267,8 +915,377 @@ mod tests {
             wildcard: vec![],
             allow_all: true,
             ips: vec!["1.1.1.1".to_string()],
+            ..Default::default()
         };
         let result = check_domain_allow_list("a.domain.com".to_string(), &destinations);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_parse_egress_ports() {
+        assert_eq!(
+            get_egress_ports("443, 8080,,not-a-port,70000"),
+            vec![443, 8080]
+        );
+        assert_eq!(
+            get_invalid_egress_ports("443, 8080,,not-a-port,70000"),
+            vec!["not-a-port", "70000"]
+        );
+    }
+
+    #[test]
+    fn test_malformed_allow_list_entries() {
+        assert!(get_malformed_allow_list_entries("").is_empty());
+        assert!(get_malformed_allow_list_entries("*").is_empty());
+        assert!(get_malformed_allow_list_entries(
+            "*.evervault.com,google.com,1.1.1.1,my_host.internal,api.com:443,*.db.com:5432,1.1.1.1:53"
+        )
+        .is_empty());
+        assert_eq!(
+            get_malformed_allow_list_entries(
+                "https://api.com,api.com:0,api.com:https, google.com,evervault.com.,*.*.com,*:443"
+            ),
+            vec![
+                "https://api.com",
+                "api.com:0",
+                "api.com:https",
+                " google.com",
+                "evervault.com.",
+                "*.*.com",
+                "*:443"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_port_allow_list() {
+        assert!(check_port_allow_list(8080, Some(&[443, 8080])).is_ok());
+        let result = check_port_allow_list(22, Some(&[443, 8080]));
+        assert!(matches!(result, Err(EgressPortNotAllowed(22))));
+        assert!(check_port_allow_list(22, None).is_ok());
+    }
+
+    #[test]
+    fn test_egress_config_defaults_to_any_port() {
+        let config: EgressConfig = serde_json::from_str(r#"{ "allow_list": "*" }"#).unwrap();
+        assert_eq!(config.ports, None);
+        assert!(!config.tls_only);
+        let config: EgressConfig =
+            serde_json::from_str(r#"{ "allow_list": "*", "ports": "443,5432" }"#).unwrap();
+        assert_eq!(config.ports, Some(vec![443, 5432]));
+        assert!(config.protocols.is_empty());
+        let config: EgressConfig = serde_json::from_str(
+            r#"{ "allow_list": "*", "ports": "5432,3306", "protocols": { "5432": "postgres", "3306": "mysql" } }"#,
+        )
+        .unwrap();
+        assert_eq!(config.protocols.get(&5432), Some(&EgressProtocol::Postgres));
+        assert_eq!(config.protocols.get(&3306), Some(&EgressProtocol::Mysql));
+        let config: EgressConfig = serde_json::from_str(
+            r#"{ "allow_list": "*", "ports": "587,143", "protocols": { "587": "smtp", "143": "starttls" } }"#,
+        )
+        .unwrap();
+        assert_eq!(config.protocols.get(&587), Some(&EgressProtocol::Smtp));
+        assert_eq!(config.protocols.get(&143), Some(&EgressProtocol::Starttls));
+    }
+
+    #[test]
+    fn test_check_tls_only() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+        let plaintext = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(check_tls_only(&client_hello, 443, true).is_ok());
+        assert!(check_tls_only(plaintext, 80, false).is_ok());
+        let result = check_tls_only(plaintext, 80, true);
+        assert!(matches!(result, Err(PlaintextEgressNotAllowed(80))));
+        let result = check_tls_only(&client_hello[..3], 443, true);
+        assert!(matches!(result, Err(PlaintextEgressNotAllowed(443))));
+    }
+
+    #[tokio::test]
+    async fn test_split_client_hello_is_peeked_whole() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let client_hello = [0x16, 0x03, 0x01, 0x00, 0x04, 0x01, 0x00, 0x01, 0xfc];
+        let (mut client, proxy_side) = tokio::io::duplex(64);
+        let mut stream = crate::utils::PeekableStream::new(proxy_side);
+        client.write_all(&client_hello[..6]).await.unwrap();
+        let rest = tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            client.write_all(&client_hello[6..]).await.unwrap();
+            client.write_all(b"more").await.unwrap();
+        });
+
+        assert_eq!(
+            &peek_client_data(&mut stream).await.unwrap()[..9],
+            client_hello
+        );
+        rest.await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(&received[..9], client_hello);
+        assert_eq!(&received[9..], b"more");
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_is_peeked_once() {
+        use tokio::io::AsyncWriteExt;
+        let (mut client, proxy_side) = tokio::io::duplex(64);
+        let mut stream = crate::utils::PeekableStream::new(proxy_side);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(
+            peek_client_data(&mut stream).await.unwrap(),
+            b"GET / HTTP/1.1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_ip_must_be_resolved_for_hostname() {
+        let destinations = EgressDestinations {
+            exact: vec![],
+            wildcard: vec![".stripe.com".to_string()],
+            allow_all: false,
+            ips: vec![],
+            ..Default::default()
+        };
+        cache_resolution("3.3.3.3".to_string(), "api.stripe.com.", 60).unwrap();
+
+        let result =
+            check_ip_resolved_for_hostname("3.3.3.3".to_string(), "API.stripe.com", &destinations);
+        assert!(result.is_ok());
+        let result = check_ip_resolved_for_hostname(
+            "3.3.3.3".to_string(),
+            "files.stripe.com",
+            &destinations,
+        );
+        assert!(matches!(result, Err(IpNotResolvedForHostname { .. })));
+        let result =
+            check_ip_resolved_for_hostname("3.3.3.3".to_string(), "evervault.com", &destinations);
+        assert!(matches!(result, Err(EgressDomainNotAllowed(_))));
+    }
+
+    #[test]
+    fn test_egress_destination_without_sni_only_checks_ip() {
+        let destinations = EgressDestinations {
+            exact: vec![],
+            wildcard: vec![".stripe.com".to_string()],
+            allow_all: false,
+            ips: vec![],
+            ..Default::default()
+        };
+        ALLOWED_IPS_FROM_DNS
+            .insert(
+                "4.4.4.4".to_string(),
+                "api.stripe.com".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        let plaintext = b"GET / HTTP/1.1\r\nHost: evil.com\r\n\r\n";
+        assert!(
+            check_egress_destination("4.4.4.4".to_string(), 443, plaintext, &destinations).is_ok()
+        );
+        let result = check_egress_destination("5.5.5.5".to_string(), 443, plaintext, &destinations);
+        assert!(matches!(result, Err(EgressIpNotAllowed(_))));
+    }
+
+    #[test]
+    fn test_dns_cache_snapshot_groups_hostnames_by_ip() {
+        ALLOWED_IPS_FROM_DNS
+            .insert(
+                "6.6.6.6".to_string(),
+                "cdn.example.com.".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        cache_resolution("6.6.6.6".to_string(), "b.example.com.", 60).unwrap();
+        cache_resolution("6.6.6.6".to_string(), "a.example.com.", 60).unwrap();
+
+        let snapshot = dns_cache_snapshot().unwrap();
+        let entry = snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.ip == "6.6.6.6")
+            .unwrap();
+        assert_eq!(entry.hostnames, vec!["a.example.com", "b.example.com"]);
+        assert!(entry.ttl_secs > 0 && entry.ttl_secs <= 60);
+    }
+
+    #[test]
+    fn test_hostnames_resolved_to_an_ip() {
+        cache_resolution("8.8.4.4".to_string(), "Hooks.Example.com.", 60).unwrap();
+        cache_resolution("8.8.4.4".to_string(), "api.example.com.", 60).unwrap();
+        assert_eq!(
+            hostnames_resolved_to("8.8.4.4").unwrap(),
+            vec!["api.example.com", "hooks.example.com"]
+        );
+        assert!(hostnames_resolved_to("8.8.8.9").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mapped_destinations_are_checked_against_allow_list() {
+        let config: EgressConfig = serde_json::from_str(
+            r#"{
+                "allow_list": "*.db.example.com,10.0.0.5",
+                "ports": "443,5432,9092",
+                "destination_map": [
+                    { "local_port": 5432, "host": "primary.db.example.com", "port": 5432 },
+                    { "local_port": 9092, "host": "10.0.0.5", "port": 9092 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.destination_map.len(), 2);
+        for destination in &config.destination_map {
+            assert!(check_mapped_destination(destination, &config).is_ok());
+        }
+
+        let mut destination = config.destination_map[0].clone();
+        destination.host = "db.evil.com".to_string();
+        let result = check_mapped_destination(&destination, &config);
+        assert!(matches!(result, Err(EgressDomainNotAllowed(_))));
+
+        destination.host = "10.0.0.6".to_string();
+        let result = check_mapped_destination(&destination, &config);
+        assert!(matches!(result, Err(EgressIpNotAllowed(_))));
+
+        let mut destination = config.destination_map[0].clone();
+        destination.port = 3306;
+        let result = check_mapped_destination(&destination, &config);
+        assert!(matches!(result, Err(EgressPortNotAllowed(3306))));
+    }
+
+    #[test]
+    fn test_policy_updates_can_only_narrow_the_egress_config() {
+        let baseline: EgressConfig = serde_json::from_str(
+            r#"{ "allow_list": "*.example.com,api.other.com,10.0.0.5", "ports": "443,5432", "tls_only": true }"#,
+        )
+        .unwrap();
+        let update = |allow_list: &str, ports: Option<&str>| EgressPolicyUpdate {
+            version: 1,
+            allow_list: allow_list.to_string(),
+            ports: ports.map(str::to_string),
+        };
+
+        let narrowed = narrow_egress_config(
+            &baseline,
+            &update("*.db.example.com,10.0.0.5", Some("5432")),
+        )
+        .unwrap();
+        assert_eq!(narrowed.allow_list.wildcard, vec![".db.example.com"]);
+        assert_eq!(narrowed.ports, Some(vec![5432]));
+        assert!(narrowed.tls_only);
+        let narrowed = narrow_egress_config(&baseline, &update("api.other.com", None)).unwrap();
+        assert_eq!(narrowed.ports, Some(vec![443, 5432]));
+
+        for (allow_list, ports) in [
+            ("*", None),
+            ("evil.com", None),
+            ("*.com", None),
+            ("10.0.0.6", None),
+            ("api.other.com", Some("443,22")),
+        ] {
+            let result = narrow_egress_config(&baseline, &update(allow_list, ports));
+            assert!(matches!(result, Err(PolicyNotNarrower(_))), "{allow_list}");
+        }
+        let result = narrow_egress_config(&baseline, &update("https://api.other.com", None));
+        assert!(matches!(result, Err(InvalidPolicy(_))));
+        let result = narrow_egress_config(&baseline, &update("api.other.com", Some("443,abc")));
+        assert!(matches!(result, Err(InvalidPolicy(_))));
+
+        // Without configured ports every port is allowed, so updates can restrict them to any set
+        let any_port = EgressConfig {
+            ports: None,
+            ..baseline
+        };
+        let narrowed =
+            narrow_egress_config(&any_port, &update("api.other.com", Some("22"))).unwrap();
+        assert_eq!(narrowed.ports, Some(vec![22]));
+        let narrowed = narrow_egress_config(&any_port, &update("api.other.com", None)).unwrap();
+        assert_eq!(narrowed.ports, None);
+    }
+
+    #[test]
+    fn test_allow_list_entries_can_be_restricted_to_ports() {
+        let destinations = get_egress_allow_list(
+            "api.example.com:443,db.internal:5432,db.internal:5433,*.example.com:8443,10.0.0.5:9092,web.com:80,web.com"
+                .to_string(),
+        );
+        assert_eq!(
+            destinations.exact,
+            vec!["api.example.com", "db.internal", "web.com"]
+        );
+        assert_eq!(destinations.wildcard, vec![".example.com"]);
+        assert_eq!(destinations.ips, vec!["10.0.0.5"]);
+        assert_eq!(destinations.port_restrictions.len(), 4);
+        assert!(check_domain_allow_list("db.internal".to_string(), &destinations).is_ok());
+
+        assert!(check_destination_port("db.internal", 5433, &destinations).is_ok());
+        assert!(check_destination_port("web.com", 8080, &destinations).is_ok());
+        assert!(check_destination_port("api.example.com", 443, &destinations).is_ok());
+        // api.example.com also matches the wildcard entry
+        assert!(check_destination_port("api.example.com", 8443, &destinations).is_ok());
+        assert!(check_destination_port("files.example.com", 8443, &destinations).is_ok());
+        for (destination, port) in [
+            ("db.internal", 443),
+            ("files.example.com", 443),
+            ("10.0.0.5", 443),
+        ] {
+            let result = check_destination_port(destination, port, &destinations);
+            assert!(
+                matches!(result, Err(DestinationPortNotAllowed { .. })),
+                "{destination}"
+            );
+        }
+
+        assert!(check_egress_ip("10.0.0.5".to_string(), 9092, &destinations).is_ok());
+        cache_resolution("7.7.7.7".to_string(), "db.internal.", 60).unwrap();
+        ALLOWED_IPS_FROM_DNS
+            .insert(
+                "7.7.7.7".to_string(),
+                "db.internal".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        assert!(check_egress_ip("7.7.7.7".to_string(), 5432, &destinations).is_ok());
+        let result = check_egress_ip("7.7.7.7".to_string(), 22, &destinations);
+        assert!(matches!(result, Err(DestinationPortNotAllowed { .. })));
+        let plaintext = b"GET / HTTP/1.1\r\nHost: db.internal\r\n\r\n";
+        let result = check_egress_destination("7.7.7.7".to_string(), 80, plaintext, &destinations);
+        assert!(matches!(result, Err(DestinationPortNotAllowed { .. })));
+    }
+
+    #[test]
+    fn test_policy_updates_can_only_narrow_port_restrictions() {
+        let baseline: EgressConfig = serde_json::from_str(
+            r#"{ "allow_list": "*.example.com:443,db.internal:5432,api.other.com", "ports": "443,5432" }"#,
+        )
+        .unwrap();
+        let update = |allow_list: &str| EgressPolicyUpdate {
+            version: 1,
+            allow_list: allow_list.to_string(),
+            ports: None,
+        };
+
+        let narrowed =
+            narrow_egress_config(&baseline, &update("api.example.com:443,api.other.com:443"))
+                .unwrap();
+        assert_eq!(
+            narrowed.allow_list.port_restrictions.get("api.other.com"),
+            Some(&vec![443])
+        );
+        for allow_list in [
+            "api.example.com",
+            "db.internal:443",
+            "*.db.example.com:5432",
+        ] {
+            let result = narrow_egress_config(&baseline, &update(allow_list));
+            assert!(matches!(result, Err(PolicyNotNarrower(_))), "{allow_list}");
+        }
+    }
+
+    #[test]
+    fn test_live_policy_swaps_for_new_readers() {
+        let policy = LivePolicy::new(vec![443u16]);
+        let snapshot = policy.load();
+        policy.store(vec![5432]);
+        assert_eq!(*snapshot, vec![443]);
+        assert_eq!(*policy.load(), vec![5432]);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::error_code::{codes, ErrorCode, HasErrorCode};
 use std::fmt::Formatter;
 use thiserror::Error;
 
@@ -18,4 +19,16 @@ impl std::fmt::Display for ServerError {
     }
 }
 
+impl HasErrorCode for ServerError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::IoError(_) | Self::Hyper(_) | Self::UnexpectedEOF => codes::CONNECTION_FAILED,
+            Self::JsonError(_) => codes::INVALID_PAYLOAD,
+            Self::InvalidPath(_) => codes::NOT_FOUND,
+            #[cfg(feature = "network_egress")]
+            Self::EgressError(e) => e.error_code(),
+        }
+    }
+}
+
 pub type ServerResult<T> = std::result::Result<T, ServerError>;
@@ -106,6 +106,97 @@ impl HealthCheck for HealthCheckLog {
     }
 }
 
+pub const LIVENESS_PATH: &str = "/live";
+pub const READINESS_PATH: &str = "/ready";
+/// Served by the data plane's health check server, and proxied by the control plane's admin server
+pub const EGRESS_DEBUG_PATH: &str = "/debug/egress";
+
+/// Liveness is whether a component's process is running and responsive, and readiness is whether it's able to serve
+/// traffic. A component can be alive but not ready, e.g. while its certs are being provisioned, in which case it
+/// shouldn't be restarted, but it shouldn't be sent traffic either.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthProbe {
+    Liveness,
+    Readiness,
+}
+
+impl HealthProbe {
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path {
+            LIVENESS_PATH => Some(HealthProbe::Liveness),
+            READINESS_PATH => Some(HealthProbe::Readiness),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LivenessReport {
+    pub alive: bool,
+}
+
+impl HealthCheck for LivenessReport {
+    fn status_code(&self) -> u16 {
+        if self.alive {
+            200
+        } else {
+            500
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessCheck {
+    pub name: String,
+    pub ready: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ReadinessCheck {
+    pub fn ready(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ready: true,
+            message: None,
+        }
+    }
+
+    pub fn not_ready(name: &str, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            ready: false,
+            message: Some(message.into()),
+        }
+    }
+}
+
+/// The result of each of a component's readiness checks. It's ready only if every check is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+impl ReadinessReport {
+    pub fn new(checks: Vec<ReadinessCheck>) -> Self {
+        Self {
+            ready: checks.iter().all(|check| check.ready),
+            checks,
+        }
+    }
+}
+
+impl HealthCheck for ReadinessReport {
+    fn status_code(&self) -> u16 {
+        if self.ready {
+            200
+        } else {
+            503
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DataPlaneState {
     Error(String),
@@ -123,11 +214,7 @@ pub struct DataPlaneDiagnostic {
 
 impl DataPlaneDiagnostic {
     pub fn is_healthy(&self) -> bool {
-        match self.user_process {
-            UserProcessHealth::Unknown(_) => true,
-            UserProcessHealth::Error(_) => false,
-            UserProcessHealth::Response { status_code, .. } => (200..300).contains(&status_code),
-        }
+        self.user_process.is_healthy()
     }
 }
 
@@ -142,6 +229,15 @@ pub enum UserProcessHealth {
 }
 
 impl UserProcessHealth {
+    /// Whether the user process is healthy, or has no healthcheck to say otherwise.
+    pub fn is_healthy(&self) -> bool {
+        match self {
+            UserProcessHealth::Unknown(_) => true,
+            UserProcessHealth::Error(_) => false,
+            UserProcessHealth::Response { status_code, .. } => (200..300).contains(status_code),
+        }
+    }
+
     pub fn rank(&self) -> u8 {
         match self {
             UserProcessHealth::Unknown(_) => 0,
@@ -173,6 +269,25 @@ impl PartialOrd for UserProcessHealth {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_readiness_requires_every_check() {
+        let report = ReadinessReport::new(vec![
+            ReadinessCheck::ready("initialized"),
+            ReadinessCheck::not_ready("e3", "connection refused"),
+        ]);
+        assert!(!report.ready);
+        assert_eq!(report.status_code(), 503);
+        assert_eq!(
+            ReadinessReport::new(vec![ReadinessCheck::ready("initialized")]).status_code(),
+            200
+        );
+        assert_eq!(
+            HealthProbe::from_path(READINESS_PATH),
+            Some(HealthProbe::Readiness)
+        );
+        assert_eq!(HealthProbe::from_path("/"), None);
+    }
+
     #[tokio::test]
     async fn it_returns_errors_over_healthy_up_responses() {
         let max = [
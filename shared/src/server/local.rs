@@ -0,0 +1,130 @@
+//! An in-process stand-in for vsock, used when the control plane and data plane run in one process. Each listener
+//! registers its (CID, port) pair, and connecting hands it one end of an in-memory duplex stream.
+use super::error::{ServerError, ServerResult};
+use super::{proxy_protocol, Listener, CID};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Bytes buffered in each direction of a local connection before writes wait on the reader
+const LOCAL_STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+lazy_static::lazy_static! {
+    static ref LOCAL_LISTENERS: Mutex<HashMap<(u8, u16), UnboundedSender<DuplexStream>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn listener_key(cid: &CID, port: u16) -> (u8, u16) {
+    let cid = match cid {
+        CID::Parent => 0,
+        CID::Enclave => 1,
+    };
+    (cid, port)
+}
+
+pub struct LocalServer {
+    key: (u8, u16),
+    incoming: UnboundedReceiver<DuplexStream>,
+}
+
+impl LocalServer {
+    pub fn bind(cid: CID, port: u16) -> ServerResult<Self> {
+        let key = listener_key(&cid, port);
+        let mut listeners = LOCAL_LISTENERS
+            .lock()
+            .expect("Local listener registry poisoned");
+        if listeners
+            .get(&key)
+            .is_some_and(|sender| !sender.is_closed())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("Local port {port} is already bound"),
+            )
+            .into());
+        }
+        let (sender, incoming) = unbounded_channel();
+        listeners.insert(key, sender);
+        Ok(Self { key, incoming })
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        // With our receiver closed, a closed entry is either ours or stale, and can be removed either way
+        self.incoming.close();
+        if let Ok(mut listeners) = LOCAL_LISTENERS.lock() {
+            if listeners
+                .get(&self.key)
+                .is_some_and(|sender| sender.is_closed())
+            {
+                listeners.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for LocalServer {
+    type Connection = DuplexStream;
+    type Error = ServerError;
+    async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
+        self.incoming.recv().await.ok_or(ServerError::UnexpectedEOF)
+    }
+}
+
+/// Open a connection to the local listener bound to the given CID and port.
+pub fn connect(cid: CID, port: u16) -> std::io::Result<DuplexStream> {
+    let refused = || {
+        std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("Nothing is listening on local port {port}"),
+        )
+    };
+    let sender = LOCAL_LISTENERS
+        .lock()
+        .expect("Local listener registry poisoned")
+        .get(&listener_key(&cid, port))
+        .cloned()
+        .ok_or_else(refused)?;
+    let (client, server) = tokio::io::duplex(LOCAL_STREAM_BUFFER_SIZE);
+    sender.send(server).map_err(|_| refused())?;
+    Ok(client)
+}
+
+impl proxy_protocol::ProxiedConnection for DuplexStream {}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, LocalServer};
+    use crate::server::{Listener, CID};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connections_reach_the_bound_listener() {
+        let mut server = LocalServer::bind(CID::Parent, 9001).unwrap();
+        // The same port on the other side is a separate listener
+        assert!(connect(CID::Enclave, 9001).is_err());
+
+        let mut client = connect(CID::Parent, 9001).unwrap();
+        let mut accepted = server.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_ports_are_released_when_the_listener_is_dropped() {
+        let server = LocalServer::bind(CID::Enclave, 9002).unwrap();
+        assert!(LocalServer::bind(CID::Enclave, 9002).is_err());
+        drop(server);
+        assert_eq!(
+            connect(CID::Enclave, 9002).unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+        assert!(LocalServer::bind(CID::Enclave, 9002).is_ok());
+    }
+}
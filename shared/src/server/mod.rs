@@ -1,26 +1,40 @@
 pub mod config_server;
 #[cfg(feature = "network_egress")]
+pub mod dns_cache;
+#[cfg(feature = "network_egress")]
 pub mod egress;
 pub mod error;
 pub mod health;
+pub mod plane_version;
 pub mod proxy_protocol;
+pub mod session_token;
 pub mod sni;
 pub mod tcp;
+pub mod tcp_passthrough;
 pub use tcp::{TcpServer, TcpServerWithProxyProtocol};
 
+#[cfg(all(feature = "local", feature = "enclave"))]
+compile_error!("The local feature replaces vsock, and can't be combined with the enclave feature");
+
+#[cfg(feature = "local")]
+pub mod local;
 #[cfg(feature = "enclave")]
 pub mod vsock;
 #[cfg(feature = "enclave")]
 use crate::ENCLAVE_CID;
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 use crate::ENCLAVE_IP;
 #[cfg(feature = "enclave")]
 use crate::PARENT_CID;
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 use crate::PARENT_IP;
 use async_trait::async_trait;
+#[cfg(feature = "local")]
+use local::LocalServer;
+#[cfg(feature = "local")]
+use tokio::io::DuplexStream;
 use tokio::io::{AsyncRead, AsyncWrite};
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 use tokio::net::TcpStream;
 #[cfg(feature = "enclave")]
 use tokio_vsock::VsockStream;
@@ -44,6 +58,12 @@ pub async fn get_vsock_server(port: u16, cid: CID) -> error::ServerResult<VsockS
     Ok(listener)
 }
 
+/// Use a vsock listener passed in by the service manager, rather than binding one.
+#[cfg(feature = "enclave")]
+pub fn get_vsock_server_from_fd(fd: std::os::fd::OwnedFd) -> error::ServerResult<VsockServer> {
+    Ok(VsockServer::from_fd(fd))
+}
+
 #[cfg(feature = "enclave")]
 pub async fn get_vsock_server_with_proxy_protocol(
     port: u16,
@@ -57,18 +77,24 @@ pub async fn get_vsock_server_with_proxy_protocol(
     Ok(listener)
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum CID {
     Parent,
     Enclave,
 }
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub async fn get_vsock_server(port: u16, cid: CID) -> error::ServerResult<TcpServer> {
     let listener = TcpServer::bind(std::net::SocketAddr::new(get_local_ip(cid), port)).await?;
     Ok(listener)
 }
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
+pub fn get_vsock_server_from_fd(fd: std::os::fd::OwnedFd) -> error::ServerResult<TcpServer> {
+    TcpServer::from_std(fd.into())
+}
+
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub async fn get_vsock_server_with_proxy_protocol(
     port: u16,
     cid: CID,
@@ -79,7 +105,7 @@ pub async fn get_vsock_server_with_proxy_protocol(
     Ok(listener)
 }
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 fn get_local_ip(cid: CID) -> std::net::IpAddr {
     use std::net::Ipv4Addr;
     // Local docker setup
@@ -90,21 +116,49 @@ fn get_local_ip(cid: CID) -> std::net::IpAddr {
     std::net::IpAddr::V4(ip.parse::<Ipv4Addr>().expect("Invalid IP address"))
 }
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 pub async fn get_vsock_client(port: u16, cid: CID) -> Result<TcpStream, tokio::io::Error> {
-    TcpStream::connect(std::net::SocketAddr::new(get_local_ip(cid), port)).await
+    let started = std::time::Instant::now();
+    let result = TcpStream::connect(std::net::SocketAddr::new(get_local_ip(cid), port)).await;
+    crate::handshake_trace::trace_vsock_connect(port, &cid, started.elapsed(), &result);
+    result
 }
 
-#[cfg(not(feature = "enclave"))]
+#[cfg(not(any(feature = "enclave", feature = "local")))]
 impl proxy_protocol::ProxiedConnection for TcpStream {}
 
+#[cfg(feature = "local")]
+pub async fn get_vsock_server(port: u16, cid: CID) -> error::ServerResult<LocalServer> {
+    LocalServer::bind(cid, port)
+}
+
+#[cfg(feature = "local")]
+pub fn get_vsock_server_from_fd(_fd: std::os::fd::OwnedFd) -> error::ServerResult<LocalServer> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "local listeners are in memory, and can't be passed in",
+    )
+    .into())
+}
+
+#[cfg(feature = "local")]
+pub async fn get_vsock_client(port: u16, cid: CID) -> Result<DuplexStream, tokio::io::Error> {
+    let started = std::time::Instant::now();
+    let result = local::connect(cid, port);
+    crate::handshake_trace::trace_vsock_connect(port, &cid, started.elapsed(), &result);
+    result
+}
+
 #[cfg(feature = "enclave")]
 pub async fn get_vsock_client(port: u16, cid: CID) -> Result<VsockStream, tokio::io::Error> {
     let context_id = match cid {
         CID::Parent => PARENT_CID,
         CID::Enclave => ENCLAVE_CID,
     };
-    VsockStream::connect(context_id, port.into()).await
+    let started = std::time::Instant::now();
+    let result = VsockStream::connect(context_id, port.into()).await;
+    crate::handshake_trace::trace_vsock_connect(port, &cid, started.elapsed(), &result);
+    result
 }
 
 #[cfg(feature = "enclave")]
@@ -0,0 +1,146 @@
+//! Version negotiation between the control plane and data plane. Each plane serves its crate version and the
+//! protocol features it supports, and checks the other's when it first contacts it, so mismatched planes in a mixed
+//! version deploy are logged up front rather than failing later with opaque parse errors.
+use super::config_server::requests::ConfigServerPayload;
+use serde::{Deserialize, Serialize};
+
+/// Served by the data plane's health check server, and the control plane's config server
+pub const VERSION_PATH: &str = "/version";
+
+pub mod features {
+    /// Trx logs posted to the config server as protobuf batches
+    pub const TRX_LOG_BATCH: &str = "trx_log_batch";
+    /// Separate liveness and readiness probes on the data plane's health check server
+    pub const READINESS_PROBE: &str = "readiness_probe";
+    /// Runtime egress policy updates from the config server
+    pub const EGRESS_POLICY_UPDATES: &str = "egress_policy_updates";
+    /// The egress debug report on the data plane's health check server
+    pub const EGRESS_DEBUG: &str = "egress_debug";
+    /// Egress client data peeked at by the control plane as it's piped, rather than sent in the egress request
+    pub const EGRESS_PEEKED_CLIENT_DATA: &str = "egress_peeked_client_data";
+}
+
+/// The protocol features supported by planes built from this version of the shared crate.
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    features::TRX_LOG_BATCH,
+    features::READINESS_PROBE,
+    features::EGRESS_POLICY_UPDATES,
+    features::EGRESS_DEBUG,
+    features::EGRESS_PEEKED_CLIENT_DATA,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlaneVersion {
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+impl ConfigServerPayload for PlaneVersion {}
+
+impl PlaneVersion {
+    /// The version of the plane being run, given its crate version.
+    pub fn current(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            features: SUPPORTED_FEATURES
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    fn major_version(&self) -> &str {
+        self.version.split('.').next().unwrap_or_default()
+    }
+
+    /// How the peer plane differs from this one. Differing major versions and features only one plane supports are
+    /// errors, as requests using them will fail.
+    pub fn mismatches(&self, peer: &PlaneVersion) -> Vec<VersionMismatch> {
+        let mut mismatches = Vec::new();
+        if self.major_version() != peer.major_version() {
+            mismatches.push(VersionMismatch::MajorVersion(peer.version.clone()));
+        } else if self.version != peer.version {
+            mismatches.push(VersionMismatch::Version(peer.version.clone()));
+        }
+        mismatches.extend(
+            self.features
+                .iter()
+                .filter(|feature| !peer.supports(feature))
+                .map(|feature| VersionMismatch::UnsupportedByPeer(feature.clone())),
+        );
+        mismatches.extend(
+            peer.features
+                .iter()
+                .filter(|feature| !self.supports(feature))
+                .map(|feature| VersionMismatch::UnsupportedLocally(feature.clone())),
+        );
+        mismatches
+    }
+
+    /// Log each way the peer plane differs from this one, returning whether they match.
+    pub fn log_mismatches(&self, peer_name: &str, peer: &PlaneVersion) -> bool {
+        let mismatches = self.mismatches(peer);
+        for mismatch in &mismatches {
+            match mismatch {
+                VersionMismatch::Version(_) => log::warn!(
+                    "Version mismatch - running {} against {peer_name} {mismatch}",
+                    self.version
+                ),
+                _ => log::error!(
+                    "Version mismatch - running {} against {peer_name} {mismatch}",
+                    self.version
+                ),
+            }
+        }
+        if mismatches.is_empty() {
+            log::info!("Negotiated version {} with the {peer_name}", peer.version);
+        }
+        mismatches.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum VersionMismatch {
+    #[error("{0}, which is a different major version")]
+    MajorVersion(String),
+    #[error("{0}")]
+    Version(String),
+    #[error("which doesn't support {0}")]
+    UnsupportedByPeer(String),
+    #[error("which supports {0}, unknown to this plane")]
+    UnsupportedLocally(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{features, PlaneVersion, VersionMismatch};
+
+    #[test]
+    fn test_mismatched_versions_and_features_are_reported() {
+        let local = PlaneVersion::current("1.2.0");
+        assert!(local.mismatches(&PlaneVersion::current("1.2.0")).is_empty());
+
+        let mut peer = PlaneVersion::current("1.3.0-beta");
+        peer.features
+            .retain(|feature| feature != features::TRX_LOG_BATCH);
+        peer.features.push("compression".to_string());
+        assert_eq!(
+            local.mismatches(&peer),
+            vec![
+                VersionMismatch::Version("1.3.0-beta".to_string()),
+                VersionMismatch::UnsupportedByPeer(features::TRX_LOG_BATCH.to_string()),
+                VersionMismatch::UnsupportedLocally("compression".to_string()),
+            ]
+        );
+        assert!(!peer.supports(features::TRX_LOG_BATCH));
+
+        assert_eq!(
+            local.mismatches(&PlaneVersion::current("2.0.0")),
+            vec![VersionMismatch::MajorVersion("2.0.0".to_string())]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use super::error::{ServerError, ServerResult};
 pub use ppp::v2::Header as PPHeader;
 use std::convert::TryFrom;
+use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 const MIN_PROXY_PROTOCOL_HEADER_LEN: usize = 16;
 
@@ -96,6 +97,10 @@ pub async fn try_parse_proxy_protocol<S: AsyncRead + AsyncWrite + Unpin + Sync>(
                 let owned_header = header.to_owned();
                 drop(header);
                 let _header_bytes: Vec<_> = buf.drain(..owned_header.len()).collect();
+                crate::handshake_trace!(
+                    "Proxy protocol header found, source {:?}",
+                    owned_header.addresses
+                );
 
                 return Ok(AcceptedConn::new(
                     incoming_conn,
@@ -110,13 +115,42 @@ pub async fn try_parse_proxy_protocol<S: AsyncRead + AsyncWrite + Unpin + Sync>(
             Err(ppp::v2::ParseError::Partial(_, required_bytes)) => {
                 buf.reserve_exact(required_bytes);
             }
-            Err(_) => {
+            Err(e) => {
+                crate::handshake_trace!("No proxy protocol header - {e}");
                 return Ok(AcceptedConn::new(incoming_conn, None, Some(buf)));
             }
         }
     }
 }
 
+/// Build a PROXY protocol v2 header for a TCP connection from `source` to `destination`, to be written ahead of the
+/// connection's bytes so the receiver can see the original client's address.
+pub fn build_proxy_protocol_header(
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> std::io::Result<Vec<u8>> {
+    // Both addresses have to be of one family, so an IPv4 address is sent as IPv4-mapped IPv6 alongside an IPv6 one
+    let addresses = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (source, destination)
+        }
+        _ => (to_ipv6(source), to_ipv6(destination)),
+    };
+    ppp::v2::Builder::with_addresses(
+        ppp::v2::Version::Two | ppp::v2::Command::Proxy,
+        ppp::v2::Protocol::Stream,
+        addresses,
+    )
+    .build()
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
 pub trait ProxiedConnection: Sync {
     fn proxy_protocol(&self) -> Option<&PPHeader<'_>> {
         None
@@ -129,7 +163,12 @@ pub trait ProxiedConnection: Sync {
         self.proxy_protocol()
             .and_then(|header| match header.addresses {
                 ppp::v2::Addresses::IPv4(ipv4) => Some(ipv4.source_address.to_string()),
-                ppp::v2::Addresses::IPv6(ipv6) => Some(ipv6.source_address.to_string()),
+                // IPv4 clients are sent as IPv4-mapped IPv6 when the destination is IPv6
+                ppp::v2::Addresses::IPv6(ipv6) => Some(
+                    ipv6.source_address
+                        .to_ipv4_mapped()
+                        .map_or_else(|| ipv6.source_address.to_string(), |ipv4| ipv4.to_string()),
+                ),
                 _ => None,
             })
     }
@@ -178,6 +217,18 @@ mod tests {
         assert_eq!(&buf[..], parsed_header.header.as_ref());
     }
 
+    #[tokio::test]
+    async fn test_built_headers_carry_the_client_address() {
+        let header = super::build_proxy_protocol_header(
+            "1.2.3.4:51000".parse().unwrap(),
+            "[::1]:443".parse().unwrap(),
+        )
+        .unwrap();
+        let mut mock = Builder::new().read(&header).build();
+        let accepted_conn = super::try_parse_proxy_protocol(&mut mock).await.unwrap();
+        assert_eq!(accepted_conn.get_remote_addr().as_deref(), Some("1.2.3.4"));
+    }
+
     #[tokio::test]
     async fn test_parse_invalid_proxy_protocol() {
         let buf = build_proxy_protocol_header();
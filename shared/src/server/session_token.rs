@@ -0,0 +1,104 @@
+//! Authentication of connections opened into the enclave by the control plane.
+//!
+//! The data plane generates a random session token at startup and registers it with the control plane over the
+//! config server. The token originates inside the enclave and the control plane never serves it back, so other
+//! processes on the parent can't fetch it. The control plane writes the token as the first bytes of every ingress
+//! connection it opens to the enclave, so any other process on the parent which can open vsock connections can't
+//! inject client traffic.
+use crate::locked_secret::LockedSecret;
+use rand::RngCore;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+pub const SESSION_TOKEN_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct SessionToken(Arc<LockedSecret<SESSION_TOKEN_LEN>>);
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        let mut token = LockedSecret::zeroed();
+        rand::thread_rng().fill_bytes(token.as_mut_slice());
+        Self(Arc::new(token))
+    }
+
+    pub fn from_base64(encoded: &str) -> Option<Self> {
+        let decoded = Zeroizing::new(base64::decode(encoded).ok()?);
+        LockedSecret::from_slice(&decoded).map(|token| Self(Arc::new(token)))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0.as_slice())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    /// Constant time comparison against the token presented on a connection
+    pub fn matches(&self, presented: &[u8]) -> bool {
+        presented.len() == SESSION_TOKEN_LEN && openssl::memcmp::eq(self.as_bytes(), presented)
+    }
+}
+
+impl std::fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionToken([REDACTED])")
+    }
+}
+
+pub async fn write_session_token<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    token: &SessionToken,
+) -> std::io::Result<()> {
+    stream.write_all(token.as_bytes()).await
+}
+
+/// Read the session token presented at the start of a connection, failing if it isn't sent within the timeout.
+pub async fn read_session_token<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    timeout: Duration,
+) -> std::io::Result<Zeroizing<[u8; SESSION_TOKEN_LEN]>> {
+    let mut presented = Zeroizing::new([0u8; SESSION_TOKEN_LEN]);
+    match tokio::time::timeout(timeout, stream.read_exact(presented.as_mut_slice())).await {
+        Ok(read_result) => read_result.map(|_| presented),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Session token not received",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_session_token, write_session_token, SessionToken};
+    use std::time::Duration;
+
+    #[test]
+    fn test_session_token_round_trips_through_base64() {
+        let token = SessionToken::generate();
+        let decoded = SessionToken::from_base64(&token.to_base64()).unwrap();
+        assert!(token.matches(decoded.as_bytes()));
+        assert!(!token.matches(SessionToken::generate().as_bytes()));
+        assert!(!token.matches(&token.as_bytes()[1..]));
+        assert!(SessionToken::from_base64(&base64::encode([0u8; 16])).is_none());
+        assert_eq!(format!("{token:?}"), "SessionToken([REDACTED])");
+    }
+
+    #[tokio::test]
+    async fn test_session_token_is_read_from_start_of_stream() {
+        let token = SessionToken::generate();
+        let (mut client, mut server) = tokio::io::duplex(64);
+        write_session_token(&mut client, &token).await.unwrap();
+        let presented = read_session_token(&mut server, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(token.matches(presented.as_slice()));
+
+        let (_client, mut server) = tokio::io::duplex(64);
+        let result = read_session_token(&mut server, Duration::from_millis(10)).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+}
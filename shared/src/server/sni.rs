@@ -1,7 +1,6 @@
 use thiserror::Error;
 use tls_parser::{
-    nom::Finish, parse_tls_extensions, parse_tls_plaintext, TlsExtension, TlsMessage,
-    TlsMessageHandshake,
+    parse_tls_extension, parse_tls_plaintext, TlsExtension, TlsMessage, TlsMessageHandshake,
 };
 
 #[derive(Debug, Error)]
@@ -17,33 +16,126 @@ pub enum SNIError {
     ExtensionMissing,
 }
 
-pub fn get_hostname(data: Vec<u8>) -> Result<String, SNIError> {
-    let (_, parsed_request) = parse_tls_plaintext(&data)
-        .finish()
+/// Extract the SNI hostname from a buffered Client Hello. The hostname is borrowed from the given buffer, and
+/// extensions are parsed one at a time so that parsing stops at the first SNI entry.
+pub fn get_hostname(data: &[u8]) -> Result<&str, SNIError> {
+    // Not using `Finish` here, as it panics if the buffer holds an incomplete record
+    let (_, parsed_request) = parse_tls_plaintext(data)
         .map_err(|tls_parse_err| SNIError::HostnameError(format!("{tls_parse_err:?}")))?;
 
-    let client_hello = match &parsed_request.msg[0] {
-        TlsMessage::Handshake(TlsMessageHandshake::ClientHello(client_hello)) => client_hello,
+    let client_hello = match parsed_request.msg.first() {
+        Some(TlsMessage::Handshake(TlsMessageHandshake::ClientHello(client_hello))) => client_hello,
         _ => return Err(SNIError::ClientHelloMissing),
     };
 
-    let raw_extensions = match client_hello.ext {
+    let mut raw_extensions = match client_hello.ext {
         Some(raw_extensions) => raw_extensions,
         _ => return Err(SNIError::ExtensionMissing),
     };
-    let mut destination = "".to_string();
-    let (_, extensions) = parse_tls_extensions(raw_extensions)
-        .finish()
-        .map_err(|tls_parse_err| SNIError::HostnameError(format!("{tls_parse_err:?}")))?;
 
-    for extension in extensions {
-        if let TlsExtension::SNI(sni_vec) = extension {
-            for (_, item) in sni_vec {
-                if let Ok(hostname) = std::str::from_utf8(item) {
-                    destination = hostname.to_string();
-                }
+    while !raw_extensions.is_empty() {
+        let (remaining, extension) = parse_tls_extension(raw_extensions)
+            .map_err(|tls_parse_err| SNIError::HostnameError(format!("{tls_parse_err:?}")))?;
+        if let TlsExtension::SNI(sni_entries) = extension {
+            if let Some(hostname) = sni_entries
+                .into_iter()
+                .find_map(|(_, item)| std::str::from_utf8(item).ok())
+            {
+                return Ok(hostname);
             }
         }
+        raw_extensions = remaining;
+    }
+    Err(SNIError::ExtensionMissing)
+}
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_MAJOR_VERSION: u8 = 0x03;
+const CLIENT_HELLO_HANDSHAKE: u8 = 0x01;
+
+/// Check whether the buffered data starts with a TLS record carrying a Client Hello. Only the record and handshake
+/// headers are inspected, so a Client Hello split across multiple reads is still recognised.
+pub fn is_client_hello(data: &[u8]) -> bool {
+    matches!(
+        data,
+        [
+            TLS_HANDSHAKE_RECORD,
+            TLS_MAJOR_VERSION,
+            _,
+            _,
+            _,
+            CLIENT_HELLO_HANDSHAKE,
+            ..
+        ]
+    )
+}
+
+/// The length of the TLS record carrying a Client Hello, including its header, so callers can tell when the whole
+/// record has been buffered.
+pub fn client_hello_record_len(data: &[u8]) -> Option<usize> {
+    if !is_client_hello(data) {
+        return None;
+    }
+    Some(5 + u16::from_be_bytes([data[3], data[4]]) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{client_hello_record_len, get_hostname, is_client_hello, SNIError};
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    fn build_client_hello(server_name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let mut connection =
+            ClientConnection::new(Arc::new(config), server_name.try_into().unwrap()).unwrap();
+        let mut client_hello = Vec::new();
+        connection.write_tls(&mut client_hello).unwrap();
+        client_hello
+    }
+
+    #[test]
+    fn test_hostname_is_extracted_from_client_hello() {
+        let client_hello = build_client_hello("api.evervault.com");
+        let hostname = get_hostname(&client_hello).unwrap();
+        assert_eq!(hostname, "api.evervault.com");
+    }
+
+    #[test]
+    fn test_client_hello_without_sni_is_rejected() {
+        // rustls omits the SNI extension when connecting to an IP address
+        let client_hello = build_client_hello("127.0.0.1");
+        let result = get_hostname(&client_hello);
+        assert!(matches!(result, Err(SNIError::ExtensionMissing)));
+    }
+
+    #[test]
+    fn test_truncated_client_hello_is_rejected() {
+        let client_hello = build_client_hello("api.evervault.com");
+        let result = get_hostname(&client_hello[..client_hello.len() / 2]);
+        assert!(matches!(result, Err(SNIError::HostnameError(_))));
+    }
+
+    #[test]
+    fn test_non_tls_data_is_rejected() {
+        let result = get_hostname(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(matches!(result, Err(SNIError::HostnameError(_))));
+    }
+
+    #[test]
+    fn test_client_hello_is_recognised_from_record_headers() {
+        let client_hello = build_client_hello("api.evervault.com");
+        assert!(is_client_hello(&client_hello));
+        assert!(is_client_hello(&client_hello[..6]));
+        assert!(!is_client_hello(&client_hello[..5]));
+        assert!(!is_client_hello(b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(
+            client_hello_record_len(&client_hello[..6]),
+            Some(client_hello.len())
+        );
+        assert_eq!(client_hello_record_len(b"GET / HTTP/1.1\r\n\r\n"), None);
     }
-    Ok(destination)
 }
@@ -13,6 +13,13 @@ impl TcpServer {
         let listener = TcpListener::bind(addr).await?;
         Ok(Self { inner: listener })
     }
+
+    /// Use a listener which has already been bound, e.g. one passed in by the service manager.
+    pub fn from_std(listener: std::net::TcpListener) -> super::error::ServerResult<Self> {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        Ok(Self { inner: listener })
+    }
 }
 
 #[async_trait]
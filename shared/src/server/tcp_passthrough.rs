@@ -0,0 +1,50 @@
+//! Raw TCP passthrough of ingress ports which aren't served by the data plane's TLS server.
+//!
+//! Every passthrough port shares one vsock port into the enclave. After the session token, the control plane writes the
+//! port the client connected to, so the data plane knows which port of the customer process to pipe the connection to.
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub async fn write_target_port<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    port: u16,
+) -> std::io::Result<()> {
+    stream.write_all(&port.to_be_bytes()).await
+}
+
+/// Read the port written at the start of a passthrough connection, failing if it isn't sent within the timeout.
+pub async fn read_target_port<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    timeout: Duration,
+) -> std::io::Result<u16> {
+    let mut port = [0u8; 2];
+    match tokio::time::timeout(timeout, stream.read_exact(&mut port)).await {
+        Ok(read_result) => read_result.map(|_| u16::from_be_bytes(port)),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Passthrough port not received",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_target_port, write_target_port};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_target_port_is_read_from_start_of_stream() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        write_target_port(&mut client, 1883).await.unwrap();
+        client.write_all(b"\x10").await.unwrap();
+        let port = read_target_port(&mut server, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(port, 1883);
+
+        let (_client, mut server) = tokio::io::duplex(64);
+        let result = read_target_port(&mut server, Duration::from_millis(10)).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+}
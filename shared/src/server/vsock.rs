@@ -1,3 +1,4 @@
+use std::os::fd::{IntoRawFd, OwnedFd};
 use tokio_vsock::{VsockListener, VsockStream};
 
 use super::{error::ServerError, Listener};
@@ -12,6 +13,13 @@ impl VsockServer {
         let listener = VsockListener::bind(cid, port)?;
         Ok(Self { inner: listener })
     }
+
+    /// Use a vsock listener which has already been bound, e.g. one passed in by the service manager.
+    pub fn from_fd(fd: OwnedFd) -> Self {
+        // Safety: ownership of the descriptor moves to the listener
+        let listener = unsafe { std::os::fd::FromRawFd::from_raw_fd(fd.into_raw_fd()) };
+        Self { inner: listener }
+    }
 }
 
 #[async_trait]
@@ -1,19 +1,379 @@
-use std::io::ErrorKind;
+use std::io::{ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::Instant;
 
-pub async fn pipe_streams<T1, T2>(mut src: T1, mut dest: T2) -> Result<(), tokio::io::Error>
+use crate::buffer_pool::{PooledBuffer, STREAM_BUFFER_POOL, STREAM_BUFFER_SIZE};
+
+/// Maximum number of pooled buffers gathered into a single vectored write.
+const MAX_VECTORED_BUFFERS: usize = 8;
+
+/// Bidirectionally pipe two streams until both sides have closed, using pooled buffers for each direction.
+/// Writers which support vectored writes (e.g. TCP sockets) are written to with `writev`, batching up all
+/// of the data the reader has ready into a single syscall.
+pub async fn pipe_streams<T1, T2>(src: T1, dest: T2) -> Result<(), tokio::io::Error>
 where
     T1: AsyncRead + AsyncWrite + Unpin,
     T2: AsyncRead + AsyncWrite + Unpin,
 {
-    match tokio::io::copy_bidirectional(&mut src, &mut dest).await {
+    let (mut src_reader, mut src_writer) = tokio::io::split(src);
+    let (mut dest_reader, mut dest_writer) = tokio::io::split(dest);
+    match tokio::try_join!(
+        copy(&mut src_reader, &mut dest_writer),
+        copy(&mut dest_reader, &mut src_writer)
+    ) {
         Ok(_) => Ok(()),
         Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
         Err(e) => Err(e),
     }
 }
 
+/// Pipe two streams as with [`pipe_streams`], closing the connection with a `TimedOut` error once no bytes have
+/// moved in either direction for the idle timeout.
+pub async fn pipe_streams_with_idle_timeout<T1, T2>(
+    src: T1,
+    dest: T2,
+    idle_timeout: Duration,
+) -> Result<(), tokio::io::Error>
+where
+    T1: AsyncRead + AsyncWrite + Unpin,
+    T2: AsyncRead + AsyncWrite + Unpin,
+{
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    // Everything piped is read from or written to the source, so tracking it covers both directions
+    let src = ActivityTrackingStream {
+        inner: src,
+        last_active: last_active.clone(),
+    };
+    let pipe = pipe_streams(src, dest);
+    tokio::pin!(pipe);
+    loop {
+        let deadline = *last_active.lock().unwrap() + idle_timeout;
+        tokio::select! {
+            result = &mut pipe => return result,
+            _ = tokio::time::sleep_until(deadline) => {
+                if last_active.lock().unwrap().elapsed() >= idle_timeout {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+            }
+        }
+    }
+}
+
+/// A stream which records when bytes were last read from or written to it.
+struct ActivityTrackingStream<S> {
+    inner: S,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl<S> ActivityTrackingStream<S> {
+    fn record<T>(&self, poll: Poll<std::io::Result<T>>, active: bool) -> Poll<std::io::Result<T>> {
+        if active {
+            *self.last_active.lock().unwrap() = Instant::now();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityTrackingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let active = buf.filled().len() > filled;
+        this.record(poll, active)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityTrackingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        let active = matches!(poll, Poll::Ready(Ok(n)) if n > 0);
+        this.record(poll, active)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        let active = matches!(poll, Poll::Ready(Ok(n)) if n > 0);
+        this.record(poll, active)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+async fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, tokio::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if writer.is_write_vectored() {
+        copy_vectored(reader, writer).await
+    } else {
+        copy_with_pooled_buffer(reader, writer).await
+    }
+}
+
+/// Copy from reader to writer until EOF, then shut down the writer to propagate the half-close.
+async fn copy_with_pooled_buffer<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<u64, tokio::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = STREAM_BUFFER_POOL.get();
+    let mut total_bytes = 0;
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total_bytes);
+        }
+        writer.write_all(&buffer[..n]).await?;
+        writer.flush().await?;
+        total_bytes += n as u64;
+    }
+}
+
+/// Copy from reader to writer until EOF, gathering every buffer the reader can fill without waiting into a
+/// single vectored write. Buffers are only taken from the pool once a transfer needs them, so small
+/// exchanges hold a single buffer.
+async fn copy_vectored<R, W>(reader: &mut R, writer: &mut W) -> Result<u64, tokio::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffers: Vec<PooledBuffer<'static>> = vec![STREAM_BUFFER_POOL.get()];
+    let mut lengths = [0usize; MAX_VECTORED_BUFFERS];
+    let mut total_bytes = 0;
+    loop {
+        let n = reader.read(&mut buffers[0]).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total_bytes);
+        }
+        lengths[0] = n;
+        let mut filled = 1;
+
+        // A full buffer suggests more data is waiting, so keep reading until the reader would block
+        while filled < MAX_VECTORED_BUFFERS && lengths[filled - 1] == STREAM_BUFFER_SIZE {
+            if buffers.len() == filled {
+                buffers.push(STREAM_BUFFER_POOL.get());
+            }
+            match try_read_ready(reader, &mut buffers[filled]).await? {
+                // EOF is picked up by the blocking read on the next iteration
+                Some(0) | None => break,
+                Some(n) => {
+                    lengths[filled] = n;
+                    filled += 1;
+                }
+            }
+        }
+
+        write_all_vectored(writer, &buffers[..filled], &lengths[..filled]).await?;
+        writer.flush().await?;
+        total_bytes += lengths[..filled].iter().sum::<usize>() as u64;
+    }
+}
+
+/// Attempt a read without waiting, returning `None` if the reader has no data ready.
+async fn try_read_ready<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> Result<Option<usize>, tokio::io::Error> {
+    std::future::poll_fn(|cx| {
+        let mut read_buf = ReadBuf::new(&mut buffer[..]);
+        match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(Some(read_buf.filled().len()))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Ready(Ok(None)),
+        }
+    })
+    .await
+}
+
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buffers: &[PooledBuffer<'_>],
+    lengths: &[usize],
+) -> Result<(), tokio::io::Error> {
+    // Position of the first unwritten byte, as a buffer index and an offset into that buffer
+    let (mut index, mut offset) = (0, 0);
+    while index < lengths.len() {
+        let mut slices = [IoSlice::new(&[]); MAX_VECTORED_BUFFERS];
+        let pending = lengths.len() - index;
+        slices[0] = IoSlice::new(&buffers[index][offset..lengths[index]]);
+        for i in 1..pending {
+            slices[i] = IoSlice::new(&buffers[index + i][..lengths[index + i]]);
+        }
+
+        let mut written = writer.write_vectored(&slices[..pending]).await?;
+        if written == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        while index < lengths.len() && written >= lengths[index] - offset {
+            written -= lengths[index] - offset;
+            index += 1;
+            offset = 0;
+        }
+        offset += written;
+    }
+    Ok(())
+}
+
+/// A stream which can be read ahead of without consuming the data. Peeked bytes are returned by later reads before
+/// anything else, so a connection can be inspected and then piped on verbatim.
+pub struct PeekableStream<S> {
+    inner: S,
+    peeked: Vec<u8>,
+    // Number of peeked bytes which have since been read
+    consumed: usize,
+}
+
+impl<S> PeekableStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_peeked(inner, Vec::new())
+    }
+
+    /// Wrap a stream which has already had bytes read from it, so they're returned first.
+    pub fn with_peeked(inner: S, peeked: Vec<u8>) -> Self {
+        Self {
+            inner,
+            peeked,
+            consumed: 0,
+        }
+    }
+
+    /// The bytes which have been peeked but not yet read.
+    pub fn peeked(&self) -> &[u8] {
+        &self.peeked[self.consumed..]
+    }
+
+    /// Remove the bytes which have been peeked but not yet read, so later reads start after them.
+    pub fn take_peeked(&mut self) -> Vec<u8> {
+        let peeked = self.peeked.split_off(self.consumed);
+        self.peeked = Vec::new();
+        self.consumed = 0;
+        peeked
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> PeekableStream<S> {
+    /// Return the bytes waiting to be read, reading from the stream first if none have been peeked. An empty slice
+    /// means the stream has closed.
+    pub async fn peek(&mut self) -> Result<&[u8], tokio::io::Error> {
+        if self.peeked().is_empty() {
+            self.peek_more().await?;
+        }
+        Ok(self.peeked())
+    }
+
+    /// Read once more from the stream into the peeked bytes, returning how many bytes were read.
+    pub async fn peek_more(&mut self) -> Result<usize, tokio::io::Error> {
+        let mut buffer = STREAM_BUFFER_POOL.get();
+        let n = self.inner.read(&mut buffer).await?;
+        self.peeked.extend_from_slice(&buffer[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeekableStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.consumed < this.peeked.len() {
+            let peeked = &this.peeked[this.consumed..];
+            let n = peeked.len().min(buf.remaining());
+            buf.put_slice(&peeked[..n]);
+            this.consumed += n;
+            if this.consumed == this.peeked.len() {
+                this.peeked = Vec::new();
+                this.consumed = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeekableStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
 pub struct HexSlice<'a>(&'a [u8]);
 
 impl<'a> std::fmt::UpperHex for HexSlice<'a> {
@@ -61,7 +421,10 @@ macro_rules! env_var_present_and_true {
 
 #[cfg(test)]
 mod tests {
-    use super::HexSlice;
+    use super::{pipe_streams, pipe_streams_with_idle_timeout, HexSlice, PeekableStream};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
 
     #[test]
     fn test_upper_hex_slice_formatting() {
@@ -78,4 +441,97 @@ mod tests {
         let expected_hex = "ff5a".to_string();
         assert_eq!(format!("{hex_slice:x}"), expected_hex);
     }
+
+    #[tokio::test]
+    async fn test_pipe_streams_copies_both_directions() {
+        let (mut client, client_proxy_side) = tokio::io::duplex(64);
+        let (server_proxy_side, mut server) = tokio::io::duplex(64);
+        let pipe = tokio::spawn(pipe_streams(client_proxy_side, server_proxy_side));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+
+        drop(client);
+        drop(server);
+        assert!(pipe.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_pipes_are_closed_once_the_timeout_passes() {
+        let (mut client, client_proxy_side) = tokio::io::duplex(64);
+        let (server_proxy_side, mut server) = tokio::io::duplex(64);
+        let pipe = tokio::spawn(pipe_streams_with_idle_timeout(
+            client_proxy_side,
+            server_proxy_side,
+            Duration::from_secs(30),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+
+        // Activity pushes back the deadline, so the pipe is only closed 30 seconds after the last write
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        assert!(!pipe.is_finished());
+        let error = pipe.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_peeked_bytes_are_read_again() {
+        let (mut client, proxy_side) = tokio::io::duplex(64);
+        let mut stream = PeekableStream::with_peeked(proxy_side, b"he".to_vec());
+        assert_eq!(stream.peek().await.unwrap(), b"he");
+
+        client.write_all(b"llo").await.unwrap();
+        assert_eq!(stream.peek_more().await.unwrap(), 3);
+        assert_eq!(stream.peek().await.unwrap(), b"hello");
+
+        client.write_all(b" world").await.unwrap();
+        drop(client);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello world");
+        assert!(stream.peek().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pipe_streams_large_transfer_over_tcp() {
+        // TCP sockets support vectored writes, so this exercises the batched write path
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+
+        let pipe = tokio::spawn(async move {
+            let (inbound, _) = proxy.accept().await.unwrap();
+            let outbound = TcpStream::connect(upstream_addr).await.unwrap();
+            assert!(outbound.is_write_vectored());
+            pipe_streams(inbound, outbound).await
+        });
+
+        let payload: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = payload.clone();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+            stream.write_all(&payload).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let (mut upstream_conn, _) = upstream.accept().await.unwrap();
+        let mut received = Vec::new();
+        upstream_conn.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        client.await.unwrap();
+        drop(upstream_conn);
+        assert!(pipe.await.unwrap().is_ok());
+    }
 }
@@ -0,0 +1,164 @@
+//! Startup configuration checks. Each plane validates its environment and config before it starts serving, and
+//! reports every problem at once, rather than panicking on the first one it happens to hit mid-request.
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The process can't run correctly with this config, and will exit
+    Fatal,
+    /// The config works, but is unlikely to do what was intended
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    /// The env var or config field at fault
+    pub field: String,
+    pub message: String,
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Fatal => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "[{severity}] {}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidationReport {
+    component: &'static str,
+    issues: Vec<ConfigIssue>,
+}
+
+impl ValidationReport {
+    pub fn new(component: &'static str) -> Self {
+        Self {
+            component,
+            issues: Vec::new(),
+        }
+    }
+
+    pub fn fatal(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Fatal, field.into(), message.into());
+    }
+
+    pub fn warning(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, field.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, field: String, message: String) {
+        self.issues.push(ConfigIssue {
+            severity,
+            field,
+            message,
+        });
+    }
+
+    /// Record a fatal issue if the env var is unset or empty, returning its value otherwise.
+    pub fn require_env(&mut self, var_name: &str) -> Option<String> {
+        match std::env::var(var_name) {
+            Ok(value) if !value.trim().is_empty() => Some(value),
+            Ok(_) => {
+                self.fatal(var_name, "is set but empty");
+                None
+            }
+            Err(std::env::VarError::NotPresent) => {
+                self.fatal(var_name, "is not set");
+                None
+            }
+            Err(std::env::VarError::NotUnicode(_)) => {
+                self.fatal(var_name, "is not valid unicode");
+                None
+            }
+        }
+    }
+
+    pub fn issues(&self) -> &[ConfigIssue] {
+        &self.issues
+    }
+
+    pub fn has_fatal(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity == Severity::Fatal)
+    }
+
+    /// Log every issue found as one report.
+    pub fn log(&self) {
+        if self.issues.is_empty() {
+            log::info!("{} configuration validated", self.component);
+            return;
+        }
+        let fatal_count = self
+            .issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Fatal)
+            .count();
+        let issues = self
+            .issues
+            .iter()
+            .map(|issue| format!("  {issue}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let summary = format!(
+            "{} configuration has {fatal_count} error(s) and {} warning(s):\n{issues}",
+            self.component,
+            self.issues.len() - fatal_count
+        );
+        if fatal_count > 0 {
+            log::error!("{summary}");
+        } else {
+            log::warn!("{summary}");
+        }
+    }
+
+    /// Log the report, and exit the process if any issue is fatal.
+    pub fn exit_on_fatal(self) {
+        self.log();
+        if self.has_fatal() {
+            log::error!(
+                "{} cannot start until the errors above are fixed",
+                self.component
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Severity, ValidationReport};
+
+    #[test]
+    fn test_required_env_vars_are_reported() {
+        std::env::set_var("VALIDATION_TEST_PRESENT", "value");
+        std::env::set_var("VALIDATION_TEST_EMPTY", " ");
+        std::env::remove_var("VALIDATION_TEST_MISSING");
+
+        let mut report = ValidationReport::new("Test");
+        assert_eq!(
+            report.require_env("VALIDATION_TEST_PRESENT"),
+            Some("value".to_string())
+        );
+        assert!(!report.has_fatal());
+        assert_eq!(report.require_env("VALIDATION_TEST_EMPTY"), None);
+        assert_eq!(report.require_env("VALIDATION_TEST_MISSING"), None);
+        assert!(report.has_fatal());
+        assert_eq!(
+            report.issues()[1].to_string(),
+            "[error] VALIDATION_TEST_MISSING: is not set"
+        );
+    }
+
+    #[test]
+    fn test_warnings_are_not_fatal() {
+        let mut report = ValidationReport::new("Test");
+        report.warning("ports", "no ports");
+        assert_eq!(report.issues()[0].severity, Severity::Warning);
+        assert!(!report.has_fatal());
+    }
+}